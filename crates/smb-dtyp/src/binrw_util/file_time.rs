@@ -96,6 +96,17 @@ impl From<FileTime> for SystemTime {
     }
 }
 
+/// Converts a system time, truncated to 100ns intervals. Times before 1601 convert to zero.
+impl From<SystemTime> for FileTime {
+    fn from(src: SystemTime) -> FileTime {
+        let epoch = SystemTime::from(FileTime::EPOCH.as_utc());
+        let since_epoch = src.duration_since(epoch).unwrap_or_default();
+        FileTime {
+            value: (since_epoch.as_nanos() / Self::SCALE_VALUE_TO_NANOS as u128) as u64,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,6 +120,7 @@ mod tests {
         assert_eq!(FileTime::from(TEST_VAL1_U64).date_time(), TEST_VAL1_DT);
         let result: SystemTime = FileTime::from(TEST_VAL1_U64).into();
         assert_eq!(time::UtcDateTime::from(result), TEST_VAL1_DT.as_utc());
+        assert_eq!(*FileTime::from(result), TEST_VAL1_U64);
     }
 
     #[test]
@@ -116,6 +128,17 @@ mod tests {
         assert_eq!(*FileTime::from(TEST_VAL1_DT), TEST_VAL1_U64)
     }
 
+    #[test]
+    pub fn test_file_time_from_system_time() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+        let file_time = FileTime::from(time);
+        assert_eq!(*file_time, 116_444_736_000_000_000 + 17_000_000_001_234_567);
+        assert_eq!(SystemTime::from(file_time), time - Duration::from_nanos(89));
+
+        let before_epoch = SystemTime::from(FileTime::EPOCH.as_utc()) - Duration::from_secs(1);
+        assert!(FileTime::from(before_epoch).is_zero());
+    }
+
     #[test]
     pub fn test_zero_file_time() {
         let ft = FileTime::ZERO;
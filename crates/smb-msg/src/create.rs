@@ -105,7 +105,8 @@ pub struct CreateRequest {
     /// The Unicode file name to be created or opened
     #[brw(align_before = 8)]
     #[bw(write_with = PosMarker::write_aoff, args(&_name_offset))]
-    #[br(args { size: SizedStringSize::bytes16(name_length) })]
+    // An empty name opens the share's root directory.
+    #[br(if(name_length > 0), args { size: SizedStringSize::bytes16(name_length) })]
     pub name: SizedWideString,
 
     /// The list of create contexts sent in this request.
@@ -821,7 +822,6 @@ pub struct DH2QResp {
 #[smb_request(size = 24)]
 pub struct CloseRequest {
     #[bw(calc = CloseFlags::new().with_postquery_attrib(true))]
-    #[br(assert(_flags == CloseFlags::new().with_postquery_attrib(true)))]
     _flags: CloseFlags,
     reserved: u32,
     /// The identifier of the open to a file or named pipe that is being closed
@@ -934,6 +934,21 @@ mod tests {
             0000000000000000000000000000000000000000"
     }
 
+    // An empty name opens the root directory of the share.
+    test_request! {
+        share_root: Create {
+            requested_oplock_level: OplockLevel::None,
+            impersonation_level: ImpersonationLevel::Impersonation,
+            desired_access: FileAccessMask::new().with_generic_read(true),
+            file_attributes: FileAttributes::new(),
+            share_access: ShareAccessFlags::new().with_read(true).with_write(true),
+            create_disposition: CreateDisposition::Open,
+            create_options: CreateOptions::new().with_directory_file(true),
+            name: "".into(),
+            contexts: vec![].into(),
+        } => "3900000002000000000000000000000000000000000000000000008000000000030000000100000001000000780000007800000000000000"
+    }
+
     #[cfg(feature = "client")]
     use smb_dtyp::make_guid;
 
@@ -1061,4 +1076,17 @@ mod tests {
             flags: DurableHandleV2Flags::new(),
         } => "b300000008000000dd000000080000008c423ea2ac1b437e845191f9f2277a9500000000"
     }
+
+    test_request! {
+        Close {
+            file_id: guid!("000000b3-0008-0000-dd00-000008000000").into(),
+        } => "1800010000000000b300000008000000dd00000008000000"
+    }
+
+    // Clients that do not need the attributes of the closed file leave the postquery flag clear.
+    test_request_read! {
+        close_without_postquery: Close {
+            file_id: guid!("000000b3-0008-0000-dd00-000008000000").into(),
+        } => "1800000000000000b300000008000000dd00000008000000"
+    }
 }
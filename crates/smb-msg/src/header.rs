@@ -9,7 +9,7 @@ use smb_msg_derive::{smb_message_binrw, smb_request_response};
 /// SMB2/SMB3 protocol command codes.
 ///
 /// Reference: MS-SMB2 2.2.1.2
-#[derive(BinRead, BinWrite, Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(BinRead, BinWrite, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[brw(repr(u16))]
 pub enum Command {
     Negotiate = 0,
@@ -145,6 +145,7 @@ make_status! {
     InfoLengthMismatch = 0xC0000004: "Info Length Mismatch",
     InvalidParameter = 0xC000000D: "Invalid Parameter",
     NoSuchDevice = 0xC000000E: "No Such Device",
+    NoSuchFile = 0xC000000F: "No Such File",
     InvalidDeviceRequest0 = 0xC0000010: "Invalid Device Request",
     EndOfFile = 0xC0000011: "End of File",
     MoreProcessingRequired = 0xC0000016: "More Processing Required",
@@ -157,6 +158,7 @@ make_status! {
     ObjectPathNotFound = 0xC000003A: "Object Path Not Found",
     NoEasOnFile = 0xC0000044: "No EAs on File",
     LogonFailure = 0xC000006D: "Logon Failure",
+    AccountDisabled = 0xC0000072: "Account Disabled",
     NotMapped = 0xC0000073: "Not Mapped",
     BadImpersonationLevel = 0xC00000A5: "Bad Impersonation Level",
     IoTimeout = 0xC00000B5: "I/O Timeout",
@@ -166,7 +168,9 @@ make_status! {
     BadNetworkName = 0xC00000CC: "Bad Network Name",
     RequestNotAccepted = 0xC00000D0: "Request Not Accepted",
     DirectoryNotEmpty = 0xC0000101: "Directory Not Empty",
+    NotADirectory = 0xC0000103: "Not a Directory",
     Cancelled = 0xC0000120: "Cancelled",
+    FileClosed = 0xC0000128: "File Closed",
     UserSessionDeleted = 0xC0000203: "User Session Deleted",
     UserAccountLockedOut = 0xC0000234: "User Account Locked Out",
     PathNotCovered = 0xC0000257: "Path Not Covered",
@@ -292,4 +296,19 @@ mod tests {
             )),
         } => "fe534d4240000000030100000f000100130000000000000008000000000000000800000000000000d72753080000000063f825deae02952fa3d8c8aaf46e7c99"
     }
+
+    #[test]
+    fn test_status_from_u32() {
+        for (value, status) in [
+            (0xC000000F, Status::NoSuchFile),
+            (0xC0000103, Status::NotADirectory),
+            (0xC0000128, Status::FileClosed),
+        ] {
+            assert_eq!(Status::try_from(value).unwrap(), status);
+        }
+        assert_eq!(
+            Status::try_display_as_status(Status::U32_FILE_CLOSED),
+            "File Closed (0xc0000128)"
+        );
+    }
 }
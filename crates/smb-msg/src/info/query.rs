@@ -216,7 +216,7 @@ pub struct QueryInfoResponse {
     #[br(seek_before = SeekFrom::Start(output_buffer_offset.value.into()))]
     #[br(map_stream = |s| s.take_seek(output_buffer_length.value.into()))]
     #[bw(write_with = PosMarker::write_aoff_size, args(&output_buffer_offset, &output_buffer_length))]
-    data: QueryInfoResponseData,
+    pub data: QueryInfoResponseData,
 }
 
 impl QueryInfoResponse {
@@ -1,9 +1,11 @@
 //! IOCTL requessts and responses implementation, and FSCTLs.
 
 mod common;
+mod content_info;
 mod fsctl;
 mod msg;
 
 pub use common::*;
+pub use content_info::*;
 pub use fsctl::*;
 pub use msg::*;
@@ -0,0 +1,171 @@
+//! BranchCache Content Information structures, as retrieved using FSCTL_SRV_READ_HASH.
+//!
+//! Version 1 follows the [`SrvReadHashRes`][crate::SrvReadHashRes] header of the Content Information File,
+//! whose portions are returned by hash-based requests ([`SrvHashRetrieveHashBased`][crate::SrvHashRetrieveHashBased]).
+//! Version 2 is returned as is by file-based requests ([`SrvHashRetrieveFileBased`][crate::SrvHashRetrieveFileBased]).
+//!
+//! Reference: MS-PCCRC 2.3, 2.4
+use binrw::prelude::*;
+use smb_msg_derive::smb_response_binrw;
+
+/// Hash algorithms of Content Information version 1.0.
+///
+/// Reference: MS-PCCRC 2.3
+#[smb_response_binrw]
+#[derive(Clone, Copy)]
+#[brw(repr(u32))]
+pub enum ContentHashAlgorithmV1 {
+    Sha256 = 0x800C,
+    Sha384 = 0x800D,
+    Sha512 = 0x800E,
+}
+
+impl ContentHashAlgorithmV1 {
+    /// The length, in bytes, of a hash produced by the algorithm.
+    pub fn hash_len(&self) -> usize {
+        match self {
+            ContentHashAlgorithmV1::Sha256 => 32,
+            ContentHashAlgorithmV1::Sha384 => 48,
+            ContentHashAlgorithmV1::Sha512 => 64,
+        }
+    }
+}
+
+/// Content Information version 1.0, describing the segments and blocks of a file's content.
+///
+/// Reference: MS-PCCRC 2.3
+#[smb_response_binrw]
+pub struct ContentInformationV1 {
+    #[bw(calc = 0x0100)]
+    #[br(temp, assert(version == 0x0100))]
+    version: u16,
+    /// The hash algorithm used for all hashes of the structure.
+    pub hash_algorithm: ContentHashAlgorithmV1,
+    /// The offset of the described content range, within the first segment.
+    pub offset_in_first_segment: u32,
+    /// The number of bytes of the described content range, within the last segment.
+    pub read_bytes_in_last_segment: u32,
+    #[bw(try_calc = segments.len().try_into())]
+    #[br(temp)]
+    segment_count: u32,
+    /// The description of each segment.
+    #[br(count = segment_count, args { inner: (hash_algorithm.hash_len(),) })]
+    #[bw(assert(segments.len() == blocks.len()))]
+    pub segments: Vec<SegmentDescriptionV1>,
+    /// The block hashes of each segment, in the same order as [`segments`][Self::segments].
+    #[br(count = segment_count, args { inner: (hash_algorithm.hash_len(),) })]
+    pub blocks: Vec<SegmentContentBlocksV1>,
+}
+
+/// Segment description of Content Information version 1.0.
+///
+/// Reference: MS-PCCRC 2.3.1.1
+#[smb_response_binrw]
+#[br(import(hash_len: usize))]
+pub struct SegmentDescriptionV1 {
+    /// The offset of the segment within the content.
+    pub offset_in_content: u64,
+    /// The length of the segment, in bytes.
+    pub segment_length: u32,
+    /// The length of each block of the segment, in bytes. The last block may be shorter.
+    pub block_size: u32,
+    /// The hash of the segment's block hashes (HoD).
+    #[br(count = hash_len)]
+    pub segment_hash_of_data: Vec<u8>,
+    /// The segment secret (Kp), derived from the HoD and the server secret.
+    #[br(count = hash_len)]
+    pub segment_secret: Vec<u8>,
+}
+
+/// Block hashes of a single segment of Content Information version 1.0.
+///
+/// Reference: MS-PCCRC 2.3.1.2
+#[smb_response_binrw]
+#[br(import(hash_len: usize))]
+pub struct SegmentContentBlocksV1 {
+    #[bw(try_calc = block_hashes.len().try_into())]
+    #[br(temp)]
+    block_count: u32,
+    /// The hash of each block of the segment.
+    #[br(count = block_count, args { inner: binrw::VecArgs { count: hash_len, inner: () } })]
+    pub block_hashes: Vec<Vec<u8>>,
+}
+
+/// Hash algorithms of Content Information version 2.0.
+///
+/// Reference: MS-PCCRC 2.4
+#[smb_response_binrw]
+#[derive(Clone, Copy)]
+#[brw(repr(u8))]
+pub enum ContentHashAlgorithmV2 {
+    /// SHA-512, truncated to its first 32 bytes.
+    TruncatedSha512 = 0x04,
+}
+
+impl ContentHashAlgorithmV2 {
+    /// The length, in bytes, of a hash produced by the algorithm.
+    pub fn hash_len(&self) -> usize {
+        match self {
+            ContentHashAlgorithmV2::TruncatedSha512 => 32,
+        }
+    }
+}
+
+/// Content Information version 2.0. Unlike version 1.0, this structure is big-endian,
+/// and each segment is hashed as a whole.
+///
+/// Reference: MS-PCCRC 2.4
+#[smb_response_binrw]
+#[brw(big)]
+pub struct ContentInformationV2 {
+    /// The major (high byte) and minor (low byte) version.
+    #[bw(calc = 0x0200)]
+    #[br(temp, assert(version == 0x0200))]
+    version: u16,
+    /// The hash algorithm used for all hashes of the structure.
+    pub hash_algorithm: ContentHashAlgorithmV2,
+    /// The offset of the described content range, within the first segment.
+    pub offset_in_first_segment: u32,
+    /// The number of bytes of the described content range, within the last segment.
+    pub read_bytes_in_last_segment: u32,
+    /// The offset of the first segment within the content.
+    pub start_in_content: u64,
+    /// The chunks of the structure, up to its end.
+    #[br(parse_with = binrw::helpers::until_eof)]
+    pub chunks: Vec<ContentChunkV2>,
+}
+
+/// A chunk of Content Information version 2.0.
+///
+/// Reference: MS-PCCRC 2.4.1
+#[smb_response_binrw]
+#[brw(big)]
+pub struct ContentChunkV2 {
+    #[bw(calc = 0)]
+    #[br(temp, assert(chunk_type == 0))]
+    chunk_type: u8,
+    #[bw(try_calc = (segments.len() * SegmentDescriptionV2::SIZE).try_into())]
+    #[br(temp, assert(chunk_data_length as usize % SegmentDescriptionV2::SIZE == 0))]
+    chunk_data_length: u32,
+    /// The segments described by the chunk.
+    #[br(count = chunk_data_length as usize / SegmentDescriptionV2::SIZE)]
+    pub segments: Vec<SegmentDescriptionV2>,
+}
+
+/// Segment description of Content Information version 2.0.
+///
+/// Reference: MS-PCCRC 2.4.1.1
+#[smb_response_binrw]
+#[brw(big)]
+pub struct SegmentDescriptionV2 {
+    /// The length of the segment, in bytes.
+    pub segment_length: u32,
+    /// The hash of the segment's data (HoD).
+    pub segment_hash_of_data: [u8; 32],
+    /// The segment secret (Kp), derived from the HoD and the server secret.
+    pub segment_secret: [u8; 32],
+}
+
+impl SegmentDescriptionV2 {
+    pub const SIZE: usize = size_of::<u32>() + 32 * 2;
+}
@@ -110,11 +110,16 @@ pub struct SrvReadHashReq {
     pub hash_version: u32,
     /// Indicates the nature of the offset field and how it should be interpreted.
     pub hash_retrieval_type: SrvHashRetrievalType,
+    /// The length, in bytes, of the data to retrieve from the location indicated by the offset field.
+    pub length: u32,
+    /// The offset of the data to retrieve: relative to the beginning of the Content Information File
+    /// when hash-based, or to the beginning of the file when file-based.
+    pub offset: u64,
 }
 
 impl IoctlRequestContent for SrvReadHashReq {
     fn get_bin_size(&self) -> u32 {
-        size_of::<u32>() as u32 * 3
+        size_of::<u32>() as u32 * 4 + size_of::<u64>() as u32
     }
 }
 
@@ -286,11 +291,12 @@ pub struct SrvCopychunkResponse {
 
 impl_fsctl_response!(SrvCopychunk, SrvCopychunkResponse);
 
-/// Response packet for SRV_READ_HASH requests.
-/// Returned by the server in an SMB2 IOCTL Response for FSCTL_SRV_READ_HASH request.
-/// The response is not valid for the SMB 2.0.2 dialect.
+/// The header of a Content Information File, which is retrieved using SRV_READ_HASH requests
+/// with [`SrvHashRetrievalType::HashBased`], followed by the Content Information itself.
+/// It is not an IOCTL output of its own: its bytes are in the blob of [`SrvHashRetrieveHashBased`].
+/// Not valid for the SMB 2.0.2 dialect.
 ///
-/// Reference: MS-SMB2 2.2.32.4
+/// Reference: MS-SMB2 2.2.32.4.1
 #[smb_response_binrw]
 pub struct SrvReadHashRes {
     /// The hash type of the response. Must be set to SRV_HASH_TYPE_PEER_DIST for branch caching.
@@ -301,27 +307,25 @@ pub struct SrvReadHashRes {
     /// Must be version 1 (branch cache version 1) or version 2 (branch cache version 2).
     #[br(assert((1..=2).contains(&hash_version)))]
     #[bw(assert((1..=2).contains(hash_version)))]
-    hash_version: u32,
+    pub hash_version: u32,
     /// The last change time of the source file.
-    source_file_change_time: FileTime,
+    pub source_file_change_time: FileTime,
     /// The size of the source file in bytes.
-    source_file_size: u64,
-    /// Position marker for the length of the hash blob.
-    hash_blob_length: PosMarker<u32>,
-    /// Position marker for the offset of the hash blob.
-    hash_blob_offset: PosMarker<u32>,
+    pub source_file_size: u64,
+    /// The length, in bytes, of the Content Information.
+    pub hash_blob_length: u32,
+    /// The offset, in bytes, of the Content Information from the beginning of this header.
+    pub hash_blob_offset: u32,
     /// Indicates whether the file has been modified since the Content Information was generated.
-    dirty: u16,
+    pub dirty: u16,
     /// The length of the source file name in bytes.
     #[bw(try_calc = source_file_name.len().try_into())]
     source_file_name_length: u16,
     /// The name of the source file.
     #[br(count = source_file_name_length)]
-    source_file_name: Vec<u8>,
+    pub source_file_name: Vec<u8>,
 }
 
-impl_fsctl_response!(SrvReadHash, SrvReadHashRes);
-
 /// Hash-based response format for SRV_READ_HASH when HashRetrievalType is SRV_HASH_RETRIEVE_HASH_BASED.
 /// Contains a portion of the Content Information File retrieved from a specified offset.
 ///
@@ -335,10 +339,10 @@ pub struct SrvHashRetrieveHashBased {
     #[bw(try_calc = blob.len().try_into())]
     buffer_length: u32,
     reserved: u32,
-    /// A variable-length buffer that contains the retrieved portion of the Content Information File.
-    /// TODO: Parse as Content Information File as specified in MS-PCCRC section 2.3.
+    /// A variable-length buffer that contains the retrieved portion of the Content Information File,
+    /// starting with a [`SrvReadHashRes`] header.
     #[br(count = buffer_length)]
-    blob: Vec<u8>,
+    pub blob: Vec<u8>,
 }
 
 impl_fsctl_response!(SrvReadHash, SrvHashRetrieveHashBased);
@@ -359,12 +363,14 @@ pub struct SrvHashRetrieveFileBased {
     #[bw(try_calc = buffer.len().try_into())]
     buffer_length: u32,
     reserved: u32,
-    /// A variable-length buffer that contains the retrieved portion of the Content Information File.
-    /// TODO: Parse as Content Information File as specified in MS-PCCRC section 2.4.
+    /// A variable-length buffer that contains the Content Information of the range,
+    /// to be parsed as [`ContentInformationV2`][crate::ContentInformationV2].
     #[br(count = buffer_length)]
     pub buffer: Vec<u8>,
 }
 
+impl_fsctl_response!(SrvReadHash, SrvHashRetrieveFileBased);
+
 pub type NetworkInterfacesInfo = ChainedItemList<NetworkInterfaceInfo>;
 
 impl_fsctl_response!(QueryNetworkInterfaceInfo, NetworkInterfacesInfo);
@@ -808,6 +814,15 @@ mod tests {
         } => "2000000000000000000000000000000000000000000000000000a00000000000"
     }
 
+    test_binrw_request! {
+        struct SrvReadHashReq {
+            hash_version: 1,
+            hash_retrieval_type: SrvHashRetrievalType::HashBased,
+            length: 0x10000,
+            offset: 0x40,
+        } => "010000000100000001000000000001004000000000000000"
+    }
+
     test_binrw_response! {
         struct SrvRequestResumeKey {
             resume_key: [
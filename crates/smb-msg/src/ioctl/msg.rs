@@ -148,7 +148,11 @@ ioctl_req_data! {
     QueryNetworkInterfaceInfo: QueryNetworkInterfaceInfoRequest, NetworkInterfacesInfo,
     SrvCopychunk: SrvCopychunkCopy, SrvCopychunkResponse,
     SrvCopychunkWrite: SrvCopyChunkCopyWrite, SrvCopychunkResponse,
-    SrvReadHash: SrvReadHashReq, SrvReadHashRes,
+    // The output of SRV_READ_HASH is SRV_HASH_RETRIEVE_HASH_BASED or SRV_HASH_RETRIEVE_FILE_BASED,
+    // by the requested retrieval type. The former is the typed response, and the latter is parsed
+    // explicitly. The HASH_HEADER ([`SrvReadHashRes`]) starts the hash-based Content Information File,
+    // which is returned in portions, so it is not an output of its own.
+    SrvReadHash: SrvReadHashReq, SrvHashRetrieveHashBased,
     LmrRequestResiliency: NetworkResiliencyRequest, LmrRequestResiliencyResponse,
     ValidateNegotiateInfo: ValidateNegotiateInfoRequest, ValidateNegotiateInfoResponse,
     DfsGetReferrals: ReqGetDfsReferral, RespGetDfsReferral,
@@ -244,7 +248,7 @@ impl IoctlResponse {
             return Err(crate::SmbMsgError::MissingFsctlDefinition(self.ctl_code));
         }
         let mut cursor = std::io::Cursor::new(&self.out_buffer);
-        Ok(T::read_le(&mut cursor).unwrap())
+        Ok(T::read_le(&mut cursor)?)
     }
 }
 
@@ -133,12 +133,24 @@ pub struct ServerToClientNotification {
     reserved: u16,
     /// Valid SMB_NOTIFICATION_ID enumeration notification type value.
     #[bw(calc = notification.get_type())]
+    #[br(temp)]
     notification_type: NotificationType,
     /// Corresponding structure type based on the notification type.
     #[br(args(notification_type))]
     pub notification: Notification,
 }
 
+impl ServerToClientNotification {
+    const STRUCTURE_SIZE: u16 = 4;
+
+    pub fn new(notification: Notification) -> Self {
+        Self {
+            structure_size: Self::STRUCTURE_SIZE,
+            notification,
+        }
+    }
+}
+
 /// SMB_NOTIFICATION_ID enumeration values for server to client notifications.
 ///
 /// Reference: MS-SMB2 2.2.44.1
@@ -176,6 +188,7 @@ impl Notification {
 ///
 /// Reference: MS-SMB2 2.2.44.2
 #[smb_response_binrw]
+#[derive(Default)]
 pub struct NotifySessionClosed {
     reserved: u32,
 }
@@ -17,11 +17,11 @@ pub struct OplockBreakMsg {
     /// The oplock level. For notifications, this is the maximum level the server will accept.
     /// For acknowledgments, this is the lowered level the client accepts.
     /// For responses, this is the granted level.
-    oplock_level: u8,
+    pub oplock_level: OplockLevel,
     reserved: u8,
     reserved: u32,
     /// The file identifier on which the oplock break occurred.
-    file_id: FileId,
+    pub file_id: FileId,
 }
 
 /// Lease Break Notification message.
@@ -34,15 +34,15 @@ pub struct OplockBreakMsg {
 pub struct LeaseBreakNotify {
     /// A 16-bit unsigned integer indicating a lease state change by the server.
     /// Only valid for SMB 3.x dialect family. For SMB 2.1, this field is reserved.
-    new_epoch: u16,
+    pub new_epoch: u16,
     /// Flag indicating whether a Lease Break Acknowledgment is required.
-    ack_required: u32,
+    pub ack_required: u32,
     /// The client-generated key that identifies the owner of the lease.
-    lease_key: Guid,
+    pub lease_key: Guid,
     /// The current lease state of the open.
-    current_lease_state: LeaseState,
+    pub current_lease_state: LeaseState,
     /// The new lease state for the open.
-    new_lease_state: LeaseState,
+    pub new_lease_state: LeaseState,
     #[bw(calc = 0)]
     #[br(assert(break_reason == 0))]
     #[br(temp)]
@@ -61,6 +61,7 @@ pub struct LeaseBreakNotify {
 ///
 /// Reference: MS-SMB2 2.2.23.1
 #[smb_message_binrw]
+#[derive(Clone, Copy)]
 #[brw(repr(u8))]
 pub enum OplockLevel {
     /// No oplock is available.
@@ -69,6 +70,10 @@ pub enum OplockLevel {
     II = 1,
     /// Exclusive oplock is available.
     Exclusive = 2,
+    /// A batch oplock is available.
+    Batch = 9,
+    /// A lease is requested/granted, and is described by a lease create context.
+    Lease = 0xff,
 }
 
 /// Lease state bitfield representing different types of caching permissions.
@@ -111,10 +116,10 @@ pub struct LeaseBreakAckResponse {
     reserved: u32,
 
     /// The client-generated key that identifies the owner of the lease.
-    lease_key: Guid,
+    pub lease_key: Guid,
     /// The lease state. For acknowledgments, this must be a subset of the lease state
     /// granted by the server. For responses, this is the requested lease state.
-    lease_state: LeaseState,
+    pub lease_state: LeaseState,
 
     /// Lease duration (reserved)
     reserved: u64,
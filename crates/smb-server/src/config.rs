@@ -0,0 +1,165 @@
+//! Server configuration: dialects, users, shares and security requirements.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use smb_msg::{CompressionAlgorithm, Dialect};
+
+use crate::{Error, Result};
+
+/// Configuration of a [`Server`][crate::Server].
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The minimal dialect the server accepts. Defaults to [`Dialect::MIN`].
+    pub min_dialect: Option<Dialect>,
+    /// The maximal dialect the server accepts. Defaults to [`Dialect::MAX`].
+    pub max_dialect: Option<Dialect>,
+
+    /// The users allowed to authenticate, using NTLM.
+    pub users: UserDatabase,
+    /// The disk shares served. The `IPC$` share is always available, but has no files.
+    pub shares: Vec<ShareConfig>,
+
+    /// Whether to require all requests, after session setup, to be signed.
+    pub require_signing: bool,
+    /// Whether to require encryption for all sessions.
+    ///
+    /// Clients that negotiate a dialect without encryption support (SMB 2.x) fail to set up sessions.
+    pub encrypt_data: bool,
+    /// Compression algorithms the server supports (SMB 3.1.1). Empty disables compression.
+    pub compression_algorithms: Vec<CompressionAlgorithm>,
+
+    /// The maximal number of credits a client may hold at once.
+    pub max_credits: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            min_dialect: None,
+            max_dialect: None,
+            users: UserDatabase::default(),
+            shares: vec![],
+            require_signing: false,
+            encrypt_data: false,
+            compression_algorithms: vec![],
+            max_credits: 512,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Validates the configuration.
+    ///
+    /// Share paths must be existing directories, and share names must be unique.
+    pub fn validate(&self) -> Result<()> {
+        let min_dialect = self.min_dialect.unwrap_or(Dialect::MIN);
+        let max_dialect = self.max_dialect.unwrap_or(Dialect::MAX);
+        if min_dialect > max_dialect {
+            return Err(Error::InvalidConfiguration(
+                "Minimum dialect is greater than maximum dialect".to_string(),
+            ));
+        }
+        if self.encrypt_data && !max_dialect.is_smb3() {
+            return Err(Error::InvalidConfiguration(
+                "Encryption requires SMB 3.0 or later".to_string(),
+            ));
+        }
+        if self.max_credits == 0 {
+            return Err(Error::InvalidConfiguration(
+                "Maximum credits must be positive".to_string(),
+            ));
+        }
+
+        for (i, share) in self.shares.iter().enumerate() {
+            if share.name.is_empty()
+                || share.name.eq_ignore_ascii_case(ShareConfig::IPC_SHARE_NAME)
+                || share.name.contains(['\\', '/'])
+            {
+                return Err(Error::InvalidConfiguration(format!(
+                    "Invalid share name: {:?}",
+                    share.name
+                )));
+            }
+            if self.shares[..i]
+                .iter()
+                .any(|other| other.name.eq_ignore_ascii_case(&share.name))
+            {
+                return Err(Error::InvalidConfiguration(format!(
+                    "Duplicate share name: {}",
+                    share.name
+                )));
+            }
+            if !share.path.is_dir() {
+                return Err(Error::InvalidConfiguration(format!(
+                    "Path of share {} is not a directory: {}",
+                    share.name,
+                    share.path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration of a share, by its case-insensitive name.
+    pub fn share(&self, name: &str) -> Option<&ShareConfig> {
+        self.shares
+            .iter()
+            .find(|share| share.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A disk share, mapped to a local directory.
+#[derive(Debug, Clone)]
+pub struct ShareConfig {
+    /// The name of the share, as used in UNC paths.
+    pub name: String,
+    /// The local directory served as the share's root.
+    pub path: PathBuf,
+    /// Whether the share rejects any modification.
+    pub read_only: bool,
+}
+
+impl ShareConfig {
+    pub const IPC_SHARE_NAME: &'static str = "IPC$";
+
+    pub fn new(name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            name: name.into(),
+            path: path.into(),
+            read_only: false,
+        }
+    }
+}
+
+/// Local user accounts, used to authenticate NTLM clients.
+///
+/// User names are case-insensitive. Domains are ignored.
+#[derive(Default, Clone)]
+pub struct UserDatabase {
+    users: HashMap<String, String>,
+}
+
+impl UserDatabase {
+    /// Adds a user, replacing the password of an existing user with the same name.
+    pub fn add_user(&mut self, name: &str, password: &str) -> &mut Self {
+        self.users.insert(name.to_lowercase(), password.to_string());
+        self
+    }
+
+    /// Returns the password of a user, if it exists.
+    pub fn password(&self, name: &str) -> Option<&str> {
+        self.users.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl std::fmt::Debug for UserDatabase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Passwords are never printed.
+        f.debug_set().entries(self.users.keys()).finish()
+    }
+}
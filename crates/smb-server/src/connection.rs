@@ -0,0 +1,1139 @@
+//! A single client connection: framing, transforms, credits, sessions and request dispatch.
+
+mod ops;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use binrw::prelude::*;
+use smb::compression::{Compressor, Decompressor};
+use smb::connection::preauth_hash::PreauthHashState;
+use smb::dialects::DialectImpl;
+use smb_dtyp::binrw_util::prelude::FileTime;
+use smb_fscc::FileAccessMask;
+use smb_msg::*;
+use smb_transport::{IoVec, SmbTransport};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::fs::{DirEntry, Share};
+use crate::notify::{ChangeEvent, Watch};
+use crate::server::ServerShared;
+use crate::session::{ServerSession, SetupStep};
+use crate::{Error, Result};
+
+/// Serves a single client connection, until the client disconnects.
+pub(crate) async fn serve(
+    shared: Arc<ServerShared>,
+    transport: Box<dyn SmbTransport>,
+) -> Result<()> {
+    let remote = transport.remote_address().ok();
+    let (mut reader, mut writer) = transport.split()?;
+
+    // Reading is done by a separate task, so change notifications may be sent while waiting for requests.
+    let (incoming_tx, mut incoming) = mpsc::channel(16);
+    let reader_task = tokio::spawn(async move {
+        loop {
+            match reader.receive().await {
+                Ok(message) => {
+                    if incoming_tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Stopped receiving from {remote:?}: {e}");
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut changes = shared.changes.subscribe();
+    let mut state = ConnectionState::new(shared.clone());
+    let result = loop {
+        let output = tokio::select! {
+            message = incoming.recv() => {
+                let Some(message) = message else {
+                    break Ok(());
+                };
+                // Requests are handled using blocking file system calls.
+                let (returned, output) = tokio::task::spawn_blocking(move || {
+                    let output = state.handle(message);
+                    (state, output)
+                })
+                .await?;
+                state = returned;
+                match output {
+                    Ok(output) => output,
+                    Err(e) => {
+                        log::warn!("Failed to process a message from {remote:?}: {e}");
+                        continue;
+                    }
+                }
+            }
+            event = changes.recv() => match event {
+                Ok(event) => state.on_change(&event)?,
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    log::warn!("Missed {count} change events, notifying all watchers");
+                    state.on_changes_lost()?
+                }
+                Err(broadcast::error::RecvError::Closed) => break Ok(()),
+            },
+        };
+
+        for event in output.events {
+            // There is always at least one receiver - this connection.
+            let _ = shared.changes.send(event);
+        }
+        let mut send_result = Ok(());
+        for response in output.responses {
+            send_result = writer.send(&response).await;
+            if send_result.is_err() {
+                break;
+            }
+        }
+        if let Err(e) = send_result {
+            break Err(e.into());
+        }
+    };
+    reader_task.abort();
+    log::debug!("Connection from {remote:?} closed");
+    result
+}
+
+/// Properties of a negotiated connection.
+pub(crate) struct Negotiated {
+    pub dialect: Arc<DialectImpl>,
+    pub signing_algo: Option<SigningAlgorithmId>,
+    pub cipher: Option<EncryptionCipher>,
+    pub compression: Option<CompressionCapabilities>,
+    pub preauth_hash: PreauthHashState,
+}
+
+/// Tracks the message IDs the client is allowed to use.
+struct CreditWindow {
+    available: BTreeSet<u64>,
+    next: u64,
+    max: u16,
+}
+
+impl CreditWindow {
+    fn new(max: u16) -> Self {
+        Self {
+            available: BTreeSet::from([0]),
+            next: 1,
+            max,
+        }
+    }
+
+    /// Consumes the message IDs used by a request. Returns false if any of them was not granted.
+    fn consume(&mut self, message_id: u64, charge: u16) -> bool {
+        let end = message_id.saturating_add(charge.max(1) as u64);
+        let mut valid = true;
+        for id in message_id..end {
+            valid &= self.available.remove(&id);
+        }
+        valid
+    }
+
+    /// Grants credits to the client, returning the number of credits granted.
+    fn grant(&mut self, requested: u16) -> u16 {
+        let room = self.max.saturating_sub(self.available.len() as u16);
+        let granted = requested.min(room).max(1);
+        self.available.extend(self.next..self.next + granted as u64);
+        self.next += granted as u64;
+        granted
+    }
+}
+
+/// Where to update the preauth integrity hash with a response.
+enum PreauthUpdate {
+    None,
+    Connection,
+    Session(u64),
+}
+
+/// The result of processing a request.
+struct Outcome {
+    status: Status,
+    content: ResponseContent,
+    session_id: Option<u64>,
+    tree_id: Option<u32>,
+    /// Sign the response, even if the request was not signed.
+    sign: bool,
+    compress: bool,
+    preauth_update: PreauthUpdate,
+    /// The request goes async, and is completed later, e.g. a change notification.
+    async_id: Option<u64>,
+}
+
+impl Outcome {
+    fn success(content: impl Into<ResponseContent>) -> Self {
+        Self::with_status(Status::Success, content.into())
+    }
+
+    fn error(status: Status) -> Self {
+        Self::with_status(status, ErrorResponse { error_data: vec![] }.into())
+    }
+
+    fn with_status(status: Status, content: ResponseContent) -> Self {
+        Self {
+            status,
+            content,
+            session_id: None,
+            tree_id: None,
+            sign: false,
+            compress: false,
+            preauth_update: PreauthUpdate::None,
+            async_id: None,
+        }
+    }
+}
+
+/// The properties of a request required to respond to it.
+#[derive(Debug, Clone)]
+struct RequestInfo {
+    header: Header,
+    signed: bool,
+    encrypted: bool,
+}
+
+/// A plain request, parsed from a (possibly compounded) message.
+struct Request {
+    info: RequestInfo,
+    content: RequestContent,
+    /// The data of a write request; empty for other requests.
+    write_data: Vec<u8>,
+    /// The plain message, as received, for signature verification and the preauth integrity hash.
+    raw: IoVec,
+}
+
+/// The messages to send, and the changes to publish, after processing a message.
+#[derive(Default)]
+struct Output {
+    responses: Vec<IoVec>,
+    events: Vec<ChangeEvent>,
+}
+
+/// A change notify request, waiting for changes.
+struct PendingNotify {
+    async_id: u64,
+    open_id: u64,
+    info: RequestInfo,
+    output_buffer_length: u32,
+}
+
+struct TreeConnect {
+    session_id: u64,
+    /// The disk share; `None` for `IPC$`.
+    share: Option<Share>,
+}
+
+/// An open file or directory.
+pub(crate) struct Open {
+    session_id: u64,
+    tree_id: u32,
+    share: Share,
+    path: PathBuf,
+    is_dir: bool,
+    file: Option<std::fs::File>,
+    access: FileAccessMask,
+    delete_on_close: bool,
+    /// The directory listing being enumerated, and the position of the next entry.
+    enumeration: Option<(Vec<DirEntry>, usize)>,
+    watch: Option<Watch>,
+}
+
+/// The state of a single connection to the server.
+struct ConnectionState {
+    shared: Arc<ServerShared>,
+    negotiated: Option<Negotiated>,
+    credits: CreditWindow,
+    sessions: HashMap<u64, ServerSession>,
+    trees: HashMap<u32, TreeConnect>,
+    opens: HashMap<u64, Open>,
+    pending: Vec<PendingNotify>,
+    next_id: u64,
+    /// Changes made while processing the current message.
+    events: Vec<ChangeEvent>,
+    /// Responses to previous requests, completed while processing the current message.
+    completions: Vec<IoVec>,
+}
+
+impl ConnectionState {
+    const MAX_TRANSACT_SIZE: u32 = 0x800000;
+    const COMPRESSION_THRESHOLD: usize = 1024;
+    const FULL_ACCESS: u32 = 0x001f01ff;
+    const READ_ACCESS: u32 = 0x001200a9;
+
+    fn new(shared: Arc<ServerShared>) -> Self {
+        let max_credits = shared.config.max_credits;
+        Self {
+            shared,
+            negotiated: None,
+            credits: CreditWindow::new(max_credits),
+            sessions: HashMap::new(),
+            trees: HashMap::new(),
+            opens: HashMap::new(),
+            pending: vec![],
+            next_id: 1,
+            events: vec![],
+            completions: vec![],
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Processes a single incoming message, returning the messages to send back.
+    fn handle(&mut self, raw: Vec<u8>) -> Result<Output> {
+        let mut responses = if raw.starts_with(b"\xffSMB") {
+            vec![self.handle_smb1_negotiate()?]
+        } else {
+            self.handle_smb2(raw)?
+        };
+        responses.append(&mut self.completions);
+        Ok(Output {
+            responses,
+            events: std::mem::take(&mut self.events),
+        })
+    }
+
+    fn handle_smb2(&mut self, raw: Vec<u8>) -> Result<Vec<IoVec>> {
+        let (data, encrypted) = self.unwrap_transforms(raw)?;
+        let requests = Self::split_compound(&data, encrypted)?;
+
+        // A cancel request is never compounded, and is not answered by itself.
+        if let [request] = requests.as_slice() {
+            if let RequestContent::Cancel(_) = request.content {
+                return self.handle_cancel(&request.info.header);
+            }
+        }
+
+        let mut responses = vec![];
+        let mut last_file_id = None;
+        let mut last_status = None;
+        for mut request in requests {
+            let related = request.info.header.flags.related_operations();
+            if related {
+                if let Some(status) = last_status.filter(|s: &Status| !Self::is_success(*s)) {
+                    // Related operations fail the same way the previous operation did.
+                    let outcome = Outcome::error(status);
+                    responses.push(self.respond(&request.info, outcome)?);
+                    continue;
+                }
+                if let (Some(file_id), Some(last)) =
+                    (Self::file_id_mut(&mut request.content), last_file_id)
+                {
+                    if *file_id == FileId::FULL {
+                        *file_id = last;
+                    }
+                }
+            }
+
+            if !self.credits.consume(
+                request.info.header.message_id,
+                request.info.header.credit_charge,
+            ) {
+                log::warn!(
+                    "Request with message ID {} uses credits that were not granted",
+                    request.info.header.message_id
+                );
+            }
+
+            let outcome = self.process(&request);
+            last_status = Some(outcome.status);
+            last_file_id = match &outcome.content {
+                ResponseContent::Create(create) => Some(create.file_id),
+                _ => Self::file_id_mut(&mut request.content).map(|id| *id),
+            };
+            let logged_off =
+                request.info.header.command == Command::Logoff && outcome.status == Status::Success;
+            responses.push(self.respond(&request.info, outcome)?);
+            if logged_off {
+                self.logoff(request.info.header.session_id);
+            }
+        }
+
+        let response = self.finish_responses(responses, encrypted)?;
+        Ok(response.into_iter().collect())
+    }
+
+    fn is_success(status: Status) -> bool {
+        (status as u32) < 0x80000000
+    }
+
+    /// Splits a plain message into its compounded requests.
+    fn split_compound(data: &[u8], encrypted: bool) -> Result<Vec<Request>> {
+        let mut requests = vec![];
+        let mut start = 0;
+        loop {
+            let rest = &data[start..];
+            let header = Header::read_le(&mut Cursor::new(rest))?;
+            let end = match header.next_command {
+                0 => data.len(),
+                next => start + next as usize,
+            };
+            if end > data.len() || end <= start {
+                return Err(Error::InvalidMessage(
+                    "Invalid compounded message offset".to_string(),
+                ));
+            }
+            let part = &data[start..end];
+            let message = PlainRequest::read_le(&mut Cursor::new(part))?;
+            let write_data = match &message.content {
+                RequestContent::Write(write) => Self::write_data(part, write.length)?,
+                _ => vec![],
+            };
+            requests.push(Request {
+                info: RequestInfo {
+                    signed: message.header.flags.signed(),
+                    header: message.header,
+                    encrypted,
+                },
+                content: message.content,
+                write_data,
+                raw: IoVec::from(part.to_vec()),
+            });
+            if end == data.len() {
+                return Ok(requests);
+            }
+            start = end;
+        }
+    }
+
+    /// Returns the file ID a request operates on, if any.
+    fn file_id_mut(content: &mut RequestContent) -> Option<&mut FileId> {
+        match content {
+            RequestContent::Close(req) => Some(&mut req.file_id),
+            RequestContent::Flush(req) => Some(&mut req.file_id),
+            RequestContent::Read(req) => Some(&mut req.file_id),
+            RequestContent::Write(req) => Some(&mut req.file_id),
+            RequestContent::QueryDirectory(req) => Some(&mut req.file_id),
+            RequestContent::ChangeNotify(req) => Some(&mut req.file_id),
+            RequestContent::QueryInfo(req) => Some(&mut req.file_id),
+            RequestContent::SetInfo(req) => Some(&mut req.file_id),
+            RequestContent::Ioctl(req) => Some(&mut req.file_id),
+            RequestContent::Lock(req) => Some(&mut req.file_id),
+            _ => None,
+        }
+    }
+
+    /// Removes encryption and compression transforms, returning the plain message.
+    fn unwrap_transforms(&mut self, raw: Vec<u8>) -> Result<(Vec<u8>, bool)> {
+        let mut data = raw;
+        let mut encrypted = false;
+
+        if data.starts_with(b"\xfdSMB") {
+            let message = EncryptedMessage::read_le(&mut Cursor::new(&data))?;
+            let decryptor = self
+                .sessions
+                .get_mut(&message.header.session_id)
+                .and_then(|s| s.decryptor.as_mut())
+                .ok_or_else(|| {
+                    Error::InvalidMessage(
+                        "Encrypted message for a session without encryption".to_string(),
+                    )
+                })?;
+            let mut buffer = message.encrypted_message;
+            decryptor.decrypt(
+                &mut buffer,
+                &message.header.aead_bytes(),
+                &message.header.nonce,
+                message.header.signature,
+            )?;
+            data = buffer;
+            encrypted = true;
+        }
+
+        if data.starts_with(b"\xfcSMB") {
+            let message = CompressedMessage::read_le(&mut Cursor::new(&data))?;
+            let caps = self
+                .negotiated
+                .as_ref()
+                .and_then(|n| n.compression.as_ref())
+                .ok_or_else(|| {
+                    Error::InvalidMessage(
+                        "Compressed message without negotiated compression".to_string(),
+                    )
+                })?;
+            data = Decompressor::new(caps).decompress_bytes(&message)?;
+        }
+
+        Ok((data, encrypted))
+    }
+
+    /// Extracts the data of a write request from the plain message.
+    fn write_data(data: &[u8], length: u32) -> Result<Vec<u8>> {
+        const DATA_OFFSET_POSITION: usize = Header::STRUCT_SIZE + 2;
+        let offset = data
+            .get(DATA_OFFSET_POSITION..DATA_OFFSET_POSITION + 2)
+            .map(|o| u16::from_le_bytes([o[0], o[1]]) as usize);
+        offset
+            .and_then(|offset| data.get(offset..offset + length as usize))
+            .map(|d| d.to_vec())
+            .ok_or_else(|| Error::InvalidMessage("Invalid write data offset".to_string()))
+    }
+
+    fn verify_signature(&self, header: &Header, data: &IoVec) -> bool {
+        // Signers are single-use, so a fresh clone is used for each message.
+        match self
+            .sessions
+            .get(&header.session_id)
+            .and_then(|s| s.signer.clone())
+        {
+            Some(mut signer) => signer.verify_signature(&mut header.clone(), data).is_ok(),
+            // Requests of unknown sessions are rejected later.
+            None => true,
+        }
+    }
+
+    /// Validates the request against the state of the connection & session, and processes it.
+    fn process(&mut self, request: &Request) -> Outcome {
+        let header = &request.info.header;
+        let command = header.command;
+        if !matches!(command, Command::Negotiate) && self.negotiated.is_none() {
+            return Outcome::error(Status::InvalidParameter);
+        }
+
+        if request.info.signed && !self.verify_signature(header, &request.raw) {
+            log::warn!("Bad signature for message ID {}", header.message_id);
+            return Outcome::error(Status::AccessDenied);
+        }
+
+        let session = self.sessions.get(&header.session_id);
+        if !matches!(
+            command,
+            Command::Negotiate | Command::SessionSetup | Command::Echo
+        ) {
+            let Some(session) = session.filter(|s| s.is_ready()) else {
+                return Outcome::error(Status::UserSessionDeleted);
+            };
+            if session.encrypt_data && !request.info.encrypted {
+                log::warn!("Rejecting unencrypted {command} request on an encrypted session");
+                return Outcome::error(Status::AccessDenied);
+            }
+            if self.shared.config.require_signing && !request.info.signed && !request.info.encrypted
+            {
+                log::warn!("Rejecting unsigned {command} request");
+                return Outcome::error(Status::AccessDenied);
+            }
+        }
+
+        let result = match &request.content {
+            RequestContent::Negotiate(req) => self.negotiate(req, &request.raw),
+            RequestContent::SessionSetup(req) => Ok(self.session_setup(request, req)),
+            // The session is removed once the response is signed, see `handle_smb2`.
+            RequestContent::Logoff(_) => Ok(Outcome::success(LogoffResponse {})),
+            RequestContent::TreeConnect(req) => Ok(self.tree_connect(header, req)),
+            RequestContent::TreeDisconnect(_) => Ok(self.tree_disconnect(header)),
+            RequestContent::Echo(_) => Ok(Outcome::success(ResponseContent::Echo(
+                EchoMessage::default(),
+            ))),
+            content => Ok(self.file_operation(request, content)),
+        };
+        result.unwrap_or_else(|e| {
+            log::warn!("Failed to handle {command}: {e}");
+            Outcome::error(Status::InvalidParameter)
+        })
+    }
+
+    fn handle_cancel(&mut self, header: &Header) -> Result<Vec<IoVec>> {
+        let position = self.pending.iter().position(|p| match header.async_id {
+            Some(async_id) => p.async_id == async_id,
+            None => p.info.header.message_id == header.message_id,
+        });
+        let Some(position) = position else {
+            return Ok(vec![]);
+        };
+        let pending = self.pending.remove(position);
+        let response = self.complete(&pending, Outcome::error(Status::Cancelled))?;
+        Ok(vec![response])
+    }
+
+    fn handle_smb1_negotiate(&mut self) -> Result<IoVec> {
+        self.credits.consume(0, 1);
+        let granted = self.credits.grant(1);
+        let response = self.negotiate_response(NegotiateDialect::Smb02Wildcard, None);
+        let mut message = PlainResponse::new(response.into());
+        message.header.message_id = 0;
+        message.header.credit_request = granted;
+        message.header.flags.set_server_to_redir(true);
+        let mut buffer = Vec::new();
+        message.write(&mut Cursor::new(&mut buffer))?;
+        Ok(IoVec::from(buffer))
+    }
+
+    /// Builds the response to a request. Asynchronous outcomes produce an interim response.
+    fn respond(
+        &mut self,
+        request: &RequestInfo,
+        outcome: Outcome,
+    ) -> Result<(PlainResponse, Transform)> {
+        let mut header = Self::response_header(
+            &request.header,
+            outcome.status,
+            outcome.session_id,
+            outcome.tree_id,
+        );
+        header.credit_request = self.credits.grant(request.header.credit_request);
+        if let Some(async_id) = outcome.async_id {
+            header.to_async(async_id);
+        }
+        Ok((
+            PlainResponse {
+                header,
+                content: outcome.content,
+            },
+            Transform {
+                sign: outcome.sign || request.signed,
+                compress: outcome.compress,
+                preauth_update: outcome.preauth_update,
+            },
+        ))
+    }
+
+    /// Builds the final response to a pending request.
+    fn complete(&mut self, pending: &PendingNotify, outcome: Outcome) -> Result<IoVec> {
+        let mut header = Self::response_header(&pending.info.header, outcome.status, None, None);
+        // Credits were granted by the interim response.
+        header.credit_request = 0;
+        header.to_async(pending.async_id);
+        let response = (
+            PlainResponse {
+                header,
+                content: outcome.content,
+            },
+            Transform {
+                sign: pending.info.signed,
+                compress: false,
+                preauth_update: PreauthUpdate::None,
+            },
+        );
+        let response = self.finish_responses(vec![response], pending.info.encrypted)?;
+        response.ok_or_else(|| Error::InvalidState("Empty response".to_string()))
+    }
+
+    fn response_header(
+        request: &Header,
+        status: Status,
+        session_id: Option<u64>,
+        tree_id: Option<u32>,
+    ) -> Header {
+        Header {
+            credit_charge: request.credit_charge,
+            status: status as u32,
+            command: request.command,
+            credit_request: 0,
+            flags: HeaderFlags::new()
+                .with_server_to_redir(true)
+                .with_related_operations(request.flags.related_operations()),
+            next_command: 0,
+            message_id: request.message_id,
+            tree_id: Some(tree_id.or(request.tree_id).unwrap_or(0)),
+            async_id: None,
+            session_id: session_id.unwrap_or(request.session_id),
+            signature: 0,
+        }
+    }
+
+    /// Serializes responses into a single (possibly compounded) message,
+    /// and signs, compresses and encrypts it as required.
+    fn finish_responses(
+        &mut self,
+        responses: Vec<(PlainResponse, Transform)>,
+        encrypt: bool,
+    ) -> Result<Option<IoVec>> {
+        let Some(session_id) = responses.first().map(|(r, _)| r.header.session_id) else {
+            return Ok(None);
+        };
+        let compound = responses.len() > 1;
+        let count = responses.len();
+        let mut message = Vec::new();
+        let mut compress = false;
+        for (i, (mut response, transform)) in responses.into_iter().enumerate() {
+            let is_last = i == count - 1;
+            let is_interim = response.header.status == Status::Pending as u32;
+            // Responses to requests of removed sessions are not signed.
+            let signer = self
+                .sessions
+                .get(&response.header.session_id)
+                .and_then(|s| s.signer.clone())
+                .filter(|_| transform.sign && !is_interim && !encrypt);
+            response.header.flags.set_signed(signer.is_some());
+            compress |= transform.compress && !compound;
+
+            let mut buffer = Self::serialize(&response)?;
+            if !is_last {
+                // Compounded responses are 8-byte aligned, and chained by their next command offset.
+                let padded = buffer.len().next_multiple_of(8);
+                response.header.next_command = padded as u32;
+                buffer = Self::serialize(&response)?;
+                buffer.resize(padded, 0);
+            }
+            let mut part = IoVec::from(buffer);
+
+            match transform.preauth_update {
+                PreauthUpdate::None => {}
+                PreauthUpdate::Connection => {
+                    if let Some(negotiated) = self.negotiated.as_mut() {
+                        negotiated.preauth_hash = negotiated.preauth_hash.clone().next(&part);
+                    }
+                }
+                PreauthUpdate::Session(id) => {
+                    if let Some(session) = self.sessions.get_mut(&id) {
+                        session.next_preauth_hash(&part);
+                    }
+                }
+            }
+
+            if let Some(mut signer) = signer {
+                signer.sign_message(&mut response.header, &mut part)?;
+            }
+            part.consolidate();
+            message.extend_from_slice(part.first().unwrap());
+        }
+        let mut data = IoVec::from(message);
+
+        let compression = self
+            .negotiated
+            .as_ref()
+            .and_then(|n| n.compression.as_ref());
+        if let Some(caps) = compression.filter(|_| compress) {
+            if data.total_size() > Self::COMPRESSION_THRESHOLD {
+                if let Some(compressed) =
+                    Compressor::new(caps).compress(&[data.first().unwrap()])?
+                {
+                    let mut buffer = Vec::with_capacity(compressed.total_size());
+                    compressed.write(&mut Cursor::new(&mut buffer))?;
+                    data = IoVec::from(buffer);
+                }
+            }
+        }
+
+        if encrypt {
+            let encryptor = self
+                .sessions
+                .get_mut(&session_id)
+                .and_then(|s| s.encryptor.as_mut())
+                .ok_or_else(|| {
+                    Error::InvalidState("No encryptor for the response's session".to_string())
+                })?;
+            let header = encryptor.encrypt_message(&mut data, session_id)?;
+            let message = EncryptedMessage {
+                header,
+                encrypted_message: data.first().unwrap().to_vec(),
+            };
+            let mut buffer = Vec::new();
+            message.write_le(&mut Cursor::new(&mut buffer))?;
+            data = IoVec::from(buffer);
+        }
+
+        Ok(Some(data))
+    }
+
+    fn serialize(response: &PlainResponse) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        response.write(&mut Cursor::new(&mut buffer))?;
+        Ok(buffer)
+    }
+
+    fn negotiate(&mut self, req: &NegotiateRequest, raw_request: &IoVec) -> Result<Outcome> {
+        let config = &self.shared.config;
+        let min_dialect = config.min_dialect.unwrap_or(Dialect::MIN);
+        let max_dialect = config.max_dialect.unwrap_or(Dialect::MAX);
+        let selected = req
+            .dialects
+            .iter()
+            .filter(|d| (min_dialect..=max_dialect).contains(*d))
+            .max()
+            .copied();
+        let Some(selected) = selected.filter(|_| self.negotiated.is_none()) else {
+            return Ok(Outcome::error(Status::NotSupported));
+        };
+        let dialect = DialectImpl::new(selected);
+
+        let mut signing_algo = None;
+        let mut cipher = None;
+        let mut compression = None;
+        let mut contexts: Option<Vec<NegotiateContext>> = None;
+        let preauth_hash = if dialect.preauth_hash_supported() {
+            let mut ctx_list: Vec<NegotiateContext> = vec![
+                PreauthIntegrityCapabilities {
+                    hash_algorithms: vec![HashAlgorithm::Sha512],
+                    salt: vec![0; 32],
+                }
+                .into(),
+            ];
+
+            signing_algo = Self::select(
+                &[
+                    SigningAlgorithmId::AesGmac,
+                    SigningAlgorithmId::AesCmac,
+                    SigningAlgorithmId::HmacSha256,
+                ],
+                req.get_ctx_signing_capabilities()
+                    .map(|c| c.signing_algorithms.as_slice()),
+                smb::crypto::SIGNING_ALGOS,
+            );
+            if let Some(algo) = signing_algo {
+                ctx_list.push(
+                    SigningCapabilities {
+                        signing_algorithms: vec![algo],
+                    }
+                    .into(),
+                );
+            }
+
+            let offered_ciphers = req
+                .get_ctx_encryption_capabilities()
+                .map(|c| c.ciphers.as_slice());
+            cipher = Self::select(
+                offered_ciphers.unwrap_or_default(),
+                offered_ciphers,
+                smb::crypto::ENCRYPTING_ALGOS,
+            );
+            if let Some(cipher) = cipher {
+                ctx_list.push(
+                    EncryptionCapabilities {
+                        ciphers: vec![cipher],
+                    }
+                    .into(),
+                );
+            }
+
+            let algorithms = req
+                .get_ctx_compression_capabilities()
+                .map(|c| {
+                    c.compression_algorithms
+                        .iter()
+                        .filter(|a| config.compression_algorithms.contains(a))
+                        .copied()
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if !algorithms.is_empty() {
+                let caps = CompressionCapabilities {
+                    flags: CompressionCapsFlags::new(),
+                    compression_algorithms: algorithms,
+                };
+                ctx_list.push(caps.clone().into());
+                compression = Some(caps);
+            }
+
+            contexts = Some(ctx_list);
+            PreauthHashState::begin().next(raw_request)
+        } else {
+            if selected.is_smb3()
+                && req.capabilities.encryption()
+                && smb::crypto::ENCRYPTING_ALGOS.contains(&EncryptionCipher::Aes128Ccm)
+            {
+                cipher = Some(EncryptionCipher::Aes128Ccm);
+            }
+            PreauthHashState::unsupported()
+        };
+
+        let mut response = self.negotiate_response(Self::negotiate_dialect(selected), contexts);
+        response.capabilities = GlobalCapabilities::new()
+            .with_large_mtu(selected > Dialect::Smb0202)
+            .with_encryption(cipher.is_some() && !dialect.preauth_hash_supported());
+
+        self.negotiated = Some(Negotiated {
+            dialect,
+            signing_algo,
+            cipher,
+            compression,
+            preauth_hash,
+        });
+
+        let mut outcome = Outcome::success(response);
+        outcome.preauth_update = PreauthUpdate::Connection;
+        Ok(outcome)
+    }
+
+    /// Selects the first candidate offered by the client, and supported by the current build.
+    fn select<T: PartialEq + Copy>(
+        candidates: &[T],
+        offered: Option<&[T]>,
+        supported: &[T],
+    ) -> Option<T> {
+        let offered = offered?;
+        candidates
+            .iter()
+            .copied()
+            .find(|algo| offered.contains(algo) && supported.contains(algo))
+    }
+
+    fn negotiate_dialect(dialect: Dialect) -> NegotiateDialect {
+        match dialect {
+            Dialect::Smb0202 => NegotiateDialect::Smb0202,
+            Dialect::Smb021 => NegotiateDialect::Smb021,
+            Dialect::Smb030 => NegotiateDialect::Smb030,
+            Dialect::Smb0302 => NegotiateDialect::Smb0302,
+            Dialect::Smb0311 => NegotiateDialect::Smb0311,
+        }
+    }
+
+    fn negotiate_response(
+        &self,
+        dialect_revision: NegotiateDialect,
+        negotiate_context_list: Option<Vec<NegotiateContext>>,
+    ) -> NegotiateResponse {
+        NegotiateResponse {
+            security_mode: NegotiateSecurityMode::new()
+                .with_signing_enabled(true)
+                .with_signing_required(self.shared.config.require_signing),
+            dialect_revision,
+            server_guid: self.shared.server_guid,
+            capabilities: GlobalCapabilities::new(),
+            max_transact_size: Self::MAX_TRANSACT_SIZE,
+            max_read_size: Self::MAX_TRANSACT_SIZE,
+            max_write_size: Self::MAX_TRANSACT_SIZE,
+            system_time: FileTime::from(std::time::SystemTime::now()),
+            server_start_time: self.shared.start_time,
+            buffer: vec![],
+            negotiate_context_list,
+        }
+    }
+
+    fn session_setup(&mut self, request: &Request, req: &SessionSetupRequest) -> Outcome {
+        let Some(preauth_hash) = self.negotiated.as_ref().map(|n| n.preauth_hash.clone()) else {
+            return Outcome::error(Status::InvalidParameter);
+        };
+        let session_id = match request.info.header.session_id {
+            0 => {
+                let session = match ServerSession::new(preauth_hash) {
+                    Ok(session) => session,
+                    Err(_) => return Outcome::error(Status::LogonFailure),
+                };
+                let id = self.next_id();
+                self.sessions.insert(id, session);
+                id
+            }
+            id if self.sessions.contains_key(&id) => id,
+            _ => return Outcome::error(Status::UserSessionDeleted),
+        };
+
+        let config = &self.shared.config;
+        let negotiated = self.negotiated.as_ref().unwrap();
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        let step = session.accept(&request.raw, &req.buffer, negotiated, &config.users);
+
+        let mut outcome = match step {
+            Ok(SetupStep::Continue(token)) => {
+                let mut outcome = Outcome::with_status(
+                    Status::MoreProcessingRequired,
+                    SessionSetupResponse {
+                        session_flags: SessionFlags::new(),
+                        buffer: token,
+                    }
+                    .into(),
+                );
+                outcome.preauth_update = PreauthUpdate::Session(session_id);
+                outcome
+            }
+            Ok(SetupStep::Done) if config.encrypt_data && session.encryptor.is_none() => {
+                log::info!("Rejecting a session that does not support encryption");
+                self.sessions.remove(&session_id);
+                Outcome::error(Status::AccessDenied)
+            }
+            Ok(SetupStep::Done) => {
+                log::info!(
+                    "User {} logged on, session {session_id:#x}",
+                    session.user_name.as_deref().unwrap_or_default()
+                );
+                session.encrypt_data = config.encrypt_data;
+                let mut outcome = Outcome::success(SessionSetupResponse {
+                    session_flags: SessionFlags::new().with_encrypt_data(session.encrypt_data),
+                    buffer: vec![],
+                });
+                outcome.sign = true;
+                outcome
+            }
+            Err(e) => {
+                log::info!("Rejected session setup: {e}");
+                self.sessions.remove(&session_id);
+                Outcome::error(Status::LogonFailure)
+            }
+        };
+        outcome.session_id = Some(session_id);
+        outcome
+    }
+
+    /// Removes a session, with its tree connects and opens.
+    fn logoff(&mut self, session_id: u64) {
+        self.sessions.remove(&session_id);
+        self.trees.retain(|_, tree| tree.session_id != session_id);
+        let opens = self
+            .opens
+            .iter()
+            .filter(|(_, open)| open.session_id == session_id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in opens {
+            self.close_open(id);
+        }
+    }
+
+    fn tree_connect(&mut self, header: &Header, req: &TreeConnectRequest) -> Outcome {
+        let path = req.buffer.to_string();
+        let name = path.rsplit('\\').next().unwrap_or_default();
+        let (share, share_type, maximal_access) =
+            if name.eq_ignore_ascii_case(crate::ShareConfig::IPC_SHARE_NAME) {
+                (None, ShareType::Pipe, Self::READ_ACCESS)
+            } else {
+                match self.shared.share(name) {
+                    Some(share) if share.read_only => {
+                        (Some(share.clone()), ShareType::Disk, Self::READ_ACCESS)
+                    }
+                    Some(share) => (Some(share.clone()), ShareType::Disk, Self::FULL_ACCESS),
+                    None => return Outcome::error(Status::BadNetworkName),
+                }
+            };
+
+        let tree_id = self.next_id() as u32;
+        self.trees.insert(
+            tree_id,
+            TreeConnect {
+                session_id: header.session_id,
+                share,
+            },
+        );
+        let mut outcome = Outcome::success(TreeConnectResponse {
+            share_type,
+            share_flags: ShareFlags::new(),
+            capabilities: TreeCapabilities::new(),
+            maximal_access,
+        });
+        outcome.tree_id = Some(tree_id);
+        outcome
+    }
+
+    fn tree_disconnect(&mut self, header: &Header) -> Outcome {
+        // A tree may only be disconnected by the session that connected it.
+        let Some(tree_id) = header.tree_id.filter(|id| {
+            self.trees
+                .get(id)
+                .is_some_and(|tree| tree.session_id == header.session_id)
+        }) else {
+            return Outcome::error(Status::NetworkNameDeleted);
+        };
+        self.trees.remove(&tree_id);
+        let opens = self
+            .opens
+            .iter()
+            .filter(|(_, open)| open.tree_id == tree_id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in opens {
+            self.close_open(id);
+        }
+        Outcome::success(TreeDisconnectResponse {})
+    }
+
+    /// Handles a change made through the server, completing pending change notifications.
+    fn on_change(&mut self, event: &ChangeEvent) -> Result<Output> {
+        for open in self.opens.values_mut() {
+            if let Some(watch) = open.watch.as_mut() {
+                watch.push(event);
+            }
+        }
+        self.complete_notifications()
+    }
+
+    /// Handles missed changes, by asking all watching clients to enumerate their directories again.
+    fn on_changes_lost(&mut self) -> Result<Output> {
+        for open in self.opens.values_mut() {
+            if let Some(watch) = open.watch.as_mut() {
+                watch.overflow();
+            }
+        }
+        self.complete_notifications()
+    }
+
+    /// Completes pending change notify requests that have changes to report.
+    fn complete_notifications(&mut self) -> Result<Output> {
+        let mut responses = vec![];
+        let mut i = 0;
+        while i < self.pending.len() {
+            let open_id = self.pending[i].open_id;
+            let has_changes = self
+                .opens
+                .get(&open_id)
+                .and_then(|open| open.watch.as_ref())
+                .is_some_and(Watch::has_changes);
+            if !has_changes {
+                i += 1;
+                continue;
+            }
+            let pending = self.pending.remove(i);
+            let outcome = self.notify_outcome(open_id, pending.output_buffer_length);
+            responses.push(self.complete(&pending, outcome)?);
+        }
+        Ok(Output {
+            responses,
+            events: vec![],
+        })
+    }
+}
+
+/// How to transform a response before sending it.
+struct Transform {
+    sign: bool,
+    compress: bool,
+    preauth_update: PreauthUpdate,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smb_dtyp::Guid;
+
+    fn make_state() -> ConnectionState {
+        let (changes, _) = broadcast::channel(1);
+        ConnectionState::new(Arc::new(ServerShared {
+            config: Default::default(),
+            server_guid: Guid::generate(),
+            shares: vec![],
+            start_time: FileTime::default(),
+            changes,
+        }))
+    }
+
+    #[test]
+    fn test_tree_disconnect_by_other_session() {
+        let mut state = make_state();
+        let tree_id = 7;
+        let header = |session_id| Header {
+            credit_charge: 1,
+            status: Status::Success as u32,
+            command: Command::TreeDisconnect,
+            credit_request: 1,
+            flags: HeaderFlags::new(),
+            next_command: 0,
+            message_id: 1,
+            tree_id: Some(tree_id),
+            async_id: None,
+            session_id,
+            signature: 0,
+        };
+        state.trees.insert(
+            tree_id,
+            TreeConnect {
+                session_id: 1,
+                share: None,
+            },
+        );
+
+        let outcome = state.tree_disconnect(&header(2));
+        assert_eq!(outcome.status, Status::NetworkNameDeleted);
+        assert!(state.trees.contains_key(&tree_id));
+
+        let outcome = state.tree_disconnect(&header(1));
+        assert_eq!(outcome.status, Status::Success);
+        assert!(state.trees.is_empty());
+    }
+}
@@ -0,0 +1,645 @@
+//! File operations: create, close, read, write, directory queries, info and change notifications.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use binrw::prelude::*;
+use smb_fscc::*;
+use smb_msg::*;
+
+use super::{ConnectionState, Open, Outcome, PendingNotify, Request, RequestInfo};
+use crate::fs::{FileInfo, Share, encode_directory, list_directory};
+use crate::notify::{ChangeEvent, ChangeKind, Watch};
+
+impl ConnectionState {
+    /// Handles a request operating on a tree connect.
+    pub(super) fn file_operation(
+        &mut self,
+        request: &Request,
+        content: &RequestContent,
+    ) -> Outcome {
+        let header = &request.info.header;
+        let share = match header.tree_id.and_then(|id| self.trees.get(&id)) {
+            Some(tree) if tree.session_id == header.session_id => tree.share.clone(),
+            _ => return Outcome::error(Status::NetworkNameDeleted),
+        };
+
+        let result = match content {
+            RequestContent::Create(req) => match share {
+                Some(share) => self.create(header, share, req),
+                // IPC$ has no named pipes.
+                None => Err(Status::ObjectNameNotFound),
+            },
+            RequestContent::Close(req) => self.close(header, req),
+            RequestContent::Flush(req) => self.flush(header, req),
+            RequestContent::Read(req) => self.read(header, req),
+            RequestContent::Write(req) => self.write(header, req, &request.write_data),
+            RequestContent::QueryDirectory(req) => self.query_directory(header, req),
+            RequestContent::QueryInfo(req) => self.query_info(header, req),
+            RequestContent::SetInfo(req) => self.set_info(header, req),
+            RequestContent::ChangeNotify(req) => self.change_notify(&request.info, req),
+            _ => Err(Status::NotSupported),
+        };
+        result.unwrap_or_else(Outcome::error)
+    }
+
+    fn open_mut<'a>(
+        opens: &'a mut HashMap<u64, Open>,
+        header: &Header,
+        file_id: &FileId,
+    ) -> Result<&'a mut Open, Status> {
+        match opens.get_mut(&file_id.volatile) {
+            Some(open)
+                if open.session_id == header.session_id && Some(open.tree_id) == header.tree_id =>
+            {
+                Ok(open)
+            }
+            _ => Err(Status::FileClosed),
+        }
+    }
+
+    fn io_status(error: &std::io::Error) -> Status {
+        use std::io::ErrorKind;
+        match error.kind() {
+            ErrorKind::NotFound => Status::ObjectNameNotFound,
+            ErrorKind::PermissionDenied => Status::AccessDenied,
+            ErrorKind::AlreadyExists => Status::ObjectNameCollision,
+            ErrorKind::DirectoryNotEmpty => Status::DirectoryNotEmpty,
+            ErrorKind::IsADirectory => Status::FileIsADirectory,
+            ErrorKind::NotADirectory => Status::NotADirectory,
+            _ => {
+                log::warn!("Unexpected I/O error: {error}");
+                Status::AccessDenied
+            }
+        }
+    }
+
+    /// Resolves the generic & maximal access rights of a create request to specific rights.
+    fn granted_access(requested: FileAccessMask, read_only: bool) -> FileAccessMask {
+        let all = if read_only {
+            Self::READ_ACCESS
+        } else {
+            Self::FULL_ACCESS
+        };
+        if requested.generic_all() || requested.maximum_allowed() {
+            return FileAccessMask::from_bytes(all.to_le_bytes());
+        }
+        let mut access = requested;
+        if requested.generic_read() {
+            access.set_file_read_data(true);
+            access.set_file_read_attributes(true);
+        }
+        if requested.generic_write() {
+            access.set_file_write_data(true);
+            access.set_file_append_data(true);
+            access.set_file_write_attributes(true);
+        }
+        access
+    }
+
+    fn create(
+        &mut self,
+        header: &Header,
+        share: Share,
+        req: &CreateRequest,
+    ) -> Result<Outcome, Status> {
+        let path = share.resolve(&req.name.to_string())?;
+        let options = &req.create_options;
+        let access = Self::granted_access(req.desired_access, share.read_only);
+        let existing = std::fs::metadata(&path).ok();
+
+        if let Some(metadata) = &existing {
+            if metadata.is_dir() && options.non_directory_file() {
+                return Err(Status::FileIsADirectory);
+            }
+            if !metadata.is_dir() && options.directory_file() {
+                return Err(Status::NotADirectory);
+            }
+        }
+
+        let create_action = match (&existing, req.create_disposition) {
+            (Some(_), CreateDisposition::Create) => return Err(Status::ObjectNameCollision),
+            (Some(_), CreateDisposition::Open | CreateDisposition::OpenIf) => CreateAction::Opened,
+            (Some(metadata), _) if metadata.is_dir() => return Err(Status::FileIsADirectory),
+            (Some(_), CreateDisposition::Superseded) => CreateAction::Superseded,
+            (Some(_), _) => CreateAction::Overwritten,
+            (None, CreateDisposition::Open | CreateDisposition::Overwrite) => {
+                return Err(Status::ObjectNameNotFound);
+            }
+            (None, _) => CreateAction::Created,
+        };
+
+        let writes = access.file_write_data() || access.file_append_data();
+        if share.read_only
+            && (create_action != CreateAction::Opened || writes || options.delete_on_close())
+        {
+            return Err(Status::AccessDenied);
+        }
+        if options.delete_on_close() && path == share.root {
+            return Err(Status::AccessDenied);
+        }
+
+        let is_dir = match &existing {
+            Some(metadata) => metadata.is_dir(),
+            None => options.directory_file(),
+        };
+        match create_action {
+            CreateAction::Created if is_dir => {
+                std::fs::create_dir(&path).map_err(|e| Self::io_status(&e))?;
+                self.events
+                    .push(ChangeEvent::new(&path, true, ChangeKind::Added));
+            }
+            CreateAction::Created => {
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .map_err(|e| Self::io_status(&e))?;
+                self.events
+                    .push(ChangeEvent::new(&path, false, ChangeKind::Added));
+            }
+            CreateAction::Overwritten | CreateAction::Superseded => {
+                OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .open(&path)
+                    .map_err(|e| Self::io_status(&e))?;
+                self.events
+                    .push(ChangeEvent::new(&path, false, ChangeKind::Written));
+            }
+            CreateAction::Opened => {}
+        }
+
+        let file = if is_dir {
+            None
+        } else {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(!share.read_only && (writes || access.file_write_attributes()))
+                .open(&path)
+                .map_err(|e| Self::io_status(&e))?;
+            Some(file)
+        };
+        let info = FileInfo::read(&path).map_err(|e| Self::io_status(&e))?;
+
+        let id = self.next_id();
+        self.opens.insert(
+            id,
+            Open {
+                session_id: header.session_id,
+                tree_id: header.tree_id.unwrap_or_default(),
+                share,
+                path,
+                is_dir,
+                file,
+                access,
+                delete_on_close: options.delete_on_close(),
+                enumeration: None,
+                watch: None,
+            },
+        );
+
+        Ok(Outcome::success(CreateResponse {
+            oplock_level: OplockLevel::None,
+            flags: CreateResponseFlags::new(),
+            create_action,
+            creation_time: info.creation_time,
+            last_access_time: info.last_access_time,
+            last_write_time: info.last_write_time,
+            change_time: info.last_write_time,
+            allocation_size: info.allocation_size(),
+            endof_file: info.size,
+            file_attributes: info.file_attributes,
+            file_id: FileId {
+                persistent: id,
+                volatile: id,
+            },
+            create_contexts: vec![].into(),
+        }))
+    }
+
+    fn close(&mut self, header: &Header, req: &CloseRequest) -> Result<Outcome, Status> {
+        let open = Self::open_mut(&mut self.opens, header, &req.file_id)?;
+        let info = FileInfo::read(&open.path).map_err(|e| Self::io_status(&e))?;
+
+        // Pending change notifications of the handle are completed with a cleanup status.
+        let file_id = req.file_id.volatile;
+        let (cleaned_up, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|p| p.open_id == file_id);
+        self.pending = pending;
+        for pending in cleaned_up {
+            let outcome = Outcome::with_status(
+                Status::NotifyCleanup,
+                ChangeNotifyResponse {
+                    buffer: vec![].into(),
+                }
+                .into(),
+            );
+            match self.complete(&pending, outcome) {
+                Ok(response) => self.completions.push(response),
+                Err(e) => log::warn!("Failed to complete a change notification: {e}"),
+            }
+        }
+        self.close_open(file_id);
+
+        Ok(Outcome::success(CloseResponse {
+            flags: CloseFlags::new().with_postquery_attrib(true),
+            creation_time: info.creation_time,
+            last_access_time: info.last_access_time,
+            last_write_time: info.last_write_time,
+            change_time: info.last_write_time,
+            allocation_size: info.allocation_size(),
+            endof_file: info.size,
+            file_attributes: info.file_attributes,
+        }))
+    }
+
+    /// Removes an open, deleting the file if it was marked for deletion.
+    pub(super) fn close_open(&mut self, id: u64) {
+        self.pending.retain(|p| p.open_id != id);
+        let Some(mut open) = self.opens.remove(&id) else {
+            return;
+        };
+        drop(open.file.take());
+        if !open.delete_on_close {
+            return;
+        }
+        let result = if open.is_dir {
+            std::fs::remove_dir(&open.path)
+        } else {
+            std::fs::remove_file(&open.path)
+        };
+        match result {
+            Ok(()) => self.events.push(ChangeEvent::new(
+                open.path,
+                open.is_dir,
+                ChangeKind::Removed,
+            )),
+            Err(e) => log::warn!("Failed to delete {}: {e}", open.path.display()),
+        }
+    }
+
+    fn flush(&mut self, header: &Header, req: &FlushRequest) -> Result<Outcome, Status> {
+        let open = Self::open_mut(&mut self.opens, header, &req.file_id)?;
+        if let Some(file) = open.file.as_ref() {
+            file.sync_all().map_err(|e| Self::io_status(&e))?;
+        }
+        Ok(Outcome::success(FlushResponse {}))
+    }
+
+    fn read(&mut self, header: &Header, req: &ReadRequest) -> Result<Outcome, Status> {
+        let open = Self::open_mut(&mut self.opens, header, &req.file_id)?;
+        if !open.access.file_read_data() {
+            return Err(Status::AccessDenied);
+        }
+        let file = open.file.as_mut().ok_or(Status::InvalidDeviceRequest0)?;
+        let length = req.length.min(Self::MAX_TRANSACT_SIZE) as u64;
+
+        let mut buffer = Vec::new();
+        file.seek(SeekFrom::Start(req.offset))
+            .and_then(|_| file.take(length).read_to_end(&mut buffer))
+            .map_err(|e| Self::io_status(&e))?;
+        if buffer.is_empty() || buffer.len() < req.minimum_count as usize {
+            return Err(Status::EndOfFile);
+        }
+
+        let mut outcome = Outcome::success(ReadResponse { buffer });
+        outcome.compress = req.flags.read_compressed();
+        Ok(outcome)
+    }
+
+    fn write(
+        &mut self,
+        header: &Header,
+        req: &WriteRequest,
+        data: &[u8],
+    ) -> Result<Outcome, Status> {
+        let open = Self::open_mut(&mut self.opens, header, &req.file_id)?;
+        if !open.access.file_write_data() && !open.access.file_append_data() {
+            return Err(Status::AccessDenied);
+        }
+        let file = open.file.as_mut().ok_or(Status::InvalidDeviceRequest0)?;
+        file.seek(SeekFrom::Start(req.offset))
+            .and_then(|_| file.write_all(data))
+            .map_err(|e| Self::io_status(&e))?;
+
+        let event = ChangeEvent::new(&open.path, false, ChangeKind::Written);
+        self.events.push(event);
+        Ok(Outcome::success(WriteResponse {
+            count: data.len() as u32,
+        }))
+    }
+
+    fn query_directory(
+        &mut self,
+        header: &Header,
+        req: &QueryDirectoryRequest,
+    ) -> Result<Outcome, Status> {
+        let open = Self::open_mut(&mut self.opens, header, &req.file_id)?;
+        if !open.is_dir {
+            return Err(Status::InvalidParameter);
+        }
+
+        let restart = req.flags.restart_scans() || req.flags.reopen() || open.enumeration.is_none();
+        if restart {
+            let entries = list_directory(&open.path, &open.share.root, &req.file_name.to_string())
+                .map_err(|e| Self::io_status(&e))?;
+            open.enumeration = Some((entries, 0));
+        }
+        let (entries, position) = open.enumeration.as_mut().unwrap();
+        if *position >= entries.len() {
+            return Err(if restart {
+                Status::NoSuchFile
+            } else {
+                Status::NoMoreFiles
+            });
+        }
+
+        let remaining = &entries[*position..];
+        let remaining = if req.flags.return_single_entry() {
+            &remaining[..1]
+        } else {
+            remaining
+        };
+        let (output_buffer, count) = encode_directory(
+            req.file_information_class,
+            remaining,
+            req.output_buffer_length as usize,
+        )?;
+        if count == 0 {
+            return Err(Status::InfoLengthMismatch);
+        }
+        *position += count;
+        Ok(Outcome::success(QueryDirectoryResponse { output_buffer }))
+    }
+
+    fn query_info(&mut self, header: &Header, req: &QueryInfoRequest) -> Result<Outcome, Status> {
+        let start_time = self.shared.start_time;
+        let open = Self::open_mut(&mut self.opens, header, &req.file_id)?;
+        let info = FileInfo::read(&open.path).map_err(|e| Self::io_status(&e))?;
+
+        let mut data = Vec::new();
+        let mut cursor = std::io::Cursor::new(&mut data);
+        let written = match req.info_class {
+            QueryInfoClass::File(class) => Self::file_info(open, &info, class)?.write(&mut cursor),
+            QueryInfoClass::FileSystem(class) => {
+                Self::file_system_info(&open.share, start_time, class)?.write(&mut cursor)
+            }
+            QueryInfoClass::Empty(_) => return Err(Status::NotSupported),
+        };
+        written.map_err(|_| Status::InvalidParameter)?;
+
+        if data.len() > req.output_buffer_length as usize {
+            return Err(Status::InfoLengthMismatch);
+        }
+        Ok(Outcome::success(QueryInfoResponse { data: data.into() }))
+    }
+
+    fn file_info(
+        open: &Open,
+        info: &FileInfo,
+        class: QueryFileInfoClass,
+    ) -> Result<QueryFileInfo, Status> {
+        let name = format!("\\{}", open.share.relative_name(&open.path));
+        Ok(match class {
+            QueryFileInfoClass::BasicInformation => info.basic().into(),
+            QueryFileInfoClass::StandardInformation => info.standard(open.delete_on_close).into(),
+            QueryFileInfoClass::NetworkOpenInformation => info.network_open().into(),
+            QueryFileInfoClass::InternalInformation => FileInternalInformation {
+                index_number: info.file_id,
+            }
+            .into(),
+            QueryFileInfoClass::EaInformation => FileEaInformation { ea_size: 0 }.into(),
+            QueryFileInfoClass::AccessInformation => FileAccessInformation {
+                access_flags: open.access,
+            }
+            .into(),
+            QueryFileInfoClass::PositionInformation => FilePositionInformation {
+                current_byte_offset: 0,
+            }
+            .into(),
+            QueryFileInfoClass::ModeInformation => FileModeInformation::new().into(),
+            QueryFileInfoClass::AlignmentInformation => FileAlignmentInformation::Byte.into(),
+            QueryFileInfoClass::AttributeTagInformation => FileAttributeTagInformation {
+                file_attributes: info.file_attributes,
+                reparse_tag: ReparseTag::ReservedZero,
+            }
+            .into(),
+            QueryFileInfoClass::AllInformation => FileAllInformation {
+                basic: info.basic(),
+                standard: info.standard(open.delete_on_close),
+                internal: FileInternalInformation {
+                    index_number: info.file_id,
+                },
+                ea: FileEaInformation { ea_size: 0 },
+                access: FileAccessInformation {
+                    access_flags: open.access,
+                },
+                position: FilePositionInformation {
+                    current_byte_offset: 0,
+                },
+                mode: FileModeInformation::new(),
+                alignment: FileAlignmentInformation::Byte,
+                name: name.as_str().into(),
+            }
+            .into(),
+            _ => return Err(Status::NotSupported),
+        })
+    }
+
+    fn file_system_info(
+        share: &Share,
+        start_time: smb_dtyp::binrw_util::prelude::FileTime,
+        class: QueryFileSystemInfoClass,
+    ) -> Result<QueryFileSystemInfo, Status> {
+        Ok(match class {
+            QueryFileSystemInfoClass::FsAttributeInformation => FileFsAttributeInformation {
+                attributes: FileSystemAttributes::new()
+                    .with_case_preserved_names(true)
+                    .with_unicode_on_disk(true),
+                maximum_component_name_length: 255,
+                file_system_name: "NTFS".into(),
+            }
+            .into(),
+            QueryFileSystemInfoClass::FsDeviceInformation => FileFsDeviceInformation {
+                device_type: FsDeviceType::Disk,
+                characteristics: FsDeviceCharacteristics::new().with_read_only(share.read_only),
+            }
+            .into(),
+            QueryFileSystemInfoClass::FsVolumeInformation => FileFsVolumeInformation {
+                volume_creation_time: start_time,
+                volume_serial_number: 0,
+                supports_objects: false.into(),
+                volume_label: share.name.as_str().into(),
+            }
+            .into(),
+            _ => return Err(Status::NotSupported),
+        })
+    }
+
+    fn set_info(&mut self, header: &Header, req: &SetInfoRequest) -> Result<Outcome, Status> {
+        let (SetInfoClass::File(class), SetInfoData::File(data)) = (&req.info_class, &req.data)
+        else {
+            return Err(Status::NotSupported);
+        };
+        let info = data.parse(*class).map_err(|_| Status::InvalidParameter)?;
+        let open = Self::open_mut(&mut self.opens, header, &req.file_id)?;
+        if open.share.read_only {
+            return Err(Status::AccessDenied);
+        }
+        // MS-FSA 2.1.5.15: each information class requires its own access right on the open.
+        let allowed = match &info {
+            SetFileInfo::BasicInformation(_) => open.access.file_write_attributes(),
+            SetFileInfo::EndOfFileInformation(_) | SetFileInfo::AllocationInformation(_) => {
+                open.access.file_write_data()
+            }
+            SetFileInfo::DispositionInformation(_) | SetFileInfo::RenameInformation(_) => {
+                open.access.delete()
+            }
+            _ => true,
+        };
+        if !allowed {
+            return Err(Status::AccessDenied);
+        }
+
+        match info {
+            SetFileInfo::BasicInformation(basic) => {
+                Self::set_times(&open.path, &basic).map_err(|e| Self::io_status(&e))?;
+                let event =
+                    ChangeEvent::new(&open.path, open.is_dir, ChangeKind::AttributesChanged);
+                self.events.push(event);
+            }
+            SetFileInfo::EndOfFileInformation(end_of_file) => {
+                let file = open.file.as_ref().ok_or(Status::InvalidParameter)?;
+                file.set_len(end_of_file.end_of_file)
+                    .map_err(|e| Self::io_status(&e))?;
+                let event = ChangeEvent::new(&open.path, false, ChangeKind::Written);
+                self.events.push(event);
+            }
+            SetFileInfo::DispositionInformation(disposition) => {
+                let delete = bool::from(disposition.delete_pending);
+                if delete && open.path == open.share.root {
+                    return Err(Status::AccessDenied);
+                }
+                if delete && open.is_dir {
+                    let mut children =
+                        std::fs::read_dir(&open.path).map_err(|e| Self::io_status(&e))?;
+                    if children.next().is_some() {
+                        return Err(Status::DirectoryNotEmpty);
+                    }
+                }
+                open.delete_on_close = delete;
+            }
+            SetFileInfo::RenameInformation(rename) => {
+                let target = open.share.resolve(&rename.file_name.to_string())?;
+                if open.path == open.share.root {
+                    return Err(Status::AccessDenied);
+                }
+                if let Ok(metadata) = std::fs::symlink_metadata(&target) {
+                    // Renaming to a name differing only by case is allowed.
+                    let same_file = target.to_string_lossy().to_lowercase()
+                        == open.path.to_string_lossy().to_lowercase();
+                    if !same_file && (!bool::from(rename.replace_if_exists) || metadata.is_dir()) {
+                        return Err(Status::ObjectNameCollision);
+                    }
+                }
+                std::fs::rename(&open.path, &target).map_err(|e| Self::io_status(&e))?;
+                self.events
+                    .extend(ChangeEvent::rename(&open.path, &target, open.is_dir));
+                open.path = target;
+                open.enumeration = None;
+            }
+            SetFileInfo::AllocationInformation(_) | SetFileInfo::PositionInformation(_) => {}
+            _ => return Err(Status::NotSupported),
+        }
+        Ok(Outcome::success(SetInfoResponse {}))
+    }
+
+    /// Sets the times of a file. Zero and -1 values leave the time unchanged.
+    fn set_times(path: &Path, basic: &FileBasicInformation) -> std::io::Result<()> {
+        let is_set =
+            |time: &smb_dtyp::binrw_util::prelude::FileTime| !time.is_zero() && **time != u64::MAX;
+        let mut times = std::fs::FileTimes::new();
+        if is_set(&basic.last_access_time) {
+            times = times.set_accessed(basic.last_access_time.into());
+        }
+        if is_set(&basic.last_write_time) {
+            times = times.set_modified(basic.last_write_time.into());
+        }
+        std::fs::File::open(path)?.set_times(times)
+    }
+
+    fn change_notify(
+        &mut self,
+        info: &RequestInfo,
+        req: &ChangeNotifyRequest,
+    ) -> Result<Outcome, Status> {
+        let open_id = req.file_id.volatile;
+        let open = Self::open_mut(&mut self.opens, &info.header, &req.file_id)?;
+        if !open.is_dir {
+            return Err(Status::InvalidParameter);
+        }
+        let watch = open.watch.get_or_insert_with(|| {
+            Watch::new(
+                open.path.clone(),
+                req.flags.watch_tree(),
+                req.completion_filter,
+            )
+        });
+        watch.watch_tree = req.flags.watch_tree();
+        watch.filter = req.completion_filter;
+
+        // Changes buffered since the last request are returned immediately.
+        if watch.has_changes() {
+            return Ok(self.notify_outcome(open_id, req.output_buffer_length));
+        }
+
+        let async_id = self.next_id();
+        self.pending.push(PendingNotify {
+            async_id,
+            open_id,
+            info: info.clone(),
+            output_buffer_length: req.output_buffer_length,
+        });
+        let mut outcome =
+            Outcome::with_status(Status::Pending, ErrorResponse { error_data: vec![] }.into());
+        outcome.async_id = Some(async_id);
+        Ok(outcome)
+    }
+
+    /// Builds the response to a change notify request, from the buffered changes of the open.
+    ///
+    /// If changes were dropped, or do not fit in the client's buffer, the client is asked
+    /// to enumerate the directory instead.
+    pub(super) fn notify_outcome(&mut self, open_id: u64, output_buffer_length: u32) -> Outcome {
+        let changes = self
+            .opens
+            .get_mut(&open_id)
+            .and_then(|open| open.watch.as_mut())
+            .and_then(Watch::take);
+        let response = changes.map(|changes| ChangeNotifyResponse {
+            buffer: changes.into(),
+        });
+        let fits = response.as_ref().is_some_and(|response| {
+            let mut buffer = Vec::new();
+            response
+                .buffer
+                .write_le(&mut std::io::Cursor::new(&mut buffer))
+                .is_ok()
+                && buffer.len() <= output_buffer_length as usize
+        });
+        match response {
+            Some(response) if fits => Outcome::success(response),
+            _ => Outcome::with_status(
+                Status::NotifyEnumDir,
+                ChangeNotifyResponse {
+                    buffer: vec![].into(),
+                }
+                .into(),
+            ),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use smb_transport::TransportError;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Unexpected Message, {0}")]
+    InvalidMessage(String),
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Binrw Error: {0}")]
+    BinRWError(#[from] binrw::Error),
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfiguration(String),
+
+    /// Errors from the shared client-side building blocks: signing, encryption and compression.
+    #[error("SMB error: {0}")]
+    SmbError(#[from] smb::Error),
+    #[error("Crypto error: {0}")]
+    CryptoError(#[from] smb::crypto::CryptoError),
+    #[error("Compression error: {0}")]
+    CompressionError(#[from] smb::compression::CompressionError),
+    #[error("SMB message error: {0}")]
+    SmbMessageError(#[from] smb_msg::SmbMsgError),
+
+    /// Indicates an error sourced from the NTLM implementation of the [`sspi`] crate.
+    #[error("Sspi error: {0}")]
+    SspiError(#[from] sspi::Error),
+
+    #[error("Transport error: {0}")]
+    TransportError(#[from] TransportError),
+    #[error("Task join error.")]
+    JoinError(#[from] tokio::task::JoinError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,458 @@
+//! Mapping of SMB paths to local files, and conversions of local metadata to FSCC information.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use binrw::prelude::*;
+use smb_dtyp::binrw_util::prelude::*;
+use smb_fscc::*;
+use smb_msg::Status;
+
+/// A disk share, with its root resolved to a canonical local path.
+#[derive(Debug, Clone)]
+pub(crate) struct Share {
+    pub name: String,
+    pub root: PathBuf,
+    pub read_only: bool,
+}
+
+impl Share {
+    /// Resolves a path, relative to the share root, to a local path.
+    ///
+    /// Path components are looked up case-insensitively, to match the expectations of SMB clients
+    /// on case-sensitive local file systems. The last component does not have to exist.
+    /// Paths leaving the share, including through symbolic links, are rejected.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf, Status> {
+        let components = name
+            .split('\\')
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<_>>();
+        let mut path = self.root.clone();
+        for (i, component) in components.iter().enumerate() {
+            if Self::is_invalid_component(component) {
+                return Err(Status::ObjectNameInvalid);
+            }
+            let is_last = i == components.len() - 1;
+            match Self::lookup(&path, component) {
+                Some(existing) => {
+                    if !is_last && !existing.is_dir() {
+                        return Err(Status::ObjectPathNotFound);
+                    }
+                    path = existing;
+                }
+                None if is_last => path = path.join(component),
+                None => return Err(Status::ObjectPathNotFound),
+            }
+        }
+
+        let existing = match path.symlink_metadata() {
+            // A dangling link can't be canonicalized, and creating it would create its target,
+            // wherever it is.
+            Ok(metadata) if metadata.is_symlink() && !path.exists() => {
+                return Err(Status::AccessDenied);
+            }
+            Ok(_) => Some(path.as_path()),
+            Err(_) => path.parent(),
+        };
+        match existing.map(Path::canonicalize) {
+            Some(Ok(canonical)) if canonical.starts_with(&self.root) => Ok(path),
+            Some(Ok(_)) => Err(Status::AccessDenied),
+            _ => Err(Status::ObjectPathNotFound),
+        }
+    }
+
+    /// Returns the path of a local file relative to the share root, using backslashes.
+    pub fn relative_name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .map(|p| {
+                p.components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("\\")
+            })
+            .unwrap_or_default()
+    }
+
+    fn is_invalid_component(component: &str) -> bool {
+        component == "."
+            || component == ".."
+            || component
+                .chars()
+                .any(|c| matches!(c, '/' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '\0'))
+    }
+
+    /// Finds an entry of a directory, by its case-insensitive name.
+    fn lookup(dir: &Path, name: &str) -> Option<PathBuf> {
+        let exact = dir.join(name);
+        if exact.symlink_metadata().is_ok() {
+            return Some(exact);
+        }
+        let name = name.to_lowercase();
+        std::fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_string_lossy().to_lowercase() == name)
+            .map(|entry| entry.path())
+    }
+}
+
+/// Information about a local file or directory, in SMB terms.
+#[derive(Debug, Clone)]
+pub(crate) struct FileInfo {
+    pub is_dir: bool,
+    pub size: u64,
+    pub creation_time: FileTime,
+    pub last_access_time: FileTime,
+    pub last_write_time: FileTime,
+    pub file_attributes: FileAttributes,
+    pub file_id: u64,
+}
+
+impl FileInfo {
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        let last_write_time = metadata.modified().map(FileTime::from).unwrap_or_default();
+        let is_dir = metadata.is_dir();
+        let file_attributes = FileAttributes::new()
+            .with_directory(is_dir)
+            .with_archive(!is_dir)
+            .with_readonly(metadata.permissions().readonly());
+        #[cfg(unix)]
+        let file_id = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let file_id = 0;
+        Self {
+            is_dir,
+            size: if is_dir { 0 } else { metadata.len() },
+            creation_time: metadata
+                .created()
+                .map(FileTime::from)
+                .unwrap_or(last_write_time),
+            last_access_time: metadata
+                .accessed()
+                .map(FileTime::from)
+                .unwrap_or(last_write_time),
+            last_write_time,
+            file_attributes,
+            file_id,
+        }
+    }
+
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        std::fs::metadata(path).map(|metadata| Self::from_metadata(&metadata))
+    }
+
+    /// The allocation size of the file, rounded up to a 4KiB cluster.
+    pub fn allocation_size(&self) -> u64 {
+        self.size.div_ceil(Self::CLUSTER_SIZE) * Self::CLUSTER_SIZE
+    }
+
+    const CLUSTER_SIZE: u64 = 4096;
+
+    pub fn basic(&self) -> FileBasicInformation {
+        FileBasicInformation {
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+            change_time: self.last_write_time,
+            file_attributes: self.file_attributes,
+        }
+    }
+
+    pub fn standard(&self, delete_pending: bool) -> FileStandardInformation {
+        FileStandardInformation {
+            allocation_size: self.allocation_size(),
+            end_of_file: self.size,
+            number_of_links: 1,
+            delete_pending: delete_pending.into(),
+            directory: self.is_dir.into(),
+        }
+    }
+
+    pub fn network_open(&self) -> FileNetworkOpenInformation {
+        FileNetworkOpenInformation {
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+            change_time: self.last_write_time,
+            allocation_size: self.allocation_size(),
+            end_of_file: self.size,
+            file_attributes: self.file_attributes,
+        }
+    }
+}
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone)]
+pub(crate) struct DirEntry {
+    pub name: String,
+    pub info: FileInfo,
+}
+
+/// Lists a directory, including the `.` and `..` entries, keeping entries matching the pattern.
+///
+/// Entries are sorted by their case-insensitive name.
+pub(crate) fn list_directory(
+    path: &Path,
+    root: &Path,
+    pattern: &str,
+) -> std::io::Result<Vec<DirEntry>> {
+    let info = FileInfo::read(path)?;
+    let parent_info = match path.parent().filter(|_| path != root) {
+        Some(parent) => FileInfo::read(parent)?,
+        None => info.clone(),
+    };
+    let mut entries = vec![
+        DirEntry {
+            name: ".".to_string(),
+            info,
+        },
+        DirEntry {
+            name: "..".to_string(),
+            info: parent_info,
+        },
+    ];
+
+    let mut children = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        // Entries that disappear while listing, or broken links, are skipped.
+        let Ok(metadata) = std::fs::metadata(entry.path()) else {
+            continue;
+        };
+        children.push(DirEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            info: FileInfo::from_metadata(&metadata),
+        });
+    }
+    children.sort_by_key(|entry| entry.name.to_lowercase());
+    entries.extend(children);
+
+    entries.retain(|entry| matches_pattern(&entry.name, pattern));
+    Ok(entries)
+}
+
+/// Matches a file name against a search pattern, case-insensitively.
+///
+/// Supports the `*` and `?` wildcards, and treats the DOS wildcards (`<`, `>` and `"`)
+/// as `*`, `?` and `.` respectively. An empty pattern matches everything.
+pub(crate) fn matches_pattern(name: &str, pattern: &str) -> bool {
+    if pattern.is_empty() || pattern == "*" {
+        return true;
+    }
+    let name = name.to_lowercase().chars().collect::<Vec<_>>();
+    let pattern = pattern
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            '<' => '*',
+            '>' => '?',
+            '"' => '.',
+            c => c,
+        })
+        .collect::<Vec<_>>();
+
+    // Iterative wildcard matching, backtracking to the last `*`.
+    let (mut n, mut p) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            n += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Serializes directory entries in the format of the information class,
+/// fitting as many entries as possible in `max_length` bytes.
+///
+/// Returns the serialized buffer, and the number of entries written.
+pub(crate) fn encode_directory(
+    class: QueryDirectoryInfoClass,
+    entries: &[DirEntry],
+    max_length: usize,
+) -> Result<(Vec<u8>, usize), Status> {
+    use QueryDirectoryInfoClass::*;
+    let result = match class {
+        DirectoryInformation => encode_chained(
+            entries.iter().map(|e| FileDirectoryInformation {
+                file_index: 0,
+                creation_time: e.info.creation_time,
+                last_access_time: e.info.last_access_time,
+                last_write_time: e.info.last_write_time,
+                change_time: e.info.last_write_time,
+                end_of_file: e.info.size,
+                allocation_size: e.info.allocation_size(),
+                file_attributes: e.info.file_attributes,
+                file_name: e.name.as_str().into(),
+            }),
+            max_length,
+        ),
+        FullDirectoryInformation => encode_chained(
+            entries.iter().map(|e| FileFullDirectoryInformation {
+                file_index: 0,
+                creation_time: e.info.creation_time,
+                last_access_time: e.info.last_access_time,
+                last_write_time: e.info.last_write_time,
+                change_time: e.info.last_write_time,
+                end_of_file: e.info.size,
+                allocation_size: e.info.allocation_size(),
+                file_attributes: e.info.file_attributes,
+                ea_size: Some(0),
+                reparse_tag: None,
+                file_name: e.name.as_str().into(),
+            }),
+            max_length,
+        ),
+        BothDirectoryInformation => encode_chained(
+            entries.iter().map(|e| FileBothDirectoryInformation {
+                file_index: 0,
+                creation_time: e.info.creation_time,
+                last_access_time: e.info.last_access_time,
+                last_write_time: e.info.last_write_time,
+                change_time: e.info.last_write_time,
+                end_of_file: e.info.size,
+                allocation_size: e.info.allocation_size(),
+                file_attributes: e.info.file_attributes,
+                ea_size: Some(0),
+                reparse_tag: None,
+                short_name_length: 0,
+                short_name: Default::default(),
+                file_name: e.name.as_str().into(),
+            }),
+            max_length,
+        ),
+        IdBothDirectoryInformation => encode_chained(
+            entries.iter().map(|e| FileIdBothDirectoryInformation {
+                file_index: 0,
+                creation_time: e.info.creation_time,
+                last_access_time: e.info.last_access_time,
+                last_write_time: e.info.last_write_time,
+                change_time: e.info.last_write_time,
+                end_of_file: e.info.size,
+                allocation_size: e.info.allocation_size(),
+                file_attributes: e.info.file_attributes,
+                ea_size: Some(0),
+                reparse_tag: None,
+                short_name_length: 0,
+                short_name: Default::default(),
+                file_id: e.info.file_id,
+                file_name: e.name.as_str().into(),
+            }),
+            max_length,
+        ),
+        IdFullDirectoryInformation => encode_chained(
+            entries.iter().map(|e| FileIdFullDirectoryInformation {
+                file_index: 0,
+                creation_time: e.info.creation_time,
+                last_access_time: e.info.last_access_time,
+                last_write_time: e.info.last_write_time,
+                change_time: e.info.last_write_time,
+                end_of_file: e.info.size,
+                allocation_size: e.info.allocation_size(),
+                file_attributes: e.info.file_attributes,
+                ea_size: Some(0),
+                reparse_tag: None,
+                file_id: e.info.file_id,
+                file_name: e.name.as_str().into(),
+            }),
+            max_length,
+        ),
+        NamesInformation => encode_chained(
+            entries.iter().map(|e| FileNamesInformation {
+                file_index: 0,
+                file_name: e.name.as_str().into(),
+            }),
+            max_length,
+        ),
+        _ => return Err(Status::InvalidInfoClass),
+    };
+    result.map_err(|_| Status::InvalidParameter)
+}
+
+/// Serializes items as a [`ChainedItemList`], stopping before the list exceeds `max_length` bytes.
+fn encode_chained<T>(
+    items: impl Iterator<Item = T>,
+    max_length: usize,
+) -> BinResult<(Vec<u8>, usize)>
+where
+    T: BinWrite,
+    for<'a> <T as BinWrite>::Args<'a>: Default,
+{
+    const ALIGNMENT: usize = QueryDirectoryInfo::CHAINED_ALIGNMENT as usize;
+    // Each item is preceded by its 4-byte next entry offset.
+    const NEXT_ENTRY_OFFSET_SIZE: usize = 4;
+
+    let mut fitting = vec![];
+    let mut next_offset = 0;
+    for item in items {
+        let mut item_data = Cursor::new(vec![]);
+        item.write_le(&mut item_data)?;
+        let end = next_offset + NEXT_ENTRY_OFFSET_SIZE + item_data.get_ref().len();
+        if end > max_length {
+            break;
+        }
+        next_offset = end.next_multiple_of(ALIGNMENT);
+        fitting.push(item);
+    }
+
+    let count = fitting.len();
+    let mut buffer = Cursor::new(vec![]);
+    if count > 0 {
+        ChainedItemList::<T, { QueryDirectoryInfo::CHAINED_ALIGNMENT }>::from(fitting)
+            .write_le(&mut buffer)?;
+    }
+    Ok((buffer.into_inner(), count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("File.TXT", "*.txt"));
+        assert!(matches_pattern("file.txt", "f?le.*"));
+        assert!(matches_pattern("file.txt", "*"));
+        assert!(matches_pattern("abcabd", "*ab?"));
+        assert!(!matches_pattern("file.txt", "*.bin"));
+        assert!(!matches_pattern("file.txt", "file"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_links_out_of_share() {
+        let base = std::env::temp_dir().join(format!("smb-server-resolve-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let outside = base.join("outside");
+        std::fs::create_dir_all(base.join("share").join("Sub")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        let share = Share {
+            name: "share".to_string(),
+            root: base.join("share").canonicalize().unwrap(),
+            read_only: false,
+        };
+        std::os::unix::fs::symlink(&outside, share.root.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("missing"), share.root.join("dangling")).unwrap();
+
+        assert_eq!(
+            share.resolve("sub\\new.txt"),
+            Ok(share.root.join("Sub").join("new.txt"))
+        );
+        assert_eq!(share.resolve("link\\new.txt"), Err(Status::AccessDenied));
+        // Creating the dangling link would create a file outside of the share.
+        assert_eq!(share.resolve("dangling"), Err(Status::AccessDenied));
+        assert!(!outside.join("missing").exists());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,34 @@
+//! ## SMB2/3 Server
+//!
+//! A small server, serving local directories as SMB disk shares over TCP or NetBIOS.
+//!
+//! Clients authenticate with NTLM against a [`UserDatabase`], and may sign and encrypt
+//! their messages using the algorithms of the [`smb`] client crate.
+//!
+//! ```no_run
+//! use smb_server::{Server, ServerConfig, ShareConfig};
+//! # async fn run() -> smb_server::Result<()> {
+//! let mut config = ServerConfig::default();
+//! config.users.add_user("user", "password");
+//! config.shares.push(ShareConfig::new("data", "/srv/data"));
+//!
+//! let server = Server::new(config)?;
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:445").await?;
+//! server.serve(listener).await
+//! # }
+//! ```
+
+#![forbid(unsafe_code)]
+
+pub mod config;
+pub mod error;
+
+mod connection;
+mod fs;
+mod notify;
+mod server;
+mod session;
+
+pub use config::*;
+pub use error::*;
+pub use server::Server;
@@ -0,0 +1,158 @@
+//! Change notifications: events of changes made through the server, and directory watches.
+//!
+//! Only changes made by clients of the same [`Server`][crate::Server] are reported;
+//! changes made to the shared directories by local processes are not detected.
+
+use std::path::{Path, PathBuf};
+
+use smb_fscc::{FileNotifyInformation, NotifyAction};
+use smb_msg::NotifyFilter;
+
+/// A change made to a file or a directory through the server.
+#[derive(Debug, Clone)]
+pub(crate) struct ChangeEvent {
+    /// The local path of the changed file.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub kind: ChangeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChangeKind {
+    Added,
+    Removed,
+    /// The data or the size of a file changed.
+    Written,
+    /// The attributes or the times of a file changed.
+    AttributesChanged,
+    RenamedOldName,
+    RenamedNewName,
+}
+
+impl ChangeEvent {
+    pub fn new(path: impl Into<PathBuf>, is_dir: bool, kind: ChangeKind) -> Self {
+        Self {
+            path: path.into(),
+            is_dir,
+            kind,
+        }
+    }
+
+    /// Returns the events describing a rename from `from` to `to`.
+    pub fn rename(from: &Path, to: &Path, is_dir: bool) -> Vec<Self> {
+        if from.parent() == to.parent() {
+            vec![
+                Self::new(from, is_dir, ChangeKind::RenamedOldName),
+                Self::new(to, is_dir, ChangeKind::RenamedNewName),
+            ]
+        } else {
+            vec![
+                Self::new(from, is_dir, ChangeKind::Removed),
+                Self::new(to, is_dir, ChangeKind::Added),
+            ]
+        }
+    }
+
+    fn matches(&self, filter: &NotifyFilter) -> bool {
+        match self.kind {
+            ChangeKind::Added
+            | ChangeKind::Removed
+            | ChangeKind::RenamedOldName
+            | ChangeKind::RenamedNewName => {
+                if self.is_dir {
+                    filter.dir_name()
+                } else {
+                    filter.file_name()
+                }
+            }
+            ChangeKind::Written => filter.size() || filter.last_write(),
+            ChangeKind::AttributesChanged => {
+                filter.attributes() || filter.last_write() || filter.creation()
+            }
+        }
+    }
+
+    fn action(&self) -> NotifyAction {
+        match self.kind {
+            ChangeKind::Added => NotifyAction::Added,
+            ChangeKind::Removed => NotifyAction::Removed,
+            ChangeKind::Written | ChangeKind::AttributesChanged => NotifyAction::Modified,
+            ChangeKind::RenamedOldName => NotifyAction::RenamedOldName,
+            ChangeKind::RenamedNewName => NotifyAction::RenamedNewName,
+        }
+    }
+}
+
+/// A directory watched by a client, through an open handle.
+///
+/// Changes are buffered between change notify requests, so none are missed
+/// while the client processes a previous notification.
+#[derive(Debug)]
+pub(crate) struct Watch {
+    pub dir: PathBuf,
+    pub watch_tree: bool,
+    pub filter: NotifyFilter,
+    buffered: Vec<FileNotifyInformation>,
+    /// Whether changes were dropped, since the buffer was full.
+    overflowed: bool,
+}
+
+impl Watch {
+    /// The maximal number of changes buffered between requests.
+    const MAX_BUFFERED: usize = 256;
+
+    pub fn new(dir: PathBuf, watch_tree: bool, filter: NotifyFilter) -> Self {
+        Self {
+            dir,
+            watch_tree,
+            filter,
+            buffered: vec![],
+            overflowed: false,
+        }
+    }
+
+    /// Buffers the event if it is relevant to the watch. Returns whether it was.
+    pub fn push(&mut self, event: &ChangeEvent) -> bool {
+        let Ok(relative) = event.path.strip_prefix(&self.dir) else {
+            return false;
+        };
+        let depth = relative.components().count();
+        if depth == 0 || (depth > 1 && !self.watch_tree) || !event.matches(&self.filter) {
+            return false;
+        }
+
+        if self.buffered.len() >= Self::MAX_BUFFERED {
+            self.overflowed = true;
+            return true;
+        }
+        let file_name = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\\");
+        self.buffered.push(FileNotifyInformation {
+            action: event.action(),
+            file_name: file_name.into(),
+        });
+        true
+    }
+
+    /// Marks the watch as overflowed: the client must enumerate the directory again.
+    pub fn overflow(&mut self) {
+        self.overflowed = true;
+    }
+
+    pub fn has_changes(&self) -> bool {
+        !self.buffered.is_empty() || self.overflowed
+    }
+
+    /// Takes the buffered changes. Returns `None` if changes were dropped.
+    pub fn take(&mut self) -> Option<Vec<FileNotifyInformation>> {
+        let changes = std::mem::take(&mut self.buffered);
+        if std::mem::take(&mut self.overflowed) {
+            None
+        } else {
+            Some(changes)
+        }
+    }
+}
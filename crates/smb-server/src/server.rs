@@ -0,0 +1,348 @@
+//! The server: accepting connections, and the state shared between them.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use smb_dtyp::Guid;
+use smb_dtyp::binrw_util::prelude::FileTime;
+use smb_transport::{NetBiosTransport, SmbTransport, TcpTransport};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+use crate::fs::Share;
+use crate::notify::ChangeEvent;
+use crate::{Result, ServerConfig};
+
+/// State shared by all the connections of a server.
+pub(crate) struct ServerShared {
+    pub config: ServerConfig,
+    pub server_guid: Guid,
+    /// The configured shares, with their roots canonicalized.
+    pub shares: Vec<Share>,
+    pub start_time: FileTime,
+    /// Changes made through any connection, for change notifications.
+    pub changes: broadcast::Sender<ChangeEvent>,
+}
+
+impl ServerShared {
+    /// The number of change events buffered for each connection.
+    const CHANGES_CAPACITY: usize = 1024;
+
+    pub fn share(&self, name: &str) -> Option<&Share> {
+        self.shares
+            .iter()
+            .find(|share| share.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// An SMB2/3 server, serving local directories as disk shares.
+///
+/// The server is cheap to clone; clones share the same configuration and state,
+/// so the same server may listen on multiple sockets.
+#[derive(Clone)]
+pub struct Server {
+    shared: Arc<ServerShared>,
+}
+
+impl Server {
+    /// Creates a new server, validating the configuration.
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        config.validate()?;
+        let shares = config
+            .shares
+            .iter()
+            .map(|share| {
+                Ok(Share {
+                    name: share.name.clone(),
+                    root: share.path.canonicalize()?,
+                    read_only: share.read_only,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (changes, _) = broadcast::channel(ServerShared::CHANGES_CAPACITY);
+        Ok(Self {
+            shared: Arc::new(ServerShared {
+                config,
+                server_guid: Guid::generate(),
+                shares,
+                start_time: FileTime::from(SystemTime::now()),
+                changes,
+            }),
+        })
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.shared.config
+    }
+
+    /// Accepts SMB over TCP connections (usually, port 445), serving each in a separate task.
+    ///
+    /// Returns only if accepting fails.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, address) = listener.accept().await?;
+            log::debug!("Accepted connection from {address}");
+            let transport = TcpTransport::from_stream(stream, Duration::ZERO);
+            self.spawn_connection(Box::new(transport));
+        }
+    }
+
+    /// Accepts NetBIOS over TCP connections (usually, port 139), serving each in a separate task.
+    ///
+    /// Returns only if accepting fails.
+    pub async fn serve_netbios(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, address) = listener.accept().await?;
+            log::debug!("Accepted NetBIOS connection from {address}");
+            let server = self.clone();
+            tokio::spawn(async move {
+                let tcp = TcpTransport::from_stream(stream, Duration::ZERO);
+                match NetBiosTransport::accept(tcp).await {
+                    Ok(transport) => {
+                        if let Err(e) = server.serve_connection(Box::new(transport)).await {
+                            log::warn!("Connection from {address} failed: {e}");
+                        }
+                    }
+                    Err(e) => log::warn!("NetBIOS session from {address} failed: {e}"),
+                }
+            });
+        }
+    }
+
+    /// Serves a single connection over an established transport, until the client disconnects.
+    pub async fn serve_connection(&self, transport: Box<dyn SmbTransport>) -> Result<()> {
+        crate::connection::serve(self.shared.clone(), transport).await
+    }
+
+    fn spawn_connection(&self, transport: Box<dyn SmbTransport>) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.serve_connection(transport).await {
+                log::warn!("Connection failed: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShareConfig;
+    use futures_util::StreamExt;
+    use smb::{ConnectionConfig, Directory, Session, Tree, UncPath, connection::Connection};
+    use smb_fscc::{
+        FileAccessMask, FileBasicInformation, FileDirectoryInformation, FileDispositionInformation,
+        FileEndOfFileInformation, FileRenameInformation,
+    };
+    use smb_msg::{CreateDisposition, NotifyFilter, Status};
+    use smb_transport::MemoryTransport;
+
+    const USER: &str = "user";
+    const PASSWORD: &str = "password";
+
+    fn make_server(name: &str, encrypt_data: bool) -> (Server, std::path::PathBuf) {
+        let root = std::env::temp_dir().join(format!("smb-server-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+
+        let mut config = ServerConfig {
+            encrypt_data,
+            ..Default::default()
+        };
+        config.users.add_user(USER, PASSWORD);
+        config.shares.push(ShareConfig::new("data", &root));
+        (Server::new(config).unwrap(), root)
+    }
+
+    async fn connect(server: &Server) -> Connection {
+        let (client, transport) = MemoryTransport::pair();
+        let server = server.clone();
+        tokio::spawn(async move { server.serve_connection(Box::new(transport)).await });
+        Connection::from_transport(
+            Box::new(client),
+            "server",
+            Guid::generate(),
+            ConnectionConfig::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn connect_tree(server: &Server) -> (Connection, Session, Tree) {
+        let connection = connect(server).await;
+        let identity = sspi::AuthIdentity {
+            username: sspi::Username::new(USER, None).unwrap(),
+            password: PASSWORD.to_string().into(),
+        };
+        let session = connection.authenticate(identity).await.unwrap();
+        let share = UncPath::new("server").unwrap().with_share("data").unwrap();
+        let tree = session.tree_connect(&share).await.unwrap();
+        (connection, session, tree)
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password() {
+        let (server, root) = make_server("wrong-password", false);
+        let connection = connect(&server).await;
+        let identity = sspi::AuthIdentity {
+            username: sspi::Username::new(USER, None).unwrap(),
+            password: "wrong".to_string().into(),
+        };
+        let result = connection.authenticate(identity).await.err();
+        assert!(
+            matches!(
+                result,
+                Some(
+                    smb::Error::UnexpectedMessageStatus(status)
+                        | smb::Error::ReceivedErrorMessage(status, _)
+                )
+                    if status == Status::LogonFailure as u32
+            ),
+            "{result:?}"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    async fn roundtrip(encrypt_data: bool) {
+        let name = if encrypt_data { "encrypted" } else { "plain" };
+        let (server, root) = make_server(name, encrypt_data);
+        let (_connection, _session, tree) = connect_tree(&server).await;
+
+        // Watch the share root, while writing a file.
+        let root_dir = tree
+            .open_existing("", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_dir();
+        let root_dir = Arc::new(root_dir);
+        let watcher = {
+            let root_dir = root_dir.clone();
+            tokio::spawn(async move {
+                root_dir
+                    .watch_timeout(
+                        NotifyFilter::new().with_file_name(true),
+                        false,
+                        Duration::from_secs(10),
+                    )
+                    .await
+            })
+        };
+        // Changes made before the change notify request is received are not reported.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let data = (0..10000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let access = FileAccessMask::new()
+            .with_generic_read(true)
+            .with_generic_write(true);
+        let file = tree
+            .create_file("File.bin", CreateDisposition::Create, access)
+            .await
+            .unwrap()
+            .unwrap_file();
+        assert_eq!(file.write_block(&data, 0, None).await.unwrap(), data.len());
+        file.close().await.unwrap();
+        assert_eq!(std::fs::read(root.join("File.bin")).unwrap(), data);
+
+        let changes = watcher.await.unwrap().unwrap();
+        assert!(changes.iter().any(|change| change.file_name == "File.bin"));
+
+        // Names are case-insensitive.
+        let file = tree
+            .open_existing("file.BIN", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        let mut read = vec![0; data.len()];
+        let read_len = file.read_block(&mut read, 0, None, false).await.unwrap();
+        assert_eq!(read_len, data.len());
+        assert_eq!(read, data);
+        file.close().await.unwrap();
+
+        let names = Directory::query::<FileDirectoryInformation>(&root_dir, "*")
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name.to_string())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(names, [".", "..", "File.bin", "sub"]);
+
+        // Paths may not leave the share.
+        assert!(
+            tree.open_existing("..\\x", FileAccessMask::new().with_generic_read(true))
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_roundtrip() {
+        roundtrip(false).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_encrypted_roundtrip() {
+        roundtrip(true).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_set_info_access() {
+        let (server, root) = make_server("set-info", false);
+        let (_connection, _session, tree) = connect_tree(&server).await;
+        std::fs::write(root.join("file.bin"), b"data").unwrap();
+        let is_denied = |result: smb::Result<()>| {
+            matches!(
+                result,
+                Err(smb::Error::ReceivedErrorMessage(
+                    Status::U32_ACCESS_DENIED,
+                    _
+                ))
+            )
+        };
+        let rename = || FileRenameInformation {
+            replace_if_exists: false.into(),
+            root_directory: 0,
+            file_name: "renamed.bin".into(),
+        };
+
+        // Reading does not allow changing attributes or the size.
+        let reader = tree
+            .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        let basic = reader.query_info::<FileBasicInformation>().await.unwrap();
+        assert!(is_denied(reader.set_info(basic).await));
+        let end_of_file = FileEndOfFileInformation { end_of_file: 0 };
+        assert!(is_denied(reader.set_info(end_of_file).await));
+        reader.close().await.unwrap();
+
+        // Writing does not allow renaming or deleting.
+        let writer = tree
+            .open_existing("file.bin", FileAccessMask::new().with_generic_write(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        writer
+            .set_info(FileEndOfFileInformation { end_of_file: 2 })
+            .await
+            .unwrap();
+        assert!(is_denied(writer.set_info(rename()).await));
+        let disposition = FileDispositionInformation::default();
+        assert!(is_denied(writer.set_info(disposition).await));
+        writer.close().await.unwrap();
+        assert_eq!(std::fs::read(root.join("file.bin")).unwrap(), b"da");
+
+        let deleter = tree
+            .open_existing("file.bin", FileAccessMask::new().with_delete(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        deleter.set_info(rename()).await.unwrap();
+        deleter.close().await.unwrap();
+        assert!(root.join("renamed.bin").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
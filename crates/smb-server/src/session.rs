@@ -0,0 +1,351 @@
+//! Server-side session state: NTLM authentication & key derivation.
+
+use hmac::{Hmac, KeyInit, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use sspi::{
+    AuthIdentity, BufferType, CredentialUse, DataRepresentation, Ntlm, SecurityBuffer,
+    SecurityStatus, ServerRequestFlags, Sspi, SspiEx, SspiImpl,
+};
+
+use smb::connection::preauth_hash::PreauthHashState;
+use smb::crypto::{
+    EncryptingAlgo, KeyToDerive, kbkdf_hmacsha256, make_encrypting_algo, make_signing_algo,
+};
+use smb::session::{MessageEncryptor, MessageSigner};
+use smb_msg::SigningAlgorithmId;
+use smb_transport::IoVec;
+
+use crate::UserDatabase;
+use crate::connection::Negotiated;
+use crate::{Error, Result};
+
+// Key derivation contexts, for dialects without preauth integrity (MS-SMB2 3.3.5.5.3).
+const NO_PREAUTH_HASH_DERIVE_SIGN_CTX: &[u8] = b"SmbSign\x00";
+const NO_PREAUTH_HASH_DERIVE_ENCRYPT_S2C_CTX: &[u8] = b"ServerOut\x00";
+const NO_PREAUTH_HASH_DERIVE_ENCRYPT_C2S_CTX: &[u8] = b"ServerIn \x00";
+
+/// The offset of the server challenge in an NTLM CHALLENGE message (MS-NLMP 2.2.1.2).
+const CHALLENGE_MESSAGE_SERVER_CHALLENGE_OFFSET: usize = 24;
+/// The offsets of the fields of an NTLM AUTHENTICATE message (MS-NLMP 2.2.1.3).
+const AUTHENTICATE_MESSAGE_NT_RESPONSE_FIELD: usize = 20;
+const AUTHENTICATE_MESSAGE_DOMAIN_NAME_FIELD: usize = 28;
+const AUTHENTICATE_MESSAGE_USER_NAME_FIELD: usize = 36;
+/// The size of the NTProofStr, at the beginning of an NTLMv2 response (MS-NLMP 2.2.2.8).
+const NT_PROOF_SIZE: usize = 16;
+
+/// The result of processing a single session setup request.
+pub(crate) enum SetupStep {
+    /// More processing is required; the token should be returned to the client.
+    Continue(Vec<u8>),
+    /// Authentication is complete, and the session is ready.
+    Done,
+}
+
+/// A session, as seen by the server.
+pub(crate) struct ServerSession {
+    ntlm: Ntlm,
+    credentials: <Ntlm as SspiImpl>::CredentialsHandle,
+    preauth_hash: PreauthHashState,
+    /// The challenge sent to the client, to verify its response with.
+    server_challenge: Option<[u8; 8]>,
+    ready: bool,
+
+    /// The name of the authenticated user.
+    pub user_name: Option<String>,
+    pub signer: Option<MessageSigner>,
+    pub encryptor: Option<MessageEncryptor>,
+    /// Decrypts client-to-server messages. [`smb::session::MessageDecryptor`] only handles responses,
+    /// so the algorithm is used directly.
+    pub decryptor: Option<Box<dyn EncryptingAlgo>>,
+    pub encrypt_data: bool,
+}
+
+impl ServerSession {
+    pub fn new(preauth_hash: PreauthHashState) -> Result<Self> {
+        let mut ntlm = Ntlm::new();
+        let credentials = ntlm
+            .acquire_credentials_handle()
+            .with_credential_use(CredentialUse::Inbound)
+            .execute(&mut ntlm)?
+            .credentials_handle;
+        Ok(Self {
+            ntlm,
+            credentials,
+            preauth_hash,
+            server_challenge: None,
+            ready: false,
+            user_name: None,
+            signer: None,
+            encryptor: None,
+            decryptor: None,
+            encrypt_data: false,
+        })
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Processes the security token of a session setup request.
+    ///
+    /// `raw_request` is the plain request, as received, for the preauth integrity hash.
+    pub fn accept(
+        &mut self,
+        raw_request: &IoVec,
+        token: &[u8],
+        negotiated: &Negotiated,
+        users: &UserDatabase,
+    ) -> Result<SetupStep> {
+        if self.ready {
+            return Err(Error::InvalidState("Session is already set up".to_string()));
+        }
+        self.preauth_hash = self.preauth_hash.clone().next(raw_request);
+
+        let mut input = [SecurityBuffer::new(token.to_vec(), BufferType::Token)];
+        let mut output = [SecurityBuffer::new(Vec::new(), BufferType::Token)];
+        let builder = self
+            .ntlm
+            .accept_security_context()
+            .with_credentials_handle(&mut self.credentials)
+            .with_context_requirements(ServerRequestFlags::empty())
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(&mut input)
+            .with_output(&mut output);
+        let result =
+            SspiImpl::accept_security_context_impl(&mut self.ntlm, builder)?.resolve_to_result()?;
+
+        match result.status {
+            SecurityStatus::ContinueNeeded => {
+                let [output] = output;
+                self.server_challenge = output
+                    .buffer
+                    .get(CHALLENGE_MESSAGE_SERVER_CHALLENGE_OFFSET..)
+                    .and_then(|challenge| challenge.get(..8))
+                    .map(|challenge| challenge.try_into().unwrap());
+                Ok(SetupStep::Continue(output.buffer))
+            }
+            SecurityStatus::CompleteNeeded | SecurityStatus::Ok => {
+                let username = self.ntlm.query_context_names()?.username;
+                let account_name = username.account_name().to_string();
+                let password = users
+                    .password(&account_name)
+                    .ok_or_else(|| Error::InvalidState(format!("Unknown user {account_name}")))?;
+                let server_challenge = self.server_challenge.ok_or_else(|| {
+                    Error::InvalidState("No challenge was sent to the client".to_string())
+                })?;
+                // sspi only verifies the MIC, and only if the client sent one.
+                verify_ntlm_v2_response(token, &server_challenge, password)?;
+                self.ntlm.custom_set_auth_identity(AuthIdentity {
+                    username,
+                    password: password.to_string().into(),
+                })?;
+                self.ntlm.complete_auth_token(&mut [])?;
+
+                let session_key = self.ntlm.query_context_session_key()?;
+                let session_key: KeyToDerive = session_key.session_key.as_ref()[..16]
+                    .try_into()
+                    .map_err(|_| Error::InvalidState("Invalid NTLM session key".to_string()))?;
+                self.preauth_hash = self.preauth_hash.clone().finish();
+                self.derive_algos(&session_key, negotiated)?;
+                self.user_name = Some(account_name);
+                self.ready = true;
+                Ok(SetupStep::Done)
+            }
+            status => Err(Error::InvalidState(format!(
+                "Unexpected NTLM status: {status:?}"
+            ))),
+        }
+    }
+
+    /// Updates the preauth integrity hash with a non-final session setup response.
+    pub fn next_preauth_hash(&mut self, raw_response: &IoVec) {
+        self.preauth_hash = self.preauth_hash.clone().next(raw_response);
+    }
+
+    fn derive_algos(&mut self, session_key: &KeyToDerive, negotiated: &Negotiated) -> Result<()> {
+        let preauth_hash = self.preauth_hash.unwrap_final_hash();
+        let context = |no_preauth_ctx: &'static [u8]| match preauth_hash {
+            Some(hash) => hash.as_slice(),
+            None => no_preauth_ctx,
+        };
+        let dialect = &negotiated.dialect;
+
+        let signing_algo = if dialect.dialect.is_smb3() {
+            let signing_key = kbkdf_hmacsha256(
+                session_key,
+                dialect.get_signing_derive_label(),
+                context(NO_PREAUTH_HASH_DERIVE_SIGN_CTX),
+            )?;
+            make_signing_algo(
+                negotiated
+                    .signing_algo
+                    .unwrap_or_else(|| dialect.default_signing_algo()),
+                &signing_key,
+            )?
+        } else {
+            make_signing_algo(SigningAlgorithmId::HmacSha256, session_key)?
+        };
+        self.signer = Some(MessageSigner::new(signing_algo));
+
+        if let Some(cipher) = negotiated.cipher {
+            let c2s_key = kbkdf_hmacsha256(
+                session_key,
+                dialect.c2s_encrypt_key_derive_label(),
+                context(NO_PREAUTH_HASH_DERIVE_ENCRYPT_C2S_CTX),
+            )?;
+            let s2c_key = kbkdf_hmacsha256(
+                session_key,
+                dialect.s2c_encrypt_key_derive_label(),
+                context(NO_PREAUTH_HASH_DERIVE_ENCRYPT_S2C_CTX),
+            )?;
+            self.encryptor = Some(MessageEncryptor::new(make_encrypting_algo(
+                cipher, &s2c_key,
+            )?));
+            self.decryptor = Some(make_encrypting_algo(cipher, &c2s_key)?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Verifies the NTLMv2 response of an NTLM AUTHENTICATE message, by computing the expected
+/// NTProofStr from the password of the user (MS-NLMP 3.3.2).
+fn verify_ntlm_v2_response(
+    authenticate: &[u8],
+    server_challenge: &[u8; 8],
+    password: &str,
+) -> Result<()> {
+    let invalid = || Error::InvalidMessage("Invalid NTLM AUTHENTICATE message".to_string());
+    let field = |offset: usize| -> Result<&[u8]> {
+        let header = authenticate.get(offset..offset + 8).ok_or_else(invalid)?;
+        let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let start = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        authenticate
+            .get(start..start.checked_add(length).ok_or_else(invalid)?)
+            .ok_or_else(invalid)
+    };
+    let nt_response = field(AUTHENTICATE_MESSAGE_NT_RESPONSE_FIELD)?;
+    let domain_name = field(AUTHENTICATE_MESSAGE_DOMAIN_NAME_FIELD)?;
+    let user_name = field(AUTHENTICATE_MESSAGE_USER_NAME_FIELD)?;
+    if nt_response.len() <= NT_PROOF_SIZE {
+        return Err(Error::InvalidMessage(
+            "Only NTLMv2 responses are supported".to_string(),
+        ));
+    }
+    let (nt_proof, client_blob) = nt_response.split_at(NT_PROOF_SIZE);
+
+    let user_name = String::from_utf16(
+        &user_name
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>(),
+    )
+    .map_err(|_| invalid())?;
+    let nt_hash = Md4::digest(utf16_le(password));
+
+    // NTOWFv2: HMAC_MD5(NT hash, UNICODE(Uppercase(User) + Domain)).
+    let mut response_key = Hmac::<Md5>::new_from_slice(&nt_hash).unwrap();
+    response_key.update(&utf16_le(&user_name.to_uppercase()));
+    response_key.update(domain_name);
+    let response_key = response_key.finalize().into_bytes();
+
+    // NTProofStr: HMAC_MD5(NTOWFv2, ServerChallenge + the client's blob).
+    let mut proof = Hmac::<Md5>::new_from_slice(&response_key).unwrap();
+    proof.update(server_challenge);
+    proof.update(client_blob);
+    proof
+        .verify_slice(nt_proof)
+        .map_err(|_| Error::InvalidState("Wrong password".to_string()))
+}
+
+fn utf16_le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smb::dialects::DialectImpl;
+    use smb_msg::Dialect;
+    use sspi::{ClientRequestFlags, Username};
+
+    const USER: &str = "user";
+    const PASSWORD: &str = "password";
+
+    /// The MsvAvFlags AV_PAIR of the client, with the MIC-present flag (MS-NLMP 2.2.2.1).
+    const MIC_PRESENT_AV_PAIR: &[u8] = &[0x06, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x00];
+
+    /// Runs an NTLM exchange between an sspi client, logging in with `password`,
+    /// and a server session. If `strip_mic` is set, the client's MIC is omitted.
+    fn authenticate(password: &str, strip_mic: bool) -> Result<SetupStep> {
+        let mut users = UserDatabase::default();
+        users.add_user(USER, PASSWORD);
+        let negotiated = Negotiated {
+            dialect: DialectImpl::new(Dialect::Smb0311),
+            signing_algo: None,
+            cipher: None,
+            compression: None,
+            preauth_hash: PreauthHashState::begin(),
+        };
+        let mut session = ServerSession::new(PreauthHashState::begin())?;
+
+        let mut client = Ntlm::new();
+        let identity = AuthIdentity {
+            username: Username::new(USER, None).unwrap(),
+            password: password.to_string().into(),
+        };
+        let mut credentials = client
+            .acquire_credentials_handle()
+            .with_credential_use(CredentialUse::Outbound)
+            .with_auth_data(&identity)
+            .execute(&mut client)?
+            .credentials_handle;
+        let mut next_token = |client: &mut Ntlm, input: Vec<u8>| -> Result<Vec<u8>> {
+            let mut input = [SecurityBuffer::new(input, BufferType::Token)];
+            let mut output = [SecurityBuffer::new(Vec::new(), BufferType::Token)];
+            let mut builder = client
+                .initialize_security_context()
+                .with_credentials_handle(&mut credentials)
+                .with_context_requirements(ClientRequestFlags::empty())
+                .with_target_data_representation(DataRepresentation::Native)
+                .with_input(&mut input)
+                .with_output(&mut output);
+            client
+                .initialize_security_context_impl(&mut builder)?
+                .resolve_to_result()?;
+            let [output] = output;
+            Ok(output.buffer)
+        };
+
+        let raw_request = IoVec::from(vec![0; 8]);
+        let negotiate = next_token(&mut client, vec![])?;
+        let SetupStep::Continue(challenge) =
+            session.accept(&raw_request, &negotiate, &negotiated, &users)?
+        else {
+            panic!("Expected an NTLM challenge");
+        };
+
+        let mut authenticate = next_token(&mut client, challenge)?;
+        if strip_mic {
+            let flags = authenticate
+                .windows(MIC_PRESENT_AV_PAIR.len())
+                .position(|pair| pair == MIC_PRESENT_AV_PAIR)
+                .expect("The client should send a MIC");
+            authenticate[flags + 4] = 0;
+        }
+        session.accept(&raw_request, &authenticate, &negotiated, &users)
+    }
+
+    #[test]
+    fn test_authenticate() {
+        assert!(matches!(authenticate(PASSWORD, false), Ok(SetupStep::Done)));
+    }
+
+    #[test]
+    fn test_authenticate_wrong_password() {
+        assert!(authenticate("wrong", false).is_err());
+        // Without a MIC, sspi accepts any password: the NTLMv2 response must be verified.
+        assert!(authenticate("wrong", true).is_err());
+    }
+}
@@ -5,6 +5,7 @@ use std::time::Duration;
 pub mod config;
 pub mod error;
 pub mod iovec;
+pub mod memory;
 pub mod tcp;
 pub mod traits;
 pub mod utils;
@@ -12,6 +13,7 @@ pub mod utils;
 pub use config::*;
 pub use error::TransportError;
 pub use iovec::*;
+pub use memory::MemoryTransport;
 
 pub use tcp::{SmbTcpMessageHeader, TcpTransport};
 pub use traits::*;
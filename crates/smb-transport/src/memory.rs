@@ -0,0 +1,302 @@
+//! In-memory transport implementation, for testing.
+//!
+//! [`MemoryTransport::pair`] creates two connected transports, where whatever is sent on one
+//! is received on the other. This allows running a client against an in-process (fake) server,
+//! without any networking.
+
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+#[cfg(not(feature = "async"))]
+use std::{
+    sync::Condvar,
+    time::{Duration, Instant},
+};
+
+use binrw::BinWrite;
+#[cfg(feature = "async")]
+use futures_core::future::BoxFuture;
+#[cfg(feature = "async")]
+use futures_util::FutureExt;
+
+use crate::error::*;
+use crate::{IoVec, SmbTcpMessageHeader, SmbTransport, SmbTransportRead, SmbTransportWrite};
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+    #[cfg(not(feature = "async"))]
+    read_timeout: Option<Duration>,
+}
+
+/// A single-direction, in-memory byte stream.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    #[cfg(feature = "async")]
+    readable: tokio::sync::Notify,
+    #[cfg(not(feature = "async"))]
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn write(&self, bufs: &[&[u8]]) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return Err(TransportError::NotConnected);
+            }
+            for buf in bufs {
+                state.data.extend(buf.iter());
+            }
+        }
+        self.notify();
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify();
+    }
+
+    fn notify(&self) {
+        #[cfg(feature = "async")]
+        self.readable.notify_one();
+        #[cfg(not(feature = "async"))]
+        self.readable.notify_all();
+    }
+
+    /// Reads exactly `out_buf.len()` bytes, if available. Returns whether the read was performed.
+    fn try_read_exact(state: &mut PipeState, out_buf: &mut [u8]) -> Result<bool> {
+        let len = out_buf.len();
+        if state.data.len() >= len {
+            for (dst, src) in out_buf.iter_mut().zip(state.data.drain(..len)) {
+                *dst = src;
+            }
+            return Ok(true);
+        }
+        if state.closed {
+            return Err(TransportError::NotConnected);
+        }
+        Ok(false)
+    }
+
+    #[cfg(feature = "async")]
+    async fn read_exact(&self, out_buf: &mut [u8]) -> Result<()> {
+        loop {
+            if Self::try_read_exact(&mut self.state.lock().unwrap(), out_buf)? {
+                return Ok(());
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn read_exact(&self, out_buf: &mut [u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let deadline = state
+            .read_timeout
+            .and_then(|t| Instant::now().checked_add(t));
+        loop {
+            if Self::try_read_exact(&mut state, out_buf)? {
+                return Ok(());
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+                    }
+                    self.readable.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.readable.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+/// An in-memory SMB transport, connected to a peer [`MemoryTransport`].
+///
+/// Use [`MemoryTransport::pair`] to create a connected pair of transports:
+/// one for the client, to be passed to [`Connection::from_transport`](https://docs.rs/smb/latest/smb/struct.Connection.html#method.from_transport),
+/// and one for the (fake) server.
+///
+/// Messages are framed with the direct TCP transport header, just like [`TcpTransport`](crate::TcpTransport).
+/// Dropping either side of the pair disconnects the other.
+pub struct MemoryTransport {
+    incoming: Option<Arc<Pipe>>,
+    outgoing: Option<Arc<Pipe>>,
+    remote_address: SocketAddr,
+}
+
+impl MemoryTransport {
+    /// The address reported as the remote address of the client side of the pair.
+    pub const SERVER_ADDRESS: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 445);
+    /// The address reported as the remote address of the server side of the pair.
+    pub const CLIENT_ADDRESS: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 49152);
+
+    /// Creates a connected pair of transports: `(client, server)`.
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let client_to_server = Arc::new(Pipe::default());
+        let server_to_client = Arc::new(Pipe::default());
+        (
+            MemoryTransport {
+                incoming: Some(server_to_client.clone()),
+                outgoing: Some(client_to_server.clone()),
+                remote_address: Self::SERVER_ADDRESS,
+            },
+            MemoryTransport {
+                incoming: Some(client_to_server),
+                outgoing: Some(server_to_client),
+                remote_address: Self::CLIENT_ADDRESS,
+            },
+        )
+    }
+
+    /// Whether the peer has disconnected.
+    pub fn is_closed(&self) -> bool {
+        [&self.incoming, &self.outgoing]
+            .into_iter()
+            .flatten()
+            .any(|pipe| pipe.state.lock().unwrap().closed)
+    }
+
+    fn outgoing(&self) -> Result<&Arc<Pipe>> {
+        self.outgoing.as_ref().ok_or(TransportError::NotConnected)
+    }
+
+    fn incoming(&self) -> Result<&Arc<Pipe>> {
+        self.incoming.as_ref().ok_or(TransportError::NotConnected)
+    }
+
+    /// Writes a complete message at once, so the reader never observes a partial message.
+    fn send_message(&self, data: &IoVec) -> Result<()> {
+        let header = SmbTcpMessageHeader {
+            stream_protocol_length: data.total_size() as u32,
+        };
+        let mut header_buf = Vec::with_capacity(SmbTcpMessageHeader::SIZE);
+        header.write(&mut Cursor::new(&mut header_buf))?;
+
+        let bufs = std::iter::once(header_buf.as_slice())
+            .chain(data.iter().map(|buf| buf.as_ref()))
+            .collect::<Vec<_>>();
+        self.outgoing()?.write(&bufs)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        for pipe in [self.incoming.take(), self.outgoing.take()]
+            .into_iter()
+            .flatten()
+        {
+            pipe.close();
+        }
+    }
+}
+
+impl SmbTransport for MemoryTransport {
+    #[cfg(feature = "async")]
+    fn connect<'a>(
+        &'a mut self,
+        _server_name: &'a str,
+        _address: SocketAddr,
+    ) -> BoxFuture<'a, Result<()>> {
+        async { self.outgoing().map(|_| ()) }.boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn connect(&mut self, _server_name: &str, _address: SocketAddr) -> Result<()> {
+        self.outgoing().map(|_| ())
+    }
+
+    fn default_port(&self) -> u16 {
+        Self::SERVER_ADDRESS.port()
+    }
+
+    fn split(self: Box<Self>) -> Result<(Box<dyn SmbTransportRead>, Box<dyn SmbTransportWrite>)> {
+        let mut this = *self;
+        let remote_address = this.remote_address;
+        Ok((
+            Box::new(MemoryTransport {
+                incoming: this.incoming.take(),
+                outgoing: None,
+                remote_address,
+            }),
+            Box::new(MemoryTransport {
+                incoming: None,
+                outgoing: this.outgoing.take(),
+                remote_address,
+            }),
+        ))
+    }
+
+    fn remote_address(&self) -> Result<SocketAddr> {
+        Ok(self.remote_address)
+    }
+}
+
+impl SmbTransportWrite for MemoryTransport {
+    #[cfg(feature = "async")]
+    fn send_raw<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async { self.outgoing()?.write(&[buf]) }.boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn send_raw(&mut self, buf: &[u8]) -> Result<()> {
+        self.outgoing()?.write(&[buf])
+    }
+
+    #[cfg(feature = "async")]
+    fn send<'a>(&'a mut self, data: &'a IoVec) -> BoxFuture<'a, Result<()>> {
+        async { self.send_message(data) }.boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn send(&mut self, data: &IoVec) -> Result<()> {
+        self.send_message(data)
+    }
+}
+
+impl SmbTransportRead for MemoryTransport {
+    #[cfg(feature = "async")]
+    fn receive_exact<'a>(&'a mut self, out_buf: &'a mut [u8]) -> BoxFuture<'a, Result<()>> {
+        async { self.incoming()?.read_exact(out_buf).await }.boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn receive_exact(&mut self, out_buf: &mut [u8]) -> Result<()> {
+        self.incoming()?.read_exact(out_buf)
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn set_read_timeout(&self, timeout: Duration) -> Result<()> {
+        self.incoming()?.state.lock().unwrap().read_timeout = Some(timeout);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[maybe_async::test(
+        feature = "is_sync",
+        async(not(feature = "is_sync"), tokio::test(flavor = "multi_thread"))
+    )]
+    async fn test_memory_pair() {
+        let (mut client, mut server) = MemoryTransport::pair();
+        SmbTransportWrite::send(
+            &mut client,
+            &IoVec::from(vec![b"hello".to_vec(), b" world".to_vec()]),
+        )
+        .await
+        .unwrap();
+        let received = SmbTransportRead::receive(&mut server).await.unwrap();
+        assert_eq!(received, b"hello world");
+
+        drop(client);
+        let result = SmbTransportRead::receive(&mut server).await;
+        assert!(matches!(result, Err(TransportError::NotConnected)));
+    }
+}
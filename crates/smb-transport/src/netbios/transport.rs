@@ -21,6 +21,43 @@ impl NetBiosTransport {
         }
     }
 
+    /// Accepts a NetBIOS session on an already-connected socket, on the server side.
+    ///
+    /// Reads the client's session request, and replies with a positive session response.
+    #[maybe_async]
+    pub async fn accept(tcp: TcpTransport) -> Result<NetBiosTransport> {
+        let mut transport = NetBiosTransport { tcp: Box::new(tcp) };
+
+        log::debug!("Waiting for NetBIOS session request");
+        let header = transport.netbios_receive_header().await?;
+        let mut request_packet = vec![0u8; header.length as usize];
+        transport.tcp.receive_exact(&mut request_packet).await?;
+        match NBSSTrailer::read_args(&mut Cursor::new(&request_packet), (header.ptype,))? {
+            NBSSTrailer::SessionRequest(request) => {
+                log::debug!("Got NetBIOS session request: {:?}", request);
+            }
+            x => {
+                log::debug!("Expected NetBIOS session request, got: {:?}", x);
+                return Err(TransportError::InvalidMessage);
+            }
+        }
+
+        let mut header_cursor = Cursor::new([0u8; NBSSPacketHeader::SIZE]);
+        NBSSPacketHeader {
+            ptype: NBSSPacketType::PositiveSessionResponse,
+            flags: 0,
+            length: 0,
+        }
+        .write(&mut header_cursor)?;
+        transport
+            .tcp
+            .send_raw(header_cursor.into_inner().as_slice())
+            .await?;
+
+        log::debug!("NetBIOS session accepted.");
+        Ok(transport)
+    }
+
     /// Starts the underlying TCP connection, and sends NetBIOS session request and expects a session response.
     #[maybe_async]
     async fn do_connect(&mut self, server_name: &str, address: SocketAddr) -> Result<()> {
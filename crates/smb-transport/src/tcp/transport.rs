@@ -49,6 +49,19 @@ impl TcpTransport {
         }
     }
 
+    /// Wraps an already-connected socket, such as one returned from a listener's `accept()`.
+    ///
+    /// This is used on the server side, where there is no need to call
+    /// [`connect`](SmbTransport::connect) on the transport.
+    pub fn from_stream(stream: TcpStream, timeout: Duration) -> TcpTransport {
+        let (r, w) = Self::split_socket(stream);
+        TcpTransport {
+            reader: Some(r),
+            writer: Some(w),
+            timeout,
+        }
+    }
+
     /// Connects to a NetBios server in the specified endpoint with a timeout.
     /// This is the threaded version of [connect](NetBiosClient::connect) -
     /// using the [std::net::TcpStream] as the underlying socket provider.
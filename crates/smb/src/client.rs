@@ -1,9 +1,14 @@
 //! High-level SMB client interface.
 
 mod config;
+mod credentials;
 mod smb_client;
 mod unc_path;
 
 pub use config::ClientConfig;
+pub use credentials::{
+    ChainCredentialProvider, CredentialProvider, CredentialProviderHandle, CredentialRequest,
+    CredentialsFileProvider, EnvCredentialProvider, StaticCredentialProvider,
+};
 pub use smb_client::Client;
 pub use unc_path::UncPath;
@@ -1,6 +1,8 @@
 use smb_dtyp::Guid;
 
 use crate::ConnectionConfig;
+use crate::client::CredentialProviderHandle;
+use crate::security::SecurityPolicy;
 
 /// Configuration for the SMB client.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -18,6 +20,16 @@ pub struct ClientConfig {
 
     pub client_guid: Guid,
 
+    /// The minimal security requirements for sessions and trees connected by the client.
+    /// Sessions and trees that do not meet them are disconnected, and their connection fails.
+    /// See [`SecurityPolicy`] for more details.
+    pub security_policy: SecurityPolicy,
+
+    /// The source of credentials for sessions the client has no credentials for,
+    /// e.g. sessions to DFS referral targets, or shares connected using [`Client::share_connect_with_provider`][crate::Client::share_connect_with_provider].
+    /// See [`CredentialProvider`][crate::client::CredentialProvider] for more details.
+    pub credential_provider: Option<CredentialProviderHandle>,
+
     #[cfg(feature = "rdma")]
     pub rdma_type: Option<crate::transport::RdmaType>,
 }
@@ -28,6 +40,8 @@ impl Default for ClientConfig {
             dfs: true,
             connection: ConnectionConfig::default(),
             client_guid: Guid::generate(),
+            security_policy: SecurityPolicy::default(),
+            credential_provider: None,
             #[cfg(feature = "rdma")]
             rdma_type: None,
         }
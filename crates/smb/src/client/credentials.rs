@@ -0,0 +1,427 @@
+//! Credential providers, which supply the [`Credentials`] of sessions made by the [`Client`][crate::Client].
+//!
+//! Set [`ClientConfig::credential_provider`][crate::ClientConfig::credential_provider] to a
+//! [`CredentialProviderHandle`], and the client consults it whenever it needs a session for a server
+//! it has no credentials for - e.g., when connecting with [`Client::share_connect_with_provider`][crate::Client::share_connect_with_provider],
+//! or when following a DFS referral into another server or domain.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use sspi::{AuthIdentity, Secret, Username};
+
+use crate::{Credentials, Error};
+
+/// The target a session is needed for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CredentialRequest<'a> {
+    /// The name of the server, as specified in the UNC path.
+    pub server: &'a str,
+    /// The share that is being connected to, if any.
+    pub share: Option<&'a str>,
+    /// The (upper-cased) realm/domain of the server: the domain part of its name, if it's a FQDN,
+    /// falling back to the configured [`AuthMethodsConfig::realm`][crate::connection::AuthMethodsConfig::realm].
+    pub realm: Option<String>,
+}
+
+impl<'a> CredentialRequest<'a> {
+    pub fn new(server: &'a str, share: Option<&'a str>, default_realm: Option<&str>) -> Self {
+        let server_domain = match server.parse::<IpAddr>() {
+            Ok(_) => None,
+            Err(_) => server.split_once('.').map(|(_, domain)| domain),
+        };
+        Self {
+            server,
+            share,
+            realm: server_domain
+                .or(default_realm)
+                .map(|realm| realm.to_ascii_uppercase()),
+        }
+    }
+}
+
+/// A source of credentials, by target server, share or realm.
+pub trait CredentialProvider: Send + Sync {
+    /// Returns the credentials to authenticate to the requested target with,
+    /// or `None` if the provider has no credentials for it.
+    fn credentials(&self, request: &CredentialRequest) -> crate::Result<Option<Credentials>>;
+}
+
+/// A shared reference to a [`CredentialProvider`] implementation, to be set in the client configuration.
+///
+/// Two handles are equal if they refer to the same provider instance.
+#[derive(Clone)]
+pub struct CredentialProviderHandle(Arc<dyn CredentialProvider>);
+
+impl CredentialProviderHandle {
+    pub fn new(provider: Arc<dyn CredentialProvider>) -> Self {
+        Self(provider)
+    }
+
+    pub(crate) fn credentials(
+        &self,
+        request: &CredentialRequest,
+    ) -> crate::Result<Option<Credentials>> {
+        self.0.credentials(request)
+    }
+}
+
+impl std::fmt::Debug for CredentialProviderHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CredentialProviderHandle").finish()
+    }
+}
+
+impl PartialEq for CredentialProviderHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CredentialProviderHandle {}
+
+/// Returns password credentials for `username`, which may be qualified (`DOMAIN\user` or `user@domain`),
+/// or be qualified by `domain`, if specified.
+fn password_credentials(
+    username: &str,
+    password: String,
+    domain: Option<&str>,
+) -> crate::Result<Credentials> {
+    let username = match domain {
+        Some(domain) if !username.contains(['\\', '@']) => Username::new(username, Some(domain)),
+        _ => Username::parse(username),
+    }
+    .map_err(|e| Error::SspiError(e.into()))?;
+    Ok(AuthIdentity {
+        username,
+        password: Secret::from(password),
+    }
+    .into())
+}
+
+/// A provider of fixed credentials, per share, server or realm.
+///
+/// When queried, the credentials of the most specific match are returned:
+/// the share, then the server, then the realm, and finally the default credentials, if set.
+/// Server names and realms are matched case-insensitively.
+#[derive(Debug, Default, Clone)]
+pub struct StaticCredentialProvider {
+    shares: HashMap<(String, String), Credentials>,
+    servers: HashMap<String, Credentials>,
+    realms: HashMap<String, Credentials>,
+    default: Option<Credentials>,
+}
+
+impl StaticCredentialProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the credentials for `share` on `server`.
+    pub fn with_share(
+        mut self,
+        server: &str,
+        share: &str,
+        credentials: impl Into<Credentials>,
+    ) -> Self {
+        self.shares.insert(
+            (server.to_ascii_lowercase(), share.to_ascii_lowercase()),
+            credentials.into(),
+        );
+        self
+    }
+
+    /// Sets the credentials for all shares on `server`.
+    pub fn with_server(mut self, server: &str, credentials: impl Into<Credentials>) -> Self {
+        self.servers
+            .insert(server.to_ascii_lowercase(), credentials.into());
+        self
+    }
+
+    /// Sets the credentials for all servers in `realm`.
+    pub fn with_realm(mut self, realm: &str, credentials: impl Into<Credentials>) -> Self {
+        self.realms
+            .insert(realm.to_ascii_uppercase(), credentials.into());
+        self
+    }
+
+    /// Sets the credentials for targets that have no more specific credentials.
+    pub fn with_default(mut self, credentials: impl Into<Credentials>) -> Self {
+        self.default = Some(credentials.into());
+        self
+    }
+}
+
+impl CredentialProvider for StaticCredentialProvider {
+    fn credentials(&self, request: &CredentialRequest) -> crate::Result<Option<Credentials>> {
+        let server = request.server.to_ascii_lowercase();
+        let by_share = request.share.and_then(|share| {
+            self.shares
+                .get(&(server.clone(), share.to_ascii_lowercase()))
+        });
+        let by_realm = request
+            .realm
+            .as_ref()
+            .and_then(|realm| self.realms.get(realm));
+        Ok(by_share
+            .or_else(|| self.servers.get(&server))
+            .or(by_realm)
+            .or(self.default.as_ref())
+            .cloned())
+    }
+}
+
+/// A provider of the user name and password set in environment variables:
+/// `SMB_USERNAME`, `SMB_PASSWORD` and optionally `SMB_DOMAIN`.
+///
+/// The variables are read on every query, and the same credentials are returned for all targets.
+/// If `SMB_USERNAME` is not set, no credentials are returned.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EnvCredentialProvider;
+
+impl EnvCredentialProvider {
+    pub const USERNAME_VAR: &str = "SMB_USERNAME";
+    pub const PASSWORD_VAR: &str = "SMB_PASSWORD";
+    pub const DOMAIN_VAR: &str = "SMB_DOMAIN";
+}
+
+impl CredentialProvider for EnvCredentialProvider {
+    fn credentials(&self, _request: &CredentialRequest) -> crate::Result<Option<Credentials>> {
+        let Ok(username) = std::env::var(Self::USERNAME_VAR) else {
+            return Ok(None);
+        };
+        let password = std::env::var(Self::PASSWORD_VAR).unwrap_or_default();
+        let domain = std::env::var(Self::DOMAIN_VAR).ok();
+        password_credentials(&username, password, domain.as_deref()).map(Some)
+    }
+}
+
+/// A provider of the credentials in a `mount.cifs`-style credentials file (e.g. `~/.smbcredentials`):
+/// ```text
+/// username=alice
+/// password=secret
+/// domain=CORP
+/// ```
+/// In addition, credentials may be specified per server or share, in sections named
+/// `[server]` or `[server\share]`; the entries before the first section are the default credentials.
+///
+/// The file is read once, when the provider is created.
+#[derive(Debug, Clone)]
+pub struct CredentialsFileProvider {
+    credentials: StaticCredentialProvider,
+}
+
+impl CredentialsFileProvider {
+    /// Reads the credentials file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+
+    /// Reads `~/.smbcredentials`.
+    pub fn from_default_file() -> crate::Result<Self> {
+        Self::from_file(Self::default_path()?)
+    }
+
+    /// Returns the path of `~/.smbcredentials`.
+    pub fn default_path() -> crate::Result<PathBuf> {
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".smbcredentials"))
+            .ok_or_else(|| {
+                Error::InvalidConfiguration("Cannot determine the home directory.".to_string())
+            })
+    }
+
+    /// Parses the content of a credentials file.
+    pub fn parse(content: &str) -> crate::Result<Self> {
+        #[derive(Default)]
+        struct Entry {
+            username: Option<String>,
+            password: Option<String>,
+            domain: Option<String>,
+        }
+
+        let mut sections: Vec<(Option<String>, Entry)> = vec![(None, Entry::default())];
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                sections.push((Some(section.trim().to_string()), Entry::default()));
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                Error::InvalidConfiguration(format!("Invalid credentials file line: {line}"))
+            })?;
+            let entry = &mut sections.last_mut().unwrap().1;
+            let value = Some(value.to_string());
+            match key.trim().to_ascii_lowercase().as_str() {
+                "username" | "user" => entry.username = value,
+                "password" | "pass" => entry.password = value,
+                "domain" | "dom" | "workgroup" => entry.domain = value,
+                key => log::debug!("Ignoring unknown credentials file key: {key}"),
+            }
+        }
+
+        let mut credentials = StaticCredentialProvider::new();
+        for (section, entry) in sections {
+            let Some(username) = entry.username else {
+                if entry.password.is_some() || section.is_some() {
+                    return Err(Error::InvalidConfiguration(format!(
+                        "Missing username in credentials file section {}",
+                        section.as_deref().unwrap_or("(default)")
+                    )));
+                }
+                continue;
+            };
+            let target_credentials = password_credentials(
+                &username,
+                entry.password.unwrap_or_default(),
+                entry.domain.as_deref(),
+            )?;
+            credentials = match section {
+                None => credentials.with_default(target_credentials),
+                Some(section) => match section.trim_start_matches('\\').split_once(['\\', '/']) {
+                    Some((server, share)) => {
+                        credentials.with_share(server, share, target_credentials)
+                    }
+                    None => credentials.with_server(&section, target_credentials),
+                },
+            };
+        }
+        Ok(Self { credentials })
+    }
+}
+
+impl CredentialProvider for CredentialsFileProvider {
+    fn credentials(&self, request: &CredentialRequest) -> crate::Result<Option<Credentials>> {
+        self.credentials.credentials(request)
+    }
+}
+
+/// A provider that queries other providers in order, and returns the first credentials found.
+#[derive(Debug, Default, Clone)]
+pub struct ChainCredentialProvider {
+    providers: Vec<CredentialProviderHandle>,
+}
+
+impl ChainCredentialProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `provider` to the chain.
+    pub fn with(mut self, provider: Arc<dyn CredentialProvider>) -> Self {
+        self.providers.push(CredentialProviderHandle::new(provider));
+        self
+    }
+}
+
+impl CredentialProvider for ChainCredentialProvider {
+    fn credentials(&self, request: &CredentialRequest) -> crate::Result<Option<Credentials>> {
+        for provider in &self.providers {
+            if let Some(credentials) = provider.credentials(request)? {
+                return Ok(Some(credentials));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_of(credentials: Option<Credentials>) -> String {
+        credentials.unwrap().to_string()
+    }
+
+    #[test]
+    fn test_credential_request_realm() {
+        let request = CredentialRequest::new("fs01.corp.example.com", Some("share"), None);
+        assert_eq!(request.realm.as_deref(), Some("CORP.EXAMPLE.COM"));
+        let request = CredentialRequest::new("10.0.0.5", None, Some("corp.example.com"));
+        assert_eq!(request.realm.as_deref(), Some("CORP.EXAMPLE.COM"));
+        let request = CredentialRequest::new("fs01", None, None);
+        assert_eq!(request.realm, None);
+    }
+
+    #[test]
+    fn test_static_provider_matching() {
+        let provider = StaticCredentialProvider::new()
+            .with_default(password_credentials("guest", String::new(), None).unwrap())
+            .with_realm(
+                "other.example.com",
+                password_credentials("bob", String::new(), Some("OTHER")).unwrap(),
+            )
+            .with_server(
+                "FS01.corp.example.com",
+                password_credentials("alice", String::new(), Some("CORP")).unwrap(),
+            )
+            .with_share(
+                "fs01.corp.example.com",
+                "Backup",
+                password_credentials("CORP\\backup", String::new(), None).unwrap(),
+            );
+
+        let query = |server, share| {
+            provider
+                .credentials(&CredentialRequest::new(server, share, None))
+                .unwrap()
+        };
+        assert_eq!(
+            user_of(query("fs01.corp.example.com", Some("backup"))),
+            "backup@CORP"
+        );
+        assert_eq!(
+            user_of(query("fs01.corp.example.com", Some("data"))),
+            "alice@CORP"
+        );
+        assert_eq!(
+            user_of(query("fs02.other.example.com", Some("data"))),
+            "bob@OTHER"
+        );
+        assert_eq!(user_of(query("fs03", None)), "guest");
+    }
+
+    #[test]
+    fn test_credentials_file() {
+        let provider = CredentialsFileProvider::parse(
+            "# default\n\
+             username=alice\n\
+             password=secret=1\n\
+             domain=CORP\n\
+             \n\
+             [fs02.other.example.com\\data]\n\
+             username=bob@OTHER\n\
+             password=secret2\n",
+        )
+        .unwrap();
+
+        let credentials = provider
+            .credentials(&CredentialRequest::new("fs01", Some("share"), None))
+            .unwrap();
+        match credentials {
+            Some(Credentials::Password(identity)) => {
+                assert_eq!(identity.username.account_name(), "alice");
+                assert_eq!(identity.username.domain_name(), Some("CORP"));
+                assert_eq!(identity.password.as_ref(), "secret=1");
+            }
+            _ => panic!("Expected password credentials"),
+        }
+        let credentials = provider
+            .credentials(&CredentialRequest::new(
+                "fs02.other.example.com",
+                Some("DATA"),
+                None,
+            ))
+            .unwrap();
+        assert_eq!(user_of(credentials), "bob@OTHER");
+
+        assert!(CredentialsFileProvider::parse("password=secret\n").is_err());
+        assert!(CredentialsFileProvider::parse("username\n").is_err());
+    }
+}
@@ -1,16 +1,21 @@
 use crate::ConnectionConfig;
-use crate::{Connection, Error, FileCreateArgs, Pipe, Resource, Session, Tree, sync_helpers::*};
+use crate::events::{EventListenerHandle, SmbEvent, SmbEventListener};
+use crate::{
+    Connection, Credentials, Error, FileCreateArgs, Pipe, Resource, Session, Tree, sync_helpers::*,
+};
 use maybe_async::maybe_async;
 use smb_msg::{NetworkInterfaceInfo, ReferralEntry, ReferralEntryValue, Status};
 use smb_rpc::interface::{ShareInfo1, SrvSvc};
 use smb_transport::TransportConfig;
 use smb_transport::utils::TransportUtils;
-use sspi::{AuthIdentity, Secret};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
-use super::{config::ClientConfig, unc_path::UncPath};
+use super::{config::ClientConfig, credentials::CredentialRequest, unc_path::UncPath};
 
 /*
     Note:
@@ -26,6 +31,19 @@ use super::{config::ClientConfig, unc_path::UncPath};
 /// When connecting to a new share, even if it's on the same server,
 /// you must always connect to the share using [`Client::share_connect`].
 ///
+/// ## Multiple identities
+/// The client keeps a single connection per server, and a session per [identity][Credentials::identity]
+/// on each connection, so several users may access the same server - and even the same share - at once,
+/// using [`Client::share_connect_with_credentials`]. When a share is connected as several identities,
+/// use the `_as` methods (e.g. [`Client::create_file_as`]) to select the identity to use.
+///
+/// ## Events
+/// The client raises [`SmbEvent`]s as the state of its connections changes - for example,
+/// when a connection is lost, or the server closes a session or breaks a lease.
+/// Use [`Client::on_event`] to register a callback for them, or, in async builds,
+/// the [`Client::events`] stream. A lost connection is reconnected on the next call that connects
+/// to its server (e.g. [`Client::share_connect`]), which raises [`SmbEvent::ConnectionRestored`].
+///
 /// ## Drop behavior
 /// When the client drops, the held connections are not forcibly closed, but rather
 /// kept alive until all their references are dropped.
@@ -65,8 +83,10 @@ pub struct Client {
     /// Server Name + [RDMA|NONE] => [`ClientConnectionInfo`]
     // It's quite common to have one connection for RDMA, and one for TCP,
     connections: RwLock<HashMap<IpAddr, ClientConnectionInfo>>,
-    /// shares (trees) that are currently connected.
-    share_connects: Mutex<HashMap<UncPath, ClientConectedTree>>,
+    /// shares (trees) that are currently connected, by share and [identity][Credentials::identity].
+    share_connects: Mutex<HashMap<(UncPath, String), ClientConectedTree>>,
+    /// Distributes the events of the client's connections.
+    events: Arc<ClientEvents>,
 }
 
 /// (Internal)
@@ -75,12 +95,16 @@ pub struct Client {
 /// This is most useful to avoid creating multiple connections to the same server,
 struct ClientConnectionInfo {
     connection: Arc<Connection>,
-    /// Sessions owned by the connection
+    /// Sessions owned by the connection; one per identity.
     sessions: HashMap<u64, ClientSessionInfo>,
 }
 
 struct ClientSessionInfo {
     session: Arc<Session>,
+    /// The [identity][Credentials::identity] the session is authenticated as.
+    identity: String,
+    /// The [digest][Credentials::digest] of the credentials last verified for the identity.
+    credentials_digest: [u8; 32],
     /// alternate channels established for this session
     session_alt_channels: Option<HashMap<u32, AltChannelInfo>>,
 }
@@ -88,7 +112,7 @@ struct ClientSessionInfo {
 struct ClientConectedTree {
     session: Arc<Session>,
     tree: Arc<Tree>,
-    credentials: Option<AuthIdentity>,
+    credentials: Option<Credentials>,
 }
 
 #[derive(Clone)]
@@ -96,14 +120,97 @@ pub struct AltChannelInfo {
     connection: Arc<Connection>,
 }
 
+/// (Internal)
+///
+/// Receives the events of all the client's connections, and distributes them
+/// to the listener in the client's configuration, and those registered on the client.
+struct ClientEvents {
+    /// The listener set in [`ConnectionConfig::events`] of the client's configuration.
+    configured: Option<EventListenerHandle>,
+    callbacks: std::sync::Mutex<Vec<EventListenerHandle>>,
+    /// Senders of the streams returned by [`Client::events`].
+    #[cfg(feature = "async")]
+    streams: std::sync::Mutex<Vec<tokio::sync::mpsc::Sender<SmbEvent>>>,
+    /// Servers whose connection was lost, and not reconnected yet.
+    lost_servers: std::sync::Mutex<HashSet<String>>,
+}
+
+impl ClientEvents {
+    fn new(configured: Option<EventListenerHandle>) -> Self {
+        Self {
+            configured,
+            callbacks: Default::default(),
+            #[cfg(feature = "async")]
+            streams: Default::default(),
+            lost_servers: Default::default(),
+        }
+    }
+
+    /// Called once a connection to `server` is made,
+    /// to raise [`SmbEvent::ConnectionRestored`] if the previous connection to it was lost.
+    fn connected(&self, server: &str) {
+        let restored = self
+            .lost_servers
+            .lock()
+            .is_ok_and(|mut lost| lost.remove(server));
+        if restored {
+            self.on_event(&SmbEvent::ConnectionRestored {
+                server: server.to_string(),
+            });
+        }
+    }
+}
+
+impl SmbEventListener for ClientEvents {
+    fn on_event(&self, event: &SmbEvent) {
+        if let SmbEvent::ConnectionLost { server } = event {
+            if let Ok(mut lost) = self.lost_servers.lock() {
+                lost.insert(server.clone());
+            }
+        }
+
+        if let Some(configured) = &self.configured {
+            configured.emit(event);
+        }
+        // Callbacks are called without holding the lock, so they may register other callbacks.
+        let callbacks = self
+            .callbacks
+            .lock()
+            .map(|callbacks| callbacks.clone())
+            .unwrap_or_default();
+        for callback in callbacks {
+            callback.emit(event);
+        }
+
+        #[cfg(feature = "async")]
+        if let Ok(mut streams) = self.streams.lock() {
+            use tokio::sync::mpsc::error::TrySendError;
+            streams.retain(|stream| match stream.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Event stream is full, dropping event: {event:?}");
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+        }
+    }
+}
+
 #[maybe_async(AFIT)]
 impl Client {
+    /// The number of events buffered by each stream returned by [`Client::events`].
+    #[cfg(feature = "async")]
+    pub const EVENTS_BUFFER_SIZE: usize = 1024;
+
     /// Creates a new `Client` instance with the given configuration.
     pub fn new(config: ClientConfig) -> Self {
+        let events = Arc::new(ClientEvents::new(config.connection.events.clone()));
         Client {
             config,
             connections: Default::default(),
             share_connects: Default::default(),
+            events,
         }
     }
 
@@ -111,6 +218,54 @@ impl Client {
         &self.config
     }
 
+    /// Registers a callback to be called on each [`SmbEvent`] of the client's connections.
+    ///
+    /// The callback is called synchronously, from the thread (or task) that detected the event,
+    /// so it should return quickly. See [`crate::events`] for more information.
+    pub fn on_event(&self, callback: impl Fn(&SmbEvent) + Send + Sync + 'static) {
+        let callback = EventListenerHandle::new(Arc::new(callback));
+        self.events
+            .callbacks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(callback);
+    }
+
+    /// Returns a stream of the [`SmbEvent`]s of the client's connections,
+    /// starting from the moment this method is called.
+    ///
+    /// The stream buffers up to [`Client::EVENTS_BUFFER_SIZE`] events;
+    /// events raised while the buffer is full are dropped, so poll the stream continuously.
+    ///
+    /// ```no_run
+    /// # use smb::{Client, ClientConfig, events::SmbEvent};
+    /// # use futures_util::StreamExt;
+    /// # #[cfg(not(feature = "async"))] fn main() {}
+    /// # #[cfg(feature = "async")]
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let client = Client::new(ClientConfig::default());
+    /// let mut events = client.events();
+    /// tokio::spawn(async move {
+    ///     while let Some(event) = events.next().await {
+    ///         if let SmbEvent::ConnectionLost { server } = event {
+    ///             eprintln!("Lost connection to {server}");
+    ///         }
+    ///     }
+    /// });
+    /// # }
+    /// ```
+    #[cfg(feature = "async")]
+    pub fn events(&self) -> impl futures_core::Stream<Item = SmbEvent> + use<> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(Self::EVENTS_BUFFER_SIZE);
+        self.events
+            .streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sender);
+        tokio_stream::wrappers::ReceiverStream::new(receiver)
+    }
+
     /// Shuts down the client, and all its managed connections.
     ///
     /// Any resource held by the client will not be accessible after calling this method,
@@ -150,9 +305,30 @@ impl Client {
     }
 
     /// Lists all shares on the specified server.
+    ///
+    /// The `IPC$` share of the server must be connected first, e.g. using [`Client::ipc_connect`],
+    /// or anonymously, using [`Client::ipc_connect_with_credentials`] with [`Credentials::Anonymous`].
     pub async fn list_shares(&self, server: &str) -> crate::Result<Vec<ShareInfo1>> {
+        self._list_shares(server, None).await
+    }
+
+    /// Similar to [`Client::list_shares`], but uses the `IPC$` share connected as the specified
+    /// [identity][Credentials::identity], when the share is connected as several identities.
+    pub async fn list_shares_as(
+        &self,
+        server: &str,
+        identity: &str,
+    ) -> crate::Result<Vec<ShareInfo1>> {
+        self._list_shares(server, Some(identity)).await
+    }
+
+    async fn _list_shares(
+        &self,
+        server: &str,
+        identity: Option<&str>,
+    ) -> crate::Result<Vec<ShareInfo1>> {
         let srvsvc_pipe_name: &str = "srvsvc";
-        let srvsvc_pipe = self.open_pipe(server, srvsvc_pipe_name).await?;
+        let srvsvc_pipe = self._open_pipe(server, identity, srvsvc_pipe_name).await?;
 
         let mut srvsvc_pipe: SrvSvc<_> = srvsvc_pipe.bind().await?;
         let shares = srvsvc_pipe.netr_share_enum(server).await?;
@@ -201,17 +377,44 @@ impl Client {
         user_name: &str,
         password: String,
     ) -> crate::Result<()> {
-        let identity = AuthIdentity {
-            username: sspi::Username::parse(user_name).map_err(|e| Error::SspiError(e.into()))?,
-            password: Secret::from(password),
-        };
+        let credentials = Credentials::password(user_name, password)?;
+        self.share_connect_with_credentials(target, credentials)
+            .await
+    }
 
-        self._share_connect(target, &identity).await?;
+    /// Similar to [`Client::share_connect`], but authenticates with the specified [`Credentials`],
+    /// e.g. Kerberos tickets from a credential cache or a keytab,
+    /// or [`Credentials::Guest`] and [`Credentials::Anonymous`] for guest and anonymous (null) sessions.
+    ///
+    /// ```no_run
+    /// # use smb::{Client, ClientConfig, Credentials, UncPath};
+    /// # use std::str::FromStr;
+    /// # #[cfg(not(all(feature = "async", feature = "kerberos")))] fn main() {}
+    /// # #[cfg(all(feature = "async", feature = "kerberos"))]
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let client = Client::new(ClientConfig::default());
+    /// let target_path = UncPath::from_str(r"\\server.corp.example.com\share").unwrap();
+    /// let credentials = Credentials::KerberosKeytab {
+    ///     principal: "svc-backup@CORP.EXAMPLE.COM".to_string(),
+    ///     keytab: Some("/etc/svc-backup.keytab".into()),
+    /// };
+    /// client.share_connect_with_credentials(&target_path, credentials).await?;
+    /// #   Ok(()) }
+    /// ```
+    pub async fn share_connect_with_credentials(
+        &self,
+        target: &UncPath,
+        credentials: impl Into<Credentials>,
+    ) -> crate::Result<()> {
+        let credentials = credentials.into();
+        self._share_connect(target, &credentials).await?;
 
         // Establish an additional channel if multi-channel is enabled.
-        let mchannel_map = self._setup_multi_channel(target, &identity).await;
+        let identity = credentials.identity();
+        let mchannel_map = self._setup_multi_channel(target, &credentials).await;
         if let Ok(mchannel_map) = mchannel_map {
-            let session = self.get_session(target).await?;
+            let session = self.get_session_as(target, &identity).await?;
             log::debug!(
                 "Established {} multi-channel connections",
                 mchannel_map.as_ref().map(|m| m.len()).unwrap_or(0)
@@ -242,11 +445,61 @@ impl Client {
         Ok(())
     }
 
+    /// Similar to [`Client::share_connect`], but authenticates with the credentials returned by
+    /// the configured [`ClientConfig::credential_provider`] for the target share.
+    ///
+    /// ```no_run
+    /// # use smb::{Client, ClientConfig, UncPath};
+    /// # use smb::client::{CredentialProviderHandle, CredentialsFileProvider};
+    /// # use std::{str::FromStr, sync::Arc};
+    /// # #[cfg(not(feature = "async"))] fn main() {}
+    /// # #[cfg(feature = "async")]
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let provider = CredentialsFileProvider::from_default_file()?;
+    /// let client = Client::new(ClientConfig {
+    ///     credential_provider: Some(CredentialProviderHandle::new(Arc::new(provider))),
+    ///     ..Default::default()
+    /// });
+    /// let target_path = UncPath::from_str(r"\\server\share").unwrap();
+    /// client.share_connect_with_provider(&target_path).await?;
+    /// #   Ok(()) }
+    /// ```
+    pub async fn share_connect_with_provider(&self, target: &UncPath) -> crate::Result<()> {
+        let credentials = self._provided_credentials(target)?.ok_or_else(|| {
+            Error::InvalidConfiguration(format!(
+                "No credentials provided for {target} by the client's credential provider."
+            ))
+        })?;
+        self.share_connect_with_credentials(target, credentials)
+            .await
+    }
+
+    /// (Internal)
+    ///
+    /// Returns the credentials for the target share from the configured credential provider, if any.
+    fn _provided_credentials(&self, target: &UncPath) -> crate::Result<Option<Credentials>> {
+        let provider = match &self.config.credential_provider {
+            Some(provider) => provider,
+            None => return Ok(None),
+        };
+        let request = CredentialRequest::new(
+            target.server(),
+            target.share(),
+            self.config.connection.auth_methods.realm.as_deref(),
+        );
+        provider.credentials(&request)
+    }
+
     /// (Internal)
     ///
     /// Performs the actual share connection logic,
     /// without setting up multi-channel.
-    async fn _share_connect(&self, target: &UncPath, identity: &AuthIdentity) -> crate::Result<()> {
+    async fn _share_connect(
+        &self,
+        target: &UncPath,
+        credentials: &Credentials,
+    ) -> crate::Result<()> {
         if target.share().is_none() {
             return Err(crate::Error::InvalidArgument(
                 "UNC path does not contain a share name.".to_string(),
@@ -254,33 +507,96 @@ impl Client {
         }
 
         let target = target.clone().with_no_path();
+        let identity = credentials.identity();
+
+        let digest = credentials.digest();
+
+        let connection = self.connect(target.server()).await?;
+        let address = TransportUtils::parse_socket_address(target.server())?;
+
+        // Re-use the session of the same identity on the connection, if there is one.
+        let existing_session = self
+            ._with_connection(address.ip(), |c| {
+                Ok(c.sessions
+                    .values()
+                    .find(|s| s.identity == identity)
+                    .map(|s| (s.session.clone(), s.credentials_digest == digest)))
+            })
+            .await?;
 
-        let already_connected = self._with_tree(&target, |_| Ok(())).await;
+        // The identity is only a name: credentials that differ from those the session was
+        // set up with (e.g. a wrong password) must authenticate before using it.
+        let existing_session = match existing_session {
+            Some((session, true)) => Some(session),
+            Some((session, false)) => {
+                log::debug!(
+                    "Credentials of {identity} differ from those of session {}. Authenticating them.",
+                    session.session_id()
+                );
+                connection
+                    .authenticate(credentials.clone())
+                    .await?
+                    .logoff()
+                    .await?;
+                self._with_connection(address.ip(), |c| {
+                    if let Some(info) = c.sessions.get_mut(&session.session_id()) {
+                        info.credentials_digest = digest;
+                    }
+                    Ok(())
+                })
+                .await?;
+                Some(session)
+            }
+            None => None,
+        };
+
+        let already_connected = self._with_tree(&target, Some(&identity), |_| Ok(())).await;
         if already_connected.is_ok() {
             log::debug!(
-                "Share {} is already connected. Ignoring duplicate connection attempt.",
+                "Share {} is already connected as {identity}. Ignoring duplicate connection attempt.",
                 target
             );
             return Ok(());
         }
 
-        let connection = self.connect(target.server()).await?;
-
-        let session = {
-            let session = connection.authenticate(identity.clone()).await?;
+        let session = if let Some(session) = existing_session {
             log::debug!(
-                "Successfully authenticated to {} as {}",
+                "Using existing session {} to {} as {identity}",
+                session.session_id(),
+                target.server()
+            );
+            // The posture of a session may change, e.g. when it is set up again after a reconnection.
+            let posture = session.security_posture().await?;
+            if let Err(e) = self.config.security_policy.check_session(&posture) {
+                log::warn!(
+                    "Existing session {} to {} rejected: {e}",
+                    session.session_id(),
+                    target.server()
+                );
+                return Err(e);
+            }
+            session
+        } else {
+            let session = connection.authenticate(credentials.clone()).await?;
+            log::debug!(
+                "Successfully authenticated to {} as {credentials}",
                 target.server(),
-                identity.username.account_name()
             );
+            let posture = session.security_posture().await?;
+            if let Err(e) = self.config.security_policy.check_session(&posture) {
+                log::warn!("Session to {} rejected: {e}", target.server());
+                session.logoff().await?;
+                return Err(e);
+            }
             let session = Arc::new(session);
 
-            let address = TransportUtils::parse_socket_address(target.server())?;
             self._with_connection(address.ip(), |f| {
                 f.sessions.insert(
                     session.session_id(),
                     ClientSessionInfo {
                         session: session.clone(),
+                        identity: identity.clone(),
+                        credentials_digest: digest,
                         session_alt_channels: None,
                     },
                 );
@@ -292,9 +608,15 @@ impl Client {
         };
 
         let tree = session.tree_connect(&target).await?;
+        let posture = tree.security_posture().await?;
+        if let Err(e) = self.config.security_policy.check_tree(&posture) {
+            log::warn!("Tree {target} rejected: {e}");
+            tree.disconnect().await?;
+            return Err(e);
+        }
 
         let credentials = if tree.is_dfs_root()? {
-            Some(identity.to_owned())
+            Some(credentials.clone())
         } else {
             None
         };
@@ -308,7 +630,7 @@ impl Client {
         self.share_connects
             .lock()
             .await?
-            .insert(target.clone(), connect_share_info);
+            .insert((target.clone(), identity), connect_share_info);
 
         log::debug!(
             "Successfully connected to share: {}",
@@ -318,8 +640,12 @@ impl Client {
         Ok(())
     }
 
-    async fn _get_credentials(&self, target: &UncPath) -> crate::Result<AuthIdentity> {
-        self._with_tree(target, |tree| {
+    async fn _get_credentials(
+        &self,
+        target: &UncPath,
+        identity: Option<&str>,
+    ) -> crate::Result<Credentials> {
+        self._with_tree(target, identity, |tree| {
             tree.credentials.as_ref().cloned().ok_or_else(|| {
                 Error::InvalidArgument(format!(
                     "No credentials found for DFS root share: {target}. Cannot resolve DFS path."
@@ -329,8 +655,15 @@ impl Client {
         .await
     }
 
-    async fn _create_file(&self, path: &UncPath, args: &FileCreateArgs) -> crate::Result<Resource> {
-        let tree = self.get_tree(path).await?;
+    async fn _create_file(
+        &self,
+        path: &UncPath,
+        identity: Option<&str>,
+        args: &FileCreateArgs,
+    ) -> crate::Result<Resource> {
+        let tree = self
+            ._with_tree(path, identity, |tree| Ok(tree.tree.clone()))
+            .await?;
         let resource = tree.create(path.path().unwrap_or(""), args).await?;
         Ok(resource)
     }
@@ -407,6 +740,10 @@ impl Client {
         } else {
             self.config.connection.clone()
         };
+        let config = ConnectionConfig {
+            events: Some(EventListenerHandle::new(self.events.clone())),
+            ..config
+        };
 
         let conn = Connection::build(server, server_address, self.config.client_guid, config)?;
 
@@ -414,8 +751,12 @@ impl Client {
 
         // TODO: This is a bit racy
         if let Ok(c) = self.get_connection_ip_channel(server_address.ip()).await {
-            log::debug!("Reusing existing connection to {server}",);
-            return Ok(c);
+            if !c.is_lost() {
+                log::debug!("Reusing existing connection to {server}",);
+                return Ok(c);
+            }
+            log::info!("Connection to {server} was lost. Reconnecting.");
+            self._remove_lost_connection(server_address.ip()).await?;
         }
         self._add_connection(conn.clone(), &server_address.ip())
             .await?;
@@ -429,10 +770,26 @@ impl Client {
         }
 
         log::debug!("Successfully connected to {server}",);
+        self.events.connected(server);
 
         Ok(conn)
     }
 
+    /// Forgets a lost connection, along with its sessions and the shares connected through them,
+    /// so they are set up again on the next connection to the server.
+    #[maybe_async]
+    async fn _remove_lost_connection(&self, ip: IpAddr) -> crate::Result<()> {
+        let removed = self.connections.write().await?.remove(&ip);
+        if let Some(removed) = removed {
+            let session_ids: HashSet<u64> = removed.sessions.keys().copied().collect();
+            self.share_connects
+                .lock()
+                .await?
+                .retain(|_, tree| !session_ids.contains(&tree.session.session_id()));
+        }
+        Ok(())
+    }
+
     #[maybe_async]
     async fn _add_connection(&self, to_add: Arc<Connection>, ip: &IpAddr) -> crate::Result<()> {
         let mut connections = self.connections.write().await?;
@@ -468,8 +825,24 @@ impl Client {
             .await
     }
 
+    /// Returns the [`Session`] of the share in the specified UNC path,
+    /// after a successful call to [`Client::share_connect`].
+    ///
+    /// Fails if the share is connected as several identities; use [`Client::get_session_as`] in that case.
     pub async fn get_session(&self, path: &UncPath) -> crate::Result<Arc<Session>> {
-        self._with_tree(path, |tree| Ok(tree.session.clone())).await
+        self._with_tree(path, None, |tree| Ok(tree.session.clone()))
+            .await
+    }
+
+    /// Returns the [`Session`] of the share in the specified UNC path, connected as the specified
+    /// [identity][Credentials::identity].
+    pub async fn get_session_as(
+        &self,
+        path: &UncPath,
+        identity: &str,
+    ) -> crate::Result<Arc<Session>> {
+        self._with_tree(path, Some(identity), |tree| Ok(tree.session.clone()))
+            .await
     }
 
     /// Returns a map of channel IDs to their corresponding connections for the specified session,
@@ -517,8 +890,18 @@ impl Client {
 
     /// Returns the underlying [`Tree`] for the specified UNC path,
     /// after a successful call to [`Client::share_connect`].
+    ///
+    /// Fails if the share is connected as several identities; use [`Client::get_tree_as`] in that case.
     pub async fn get_tree(&self, path: &UncPath) -> crate::Result<Arc<Tree>> {
-        self._with_tree(path, |tree| Ok(tree.tree.clone())).await
+        self._with_tree(path, None, |tree| Ok(tree.tree.clone()))
+            .await
+    }
+
+    /// Returns the underlying [`Tree`] for the specified UNC path, connected as the specified
+    /// [identity][Credentials::identity].
+    pub async fn get_tree_as(&self, path: &UncPath, identity: &str) -> crate::Result<Arc<Tree>> {
+        self._with_tree(path, Some(identity), |tree| Ok(tree.tree.clone()))
+            .await
     }
 
     #[maybe_async]
@@ -533,18 +916,41 @@ impl Client {
         f(conn)
     }
 
-    /// Locks `share_connects`, locates the tree for the specified path,
+    /// Locks `share_connects`, locates the tree for the specified path and identity,
     /// and calls the specified closure with the tree.
+    ///
+    /// If no identity is specified, the share must be connected as a single identity.
     #[maybe_async]
-    async fn _with_tree<F, R>(&self, path: &UncPath, f: F) -> crate::Result<R>
+    async fn _with_tree<F, R>(
+        &self,
+        path: &UncPath,
+        identity: Option<&str>,
+        f: F,
+    ) -> crate::Result<R>
     where
         F: FnOnce(&mut ClientConectedTree) -> crate::Result<R>,
     {
         let tree_path = path.clone().with_no_path();
         let mut sc = self.share_connects.lock().await?;
-        let sc = sc.get_mut(&tree_path).ok_or_else(|| {
-            Error::NotFound(format!("No connected share found for path: {path}",))
-        })?;
+        let not_found = || Error::NotFound(format!("No connected share found for path: {path}",));
+        let sc = match identity {
+            Some(identity) => sc
+                .get_mut(&(tree_path, identity.to_lowercase()))
+                .ok_or_else(not_found)?,
+            None => {
+                let mut trees = sc
+                    .iter_mut()
+                    .filter(|((share, _), _)| *share == tree_path)
+                    .map(|(_, tree)| tree);
+                let tree = trees.next().ok_or_else(not_found)?;
+                if trees.next().is_some() {
+                    return Err(Error::InvalidArgument(format!(
+                        "Share {tree_path} is connected as several identities. Specify the identity to use."
+                    )));
+                }
+                tree
+            }
+        };
         f(sc)
     }
 
@@ -565,13 +971,55 @@ impl Client {
         path: &UncPath,
         args: &FileCreateArgs,
     ) -> crate::Result<Resource> {
-        let file_result = self._create_file(path, args).await;
+        self._create_file_with_dfs(path, None, args).await
+    }
+
+    /// Similar to [`Client::create_file`], but uses the share connected as the specified
+    /// [identity][Credentials::identity].
+    ///
+    /// This allows accessing the same share as several users, e.g.:
+    /// ```no_run
+    /// # use smb::{Client, ClientConfig, Credentials, UncPath, FileCreateArgs, FileAccessMask};
+    /// # use std::str::FromStr;
+    /// # #[cfg(not(feature = "async"))] fn main() {}
+    /// # #[cfg(feature = "async")]
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let client = Client::new(ClientConfig::default());
+    /// let share = UncPath::from_str(r"\\server\share").unwrap();
+    /// let alice = Credentials::password("CORP\\alice", "alice's password".to_string())?;
+    /// let bob = Credentials::password("CORP\\bob", "bob's password".to_string())?;
+    /// client.share_connect_with_credentials(&share, alice.clone()).await?;
+    /// client.share_connect_with_credentials(&share, bob.clone()).await?;
+    ///
+    /// let args = FileCreateArgs::make_open_existing(FileAccessMask::new().with_generic_read(true));
+    /// let file = client.create_file_as(&share.clone().with_path("file.txt"), &alice.identity(), &args).await?;
+    /// #   Ok(()) }
+    /// ```
+    pub async fn create_file_as(
+        &self,
+        path: &UncPath,
+        identity: &str,
+        args: &FileCreateArgs,
+    ) -> crate::Result<Resource> {
+        self._create_file_with_dfs(path, Some(identity), args).await
+    }
+
+    async fn _create_file_with_dfs(
+        &self,
+        path: &UncPath,
+        identity: Option<&str>,
+        args: &FileCreateArgs,
+    ) -> crate::Result<Resource> {
+        let file_result = self._create_file(path, identity, args).await;
 
         let resource = match file_result {
             Ok(file) => Ok(file),
             Err(Error::ReceivedErrorMessage(Status::U32_PATH_NOT_COVERED, _)) => {
                 if self.config.dfs {
-                    DfsResolver::new(self).resolve_to_dfs_file(path, args).await
+                    DfsResolver::new(self)
+                        .resolve_to_dfs_file(path, identity, args)
+                        .await
                 } else {
                     Err(Error::UnsupportedOperation(
                         "DFS is not enabled, but the server returned path not covered (dfs must be enabled in config to resolve the path!).".to_string(),
@@ -593,17 +1041,35 @@ impl Client {
         username: &str,
         password: String,
     ) -> crate::Result<()> {
-        let ipc_share = UncPath::ipc_share(server)?;
-        let identity = AuthIdentity {
-            username: sspi::Username::parse(username).map_err(|e| Error::SspiError(e.into()))?,
-            password: Secret::from(password),
-        };
-        self._share_connect(&ipc_share, &identity).await
+        let credentials = Credentials::password(username, password)?;
+        self._ipc_connect(server, &credentials).await
+    }
+
+    /// Similar to [`Client::ipc_connect`], but authenticates with the specified [`Credentials`].
+    ///
+    /// For example, to list the shares of a server that allows anonymous enumeration:
+    /// ```no_run
+    /// # use smb::{Client, ClientConfig, Credentials};
+    /// # #[cfg(not(feature = "async"))] fn main() {}
+    /// # #[cfg(feature = "async")]
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let client = Client::new(ClientConfig::default());
+    /// client.ipc_connect_with_credentials("server", Credentials::Anonymous).await?;
+    /// let shares = client.list_shares("server").await?;
+    /// #   Ok(()) }
+    /// ```
+    pub async fn ipc_connect_with_credentials(
+        &self,
+        server: &str,
+        credentials: impl Into<Credentials>,
+    ) -> crate::Result<()> {
+        self._ipc_connect(server, &credentials.into()).await
     }
 
-    pub async fn _ipc_connect(&self, server: &str, identity: &AuthIdentity) -> crate::Result<()> {
+    pub async fn _ipc_connect(&self, server: &str, credentials: &Credentials) -> crate::Result<()> {
         let ipc_share = UncPath::ipc_share(server)?;
-        self._share_connect(&ipc_share, identity).await
+        self._share_connect(&ipc_share, credentials).await
     }
 
     /// Opens a named pipe on the specified server.
@@ -620,9 +1086,29 @@ impl Client {
     /// before calling this method, you MUST call the [`Client::ipc_connect`] method,
     /// that connects to the IPC$ share on the server, which then allows for communication with the named pipe.
     pub async fn open_pipe(&self, server: &str, pipe_name: &str) -> crate::Result<Pipe> {
+        self._open_pipe(server, None, pipe_name).await
+    }
+
+    /// Similar to [`Client::open_pipe`], but uses the `IPC$` share connected as the specified
+    /// [identity][Credentials::identity].
+    pub async fn open_pipe_as(
+        &self,
+        server: &str,
+        identity: &str,
+        pipe_name: &str,
+    ) -> crate::Result<Pipe> {
+        self._open_pipe(server, Some(identity), pipe_name).await
+    }
+
+    async fn _open_pipe(
+        &self,
+        server: &str,
+        identity: Option<&str>,
+        pipe_name: &str,
+    ) -> crate::Result<Pipe> {
         let path = UncPath::ipc_share(server)?.with_path(pipe_name);
         let pipe = self
-            ._create_file(&path, &FileCreateArgs::make_pipe())
+            ._create_file(&path, identity, &FileCreateArgs::make_pipe())
             .await?;
         match pipe {
             Resource::Pipe(file) => {
@@ -644,7 +1130,7 @@ impl Client {
     async fn _setup_multi_channel(
         &self,
         unc: &UncPath,
-        identity: &AuthIdentity,
+        credentials: &Credentials,
     ) -> crate::Result<Option<HashMap<u32, AltChannelInfo>>> {
         if unc.is_ipc_share() {
             return Err(Error::InvalidArgument(
@@ -652,6 +1138,13 @@ impl Client {
             ));
         }
 
+        if credentials.is_anonymous_or_guest() {
+            log::debug!(
+                "Multi-channel is not available for guest or anonymous sessions. Skipping setup."
+            );
+            return Ok(None);
+        }
+
         if !self.config.connection.multichannel.is_enabled() {
             log::debug!("Multi-channel is not enabled in client configuration. Skipping setup.");
             return Ok(None);
@@ -674,10 +1167,27 @@ impl Client {
             "Multi-channel is enabled for connection to {unc}. Scanning for alternate channels."
         );
 
+        let identity = credentials.identity();
+        let session = self.get_session_as(unc, &identity).await?;
+        let address = TransportUtils::parse_socket_address(unc.server())?;
+        let has_alt_channels = self
+            ._with_connection(address.ip(), |c| {
+                Ok(c.sessions
+                    .get(&session.session_id())
+                    .is_some_and(|s| s.session_alt_channels.is_some()))
+            })
+            .await?;
+        if has_alt_channels {
+            log::debug!(
+                "Multi-channel is already set up for the session of {identity}. Skipping setup."
+            );
+            return Ok(None);
+        }
+
         // Connect IPC and query network interfaces.
         let ipc_share = UncPath::ipc_share(unc.server())?;
-        self._ipc_connect(ipc_share.server(), identity).await?;
-        let ipc_tree = self.get_tree(&ipc_share).await?;
+        self._ipc_connect(ipc_share.server(), credentials).await?;
+        let ipc_tree = self.get_tree_as(&ipc_share, &identity).await?;
         let network_interfaces = ipc_tree
             .as_ipc_tree()
             .unwrap()
@@ -702,7 +1212,6 @@ impl Client {
             return Ok(None);
         }
 
-        let session = self.get_session(unc).await?;
         for (if_index, &interface) in other_interfaces.iter() {
             let address = interface.sockaddr.socket_addr();
             log::debug!("Found alternate interface for multi-channel: {if_index} => {address}");
@@ -729,7 +1238,9 @@ impl Client {
                     self.connect_to_address(unc.server(), address).await?
                 };
 
-                let channel = connection.bind_session(&session, identity.clone()).await?;
+                let channel = connection
+                    .bind_session(&session, credentials.clone())
+                    .await?;
 
                 (connection, channel)
             };
@@ -762,24 +1273,37 @@ impl<'a> DfsResolver<'a> {
     async fn resolve_to_dfs_file(
         &self,
         dfs_path: &UncPath,
+        identity: Option<&str>,
         args: &FileCreateArgs,
     ) -> crate::Result<Resource> {
-        let dfs_ref_paths = self.get_dfs_refs(dfs_path).await?;
+        let dfs_ref_paths = self.get_dfs_refs(dfs_path, identity).await?;
 
-        // Re-use the same credentials for the DFS referral.
-        let dfs_creds = self.client._get_credentials(dfs_path).await?;
+        // Re-use the same credentials for the DFS referral, unless the credential provider
+        // has specific credentials for the referral target.
+        let dfs_creds = self.client._get_credentials(dfs_path, identity).await;
 
         // Open the next DFS referral. Try each referral path, since some may be down.
         for ref_unc_path in dfs_ref_paths.iter() {
+            let ref_creds = match self.client._provided_credentials(ref_unc_path)? {
+                Some(creds) => creds,
+                None => match &dfs_creds {
+                    Ok(creds) => creds.clone(),
+                    Err(e) => {
+                        log::error!("No credentials for DFS referral {ref_unc_path}: {e}");
+                        continue;
+                    }
+                },
+            };
+
             // Try opening the share. Log failure, and try next ref.
-            if let Err(e) = self.client._share_connect(ref_unc_path, &dfs_creds).await {
+            if let Err(e) = self.client._share_connect(ref_unc_path, &ref_creds).await {
                 log::error!("Failed to open DFS referral: {e}",);
                 continue;
             };
 
             let resource = self
                 .client
-                ._create_file(ref_unc_path, args)
+                ._create_file(ref_unc_path, Some(&ref_creds.identity()), args)
                 .await
                 .map_err(|e| {
                     log::error!("Failed to create file on DFS referral: {e}",);
@@ -793,12 +1317,19 @@ impl<'a> DfsResolver<'a> {
 
     /// Returns a list of DFS referral paths for the given input UNC path.
     #[maybe_async]
-    async fn get_dfs_refs(&self, unc: &UncPath) -> crate::Result<Vec<UncPath>> {
+    async fn get_dfs_refs(
+        &self,
+        unc: &UncPath,
+        identity: Option<&str>,
+    ) -> crate::Result<Vec<UncPath>> {
         log::debug!("Resolving DFS referral for {unc}");
         let dfs_path_string = unc.to_string();
 
         let dfs_refs = {
-            let dfs_root = &self.client.get_tree(unc).await?;
+            let dfs_root = self
+                .client
+                ._with_tree(unc, identity, |tree| Ok(tree.tree.clone()))
+                .await?;
             dfs_root
                 .as_dfs_tree()?
                 .dfs_get_referrals(&dfs_path_string)
@@ -915,3 +1446,285 @@ impl MultiChannelUtils {
         Ok(index_to_address)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{AuthMechanism, SecurityPolicy};
+    use crate::testing::{FakeReply, FakeServer, FakeServerConfig};
+    use smb_msg::Command;
+
+    const SERVER_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    /// Returns a client, connected to the fake server as if it were at [`SERVER_IP`].
+    #[maybe_async]
+    async fn connect_client(
+        server: &FakeServer,
+        config: ClientConfig,
+    ) -> (Client, Arc<Connection>) {
+        let client = Client::new(config);
+        let connection = server
+            .connect(client.config.connection.clone())
+            .await
+            .unwrap();
+        let connection = Arc::new(connection);
+        client
+            ._add_connection(connection.clone(), &SERVER_IP)
+            .await
+            .unwrap();
+        (client, connection)
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_reused_session_security_policy() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.on(Command::TreeConnect, |_| {
+            FakeReply::Error(Status::AccessDenied)
+        });
+        let config = ClientConfig {
+            security_policy: SecurityPolicy {
+                auth_mechanisms: Some(vec![AuthMechanism::Kerberos]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (client, connection) = connect_client(&server, config).await;
+
+        // A session the client has not checked, e.g. one set up again after a reconnection.
+        let credentials = Credentials::from(server.identity());
+        let session = connection.authenticate(credentials.clone()).await.unwrap();
+        let identity = credentials.identity();
+        client
+            ._with_connection(SERVER_IP, |c| {
+                c.sessions.insert(
+                    session.session_id(),
+                    ClientSessionInfo {
+                        session: Arc::new(session),
+                        identity,
+                        credentials_digest: credentials.digest(),
+                        session_alt_channels: None,
+                    },
+                );
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        // The session is rejected before connecting the tree.
+        let target = UncPath::from_str(&format!(r"\\{SERVER_IP}\share")).unwrap();
+        let result = client
+            .share_connect_with_credentials(&target, credentials)
+            .await;
+        assert!(matches!(result, Err(Error::SecurityPolicyViolation(_))));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_session_reuse_requires_same_credentials() {
+        let server = FakeServer::new(FakeServerConfig {
+            shares: vec!["share".to_string(), "other".to_string()],
+            ..Default::default()
+        });
+        let (client, _connection) = connect_client(&server, ClientConfig::default()).await;
+        let valid = Credentials::from(server.identity());
+        let mut wrong_password = server.identity();
+        wrong_password.password = "wrong".to_string().into();
+        let wrong_password = Credentials::from(wrong_password);
+        assert_eq!(valid.identity(), wrong_password.identity());
+
+        let share = UncPath::from_str(&format!(r"\\{SERVER_IP}\share")).unwrap();
+        let other = UncPath::from_str(&format!(r"\\{SERVER_IP}\other")).unwrap();
+        client
+            .share_connect_with_credentials(&share, valid.clone())
+            .await
+            .unwrap();
+
+        // Neither the connected share nor the session of the identity is used with a wrong password.
+        let result = client
+            .share_connect_with_credentials(&share, wrong_password.clone())
+            .await;
+        assert!(result.is_err());
+        let result = client
+            .share_connect_with_credentials(&other, wrong_password)
+            .await;
+        assert!(result.is_err());
+
+        // The valid credentials still share the session of the identity.
+        client
+            .share_connect_with_credentials(&other, valid)
+            .await
+            .unwrap();
+        let sessions = client
+            ._with_connection(SERVER_IP, |c| Ok(c.sessions.len()))
+            .await
+            .unwrap();
+        assert_eq!(sessions, 1);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_share_connected_as_several_identities() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let create_sessions = Arc::new(std::sync::Mutex::new(vec![]));
+        server.on(Command::Create, {
+            let create_sessions = create_sessions.clone();
+            move |request| {
+                create_sessions
+                    .lock()
+                    .unwrap()
+                    .push(request.header.session_id);
+                FakeReply::Default
+            }
+        });
+        let (client, _connection) = connect_client(&server, ClientConfig::default()).await;
+
+        // The fake server checks only the account name, so both identities are accepted.
+        let alice = Credentials::from(server.identity());
+        let bob = Credentials::password(r"OTHER\user", "password".to_string()).unwrap();
+        assert_ne!(alice.identity(), bob.identity());
+
+        let share = UncPath::from_str(&format!(r"\\{SERVER_IP}\share")).unwrap();
+        client
+            .share_connect_with_credentials(&share, alice.clone())
+            .await
+            .unwrap();
+        client
+            .share_connect_with_credentials(&share, bob.clone())
+            .await
+            .unwrap();
+
+        // Each identity has its own session.
+        let session_of = client
+            ._with_connection(SERVER_IP, |c| {
+                Ok(c.sessions
+                    .iter()
+                    .map(|(id, info)| (info.identity.clone(), *id))
+                    .collect::<HashMap<_, _>>())
+            })
+            .await
+            .unwrap();
+        assert_eq!(session_of.len(), 2);
+        let alice_session = session_of[&alice.identity()];
+        let bob_session = session_of[&bob.identity()];
+
+        // Without an identity, the share is ambiguous.
+        let file = share.clone().with_path("file.txt");
+        let args = FileCreateArgs::make_overwrite(Default::default(), Default::default());
+        let result = client.get_tree(&share).await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        let result = client.create_file(&file, &args).await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        assert!(create_sessions.lock().unwrap().is_empty());
+
+        // With an identity, its own tree and session are used.
+        let alice_tree = client.get_tree_as(&share, &alice.identity()).await.unwrap();
+        let bob_tree = client.get_tree_as(&share, &bob.identity()).await.unwrap();
+        assert!(!Arc::ptr_eq(&alice_tree, &bob_tree));
+
+        client
+            .create_file_as(&file, &bob.identity(), &args)
+            .await
+            .unwrap();
+        client
+            .create_file_as(&file, &alice.identity(), &args)
+            .await
+            .unwrap();
+        assert_eq!(
+            *create_sessions.lock().unwrap(),
+            [bob_session, alice_session]
+        );
+
+        let result = client.create_file_as(&file, "nobody", &args).await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_client_events() {
+        type Events = Arc<std::sync::Mutex<Vec<SmbEvent>>>;
+        fn recorder() -> (Events, impl Fn(&SmbEvent) + Send + Sync + 'static) {
+            let events = Events::default();
+            let listener = {
+                let events = events.clone();
+                move |event: &SmbEvent| events.lock().unwrap().push(event.clone())
+            };
+            (events, listener)
+        }
+
+        let server = FakeServer::new(FakeServerConfig::default());
+        let (configured, listener) = recorder();
+        let client = Client::new(ClientConfig {
+            connection: ConnectionConfig {
+                timeout: Some(std::time::Duration::from_secs(1)),
+                events: Some(EventListenerHandle::new(Arc::new(listener))),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let (first, listener) = recorder();
+        client.on_event(listener);
+        let (second, listener) = recorder();
+        client.on_event(listener);
+        #[cfg(feature = "async")]
+        let mut stream = client.events();
+
+        // Connected the way the client connects, reporting to the client's events.
+        let config = ConnectionConfig {
+            events: Some(EventListenerHandle::new(client.events.clone())),
+            ..client.config.connection.clone()
+        };
+        let (connection, faults) = server
+            .connect_with_faults(config, crate::testing::FaultRules::new())
+            .await
+            .unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let share = server.share_path("share");
+        let tree = session.tree_connect(&share).await.unwrap();
+        tree.disconnect().await.unwrap();
+
+        faults.disconnect();
+        let result = session.tree_connect(&share).await;
+        assert!(result.is_err());
+        for _ in 0..50 {
+            if connection.is_lost() {
+                break;
+            }
+            #[cfg(feature = "async")]
+            {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            #[cfg(not(feature = "async"))]
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(connection.is_lost());
+
+        // Connecting to the server again restores its connection, once.
+        client.events.connected(FakeServer::SERVER_NAME);
+        client.events.connected(FakeServer::SERVER_NAME);
+
+        let server_name = FakeServer::SERVER_NAME.to_string();
+        let events = configured.lock().unwrap().clone();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                SmbEvent::TreeDisconnected { by_server: false, .. },
+                SmbEvent::ConnectionLost { server: lost },
+                SmbEvent::ConnectionRestored { server: restored },
+            ] if *lost == server_name && *restored == server_name
+        ));
+        assert_eq!(*first.lock().unwrap(), events);
+        assert_eq!(*second.lock().unwrap(), events);
+
+        #[cfg(feature = "async")]
+        {
+            use tokio_stream::StreamExt;
+            for event in &events {
+                assert_eq!(stream.next().await.as_ref(), Some(event));
+            }
+
+            // Dropped streams are no longer fed.
+            drop(stream);
+            client.events.on_event(&SmbEvent::ConnectionLost {
+                server: server_name,
+            });
+            assert!(client.events.streams.lock().unwrap().is_empty());
+        }
+    }
+}
@@ -1,4 +1,21 @@
 //! Implements (de)compression logic.
+#[cfg(any(
+    feature = "compress_lznt1",
+    feature = "compress_lz77",
+    feature = "compress_lz77_huffman"
+))]
+mod lz;
+#[cfg(feature = "compress_lz77")]
+mod lz77;
+#[cfg(feature = "compress_lz77_huffman")]
+mod lz77_huffman;
+#[cfg(feature = "compress_lznt1")]
+mod lznt1;
+mod policy;
+
+pub(crate) use policy::CompressionTracker;
+pub use policy::{CompressionPolicy, CompressionStats};
+
 use binrw::prelude::*;
 #[cfg(feature = "compress_lz4")]
 use lz4_flex;
@@ -22,6 +39,20 @@ impl Decompressor {
         &self,
         original: &CompressedMessage,
     ) -> Result<(Response, Vec<u8>), CompressionError> {
+        let bytes = self.decompress_bytes(original)?;
+        let mut cursor = std::io::Cursor::new(&bytes);
+        Ok((
+            Response::read(&mut cursor)
+                .map_err(|_| CompressionError::InvalidDecompressedMessage)?,
+            bytes,
+        ))
+    }
+
+    /// Decompresses a compressed message, returning the raw, decompressed message bytes.
+    pub fn decompress_bytes(
+        &self,
+        original: &CompressedMessage,
+    ) -> Result<Vec<u8>, CompressionError> {
         let method: Box<dyn CompressionMethod> = match original {
             CompressedMessage::Unchained(_) => Box::new(UnchainedCompression),
             CompressedMessage::Chained(_) => {
@@ -32,29 +63,57 @@ impl Decompressor {
                 }
             }
         };
-        let bytes = method.decompress(original)?;
-        let mut cursor = std::io::Cursor::new(&bytes);
-        Ok((
-            Response::read(&mut cursor)
-                .map_err(|_| CompressionError::InvalidDecompressedMessage)?,
-            bytes,
-        ))
+        method.decompress(original)
     }
 }
 
 #[derive(Debug)]
 pub struct Compressor {
     caps: CompressionCapabilities,
+    max_ratio_percent: u8,
 }
 
 impl Compressor {
+    /// The default maximal size of a compressed message, in percents of its original size,
+    /// for it to be sent compressed.
+    pub const DEFAULT_MAX_RATIO_PERCENT: u8 = 90;
+
     pub fn new(caps: &CompressionCapabilities) -> Compressor {
-        Compressor { caps: caps.clone() }
+        Compressor {
+            caps: caps.clone(),
+            max_ratio_percent: Self::DEFAULT_MAX_RATIO_PERCENT,
+        }
     }
 
-    pub fn compress(&self, bytes: &[u8]) -> crate::Result<CompressedMessage> {
-        // TODO: Add Chained.
-        Ok(UnchainedCompression.compress(bytes, &self.caps.compression_algorithms)?)
+    /// Sets the maximal size of a compressed message, in percents of its original size,
+    /// for it to be sent compressed.
+    pub fn with_max_ratio_percent(mut self, max_ratio_percent: u8) -> Self {
+        self.max_ratio_percent = max_ratio_percent;
+        self
+    }
+
+    /// Compresses a message, given as its consecutive segments
+    /// (e.g. the message, followed by its additional data).
+    ///
+    /// Uses chained compression if negotiated, compressing each segment separately.
+    /// Returns `None` if the message does not compress well enough to be worth it.
+    pub fn compress(&self, segments: &[&[u8]]) -> crate::Result<Option<CompressedMessage>> {
+        let method: Box<dyn CompressionMethod> = if self.caps.flags.chained() {
+            Box::new(ChainedCompression)
+        } else {
+            Box::new(UnchainedCompression)
+        };
+        let compressed = match method.compress(segments, &self.caps.compression_algorithms) {
+            Ok(compressed) => compressed,
+            Err(CompressionError::NoSupportedCompressionAlgorithm) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let original_size = segments.iter().map(|s| s.len()).sum::<usize>();
+        if compressed.total_size() * 100 > original_size * self.max_ratio_percent as usize {
+            return Ok(None);
+        }
+        Ok(Some(compressed))
     }
 }
 
@@ -66,9 +125,10 @@ impl Compressor {
 trait CompressionMethod {
     fn decompress(&self, compressed: &CompressedMessage) -> Result<Vec<u8>, CompressionError>;
 
+    /// Compresses the data, given as its consecutive segments.
     fn compress(
         &self,
-        data: &[u8],
+        segments: &[&[u8]],
         algorithms: &[CompressionAlgorithm],
     ) -> Result<CompressedMessage, CompressionError>;
 
@@ -82,6 +142,14 @@ trait CompressionMethod {
             CompressionAlgorithm::PatternV1 => Box::new(PatternV1Compression),
             #[cfg(feature = "compress_lz4")]
             CompressionAlgorithm::LZ4 => Box::new(Lz4Compression),
+            #[cfg(feature = "compress_lznt1")]
+            CompressionAlgorithm::LZNT1 => Box::new(Lznt1Compression),
+            #[cfg(feature = "compress_lz77")]
+            CompressionAlgorithm::LZ77 => Box::new(Lz77Compression),
+            #[cfg(feature = "compress_lz77_huffman")]
+            CompressionAlgorithm::LZ77Huffman => Box::new(Lz77HuffmanCompression),
+            // Reachable unless all the compression features are enabled.
+            #[allow(unreachable_patterns)]
             _ => Err(CompressionError::UnsupportedAlgorithm(algo))?,
         })
     }
@@ -90,7 +158,17 @@ trait CompressionMethod {
 struct UnchainedCompression;
 
 impl UnchainedCompression {
-    pub const ALGORITHM_PRIORITY: [CompressionAlgorithm; 1] = [CompressionAlgorithm::LZ4];
+    /// The algorithms that may compress a whole message.
+    pub const ALGORITHMS: &[CompressionAlgorithm] = &[
+        #[cfg(feature = "compress_lz4")]
+        CompressionAlgorithm::LZ4,
+        #[cfg(feature = "compress_lz77")]
+        CompressionAlgorithm::LZ77,
+        #[cfg(feature = "compress_lz77_huffman")]
+        CompressionAlgorithm::LZ77Huffman,
+        #[cfg(feature = "compress_lznt1")]
+        CompressionAlgorithm::LZNT1,
+    ];
 }
 
 impl CompressionMethod for UnchainedCompression {
@@ -107,25 +185,31 @@ impl CompressionMethod for UnchainedCompression {
 
     fn compress(
         &self,
-        data: &[u8],
+        segments: &[&[u8]],
         allowed_algorithms: &[CompressionAlgorithm],
     ) -> Result<CompressedMessage, CompressionError> {
-        // Check what algos are supported.
-        for algo in Self::ALGORITHM_PRIORITY.iter() {
-            if !allowed_algorithms.contains(algo) {
-                continue;
-            }
-
-            let algo_impl = self.get_compression_algorithm(*algo)?;
-            let compressed = algo_impl.compress(data)?;
-            return Ok(CompressedMessage::Unchained(CompressedUnchainedMessage {
-                compression_algorithm: *algo,
-                data: compressed,
-                original_size: data.len() as u32,
-            }));
-        }
+        let algo = Self::select_algorithm(allowed_algorithms)
+            .ok_or(CompressionError::NoSupportedCompressionAlgorithm)?;
+        let data = segments.concat();
+        let compressed = self.get_compression_algorithm(algo)?.compress(&data)?;
+        Ok(CompressedMessage::Unchained(CompressedUnchainedMessage {
+            compression_algorithm: algo,
+            data: compressed,
+            original_size: data.len() as u32,
+        }))
+    }
+}
 
-        Err(CompressionError::NoSupportedCompressionAlgorithm)
+impl UnchainedCompression {
+    /// Returns the most preferred of the allowed algorithms that may compress a whole message.
+    /// The negotiated algorithms are ordered by preference.
+    fn select_algorithm(
+        allowed_algorithms: &[CompressionAlgorithm],
+    ) -> Option<CompressionAlgorithm> {
+        allowed_algorithms
+            .iter()
+            .find(|algo| Self::ALGORITHMS.contains(algo))
+            .copied()
     }
 }
 
@@ -148,6 +232,13 @@ impl CompressionMethod for ChainedCompression {
 
         for item in compressed.items.iter() {
             let len_before = data.len();
+            if let Some(original_size) = item.original_size {
+                if len_before + original_size as usize > compressed.original_size as usize {
+                    return Err(CompressionError::ChainedCompressionFailed(
+                        "Item size exceeds the expected size".to_string(),
+                    ))?;
+                }
+            }
             self.get_compression_algorithm(item.compression_algorithm)?
                 .decompress(&item.payload_data, item.original_size, &mut data)?;
             let len_after = data.len();
@@ -174,12 +265,90 @@ impl CompressionMethod for ChainedCompression {
         Ok(data)
     }
 
+    /// Compresses each segment separately: leading and trailing runs of a repeated byte are
+    /// sent as PatternV1 payloads, and the rest is compressed with the negotiated algorithm,
+    /// unless it does not shrink.
     fn compress(
         &self,
-        _data: &[u8],
-        _algorithms: &[CompressionAlgorithm],
+        segments: &[&[u8]],
+        allowed_algorithms: &[CompressionAlgorithm],
     ) -> Result<CompressedMessage, CompressionError> {
-        todo!()
+        let algo = UnchainedCompression::select_algorithm(allowed_algorithms);
+        let scan_patterns = cfg!(feature = "compress_pattern_v1")
+            && allowed_algorithms.contains(&CompressionAlgorithm::PatternV1);
+        if algo.is_none() && !scan_patterns {
+            return Err(CompressionError::NoSupportedCompressionAlgorithm);
+        }
+
+        let mut items = vec![];
+        for segment in segments.iter().filter(|s| !s.is_empty()) {
+            let mut middle = *segment;
+            let mut trailing = None;
+            if scan_patterns {
+                let leading = Self::run_length(middle.iter());
+                if leading >= Self::MIN_PATTERN_LENGTH {
+                    items
+                        .push(self.make_item(CompressionAlgorithm::PatternV1, &middle[..leading])?);
+                    middle = &middle[leading..];
+                }
+                let trailing_len = Self::run_length(middle.iter().rev());
+                if trailing_len >= Self::MIN_PATTERN_LENGTH {
+                    trailing = Some(&middle[middle.len() - trailing_len..]);
+                    middle = &middle[..middle.len() - trailing_len];
+                }
+            }
+
+            if !middle.is_empty() {
+                let compressed = algo
+                    .map(|algo| self.make_item(algo, middle))
+                    .transpose()?
+                    .filter(|item| item.payload_data.len() + 4 < middle.len());
+                let item = match compressed {
+                    Some(item) => item,
+                    None => self.make_item(CompressionAlgorithm::None, middle)?,
+                };
+                items.push(item);
+            }
+            if let Some(trailing) = trailing {
+                items.push(self.make_item(CompressionAlgorithm::PatternV1, trailing)?);
+            }
+        }
+        if let Some(first) = items.first_mut() {
+            first.flags = Self::FLAG_CHAINED;
+        }
+
+        Ok(CompressedMessage::Chained(CompressedChainedMessage {
+            original_size: segments.iter().map(|s| s.len()).sum::<usize>() as u32,
+            items,
+        }))
+    }
+}
+
+impl ChainedCompression {
+    /// The minimal length of a run of a repeated byte, to be sent as a PatternV1 payload.
+    const MIN_PATTERN_LENGTH: usize = 32;
+    /// SMB2_COMPRESSION_FLAG_CHAINED
+    const FLAG_CHAINED: u16 = 0x0001;
+
+    /// Returns the length of the run of the first byte.
+    fn run_length<'a>(mut data: impl Iterator<Item = &'a u8>) -> usize {
+        match data.next() {
+            Some(first) => 1 + data.take_while(|b| *b == first).count(),
+            None => 0,
+        }
+    }
+
+    fn make_item(
+        &self,
+        algo: CompressionAlgorithm,
+        data: &[u8],
+    ) -> Result<CompressedChainedItem, CompressionError> {
+        Ok(CompressedChainedItem {
+            compression_algorithm: algo,
+            flags: 0,
+            original_size: algo.original_size_required().then_some(data.len() as u32),
+            payload_data: self.get_compression_algorithm(algo)?.compress(data)?,
+        })
     }
 }
 
@@ -200,12 +369,22 @@ trait CompressionAlgorithmImpl {
     fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError>;
 }
 
+/// The supported compression algorithms, in the default order of preference.
+///
+/// The order is negotiated as is, and both peers compress with the first common algorithm,
+/// so the algorithms that compress well come first, and [`CompressionAlgorithm::None`] last.
 pub const SUPPORTED_ALGORITHMS: &[CompressionAlgorithm] = &[
-    CompressionAlgorithm::None,
-    #[cfg(feature = "compress_pattern_v1")]
-    CompressionAlgorithm::PatternV1,
     #[cfg(feature = "compress_lz4")]
     CompressionAlgorithm::LZ4,
+    #[cfg(feature = "compress_lz77")]
+    CompressionAlgorithm::LZ77,
+    #[cfg(feature = "compress_lz77_huffman")]
+    CompressionAlgorithm::LZ77Huffman,
+    #[cfg(feature = "compress_lznt1")]
+    CompressionAlgorithm::LZNT1,
+    #[cfg(feature = "compress_pattern_v1")]
+    CompressionAlgorithm::PatternV1,
+    CompressionAlgorithm::None,
 ];
 
 struct NoneCompression;
@@ -270,8 +449,19 @@ impl CompressionAlgorithmImpl for PatternV1Compression {
         Ok(())
     }
 
-    fn compress(&self, _data: &[u8]) -> Result<Vec<u8>, CompressionError> {
-        todo!()
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let pattern = *data.first().ok_or(CompressionError::PatternV1NotRepeated)?;
+        if data.iter().any(|&b| b != pattern) {
+            return Err(CompressionError::PatternV1NotRepeated);
+        }
+        let mut out = Vec::with_capacity(8);
+        PatternV1Payload {
+            pattern,
+            repetitions: data.len() as u32,
+        }
+        .write(&mut Cursor::new(&mut out))
+        .map_err(CompressionError::PatternV1InvalidPayload)?;
+        Ok(out)
     }
 }
 
@@ -290,11 +480,7 @@ impl CompressionAlgorithmImpl for Lz4Compression {
         out.resize(start_index + original_size.unwrap() as usize, 0);
 
         let size = lz4_flex::decompress_into(compressed, &mut out[start_index..])?;
-
-        if size != original_size.unwrap() as usize {
-            Err(CompressionError::PatternV1InvalidDecompressedSize)?;
-        }
-        Ok(())
+        check_decompressed_size(size, original_size)
     }
 
     fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
@@ -302,6 +488,82 @@ impl CompressionAlgorithmImpl for Lz4Compression {
     }
 }
 
+#[cfg(feature = "compress_lznt1")]
+struct Lznt1Compression;
+
+#[cfg(feature = "compress_lznt1")]
+impl CompressionAlgorithmImpl for Lznt1Compression {
+    fn decompress(
+        &self,
+        compressed: &[u8],
+        original_size: Option<u32>,
+        out: &mut Vec<u8>,
+    ) -> Result<(), CompressionError> {
+        let original_size = original_size.ok_or(CompressionError::InvalidCompressedMessage)?;
+        lznt1::decompress(compressed, original_size as usize, out)
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        Ok(lznt1::compress(data))
+    }
+}
+
+#[cfg(feature = "compress_lz77")]
+struct Lz77Compression;
+
+#[cfg(feature = "compress_lz77")]
+impl CompressionAlgorithmImpl for Lz77Compression {
+    fn decompress(
+        &self,
+        compressed: &[u8],
+        original_size: Option<u32>,
+        out: &mut Vec<u8>,
+    ) -> Result<(), CompressionError> {
+        let original_size = original_size.ok_or(CompressionError::InvalidCompressedMessage)?;
+        lz77::decompress(compressed, original_size as usize, out)
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        Ok(lz77::compress(data))
+    }
+}
+
+#[cfg(feature = "compress_lz77_huffman")]
+struct Lz77HuffmanCompression;
+
+#[cfg(feature = "compress_lz77_huffman")]
+impl CompressionAlgorithmImpl for Lz77HuffmanCompression {
+    fn decompress(
+        &self,
+        compressed: &[u8],
+        original_size: Option<u32>,
+        out: &mut Vec<u8>,
+    ) -> Result<(), CompressionError> {
+        // The stream has no end marker the decoder may rely on, so the size is required.
+        // It also bounds the output of the other LZ decoders.
+        let original_size = original_size.ok_or(CompressionError::InvalidCompressedMessage)?;
+        lz77_huffman::decompress(compressed, original_size as usize, out)
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        Ok(lz77_huffman::compress(data))
+    }
+}
+
+/// Checks that the size of the decompressed data matches the expected size, if known.
+#[cfg(feature = "compress_lz4")]
+fn check_decompressed_size(
+    size: usize,
+    original_size: Option<u32>,
+) -> Result<(), CompressionError> {
+    match original_size {
+        Some(original_size) if original_size as usize != size => {
+            Err(CompressionError::InvalidDecompressedSize)
+        }
+        _ => Ok(()),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum CompressionError {
     // --- General
@@ -317,6 +579,8 @@ pub enum CompressionError {
     UnsupportedCompressionMethod,
     #[error("There is no supported compression algorithm available.")]
     NoSupportedCompressionAlgorithm,
+    #[error("Decompressed size does not match the original size")]
+    InvalidDecompressedSize,
 
     // --- LZ4
     #[cfg(feature = "compress_lz4")]
@@ -333,6 +597,24 @@ pub enum CompressionError {
     #[cfg(feature = "compress_pattern_v1")]
     #[error("PatternV1 invalid decompressed size")]
     PatternV1InvalidDecompressedSize,
+    #[cfg(feature = "compress_pattern_v1")]
+    #[error("PatternV1 data is not a repeated byte")]
+    PatternV1NotRepeated,
+
+    // --- LZNT1
+    #[cfg(feature = "compress_lznt1")]
+    #[error("LZNT1 invalid compressed data")]
+    Lznt1InvalidData,
+
+    // --- LZ77
+    #[cfg(feature = "compress_lz77")]
+    #[error("LZ77 invalid compressed data")]
+    Lz77InvalidData,
+
+    // --- LZ77+Huffman
+    #[cfg(feature = "compress_lz77_huffman")]
+    #[error("LZ77+Huffman invalid compressed data")]
+    Lz77HuffmanInvalidData,
 }
 
 #[cfg(test)]
@@ -350,6 +632,242 @@ mod tests {
         assert_eq!(compressed, out);
     }
 
+    /// Returns test inputs: empty, short, repetitive, random, and large enough to span many chunks/blocks.
+    #[cfg(any(
+        feature = "compress_lznt1",
+        feature = "compress_lz77",
+        feature = "compress_lz77_huffman"
+    ))]
+    fn round_trip_inputs() -> Vec<Vec<u8>> {
+        use crate::testing::random_bytes;
+        let text = b"The quick brown fox jumps over the lazy dog. "
+            .iter()
+            .cycle()
+            .take(200_000)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut mixed = random_bytes(1, 70_000);
+        mixed.extend_from_slice(&text[..50_000]);
+        mixed.extend(std::iter::repeat_n(0xAB, 300_000));
+        mixed.extend(random_bytes(2, 1000));
+        vec![
+            vec![],
+            vec![7],
+            b"abcabcabcabcabcabc".to_vec(),
+            vec![0; 100_000],
+            random_bytes(3, 20_000),
+            text,
+            mixed,
+        ]
+    }
+
+    #[cfg(any(
+        feature = "compress_lznt1",
+        feature = "compress_lz77",
+        feature = "compress_lz77_huffman"
+    ))]
+    fn test_round_trip(algorithm: CompressionAlgorithm) {
+        let algorithm = UnchainedCompression
+            .get_compression_algorithm(algorithm)
+            .unwrap();
+        for data in round_trip_inputs() {
+            let compressed = algorithm.compress(&data).unwrap();
+            if data.len() > 1000 && data.iter().all(|&b| b == data[0]) {
+                assert!(compressed.len() < data.len() / 50);
+            }
+            let mut out = vec![];
+            algorithm
+                .decompress(&compressed, Some(data.len() as u32), &mut out)
+                .unwrap();
+            assert_eq!(out.len(), data.len());
+            assert!(out == data);
+        }
+    }
+
+    #[cfg(feature = "compress_lznt1")]
+    #[test]
+    pub fn test_lznt1_round_trip() {
+        test_round_trip(CompressionAlgorithm::LZNT1);
+    }
+
+    #[cfg(feature = "compress_lz77")]
+    #[test]
+    pub fn test_lz77_round_trip() {
+        test_round_trip(CompressionAlgorithm::LZ77);
+    }
+
+    #[cfg(feature = "compress_lz77_huffman")]
+    #[test]
+    pub fn test_lz77_huffman_round_trip() {
+        test_round_trip(CompressionAlgorithm::LZ77Huffman);
+    }
+
+    /// Sample from MS-XCA 3.2: "abc" repeated 100 times.
+    #[cfg(feature = "compress_lz77")]
+    #[test]
+    pub fn test_lz77_decompression() {
+        let compressed = [
+            0xff, 0xff, 0xff, 0x1f, 0x61, 0x62, 0x63, 0x17, 0x00, 0x0f, 0xff, 0x26, 0x01,
+        ];
+        let mut out = vec![];
+        Lz77Compression
+            .decompress(&compressed, Some(300), &mut out)
+            .unwrap();
+        assert_eq!(out, b"abc".repeat(100));
+    }
+
+    #[cfg(feature = "compress_lz77")]
+    #[test]
+    pub fn test_lz77_decompression_exceeding_original_size() {
+        let compressed = [
+            0xff, 0xff, 0xff, 0x1f, 0x61, 0x62, 0x63, 0x17, 0x00, 0x0f, 0xff, 0x26, 0x01,
+        ];
+        for original_size in [100, 301] {
+            let mut out = vec![];
+            let result = Lz77Compression.decompress(&compressed, Some(original_size), &mut out);
+            assert!(matches!(
+                result,
+                Err(CompressionError::InvalidDecompressedSize)
+            ));
+            assert!(out.len() <= original_size as usize);
+        }
+    }
+
+    /// Sample from MS-XCA 3.1: the alphabet, compressed with LZ77+Huffman.
+    #[cfg(feature = "compress_lz77_huffman")]
+    #[test]
+    pub fn test_lz77_huffman_decompression() {
+        let mut compressed = vec![0; 256];
+        compressed[0x30] = 0x50;
+        compressed[0x31..0x3b].fill(0x55);
+        compressed[0x3b..0x3e].copy_from_slice(&[0x45, 0x44, 0x04]);
+        compressed[0x80] = 0x04;
+        compressed.extend_from_slice(&[
+            0xd8, 0x52, 0x3e, 0xd7, 0x94, 0x11, 0x5b, 0xe9, 0x19, 0x5f, 0xf9, 0xd6, 0x7c, 0xdf,
+            0x8d, 0x04, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        let mut out = vec![];
+        Lz77HuffmanCompression
+            .decompress(&compressed, Some(26), &mut out)
+            .unwrap();
+        assert_eq!(out, b"abcdefghijklmnopqrstuvwxyz");
+
+        // The output stops at the original size, even before the end-of-stream symbol.
+        let mut out = vec![];
+        Lz77HuffmanCompression
+            .decompress(&compressed, Some(20), &mut out)
+            .unwrap();
+        assert_eq!(out, b"abcdefghijklmnopqrst");
+    }
+
+    /// Chunks assembled according to MS-XCA 2.5.
+    #[cfg(feature = "compress_lznt1")]
+    #[test]
+    pub fn test_lznt1_decompression() {
+        let alphabet = b"abcdefghijklmnopqrstuvwxyz";
+        let mut uncompressed = vec![0x19, 0x30];
+        uncompressed.extend_from_slice(alphabet);
+        // After 16 bytes of the chunk, the offset takes 11 bits of the token.
+        let mut compressed = vec![0x1f, 0xb0];
+        for (i, literals) in alphabet.chunks(8).enumerate() {
+            compressed.push(if i == 3 { 0x04 } else { 0x00 });
+            compressed.extend_from_slice(literals);
+        }
+        compressed.extend_from_slice(&[0x17, 0xc8]);
+        let cases = [
+            (uncompressed, alphabet.to_vec()),
+            (compressed, alphabet.repeat(2)),
+            (
+                vec![0x05, 0xb0, 0x08, 0x61, 0x62, 0x63, 0x26, 0x21],
+                b"abc".repeat(100),
+            ),
+        ];
+        for (compressed, expected) in cases {
+            let mut out = vec![];
+            Lznt1Compression
+                .decompress(&compressed, Some(expected.len() as u32), &mut out)
+                .unwrap();
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[cfg(feature = "compress_lznt1")]
+    #[test]
+    pub fn test_lznt1_decompression_exceeding_chunk_size() {
+        // "abc", then a match of 4098 bytes: the last chunk represents more than 4096 bytes.
+        let compressed = [0x05, 0xb0, 0x08, 0x61, 0x62, 0x63, 0xff, 0x2f];
+        let mut out = vec![];
+        let result = Lznt1Compression.decompress(&compressed, Some(0x2000), &mut out);
+        assert!(matches!(result, Err(CompressionError::Lznt1InvalidData)));
+
+        let compressed = [0x05, 0xb0, 0x08, 0x61, 0x62, 0x63, 0x26, 0x21];
+        let mut out = vec![];
+        let result = Lznt1Compression.decompress(&compressed, Some(100), &mut out);
+        assert!(matches!(
+            result,
+            Err(CompressionError::InvalidDecompressedSize)
+        ));
+        assert!(out.len() <= 100);
+    }
+
+    #[cfg(all(feature = "compress_pattern_v1", feature = "compress_lz77"))]
+    #[test]
+    pub fn test_chained_compression() {
+        let caps = CompressionCapabilities {
+            flags: CompressionCapsFlags::new().with_chained(true),
+            compression_algorithms: vec![
+                CompressionAlgorithm::LZ77,
+                CompressionAlgorithm::PatternV1,
+            ],
+        };
+        let header = (0..100u8).collect::<Vec<_>>();
+        let mut payload = vec![0; 0x4000];
+        payload.extend(b"database page ".iter().cycle().take(0x1000));
+        payload.extend(std::iter::repeat_n(0xFF, 0x2000));
+
+        let compressed = Compressor::new(&caps)
+            .compress(&[&header, &payload])
+            .unwrap()
+            .unwrap();
+        let CompressedMessage::Chained(chained) = &compressed else {
+            panic!("Expected chained message");
+        };
+        let algorithms = chained
+            .items
+            .iter()
+            .map(|i| i.compression_algorithm)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            algorithms,
+            [
+                CompressionAlgorithm::None,
+                CompressionAlgorithm::PatternV1,
+                CompressionAlgorithm::LZ77,
+                CompressionAlgorithm::PatternV1
+            ]
+        );
+        assert!(compressed.total_size() < 0x200);
+
+        let decompressed = Decompressor::new(&caps)
+            .decompress_bytes(&compressed)
+            .unwrap();
+        assert_eq!(decompressed, [header, payload].concat());
+    }
+
+    #[cfg(feature = "compress_lz77")]
+    #[test]
+    pub fn test_incompressible_message_is_not_compressed() {
+        let caps = CompressionCapabilities {
+            flags: CompressionCapsFlags::new(),
+            compression_algorithms: vec![CompressionAlgorithm::LZ77],
+        };
+        let data = crate::testing::random_bytes(1, 0x1000);
+        let compressor = Compressor::new(&caps);
+        assert!(compressor.compress(&[&data]).unwrap().is_none());
+        let compressor = Compressor::new(&caps).with_max_ratio_percent(100);
+        assert!(compressor.compress(&[&data]).unwrap().is_none());
+    }
+
     #[cfg(feature = "compress_pattern_v1")]
     #[test]
     pub fn test_pattern_v1_algorithm_decompression() {
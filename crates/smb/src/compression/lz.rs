@@ -0,0 +1,116 @@
+//! Building blocks shared by the LZ-based compression algorithms.
+
+#[cfg(feature = "compress_lz77")]
+use super::CompressionError;
+
+/// Finds the longest previous occurrences of the data at a position, using hash chains of 3-byte prefixes.
+pub(super) struct MatchFinder<'a> {
+    data: &'a [u8],
+    /// The last position of each hash, plus one (0 means none).
+    head: Vec<u32>,
+    /// The previous position with the same hash as each position, plus one (0 means none).
+    prev: Vec<u32>,
+    max_chain: usize,
+}
+
+impl<'a> MatchFinder<'a> {
+    const HASH_BITS: u32 = 15;
+    pub const MIN_MATCH: usize = 3;
+    /// The default maximal number of candidates to check for each position.
+    pub const DEFAULT_MAX_CHAIN: usize = 32;
+
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            head: vec![0; 1 << Self::HASH_BITS],
+            prev: vec![0; data.len()],
+            max_chain: Self::DEFAULT_MAX_CHAIN,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let value = u32::from_le_bytes([self.data[pos], self.data[pos + 1], self.data[pos + 2], 0]);
+        (value.wrapping_mul(0x9E37_79B1) >> (32 - Self::HASH_BITS)) as usize
+    }
+
+    /// Makes the position available as a match source for later positions.
+    /// Positions must be inserted in increasing order.
+    pub fn insert(&mut self, pos: usize) {
+        if pos + Self::MIN_MATCH > self.data.len() {
+            return;
+        }
+        let hash = self.hash(pos);
+        self.prev[pos] = self.head[hash];
+        self.head[hash] = pos as u32 + 1;
+    }
+
+    /// Finds the longest match for the data at `pos`, starting no earlier than `window_start`,
+    /// of at most `max_len` bytes. Returns `(offset, length)`, where offset is the distance back from `pos`.
+    ///
+    /// Matches may overlap `pos`. Only positions inserted so far are considered.
+    pub fn find(&self, pos: usize, window_start: usize, max_len: usize) -> Option<(usize, usize)> {
+        let max_len = max_len.min(self.data.len() - pos);
+        if max_len < Self::MIN_MATCH {
+            return None;
+        }
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..self.max_chain {
+            if candidate == 0 {
+                break;
+            }
+            let source = candidate as usize - 1;
+            if source < window_start || source >= pos {
+                break;
+            }
+            let length = self.data[source..]
+                .iter()
+                .zip(&self.data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length >= Self::MIN_MATCH && best.is_none_or(|(_, l)| length > l) {
+                best = Some((pos - source, length));
+                if length == max_len {
+                    break;
+                }
+            }
+            candidate = self.prev[source];
+        }
+        best
+    }
+}
+
+/// A little-endian reader over the compressed data.
+#[cfg(feature = "compress_lz77")]
+pub(super) struct Reader<'a> {
+    pub data: &'a [u8],
+    pub position: usize,
+}
+
+#[cfg(feature = "compress_lz77")]
+impl Reader<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CompressionError> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + N)
+            .ok_or(CompressionError::InvalidCompressedMessage)?;
+        self.position += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, CompressionError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, CompressionError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, CompressionError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+}
@@ -0,0 +1,184 @@
+//! The Plain LZ77 compression algorithm.
+//!
+//! Reference: MS-XCA 2.3, 2.4
+
+use super::CompressionError;
+use super::lz::{MatchFinder, Reader};
+
+/// The maximal distance of a match.
+const MAX_OFFSET: usize = 1 << 13;
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    let mut finder = MatchFinder::new(data);
+
+    let mut flags = 0u32;
+    let mut flag_count = 0;
+    let mut flags_position = 0;
+    out.extend_from_slice(&[0; 4]);
+    // The position of a byte whose high nibble is still free to hold a match length.
+    let mut last_length_half_byte = None;
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let window_start = pos.saturating_sub(MAX_OFFSET);
+        let advance = match finder.find(pos, window_start, u32::MAX as usize) {
+            Some((offset, length)) => {
+                write_match(&mut out, offset, length, &mut last_length_half_byte);
+                flags = (flags << 1) | 1;
+                length
+            }
+            None => {
+                out.push(data[pos]);
+                flags <<= 1;
+                1
+            }
+        };
+        flag_count += 1;
+        for p in pos..pos + advance {
+            finder.insert(p);
+        }
+        pos += advance;
+
+        if flag_count == 32 {
+            out[flags_position..flags_position + 4].copy_from_slice(&flags.to_le_bytes());
+            flag_count = 0;
+            flags_position = out.len();
+            out.extend_from_slice(&[0; 4]);
+        }
+    }
+
+    // The remaining flag bits are set, so the decoder stops when running out of input.
+    let free = 32 - flag_count;
+    let flags = (((flags as u64) << free) | ((1u64 << free) - 1)) as u32;
+    out[flags_position..flags_position + 4].copy_from_slice(&flags.to_le_bytes());
+    out
+}
+
+fn write_match(
+    out: &mut Vec<u8>,
+    offset: usize,
+    length: usize,
+    last_length_half_byte: &mut Option<usize>,
+) {
+    let offset = (offset - 1) as u16;
+    let length = length - 3;
+    if length < 7 {
+        out.extend_from_slice(&((offset << 3) | length as u16).to_le_bytes());
+        return;
+    }
+    out.extend_from_slice(&((offset << 3) | 7).to_le_bytes());
+
+    let remaining = length - 7;
+    let nibble = remaining.min(15) as u8;
+    match last_length_half_byte.take() {
+        Some(position) => out[position] |= nibble << 4,
+        None => {
+            *last_length_half_byte = Some(out.len());
+            out.push(nibble);
+        }
+    }
+    if remaining < 15 {
+        return;
+    }
+    let remaining = remaining - 15;
+    if remaining < 255 {
+        out.push(remaining as u8);
+        return;
+    }
+    out.push(255);
+    if length < (1 << 16) {
+        out.extend_from_slice(&(length as u16).to_le_bytes());
+    } else {
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&(length as u32).to_le_bytes());
+    }
+}
+
+/// Decompresses the data, appending at most `original_size` bytes to `out`.
+pub fn decompress(
+    compressed: &[u8],
+    original_size: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), CompressionError> {
+    let mut input = Reader {
+        data: compressed,
+        position: 0,
+    };
+    let mut flags = 0u32;
+    let mut flag_count = 0;
+    let mut last_length_half_byte = None;
+    let start = out.len();
+    let target = start + original_size;
+    let check_size = |size: usize| {
+        if size > target {
+            return Err(CompressionError::InvalidDecompressedSize);
+        }
+        Ok(())
+    };
+
+    loop {
+        if flag_count == 0 {
+            if input.is_empty() {
+                break;
+            }
+            flags = input.read_u32()?;
+            flag_count = 32;
+        }
+        flag_count -= 1;
+
+        if flags & (1 << flag_count) == 0 {
+            if input.is_empty() {
+                break;
+            }
+            check_size(out.len() + 1)?;
+            out.push(input.read_u8()?);
+            continue;
+        }
+        if input.is_empty() {
+            break;
+        }
+
+        let match_bytes = input.read_u16()? as usize;
+        let offset = (match_bytes >> 3) + 1;
+        let mut length = match_bytes & 7;
+        if length == 7 {
+            length = match last_length_half_byte.take() {
+                Some(position) => (compressed[position] >> 4) as usize,
+                None => {
+                    last_length_half_byte = Some(input.position);
+                    (input.read_u8()? & 0xF) as usize
+                }
+            };
+            if length == 15 {
+                length = input.read_u8()? as usize;
+                if length == 255 {
+                    length = input.read_u16()? as usize;
+                    if length == 0 {
+                        length = input.read_u32()? as usize;
+                    }
+                    if length < 15 + 7 {
+                        return Err(CompressionError::Lz77InvalidData);
+                    }
+                    length -= 15 + 7;
+                }
+                length += 15;
+            }
+            length += 7;
+        }
+        length += 3;
+
+        if offset > out.len() - start {
+            return Err(CompressionError::Lz77InvalidData);
+        }
+        check_size(out.len() + length)?;
+        let source = out.len() - offset;
+        for k in 0..length {
+            out.push(out[source + k]);
+        }
+    }
+    if out.len() != target {
+        return Err(CompressionError::InvalidDecompressedSize);
+    }
+    Ok(())
+}
@@ -0,0 +1,396 @@
+//! The LZ77+Huffman compression algorithm.
+//!
+//! Reference: MS-XCA 2.1, 2.2
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use super::CompressionError;
+use super::lz::MatchFinder;
+
+/// The size of the uncompressed data encoded by each block.
+const BLOCK_SIZE: usize = 1 << 16;
+/// The number of symbols: 256 literals, and 256 match symbols.
+const SYMBOLS: usize = 512;
+/// The size of the code lengths table at the start of each block.
+const TABLE_SIZE: usize = SYMBOLS / 2;
+const MAX_CODE_LENGTH: u8 = 15;
+/// The maximal distance of a match.
+const MAX_OFFSET: usize = (1 << 16) - 1;
+/// The end-of-stream symbol, written at the end of the last block.
+const EOF_SYMBOL: u16 = 256;
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { offset: usize, length: usize },
+}
+
+impl Token {
+    fn symbol(&self) -> u16 {
+        match *self {
+            Token::Literal(byte) => byte as u16,
+            Token::Match { offset, length } => {
+                256 + ((offset_bit_length(offset) as u16) << 4) + (length - 3).min(15) as u16
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => length,
+        }
+    }
+}
+
+fn offset_bit_length(offset: usize) -> u32 {
+    usize::BITS - 1 - offset.leading_zeros()
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let tokens = parse(data);
+    let mut out = Vec::with_capacity(data.len() / 2 + TABLE_SIZE + 16);
+
+    let mut remaining = tokens.as_slice();
+    loop {
+        // A block encodes the tokens starting before its end.
+        let mut produced = 0;
+        let count = remaining
+            .iter()
+            .take_while(|token| {
+                let included = produced < BLOCK_SIZE;
+                produced += token.len();
+                included
+            })
+            .count();
+        let (block, rest) = remaining.split_at(count);
+        remaining = rest;
+        let last = remaining.is_empty();
+        write_block(block, last, &mut out);
+        if last {
+            return out;
+        }
+    }
+}
+
+/// Parses the data into literals and matches.
+fn parse(data: &[u8]) -> Vec<Token> {
+    let mut finder = MatchFinder::new(data);
+    let mut tokens = Vec::with_capacity(data.len() / 2);
+    let mut pos = 0;
+    while pos < data.len() {
+        let window_start = pos.saturating_sub(MAX_OFFSET);
+        let token = match finder.find(pos, window_start, u32::MAX as usize) {
+            Some((offset, length)) => Token::Match { offset, length },
+            None => Token::Literal(data[pos]),
+        };
+        for p in pos..pos + token.len() {
+            finder.insert(p);
+        }
+        pos += token.len();
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn write_block(tokens: &[Token], last: bool, out: &mut Vec<u8>) {
+    let mut frequencies = [0u32; SYMBOLS];
+    for token in tokens {
+        frequencies[token.symbol() as usize] += 1;
+    }
+    if last {
+        frequencies[EOF_SYMBOL as usize] += 1;
+    }
+    let lengths = code_lengths(&frequencies);
+    let codes = canonical_codes(&lengths);
+
+    for pair in lengths.chunks(2) {
+        out.push(pair[0] | (pair[1] << 4));
+    }
+
+    let mut writer = BitWriter::new(out);
+    for token in tokens {
+        let symbol = token.symbol() as usize;
+        writer.write_bits(codes[symbol] as u32, lengths[symbol] as u32);
+        if let Token::Match { offset, length } = *token {
+            let length = length - 3;
+            if length >= 15 {
+                if length - 15 < 255 {
+                    writer.write_byte((length - 15) as u8);
+                } else {
+                    writer.write_byte(255);
+                    if length < (1 << 16) {
+                        writer.write_bytes(&(length as u16).to_le_bytes());
+                    } else {
+                        writer.write_bytes(&[0, 0]);
+                        writer.write_bytes(&(length as u32).to_le_bytes());
+                    }
+                }
+            }
+            let bit_length = offset_bit_length(offset);
+            writer.write_bits((offset - (1 << bit_length)) as u32, bit_length);
+        }
+    }
+    if last {
+        let symbol = EOF_SYMBOL as usize;
+        writer.write_bits(codes[symbol] as u32, lengths[symbol] as u32);
+    }
+    writer.finish();
+}
+
+/// Computes the Huffman code lengths of the symbols, limited to [`MAX_CODE_LENGTH`] bits.
+fn code_lengths(frequencies: &[u32; SYMBOLS]) -> [u8; SYMBOLS] {
+    let mut lengths = [0u8; SYMBOLS];
+    let used = (0..SYMBOLS)
+        .filter(|&s| frequencies[s] > 0)
+        .collect::<Vec<_>>();
+    if used.len() == 1 {
+        lengths[used[0]] = 1;
+        return lengths;
+    }
+
+    let mut weights = used.iter().map(|&s| frequencies[s]).collect::<Vec<_>>();
+    loop {
+        // Build the tree: leaves are 0..used.len(), followed by the internal nodes.
+        let mut parents = vec![usize::MAX; used.len()];
+        let mut heap = weights
+            .iter()
+            .enumerate()
+            .map(|(node, &weight)| Reverse((weight as u64, node)))
+            .collect::<BinaryHeap<_>>();
+        while heap.len() > 1 {
+            let Reverse((weight1, node1)) = heap.pop().unwrap();
+            let Reverse((weight2, node2)) = heap.pop().unwrap();
+            let parent = parents.len();
+            parents.push(usize::MAX);
+            parents[node1] = parent;
+            parents[node2] = parent;
+            heap.push(Reverse((weight1 + weight2, parent)));
+        }
+
+        let mut depths = vec![0u8; parents.len()];
+        for node in (0..parents.len()).rev() {
+            if parents[node] != usize::MAX {
+                depths[node] = depths[parents[node]] + 1;
+            }
+        }
+        if depths[..used.len()].iter().all(|&d| d <= MAX_CODE_LENGTH) {
+            for (i, &symbol) in used.iter().enumerate() {
+                lengths[symbol] = depths[i];
+            }
+            return lengths;
+        }
+        // Flatten the distribution, until the tree is shallow enough.
+        for weight in weights.iter_mut() {
+            *weight = (*weight >> 1) | 1;
+        }
+    }
+}
+
+/// Assigns canonical codes: shorter codes first, and by symbol order for codes of the same length.
+fn canonical_codes(lengths: &[u8; SYMBOLS]) -> [u16; SYMBOLS] {
+    let mut codes = [0u16; SYMBOLS];
+    let mut code = 0u32;
+    for length in 1..=MAX_CODE_LENGTH {
+        for symbol in 0..SYMBOLS {
+            if lengths[symbol] == length {
+                codes[symbol] = code as u16;
+                code += 1;
+            }
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+/// Writes bits in 16-bit little-endian words, most significant bit first.
+///
+/// Bytes written in between (match lengths) are placed after the next two words,
+/// which are reserved in advance, since the decoder reads two words ahead.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    slot1: usize,
+    slot2: usize,
+    bits: u32,
+    free: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        let slot1 = out.len();
+        out.extend_from_slice(&[0; 4]);
+        Self {
+            out,
+            slot1,
+            slot2: slot1 + 2,
+            bits: 0,
+            free: 16,
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        if count == 0 {
+            return;
+        }
+        if count <= self.free {
+            self.free -= count;
+            self.bits |= value << self.free;
+            return;
+        }
+        let overflow = count - self.free;
+        self.bits |= value >> overflow;
+        self.write_word();
+        self.slot1 = self.slot2;
+        self.slot2 = self.out.len();
+        self.out.extend_from_slice(&[0; 2]);
+        self.free = 16 - overflow;
+        self.bits = (value << self.free) & 0xFFFF;
+    }
+
+    fn write_word(&mut self) {
+        let word = (self.bits as u16).to_le_bytes();
+        self.out[self.slot1..self.slot1 + 2].copy_from_slice(&word);
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.out.push(byte);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn finish(mut self) {
+        self.write_word();
+    }
+}
+
+/// Decompresses the data, appending exactly `original_size` bytes to `out`.
+pub fn decompress(
+    compressed: &[u8],
+    original_size: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), CompressionError> {
+    let start = out.len();
+    let target = start + original_size;
+    let word = |position: usize| -> u32 {
+        compressed
+            .get(position..position + 2)
+            .map_or(0, |w| u16::from_le_bytes([w[0], w[1]]) as u32)
+    };
+
+    let mut position = 0;
+    while out.len() < target {
+        let table = compressed
+            .get(position..position + TABLE_SIZE)
+            .ok_or(CompressionError::Lz77HuffmanInvalidData)?;
+        let mut lengths = [0u8; SYMBOLS];
+        for (i, byte) in table.iter().enumerate() {
+            lengths[2 * i] = byte & 0xF;
+            lengths[2 * i + 1] = byte >> 4;
+        }
+        let decoding_table = decoding_table(&lengths)?;
+
+        position += TABLE_SIZE;
+        let mut next_bits = (word(position) << 16) | word(position + 2);
+        position += 4;
+        let mut extra_bits = 16i32;
+        let mut consume = |next_bits: &mut u32, count: u32, position: &mut usize| {
+            if count == 0 {
+                return;
+            }
+            *next_bits <<= count;
+            extra_bits -= count as i32;
+            if extra_bits < 0 {
+                *next_bits |= word(*position) << (-extra_bits);
+                extra_bits += 16;
+                *position += 2;
+            }
+        };
+
+        let block_end = out.len() + BLOCK_SIZE;
+        while out.len() < block_end && out.len() < target {
+            let symbol = decoding_table[(next_bits >> (32 - MAX_CODE_LENGTH)) as usize];
+            if symbol == u16::MAX {
+                return Err(CompressionError::Lz77HuffmanInvalidData);
+            }
+            consume(
+                &mut next_bits,
+                lengths[symbol as usize] as u32,
+                &mut position,
+            );
+            if symbol < 256 {
+                out.push(symbol as u8);
+                continue;
+            }
+
+            let symbol = symbol as usize - 256;
+            let mut length = symbol & 0xF;
+            let offset_bit_length = (symbol >> 4) as u32;
+            if length == 15 {
+                let mut read = |size: usize| -> Result<usize, CompressionError> {
+                    let bytes = compressed
+                        .get(position..position + size)
+                        .ok_or(CompressionError::Lz77HuffmanInvalidData)?;
+                    position += size;
+                    Ok(bytes
+                        .iter()
+                        .rev()
+                        .fold(0usize, |value, &byte| (value << 8) | byte as usize))
+                };
+                length = read(1)?;
+                if length == 255 {
+                    length = read(2)?;
+                    if length == 0 {
+                        length = read(4)?;
+                    }
+                    if length < 15 {
+                        return Err(CompressionError::Lz77HuffmanInvalidData);
+                    }
+                    length -= 15;
+                }
+                length += 15;
+            }
+            length += 3;
+
+            let offset_bits = if offset_bit_length == 0 {
+                0
+            } else {
+                next_bits >> (32 - offset_bit_length)
+            };
+            let offset = (1usize << offset_bit_length) + offset_bits as usize;
+            consume(&mut next_bits, offset_bit_length, &mut position);
+
+            if offset > out.len() - start {
+                return Err(CompressionError::Lz77HuffmanInvalidData);
+            }
+            if out.len() + length > target {
+                return Err(CompressionError::InvalidDecompressedSize);
+            }
+            let source = out.len() - offset;
+            for k in 0..length {
+                out.push(out[source + k]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds a table mapping the next 15 bits of the stream to the symbol they start with.
+/// Entries that start with no valid code are `u16::MAX`.
+fn decoding_table(lengths: &[u8; SYMBOLS]) -> Result<Vec<u16>, CompressionError> {
+    let mut table = vec![u16::MAX; 1 << MAX_CODE_LENGTH];
+    let mut next = 0usize;
+    for length in 1..=MAX_CODE_LENGTH {
+        let span = 1usize << (MAX_CODE_LENGTH - length);
+        for (symbol, _) in lengths.iter().enumerate().filter(|(_, l)| **l == length) {
+            let entries = table
+                .get_mut(next..next + span)
+                .ok_or(CompressionError::Lz77HuffmanInvalidData)?;
+            entries.fill(symbol as u16);
+            next += span;
+        }
+    }
+    Ok(table)
+}
@@ -0,0 +1,220 @@
+//! The LZNT1 compression algorithm.
+//!
+//! Reference: MS-XCA 2.5
+
+use super::CompressionError;
+use super::lz::MatchFinder;
+
+/// The size of the uncompressed data represented by each chunk.
+const CHUNK_SIZE: usize = 4096;
+/// Chunk header: the chunk is compressed.
+const CHUNK_COMPRESSED: u16 = 0x8000;
+/// Chunk header: the signature bits, always 0b011.
+const CHUNK_SIGNATURE: u16 = 0x3000;
+const CHUNK_SIZE_MASK: u16 = 0x0FFF;
+
+/// Returns the length mask and offset shift of a phrase token, at a position within a chunk.
+///
+/// The more data precedes the token in the chunk, the more bits are used for the offset.
+fn token_format(position: usize) -> (u16, u32) {
+    let mut length_mask = 0x0FFFu16;
+    let mut offset_shift = 12;
+    let mut i = position - 1;
+    while i >= 0x10 {
+        length_mask >>= 1;
+        offset_shift -= 1;
+        i >>= 1;
+    }
+    (length_mask, offset_shift)
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    let mut finder = MatchFinder::new(data);
+    for chunk_start in (0..data.len()).step_by(CHUNK_SIZE) {
+        let chunk_end = (chunk_start + CHUNK_SIZE).min(data.len());
+        let compressed = compress_chunk(data, chunk_start, chunk_end, &mut finder);
+        match compressed {
+            Some(compressed) => {
+                let header = CHUNK_COMPRESSED | CHUNK_SIGNATURE | (compressed.len() + 2 - 3) as u16;
+                out.extend_from_slice(&header.to_le_bytes());
+                out.extend_from_slice(&compressed);
+            }
+            None => {
+                let raw = &data[chunk_start..chunk_end];
+                let header = CHUNK_SIGNATURE | (raw.len() + 2 - 3) as u16;
+                out.extend_from_slice(&header.to_le_bytes());
+                out.extend_from_slice(raw);
+            }
+        }
+    }
+    out
+}
+
+/// Compresses a single chunk, returning `None` if compression does not reduce its size.
+fn compress_chunk(
+    data: &[u8],
+    chunk_start: usize,
+    chunk_end: usize,
+    finder: &mut MatchFinder,
+) -> Option<Vec<u8>> {
+    let chunk_len = chunk_end - chunk_start;
+    let mut out = Vec::with_capacity(chunk_len + chunk_len / 8 + 1);
+    let mut flags_position = 0;
+    let mut flag_count = 8;
+    let mut pos = chunk_start;
+    while pos < chunk_end {
+        if flag_count == 8 {
+            flags_position = out.len();
+            out.push(0);
+            flag_count = 0;
+        }
+        let position = pos - chunk_start;
+        let found = if position > 0 {
+            let (length_mask, offset_shift) = token_format(position);
+            let max_offset = (0xFFFF >> offset_shift) + 1;
+            let max_len = length_mask as usize + 3;
+            let window_start = pos.saturating_sub(max_offset).max(chunk_start);
+            finder
+                .find(pos, window_start, max_len.min(chunk_end - pos))
+                .map(|(offset, length)| (offset, length, length_mask, offset_shift))
+        } else {
+            None
+        };
+        let advance = match found {
+            Some((offset, length, _, offset_shift)) => {
+                let token = (((offset - 1) as u16) << offset_shift) | (length - 3) as u16;
+                out.extend_from_slice(&token.to_le_bytes());
+                out[flags_position] |= 1 << flag_count;
+                length
+            }
+            None => {
+                out.push(data[pos]);
+                1
+            }
+        };
+        for p in pos..pos + advance {
+            finder.insert(p);
+        }
+        pos += advance;
+        flag_count += 1;
+        if out.len() >= chunk_len {
+            // Make sure the finder is up to date with the rest of the chunk.
+            for p in pos..chunk_end {
+                finder.insert(p);
+            }
+            return None;
+        }
+    }
+    Some(out)
+}
+
+/// Decompresses the data, appending at most `original_size` bytes to `out`.
+pub fn decompress(
+    compressed: &[u8],
+    original_size: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), CompressionError> {
+    let target = out.len() + original_size;
+    let mut input = compressed;
+    while input.len() >= 2 {
+        let header = u16::from_le_bytes([input[0], input[1]]);
+        if header == 0 {
+            break;
+        }
+        let chunk_len = (header & CHUNK_SIZE_MASK) as usize + 3;
+        if chunk_len > input.len() {
+            return Err(CompressionError::Lznt1InvalidData);
+        }
+        let chunk = &input[2..chunk_len];
+        input = &input[chunk_len..];
+
+        // Every chunk, including the last one, represents at most a full chunk of data.
+        let chunk_start = out.len();
+        let chunk_limit = Limit {
+            chunk_end: chunk_start + CHUNK_SIZE,
+            target,
+        };
+        if header & CHUNK_COMPRESSED == 0 {
+            chunk_limit.check(out.len() + chunk.len())?;
+            out.extend_from_slice(chunk);
+        } else {
+            decompress_chunk(chunk, out, chunk_start, &chunk_limit)?;
+        }
+        // Each chunk but the last represents a full chunk of data.
+        if input.len() >= 2 && input[..2] != [0, 0] {
+            chunk_limit.check(chunk_limit.chunk_end)?;
+            out.resize(chunk_limit.chunk_end, 0);
+        }
+    }
+    if out.len() != target {
+        return Err(CompressionError::InvalidDecompressedSize);
+    }
+    Ok(())
+}
+
+/// The bounds of the output of a chunk.
+struct Limit {
+    /// The end of the data the chunk may represent.
+    chunk_end: usize,
+    /// The end of the whole decompressed data.
+    target: usize,
+}
+
+impl Limit {
+    /// Checks that the output may grow up to `end`.
+    fn check(&self, end: usize) -> Result<(), CompressionError> {
+        if end > self.target {
+            return Err(CompressionError::InvalidDecompressedSize);
+        }
+        if end > self.chunk_end {
+            return Err(CompressionError::Lznt1InvalidData);
+        }
+        Ok(())
+    }
+}
+
+fn decompress_chunk(
+    chunk: &[u8],
+    out: &mut Vec<u8>,
+    chunk_start: usize,
+    limit: &Limit,
+) -> Result<(), CompressionError> {
+    let mut i = 0;
+    while i < chunk.len() {
+        let flags = chunk[i];
+        i += 1;
+        for bit in 0..8 {
+            if i >= chunk.len() {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                limit.check(out.len() + 1)?;
+                out.push(chunk[i]);
+                i += 1;
+                continue;
+            }
+            if i + 2 > chunk.len() {
+                return Err(CompressionError::Lznt1InvalidData);
+            }
+            let position = out.len() - chunk_start;
+            if position == 0 {
+                return Err(CompressionError::Lznt1InvalidData);
+            }
+            let token = u16::from_le_bytes([chunk[i], chunk[i + 1]]);
+            i += 2;
+            let (length_mask, offset_shift) = token_format(position);
+            let length = (token & length_mask) as usize + 3;
+            let offset = (token >> offset_shift) as usize + 1;
+            if offset > position {
+                return Err(CompressionError::Lznt1InvalidData);
+            }
+            limit.check(out.len() + length)?;
+            let source = out.len() - offset;
+            for k in 0..length {
+                out.push(out[source + k]);
+            }
+        }
+    }
+    Ok(())
+}
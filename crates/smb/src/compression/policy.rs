@@ -0,0 +1,352 @@
+//! Decides which messages are worth compressing.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use smb_msg::Command;
+
+/// Configures which messages are compressed, when compression is negotiated.
+///
+/// A message is compressed only if all of the following hold:
+/// * Compression is enabled for its share (see [`shares`][Self::shares]), or for the connection.
+/// * Its command is one of [`commands`][Self::commands].
+/// * It is at least [`min_size`][Self::min_size] bytes long.
+/// * Its command is not backing off, after recent messages did not compress well
+///   (see [`backoff_after`][Self::backoff_after]).
+/// * A sample of its payload is estimated to compress well enough.
+///
+/// For reads, the same rules decide whether to ask the server to compress the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    /// The commands whose messages may be compressed. If not set, all commands may be compressed.
+    pub commands: Option<Vec<Command>>,
+
+    /// The minimal size of a message to be compressed, or of the data of a read to be requested compressed.
+    pub min_size: usize,
+
+    /// The number of bytes sampled from the payload of a message (e.g. written data) to estimate how well it compresses,
+    /// from the entropy of its bytes. Zero disables the estimation.
+    ///
+    /// The estimation is cheap, and detects already-compressed or encrypted data, but it cannot detect
+    /// repetitions of longer sequences.
+    pub sample_size: usize,
+
+    /// The maximal size of a compressed message, in percents of its original size.
+    /// Messages that do not (or are not estimated to) compress as well are sent uncompressed.
+    pub max_ratio_percent: u8,
+
+    /// Enables or disables compression for specific shares, overriding
+    /// [`ConnectionConfig::compression_enabled`][crate::ConnectionConfig::compression_enabled].
+    ///
+    /// Keys are share names (e.g. `"media"` for `\\server\media`), and are case-insensitive.
+    pub shares: HashMap<String, bool>,
+
+    /// The number of consecutive messages of a command that do not compress well,
+    /// after which compression of that command backs off. Zero disables backing off.
+    pub backoff_after: u32,
+    /// The number of messages of a command sent uncompressed, when backing off.
+    pub backoff_skip: u32,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            commands: None,
+            min_size: Self::DEFAULT_MIN_SIZE,
+            sample_size: Self::DEFAULT_SAMPLE_SIZE,
+            max_ratio_percent: Self::DEFAULT_MAX_RATIO_PERCENT,
+            shares: HashMap::new(),
+            backoff_after: 4,
+            backoff_skip: 32,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub const DEFAULT_MIN_SIZE: usize = 1024;
+    pub const DEFAULT_SAMPLE_SIZE: usize = 4096;
+    pub const DEFAULT_MAX_RATIO_PERCENT: u8 = super::Compressor::DEFAULT_MAX_RATIO_PERCENT;
+
+    /// Returns whether compression is enabled for the share, if overridden.
+    fn share_override(&self, tree_name: &str) -> Option<bool> {
+        let share = tree_name
+            .trim_end_matches('\\')
+            .rsplit('\\')
+            .next()
+            .unwrap_or(tree_name);
+        self.shares
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(share))
+            .map(|(_, enabled)| *enabled)
+    }
+
+    fn allows_command(&self, command: Command) -> bool {
+        self.commands
+            .as_ref()
+            .is_none_or(|commands| commands.contains(&command))
+    }
+
+    /// Estimates the size of the compressed data, in percents of its size,
+    /// from the entropy of the bytes of a sample of it.
+    fn estimate_ratio_percent(&self, data: &[u8]) -> u8 {
+        const SAMPLE_CHUNK: usize = 64;
+        let mut histogram = [0u32; 256];
+        let mut sampled = 0usize;
+        if data.len() <= self.sample_size {
+            data.iter().for_each(|&b| histogram[b as usize] += 1);
+            sampled = data.len();
+        } else {
+            // Sample evenly spread chunks of the data.
+            let chunks = self.sample_size.div_ceil(SAMPLE_CHUNK);
+            let stride = data.len() / chunks;
+            for i in 0..chunks {
+                let start = i * stride;
+                let chunk = &data[start..(start + SAMPLE_CHUNK).min(data.len())];
+                chunk.iter().for_each(|&b| histogram[b as usize] += 1);
+                sampled += chunk.len();
+            }
+        }
+        if sampled == 0 {
+            return 0;
+        }
+
+        let entropy = histogram
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / sampled as f64;
+                -p * p.log2()
+            })
+            .sum::<f64>();
+        (entropy / 8.0 * 100.0).round() as u8
+    }
+}
+
+/// Counters of the compression of a connection's messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// The number of messages sent compressed.
+    pub messages_compressed: u64,
+    /// The number of messages that were compressed, but sent uncompressed, since they did not compress well enough.
+    pub messages_not_worth: u64,
+    /// The number of messages that the policy decided not to compress, without compressing them.
+    pub messages_skipped: u64,
+    /// The size of the messages sent compressed, before compression.
+    pub bytes_original: u64,
+    /// The size of the messages sent compressed, after compression.
+    pub bytes_compressed: u64,
+    /// The number of compressed messages received.
+    pub messages_received_compressed: u64,
+    /// The size of the compressed messages received, before decompression.
+    pub bytes_received_compressed: u64,
+    /// The size of the compressed messages received, after decompression.
+    pub bytes_received_original: u64,
+}
+
+impl CompressionStats {
+    /// The number of bytes saved by compression, in both directions.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_original.saturating_sub(self.bytes_compressed)
+            + self
+                .bytes_received_original
+                .saturating_sub(self.bytes_received_compressed)
+    }
+}
+
+/// (Internal)
+///
+/// Applies the [`CompressionPolicy`] of a connection, and collects its [`CompressionStats`].
+#[derive(Debug)]
+pub(crate) struct CompressionTracker {
+    policy: CompressionPolicy,
+    enabled: bool,
+    state: Mutex<TrackerState>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    /// Whether compression is enabled for each connected tree, if overridden.
+    trees: HashMap<u32, bool>,
+    backoff: HashMap<Command, Backoff>,
+    stats: CompressionStats,
+}
+
+#[derive(Debug, Default)]
+struct Backoff {
+    /// The number of consecutive messages that did not compress well.
+    poor: u32,
+    /// The number of messages left to skip.
+    skip: u32,
+}
+
+impl CompressionTracker {
+    pub fn new(policy: CompressionPolicy, enabled: bool) -> Self {
+        Self {
+            policy,
+            enabled,
+            state: Default::default(),
+        }
+    }
+
+    pub fn policy(&self) -> &CompressionPolicy {
+        &self.policy
+    }
+
+    /// Notifies that a tree is connected, to apply the share's override, if any.
+    pub fn tree_connected(&self, tree_id: u32, tree_name: &str) {
+        let mut state = self.state.lock().unwrap();
+        match self.policy.share_override(tree_name) {
+            Some(enabled) => state.trees.insert(tree_id, enabled),
+            None => state.trees.remove(&tree_id),
+        };
+    }
+
+    /// Decides whether to compress a message, whose payload is `payload` and whose total size is `size`.
+    ///
+    /// The result of compressing the message must be reported with [`Self::compressed`].
+    pub fn should_compress(
+        &self,
+        command: Command,
+        tree_id: Option<u32>,
+        payload: &[u8],
+        size: usize,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !self.is_enabled(&state, command, tree_id) || size < self.policy.min_size {
+            return false;
+        }
+
+        let compress = match state.backoff.get_mut(&command) {
+            Some(backoff) if backoff.skip > 0 => {
+                backoff.skip -= 1;
+                false
+            }
+            _ => {
+                self.policy.sample_size == 0
+                    || self.policy.estimate_ratio_percent(payload) <= self.policy.max_ratio_percent
+            }
+        };
+        if !compress {
+            state.stats.messages_skipped += 1;
+        }
+        compress
+    }
+
+    /// Decides whether to request the server to compress the response of a read of `length` bytes.
+    pub fn should_request_compressed_read(&self, tree_id: Option<u32>, length: u32) -> bool {
+        let state = self.state.lock().unwrap();
+        self.is_enabled(&state, Command::Read, tree_id) && length as usize >= self.policy.min_size
+    }
+
+    fn is_enabled(&self, state: &TrackerState, command: Command, tree_id: Option<u32>) -> bool {
+        let enabled = tree_id
+            .and_then(|tree_id| state.trees.get(&tree_id).copied())
+            .unwrap_or(self.enabled);
+        enabled && self.policy.allows_command(command)
+    }
+
+    /// Reports the result of compressing a message: its compressed size,
+    /// or `None` if it did not compress well enough to be sent compressed.
+    pub fn compressed(
+        &self,
+        command: Command,
+        original_size: usize,
+        compressed_size: Option<usize>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        match compressed_size {
+            Some(compressed_size) => {
+                state.stats.messages_compressed += 1;
+                state.stats.bytes_original += original_size as u64;
+                state.stats.bytes_compressed += compressed_size as u64;
+                state.backoff.remove(&command);
+            }
+            None => {
+                state.stats.messages_not_worth += 1;
+                if self.policy.backoff_after > 0 {
+                    let backoff = state.backoff.entry(command).or_default();
+                    backoff.poor += 1;
+                    if backoff.poor >= self.policy.backoff_after {
+                        log::debug!(
+                            "Recent {command} messages did not compress well, backing off for {} messages",
+                            self.policy.backoff_skip
+                        );
+                        backoff.poor = 0;
+                        backoff.skip = self.policy.backoff_skip;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports a compressed message received.
+    pub fn received(&self, compressed_size: usize, original_size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.messages_received_compressed += 1;
+        state.stats.bytes_received_compressed += compressed_size as u64;
+        state.stats.bytes_received_original += original_size as u64;
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_ratio() {
+        let policy = CompressionPolicy::default();
+        assert_eq!(policy.estimate_ratio_percent(&[0; 0x10000]), 0);
+        let text = b"hello world ".repeat(1000);
+        assert!(policy.estimate_ratio_percent(&text) < 50);
+        let random = crate::testing::random_bytes(1, 0x10000);
+        assert!(policy.estimate_ratio_percent(&random) > 95);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = CompressionPolicy {
+            sample_size: 0,
+            backoff_after: 2,
+            backoff_skip: 3,
+            ..Default::default()
+        };
+        let tracker = CompressionTracker::new(policy, true);
+        let data = [0; 0x1000];
+        let should_compress = || tracker.should_compress(Command::Write, None, &data, data.len());
+
+        for _ in 0..2 {
+            assert!(should_compress());
+            tracker.compressed(Command::Write, data.len(), None);
+        }
+        for _ in 0..3 {
+            assert!(!should_compress());
+        }
+        assert!(should_compress());
+        // Other commands are not affected.
+        assert!(tracker.should_compress(Command::Ioctl, None, &data, data.len()));
+
+        let stats = tracker.stats();
+        assert_eq!(stats.messages_not_worth, 2);
+        assert_eq!(stats.messages_skipped, 3);
+    }
+
+    #[test]
+    fn test_share_override() {
+        let policy = CompressionPolicy {
+            shares: [("Media".to_string(), false), ("docs".to_string(), true)].into(),
+            ..Default::default()
+        };
+        let tracker = CompressionTracker::new(policy, false);
+        tracker.tree_connected(1, r"\\server\media");
+        tracker.tree_connected(2, r"\\server\DOCS");
+        tracker.tree_connected(3, r"\\server\other");
+        assert!(!tracker.should_request_compressed_read(Some(1), 0x10000));
+        assert!(tracker.should_request_compressed_read(Some(2), 0x10000));
+        assert!(!tracker.should_request_compressed_read(Some(3), 0x10000));
+        assert!(!tracker.should_request_compressed_read(Some(2), 100));
+    }
+}
@@ -1,32 +1,42 @@
+pub mod capture;
 pub mod config;
 pub mod connection_info;
 pub mod preauth_hash;
+pub mod replay;
 pub mod transformer;
 pub mod worker;
 
-use crate::compression;
+use crate::compression::{CompressionStats, CompressionTracker};
 use crate::connection::preauth_hash::PreauthHashState;
 use crate::dialects::DialectImpl;
+use crate::events::{EventListenerHandle, SmbEvent};
+use crate::metrics::{MetricsHandle, PendingResponse, RequestTracker};
+use crate::resource::LeaseRouter;
+use crate::security::SecurityPosture;
 use crate::session::ChannelMessageHandler;
 use crate::sync_helpers::*;
-use crate::{Error, crypto, msg_handler::*, session::Session};
+use crate::{
+    Error,
+    msg_handler::*,
+    session::{Credentials, Session},
+};
 use binrw::prelude::*;
+use capture::Capture;
 pub use config::*;
 use connection_info::{ConnectionInfo, NegotiatedProperties};
 use maybe_async::*;
 use rand::RngCore;
 use rand::rngs::OsRng;
+use replay::{RecordFile, RecordingTransport, Replayer, SessionKeysObserver};
 use smb_dtyp::*;
 use smb_msg::{Command, Response, negotiate::*, plain::*, smb1::SMB1NegotiateMessage};
 use smb_transport::*;
 use std::cmp::max;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-#[cfg(feature = "multi_threaded")]
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 pub use transformer::TransformError;
-use worker::{Worker, WorkerImpl};
+use worker::{ConnectionLostCallback, Worker, WorkerImpl};
 
 /// Represents an SMB connection.
 ///
@@ -55,6 +65,7 @@ impl Connection {
             handler: HandlerReference::new(ConnectionMessageHandler::new(
                 client_guid,
                 config.credits_backlog,
+                config.metrics.clone(),
             )),
             config,
             server_name: server_name.to_string(),
@@ -69,7 +80,7 @@ impl Connection {
     pub async fn bind_session(
         &self,
         primary_session: &Session,
-        identity: sspi::AuthIdentity,
+        credentials: impl Into<Credentials>,
     ) -> crate::Result<u32> {
         log::debug!("Binding alternate session to new connection");
 
@@ -94,7 +105,7 @@ impl Connection {
 
         primary_session
             .bind(
-                identity,
+                credentials.into(),
                 &self.handler,
                 self.handler.conn_info.get().unwrap(),
             )
@@ -102,11 +113,28 @@ impl Connection {
     }
 
     /// Connects to the specified server, if it is not already connected, and negotiates the connection.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smb.connect", skip_all, fields(server = %self.server_name)))]
     pub async fn connect(&self) -> crate::Result<()> {
         if self.handler.worker().is_some() {
             return Err(Error::InvalidState("Already connected".into()));
         }
 
+        if let Some(path) = &self.config.replay_file {
+            log::info!(
+                "Replaying connection to {} from {}",
+                &self.server_name,
+                path.display()
+            );
+            let (transport, observer) = Replayer::start(path, self.config.timeout())?;
+            return self
+                ._negotiate(
+                    Box::new(transport),
+                    self.config.smb2_only_negotiate,
+                    Some(observer),
+                )
+                .await;
+        }
+
         let mut transport = make_transport(&self.config.transport, self.config.timeout())?;
 
         let mut actual_connect_address = self.server_address;
@@ -124,7 +152,7 @@ impl Connection {
             .await?;
 
         log::info!("Connected to {}. Negotiating.", &self.server_name);
-        self._negotiate(transport, self.config.smb2_only_negotiate)
+        self._negotiate(transport, self.config.smb2_only_negotiate, None)
             .await?;
 
         Ok(())
@@ -164,7 +192,7 @@ impl Connection {
         config: ConnectionConfig,
     ) -> crate::Result<Self> {
         let conn = Self::build(server, transport.remote_address()?, client_guid, config)?;
-        conn._negotiate(transport, conn.config.smb2_only_negotiate)
+        conn._negotiate(transport, conn.config.smb2_only_negotiate, None)
             .await?;
         Ok(conn)
     }
@@ -238,6 +266,7 @@ impl Connection {
     async fn _negotiate_smb2(
         &self,
         server_address: std::net::SocketAddr,
+        keys_observer: Option<Arc<dyn SessionKeysObserver>>,
     ) -> crate::Result<ConnectionInfo> {
         // Confirm that we're not already negotiated.
         if self.handler.conn_info.get().is_some() {
@@ -262,7 +291,7 @@ impl Connection {
         }
 
         let encryption_algos = if !self.config.encryption_mode.is_disabled() {
-            crypto::ENCRYPTING_ALGOS.into()
+            self.config.encryption_ciphers()
         } else {
             vec![]
         };
@@ -274,9 +303,9 @@ impl Connection {
                 OutgoingMessage::new(
                     self._make_smb2_neg_request(
                         dialects,
-                        crypto::SIGNING_ALGOS.to_vec(),
+                        self.config.signing_algorithms(),
                         encryption_algos,
-                        compression::SUPPORTED_ALGORITHMS.to_vec(),
+                        self.config.compression_algorithms(),
                     )
                     .into(),
                 )
@@ -348,6 +377,11 @@ impl Connection {
             preauth_hash,
             client_guid: self.handler.client_guid,
             server_address,
+            keys_observer,
+            compression: Arc::new(CompressionTracker::new(
+                self.config.compression_policy.clone(),
+                self.config.compression_enabled,
+            )),
         })
     }
 
@@ -461,23 +495,40 @@ impl Connection {
     #[maybe_async]
     async fn _negotiate(
         &self,
-        transport: Box<dyn SmbTransport>,
+        mut transport: Box<dyn SmbTransport>,
         smb2_only_neg: bool,
+        mut keys_observer: Option<Arc<dyn SessionKeysObserver>>,
     ) -> crate::Result<()> {
         if self.handler.conn_info.get().is_some() {
             return Err(Error::InvalidState("Already negotiated".into()));
         }
 
         let server_address = transport.remote_address()?;
+        if let Some(path) = RecordFile::path_from(&self.config.record_file) {
+            let file = RecordFile::create(&path)?;
+            transport = Box::new(RecordingTransport::new(transport, file.clone()));
+            keys_observer = Some(file);
+        }
+
         // Negotiate SMB1, Switch to SMB2
         let worker = self
             ._negotiate_switch_to_smb2(transport, smb2_only_neg)
             .await?;
 
+        if let Some(path) = Capture::path_from(&self.config.capture_file) {
+            worker
+                .transformer()
+                .start_capture(Capture::new(&path, server_address)?)?;
+        }
+
+        worker.on_connection_lost(
+            self.handler
+                .connection_lost_callback(&self.server_name, self.config.events.clone()),
+        )?;
         self.handler.worker.set(worker).unwrap();
 
         // Negotiate SMB2
-        let info = self._negotiate_smb2(server_address).await?;
+        let info = self._negotiate_smb2(server_address, keys_observer).await?;
 
         self.handler
             .worker
@@ -488,7 +539,9 @@ impl Connection {
             .await;
 
         #[cfg(not(feature = "single_threaded"))]
-        if !self.config.disable_notifications && info.negotiation.caps.notifications() {
+        if (!self.config.disable_notifications && info.negotiation.caps.notifications())
+            || self.config.handle_caching.is_some()
+        {
             log::debug!("Starting Notification job.");
             self.handler.handler.start_notify().await?;
             log::debug!("Notification job started.");
@@ -501,20 +554,24 @@ impl Connection {
     }
 
     /// Starts a new session for the current connection, and authenticates it
-    /// using the provided user name and password.
+    /// using the provided credentials.
     ///
     /// ## Arguments
-    /// * `user_name` - The user to authenticate with.
-    /// * `password` - The password for the user.
+    /// * `credentials` - The credentials to authenticate with: an [`sspi::AuthIdentity`]
+    ///   with a user name and password, or any other [`Credentials`].
     ///
     /// ## Returns
     /// A [`Session`] object representing the authenticated session.
     ///
     /// ## Notes:
     /// * Use the [`ConnectionConfig`] to configure authentication options.
-    pub async fn authenticate(&self, identity: sspi::AuthIdentity) -> crate::Result<Session> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smb.authenticate", skip_all, fields(server = %self.server_name)))]
+    pub async fn authenticate(
+        &self,
+        credentials: impl Into<Credentials>,
+    ) -> crate::Result<Session> {
         let session = Session::create(
-            identity,
+            credentials.into(),
             &self.handler,
             self.handler.conn_info.get().unwrap(),
         )
@@ -533,6 +590,30 @@ impl Connection {
     pub fn conn_info(&self) -> Option<&Arc<ConnectionInfo>> {
         self.handler.conn_info.get()
     }
+
+    /// Returns whether the connection was lost: closed by the server, or due to a network failure,
+    /// rather than by [`Connection::close`].
+    ///
+    /// A lost connection, and everything established on it, is no longer usable.
+    pub fn is_lost(&self) -> bool {
+        self.handler.lost_state.lost.load(Ordering::SeqCst)
+    }
+
+    /// Returns the compression counters of the connection, if the connection has been negotiated.
+    /// Otherwise, returns `None`.
+    pub fn compression_stats(&self) -> Option<CompressionStats> {
+        self.conn_info().map(|info| info.compression.stats())
+    }
+
+    /// Returns the security posture of the connection, if the connection has been negotiated.
+    /// Otherwise, returns `None`.
+    ///
+    /// Session-level properties (e.g. the authentication mechanism) are not set;
+    /// Use [`Session::security_posture`] for those.
+    pub fn security_posture(&self) -> Option<SecurityPosture> {
+        self.conn_info()
+            .map(|info| SecurityPosture::for_connection(info))
+    }
 }
 
 /// This struct is the internal message handler for the SMB client.
@@ -565,10 +646,33 @@ pub(crate) struct ConnectionMessageHandler {
     /// The number of credits granted to the client by the server, including the being-used ones.
     /// This field is used ONLY when large MTU is enabled.
     credit_pool: AtomicU16,
+
+    /// Routes lease breaks to the trees holding the leases.
+    lease_router: Arc<LeaseRouter>,
+
+    /// Reports metrics and tracing spans of requests.
+    requests: Arc<RequestTracker>,
+
+    /// Whether the connection was lost, and the channels bound to it.
+    lost_state: Arc<ConnectionLostState>,
+}
+
+/// (Internal)
+///
+/// The state of a connection that is updated once it's lost, by the worker.
+#[derive(Default)]
+struct ConnectionLostState {
+    lost: AtomicBool,
+    /// Alternate channels bound to the connection: (session ID, channel ID).
+    bound_channels: std::sync::Mutex<Vec<(u64, u32)>>,
 }
 
 impl ConnectionMessageHandler {
-    fn new(client_guid: Guid, credits_backlog: Option<u16>) -> ConnectionMessageHandler {
+    fn new(
+        client_guid: Guid,
+        credits_backlog: Option<u16>,
+        metrics: Option<MetricsHandle>,
+    ) -> ConnectionMessageHandler {
         ConnectionMessageHandler {
             client_guid,
             worker: OnceCell::new(),
@@ -580,6 +684,62 @@ impl ConnectionMessageHandler {
             #[cfg(not(feature = "single_threaded"))]
             stop_notifications: Default::default(),
             sessions: Mutex::new(HashMap::with_capacity(1)),
+            lease_router: Default::default(),
+            requests: Arc::new(RequestTracker::new(metrics)),
+            lost_state: Default::default(),
+        }
+    }
+
+    /// Returns the callback for the worker to call once the connection is lost,
+    /// which marks the connection as lost, and raises the matching events.
+    ///
+    /// The callback does not reference the handler itself, since it's called by the worker.
+    fn connection_lost_callback(
+        &self,
+        server_name: &str,
+        events: Option<EventListenerHandle>,
+    ) -> ConnectionLostCallback {
+        let lost_state = self.lost_state.clone();
+        let server = server_name.to_string();
+        Box::new(move || {
+            lost_state.lost.store(true, Ordering::SeqCst);
+            log::warn!("Connection to {server} was lost.");
+            let events = match &events {
+                Some(events) => events,
+                None => return,
+            };
+            events.emit(&SmbEvent::ConnectionLost {
+                server: server.clone(),
+            });
+            let bound_channels = lost_state
+                .bound_channels
+                .lock()
+                .map(|channels| channels.clone())
+                .unwrap_or_default();
+            for (session_id, channel_id) in bound_channels {
+                events.emit(&SmbEvent::ChannelRemoved {
+                    server: server.clone(),
+                    session_id,
+                    channel_id,
+                });
+            }
+        })
+    }
+
+    /// Registers an alternate channel that was bound to the connection.
+    pub(crate) fn channel_bound(&self, session_id: u64, channel_id: u32) -> crate::Result<()> {
+        self.lost_state
+            .bound_channels
+            .lock()?
+            .push((session_id, channel_id));
+        Ok(())
+    }
+
+    /// Raises the event made by `event` from the server name,
+    /// if the connection is negotiated and an event listener is configured.
+    pub(crate) fn emit_event(&self, event: impl FnOnce(String) -> SmbEvent) {
+        if let Some(conn_info) = self.conn_info.get() {
+            conn_info.emit_event(event);
         }
     }
 
@@ -587,6 +747,10 @@ impl ConnectionMessageHandler {
         self.worker.get()
     }
 
+    pub fn lease_router(&self) -> &Arc<LeaseRouter> {
+        &self.lease_router
+    }
+
     const SET_CREDIT_CHARGE_CMDS: &'static [Command] = &[
         Command::Read,
         Command::Write,
@@ -705,12 +869,11 @@ impl ConnectionMessageHandler {
                         log::info!("Notification handler cancelled.");
                         break;
                     }
-                    else => {
-                        while let Some(msg) = rx.recv().await {
-                            self_clone.notify(msg).await.unwrap_or_else(|e| {
-                                log::error!("Error handling notification: {e:?}");
-                            });
-                        }
+                    msg = rx.recv() => match msg {
+                        Some(msg) => self_clone.notify(msg).await.unwrap_or_else(|e| {
+                            log::error!("Error handling notification: {e:?}");
+                        }),
+                        None => break,
                     }
                 }
             }
@@ -760,7 +923,7 @@ impl MessageHandler for ConnectionMessageHandler {
     async fn sendo(&self, mut msg: OutgoingMessage) -> crate::Result<SendMessageResult> {
         let priority_value = match self.conn_info.get() {
             Some(neg_info) => match neg_info.negotiation.dialect_rev {
-                Dialect::Smb0311 => 1,
+                Dialect::Smb0311 => msg.priority.unwrap_or(1),
                 _ => 0,
             },
             None => 0,
@@ -776,16 +939,28 @@ impl MessageHandler for ConnectionMessageHandler {
             ));
         }
 
-        self.worker
+        let worker = self
+            .worker
             .get()
-            .ok_or(Error::InvalidState("Worker is uninitialized".into()))?
-            .send(msg)
-            .await
+            .ok_or(Error::InvalidState("Worker is uninitialized".into()))?;
+        let msg_id = msg.message.header.message_id;
+        if !is_cancel {
+            self.requests.request_sent(&msg).await?;
+        }
+
+        let result = worker.send(msg).await;
+        if result.is_err() && !is_cancel {
+            self.requests.response_received(msg_id, None).await?;
+        }
+        result
     }
 
     #[maybe_async]
     async fn recvo(&self, options: ReceiveOptions<'_>) -> crate::Result<IncomingMessage> {
-        let msg = self.worker.get().unwrap().receive(&options).await?;
+        let pending = PendingResponse::new(&self.requests, options.msg_id);
+        let msg = self.worker.get().unwrap().receive(&options).await;
+        pending.complete(msg.as_ref().ok()).await?;
+        let msg = msg?;
 
         // Command matching (if needed).
         if let Some(cmd) = options.cmd {
@@ -823,6 +998,31 @@ impl MessageHandler for ConnectionMessageHandler {
 
     #[maybe_async]
     async fn notify(&self, msg: IncomingMessage) -> crate::Result<()> {
+        match &msg.message.content {
+            // Lease breaks are not associated with a session.
+            ResponseContent::LeaseBreakNotify(notify) => {
+                self.emit_event(|server| SmbEvent::LeaseBreak {
+                    server,
+                    lease_key: notify.lease_key,
+                    current_state: notify.current_lease_state,
+                    new_state: notify.new_lease_state,
+                });
+                return self.lease_router.lease_break(notify).await;
+            }
+            // Oplocks are not requested by the client, so there is nothing to release.
+            ResponseContent::OplockBreakNotify(notify) => {
+                log::debug!("Received oplock break for {:?}", notify.file_id);
+                self.emit_event(|server| SmbEvent::OplockBreak {
+                    server,
+                    session_id: msg.message.header.session_id,
+                    file_id: notify.file_id,
+                    new_level: notify.oplock_level,
+                });
+                return Ok(());
+            }
+            _ => {}
+        }
+
         if msg.message.header.session_id == 0 {
             log::warn!("Received notification without session ID: {msg:?}");
             return Ok(());
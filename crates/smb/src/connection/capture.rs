@@ -0,0 +1,278 @@
+//! Capturing of plain SMB2 traffic into pcapng files.
+//!
+//! Outgoing messages are captured before compression and encryption, and incoming messages are captured
+//! after decryption and decompression, so the capture file contains the plain SMB2 messages,
+//! even for encrypted or compressed connections.
+//!
+//! Each message is wrapped in synthetic IPv4, TCP and NetBIOS session headers,
+//! so Wireshark can dissect the capture as a regular SMB2-over-TCP (port 445) conversation.
+//! Each connection is captured as a separate TCP stream. Connections capturing into the same
+//! file path share the same capture file.
+//!
+//! Use [`ConnectionConfig::capture_file`][crate::ConnectionConfig::capture_file],
+//! or set the [`CAPTURE_FILE_ENV_VAR`] environment variable, to enable capturing.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use smb_transport::IoVec;
+
+/// The environment variable that specifies a capture file path,
+/// if not set in the connection configuration.
+pub const CAPTURE_FILE_ENV_VAR: &str = "SMB_CAPTURE_FILE";
+
+/// Open capture files, by path.
+static CAPTURE_FILES: LazyLock<Mutex<HashMap<PathBuf, Weak<CaptureFile>>>> =
+    LazyLock::new(Default::default);
+
+/// The next synthetic client port to use for a captured connection.
+static NEXT_CLIENT_PORT: AtomicU16 = AtomicU16::new(49152);
+
+/// A pcapng file, with a single raw IPv4 interface.
+struct CaptureFile {
+    file: Mutex<File>,
+    /// Set after the first write failure, to avoid flooding the logs.
+    failed: AtomicBool,
+}
+
+impl CaptureFile {
+    const BLOCK_SHB: u32 = 0x0A0D0D0A;
+    const BLOCK_IDB: u32 = 0x00000001;
+    const BLOCK_EPB: u32 = 0x00000006;
+    const LINKTYPE_RAW: u16 = 101;
+
+    /// Returns the capture file for the specified path, creating it if it's not open yet.
+    fn open(path: &Path) -> crate::Result<Arc<CaptureFile>> {
+        let mut files = CAPTURE_FILES.lock().unwrap();
+        files.retain(|_, file| file.strong_count() > 0);
+        if let Some(file) = files.get(path).and_then(Weak::upgrade) {
+            return Ok(file);
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(&Self::header())?;
+        log::info!("Capturing plain SMB traffic to {}", path.display());
+
+        let file = Arc::new(CaptureFile {
+            file: Mutex::new(file),
+            failed: AtomicBool::new(false),
+        });
+        files.insert(path.to_path_buf(), Arc::downgrade(&file));
+        Ok(file)
+    }
+
+    /// Builds the section header and interface description blocks.
+    fn header() -> Vec<u8> {
+        let mut shb_body = Vec::with_capacity(16);
+        shb_body.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes());
+        shb_body.extend_from_slice(&1u16.to_le_bytes());
+        shb_body.extend_from_slice(&0u16.to_le_bytes());
+        shb_body.extend_from_slice(&(-1i64).to_le_bytes());
+
+        let mut idb_body = Vec::with_capacity(8);
+        idb_body.extend_from_slice(&Self::LINKTYPE_RAW.to_le_bytes());
+        idb_body.extend_from_slice(&0u16.to_le_bytes());
+        idb_body.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut result = Self::block(Self::BLOCK_SHB, &shb_body);
+        result.extend(Self::block(Self::BLOCK_IDB, &idb_body));
+        result
+    }
+
+    /// Builds a pcapng block of the specified type, with the specified body (padded to 32 bits).
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let padded_len = body.len().next_multiple_of(4);
+        let total_len = (12 + padded_len) as u32;
+        let mut block = Vec::with_capacity(total_len as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.resize(8 + padded_len, 0);
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block
+    }
+
+    /// Writes a single packet, in an enhanced packet block.
+    fn write_packet(&self, packet: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut body = Vec::with_capacity(20 + packet.len());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        let block = Self::block(Self::BLOCK_EPB, &body);
+
+        let result = self.file.lock().unwrap().write_all(&block);
+        if let Err(e) = result {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                log::warn!("Failed to write to capture file: {e}");
+            }
+        }
+    }
+}
+
+/// The state of a single TCP direction.
+#[derive(Default)]
+struct Flow {
+    seq: u32,
+    ip_id: u16,
+}
+
+/// Captures the plain traffic of a single connection.
+pub(crate) struct Capture {
+    file: Arc<CaptureFile>,
+    client: (Ipv4Addr, u16),
+    server: (Ipv4Addr, u16),
+    /// Client-to-server and server-to-client flows.
+    flows: Mutex<(Flow, Flow)>,
+}
+
+impl Capture {
+    /// The maximum TCP payload per synthetic segment, keeping the IPv4 total length in bounds.
+    const MAX_SEGMENT: usize = 0xFFFF - 40;
+    const SMB_PORT: u16 = 445;
+
+    /// Starts capturing a connection to the specified server, to the specified capture file.
+    pub fn new(path: &Path, server: SocketAddr) -> crate::Result<Self> {
+        let server_ip = match server {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => Ipv4Addr::new(10, 0, 0, 2),
+        };
+        let client_port = NEXT_CLIENT_PORT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |port| {
+                Some(port.checked_add(1).unwrap_or(49152))
+            })
+            .unwrap();
+        Ok(Self {
+            file: CaptureFile::open(path)?,
+            client: (Ipv4Addr::new(10, 0, 0, 1), client_port),
+            server: (server_ip, Self::SMB_PORT),
+            flows: Default::default(),
+        })
+    }
+
+    /// Returns the capture file path from the configuration, or from the environment, if any.
+    pub fn path_from(configured: &Option<PathBuf>) -> Option<PathBuf> {
+        configured
+            .clone()
+            .or_else(|| std::env::var_os(CAPTURE_FILE_ENV_VAR).map(PathBuf::from))
+    }
+
+    /// Captures a plain message sent to the server.
+    pub fn outgoing(&self, message: &IoVec) {
+        let mut data = Vec::with_capacity(message.total_size());
+        for buf in message.iter() {
+            data.extend_from_slice(buf);
+        }
+        self.capture(true, &data);
+    }
+
+    /// Captures a plain message received from the server.
+    pub fn incoming(&self, message: &[u8]) {
+        self.capture(false, message);
+    }
+
+    fn capture(&self, outgoing: bool, message: &[u8]) {
+        // NetBIOS session message header
+        let mut stream = Vec::with_capacity(4 + message.len());
+        stream.extend_from_slice(&(message.len() as u32 & 0x00FFFFFF).to_be_bytes());
+        stream.extend_from_slice(message);
+
+        let mut flows = self.flows.lock().unwrap();
+        let (flows_tx, flows_rx) = &mut *flows;
+        let (flow, ack, src, dst) = if outgoing {
+            (flows_tx, flows_rx.seq, self.client, self.server)
+        } else {
+            (flows_rx, flows_tx.seq, self.server, self.client)
+        };
+
+        for segment in stream.chunks(Self::MAX_SEGMENT) {
+            let packet = Self::make_packet(src, dst, flow, ack, segment);
+            self.file.write_packet(&packet);
+            flow.seq = flow.seq.wrapping_add(segment.len() as u32);
+            flow.ip_id = flow.ip_id.wrapping_add(1);
+        }
+    }
+
+    /// Builds an IPv4 + TCP packet, with the specified payload.
+    fn make_packet(
+        src: (Ipv4Addr, u16),
+        dst: (Ipv4Addr, u16),
+        flow: &Flow,
+        ack: u32,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let total_len = (40 + payload.len()) as u16;
+        let mut packet = Vec::with_capacity(total_len as usize);
+
+        // IPv4 header
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&flow.ip_id.to_be_bytes());
+        packet.extend_from_slice(&0x4000u16.to_be_bytes()); // Don't fragment
+        packet.extend_from_slice(&[64, 6]); // TTL, TCP
+        packet.extend_from_slice(&[0, 0]); // Checksum
+        packet.extend_from_slice(&src.0.octets());
+        packet.extend_from_slice(&dst.0.octets());
+        let checksum = Self::ip_checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        // TCP header. The checksum is left unset; Wireshark does not validate it by default.
+        packet.extend_from_slice(&src.1.to_be_bytes());
+        packet.extend_from_slice(&dst.1.to_be_bytes());
+        packet.extend_from_slice(&flow.seq.to_be_bytes());
+        packet.extend_from_slice(&ack.to_be_bytes());
+        packet.extend_from_slice(&[0x50, 0x18]); // Header length (20), PSH | ACK
+        packet.extend_from_slice(&0xFFFFu16.to_be_bytes()); // Window
+        packet.extend_from_slice(&[0, 0, 0, 0]); // Checksum, urgent pointer
+
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ip_checksum(header: &[u8]) -> u16 {
+        let mut sum = header
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+            .sum::<u32>();
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_checksum() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(Capture::ip_checksum(&header), 0xb861);
+    }
+
+    #[test]
+    fn test_block_padding() {
+        let block = CaptureFile::block(CaptureFile::BLOCK_EPB, &[1, 2, 3, 4, 5]);
+        assert_eq!(block.len(), 20);
+        assert_eq!(&block[4..8], &20u32.to_le_bytes());
+        assert_eq!(&block[8..16], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        assert_eq!(&block[16..20], &20u32.to_le_bytes());
+    }
+}
@@ -1,10 +1,16 @@
 //! Connection configuration settings.
 
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use smb_msg::Dialect;
+use smb_msg::{CompressionAlgorithm, Dialect, EncryptionCipher, SigningAlgorithmId};
 use smb_transport::config::*;
 
+use crate::compression::CompressionPolicy;
+use crate::events::EventListenerHandle;
+use crate::metrics::MetricsHandle;
+
 /// Specifies the encryption mode for the connection.
 /// Use this as part of the [ConnectionConfig] to specify the encryption mode for the connection.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -73,6 +79,44 @@ pub struct AuthMethodsConfig {
     /// This is supported only if the `kerberos` feature is enabled,
     /// and if so, enabled by default.
     pub kerberos: bool,
+
+    /// The KDC to use for Kerberos authentication, e.g. `tcp://kdc.corp.example.com:88`,
+    /// or `kdc.corp.example.com` (TCP on port 88 is assumed).
+    ///
+    /// If not set, the KDC is discovered using the `SSPI_KDC_URL` environment variable, `krb5.conf`, or DNS.
+    pub kdc_url: Option<String>,
+
+    /// The Kerberos realm of users and principals that do not specify one,
+    /// e.g. `CORP.EXAMPLE.COM`.
+    pub realm: Option<String>,
+
+    /// The service principal name (SPN) to authenticate to with Kerberos,
+    /// e.g. `cifs/fs01.corp.example.com`, overriding the one derived from the server name.
+    ///
+    /// Since it applies to every server, prefer [`spn_host_aliases`][Self::spn_host_aliases]
+    /// for clients that connect to multiple servers.
+    pub spn: Option<String>,
+
+    /// Maps names used to connect to servers (DNS aliases, cluster names or IP addresses)
+    /// to the host names in their SPNs. Names are matched case-insensitively.
+    ///
+    /// Aliases are applied before [`spn_canonicalization`][Self::spn_canonicalization].
+    pub spn_host_aliases: HashMap<String, String>,
+
+    /// How the host name in the SPN of a server is derived from the name used to connect to it.
+    pub spn_canonicalization: SpnCanonicalization,
+
+    /// Whether to require the server to prove its identity when authenticating with Kerberos
+    /// (mutual authentication). This is enabled by default.
+    ///
+    /// Kerberos authentication with a password always requires mutual authentication.
+    pub mutual_auth: bool,
+
+    /// Whether to fall back to NTLM if Kerberos authentication is attempted, but fails;
+    /// e.g. if the server has no SPN matching its name. This is enabled by default.
+    ///
+    /// Kerberos is attempted if [`kdc_url`][Self::kdc_url] is set, or a KDC is discovered for the user's domain.
+    pub ntlm_fallback: bool,
 }
 
 impl Default for AuthMethodsConfig {
@@ -80,6 +124,54 @@ impl Default for AuthMethodsConfig {
         Self {
             ntlm: true,
             kerberos: cfg!(feature = "kerberos"),
+            kdc_url: None,
+            realm: None,
+            spn: None,
+            spn_host_aliases: HashMap::new(),
+            spn_canonicalization: SpnCanonicalization::default(),
+            mutual_auth: true,
+            ntlm_fallback: true,
+        }
+    }
+}
+
+/// Specifies how the host name in the SPN of a server is derived from the name used to connect to it.
+///
+/// Use this as part of the [AuthMethodsConfig] when connecting to servers by IP address,
+/// or through DNS aliases that are not registered as SPNs of the servers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpnCanonicalization {
+    /// The server name is used as is.
+    #[default]
+    None,
+    /// The server name is resolved, and its canonical name is used, following CNAME records.
+    Forward,
+    /// The server name is resolved to an address (unless it is an IP address),
+    /// and the name the address reversely resolves to is used.
+    Reverse,
+}
+
+/// Configures deferred close and reuse of opened file handles.
+///
+/// When enabled, files are opened with a read/handle-caching lease, if the server supports leasing.
+/// As long as the handle-caching (H) lease is held, closing a file does not close the handle on the server
+/// right away; instead, the handle is kept open for [`grace_period`][Self::grace_period],
+/// and a subsequent open of the same path with compatible access reuses it, without any round-trip.
+/// Once the grace period elapses, or the server breaks the lease, the handle is closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleCachingConfig {
+    /// The time to keep a closed handle open on the server, waiting for it to be reused.
+    pub grace_period: Duration,
+    /// The maximum number of closed handles to keep open, per tree (share).
+    /// When exceeded, the oldest handles are closed.
+    pub max_idle_handles: usize,
+}
+
+impl Default for HandleCachingConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(5),
+            max_idle_handles: 64,
         }
     }
 }
@@ -111,15 +203,42 @@ pub struct ConnectionConfig {
     /// See [EncryptionMode] for more information.
     pub encryption_mode: EncryptionMode,
 
+    /// The encryption ciphers to negotiate, in order of preference.
+    /// A connection whose cipher is not one of those is not encrypted, and fails
+    /// if encryption is required.
+    ///
+    /// If not set, all the ciphers supported by the crate are allowed,
+    /// see [`ENCRYPTING_ALGOS`][crate::crypto::ENCRYPTING_ALGOS].
+    pub encryption_ciphers: Option<Vec<EncryptionCipher>>,
+
+    /// The signing algorithms to negotiate, in order of preference.
+    /// The connection fails if its signing algorithm, including the fixed algorithm
+    /// of dialects older than SMB 3.1.1, is not one of those.
+    ///
+    /// If not set, all the algorithms supported by the crate are allowed,
+    /// see [`SIGNING_ALGOS`][crate::crypto::SIGNING_ALGOS].
+    pub signing_algorithms: Option<Vec<SigningAlgorithmId>>,
+
     /// Sets whether signing may be skipped for guest or anonymous access.
     pub allow_unsigned_guest_access: bool,
 
     /// Whether to enable compression, if supported by the server and specified connection dialects.
+    /// May be overridden per share, see [`CompressionPolicy::shares`].
     ///
     /// Note: you must also have compression features enabled when building the crate, otherwise compression
     /// would not be available. *The compression feature is enabled by default.*
     pub compression_enabled: bool,
 
+    /// The compression algorithms to negotiate, in order of preference.
+    ///
+    /// If not set, all the algorithms supported by the crate are negotiated,
+    /// see [`SUPPORTED_ALGORITHMS`][crate::compression::SUPPORTED_ALGORITHMS].
+    pub compression_algorithms: Option<Vec<CompressionAlgorithm>>,
+
+    /// Decides which messages are compressed, when compression is negotiated.
+    /// See [`CompressionPolicy`] for more information.
+    pub compression_policy: CompressionPolicy,
+
     /// Multi-channel configuration
     pub multichannel: MultiChannelConfig,
 
@@ -158,6 +277,52 @@ pub struct ConnectionConfig {
     /// their respective `set_*_info` counterparts (such as [`ResourceHandle::set_info`][crate::ResourceHandle::set_info]),
     /// [`Directory::query`][crate::Directory::query] and [`Directory::watch`][crate::Directory::watch] operations.
     pub default_transaction_size: Option<u32>,
+
+    /// Enables deferred close and reuse of opened file handles, when a handle-caching lease is held.
+    /// See [`HandleCachingConfig`] for more information.
+    ///
+    /// This is disabled by default, and is not supported when using the `single_threaded` feature,
+    /// since lease breaks can't be received asynchronously in that mode.
+    pub handle_caching: Option<HandleCachingConfig>,
+
+    /// Receives per-request metrics (latency, status and data size) of the connection.
+    /// See [`crate::metrics`] for more information.
+    pub metrics: Option<MetricsHandle>,
+
+    /// Receives the events of the connection, such as the connection being lost,
+    /// or the server breaking a lease. See [`crate::events`] for more information.
+    pub events: Option<EventListenerHandle>,
+
+    /// Writes the plain (decrypted and decompressed) SMB2 traffic of the connection to the specified pcapng file.
+    /// If unset, the path in the [`SMB_CAPTURE_FILE`][super::capture::CAPTURE_FILE_ENV_VAR] environment variable
+    /// is used, if set. See [`capture`][super::capture] for more information.
+    ///
+    /// The capture file contains all the data sent on the connection, including file contents,
+    /// so this should only be used for debugging.
+    pub capture_file: Option<PathBuf>,
+
+    /// Appends the keys of each session set up on the connection to the specified file,
+    /// in a format that Wireshark can use for decrypting captured traffic.
+    /// If unset, the path in the [`SMBKEYLOGFILE`][crate::crypto::KEYLOG_FILE_ENV_VAR] environment variable
+    /// is used, if set.
+    ///
+    /// Anyone with access to the key log file can decrypt and tamper with the sessions,
+    /// so this should only be used for debugging.
+    pub keylog_file: Option<PathBuf>,
+
+    /// Records the raw traffic of the connection, along with the keys of its encrypted sessions,
+    /// to the specified file, so it can be replayed later using [`ConnectionConfig::replay_file`].
+    /// If unset, the path in the [`SMB_RECORD_FILE`][super::replay::RECORD_FILE_ENV_VAR] environment variable
+    /// is used, if set. See [`replay`][super::replay] for more information.
+    ///
+    /// Like the key log file, the record file allows decrypting the recorded sessions,
+    /// so this should only be used for debugging and testing.
+    pub record_file: Option<PathBuf>,
+
+    /// Replays the specified record file instead of connecting to the server.
+    /// The client must perform the same operations that were recorded.
+    /// See [`replay`][super::replay] for more information.
+    pub replay_file: Option<PathBuf>,
 }
 
 impl ConnectionConfig {
@@ -183,6 +348,85 @@ impl ConnectionConfig {
             }
         }
 
+        if self.record_file.is_some() && self.replay_file.is_some() {
+            return Err(crate::Error::InvalidConfiguration(
+                "Cannot record and replay a connection at the same time".to_string(),
+            ));
+        }
+
+        if let Some(ciphers) = &self.encryption_ciphers {
+            if let Some(cipher) = ciphers
+                .iter()
+                .find(|c| !crate::crypto::ENCRYPTING_ALGOS.contains(c))
+            {
+                return Err(crate::Error::InvalidConfiguration(format!(
+                    "Encryption cipher {cipher:?} is not supported"
+                )));
+            }
+            if ciphers.is_empty() && self.encryption_mode.is_required() {
+                return Err(crate::Error::InvalidConfiguration(
+                    "Encryption is required, but no encryption ciphers are allowed".to_string(),
+                ));
+            }
+        }
+
+        if let Some(kdc_url) = &self.auth_methods.kdc_url {
+            let url = if kdc_url.contains("://") {
+                url::Url::parse(kdc_url)
+            } else {
+                url::Url::parse(&format!("tcp://{kdc_url}"))
+            };
+            if !url.is_ok_and(|url| url.host_str().is_some()) {
+                return Err(crate::Error::InvalidConfiguration(format!(
+                    "Invalid KDC URL: {kdc_url}"
+                )));
+            }
+        }
+
+        if let Some(spn) = &self.auth_methods.spn {
+            if !spn
+                .split_once('/')
+                .is_some_and(|(service, host)| !service.is_empty() && !host.is_empty())
+            {
+                return Err(crate::Error::InvalidConfiguration(format!(
+                    "Invalid SPN: {spn}, expected service/host"
+                )));
+            }
+        }
+
+        if let Some(signing_algorithms) = &self.signing_algorithms {
+            if let Some(algorithm) = signing_algorithms
+                .iter()
+                .find(|a| !crate::crypto::SIGNING_ALGOS.contains(a))
+            {
+                return Err(crate::Error::InvalidConfiguration(format!(
+                    "Signing algorithm {algorithm:?} is not supported"
+                )));
+            }
+            if signing_algorithms.is_empty() {
+                return Err(crate::Error::InvalidConfiguration(
+                    "At least one signing algorithm must be allowed".to_string(),
+                ));
+            }
+        }
+
+        if let Some(compression_algorithms) = &self.compression_algorithms {
+            if let Some(algorithm) = compression_algorithms
+                .iter()
+                .find(|a| !crate::compression::SUPPORTED_ALGORITHMS.contains(a))
+            {
+                return Err(crate::Error::InvalidConfiguration(format!(
+                    "Compression algorithm {algorithm} is not supported"
+                )));
+            }
+        }
+
+        if !(1..=100).contains(&self.compression_policy.max_ratio_percent) {
+            return Err(crate::Error::InvalidConfiguration(
+                "Compression ratio must be between 1 and 100 percent".to_string(),
+            ));
+        }
+
         if let Some(default_transaction_size) = self.default_transaction_size {
             if default_transaction_size == 0 {
                 return Err(crate::Error::InvalidConfiguration(
@@ -190,9 +434,47 @@ impl ConnectionConfig {
                 ));
             }
         }
+
+        if let Some(handle_caching) = &self.handle_caching {
+            if cfg!(feature = "single_threaded") {
+                return Err(crate::Error::InvalidConfiguration(
+                    "Handle caching is not supported in single-threaded mode".to_string(),
+                ));
+            }
+            if handle_caching.max_idle_handles == 0 || handle_caching.grace_period.is_zero() {
+                return Err(crate::Error::InvalidConfiguration(
+                    "Handle caching requires a non-zero grace period and idle handles limit"
+                        .to_string(),
+                ));
+            }
+        }
         Ok(())
     }
 
+    /// Returns the allowed encryption ciphers, in order of preference.
+    pub fn encryption_ciphers(&self) -> Vec<EncryptionCipher> {
+        match &self.encryption_ciphers {
+            Some(ciphers) => ciphers.clone(),
+            None => crate::crypto::ENCRYPTING_ALGOS.to_vec(),
+        }
+    }
+
+    /// Returns the allowed signing algorithms, in order of preference.
+    pub fn signing_algorithms(&self) -> Vec<SigningAlgorithmId> {
+        match &self.signing_algorithms {
+            Some(algorithms) => algorithms.clone(),
+            None => crate::crypto::SIGNING_ALGOS.to_vec(),
+        }
+    }
+
+    /// Returns the compression algorithms to negotiate, in order of preference.
+    pub fn compression_algorithms(&self) -> Vec<CompressionAlgorithm> {
+        match &self.compression_algorithms {
+            Some(algorithms) => algorithms.clone(),
+            None => crate::compression::SUPPORTED_ALGORITHMS.to_vec(),
+        }
+    }
+
     /// Returns the effective timeout to be used if [`timeout`][`Self::timeout`] is not set.
     pub fn timeout(&self) -> Duration {
         self.timeout.unwrap_or(Self::DEFAULT_TIMEOUT)
@@ -206,3 +488,54 @@ impl ConnectionConfig {
             .unwrap_or(Self::DEFAULT_TRANSACTION_SIZE)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_algorithm_preferences() {
+        let config = ConnectionConfig::default();
+        config.validate().unwrap();
+        assert_eq!(config.encryption_ciphers(), crate::crypto::ENCRYPTING_ALGOS);
+        assert_eq!(config.signing_algorithms(), crate::crypto::SIGNING_ALGOS);
+
+        // The order of preference is kept.
+        let signing_algorithms = crate::crypto::SIGNING_ALGOS
+            .iter()
+            .rev()
+            .copied()
+            .collect::<Vec<_>>();
+        let encryption_ciphers = crate::crypto::ENCRYPTING_ALGOS
+            .iter()
+            .rev()
+            .copied()
+            .collect::<Vec<_>>();
+        let config = ConnectionConfig {
+            signing_algorithms: Some(signing_algorithms.clone()),
+            encryption_ciphers: Some(encryption_ciphers.clone()),
+            ..Default::default()
+        };
+        assert_eq!(config.signing_algorithms(), signing_algorithms);
+        assert_eq!(config.encryption_ciphers(), encryption_ciphers);
+
+        let config = ConnectionConfig {
+            signing_algorithms: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(crate::Error::InvalidConfiguration(_))
+        ));
+
+        let config = ConnectionConfig {
+            encryption_mode: EncryptionMode::Required,
+            encryption_ciphers: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(crate::Error::InvalidConfiguration(_))
+        ));
+    }
+}
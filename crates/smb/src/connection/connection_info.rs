@@ -6,6 +6,9 @@ use smb_dtyp::Guid;
 use smb_msg::*;
 
 use super::ConnectionConfig;
+use super::replay::SessionKeysObserver;
+use crate::compression::CompressionTracker;
+use crate::events::SmbEvent;
 
 /// Contains important information from the negotiation process,
 /// to be used during connection operations.
@@ -65,4 +68,17 @@ pub struct ConnectionInfo {
     pub preauth_hash: PreauthHashState,
     /// The client GUID used for the connection.
     pub client_guid: Guid,
+    /// Receives the keys of the sessions set up on the connection, when recording or replaying it.
+    pub(crate) keys_observer: Option<Arc<dyn SessionKeysObserver>>,
+    /// Applies the compression policy of the connection.
+    pub(crate) compression: Arc<CompressionTracker>,
+}
+
+impl ConnectionInfo {
+    /// Raises the event made by `event` from the server name, if an event listener is configured.
+    pub(crate) fn emit_event(&self, event: impl FnOnce(String) -> SmbEvent) {
+        if let Some(events) = &self.config.events {
+            events.emit(&event(self.server_name.clone()));
+        }
+    }
 }
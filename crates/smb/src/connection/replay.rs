@@ -0,0 +1,319 @@
+//! Recording of raw SMB traffic, and replaying it offline.
+//!
+//! A recording contains every raw frame exchanged with the server, as sent on the wire
+//! (signed, compressed and encrypted), along with the keys of the encrypted sessions,
+//! so the recorded frames can be decrypted later.
+//!
+//! Use [`ConnectionConfig::record_file`][crate::ConnectionConfig::record_file],
+//! or set the [`RECORD_FILE_ENV_VAR`] environment variable, to record a connection.
+//!
+//! Set [`ConnectionConfig::replay_file`][crate::ConnectionConfig::replay_file] to replay a recording:
+//! instead of connecting to the server, the connection is served with the recorded responses.
+//! The client must repeat the same sequence of requests that was recorded; each request is matched
+//! to the next recorded request by its command. Since message IDs and nonces may differ between runs,
+//! the recorded responses are adjusted to the replaying client: message IDs are mapped to the live ones,
+//! and the responses are decrypted using the recorded keys, and then signed and encrypted again
+//! using the keys of the live session.
+//!
+//! Replaying is only supported for NTLM authentication, since Kerberos requires the client
+//! to contact the KDC, which is not part of the recording.
+//!
+//! # File format
+//! The file begins with the [`MAGIC`] bytes, followed by a sequence of records.
+//! Each record is made of its kind (`u8`), the length of its body (`u32`, little-endian) and the body:
+//! * `1` - a frame sent by the client. The body is the SMB message, without the transport header.
+//! * `2` - a frame received from the server. The body is the SMB message, without the transport header.
+//! * `3` - the keys of a session: session ID (`u64`), cipher (`u16`),
+//!   client-to-server key and server-to-client key (16 bytes each).
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
+use binrw::prelude::*;
+use smb_msg::EncryptionCipher;
+
+use crate::crypto::{DerivedKey, SessionKeys};
+use crate::session::MessageSigner;
+
+mod player;
+mod recorder;
+
+pub(crate) use player::Replayer;
+pub(crate) use recorder::{RecordFile, RecordingTransport};
+
+/// The environment variable that specifies a record file path,
+/// if not set in the connection configuration.
+pub const RECORD_FILE_ENV_VAR: &str = "SMB_RECORD_FILE";
+
+/// The magic bytes at the beginning of a record file.
+pub const MAGIC: &[u8; 8] = b"SMBREC\x00\x01";
+
+/// Receives the keys of each session set up on a connection, in-process.
+pub(crate) trait SessionKeysObserver: std::fmt::Debug + Send + Sync {
+    /// Called when the keys of a new session are derived, before the final session setup response is verified.
+    fn session_keys(&self, keys: &SessionKeys, signer: &MessageSigner);
+}
+
+/// The keys of a recorded, encrypted session.
+#[derive(Debug, Clone, Copy)]
+struct RecordedKeys {
+    cipher: EncryptionCipher,
+    c2s_key: DerivedKey,
+    s2c_key: DerivedKey,
+}
+
+/// A single record of a record file.
+#[derive(Debug)]
+enum Record {
+    Frame { from_client: bool, data: Vec<u8> },
+    Keys { session_id: u64, keys: RecordedKeys },
+}
+
+impl Record {
+    const KIND_CLIENT_FRAME: u8 = 1;
+    const KIND_SERVER_FRAME: u8 = 2;
+    const KIND_KEYS: u8 = 3;
+
+    /// The maximum length of a record body: the largest SMB2 message, as limited by
+    /// the 24-bit length of the direct TCP transport header.
+    const MAX_BODY_LENGTH: usize = 0xFFFFFF;
+
+    fn write(&self, out: &mut impl Write) -> crate::Result<()> {
+        let (kind, body) = match self {
+            Record::Frame { from_client, data } => {
+                let kind = if *from_client {
+                    Self::KIND_CLIENT_FRAME
+                } else {
+                    Self::KIND_SERVER_FRAME
+                };
+                (kind, data.clone())
+            }
+            Record::Keys { session_id, keys } => {
+                let mut body = Cursor::new(Vec::with_capacity(42));
+                body.write_all(&session_id.to_le_bytes())?;
+                keys.cipher.write_le(&mut body)?;
+                body.write_all(&keys.c2s_key)?;
+                body.write_all(&keys.s2c_key)?;
+                (Self::KIND_KEYS, body.into_inner())
+            }
+        };
+
+        let mut record = Vec::with_capacity(5 + body.len());
+        record.push(kind);
+        record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        record.extend_from_slice(&body);
+        out.write_all(&record)?;
+        Ok(())
+    }
+
+    /// Reads the next record. Returns `None` at the end of the file.
+    fn read(input: &mut impl Read) -> crate::Result<Option<Record>> {
+        let mut kind = [0; 1];
+        if input.read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+        let mut length = [0; 4];
+        input.read_exact(&mut length)?;
+        let length = u32::from_le_bytes(length) as usize;
+        if length > Self::MAX_BODY_LENGTH {
+            return Err(crate::Error::InvalidMessage(format!(
+                "Record length {length} exceeds the maximum of {}",
+                Self::MAX_BODY_LENGTH
+            )));
+        }
+        let mut body = vec![0; length];
+        input.read_exact(&mut body)?;
+
+        let record = match kind[0] {
+            Self::KIND_CLIENT_FRAME | Self::KIND_SERVER_FRAME => Record::Frame {
+                from_client: kind[0] == Self::KIND_CLIENT_FRAME,
+                data: body,
+            },
+            Self::KIND_KEYS => {
+                if body.len() != 42 {
+                    return Err(crate::Error::InvalidMessage(
+                        "Invalid session keys record".to_string(),
+                    ));
+                }
+                let session_id = u64::from_le_bytes(body[0..8].try_into().unwrap());
+                let cipher = EncryptionCipher::read_le(&mut Cursor::new(&body[8..10]))?;
+                Record::Keys {
+                    session_id,
+                    keys: RecordedKeys {
+                        cipher,
+                        c2s_key: body[10..26].try_into().unwrap(),
+                        s2c_key: body[26..42].try_into().unwrap(),
+                    },
+                }
+            }
+            kind => {
+                return Err(crate::Error::InvalidMessage(format!(
+                    "Unknown record kind {kind}"
+                )));
+            }
+        };
+        Ok(Some(record))
+    }
+}
+
+/// The contents of a record file.
+#[derive(Debug, Default)]
+struct Recording {
+    /// The recorded frames, in order: `(from_client, data)`.
+    frames: Vec<(bool, Vec<u8>)>,
+    keys: HashMap<u64, RecordedKeys>,
+}
+
+impl Recording {
+    fn load(path: &Path) -> crate::Result<Recording> {
+        let mut file = std::io::BufReader::new(File::open(path)?);
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(crate::Error::InvalidArgument(format!(
+                "{} is not an SMB record file",
+                path.display()
+            )));
+        }
+
+        let mut recording = Recording::default();
+        while let Some(record) = Record::read(&mut file)? {
+            match record {
+                Record::Frame { from_client, data } => recording.frames.push((from_client, data)),
+                Record::Keys { session_id, keys } => {
+                    recording.keys.insert(session_id, keys);
+                }
+            }
+        }
+        Ok(recording)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConnectionConfig;
+    use crate::connection::EncryptionMode;
+    use crate::testing::{FakeServer, FakeServerConfig};
+    use smb_dtyp::Guid;
+    use smb_fscc::FileAccessMask;
+    use smb_msg::CreateDisposition;
+
+    #[test]
+    fn test_record_roundtrip() {
+        let records = [
+            Record::Frame {
+                from_client: true,
+                data: b"\xfeSMB request".to_vec(),
+            },
+            Record::Keys {
+                session_id: 0x1122334455667788,
+                keys: RecordedKeys {
+                    cipher: EncryptionCipher::Aes128Gcm,
+                    c2s_key: [1; 16],
+                    s2c_key: [2; 16],
+                },
+            },
+            Record::Frame {
+                from_client: false,
+                data: b"\xfeSMB response".to_vec(),
+            },
+        ];
+        let mut data = vec![];
+        for record in &records {
+            record.write(&mut data).unwrap();
+        }
+
+        let mut cursor = Cursor::new(data);
+        for expected in &records {
+            let read = Record::read(&mut cursor).unwrap().unwrap();
+            assert_eq!(format!("{read:?}"), format!("{expected:?}"));
+        }
+        assert!(Record::read(&mut cursor).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_record_too_long() {
+        let mut data = vec![Record::KIND_SERVER_FRAME];
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Record::read(&mut Cursor::new(data)),
+            Err(crate::Error::InvalidMessage(_))
+        ));
+    }
+
+    #[maybe_async::maybe_async]
+    async fn write_and_read(connection: &crate::Connection, server: &FakeServer) -> Vec<u8> {
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+        let file = tree
+            .create_file(
+                "file.bin",
+                CreateDisposition::OverwriteIf,
+                FileAccessMask::new()
+                    .with_generic_read(true)
+                    .with_generic_write(true),
+            )
+            .await
+            .unwrap()
+            .unwrap_file();
+        file.write_block(b"recorded", 0, None).await.unwrap();
+        file.close().await.unwrap();
+
+        let file = tree
+            .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        let mut data = vec![0; 8];
+        let read = file.read_block(&mut data, 0, None, false).await.unwrap();
+        file.close().await.unwrap();
+        data.truncate(read);
+        data
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("smb-replay-{}.rec", std::process::id()));
+        let server = FakeServer::new(FakeServerConfig {
+            encrypt_data: true,
+            ..Default::default()
+        });
+        let config = ConnectionConfig {
+            encryption_mode: EncryptionMode::Required,
+            ..Default::default()
+        };
+
+        let connection = server
+            .connect(ConnectionConfig {
+                record_file: Some(path.clone()),
+                ..config.clone()
+            })
+            .await
+            .unwrap();
+        let data = write_and_read(&connection, &server).await;
+        assert_eq!(data, b"recorded");
+        drop(connection);
+
+        // Replay without the server.
+        let connection = crate::Connection::build(
+            FakeServer::SERVER_NAME,
+            "127.0.0.1:445".parse().unwrap(),
+            Guid::generate(),
+            ConnectionConfig {
+                replay_file: Some(path.clone()),
+                ..config
+            },
+        )
+        .unwrap();
+        connection.connect().await.unwrap();
+        let data = write_and_read(&connection, &server).await;
+        assert_eq!(data, b"recorded");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,375 @@
+//! Replaying of recorded frames to a live client.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+#[cfg(not(feature = "async"))]
+use std::sync::Condvar;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use binrw::prelude::*;
+use maybe_async::maybe_async;
+use smb_msg::{
+    CompressedMessage, CompressionCapabilities, EncryptedMessage, EncryptionCipher, Header,
+    Response,
+};
+use smb_transport::{IoVec, MemoryTransport, SmbTransportRead, SmbTransportWrite};
+
+use super::{Recording, SessionKeysObserver};
+use crate::compression::Decompressor;
+use crate::crypto::{DerivedKey, SessionKeys, make_encrypting_algo};
+use crate::session::{MessageEncryptor, MessageSigner};
+
+/// The keys of a session of the replaying client.
+#[derive(Clone)]
+struct LiveSession {
+    signer: MessageSigner,
+    /// `(cipher, client-to-server key, server-to-client key)`, if encryption is available.
+    cipher: Option<(EncryptionCipher, DerivedKey, DerivedKey)>,
+}
+
+/// Collects the keys of the sessions of the replaying client,
+/// so the recorded responses can be signed and encrypted for it.
+#[derive(Default)]
+struct LiveKeys {
+    sessions: Mutex<HashMap<u64, LiveSession>>,
+    #[cfg(feature = "async")]
+    updated: tokio::sync::Notify,
+    #[cfg(not(feature = "async"))]
+    updated: Condvar,
+}
+
+impl std::fmt::Debug for LiveKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LiveKeys")
+            .field(
+                "sessions",
+                &self.sessions.lock().unwrap().keys().collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl SessionKeysObserver for LiveKeys {
+    fn session_keys(&self, keys: &SessionKeys, signer: &MessageSigner) {
+        let cipher = match (keys.cipher, keys.c2s_key, keys.s2c_key) {
+            (Some(cipher), Some(c2s_key), Some(s2c_key)) => Some((cipher, *c2s_key, *s2c_key)),
+            _ => None,
+        };
+        self.sessions.lock().unwrap().insert(
+            keys.session_id,
+            LiveSession {
+                signer: signer.clone(),
+                cipher,
+            },
+        );
+        #[cfg(feature = "async")]
+        self.updated.notify_one();
+        #[cfg(not(feature = "async"))]
+        self.updated.notify_all();
+    }
+}
+
+impl LiveKeys {
+    fn get(&self, session_id: u64) -> crate::Result<LiveSession> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&session_id)
+            .cloned()
+            .ok_or_else(|| {
+                crate::Error::InvalidState(format!("No keys for live session {session_id:#x}"))
+            })
+    }
+
+    /// Waits for the keys of a session. The keys of a session become available only after the client
+    /// sends its final session setup request, which may happen after the request is received.
+    #[cfg(feature = "async")]
+    async fn wait(&self, session_id: u64, timeout: Duration) -> crate::Result<LiveSession> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let updated = self.updated.notified();
+            if let Ok(session) = self.get(session_id) {
+                return Ok(session);
+            }
+            if tokio::time::timeout_at(deadline, updated).await.is_err() {
+                return self.get(session_id);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn wait(&self, session_id: u64, timeout: Duration) -> crate::Result<LiveSession> {
+        let sessions = self.sessions.lock().unwrap();
+        let (sessions, _) = self
+            .updated
+            .wait_timeout_while(sessions, timeout, |s| !s.contains_key(&session_id))
+            .unwrap();
+        drop(sessions);
+        self.get(session_id)
+    }
+}
+
+/// Serves a recording to a live client, over an in-memory transport.
+pub(crate) struct Replayer {
+    recording: Recording,
+    keys: Arc<LiveKeys>,
+    /// How long to wait for the keys of a live session.
+    timeout: Duration,
+    /// Maps recorded message IDs to the live ones.
+    message_ids: HashMap<u64, u64>,
+    /// The compression capabilities, from the recorded negotiate response.
+    compression: Option<CompressionCapabilities>,
+}
+
+impl Replayer {
+    /// Loads the recording at `path`, and starts serving it in the background.
+    ///
+    /// Returns the client end of the transport, and the observer that must receive the keys
+    /// of the client's sessions.
+    pub fn start(
+        path: &Path,
+        timeout: Duration,
+    ) -> crate::Result<(MemoryTransport, Arc<dyn SessionKeysObserver>)> {
+        let recording = Recording::load(path)?;
+        log::info!(
+            "Replaying {} recorded frames from {}",
+            recording.frames.len(),
+            path.display()
+        );
+        let keys = Arc::new(LiveKeys::default());
+        let replayer = Replayer {
+            recording,
+            keys: keys.clone(),
+            timeout,
+            message_ids: HashMap::new(),
+            compression: None,
+        };
+
+        let (client, server) = MemoryTransport::pair();
+        #[cfg(feature = "async")]
+        tokio::spawn(replayer.run(server));
+        #[cfg(not(feature = "async"))]
+        std::thread::spawn(move || replayer.run(server));
+        Ok((client, keys))
+    }
+
+    #[maybe_async]
+    async fn run(mut self, mut transport: MemoryTransport) {
+        match self.serve(&mut transport).await {
+            Ok(()) => log::debug!("Replay completed"),
+            Err(e) => log::warn!("Replay stopped: {e}"),
+        }
+        // Dropping the transport disconnects the client.
+    }
+
+    #[maybe_async]
+    async fn serve(&mut self, transport: &mut MemoryTransport) -> crate::Result<()> {
+        let frames = std::mem::take(&mut self.recording.frames);
+        for (from_client, recorded) in frames {
+            if from_client {
+                let live = SmbTransportRead::receive(transport).await?;
+                self.match_request(recorded, live)?;
+            } else {
+                let response = self.rewrite_response(recorded).await?;
+                SmbTransportWrite::send(transport, &IoVec::from(response)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches a live request to the recorded one, mapping their message IDs.
+    fn match_request(&mut self, recorded: Vec<u8>, live: Vec<u8>) -> crate::Result<()> {
+        let is_smb1 = |data: &[u8]| data.starts_with(b"\xffSMB");
+        if is_smb1(&recorded) || is_smb1(&live) {
+            if is_smb1(&recorded) != is_smb1(&live) {
+                return Err(crate::Error::InvalidMessage(
+                    "Live request does not match the recorded SMB1 negotiate".to_string(),
+                ));
+            }
+            // The SMB2 response to an SMB1 negotiate request always has message ID 0.
+            self.message_ids.insert(0, 0);
+            return Ok(());
+        }
+
+        let (recorded, _) = self.unwrap(recorded, |session_id| {
+            self.recorded_key(session_id, |k| k.c2s_key)
+        })?;
+        let (live, _) = self.unwrap(live, |session_id| {
+            let (cipher, c2s_key, _) = self.keys.get(session_id)?.cipher.ok_or_else(|| {
+                crate::Error::InvalidMessage(format!(
+                    "Encrypted request for live session {session_id:#x} without encryption"
+                ))
+            })?;
+            Ok((cipher, c2s_key))
+        })?;
+
+        let recorded = Self::headers(&recorded)?;
+        let live = Self::headers(&live)?;
+        let commands = |headers: &[(usize, usize, Header)]| {
+            headers
+                .iter()
+                .map(|(_, _, h)| h.command)
+                .collect::<Vec<_>>()
+        };
+        if commands(&recorded) != commands(&live) {
+            return Err(crate::Error::InvalidMessage(format!(
+                "Live request {:?} does not match the recorded request {:?}",
+                commands(&live),
+                commands(&recorded)
+            )));
+        }
+        for ((_, _, recorded), (_, _, live)) in recorded.iter().zip(live.iter()) {
+            self.message_ids
+                .insert(recorded.message_id, live.message_id);
+        }
+        Ok(())
+    }
+
+    /// Adjusts a recorded response to the live client:
+    /// maps the message IDs, and signs and encrypts it using the live keys.
+    ///
+    /// Compressed responses are sent decompressed.
+    #[maybe_async]
+    async fn rewrite_response(&mut self, recorded: Vec<u8>) -> crate::Result<Vec<u8>> {
+        if recorded.starts_with(b"\xffSMB") {
+            return Ok(recorded);
+        }
+
+        let (mut data, encrypted_session) = self.unwrap(recorded, |session_id| {
+            self.recorded_key(session_id, |k| k.s2c_key)
+        })?;
+
+        for (start, end, mut header) in Self::headers(&data)? {
+            if header.command == smb_msg::Command::Negotiate {
+                let response = Response::try_from(&data[start..end])?;
+                if let Response::Plain(response) = response {
+                    self.compression = response
+                        .content
+                        .to_negotiate()?
+                        .get_ctx_compression_capabilities()
+                        .cloned();
+                }
+            }
+
+            if header.message_id != u64::MAX {
+                header.message_id = *self.message_ids.get(&header.message_id).ok_or_else(|| {
+                    crate::Error::InvalidMessage(format!(
+                        "Recorded response to an unknown message ID {}",
+                        header.message_id
+                    ))
+                })?;
+            }
+            header.write(&mut Cursor::new(
+                &mut data[start..start + Header::STRUCT_SIZE],
+            ))?;
+
+            if header.flags.signed() {
+                let mut signer = self
+                    .keys
+                    .wait(header.session_id, self.timeout)
+                    .await?
+                    .signer;
+                let mut message = IoVec::from(data[start..end].to_vec());
+                signer.sign_message(&mut header, &mut message)?;
+                data[start..start + Header::STRUCT_SIZE]
+                    .copy_from_slice(&message.first().unwrap()[..Header::STRUCT_SIZE]);
+            }
+        }
+
+        let session_id = match encrypted_session {
+            Some(session_id) => session_id,
+            None => return Ok(data),
+        };
+        let live = self.keys.wait(session_id, self.timeout).await?;
+        let (cipher, _, s2c_key) = live.cipher.ok_or_else(|| {
+            crate::Error::InvalidState(format!(
+                "Live session {session_id:#x} has no encryption keys"
+            ))
+        })?;
+        let mut message = IoVec::from(data);
+        let header = MessageEncryptor::new(make_encrypting_algo(cipher, &s2c_key)?)
+            .encrypt_message(&mut message, session_id)?;
+        let mut result = Cursor::new(vec![]);
+        header.write(&mut result)?;
+        let mut result = result.into_inner();
+        for buf in message.iter() {
+            result.extend_from_slice(buf);
+        }
+        Ok(result)
+    }
+
+    fn recorded_key(
+        &self,
+        session_id: u64,
+        key: impl Fn(&super::RecordedKeys) -> DerivedKey,
+    ) -> crate::Result<(EncryptionCipher, DerivedKey)> {
+        let keys = self.recording.keys.get(&session_id).ok_or_else(|| {
+            crate::Error::InvalidMessage(format!(
+                "No recorded keys for encrypted session {session_id:#x}"
+            ))
+        })?;
+        Ok((keys.cipher, key(keys)))
+    }
+
+    /// Removes the encryption and compression of a frame, using the key returned by `key`
+    /// for the session of the frame.
+    ///
+    /// Returns the plain frame, and the session ID if the frame was encrypted.
+    fn unwrap(
+        &self,
+        mut data: Vec<u8>,
+        key: impl Fn(u64) -> crate::Result<(EncryptionCipher, DerivedKey)>,
+    ) -> crate::Result<(Vec<u8>, Option<u64>)> {
+        let mut encrypted_session = None;
+        if data.starts_with(b"\xfdSMB") {
+            let message = EncryptedMessage::read_le(&mut Cursor::new(&data))?;
+            let (cipher, key) = key(message.header.session_id)?;
+            let mut buffer = message.encrypted_message;
+            make_encrypting_algo(cipher, &key)?.decrypt(
+                &mut buffer,
+                &message.header.aead_bytes(),
+                &message.header.nonce,
+                message.header.signature,
+            )?;
+            data = buffer;
+            encrypted_session = Some(message.header.session_id);
+        }
+
+        if data.starts_with(b"\xfcSMB") {
+            let message = CompressedMessage::read_le(&mut Cursor::new(&data))?;
+            let caps = self.compression.as_ref().ok_or_else(|| {
+                crate::Error::InvalidMessage(
+                    "Compressed frame without negotiated compression".to_string(),
+                )
+            })?;
+            data = Decompressor::new(caps).decompress_bytes(&message)?;
+        }
+
+        Ok((data, encrypted_session))
+    }
+
+    /// Returns the `(start, end, header)` of each message in a plain (possibly compounded) frame.
+    fn headers(data: &[u8]) -> crate::Result<Vec<(usize, usize, Header)>> {
+        let mut result = vec![];
+        let mut start = 0;
+        loop {
+            let header = Header::read_le(&mut Cursor::new(&data[start..]))?;
+            let end = match header.next_command {
+                0 => data.len(),
+                next => start + next as usize,
+            };
+            if end > data.len() || end < start + Header::STRUCT_SIZE {
+                return Err(crate::Error::InvalidMessage(
+                    "Invalid next command offset".to_string(),
+                ));
+            }
+            result.push((start, end, header));
+            if end == data.len() {
+                return Ok(result);
+            }
+            start = end;
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Recording of raw frames and session keys.
+
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "async")]
+use futures_core::future::BoxFuture;
+#[cfg(feature = "async")]
+use futures_util::FutureExt;
+use smb_transport::{IoVec, SmbTransport, SmbTransportRead, SmbTransportWrite};
+
+use super::{MAGIC, Record, RecordedKeys, SessionKeysObserver};
+use crate::crypto::SessionKeys;
+use crate::session::MessageSigner;
+
+/// A record file, being written.
+#[derive(Debug)]
+pub(crate) struct RecordFile {
+    file: Mutex<File>,
+    /// Set after the first write failure, to avoid flooding the logs.
+    failed: AtomicBool,
+}
+
+impl RecordFile {
+    pub fn create(path: &Path) -> crate::Result<Arc<RecordFile>> {
+        let mut file = File::create(path)?;
+        std::io::Write::write_all(&mut file, MAGIC)?;
+        log::info!("Recording raw SMB traffic to {}", path.display());
+        Ok(Arc::new(RecordFile {
+            file: Mutex::new(file),
+            failed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Returns the record file path from the configuration, or from the environment, if any.
+    pub fn path_from(configured: &Option<PathBuf>) -> Option<PathBuf> {
+        configured
+            .clone()
+            .or_else(|| std::env::var_os(super::RECORD_FILE_ENV_VAR).map(PathBuf::from))
+    }
+
+    fn write(&self, record: Record) {
+        let result = record.write(&mut *self.file.lock().unwrap());
+        if let Err(e) = result {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                log::warn!("Failed to write to record file: {e}");
+            }
+        }
+    }
+
+    fn frame(&self, from_client: bool, data: Vec<u8>) {
+        self.write(Record::Frame { from_client, data });
+    }
+}
+
+impl SessionKeysObserver for RecordFile {
+    fn session_keys(&self, keys: &SessionKeys, _signer: &MessageSigner) {
+        // Signed frames can be parsed without the signing key, so only encryption keys are recorded.
+        if let (Some(cipher), Some(c2s_key), Some(s2c_key)) =
+            (keys.cipher, keys.c2s_key, keys.s2c_key)
+        {
+            self.write(Record::Keys {
+                session_id: keys.session_id,
+                keys: RecordedKeys {
+                    cipher,
+                    c2s_key: *c2s_key,
+                    s2c_key: *s2c_key,
+                },
+            });
+        }
+    }
+}
+
+/// Wraps a transport, recording every frame sent and received through it.
+///
+/// Only complete messages are recorded - that is, ones sent using [`SmbTransportWrite::send`]
+/// and received using [`SmbTransportRead::receive`].
+pub(crate) struct RecordingTransport {
+    inner: Box<dyn SmbTransport>,
+    file: Arc<RecordFile>,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Box<dyn SmbTransport>, file: Arc<RecordFile>) -> Self {
+        Self { inner, file }
+    }
+}
+
+struct RecordingRead {
+    inner: Box<dyn SmbTransportRead>,
+    file: Arc<RecordFile>,
+}
+
+struct RecordingWrite {
+    inner: Box<dyn SmbTransportWrite>,
+    file: Arc<RecordFile>,
+}
+
+fn consolidate(data: &IoVec) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.total_size());
+    for buf in data.iter() {
+        result.extend_from_slice(buf);
+    }
+    result
+}
+
+impl SmbTransport for RecordingTransport {
+    #[cfg(feature = "async")]
+    fn connect<'a>(
+        &'a mut self,
+        server_name: &'a str,
+        address: SocketAddr,
+    ) -> BoxFuture<'a, smb_transport::error::Result<()>> {
+        self.inner.connect(server_name, address)
+    }
+    #[cfg(not(feature = "async"))]
+    fn connect(
+        &mut self,
+        server_name: &str,
+        address: SocketAddr,
+    ) -> smb_transport::error::Result<()> {
+        self.inner.connect(server_name, address)
+    }
+
+    fn default_port(&self) -> u16 {
+        self.inner.default_port()
+    }
+
+    fn split(
+        self: Box<Self>,
+    ) -> smb_transport::error::Result<(Box<dyn SmbTransportRead>, Box<dyn SmbTransportWrite>)> {
+        let (read, write) = self.inner.split()?;
+        Ok((
+            Box::new(RecordingRead {
+                inner: read,
+                file: self.file.clone(),
+            }),
+            Box::new(RecordingWrite {
+                inner: write,
+                file: self.file,
+            }),
+        ))
+    }
+
+    fn remote_address(&self) -> smb_transport::error::Result<SocketAddr> {
+        self.inner.remote_address()
+    }
+}
+
+impl SmbTransportWrite for RecordingTransport {
+    #[cfg(feature = "async")]
+    fn send_raw<'a>(
+        &'a mut self,
+        buf: &'a [u8],
+    ) -> BoxFuture<'a, smb_transport::error::Result<()>> {
+        self.inner.send_raw(buf)
+    }
+    #[cfg(not(feature = "async"))]
+    fn send_raw(&mut self, buf: &[u8]) -> smb_transport::error::Result<()> {
+        self.inner.send_raw(buf)
+    }
+
+    #[cfg(feature = "async")]
+    fn send<'a>(&'a mut self, data: &'a IoVec) -> BoxFuture<'a, smb_transport::error::Result<()>> {
+        self.file.frame(true, consolidate(data));
+        self.inner.send(data)
+    }
+    #[cfg(not(feature = "async"))]
+    fn send(&mut self, data: &IoVec) -> smb_transport::error::Result<()> {
+        self.file.frame(true, consolidate(data));
+        self.inner.send(data)
+    }
+}
+
+impl SmbTransportRead for RecordingTransport {
+    #[cfg(feature = "async")]
+    fn receive_exact<'a>(
+        &'a mut self,
+        out_buf: &'a mut [u8],
+    ) -> BoxFuture<'a, smb_transport::error::Result<()>> {
+        self.inner.receive_exact(out_buf)
+    }
+    #[cfg(not(feature = "async"))]
+    fn receive_exact(&mut self, out_buf: &mut [u8]) -> smb_transport::error::Result<()> {
+        self.inner.receive_exact(out_buf)
+    }
+
+    #[cfg(feature = "async")]
+    fn receive<'a>(&'a mut self) -> BoxFuture<'a, smb_transport::error::Result<Vec<u8>>> {
+        async {
+            let data = self.inner.receive().await?;
+            self.file.frame(false, data.clone());
+            Ok(data)
+        }
+        .boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn receive(&mut self) -> smb_transport::error::Result<Vec<u8>> {
+        let data = self.inner.receive()?;
+        self.file.frame(false, data.clone());
+        Ok(data)
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn set_read_timeout(&self, timeout: std::time::Duration) -> smb_transport::error::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+impl SmbTransportWrite for RecordingWrite {
+    #[cfg(feature = "async")]
+    fn send_raw<'a>(
+        &'a mut self,
+        buf: &'a [u8],
+    ) -> BoxFuture<'a, smb_transport::error::Result<()>> {
+        self.inner.send_raw(buf)
+    }
+    #[cfg(not(feature = "async"))]
+    fn send_raw(&mut self, buf: &[u8]) -> smb_transport::error::Result<()> {
+        self.inner.send_raw(buf)
+    }
+
+    #[cfg(feature = "async")]
+    fn send<'a>(&'a mut self, data: &'a IoVec) -> BoxFuture<'a, smb_transport::error::Result<()>> {
+        self.file.frame(true, consolidate(data));
+        self.inner.send(data)
+    }
+    #[cfg(not(feature = "async"))]
+    fn send(&mut self, data: &IoVec) -> smb_transport::error::Result<()> {
+        self.file.frame(true, consolidate(data));
+        self.inner.send(data)
+    }
+}
+
+impl SmbTransportRead for RecordingRead {
+    #[cfg(feature = "async")]
+    fn receive_exact<'a>(
+        &'a mut self,
+        out_buf: &'a mut [u8],
+    ) -> BoxFuture<'a, smb_transport::error::Result<()>> {
+        self.inner.receive_exact(out_buf)
+    }
+    #[cfg(not(feature = "async"))]
+    fn receive_exact(&mut self, out_buf: &mut [u8]) -> smb_transport::error::Result<()> {
+        self.inner.receive_exact(out_buf)
+    }
+
+    #[cfg(feature = "async")]
+    fn receive<'a>(&'a mut self) -> BoxFuture<'a, smb_transport::error::Result<Vec<u8>>> {
+        async {
+            let data = self.inner.receive().await?;
+            self.file.frame(false, data.clone());
+            Ok(data)
+        }
+        .boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn receive(&mut self) -> smb_transport::error::Result<Vec<u8>> {
+        let data = self.inner.receive()?;
+        self.file.frame(false, data.clone());
+        Ok(data)
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn set_read_timeout(&self, timeout: std::time::Duration) -> smb_transport::error::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
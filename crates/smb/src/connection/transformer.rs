@@ -7,6 +7,7 @@ use smb_msg::*;
 use smb_transport::IoVec;
 use std::{collections::HashMap, io::Cursor, sync::Arc};
 
+use super::capture::Capture;
 use super::connection_info::ConnectionInfo;
 
 /// The [`Transformer`] structure is responsible for transforming messages to and from bytes,
@@ -21,12 +22,17 @@ pub struct Transformer {
     sessions: RwLock<HashMap<u64, Arc<RwLock<SessionAndChannel>>>>,
 
     config: RwLock<TransformerConfig>,
+
+    /// Captures plain messages, if enabled.
+    capture: OnceCell<Capture>,
 }
 
 #[derive(Default, Debug)]
 struct TransformerConfig {
     /// Compressors for this connection.
     compress: Option<(Compressor, Decompressor)>,
+    /// Decides which messages to compress.
+    compression: Option<Arc<CompressionTracker>>,
 
     negotiated: bool,
 }
@@ -46,12 +52,16 @@ impl Transformer {
         }
 
         let mut config = self.config.write().await?;
-        if neg_info.dialect.supports_compression() && neg_info.config.compression_enabled {
-            let compress = neg_info
-                .negotiation
-                .compression
-                .as_ref()
-                .map(|c| (Compressor::new(c), Decompressor::new(c)));
+        // Compression may be enabled per share, so the compressors are set up whenever negotiated.
+        if neg_info.dialect.supports_compression() {
+            let max_ratio_percent = neg_info.compression.policy().max_ratio_percent;
+            let compress = neg_info.negotiation.compression.as_ref().map(|c| {
+                (
+                    Compressor::new(c).with_max_ratio_percent(max_ratio_percent),
+                    Decompressor::new(c),
+                )
+            });
+            config.compression = compress.as_ref().map(|_| neg_info.compression.clone());
             config.compress = compress;
         }
 
@@ -60,6 +70,13 @@ impl Transformer {
         Ok(())
     }
 
+    /// Starts capturing plain messages that pass through the transformer.
+    pub(crate) fn start_capture(&self, capture: Capture) -> crate::Result<()> {
+        self.capture
+            .set(capture)
+            .map_err(|_| crate::Error::InvalidState("Capture is already started".into()))
+    }
+
     /// Notifies that a session has started.
     pub async fn session_started(
         &self,
@@ -161,6 +178,15 @@ impl Transformer {
         let should_sign = msg.message.header.flags.signed();
         let session_id = msg.message.header.session_id;
 
+        // Ask the server to compress read responses, as the policy decides.
+        if let RequestContent::Read(read) = &mut msg.message.content {
+            if let Some(compression) = &self.config.read().await?.compression {
+                let compressed = compression
+                    .should_request_compressed_read(msg.message.header.tree_id, read.length);
+                read.flags.set_read_compressed(compressed);
+            }
+        }
+
         let mut outgoing_data = IoVec::default();
         // Plain header + content
         {
@@ -206,22 +232,39 @@ impl Transformer {
             );
         };
 
+        if let Some(capture) = self.capture.get() {
+            capture.outgoing(&outgoing_data);
+        }
+
         // 2. Compress
-        const COMPRESSION_THRESHOLD: usize = 1024;
         outgoing_data = {
-            if msg.compress && outgoing_data.total_size() > COMPRESSION_THRESHOLD {
-                let rconfig = self.config.read().await?;
-                if let Some(compress) = &rconfig.compress {
-                    // Build a vector of the entire data. In the future, this may be optimized to avoid copying.
-                    // currently, there's not chained compression, and copy will occur anyway.
-                    outgoing_data.consolidate();
-                    let compressed = compress.0.compress(outgoing_data.first().unwrap())?;
-
-                    let mut compressed_result = IoVec::default();
-                    let write_compressed =
-                        compressed_result.add_owned(Vec::with_capacity(compressed.total_size()));
-                    compressed.write(&mut Cursor::new(write_compressed))?;
-                    compressed_result
+            let rconfig = self.config.read().await?;
+            if let (true, Some(compress), Some(compression)) =
+                (msg.compress, &rconfig.compress, &rconfig.compression)
+            {
+                // The additional data (e.g. a write payload) is a separate segment,
+                // so chained compression may handle it apart from the message itself.
+                let segments = outgoing_data.iter().map(|b| &**b).collect::<Vec<_>>();
+                let command = msg.message.header.command;
+                let size = outgoing_data.total_size();
+                let payload = segments.last().unwrap();
+                if compression.should_compress(command, msg.message.header.tree_id, payload, size) {
+                    let compressed = compress.0.compress(&segments)?;
+                    compression.compressed(
+                        command,
+                        size,
+                        compressed.as_ref().map(|c| c.total_size()),
+                    );
+                    match compressed {
+                        Some(compressed) => {
+                            let mut compressed_result = IoVec::default();
+                            let write_compressed = compressed_result
+                                .add_owned(Vec::with_capacity(compressed.total_size()));
+                            compressed.write(&mut Cursor::new(write_compressed))?;
+                            compressed_result
+                        }
+                        None => outgoing_data,
+                    }
                 } else {
                     outgoing_data
                 }
@@ -296,7 +339,13 @@ impl Transformer {
             let rconfig = self.config.read().await?;
             form.compressed = true;
             match &rconfig.compress {
-                Some(compress) => compress.1.decompress(&compressed_message)?,
+                Some(compress) => {
+                    let (message, raw) = compress.1.decompress(&compressed_message)?;
+                    if let Some(compression) = &rconfig.compression {
+                        compression.received(compressed_message.total_size(), raw.len());
+                    }
+                    (message, raw)
+                }
                 None => {
                     return Err(crate::Error::TranformFailed(TransformError {
                         outgoing: false,
@@ -316,6 +365,10 @@ impl Transformer {
             _ => panic!("Unexpected message type"),
         };
 
+        if let Some(capture) = self.capture.get() {
+            capture.incoming(&raw);
+        }
+
         let iovec = IoVec::from(raw);
         // If fails, return TranformFailed, with message id.
         // this allows to notify the error to the task that was waiting for this message.
@@ -360,24 +413,34 @@ impl Transformer {
             return Ok(());
         }
 
+        // The final session setup response is signed with the keys of the channel being set up,
+        // which may only be derived from the response itself (e.g. from a Kerberos AP-REP),
+        // and the session may not even be known yet. It is verified by the session setup, once the channel is ready.
+        if message.header.command == Command::SessionSetup
+            && message.header.status == Status::Success as u32
+        {
+            return Ok(());
+        }
+
         // Verify signature (if required, according to the spec)
         let session_id = message.header.session_id;
-        let mut signer = self
-            ._with_channel(session_id, |session| {
-                let channel_info = session
-                    .channel
-                    .as_ref()
-                    .ok_or(crate::Error::TranformFailed(TransformError {
-                        outgoing: false,
-                        phase: TransformPhase::SignVerify,
-                        session_id: Some(session_id),
-                        why: "Message is required to be signed, but no channel is set up!",
-                        msg_id: Some(message.header.message_id),
-                    }))?;
-
-                Ok(channel_info.signer()?.clone())
+        let signer = self
+            ._with_channel(session_id, |session| match session.channel.as_ref() {
+                Some(channel_info) => Ok(Some(channel_info.signer()?.clone())),
+                // Intermediate session setup responses of new sessions are not signed.
+                None if message.header.command == Command::SessionSetup => Ok(None),
+                None => Err(crate::Error::TranformFailed(TransformError {
+                    outgoing: false,
+                    phase: TransformPhase::SignVerify,
+                    session_id: Some(session_id),
+                    why: "Message is required to be signed, but no channel is set up!",
+                    msg_id: Some(message.header.message_id),
+                })),
             })
             .await?;
+        let Some(mut signer) = signer else {
+            return Ok(());
+        };
 
         signer.verify_signature(&mut message.header, raw)?;
         log::debug!(
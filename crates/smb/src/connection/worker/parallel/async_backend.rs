@@ -34,6 +34,7 @@ impl AsyncBackend {
                 Err(Error::TransportError(TransportError::NotConnected)) => {
                     log::error!("Connection was force-closed by the server.");
                     self_ref.token.cancel();
+                    worker.connection_lost();
                     break;
                 }
                 Err(Error::ConnectionStopped) => {
@@ -71,6 +72,7 @@ impl AsyncBackend {
                 Err(Error::TransportError(TransportError::NotConnected)) => {
                     log::error!("Connection was force-closed by the server.");
                     self_ref.token.cancel();
+                    worker.connection_lost();
                     break;
                 }
                 Err(Error::ConnectionStopped) => {
@@ -95,7 +97,10 @@ impl AsyncBackend {
         rtransport: &mut dyn SmbTransportRead,
         worker: &Arc<ParallelWorker<Self>>,
     ) -> crate::Result<()> {
+        // Branches are polled in order, rather than randomly, so runs are reproducible
+        // under a simulated runtime.
         select! {
+            biased;
             // Receive a message from the server.
             message_from_server = rtransport.receive() => {
                 worker.incoming_data_callback(message_from_server).await
@@ -116,6 +121,7 @@ impl AsyncBackend {
         worker: &Arc<ParallelWorker<Self>>,
     ) -> crate::Result<()> {
         select! {
+            biased;
             // Send a message to the server.
             message_to_send = send_channel.recv() => {
                 worker.outgoing_data_callback(message_to_send, wtransport).await
@@ -194,6 +200,7 @@ impl MultiWorkerBackend for AsyncBackend {
             })?
         } else {
             tokio::select! {
+                biased;
                 msg = waiter => {
                     msg.map_err(|_| Error::MessageProcessingError("Failed to receive message.".to_string()))?
                 },
@@ -207,10 +214,8 @@ impl MultiWorkerBackend for AsyncBackend {
     fn send_notify(
         tx: Self::AwaitingNotifier,
         msg: crate::Result<IncomingMessage>,
-    ) -> crate::Result<()> {
-        tx.send(msg).map_err(|_| {
-            Error::MessageProcessingError("Failed to send message to awaiting task.".to_string())
-        })
+    ) -> Option<crate::Result<IncomingMessage>> {
+        tx.send(msg).err()
     }
 
     fn make_send_channel_pair() -> (
@@ -36,8 +36,10 @@ pub trait MultiWorkerBackend {
         waiter: Self::AwaitingWaiter,
         timeout: Duration,
     ) -> crate::Result<IncomingMessage>;
+    /// Notifies an awaiting task of its message.
+    /// Returns the message back, if the task is no longer awaiting it (e.g., it timed out).
     fn send_notify(
         tx: Self::AwaitingNotifier,
         msg: crate::Result<IncomingMessage>,
-    ) -> crate::Result<()>;
+    ) -> Option<crate::Result<IncomingMessage>>;
 }
@@ -1,5 +1,5 @@
 use crate::connection::transformer::Transformer;
-use crate::connection::worker::Worker;
+use crate::connection::worker::{ConnectionLostCallback, Worker};
 use crate::msg_handler::ReceiveOptions;
 use crate::sync_helpers::*;
 use maybe_async::*;
@@ -47,6 +47,10 @@ where
 
     /// A flag that indicates whether the worker is stopped.
     stopped: AtomicBool,
+    /// A flag that indicates whether the connection was lost.
+    lost: AtomicBool,
+    /// Called once the connection is lost.
+    connection_lost_callback: OnceCell<ConnectionLostCallback>,
     /// The current timeout configured for the worker.
     timeout: AtomicU64,
 }
@@ -90,6 +94,18 @@ where
         self.stopped.load(std::sync::atomic::Ordering::SeqCst)
     }
 
+    /// This is a function that should be used by multi worker implementations (async/mtd),
+    /// once the transport is disconnected. Unless the worker is being stopped,
+    /// calls the connection lost callback (once).
+    pub(crate) fn connection_lost(&self) {
+        if self.stopped() || self.lost.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(callback) = self.connection_lost_callback.get() {
+            callback();
+        }
+    }
+
     /// This is a function that should be used by multi worker implementations (async/mtd),
     /// after gettting a messages from the server, this function processes it and
     /// notifies the awaiting tasks.
@@ -127,14 +143,15 @@ where
             let msg = msg?;
 
             // Server-to-client commands check.
-            // allow only oplock break and server to client notification.
+            // allow only oplock/lease break and server to client notification.
             if !matches!(
                 msg.message.content,
                 ResponseContent::OplockBreakNotify(_)
+                    | ResponseContent::LeaseBreakNotify(_)
                     | ResponseContent::ServerToClientNotification(_)
             ) {
                 return Err(Error::MessageProcessingError(
-                    "Received notification message, but not an OPLOCK_BREAK or SERVER_TO_CLIENT_NOTIFICATION.".to_string(),
+                    "Received notification message, but not an OPLOCK_BREAK, LEASE_BREAK or SERVER_TO_CLIENT_NOTIFICATION.".to_string(),
                 ));
             }
 
@@ -157,7 +174,11 @@ where
         match message_waiter {
             Some(tx) => {
                 log::trace!("Waking up awaiting task for key {msg_id}.");
-                T::send_notify(tx, msg)?;
+                if let Some(msg) = T::send_notify(tx, msg) {
+                    // The task stopped awaiting (e.g. timed out), but may await the message again.
+                    log::trace!("Task awaiting {msg_id} is gone. Storing message until awaited.");
+                    state.pending.insert(msg_id, msg);
+                }
             }
             None => {
                 log::trace!("Storing message until awaited: {msg_id}.",);
@@ -222,6 +243,8 @@ where
             notify_messages_channel: Default::default(),
             sender: tx,
             stopped: AtomicBool::new(false),
+            lost: AtomicBool::new(false),
+            connection_lost_callback: Default::default(),
             timeout: AtomicU64::new(u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)),
         });
 
@@ -250,6 +273,12 @@ where
         .await
     }
 
+    fn on_connection_lost(&self, callback: ConnectionLostCallback) -> crate::Result<()> {
+        self.connection_lost_callback
+            .set(callback)
+            .map_err(|_| Error::InvalidState("Connection lost callback is already set.".into()))
+    }
+
     async fn send(&self, msg: OutgoingMessage) -> crate::Result<SendMessageResult> {
         log::trace!("ParallelWorker::send({msg:?}) called");
         let return_raw_data = msg.return_raw_data;
@@ -277,7 +306,7 @@ where
     async fn receive_next(&self, options: &ReceiveOptions<'_>) -> crate::Result<IncomingMessage> {
         let wait_for_receive = {
             let mut state = self.state.lock().await?;
-            if self.stopped() {
+            if self.stopped() || self.lost.load(Ordering::SeqCst) {
                 log::trace!("Connection is closed, avoid receiving.");
                 return Err(Error::ConnectionStopped);
             }
@@ -41,6 +41,7 @@ impl ThreadingBackend {
                     log::error!("Connection closed.");
                     self.stopped
                         .store(true, std::sync::atomic::Ordering::SeqCst);
+                    self.worker.connection_lost();
                     break;
                 }
                 Err(Error::ConnectionStopped) => {
@@ -71,6 +72,7 @@ impl ThreadingBackend {
                     log::error!("Connection closed.");
                     self.stopped
                         .store(true, std::sync::atomic::Ordering::SeqCst);
+                    self.worker.connection_lost();
                     break;
                 }
                 Err(Error::ConnectionStopped) => {
@@ -148,8 +150,8 @@ impl MultiWorkerBackend for ThreadingBackend {
             .take()
             .ok_or(Error::ConnectionStopped)?;
 
-        // wake up the sender to stop the loop.
-        self.worker.sender.send(None).unwrap();
+        // wake up the sender to stop the loop. It might have already stopped, if the transport failed.
+        let _ = self.worker.sender.send(None);
 
         // Join the threads.
         handles
@@ -194,10 +196,8 @@ impl MultiWorkerBackend for ThreadingBackend {
     fn send_notify(
         tx: Self::AwaitingNotifier,
         msg: crate::Result<IncomingMessage>,
-    ) -> crate::Result<()> {
-        tx.send(msg).map_err(|_| {
-            Error::MessageProcessingError("Failed to send message to awaiting task.".to_string())
-        })
+    ) -> Option<crate::Result<IncomingMessage>> {
+        tx.send(msg).map_err(|e| e.0).err()
     }
 
     fn make_send_channel_pair() -> (
@@ -6,11 +6,11 @@ use crate::{
 use smb_transport::{SmbTransport, TransportError};
 use std::sync::OnceLock;
 use std::{
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard},
     time::Duration,
 };
 
-use super::Worker;
+use super::{ConnectionLostCallback, Worker};
 
 /// Single-threaded worker.
 pub struct SingleWorker {
@@ -20,6 +20,20 @@ pub struct SingleWorker {
     transport: Mutex<OnceLock<Box<dyn SmbTransport>>>,
     transformer: Transformer,
     timeout: Mutex<Option<Duration>>,
+    /// Called once the connection is lost.
+    connection_lost_callback: OnceLock<ConnectionLostCallback>,
+}
+
+impl SingleWorker {
+    /// Drops the disconnected transport, so the connection is reported lost only once,
+    /// and calls the connection lost callback.
+    fn connection_lost(&self, mut transport: MutexGuard<'_, OnceLock<Box<dyn SmbTransport>>>) {
+        transport.take();
+        drop(transport);
+        if let Some(callback) = self.connection_lost_callback.get() {
+            callback();
+        }
+    }
 }
 
 impl Worker for SingleWorker {
@@ -29,6 +43,7 @@ impl Worker for SingleWorker {
             transport: Mutex::new(OnceLock::from(transport)),
             transformer: Transformer::default(),
             timeout: Mutex::new(Some(timeout)),
+            connection_lost_callback: OnceLock::new(),
         }))
     }
 
@@ -40,6 +55,12 @@ impl Worker for SingleWorker {
         Ok(())
     }
 
+    fn on_connection_lost(&self, callback: ConnectionLostCallback) -> crate::Result<()> {
+        self.connection_lost_callback.set(callback).map_err(|_| {
+            crate::Error::InvalidState("Connection lost callback is already set.".into())
+        })
+    }
+
     fn send(&self, msg: OutgoingMessage) -> crate::Result<SendMessageResult> {
         let msg_id = msg.message.header.message_id;
         let return_raw_data = msg.return_raw_data;
@@ -47,9 +68,14 @@ impl Worker for SingleWorker {
         let msg_to_send = self.transformer.transform_outgoing(msg)?;
 
         let mut t = self.transport.lock()?;
-        t.get_mut()
+        let result = t
+            .get_mut()
             .ok_or(crate::Error::ConnectionStopped)?
-            .send(&msg_to_send)?;
+            .send(&msg_to_send);
+        if let Err(TransportError::NotConnected) = result {
+            self.connection_lost(t);
+        }
+        result?;
 
         let raw_msg = if return_raw_data {
             Some(msg_to_send)
@@ -65,20 +91,36 @@ impl Worker for SingleWorker {
         let mut self_mut = self.transport.lock()?;
         let transport = self_mut.get_mut().ok_or(crate::Error::ConnectionStopped)?;
 
+        // A timeout in the options applies to this receive only.
+        let timeout = match options.timeout {
+            Some(Duration::ZERO) => {
+                return Err(Error::OperationTimeout(
+                    TimedOutTask::ReceiveNextMessage,
+                    Duration::ZERO,
+                ));
+            }
+            Some(timeout) => {
+                transport.set_read_timeout(timeout)?;
+                Some(timeout)
+            }
+            None => *self.timeout.lock()?,
+        };
+
+        let msg = transport.receive();
         if options.timeout.is_some() {
-            // TODO: implement receive with timeout in options.
-            unimplemented!("Receive with timeout is not supported in SingleWorker");
+            if let Some(default_timeout) = *self.timeout.lock()? {
+                transport.set_read_timeout(default_timeout).ok();
+            }
         }
-
-        let msg = transport.receive().map_err(|e| match e {
+        if let Err(TransportError::NotConnected) = msg {
+            self.connection_lost(self_mut);
+        }
+        let msg = msg.map_err(|e| match e {
             TransportError::IoError(ioe) => {
                 if ioe.kind() == std::io::ErrorKind::WouldBlock {
                     Error::OperationTimeout(
                         TimedOutTask::ReceiveNextMessage,
-                        self.timeout
-                            .lock()
-                            .map(|v| v.unwrap_or(Duration::ZERO))
-                            .unwrap_or(Duration::MAX),
+                        timeout.unwrap_or(Duration::ZERO),
                     )
                 } else {
                     crate::Error::IoError(ioe)
@@ -14,6 +14,9 @@ use crate::{
     msg_handler::{IncomingMessage, OutgoingMessage, SendMessageResult},
 };
 
+/// A callback, called by a worker once its connection is lost.
+pub type ConnectionLostCallback = Box<dyn Fn() + Send + Sync>;
+
 /// SMB2 connection worker.
 ///
 /// Each Implementation of this trait is responsible for handling the connection to the server,
@@ -28,6 +31,10 @@ pub trait Worker: Sized + std::fmt::Debug {
     /// Stops the worker, shutting down the connection.
     async fn stop(&self) -> crate::Result<()>;
 
+    /// Sets the callback to call once the connection is lost:
+    /// when the transport is disconnected, rather than the worker being stopped.
+    fn on_connection_lost(&self, callback: ConnectionLostCallback) -> crate::Result<()>;
+
     async fn send(&self, msg: OutgoingMessage) -> crate::Result<SendMessageResult>;
 
     /// (Internal)
@@ -1,9 +1,12 @@
 mod encryption;
 mod kbkdf;
+mod keylog;
 mod signing;
 
 pub use encryption::{ENCRYPTING_ALGOS, EncryptingAlgo, make_encrypting_algo};
 pub use kbkdf::{DerivedKey, KeyToDerive, kbkdf_hmacsha256};
+pub use keylog::KEYLOG_FILE_ENV_VAR;
+pub(crate) use keylog::{SessionKeys, export_session_keys};
 pub use signing::{SIGNING_ALGOS, SigningAlgo, make_signing_algo};
 
 use crypto_common::InvalidLength;
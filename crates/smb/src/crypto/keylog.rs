@@ -0,0 +1,110 @@
+//! Export of session keys, for decrypting captured SMB traffic in Wireshark.
+//!
+//! Each session that is set up appends a single line to the key log file,
+//! in the format of Wireshark's SMB2 "Secret session keys for decryption" table (`smb2_seskey_list`):
+//! ```text
+//! "<Session ID>","<Session Key>","<Server-to-client key (ServerOut)>","<Client-to-server key (ServerIn)>"
+//! ```
+//! All the values are hex strings. The session ID is written in wire (little-endian) byte order,
+//! as displayed by Wireshark. Encryption keys are empty if encryption is not available for the session.
+//!
+//! To use the file, either copy it to `smb2_seskey_list` in your Wireshark profile directory,
+//! or import it through *Preferences → Protocols → SMB2 → Secret session keys for decryption*.
+//!
+//! Use [`ConnectionConfig::keylog_file`][crate::ConnectionConfig::keylog_file],
+//! or set the [`KEYLOG_FILE_ENV_VAR`] environment variable, to enable exporting.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use smb_msg::EncryptionCipher;
+
+use super::{DerivedKey, KeyToDerive};
+
+/// The environment variable that specifies a key log file path,
+/// if not set in the connection configuration.
+pub const KEYLOG_FILE_ENV_VAR: &str = "SMBKEYLOGFILE";
+
+/// Serializes writes to key log files across connections.
+static KEYLOG_WRITE: Mutex<()> = Mutex::new(());
+
+/// The keys of a single session, to be exported.
+pub(crate) struct SessionKeys<'a> {
+    pub session_id: u64,
+    pub session_key: &'a KeyToDerive,
+    /// The cipher of the session, if encryption is available.
+    pub cipher: Option<EncryptionCipher>,
+    /// Server-to-client (decryption) key, if any.
+    pub s2c_key: Option<&'a DerivedKey>,
+    /// Client-to-server (encryption) key, if any.
+    pub c2s_key: Option<&'a DerivedKey>,
+}
+
+impl SessionKeys<'_> {
+    /// Formats the keys as a Wireshark `smb2_seskey_list` line.
+    fn to_line(&self) -> String {
+        fn hex(bytes: &[u8]) -> String {
+            bytes.iter().map(|b| format!("{b:02x}")).collect()
+        }
+        format!(
+            "\"{}\",\"{}\",\"{}\",\"{}\"\n",
+            hex(&self.session_id.to_le_bytes()),
+            hex(self.session_key),
+            self.s2c_key.map(|k| hex(k)).unwrap_or_default(),
+            self.c2s_key.map(|k| hex(k)).unwrap_or_default(),
+        )
+    }
+}
+
+/// Appends the session keys to the key log file, if one is configured,
+/// either by the connection configuration, or by the [`KEYLOG_FILE_ENV_VAR`] environment variable.
+///
+/// Failures are logged, and do not fail the session setup.
+pub(crate) fn export_session_keys(configured: &Option<PathBuf>, keys: &SessionKeys) {
+    let path = match configured
+        .clone()
+        .or_else(|| std::env::var_os(KEYLOG_FILE_ENV_VAR).map(PathBuf::from))
+    {
+        Some(path) => path,
+        None => return,
+    };
+
+    let _guard = KEYLOG_WRITE.lock().unwrap();
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(keys.to_line().as_bytes()));
+    match result {
+        Ok(()) => log::debug!(
+            "Exported keys of session {:#x} to {}",
+            keys.session_id,
+            path.display()
+        ),
+        Err(e) => log::warn!("Failed to export session keys to {}: {e}", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keylog_line() {
+        let session_key = [0x11; 16];
+        let s2c = [0x22; 16];
+        let keys = SessionKeys {
+            session_id: 0x1122334455667788,
+            session_key: &session_key,
+            cipher: None,
+            s2c_key: Some(&s2c),
+            c2s_key: None,
+        };
+        assert_eq!(
+            keys.to_line(),
+            "\"8877665544332211\",\"11111111111111111111111111111111\",\"22222222222222222222222222222222\",\"\"\n"
+        );
+    }
+}
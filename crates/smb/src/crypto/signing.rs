@@ -4,6 +4,10 @@ use super::CryptoError;
 
 type SigningKey = [u8; 16];
 
+#[cfg_attr(
+    not(any(feature = "sign_hmac", feature = "sign_cmac", feature = "sign_gmac")),
+    allow(unused_variables)
+)]
 pub fn make_signing_algo(
     signing_algorithm: SigningAlgorithmId,
     signing_key: &SigningKey,
@@ -11,13 +15,6 @@ pub fn make_signing_algo(
     if !SIGNING_ALGOS.contains(&signing_algorithm) {
         return Err(CryptoError::UnsupportedSigningAlgorithm(signing_algorithm));
     }
-    if cfg!(feature = "__debug-dump-keys") {
-        log::debug!(
-            "Using signing algorithm {:?} with key {:02x?}",
-            signing_algorithm,
-            signing_key
-        );
-    }
     match signing_algorithm {
         #[cfg(feature = "sign_hmac")]
         SigningAlgorithmId::HmacSha256 => Ok(hmac_signer::HmacSha256Signer::build(signing_key)),
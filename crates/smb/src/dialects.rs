@@ -5,11 +5,10 @@ use std::sync::Arc;
 use crate::{
     ConnectionConfig, Error,
     connection::{connection_info::NegotiatedProperties, preauth_hash},
-    crypto,
 };
 use smb_msg::{
-    Dialect, GlobalCapabilities, NegotiateResponse, ShareCacheMode, ShareFlags, SigningAlgorithmId,
-    TreeCapabilities,
+    Dialect, EncryptionCipher, GlobalCapabilities, NegotiateResponse, ShareCacheMode, ShareFlags,
+    SigningAlgorithmId, TreeCapabilities,
 };
 
 /// This is a utility struct that returns constants and functions for the given dialect.
@@ -104,7 +103,18 @@ impl DialectImpl {
             Dialect::Smb021 | Dialect::Smb0202 => {
                 Smb201.process_negotiate_request(response, state, config)
             }
+        }?;
+
+        // Older dialects use fixed algorithms, which must be allowed as well.
+        let signing_algo = state
+            .signing_algo
+            .unwrap_or_else(|| self.default_signing_algo());
+        if !config.signing_algorithms().contains(&signing_algo) {
+            return Err(Error::NegotiationError(format!(
+                "Signing algorithm {signing_algo:?} is not allowed by the configuration"
+            )));
         }
+        Ok(())
     }
 
     pub fn get_signing_derive_label(&self) -> &[u8] {
@@ -184,7 +194,7 @@ impl DialectMethods for Smb311 {
         let signing_algo = if let Some(signing_algo) =
             ctx_signing.and_then(|ctx| ctx.signing_algorithms.first())
         {
-            if !crypto::SIGNING_ALGOS.contains(signing_algo) {
+            if !config.signing_algorithms().contains(signing_algo) {
                 return Err(Error::NegotiationError(
                     "Unsupported signing algorithm selected!".into(),
                 ));
@@ -208,7 +218,7 @@ impl DialectMethods for Smb311 {
         let encryption = response.get_ctx_encryption_capabilities();
         let first_cipher = encryption.and_then(|ctx| ctx.ciphers.first());
         if let Some(encryption_cipher) = first_cipher {
-            if !crypto::ENCRYPTING_ALGOS.contains(encryption_cipher) {
+            if !config.encryption_ciphers().contains(encryption_cipher) {
                 return Err(Error::NegotiationError(
                     "Unsupported encryption algorithm received".into(),
                 ));
@@ -254,6 +264,16 @@ impl DialectMethods for Smb30X {
                 "Encryption is required, but cap not supported by the server.".into(),
             ));
         }
+        if config.encryption_mode.is_required()
+            && !config
+                .encryption_ciphers()
+                .contains(&EncryptionCipher::Aes128Ccm)
+        {
+            return Err(Error::NegotiationError(
+                "Encryption is required, but the dialect's cipher is not allowed by the configuration."
+                    .into(),
+            ));
+        }
 
         Ok(())
     }
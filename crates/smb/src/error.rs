@@ -9,6 +9,8 @@ use smb_msg::{Command, ErrorResponse, Status};
 #[derive(Debug)]
 pub enum TimedOutTask {
     ReceiveNextMessage,
+    /// An operation bound by the deadline of its [`OpOptions`][crate::op_options::OpOptions].
+    Operation,
 }
 
 #[derive(Error, Debug)]
@@ -65,6 +67,12 @@ pub enum Error {
     /// For more references, see the [`sspi` crate documentation][sspi]
     #[error("Sspi error: {0}")]
     SspiError(#[from] sspi::Error),
+    #[error("Kerberos error: {0}")]
+    KerberosError(String),
+    /// Indicates that the server rejected the requested authentication,
+    /// e.g. since it does not allow guest or anonymous sessions.
+    #[error("Authentication rejected: {0}")]
+    AuthenticationRejected(String),
 
     #[error("Provided buffer size too small to contain {data_type}")]
     BufferTooSmall {
@@ -108,6 +116,10 @@ pub enum Error {
     DfsError(UncPath),
     #[error("Not found: {0}")]
     NotFound(String),
+    #[error("Security policy violation: {0}")]
+    SecurityPolicyViolation(String),
+    #[error("Content hash verification failed for the block at offset {0}")]
+    ContentHashMismatch(u64),
 
     #[error("Channel {1} for session {0} not found.")]
     ChannelNotFound(u64, u32),
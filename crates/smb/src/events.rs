@@ -0,0 +1,413 @@
+//! Events of the SMB client, raised as the state of connections, sessions and trees changes.
+//!
+//! Set [`ConnectionConfig::events`][crate::ConnectionConfig::events] to an [`EventListenerHandle`]
+//! to receive the [`SmbEvent`]s of a connection, and everything established on it.
+//! When using a [`Client`][crate::Client], prefer [`Client::on_event`][crate::Client::on_event],
+//! or, in async builds, the [`Client::events`][crate::Client::events] stream, which cover all
+//! the connections of the client.
+//!
+//! Events are informational: the client handles the underlying condition on its own (e.g., it acknowledges
+//! lease breaks and invalidates sessions closed by the server), and the events let applications react to it.
+
+use std::sync::Arc;
+
+use smb_dtyp::Guid;
+use smb_msg::{FileId, LeaseState, OplockLevel};
+
+/// An event of the SMB client.
+///
+/// All events carry the name of the server of the connection they occurred on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmbEvent {
+    /// The connection to the server was closed unexpectedly, e.g. by the server, or due to a network failure.
+    /// Sessions, trees and files of the connection are no longer usable.
+    ConnectionLost { server: String },
+    /// A new connection was made to a server, after the previous connection to it was lost.
+    ConnectionRestored { server: String },
+    /// The server closed the session (SMB2_NOTIFY_SESSION_CLOSED), e.g. since the user was logged off by an administrator.
+    SessionClosedByServer { server: String, session_id: u64 },
+    /// The server rejected a request, since the session has expired (`STATUS_NETWORK_SESSION_EXPIRED`),
+    /// and it must be re-authenticated.
+    SessionExpired { server: String, session_id: u64 },
+    /// The server is breaking a lease held by the client.
+    LeaseBreak {
+        server: String,
+        lease_key: Guid,
+        current_state: LeaseState,
+        new_state: LeaseState,
+    },
+    /// The server is breaking an oplock held by the client on an open file.
+    OplockBreak {
+        server: String,
+        session_id: u64,
+        file_id: FileId,
+        /// The maximum oplock level the server allows the client to keep.
+        new_level: OplockLevel,
+    },
+    /// An alternate channel was bound to the session (multichannel).
+    ChannelAdded {
+        server: String,
+        session_id: u64,
+        channel_id: u32,
+    },
+    /// An alternate channel of the session is no longer usable, since its connection was lost.
+    ChannelRemoved {
+        server: String,
+        session_id: u64,
+        channel_id: u32,
+    },
+    /// A tree (share) was disconnected: either by the client, or by the server,
+    /// e.g. since the share was deleted (`STATUS_NETWORK_NAME_DELETED`).
+    TreeDisconnected {
+        server: String,
+        session_id: u64,
+        tree_id: u32,
+        /// The UNC path of the share.
+        share: String,
+        by_server: bool,
+    },
+}
+
+impl SmbEvent {
+    /// Returns the name of the server the event occurred on.
+    pub fn server(&self) -> &str {
+        match self {
+            SmbEvent::ConnectionLost { server }
+            | SmbEvent::ConnectionRestored { server }
+            | SmbEvent::SessionClosedByServer { server, .. }
+            | SmbEvent::SessionExpired { server, .. }
+            | SmbEvent::LeaseBreak { server, .. }
+            | SmbEvent::OplockBreak { server, .. }
+            | SmbEvent::ChannelAdded { server, .. }
+            | SmbEvent::ChannelRemoved { server, .. }
+            | SmbEvent::TreeDisconnected { server, .. } => server,
+        }
+    }
+}
+
+/// A receiver of the client's events.
+///
+/// Implementations are called synchronously, from the thread/task that detected the event
+/// (possibly, a background worker of the connection), so they should return quickly,
+/// and must not block on operations of the same connection.
+///
+/// Any `Fn(&SmbEvent)` closure is a listener.
+pub trait SmbEventListener: Send + Sync {
+    /// Called once for each event.
+    fn on_event(&self, event: &SmbEvent);
+}
+
+impl<F> SmbEventListener for F
+where
+    F: Fn(&SmbEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &SmbEvent) {
+        self(event)
+    }
+}
+
+/// A shared reference to an [`SmbEventListener`] implementation, to be set in the connection configuration.
+///
+/// Two handles are equal if they refer to the same listener instance.
+#[derive(Clone)]
+pub struct EventListenerHandle(Arc<dyn SmbEventListener>);
+
+impl EventListenerHandle {
+    pub fn new(listener: Arc<dyn SmbEventListener>) -> Self {
+        Self(listener)
+    }
+
+    pub(crate) fn emit(&self, event: &SmbEvent) {
+        log::debug!("Event: {event:?}");
+        self.0.on_event(event);
+    }
+}
+
+impl std::fmt::Debug for EventListenerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("EventListenerHandle").finish()
+    }
+}
+
+impl PartialEq for EventListenerHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for EventListenerHandle {}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use smb_msg::{Command, Status};
+
+    use super::*;
+    use crate::testing::{
+        FakeReply, FakeServer, FakeServerConfig, FaultController, FaultRules, FaultyTransport,
+        MemoryTransport,
+    };
+    use crate::{Connection, ConnectionConfig, FileAccessMask};
+
+    type Events = Arc<Mutex<Vec<SmbEvent>>>;
+
+    /// Returns a connection configuration with a listener recording the events in the returned list.
+    fn config() -> (ConnectionConfig, Events) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener = {
+            let events = events.clone();
+            move |event: &SmbEvent| events.lock().unwrap().push(event.clone())
+        };
+        let config = ConnectionConfig {
+            timeout: Some(Duration::from_secs(1)),
+            events: Some(EventListenerHandle::new(Arc::new(listener))),
+            ..Default::default()
+        };
+        (config, events)
+    }
+
+    fn server_name() -> String {
+        FakeServer::SERVER_NAME.to_string()
+    }
+
+    /// Waits for the condition to hold, e.g. once the worker handles a notification,
+    /// or detects a lost connection, in the background.
+    #[maybe_async::maybe_async]
+    async fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..50 {
+            if condition() {
+                return;
+            }
+            #[cfg(feature = "async")]
+            {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            #[cfg(not(feature = "async"))]
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(condition());
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_connection_events() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let (config, events) = config();
+        let (connection, faults) = server
+            .connect_with_faults(config, FaultRules::new())
+            .await
+            .unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let share = server.share_path("share");
+        let tree = session.tree_connect(&share).await.unwrap();
+        tree.disconnect().await.unwrap();
+        assert!(matches!(
+            events.lock().unwrap().as_slice(),
+            [SmbEvent::TreeDisconnected {
+                session_id,
+                share: disconnected_share,
+                by_server: false,
+                ..
+            }] if *session_id == session.session_id() && *disconnected_share == share.to_string()
+        ));
+
+        // The loss is detected by the worker in the background,
+        // or by the next receive, in single-threaded builds.
+        faults.disconnect();
+        let result = session.tree_connect(&share).await;
+        assert!(result.is_err());
+        wait_until(|| connection.is_lost()).await;
+        assert_eq!(
+            events.lock().unwrap().last(),
+            Some(&SmbEvent::ConnectionLost {
+                server: server_name()
+            })
+        );
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_session_expired_event() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let (config, events) = config();
+        let connection = server.connect(config).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+
+        server.on(Command::Create, |_| {
+            FakeReply::Error(Status::NetworkSessionExpired)
+        });
+        let result = tree
+            .open_existing("file.txt", FileAccessMask::new().with_generic_read(true))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(
+            events.lock().unwrap().as_slice(),
+            [SmbEvent::SessionExpired {
+                server: server_name(),
+                session_id: session.session_id()
+            }]
+        );
+    }
+
+    // The single-threaded worker does not receive notifications.
+    #[cfg(not(feature = "single_threaded"))]
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_session_closed_event() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let (config, events) = config();
+        let connection = server.connect(config).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let share = server.share_path("share");
+        let tree = session.tree_connect(&share).await.unwrap();
+        let session_id = session.session_id();
+
+        // The notification is sent along with the response to the next request.
+        server.close_session(session_id);
+        let result = tree
+            .open_existing("missing.bin", FileAccessMask::new().with_generic_read(true))
+            .await;
+        assert!(result.is_err());
+        wait_until(|| !events.lock().unwrap().is_empty()).await;
+        assert_eq!(
+            events.lock().unwrap().as_slice(),
+            [SmbEvent::SessionClosedByServer {
+                server: server_name(),
+                session_id
+            }]
+        );
+
+        // The session is no longer usable.
+        let result = session.tree_connect(&share).await;
+        assert!(result.is_err());
+        assert!(!connection.is_lost());
+    }
+
+    #[cfg(not(feature = "single_threaded"))]
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_break_events() {
+        let server = FakeServer::new(FakeServerConfig {
+            leasing: true,
+            ..Default::default()
+        });
+        server.add_file("share", "file.bin", b"hello".to_vec());
+        let (config, events) = config();
+        let config = ConnectionConfig {
+            // Leases are requested for cached handles.
+            handle_caching: Some(crate::connection::HandleCachingConfig {
+                grace_period: Duration::from_secs(5),
+                max_idle_handles: 4,
+            }),
+            ..config
+        };
+        let connection = server.connect(config).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let share = server.share_path("share");
+        let tree = session.tree_connect(&share).await.unwrap();
+        let _file = tree
+            .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            server.break_lease("share", "file.bin", LeaseState::new()),
+            1
+        );
+        server.break_oplock("share", "file.bin", OplockLevel::II);
+        // The notifications are sent along with the response to the next request.
+        let result = tree
+            .open_existing("missing.bin", FileAccessMask::new().with_generic_read(true))
+            .await;
+        assert!(result.is_err());
+
+        wait_until(|| events.lock().unwrap().len() == 2).await;
+        let events = events.lock().unwrap();
+        assert!(matches!(
+            &events[0],
+            SmbEvent::LeaseBreak { server: s, current_state, new_state, .. }
+                if *s == server_name() && current_state.read_caching() && *new_state == LeaseState::new()
+        ));
+        assert!(matches!(
+            &events[1],
+            SmbEvent::OplockBreak { server: s, new_level: OplockLevel::II, .. } if *s == server_name()
+        ));
+    }
+
+    /// Connects to the server with the specified client GUID, which must be shared by the connections
+    /// of a session.
+    #[maybe_async::maybe_async]
+    async fn connect(
+        server: &FakeServer,
+        config: ConnectionConfig,
+        client_guid: Guid,
+    ) -> (Connection, FaultController) {
+        let (client, server_side) = MemoryTransport::pair();
+        server.start(server_side);
+        let transport = FaultyTransport::new(Box::new(client), FaultRules::new());
+        let faults = transport.controller();
+        let connection = Connection::from_transport(
+            Box::new(transport),
+            FakeServer::SERVER_NAME,
+            client_guid,
+            config,
+        )
+        .await
+        .unwrap();
+        (connection, faults)
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_channel_events() {
+        let server = FakeServer::new(FakeServerConfig {
+            multichannel: true,
+            ..Default::default()
+        });
+        let (config, events) = config();
+        let client_guid = Guid::generate();
+        let (primary, _) = connect(&server, config.clone(), client_guid).await;
+        let (alternate, faults) = connect(&server, config, client_guid).await;
+        let session = primary.authenticate(server.identity()).await.unwrap();
+        let session_id = session.session_id();
+
+        let channel_id = alternate
+            .bind_session(&session, server.identity())
+            .await
+            .unwrap();
+        assert_eq!(
+            events.lock().unwrap().as_slice(),
+            [SmbEvent::ChannelAdded {
+                server: server_name(),
+                session_id,
+                channel_id
+            }]
+        );
+
+        // The loss is detected in the background, or by the next receive, in single-threaded builds.
+        faults.disconnect();
+        let result = alternate.authenticate(server.identity()).await;
+        assert!(result.is_err());
+        wait_until(|| alternate.is_lost()).await;
+        assert_eq!(
+            events.lock().unwrap()[1..],
+            [
+                SmbEvent::ConnectionLost {
+                    server: server_name()
+                },
+                SmbEvent::ChannelRemoved {
+                    server: server_name(),
+                    session_id,
+                    channel_id
+                }
+            ]
+        );
+
+        // The session is still usable over the primary connection.
+        session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+    }
+}
@@ -25,19 +25,29 @@ pub mod crypto;
 pub mod dialects;
 pub mod docs;
 pub mod error;
+pub mod events;
+pub mod metrics;
 pub mod msg_handler;
+pub mod op_options;
 pub mod resource;
+pub mod security;
 pub mod session;
+#[cfg(feature = "std-fs-impls")]
+pub mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod tree;
+pub mod watcher;
 
 pub use client::{Client, ClientConfig, UncPath};
 pub use connection::{Connection, ConnectionConfig};
 pub use error::Error;
+pub use op_options::OpOptions;
 pub use resource::{
     Directory, File, FileCreateArgs, GetLen, Pipe, PipeRpcConnection, ReadAt, ReadAtChannel,
     Resource, ResourceHandle, WriteAt, WriteAtChannel,
 };
-pub use session::Session;
+pub use session::{Credentials, Session};
 pub use tree::{DfsRootTreeRef, Tree};
 
 pub use smb_dtyp::*;
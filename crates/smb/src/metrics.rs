@@ -0,0 +1,417 @@
+//! Per-request metrics of the SMB client.
+//!
+//! Set [`ConnectionConfig::metrics`][crate::ConnectionConfig::metrics] to a [`MetricsHandle`]
+//! to receive a [`RequestMetrics`] record for every request sent on the connection.
+//! You may implement [`SmbMetrics`] to export those records into your own metrics system (e.g. Prometheus),
+//! or use the built-in [`InMemoryMetrics`] aggregator, and read its [`snapshot`][InMemoryMetrics::snapshot]
+//! periodically.
+//!
+//! When the `tracing` crate feature is enabled, the same information is also attached to
+//! a `smb.request` [tracing](https://docs.rs/tracing) span, for each request.
+
+use std::collections::HashMap;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+
+use maybe_async::maybe_async;
+use smb_msg::{Command, RequestContent, ResponseContent, Status};
+
+use crate::msg_handler::{IncomingMessage, OutgoingMessage};
+use crate::sync_helpers::{Arc, Mutex};
+
+/// Describes a single completed request: a request that was sent,
+/// and either got a (final) response, or failed receiving one.
+#[derive(Debug, Clone)]
+pub struct RequestMetrics {
+    pub command: Command,
+    pub message_id: u64,
+    pub session_id: u64,
+    pub tree_id: Option<u32>,
+    /// The status of the response, or `None` if no response was received,
+    /// for example, due to a timeout or a connection failure.
+    pub status: Option<u32>,
+    /// The time between sending the request and receiving the final response.
+    /// For async operations (e.g. change notifications), this includes the time spent pending.
+    pub latency: Duration,
+    /// The number of data bytes carried by the request (e.g. written data).
+    pub bytes_sent: u64,
+    /// The number of data bytes carried by the response (e.g. read data, directory listing).
+    pub bytes_received: u64,
+}
+
+impl RequestMetrics {
+    /// Whether the request completed successfully.
+    pub fn is_success(&self) -> bool {
+        self.status == Some(Status::Success as u32)
+    }
+}
+
+/// A sink for the client's per-request metrics.
+///
+/// Implementations are called synchronously, from the thread/task that receives the response,
+/// so they should be cheap - e.g., updating counters and histograms.
+pub trait SmbMetrics: Send + Sync {
+    /// Called once for each completed request.
+    fn record_request(&self, request: &RequestMetrics);
+}
+
+/// A shared reference to an [`SmbMetrics`] implementation, to be set in the connection configuration.
+///
+/// Two handles are equal if they refer to the same metrics instance.
+#[derive(Clone)]
+pub struct MetricsHandle(Arc<dyn SmbMetrics>);
+
+impl MetricsHandle {
+    pub fn new(metrics: Arc<dyn SmbMetrics>) -> Self {
+        Self(metrics)
+    }
+
+    pub(crate) fn record_request(&self, request: &RequestMetrics) {
+        self.0.record_request(request);
+    }
+}
+
+impl std::fmt::Debug for MetricsHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MetricsHandle").finish()
+    }
+}
+
+impl PartialEq for MetricsHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MetricsHandle {}
+
+/// Aggregated metrics of a single command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandMetrics {
+    /// The number of completed requests.
+    pub count: u64,
+    /// The total latency of all the requests.
+    pub total_latency: Duration,
+    /// The number of requests, by latency bucket.
+    /// Entry `i` counts the requests whose latency is at most [`InMemoryMetrics::LATENCY_BUCKETS`]`[i]`,
+    /// and the last entry counts all the requests exceeding the largest bucket.
+    pub latency_histogram: Vec<u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// The number of failed requests, by response status. `None` stands for no response.
+    pub errors: HashMap<Option<u32>, u64>,
+}
+
+/// An [`SmbMetrics`] implementation that aggregates the metrics in memory, per command.
+#[derive(Debug, Default)]
+pub struct InMemoryMetrics {
+    // Recording is synchronous, in all threading models.
+    commands: std::sync::Mutex<HashMap<Command, CommandMetrics>>,
+}
+
+impl InMemoryMetrics {
+    /// Upper bounds of the latency histogram buckets.
+    pub const LATENCY_BUCKETS: &'static [Duration] = &[
+        Duration::from_millis(1),
+        Duration::from_millis(5),
+        Duration::from_millis(10),
+        Duration::from_millis(50),
+        Duration::from_millis(100),
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+        Duration::from_secs(5),
+    ];
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the metrics aggregated so far.
+    pub fn snapshot(&self) -> HashMap<Command, CommandMetrics> {
+        self.commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl SmbMetrics for InMemoryMetrics {
+    fn record_request(&self, request: &RequestMetrics) {
+        let mut commands = self.commands.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = commands.entry(request.command).or_default();
+        if entry.latency_histogram.is_empty() {
+            entry.latency_histogram = vec![0; Self::LATENCY_BUCKETS.len() + 1];
+        }
+
+        entry.count += 1;
+        entry.total_latency += request.latency;
+        let bucket = Self::LATENCY_BUCKETS
+            .iter()
+            .position(|bound| request.latency <= *bound)
+            .unwrap_or(Self::LATENCY_BUCKETS.len());
+        entry.latency_histogram[bucket] += 1;
+        entry.bytes_sent += request.bytes_sent;
+        entry.bytes_received += request.bytes_received;
+        if !request.is_success() {
+            *entry.errors.entry(request.status).or_default() += 1;
+        }
+    }
+}
+
+/// (Internal)
+///
+/// Tracks the in-flight requests of a connection, to report their metrics (and tracing spans) once completed.
+pub(crate) struct RequestTracker {
+    metrics: Option<MetricsHandle>,
+    in_flight: Mutex<HashMap<u64, InFlightRequest>>,
+}
+
+struct InFlightRequest {
+    started: Instant,
+    metrics: RequestMetrics,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+#[maybe_async(AFIT)]
+impl RequestTracker {
+    pub fn new(metrics: Option<MetricsHandle>) -> Self {
+        Self {
+            metrics,
+            in_flight: Default::default(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.metrics.is_some() || cfg!(feature = "tracing")
+    }
+
+    /// Starts tracking a request that is about to be sent. The message ID of the request must be set.
+    pub async fn request_sent(&self, msg: &OutgoingMessage) -> crate::Result<()> {
+        if !self.is_enabled() || !msg.has_response {
+            return Ok(());
+        }
+
+        let header = &msg.message.header;
+        let metrics = RequestMetrics {
+            command: header.command,
+            message_id: header.message_id,
+            session_id: header.session_id,
+            tree_id: header.tree_id,
+            status: None,
+            latency: Duration::ZERO,
+            bytes_sent: Self::request_data_size(&msg.message.content),
+            bytes_received: 0,
+        };
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "smb.request",
+            command = ?metrics.command,
+            message_id = metrics.message_id,
+            session_id = metrics.session_id,
+            tree_id = metrics.tree_id,
+            bytes_sent = metrics.bytes_sent,
+            status = tracing::field::Empty,
+            bytes_received = tracing::field::Empty,
+        );
+
+        self.in_flight.lock().await?.insert(
+            header.message_id,
+            InFlightRequest {
+                started: Instant::now(),
+                metrics,
+                #[cfg(feature = "tracing")]
+                span,
+            },
+        );
+        Ok(())
+    }
+
+    /// Completes tracking a request, once its final response is received, or receiving it has failed.
+    ///
+    /// A request that is not going to be received (e.g. it failed to be sent) must be completed
+    /// with no response, as well.
+    pub async fn response_received(
+        &self,
+        msg_id: u64,
+        response: Option<&IncomingMessage>,
+    ) -> crate::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let request = match self.in_flight.lock().await?.remove(&msg_id) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        let mut metrics = request.metrics;
+        metrics.latency = request.started.elapsed();
+        if let Some(response) = response {
+            metrics.status = Some(response.message.header.status);
+            metrics.bytes_received = Self::response_data_size(&response.message.content);
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            let span = request.span;
+            if let Some(status) = metrics.status {
+                span.record("status", format_args!("{status:#x}"));
+            }
+            span.record("bytes_received", metrics.bytes_received);
+            tracing::debug!(
+                parent: &span,
+                latency_us = metrics.latency.as_micros() as u64,
+                success = metrics.is_success(),
+                "request completed"
+            );
+        }
+
+        if let Some(sink) = &self.metrics {
+            sink.record_request(&metrics);
+        }
+        Ok(())
+    }
+
+    fn request_data_size(content: &RequestContent) -> u64 {
+        match content {
+            RequestContent::Write(req) => req.length as u64,
+            RequestContent::Ioctl(req) => req.buffer.get_size() as u64,
+            _ => 0,
+        }
+    }
+
+    fn response_data_size(content: &ResponseContent) -> u64 {
+        match content {
+            ResponseContent::Read(res) => res.buffer.len() as u64,
+            ResponseContent::QueryDirectory(res) => res.output_buffer.len() as u64,
+            ResponseContent::Ioctl(res) => res.out_buffer.len() as u64,
+            _ => 0,
+        }
+    }
+}
+
+/// (Internal)
+///
+/// A request whose response is being received.
+///
+/// If it is dropped before [`PendingResponse::complete`] is called - for example, when the receiving
+/// future is dropped, the request is completed with no response, so it is not tracked forever.
+pub(crate) struct PendingResponse {
+    tracker: Arc<RequestTracker>,
+    msg_id: u64,
+    completed: bool,
+}
+
+#[maybe_async(AFIT)]
+impl PendingResponse {
+    pub fn new(tracker: &Arc<RequestTracker>, msg_id: u64) -> Self {
+        Self {
+            tracker: tracker.clone(),
+            msg_id,
+            completed: !tracker.is_enabled(),
+        }
+    }
+
+    pub async fn complete(mut self, response: Option<&IncomingMessage>) -> crate::Result<()> {
+        self.completed = true;
+        self.tracker.response_received(self.msg_id, response).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let tracker = self.tracker.clone();
+        let msg_id = self.msg_id;
+        tokio::task::spawn(async move {
+            if let Err(e) = tracker.response_received(msg_id, None).await {
+                log::error!("Failed to complete tracking request {msg_id}: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        if let Err(e) = self.tracker.response_received(self.msg_id, None) {
+            log::error!("Failed to complete tracking request {}: {e}", self.msg_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_request(status: Option<u32>, latency: Duration) -> RequestMetrics {
+        RequestMetrics {
+            command: Command::Read,
+            message_id: 1,
+            session_id: 1,
+            tree_id: Some(1),
+            status,
+            latency,
+            bytes_sent: 0,
+            bytes_received: 100,
+        }
+    }
+
+    #[test]
+    fn test_in_memory_aggregation() {
+        let metrics = InMemoryMetrics::new();
+        metrics.record_request(&make_request(
+            Some(Status::Success as u32),
+            Duration::from_micros(500),
+        ));
+        metrics.record_request(&make_request(
+            Some(Status::AccessDenied as u32),
+            Duration::from_millis(7),
+        ));
+        metrics.record_request(&make_request(None, Duration::from_secs(10)));
+
+        let snapshot = metrics.snapshot();
+        let read = &snapshot[&Command::Read];
+        assert_eq!(read.count, 3);
+        assert_eq!(read.bytes_received, 300);
+        assert_eq!(read.latency_histogram, vec![1, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(read.errors.len(), 2);
+        assert_eq!(read.errors[&Some(Status::AccessDenied as u32)], 1);
+        assert_eq!(read.errors[&None], 1);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_abandoned_request() {
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let tracker = Arc::new(RequestTracker::new(Some(MetricsHandle::new(
+            metrics.clone(),
+        ))));
+        let mut msg = OutgoingMessage::new(smb_msg::EchoRequest::default().into());
+        msg.message.header.message_id = 7;
+        tracker.request_sent(&msg).await.unwrap();
+
+        // The receiving side gave up on the response.
+        drop(PendingResponse::new(&tracker, 7));
+        for _ in 0..50 {
+            if tracker.in_flight.lock().await.unwrap().is_empty() {
+                break;
+            }
+            #[cfg(feature = "async")]
+            {
+                tokio::task::yield_now().await;
+            }
+        }
+        let in_flight = tracker.in_flight.lock().await.unwrap().len();
+        assert_eq!(in_flight, 0);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot[&Command::Echo].errors[&None], 1);
+    }
+}
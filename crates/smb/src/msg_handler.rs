@@ -27,6 +27,10 @@ pub struct OutgoingMessage {
 
     /// Channel ID to use for this message, if any.
     pub channel_id: Option<u32>,
+
+    /// The I/O priority (0-7) to set in the message header (SMB 3.1.1 only).
+    /// If not set, the default priority of the connection is used.
+    pub priority: Option<u8>,
 }
 
 impl OutgoingMessage {
@@ -39,6 +43,7 @@ impl OutgoingMessage {
             has_response: true,
             additional_data: None,
             channel_id: None,
+            priority: None,
         }
     }
 
@@ -61,6 +66,11 @@ impl OutgoingMessage {
         self.channel_id = channel_id;
         self
     }
+
+    pub fn with_priority(mut self, priority: Option<u8>) -> Self {
+        self.priority = priority;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,485 @@
+//! Per-operation options: deadlines, cancellation and I/O priority.
+//!
+//! Operations accepting an [`OpOptions`] (e.g. [`File::read_block_with`][crate::File::read_block_with],
+//! [`ResourceHandle::query_info_with`][crate::ResourceHandle::query_info_with] or
+//! [`Tree::create_with`][crate::Tree::create_with]) are bound by its deadline, instead of only by the
+//! connection's [`timeout`][crate::ConnectionConfig::timeout], may be cancelled while in flight,
+//! and are sent with its I/O priority.
+//!
+//! When an operation is cancelled, or its deadline passes, an SMB2 CANCEL request is sent to the server,
+//! and the operation fails with [`Error::Cancelled`] or [`Error::OperationTimeout`], respectively.
+//! If the server completes the operation before processing the cancellation, its result is returned instead.
+
+use std::sync::atomic::Ordering;
+#[cfg(not(feature = "async"))]
+use std::sync::{Arc, atomic::AtomicBool};
+use std::time::{Duration, Instant};
+
+use maybe_async::maybe_async;
+use smb_msg::CancelRequest;
+#[cfg(feature = "async")]
+use tokio_util::sync::CancellationToken;
+
+use crate::Error;
+use crate::error::TimedOutTask;
+use crate::msg_handler::{
+    AsyncMessageIds, IncomingMessage, MessageHandler, OutgoingMessage, ReceiveOptions,
+    SendMessageResult,
+};
+
+/// Options of a single operation.
+///
+/// Use a builder pattern to set the options:
+/// ```
+/// use std::time::Duration;
+/// use smb::op_options::OpOptions;
+///
+/// let options = OpOptions::new()
+///     .with_timeout(Duration::from_secs(5))
+///     .with_priority(OpOptions::MAX_PRIORITY);
+/// ```
+///
+/// # Notes
+/// * In async builds, cancellation and the deadline take effect at any point while the operation is in flight.
+/// * In threaded builds, the deadline bounds each wait for a response of the operation,
+///   and cancellation takes effect before the request is sent, and while the server processes it asynchronously
+///   (after it responded with `STATUS_PENDING`).
+#[derive(Debug, Clone, Default)]
+pub struct OpOptions {
+    /// The time by which the operation must complete.
+    pub deadline: Option<Instant>,
+
+    #[cfg(feature = "async")]
+    /// A token to cancel the operation.
+    pub cancel: Option<CancellationToken>,
+
+    #[cfg(not(feature = "async"))]
+    /// A flag to cancel the operation, when set to `true`.
+    pub cancel: Option<Arc<AtomicBool>>,
+
+    /// The I/O priority of the operation's requests, from 0 (lowest) to [`OpOptions::MAX_PRIORITY`].
+    /// Only applies to SMB 3.1.1 connections. If not set, the default priority (1) is used.
+    pub priority: Option<u8>,
+}
+
+impl OpOptions {
+    /// The highest I/O priority that may be set in an SMB2 header.
+    pub const MAX_PRIORITY: u8 = 7;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the deadline of the operation to `timeout` from now.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.with_deadline(deadline),
+            None => self,
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    #[cfg(not(feature = "async"))]
+    pub fn with_cancellation_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Returns whether the operation was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        #[cfg(feature = "async")]
+        return self.cancel.as_ref().is_some_and(|c| c.is_cancelled());
+        #[cfg(not(feature = "async"))]
+        return self
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::SeqCst));
+    }
+
+    /// Returns the time left until the deadline, if set.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// (Internal)
+    ///
+    /// Validates the options, and makes sure the operation may (still) start.
+    pub(crate) fn check(&self) -> crate::Result<()> {
+        if self.priority.is_some_and(|p| p > Self::MAX_PRIORITY) {
+            return Err(Error::InvalidArgument(format!(
+                "I/O priority must be at most {}",
+                Self::MAX_PRIORITY
+            )));
+        }
+        if self.is_cancelled() {
+            return Err(Error::Cancelled("operation"));
+        }
+        if self.remaining() == Some(Duration::ZERO) {
+            return Err(Error::OperationTimeout(
+                TimedOutTask::Operation,
+                Duration::ZERO,
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    async fn cancelled(&self) {
+        match &self.cancel {
+            Some(token) => token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn expired(&self) {
+        match self.deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// (Internal)
+///
+/// Sends an SMB2 CANCEL request for a request sent earlier.
+/// If the request went async (`msg_ids.async_id` is set), the async form of the cancel request is sent.
+#[maybe_async]
+pub(crate) async fn send_cancel<H: MessageHandler>(
+    handler: &H,
+    msg_ids: &AsyncMessageIds,
+) -> crate::Result<SendMessageResult> {
+    let mut outgoing_message = OutgoingMessage::new(CancelRequest {}.into());
+    outgoing_message.message.header.message_id = msg_ids.msg_id.load(Ordering::SeqCst);
+    let async_id = msg_ids.async_id.load(Ordering::SeqCst);
+    if async_id != u64::MAX {
+        outgoing_message.message.header.to_async(async_id);
+    }
+
+    handler.sendo(outgoing_message).await
+}
+
+/// (Internal)
+///
+/// Sends a request and receives its response, as [`MessageHandler::sendo_recvo`] does,
+/// applying the per-operation options: the request is sent with the operation's priority,
+/// and if the operation is cancelled or its deadline passes, the request is cancelled (SMB2 CANCEL),
+/// and the server's final response is awaited, so the request's credits are granted back.
+#[maybe_async]
+pub(crate) async fn sendo_recvo<H: MessageHandler>(
+    handler: &H,
+    msg: OutgoingMessage,
+    options: ReceiveOptions<'_>,
+    op: &OpOptions,
+) -> crate::Result<IncomingMessage> {
+    op.check()?;
+    let started = Instant::now();
+
+    let channel_id = msg.channel_id;
+    let priority = op.priority.or(msg.priority);
+    let send_result = handler.sendo(msg.with_priority(priority)).await?;
+
+    let msg_ids = options.async_msg_ids.clone().unwrap_or_default();
+    msg_ids.set(send_result.msg_id, u64::MAX);
+    let remaining = op.remaining();
+    let options = ReceiveOptions {
+        msg_id: send_result.msg_id,
+        channel_id,
+        async_msg_ids: Some(msg_ids.clone()),
+        timeout: remaining.or(options.timeout),
+        ..options
+    };
+    // Once cancelled, the final response is awaited with the connection's default timeout.
+    let final_options = ReceiveOptions {
+        timeout: None,
+        ..options.clone()
+    };
+
+    // The deadline passed while sending: a zero timeout must not reach the worker,
+    // which would wait for the response without a timeout.
+    if remaining == Some(Duration::ZERO) {
+        let error = Error::OperationTimeout(TimedOutTask::Operation, started.elapsed());
+        return cancel_and_finish(handler, &msg_ids, final_options, error).await;
+    }
+
+    receive(handler, options, final_options, &msg_ids, op, started).await
+}
+
+/// (Internal)
+///
+/// Receives the response of a request sent by [`sendo_recvo`], interrupting the wait
+/// once the operation is cancelled, or its deadline passes.
+#[cfg(feature = "async")]
+async fn receive<H: MessageHandler>(
+    handler: &H,
+    options: ReceiveOptions<'_>,
+    final_options: ReceiveOptions<'_>,
+    msg_ids: &AsyncMessageIds,
+    op: &OpOptions,
+    started: Instant,
+) -> crate::Result<IncomingMessage> {
+    let timed_out = || Error::OperationTimeout(TimedOutTask::Operation, started.elapsed());
+
+    // The interrupted wait for the response is dropped, and the final response is received anew,
+    // with the connection's default timeout, rather than what is left of the deadline.
+    let error = {
+        let receive = handler.recvo(options);
+        tokio::pin!(receive);
+        tokio::select! {
+            biased;
+            result = &mut receive => match result {
+                Err(Error::OperationTimeout(..)) if op.deadline.is_some() => timed_out(),
+                result => return result,
+            },
+            _ = op.cancelled() => Error::Cancelled("operation"),
+            _ = op.expired() => timed_out(),
+        }
+    };
+
+    cancel_and_finish(handler, msg_ids, final_options, error).await
+}
+
+/// (Internal)
+///
+/// Receives the response of a request sent by [`sendo_recvo`], cancelling it
+/// if the operation is cancelled while the server processes it asynchronously, or its deadline passes.
+#[cfg(not(feature = "async"))]
+fn receive<H: MessageHandler>(
+    handler: &H,
+    options: ReceiveOptions<'_>,
+    final_options: ReceiveOptions<'_>,
+    msg_ids: &AsyncMessageIds,
+    op: &OpOptions,
+    started: Instant,
+) -> crate::Result<IncomingMessage> {
+    let options = ReceiveOptions {
+        async_cancel: op.cancel.clone().or(options.async_cancel),
+        ..options
+    };
+    match handler.recvo(options) {
+        Err(Error::Cancelled(_)) if op.is_cancelled() => cancel_and_finish(
+            handler,
+            msg_ids,
+            final_options,
+            Error::Cancelled("operation"),
+        ),
+        Err(Error::OperationTimeout(..)) if op.deadline.is_some() => cancel_and_finish(
+            handler,
+            msg_ids,
+            final_options,
+            Error::OperationTimeout(TimedOutTask::Operation, started.elapsed()),
+        ),
+        result => result,
+    }
+}
+
+/// (Internal)
+///
+/// Cancels a request whose response was not received yet, and receives the final response.
+/// Returns the response if the server completed the request anyway, or `error` otherwise.
+#[maybe_async]
+async fn cancel_and_finish<H: MessageHandler>(
+    handler: &H,
+    msg_ids: &AsyncMessageIds,
+    options: ReceiveOptions<'_>,
+    error: Error,
+) -> crate::Result<IncomingMessage> {
+    log::debug!(
+        "Operation interrupted ({error}), cancelling request {}",
+        msg_ids.msg_id.load(Ordering::SeqCst)
+    );
+    send_cancel(handler, msg_ids).await.ok();
+    handler.recvo(options).await.or(Err(error))
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::{Arc, Mutex};
+
+    use smb_fscc::FileAccessMask;
+    use smb_msg::{Command, CreateDisposition};
+
+    use super::*;
+    use crate::ConnectionConfig;
+    use crate::testing::{
+        FakeReply, FakeServer, FakeServerConfig, FaultAction, FaultRule, FaultRules, FrameDirection,
+    };
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_op_options() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_file("share", "file.txt", b"data".to_vec());
+        let priorities = Arc::new(Mutex::new(Vec::new()));
+        let hang = Arc::new(AtomicBool::new(false));
+        server.on(Command::Flush, {
+            let priorities = priorities.clone();
+            let hang = hang.clone();
+            move |request| {
+                priorities
+                    .lock()
+                    .unwrap()
+                    .push(request.header.flags.priority_mask());
+                if hang.load(Ordering::SeqCst) {
+                    FakeReply::PendingUntilCancelled
+                } else {
+                    FakeReply::Default
+                }
+            }
+        });
+        let connection = server.connect(ConnectionConfig::default()).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+        let file = tree
+            .create_file(
+                "file.txt",
+                CreateDisposition::Open,
+                FileAccessMask::new()
+                    .with_generic_read(true)
+                    .with_generic_write(true),
+            )
+            .await
+            .unwrap()
+            .unwrap_file();
+
+        file.flush().await.unwrap();
+        file.flush_with(&OpOptions::new().with_priority(5))
+            .await
+            .unwrap();
+        assert_eq!(*priorities.lock().unwrap(), [1, 5]);
+        let result = file.flush_with(&OpOptions::new().with_priority(8)).await;
+        assert!(result.is_err());
+
+        // A request the server does not complete is cancelled once the deadline passes.
+        hang.store(true, Ordering::SeqCst);
+        let result = file
+            .flush_with(&OpOptions::new().with_timeout(Duration::from_millis(200)))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(server.stats().cancelled, 1);
+
+        // A cancelled operation is not sent at all.
+        let op = OpOptions::new();
+        #[cfg(feature = "async")]
+        let op = {
+            let token = CancellationToken::new();
+            token.cancel();
+            op.with_cancellation_token(token)
+        };
+        #[cfg(not(feature = "async"))]
+        let op = op.with_cancellation_flag(Arc::new(AtomicBool::new(true)));
+        let result = file.flush_with(&op).await;
+        assert!(result.is_err());
+        assert_eq!(priorities.lock().unwrap().len(), 3);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_deadline_passes_before_response() {
+        const DEADLINE: Duration = Duration::from_millis(100);
+        const DELAY: Duration = Duration::from_millis(400);
+        // A single credit: a request is sent only once the response of the previous one arrives.
+        let server = FakeServer::new(FakeServerConfig {
+            max_credits: 1,
+            ..Default::default()
+        });
+        server.add_file("share", "file.txt", b"data".to_vec());
+        // The first flush goes async, the second completes, and the third is completed only once cancelled.
+        let flushes = Arc::new(AtomicUsize::new(0));
+        server.on(Command::Flush, {
+            let flushes = flushes.clone();
+            move |_| match flushes.fetch_add(1, Ordering::SeqCst) {
+                0 => FakeReply::Pending(Box::new(FakeReply::Default)),
+                1 => FakeReply::Default,
+                _ => FakeReply::PendingUntilCancelled,
+            }
+        });
+        let (connection, faults) = server
+            .connect_with_faults(ConnectionConfig::default(), FaultRules::new())
+            .await
+            .unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+        let file = tree
+            .create_file(
+                "file.txt",
+                CreateDisposition::Open,
+                FileAccessMask::new().with_generic_write(true),
+            )
+            .await
+            .unwrap()
+            .unwrap_file();
+
+        // The final response arrives after the deadline: the request is cancelled, but the server
+        // already completed it, so its final response is awaited and returned.
+        faults.set_policy(
+            FaultRules::new()
+                .rule(
+                    FaultRule::new(FaultAction::Delay(DEADLINE / 2))
+                        .direction(FrameDirection::Incoming)
+                        .command(Command::Flush)
+                        .times(1),
+                )
+                .rule(
+                    FaultRule::new(FaultAction::Delay(DELAY))
+                        .direction(FrameDirection::Incoming)
+                        .command(Command::Flush)
+                        .times(1),
+                ),
+        );
+        let start = Instant::now();
+        let result = file
+            .flush_with(&OpOptions::new().with_timeout(DEADLINE))
+            .await;
+        assert!(result.is_ok());
+        assert!(start.elapsed() >= DELAY);
+
+        // The deadline passes while the request waits for a credit to be sent: it is cancelled
+        // right after it is sent, instead of waiting for its response without a timeout.
+        faults.set_policy(
+            FaultRules::new().rule(
+                FaultRule::new(FaultAction::Delay(DELAY))
+                    .direction(FrameDirection::Incoming)
+                    .command(Command::Flush)
+                    .times(1),
+            ),
+        );
+        #[cfg(feature = "async")]
+        let (first, second) = tokio::join!(file.flush(), async {
+            tokio::time::sleep(DEADLINE / 2).await;
+            file.flush_with(&OpOptions::new().with_timeout(DEADLINE))
+                .await
+        });
+        #[cfg(not(feature = "async"))]
+        let (first, second) = std::thread::scope(|scope| {
+            let first = scope.spawn(|| file.flush());
+            std::thread::sleep(DEADLINE / 2);
+            let second = file.flush_with(&OpOptions::new().with_timeout(DEADLINE));
+            (first.join().unwrap(), second)
+        });
+        assert!(first.is_ok());
+        assert!(second.is_err());
+        assert_eq!(server.stats().cancelled, 1);
+    }
+}
@@ -1,7 +1,4 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering},
-};
+use std::sync::{Arc, atomic::AtomicBool};
 
 use maybe_async::*;
 use smb_dtyp::SecurityDescriptor;
@@ -16,19 +13,28 @@ use crate::{
         AsyncMessageIds, HandlerReference, IncomingMessage, MessageHandler, OutgoingMessage,
         ReceiveOptions, SendMessageResult,
     },
+    op_options::{self, OpOptions},
     tree::TreeMessageHandler,
 };
 
+pub mod branch_cache;
+pub mod checkpoint;
 pub mod directory;
 pub mod file;
 pub mod file_util;
+mod handle_cache;
 pub mod pipe;
 
+pub use branch_cache::*;
+pub use checkpoint::*;
 pub use directory::*;
 pub use file::*;
 pub use file_util::*;
 pub use pipe::*;
 
+pub(crate) use handle_cache::{HandleCache, LeaseRouter};
+use handle_cache::{HandleLease, IdleHandle};
+
 type Upstream = HandlerReference<TreeMessageHandler>;
 
 #[derive(Default)]
@@ -101,6 +107,7 @@ impl Resource {
         conn_info: &Arc<ConnectionInfo>,
         share_type: ShareType,
         is_dfs: bool,
+        op: &OpOptions,
     ) -> crate::Result<Resource> {
         let share_access = if share_type == ShareType::Disk {
             ShareAccessFlags::new()
@@ -123,9 +130,34 @@ impl Resource {
             ));
         }
 
+        let cache = upstream.handle_cache();
+        if let Some(idle) = cache
+            .take(upstream, name, create_args, share_access)
+            .await?
+        {
+            match Self::reuse(name, upstream, idle, conn_info, share_type).await {
+                Ok(resource) => return Ok(resource),
+                Err(e) => log::debug!("Failed to reuse idle handle for '{name}': {e}"),
+            }
+        }
+
+        let lease = cache.lease_for(upstream, name, create_args).await?;
+        let generation = lease.as_ref().map_or(0, |lease| lease.generation());
+        let mut contexts = vec![
+            QueryMaximalAccessRequest::default().into(),
+            QueryOnDiskIdReq.into(),
+        ];
+        let requested_oplock_level = match &lease {
+            Some(lease) => {
+                contexts.push(lease.make_context(conn_info.negotiation.dialect_rev));
+                OplockLevel::Lease
+            }
+            None => OplockLevel::None,
+        };
+
         let mut msg = OutgoingMessage::new(
             CreateRequest {
-                requested_oplock_level: OplockLevel::None,
+                requested_oplock_level,
                 impersonation_level: ImpersonationLevel::Impersonation,
                 desired_access: create_args.desired_access,
                 file_attributes: create_args.attributes,
@@ -133,22 +165,30 @@ impl Resource {
                 create_disposition: create_args.disposition,
                 create_options: create_args.options,
                 name: name.into(),
-                contexts: vec![
-                    QueryMaximalAccessRequest::default().into(),
-                    QueryOnDiskIdReq.into(),
-                ]
-                .into(),
+                contexts: contexts.into(),
             }
             .into(),
         );
         // Make sure to set DFS if required.
         msg.message.header.flags.set_dfs_operation(is_dfs);
 
-        let response = upstream
-            .sendo_recvo(msg, ReceiveOptions::new().with_allow_async(true))
-            .await?;
-
-        let response = response.message.content.to_create()?;
+        let response = op_options::sendo_recvo(
+            &**upstream,
+            msg,
+            ReceiveOptions::new().with_allow_async(true),
+            op,
+        )
+        .await
+        .and_then(|response| Ok(response.message.content.to_create()?));
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                if let Some(lease) = &lease {
+                    cache.release_lease(lease).await?;
+                }
+                return Err(e);
+            }
+        };
         log::debug!("Created file '{}', ({:?})", name, response.file_id);
 
         let is_dir = response.file_attributes.directory();
@@ -164,6 +204,25 @@ impl Resource {
                 }
             );
 
+        let lease = match lease {
+            Some(lease) if !is_dir && response.oplock_level == OplockLevel::Lease => {
+                lease.granted(&response.create_contexts);
+                Some(HandleLease {
+                    lease,
+                    desired_access: create_args.desired_access,
+                    share_access,
+                    options: create_args.options,
+                    end_of_file: response.endof_file,
+                    generation,
+                })
+            }
+            Some(lease) => {
+                cache.release_lease(&lease).await?;
+                None
+            }
+            None => None,
+        };
+
         // Common information is held in the handle object.
         let handle = ResourceHandle {
             name: name.to_string(),
@@ -175,6 +234,7 @@ impl Resource {
             access,
             share_type,
             conn_info: conn_info.clone(),
+            lease,
         };
 
         // Construct specific resource and return it.
@@ -191,6 +251,52 @@ impl Resource {
         Ok(resource)
     }
 
+    /// (Internal)
+    ///
+    /// Constructs a file resource from an idle handle, kept open by the tree's handle cache.
+    #[maybe_async]
+    async fn reuse(
+        name: &str,
+        upstream: &Upstream,
+        idle: IdleHandle,
+        conn_info: &Arc<ConnectionInfo>,
+        share_type: ShareType,
+    ) -> crate::Result<Resource> {
+        let mut handle = ResourceHandle {
+            name: name.to_string(),
+            handler: ResourceMessageHandle::new(upstream),
+            open: AtomicBool::new(true),
+            _file_id: idle.file_id,
+            created: idle.created,
+            modified: idle.modified,
+            access: idle.access,
+            share_type,
+            conn_info: conn_info.clone(),
+            lease: None,
+        };
+
+        let mut lease = idle.lease;
+        let generation = lease.lease.generation();
+        if lease.generation != generation {
+            match handle.query_info::<FileStandardInformation>().await {
+                Ok(info) => {
+                    lease.end_of_file = info.end_of_file;
+                    lease.generation = generation;
+                }
+                Err(e) => {
+                    // Do not cache this handle again.
+                    upstream.handle_cache().release_lease(&lease.lease).await?;
+                    handle.close().await.ok();
+                    return Err(e);
+                }
+            }
+        }
+
+        let end_of_file = lease.end_of_file;
+        handle.lease = Some(lease);
+        Ok(Resource::File(File::new(handle, end_of_file)))
+    }
+
     pub fn as_file(&self) -> Option<&File> {
         match self {
             Resource::File(f) => Some(f),
@@ -270,6 +376,9 @@ pub struct ResourceHandle {
     access: FileAccessMask,
 
     conn_info: Arc<ConnectionInfo>,
+
+    /// Set if the handle was opened with a handle-caching lease.
+    lease: Option<HandleLease>,
 }
 
 #[maybe_async(AFIT)]
@@ -343,20 +452,22 @@ impl ResourceHandle {
         mut req: QueryInfoRequest,
         output_buffer_length: Option<usize>,
         data_type: &'static str,
+        op: &OpOptions,
     ) -> crate::Result<QueryInfoData> {
         let buffer_length = self.calc_transact_size(output_buffer_length);
         req.output_buffer_length = buffer_length;
 
         let info_type = req.info_type;
         let result = self
-            .send_recvo(
-                req.into(),
+            .sendo_recvo_with(
+                OutgoingMessage::new(req.into()),
                 ReceiveOptions::new().with_status(&[
                     Status::Success,
                     Status::BufferOverflow,
                     Status::BufferTooSmall,
                     Status::InfoLengthMismatch,
                 ]),
+                op,
             )
             .await;
 
@@ -404,21 +515,75 @@ impl ResourceHandle {
     ///
     /// Sends a Set Information Request and parses the response.
     #[maybe_async]
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smb.set_info", skip_all, fields(path = %self.name)))]
     async fn set_info_common<T>(
         &self,
         data: T,
         cls: SetInfoClass,
         additional_info: AdditionalInfo,
+        op: &OpOptions,
     ) -> crate::Result<()>
     where
         T: Into<SetInfoData>,
     {
         let data = data.into().to_req(cls, self.file_id()?, additional_info);
-        let response = self.send_receive(data.into()).await?;
+        let response = self
+            .sendo_recvo_with(
+                OutgoingMessage::new(data.into()),
+                ReceiveOptions::new().with_cmd(Some(Command::SetInfo)),
+                op,
+            )
+            .await?;
         response.message.content.to_setinfo()?;
+        self.mark_modified();
         Ok(())
     }
 
+    /// (Internal)
+    ///
+    /// Marks the resource as modified, invalidating metadata of cached handles of the same file.
+    fn mark_modified(&self) {
+        if let Some(lease) = &self.lease {
+            lease.lease.mark_modified();
+        }
+    }
+
+    /// (Internal)
+    ///
+    /// Returns the state to keep, if the handle is closed and may be cached for later reuse.
+    fn make_idle(&self) -> Option<IdleHandle> {
+        self.lease.as_ref().map(|lease| IdleHandle {
+            file_id: self._file_id,
+            access: self.access,
+            created: self.created,
+            modified: self.modified,
+            lease: lease.clone(),
+        })
+    }
+
+    /// (Internal)
+    ///
+    /// Releases the file ID of a closed resource: either defers the close, using the tree's handle cache,
+    /// or sends a close request to the server.
+    #[maybe_async]
+    async fn release(
+        name: &str,
+        file_id: FileId,
+        idle: Option<IdleHandle>,
+        handler: &HandlerReference<ResourceMessageHandle>,
+    ) -> crate::Result<()> {
+        if let Some(idle) = idle {
+            let tree = &handler.upstream;
+            let lease = idle.lease.lease.clone();
+            if tree.handle_cache().defer(tree, name, idle).await? {
+                log::debug!("Deferred close of {name} ({file_id:?})");
+                return Ok(());
+            }
+            tree.handle_cache().release_lease(&lease).await?;
+        }
+        Self::send_close(file_id, handler).await
+    }
+
     /// Queries the file for information.
     /// # Type Parameters
     /// * `T` - The type of information to query. Must implement the [QueryFileInfoValue] trait.
@@ -484,6 +649,7 @@ impl ResourceHandle {
                 },
                 output_buffer_length,
                 std::any::type_name::<QueryFileFullEaInformation>(),
+                &OpOptions::default(),
             )
             .await?
             .as_file()?
@@ -504,10 +670,33 @@ impl ResourceHandle {
     /// A `Result` containing the requested information.
     /// # Notes
     /// * use [ResourceHandle::query_full_ea_info] to query extended attributes information.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smb.query_info", skip_all, fields(path = %self.name)))]
     pub async fn query_info_with_options<T: QueryFileInfoValue>(
         &self,
         flags: QueryInfoFlags,
         output_buffer_length: Option<usize>,
+    ) -> crate::Result<T> {
+        self._query_info(flags, output_buffer_length, &OpOptions::default())
+            .await
+    }
+
+    /// Queries the file for information, as [`ResourceHandle::query_info`] does,
+    /// bound by the given per-operation options.
+    pub async fn query_info_with<T: QueryFileInfoValue>(&self, op: &OpOptions) -> crate::Result<T> {
+        let flags = QueryInfoFlags::new()
+            .with_restart_scan(true)
+            .with_return_single_entry(true);
+
+        self._query_info(flags, None, op).await
+    }
+
+    /// (Internal)
+    #[maybe_async]
+    async fn _query_info<T: QueryFileInfoValue>(
+        &self,
+        flags: QueryInfoFlags,
+        output_buffer_length: Option<usize>,
+        op: &OpOptions,
     ) -> crate::Result<T> {
         let result: T = self
             .query_common(
@@ -522,6 +711,7 @@ impl ResourceHandle {
                 },
                 output_buffer_length,
                 std::any::type_name::<T>(),
+                op,
             )
             .await?
             .as_file()?
@@ -569,6 +759,7 @@ impl ResourceHandle {
                 },
                 output_buffer_length,
                 "SecurityDescriptor",
+                &OpOptions::default(),
             )
             .await?
             .as_security()?)
@@ -582,9 +773,18 @@ impl ResourceHandle {
     /// # Returns
     /// A `Result` containing the requested information, as bound to [`FsctlRequest::Response`].
     pub async fn fsctl<T: FsctlRequest>(&self, request: T) -> crate::Result<T::Response> {
+        self.fsctl_with(request, &OpOptions::default()).await
+    }
+
+    /// Sends an FSCTL message for the current resource (file), as [`ResourceHandle::fsctl`] does,
+    /// bound by the given per-operation options.
+    pub async fn fsctl_with<T: FsctlRequest>(
+        &self,
+        request: T,
+        op: &OpOptions,
+    ) -> crate::Result<T::Response> {
         const DEFAULT_RESPONSE_OUT_SIZE: u32 = 1024;
-        self.fsctl_with_options(request, DEFAULT_RESPONSE_OUT_SIZE)
-            .await
+        self._fsctl(request, DEFAULT_RESPONSE_OUT_SIZE, op).await
     }
 
     /// Sends an FSCTL message for the current resource (file) with additional options.
@@ -600,6 +800,18 @@ impl ResourceHandle {
         &self,
         request: T,
         max_output_response: u32,
+    ) -> crate::Result<T::Response> {
+        self._fsctl(request, max_output_response, &OpOptions::default())
+            .await
+    }
+
+    /// (Internal)
+    #[maybe_async]
+    async fn _fsctl<T: FsctlRequest>(
+        &self,
+        request: T,
+        max_output_response: u32,
+        op: &OpOptions,
     ) -> crate::Result<T::Response> {
         const NO_INPUT_IN_RESPONSE: u32 = 0;
         let ioctl_result = self
@@ -609,6 +821,7 @@ impl ResourceHandle {
                 NO_INPUT_IN_RESPONSE,
                 max_output_response,
                 IoctlRequestFlags::new().with_is_fsctl(true),
+                op,
             )
             .await?
             .parse_fsctl::<T::Response>()?;
@@ -627,6 +840,24 @@ impl ResourceHandle {
         ctl_code: u32,
         request: Vec<u8>,
         max_output_response: u32,
+    ) -> crate::Result<Vec<u8>> {
+        self.ioctl_with(
+            ctl_code,
+            request,
+            max_output_response,
+            &OpOptions::default(),
+        )
+        .await
+    }
+
+    /// Sends an IOCTL message for the current resource (file), as [`ResourceHandle::ioctl`] does,
+    /// bound by the given per-operation options.
+    pub async fn ioctl_with(
+        &self,
+        ctl_code: u32,
+        request: Vec<u8>,
+        max_output_response: u32,
+        op: &OpOptions,
     ) -> crate::Result<Vec<u8>> {
         const NO_INPUT_IN_RESPONSE: u32 = 0;
         let response = self
@@ -636,6 +867,7 @@ impl ResourceHandle {
                 NO_INPUT_IN_RESPONSE,
                 max_output_response,
                 IoctlRequestFlags::new(),
+                op,
             )
             .await?;
         Ok(response.out_buffer)
@@ -650,19 +882,20 @@ impl ResourceHandle {
         max_in: u32,
         max_out: u32,
         flags: IoctlRequestFlags,
+        op: &OpOptions,
     ) -> crate::Result<IoctlResponse> {
         let result = self
-            .handler
-            .send_recvo(
-                RequestContent::Ioctl(IoctlRequest {
+            .sendo_recvo_with(
+                OutgoingMessage::new(RequestContent::Ioctl(IoctlRequest {
                     ctl_code,
                     file_id: self.file_id()?,
                     max_input_response: max_in,
                     max_output_response: max_out,
                     flags,
                     buffer: req_data,
-                }),
+                })),
                 ReceiveOptions::new().with_allow_async(true),
+                op,
             )
             .await?
             .message
@@ -714,6 +947,7 @@ impl ResourceHandle {
                 },
                 output_buffer_length,
                 std::any::type_name::<T>(),
+                &OpOptions::default(),
             )
             .await?
             .as_filesystem()?
@@ -726,6 +960,15 @@ impl ResourceHandle {
     /// # Type Parameters
     /// * `T` - The type of information to set. Must implement the [SetFileInfoValue] trait.
     pub async fn set_info<T>(&self, info: T) -> crate::Result<()>
+    where
+        T: SetFileInfoValue,
+    {
+        self.set_info_with(info, &OpOptions::default()).await
+    }
+
+    /// Sets the file information for the current file, as [`ResourceHandle::set_info`] does,
+    /// bound by the given per-operation options.
+    pub async fn set_info_with<T>(&self, info: T, op: &OpOptions) -> crate::Result<()>
     where
         T: SetFileInfoValue,
     {
@@ -733,6 +976,7 @@ impl ResourceHandle {
             RawSetInfoData::from(info.into()),
             T::CLASS_ID.into(),
             Default::default(),
+            op,
         )
         .await
     }
@@ -754,6 +998,7 @@ impl ResourceHandle {
             RawSetInfoData::from(info.into()),
             T::CLASS_ID.into(),
             Default::default(),
+            &OpOptions::default(),
         )
         .await
     }
@@ -771,6 +1016,7 @@ impl ResourceHandle {
             info,
             SetInfoClass::Security(Default::default()),
             additional_info,
+            &OpOptions::default(),
         )
         .await
     }
@@ -796,13 +1042,14 @@ impl ResourceHandle {
     ///
     /// # Returns
     /// A `Result` indicating success or failure.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smb.close", skip_all, fields(path = %self.name)))]
     pub async fn close(&self) -> crate::Result<()> {
         if !self.open.swap(false, std::sync::atomic::Ordering::Relaxed) {
             return Err(Error::InvalidState("Resource is already closed".into()));
         }
 
         log::debug!("Closing handle for {} ({:?})", self.name, self._file_id);
-        Self::send_close(self._file_id, &self.handler).await?;
+        Self::release(&self.name, self._file_id, self.make_idle(), &self.handler).await?;
 
         log::debug!("Closed file {}.", self.name);
 
@@ -842,15 +1089,22 @@ impl ResourceHandle {
 
     #[maybe_async]
     #[inline]
-    pub async fn send_cancel(&self, msg_ids: &AsyncMessageIds) -> crate::Result<SendMessageResult> {
-        let mut outgoing_message = OutgoingMessage::new(CancelRequest {}.into());
-        outgoing_message.message.header.message_id = msg_ids.msg_id.load(Ordering::SeqCst);
-        outgoing_message
-            .message
-            .header
-            .to_async(msg_ids.async_id.load(Ordering::SeqCst));
+    async fn sendo_recvo_with(
+        &self,
+        msg: OutgoingMessage,
+        options: ReceiveOptions<'_>,
+        op: &OpOptions,
+    ) -> crate::Result<IncomingMessage> {
+        op_options::sendo_recvo(&*self.handler, msg, options, op).await
+    }
 
-        self.handler.sendo(outgoing_message).await
+    /// Sends an SMB2 CANCEL request for a request sent earlier on this resource.
+    ///
+    /// If the request went async (`msg_ids.async_id` is set), the async form of the cancel request is sent.
+    #[maybe_async]
+    #[inline]
+    pub async fn send_cancel(&self, msg_ids: &AsyncMessageIds) -> crate::Result<SendMessageResult> {
+        crate::op_options::send_cancel(&*self.handler, msg_ids).await
     }
 
     /// Returns whether current resource is opened from the same tree as the other resource.
@@ -925,10 +1179,12 @@ impl Drop for ResourceHandle {
 
         let file_id = self._file_id;
         let handler = self.handler.clone();
+        let name = std::mem::take(&mut self.name);
+        let idle = self.make_idle();
         log::debug!("Spawning task to close file with ID: {file_id:?}");
         tokio::task::spawn(async move {
             if file_id != FileId::EMPTY {
-                if let Err(e) = Self::send_close(file_id, &handler).await {
+                if let Err(e) = Self::release(&name, file_id, idle, &handler).await {
                     log::error!("Error closing file: {e}");
                 }
             }
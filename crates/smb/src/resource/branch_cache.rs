@@ -0,0 +1,807 @@
+//! BranchCache content information, for verifying the integrity of file data.
+//!
+//! Servers that enable BranchCache on a share generate hashes of the files' data,
+//! which may be retrieved using [`File::content_info`], and then used to verify the data
+//! read from the file, using a [`VerifyingReader`], or to find segments of a local copy
+//! that do not need to be transferred again, using [`ContentInfo::unchanged_segments`].
+use super::File;
+use super::file_util::*;
+use crate::Error;
+use crate::op_options::OpOptions;
+use binrw::prelude::*;
+use maybe_async::maybe_async;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use smb_msg::*;
+use std::io::Cursor;
+
+/// The hash algorithm used by a [`ContentInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentHashAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+    /// SHA-512, truncated to its first 32 bytes. Used by version 2 of the content information.
+    TruncatedSha512,
+}
+
+impl ContentHashAlgorithm {
+    /// Hashes the given data.
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ContentHashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            ContentHashAlgorithm::Sha384 => Sha384::digest(data).to_vec(),
+            ContentHashAlgorithm::Sha512 => Sha512::digest(data).to_vec(),
+            ContentHashAlgorithm::TruncatedSha512 => Sha512::digest(data)[..32].to_vec(),
+        }
+    }
+}
+
+impl From<ContentHashAlgorithmV1> for ContentHashAlgorithm {
+    fn from(value: ContentHashAlgorithmV1) -> Self {
+        match value {
+            ContentHashAlgorithmV1::Sha256 => ContentHashAlgorithm::Sha256,
+            ContentHashAlgorithmV1::Sha384 => ContentHashAlgorithm::Sha384,
+            ContentHashAlgorithmV1::Sha512 => ContentHashAlgorithm::Sha512,
+        }
+    }
+}
+
+impl From<ContentHashAlgorithmV2> for ContentHashAlgorithm {
+    fn from(value: ContentHashAlgorithmV2) -> Self {
+        match value {
+            ContentHashAlgorithmV2::TruncatedSha512 => ContentHashAlgorithm::TruncatedSha512,
+        }
+    }
+}
+
+/// The BranchCache content information of a file: the hashes of its segments and blocks.
+///
+/// Both versions of the content information (MS-PCCRC 2.3, 2.4) are represented by this struct;
+/// since version 2 hashes each segment as a whole, its segments consist of a single block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentInfo {
+    /// The version of the content information, 1 or 2.
+    pub version: u32,
+    /// The hash algorithm of all the hashes of the content information.
+    pub hash_algorithm: ContentHashAlgorithm,
+    /// The segments of the content, ordered by their offset.
+    pub segments: Vec<ContentSegment>,
+}
+
+/// A segment of the content, described by a [`ContentInfo`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentSegment {
+    /// The offset of the segment within the content.
+    pub offset: u64,
+    /// The length of the segment, in bytes.
+    pub length: u32,
+    /// The length of each block of the segment, in bytes. The last block may be shorter.
+    pub block_size: u32,
+    /// The hash of the segment's data (HoD).
+    pub hash_of_data: Vec<u8>,
+    /// The segment secret, derived from the HoD and the server secret.
+    pub secret: Vec<u8>,
+    /// The hash of each block of the segment.
+    pub block_hashes: Vec<Vec<u8>>,
+}
+
+impl ContentSegment {
+    /// The offset of the end of the segment within the content.
+    pub fn end(&self) -> u64 {
+        self.offset + self.length as u64
+    }
+}
+
+/// A single block of a [`ContentSegment`].
+struct ContentBlock<'a> {
+    offset: u64,
+    length: usize,
+    hash: &'a [u8],
+}
+
+impl ContentInfo {
+    /// Parses a Content Information File, as retrieved from the server using
+    /// hash-based SRV_READ_HASH requests: a [`SrvReadHashRes`] header, followed by
+    /// version 1 of the content information.
+    pub fn parse_v1(file: &[u8]) -> crate::Result<Self> {
+        let header = SrvReadHashRes::read_le(&mut Cursor::new(file))?;
+        if header.hash_version != 1 {
+            return Err(Error::InvalidMessage(format!(
+                "Unexpected content information version {}",
+                header.hash_version
+            )));
+        }
+        if header.dirty != 0 {
+            return Err(Error::InvalidState(
+                "Content information is out of date".to_string(),
+            ));
+        }
+
+        let start = header.hash_blob_offset as usize;
+        let blob = file
+            .get(start..start + header.hash_blob_length as usize)
+            .ok_or_else(|| {
+                Error::InvalidMessage("Content information exceeds its file".to_string())
+            })?;
+        let info = ContentInformationV1::read_le(&mut Cursor::new(blob))?;
+
+        let mut content_info = ContentInfo {
+            version: 1,
+            hash_algorithm: info.hash_algorithm.into(),
+            segments: Vec::with_capacity(info.segments.len()),
+        };
+        for (segment, blocks) in info.segments.into_iter().zip(info.blocks) {
+            let segment = ContentSegment {
+                offset: segment.offset_in_content,
+                length: segment.segment_length,
+                block_size: segment.block_size,
+                hash_of_data: segment.segment_hash_of_data,
+                secret: segment.segment_secret,
+                block_hashes: blocks.block_hashes,
+            };
+            if content_info
+                .hash_algorithm
+                .hash(&segment.block_hashes.concat())
+                != segment.hash_of_data
+            {
+                return Err(Error::InvalidMessage(format!(
+                    "Hash of segment at {} does not match its block hashes",
+                    segment.offset
+                )));
+            }
+            content_info.push(segment)?;
+        }
+        Ok(content_info)
+    }
+
+    /// Appends the segments of version 2 of the content information, that follow the existing ones.
+    fn extend_v2(&mut self, info: ContentInformationV2) -> crate::Result<()> {
+        let mut offset = info.start_in_content;
+        for segment in info.chunks.into_iter().flat_map(|chunk| chunk.segments) {
+            let length = segment.segment_length;
+            let segment = ContentSegment {
+                offset,
+                length,
+                block_size: length,
+                hash_of_data: segment.segment_hash_of_data.to_vec(),
+                secret: segment.segment_secret.to_vec(),
+                block_hashes: vec![segment.segment_hash_of_data.to_vec()],
+            };
+            offset = segment.offset.checked_add(length as u64).ok_or_else(|| {
+                Error::InvalidMessage(format!("Segment at {offset} exceeds the content"))
+            })?;
+            if segment.offset >= self.len() {
+                self.push(segment)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a segment, which must directly follow the existing ones,
+    /// and consist of the blocks its length and block size imply.
+    fn push(&mut self, segment: ContentSegment) -> crate::Result<()> {
+        let invalid = |reason: &str| {
+            Err(Error::InvalidMessage(format!(
+                "Segment at {} {reason}",
+                segment.offset
+            )))
+        };
+        if segment.length == 0 || segment.block_size == 0 {
+            return invalid("is empty");
+        }
+        if segment.offset != self.len() {
+            return invalid("does not follow the previous segment");
+        }
+        if segment.offset.checked_add(segment.length as u64).is_none() {
+            return invalid("exceeds the content");
+        }
+        if segment.block_hashes.len() != segment.length.div_ceil(segment.block_size) as usize {
+            return invalid("does not have a hash for each of its blocks");
+        }
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// The length of the content described by the content information.
+    pub fn len(&self) -> u64 {
+        self.segments.last().map_or(0, ContentSegment::end)
+    }
+
+    /// Whether the content information describes no content at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the block containing the given offset, if described.
+    fn block_at(&self, offset: u64) -> Option<ContentBlock<'_>> {
+        let index = self.segments.partition_point(|s| s.end() <= offset);
+        let segment = self.segments.get(index).filter(|s| s.offset <= offset)?;
+        let block_index = ((offset - segment.offset) / segment.block_size as u64) as usize;
+        let block_offset = segment.offset + block_index as u64 * segment.block_size as u64;
+        Some(ContentBlock {
+            offset: block_offset,
+            length: (segment.end() - block_offset).min(segment.block_size as u64) as usize,
+            hash: segment.block_hashes.get(block_index)?,
+        })
+    }
+
+    /// Verifies the data of the content, starting at the given offset, against the block hashes.
+    ///
+    /// The data must start at a block boundary, and consist of whole blocks; an incomplete
+    /// block (or no data at all) fails the verification, just as a block with unmatching data does.
+    pub fn verify(&self, offset: u64, data: &[u8]) -> crate::Result<()> {
+        if data.is_empty() {
+            return Err(Error::InvalidArgument("No data to verify".to_string()));
+        }
+        let mut position = 0;
+        while position < data.len() {
+            let block_offset = offset + position as u64;
+            let block = self.block_at(block_offset).ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "Offset {block_offset} is not described by the content information"
+                ))
+            })?;
+            if block.offset != block_offset {
+                return Err(Error::InvalidArgument(format!(
+                    "Offset {block_offset} is not aligned to a block"
+                )));
+            }
+            let block_data = data
+                .get(position..position + block.length)
+                .ok_or(Error::ContentHashMismatch(block.offset))?;
+            if self.hash_algorithm.hash(block_data) != block.hash {
+                return Err(Error::ContentHashMismatch(block.offset));
+            }
+            position += block.length;
+        }
+        Ok(())
+    }
+
+    /// Returns the segments whose data matches the data of the given (e.g. local) copy of the content,
+    /// and therefore do not have to be transferred again.
+    #[maybe_async]
+    pub async fn unchanged_segments<R: ReadAtChannel + ?Sized>(
+        &self,
+        copy: &R,
+    ) -> crate::Result<Vec<&ContentSegment>> {
+        let mut unchanged = Vec::new();
+        for segment in &self.segments {
+            let mut data = vec![0; segment.length as usize];
+            let read = read_full(copy, &mut data, segment.offset, None).await?;
+            if read == data.len() && self.verify(segment.offset, &data).is_ok() {
+                unchanged.push(segment);
+            }
+        }
+        Ok(unchanged)
+    }
+}
+
+#[maybe_async(AFIT)]
+impl File {
+    /// Retrieves the BranchCache content information of the file from the server.
+    ///
+    /// Version 2 of the content information is retrieved over SMB 3.x, if the server provides it;
+    /// Otherwise, version 1 is retrieved.
+    /// The server must have BranchCache enabled for the share, and have the hashes of the file
+    /// already generated, or an error is returned.
+    pub async fn content_info(&self) -> crate::Result<ContentInfo> {
+        if self.conn_info.negotiation.dialect_rev.is_smb3() {
+            match self.content_info_v2().await {
+                Err(Error::ReceivedErrorMessage(status, _)) => {
+                    log::debug!(
+                        "Failed to retrieve content information v2 of {} ({status:#x}), falling back to v1.",
+                        self.name()
+                    );
+                }
+                result => return result,
+            }
+        }
+        self.content_info_v1().await
+    }
+
+    /// Retrieves the whole Content Information File of the file, using hash-based requests.
+    async fn content_info_v1(&self) -> crate::Result<ContentInfo> {
+        let mut file = Vec::new();
+        loop {
+            let request = SrvReadHashReq {
+                hash_version: 1,
+                hash_retrieval_type: SrvHashRetrievalType::HashBased,
+                length: READ_HASH_LENGTH,
+                offset: file.len() as u64,
+            };
+            let response = self
+                .fsctl_with_options(request, READ_HASH_MAX_OUTPUT)
+                .await?;
+            if response.blob.is_empty() {
+                break;
+            }
+            file.extend_from_slice(&response.blob);
+
+            if let Ok(header) = SrvReadHashRes::read_le(&mut Cursor::new(&file)) {
+                let total = header.hash_blob_offset as usize + header.hash_blob_length as usize;
+                if file.len() >= total {
+                    break;
+                }
+            }
+        }
+        ContentInfo::parse_v1(&file)
+    }
+
+    /// Retrieves the content information of the whole file, range by range, using file-based requests.
+    async fn content_info_v2(&self) -> crate::Result<ContentInfo> {
+        let end_of_file = self.get_len().await?;
+        let mut content_info = ContentInfo {
+            version: 2,
+            hash_algorithm: ContentHashAlgorithm::TruncatedSha512,
+            segments: Vec::new(),
+        };
+        while content_info.len() < end_of_file {
+            let request = SrvReadHashReq {
+                hash_version: 2,
+                hash_retrieval_type: SrvHashRetrievalType::FileBased,
+                length: READ_HASH_LENGTH,
+                offset: content_info.len(),
+            };
+            let response = self
+                ._ioctl(
+                    FsctlCodes::SrvReadHash as u32,
+                    request.into(),
+                    0,
+                    READ_HASH_MAX_OUTPUT,
+                    IoctlRequestFlags::new().with_is_fsctl(true),
+                    &OpOptions::default(),
+                )
+                .await?
+                .parse_fsctl::<SrvHashRetrieveFileBased>()?;
+
+            let info = ContentInformationV2::read_be(&mut Cursor::new(&response.buffer))?;
+            content_info.hash_algorithm = info.hash_algorithm.into();
+            let covered = content_info.len();
+            content_info.extend_v2(info)?;
+            if content_info.len() <= covered {
+                return Err(Error::InvalidMessage(format!(
+                    "Content information of {} does not cover offset {covered}",
+                    self.name()
+                )));
+            }
+        }
+        Ok(content_info)
+    }
+}
+
+/// The maximum length of content information to retrieve in a single request.
+const READ_HASH_LENGTH: u32 = READ_HASH_MAX_OUTPUT - 0x20;
+const READ_HASH_MAX_OUTPUT: u32 = 0x10000;
+
+/// A reader that verifies all the data it reads against the [`ContentInfo`] of the content.
+///
+/// Since blocks may only be verified as a whole, each read is extended to whole blocks;
+/// Reading at block-aligned offsets, with a length that is a multiple of the block size, avoids
+/// reading the same data more than once.
+/// Reads past the end of the described content return no data. A block with data that does not
+/// match its hash, or that is cut short by the end of the inner data, fails the read
+/// with [`Error::ContentHashMismatch`].
+pub struct VerifyingReader<R> {
+    inner: R,
+    content_info: ContentInfo,
+}
+
+impl<R> VerifyingReader<R> {
+    /// Creates a new verifying reader of the given content, described by the content information.
+    pub fn new(inner: R, content_info: ContentInfo) -> Self {
+        Self {
+            inner,
+            content_info,
+        }
+    }
+
+    /// Returns the content information the data is verified against.
+    pub fn content_info(&self) -> &ContentInfo {
+        &self.content_info
+    }
+
+    /// Returns the inner reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: ReadAtChannel + Sync> ReadAtChannel for VerifyingReader<R> {
+    #[maybe_async]
+    async fn read_at_channel(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        channel: Option<u32>,
+    ) -> crate::Result<usize> {
+        let content_end = self.content_info.len();
+        if buf.is_empty() || offset >= content_end {
+            return Ok(0);
+        }
+
+        let first = self.content_info.block_at(offset).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "Offset {offset} is not described by the content information"
+            ))
+        })?;
+        let end = content_end.min(offset + buf.len() as u64);
+        let last = self.content_info.block_at(end - 1).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "Offset {} is not described by the content information",
+                end - 1
+            ))
+        })?;
+
+        let mut data = vec![0; (last.offset + last.length as u64 - first.offset) as usize];
+        let read = read_full(&self.inner, &mut data, first.offset, channel).await?;
+        if read < data.len() {
+            // The content is shorter than described, so the block at its end is incomplete.
+            let missing = first.offset + read as u64;
+            let block = self
+                .content_info
+                .block_at(missing)
+                .map_or(missing, |b| b.offset);
+            return Err(Error::ContentHashMismatch(block));
+        }
+        self.content_info.verify(first.offset, &data)?;
+
+        let skip = (offset - first.offset) as usize;
+        let length = buf.len().min(data.len() - skip);
+        buf[..length].copy_from_slice(&data[skip..skip + length]);
+        Ok(length)
+    }
+}
+
+impl<R: GetLen> GetLen for VerifyingReader<R> {
+    #[maybe_async]
+    async fn get_len(&self) -> crate::Result<u64> {
+        self.inner.get_len().await
+    }
+}
+
+/// Reads into the whole buffer, unless the end of the data is reached first.
+#[maybe_async]
+async fn read_full<R: ReadAtChannel + ?Sized>(
+    reader: &R,
+    buf: &mut [u8],
+    offset: u64,
+    channel: Option<u32>,
+) -> crate::Result<usize> {
+    let mut position = 0;
+    while position < buf.len() {
+        let read = reader
+            .read_at_channel(&mut buf[position..], offset + position as u64, channel)
+            .await?;
+        if read == 0 {
+            break;
+        }
+        position += read;
+    }
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8; 16] = b"0123456789abcdef";
+
+    /// Describes a segment of [`DATA`] in version 1 of the content information, with 4-byte blocks.
+    fn segment_v1(offset: usize, length: usize) -> (SegmentDescriptionV1, SegmentContentBlocksV1) {
+        let algorithm = ContentHashAlgorithm::Sha256;
+        let block_hashes = DATA[offset..offset + length]
+            .chunks(4)
+            .map(|block| algorithm.hash(block))
+            .collect::<Vec<_>>();
+        (
+            SegmentDescriptionV1 {
+                offset_in_content: offset as u64,
+                segment_length: length as u32,
+                block_size: 4,
+                segment_hash_of_data: algorithm.hash(&block_hashes.concat()),
+                segment_secret: vec![0; 32],
+            },
+            SegmentContentBlocksV1 { block_hashes },
+        )
+    }
+
+    /// Returns a Content Information File of the segments: a header, followed by version 1 of the content information.
+    fn content_info_file_v1(
+        segments: Vec<(SegmentDescriptionV1, SegmentContentBlocksV1)>,
+    ) -> Vec<u8> {
+        let (segments, blocks) = segments.into_iter().unzip();
+        let info = ContentInformationV1 {
+            hash_algorithm: ContentHashAlgorithmV1::Sha256,
+            offset_in_first_segment: 0,
+            read_bytes_in_last_segment: 6,
+            segments,
+            blocks,
+        };
+        let mut blob = Cursor::new(Vec::new());
+        info.write_le(&mut blob).unwrap();
+        let blob = blob.into_inner();
+
+        const HEADER_SIZE: u32 = 36;
+        let header = SrvReadHashRes {
+            hash_version: 1,
+            source_file_change_time: Default::default(),
+            source_file_size: DATA.len() as u64,
+            hash_blob_length: blob.len() as u32,
+            hash_blob_offset: HEADER_SIZE,
+            dirty: 0,
+            source_file_name: vec![],
+        };
+        let mut file = Cursor::new(Vec::new());
+        header.write_le(&mut file).unwrap();
+        assert_eq!(file.get_ref().len(), HEADER_SIZE as usize);
+        file.get_mut().extend_from_slice(&blob);
+        file.into_inner()
+    }
+
+    fn content_info_v1() -> ContentInfo {
+        ContentInfo::parse_v1(&content_info_file_v1(vec![
+            segment_v1(0, 10),
+            segment_v1(10, 6),
+        ]))
+        .unwrap()
+    }
+
+    /// Version 2 of the content information of [`DATA`], from `start`, with a (big-endian) chunk per segment.
+    fn content_information_v2(start: usize, lengths: &[u32]) -> Vec<u8> {
+        let mut info = vec![0x02, 0x00, 0x04];
+        info.extend_from_slice(&0u32.to_be_bytes());
+        info.extend_from_slice(&0u32.to_be_bytes());
+        info.extend_from_slice(&(start as u64).to_be_bytes());
+        let mut offset = start;
+        for chunk in lengths.chunks(1) {
+            info.push(0);
+            info.extend_from_slice(
+                &((chunk.len() * SegmentDescriptionV2::SIZE) as u32).to_be_bytes(),
+            );
+            for length in chunk {
+                let end = (offset + *length as usize).min(DATA.len());
+                info.extend_from_slice(&length.to_be_bytes());
+                info.extend_from_slice(
+                    &ContentHashAlgorithm::TruncatedSha512.hash(&DATA[offset..end]),
+                );
+                info.extend_from_slice(&[0xcc; 32]);
+                offset = end;
+            }
+        }
+        info
+    }
+
+    /// Reads from a buffer in memory.
+    struct MemoryReader(Vec<u8>);
+
+    impl ReadAtChannel for MemoryReader {
+        #[maybe_async]
+        async fn read_at_channel(
+            &self,
+            buf: &mut [u8],
+            offset: u64,
+            _channel: Option<u32>,
+        ) -> crate::Result<usize> {
+            let data = self.0.get(offset as usize..).unwrap_or_default();
+            let length = buf.len().min(data.len());
+            buf[..length].copy_from_slice(&data[..length]);
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn test_parse_and_verify_v1() {
+        let content_info = content_info_v1();
+        assert_eq!(content_info.len(), 16);
+        assert_eq!(content_info.segments[0].block_hashes.len(), 3);
+        assert_eq!(content_info.segments[1].block_hashes.len(), 2);
+
+        content_info.verify(0, DATA).unwrap();
+        content_info.verify(8, &DATA[8..14]).unwrap();
+
+        let mut tampered = DATA.to_vec();
+        tampered[5] = b'x';
+        assert!(matches!(
+            content_info.verify(0, &tampered),
+            Err(Error::ContentHashMismatch(4))
+        ));
+        assert!(matches!(
+            content_info.verify(0, &DATA[..6]),
+            Err(Error::ContentHashMismatch(4))
+        ));
+        assert!(matches!(
+            content_info.verify(2, &DATA[2..4]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(content_info.verify(0, &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_v1() {
+        let mut empty_blocks = segment_v1(0, 10);
+        empty_blocks.0.block_size = 0;
+        let mut missing_block = segment_v1(0, 10);
+        missing_block.1.block_hashes.pop();
+        missing_block.0.segment_hash_of_data =
+            ContentHashAlgorithm::Sha256.hash(&missing_block.1.block_hashes.concat());
+        for segments in [
+            vec![empty_blocks],
+            vec![missing_block],
+            vec![segment_v1(0, 10), segment_v1(12, 4)],
+            vec![segment_v1(10, 6), segment_v1(0, 10)],
+        ] {
+            let file = content_info_file_v1(segments);
+            assert!(matches!(
+                ContentInfo::parse_v1(&file),
+                Err(Error::InvalidMessage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_and_verify_v2() {
+        let mut content_info = ContentInfo {
+            version: 2,
+            hash_algorithm: ContentHashAlgorithm::TruncatedSha512,
+            segments: Vec::new(),
+        };
+        let info = content_information_v2(0, &[6, 4]);
+        let info = ContentInformationV2::read_be(&mut Cursor::new(&info)).unwrap();
+        assert_eq!(info.chunks.len(), 2);
+        content_info.extend_v2(info).unwrap();
+        assert_eq!(content_info.len(), 10);
+
+        // A following range, which repeats the last segment.
+        let info = content_information_v2(6, &[4, 6]);
+        let info = ContentInformationV2::read_be(&mut Cursor::new(&info)).unwrap();
+        content_info.extend_v2(info).unwrap();
+        assert_eq!(content_info.len(), 16);
+        assert_eq!(
+            content_info
+                .segments
+                .iter()
+                .map(|s| (s.offset, s.length, s.block_size))
+                .collect::<Vec<_>>(),
+            vec![(0, 6, 6), (6, 4, 4), (10, 6, 6)]
+        );
+        content_info.verify(0, DATA).unwrap();
+        assert!(matches!(
+            content_info.verify(0, &DATA[..8]),
+            Err(Error::ContentHashMismatch(6))
+        ));
+
+        let info = content_information_v2(16, &[0]);
+        let info = ContentInformationV2::read_be(&mut Cursor::new(&info)).unwrap();
+        assert!(matches!(
+            content_info.extend_v2(info),
+            Err(Error::InvalidMessage(_))
+        ));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_verifying_reader() {
+        let reader = VerifyingReader::new(MemoryReader(DATA.to_vec()), content_info_v1());
+        let mut buf = [0; 16];
+        let read = reader.read_at(&mut buf, 0).await.unwrap();
+        assert_eq!(&buf[..read], DATA);
+        let read = reader.read_at(&mut buf[..3], 5).await.unwrap();
+        assert_eq!(&buf[..read], b"567");
+        let read = reader.read_at(&mut buf, 16).await.unwrap();
+        assert_eq!(read, 0);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_verifying_reader_hash_mismatch() {
+        let mut tampered = DATA.to_vec();
+        tampered[5] = b'x';
+        let reader = VerifyingReader::new(MemoryReader(tampered), content_info_v1());
+        let mut buf = [0; 2];
+        let result = reader.read_at(&mut buf, 6).await;
+        assert!(matches!(result, Err(Error::ContentHashMismatch(4))));
+        // Blocks that were not tampered with are read as usual.
+        let read = reader.read_at(&mut buf, 8).await.unwrap();
+        assert_eq!(&buf[..read], b"89");
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_verifying_reader_short_read() {
+        let reader = VerifyingReader::new(MemoryReader(DATA[..13].to_vec()), content_info_v1());
+        let mut buf = [0; 16];
+        let result = reader.read_at(&mut buf, 0).await;
+        assert!(matches!(result, Err(Error::ContentHashMismatch(10))));
+        let result = reader.read_at(&mut buf, 15).await;
+        assert!(matches!(result, Err(Error::ContentHashMismatch(14))));
+        // Blocks before the end of the data are read as usual.
+        let read = reader.read_at(&mut buf[..4], 4).await.unwrap();
+        assert_eq!(&buf[..read], b"4567");
+    }
+
+    #[cfg(feature = "testing")]
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_file_content_info() {
+        use crate::testing::{FakeReply, FakeServer, FakeServerConfig};
+        use crate::{ConnectionConfig, sync_helpers::Arc};
+        use smb_fscc::FileAccessMask;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_file("share", "file.bin", DATA.to_vec());
+        let file_based = Arc::new(AtomicBool::new(true));
+        server.on(Command::Ioctl, {
+            let file_based = file_based.clone();
+            move |request| {
+                let RequestContent::Ioctl(ioctl) = &request.content else {
+                    return FakeReply::Default;
+                };
+                let IoctlReqData::FsctlSrvReadHash(req) = &ioctl.buffer else {
+                    return FakeReply::Default;
+                };
+                let mut output = Cursor::new(Vec::new());
+                match req.hash_retrieval_type {
+                    SrvHashRetrievalType::FileBased if file_based.load(Ordering::SeqCst) => {
+                        // Two segments at a time.
+                        let lengths: &[u32] = if req.offset == 0 { &[6, 4] } else { &[6] };
+                        SrvHashRetrieveFileBased {
+                            file_data_offset: req.offset,
+                            file_data_length: lengths.iter().sum::<u32>() as u64,
+                            buffer: content_information_v2(req.offset as usize, lengths),
+                        }
+                        .write_le(&mut output)
+                        .unwrap();
+                    }
+                    SrvHashRetrievalType::FileBased => {
+                        return FakeReply::Error(Status::NotSupported);
+                    }
+                    SrvHashRetrievalType::HashBased => {
+                        // The Content Information File, in small portions.
+                        let file = content_info_file_v1(vec![segment_v1(0, 10), segment_v1(10, 6)]);
+                        let start = (req.offset as usize).min(file.len());
+                        let end = (start + 64).min(file.len());
+                        SrvHashRetrieveHashBased {
+                            offset: req.offset,
+                            blob: file[start..end].to_vec(),
+                        }
+                        .write_le(&mut output)
+                        .unwrap();
+                    }
+                }
+                FakeReply::Respond(
+                    IoctlResponse {
+                        ctl_code: ioctl.ctl_code,
+                        file_id: ioctl.file_id,
+                        in_buffer: vec![],
+                        out_buffer: output.into_inner(),
+                    }
+                    .into(),
+                )
+            }
+        });
+        let connection = server.connect(ConnectionConfig::default()).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+        let file = tree
+            .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+
+        let content_info = file.content_info().await.unwrap();
+        assert_eq!(content_info.version, 2);
+        assert_eq!(content_info.segments.len(), 3);
+        assert_eq!(content_info.len(), 16);
+
+        // Servers that do not provide version 2 fall back to version 1.
+        file_based.store(false, Ordering::SeqCst);
+        let content_info = file.content_info().await.unwrap();
+        assert_eq!(content_info, content_info_v1());
+
+        let reader = VerifyingReader::new(file, content_info);
+        let mut buf = [0; 16];
+        let read = reader.read_at(&mut buf, 0).await.unwrap();
+        assert_eq!(&buf[..read], DATA);
+        reader.into_inner().close().await.unwrap();
+    }
+}
@@ -0,0 +1,461 @@
+//! On-disk checkpoints of block copies, allowing interrupted copies to resume.
+//!
+//! A checkpointed copy periodically persists the ranges it has completed to a sidecar
+//! state file. When the copy is started again with the same state file, the source
+//! has not changed since, and the destination is the same file and still covers the completed
+//! ranges, only the missing ranges are copied.
+//! See [`prepare_checkpointed_copy`][super::prepare_checkpointed_copy] and
+//! [`block_copy_checkpointed`][super::block_copy_checkpointed].
+//!
+//! The state file is removed once the copy completes.
+use super::File;
+use binrw::prelude::*;
+use maybe_async::maybe_async;
+use smb_dtyp::binrw_util::prelude::FileTime;
+use smb_fscc::FileAllInformation;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Identifies a version of a copy source (or a copy destination), so that a change of the source
+/// between an interrupted copy and its resumption can be detected.
+#[binrw::binrw]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct FileIdentity {
+    /// The file ID (index number) of the source, if available; 0 otherwise.
+    pub file_id: u64,
+    /// The size of the source, in bytes.
+    pub size: u64,
+    /// The last change time of the source.
+    pub change_time: FileTime,
+}
+
+/// Trait for copy sources and destinations that can identify their current version.
+#[maybe_async(AFIT)]
+#[allow(async_fn_in_trait)]
+pub trait GetIdentity {
+    async fn get_identity(&self) -> crate::Result<FileIdentity>;
+}
+
+impl GetIdentity for File {
+    #[maybe_async]
+    async fn get_identity(&self) -> crate::Result<FileIdentity> {
+        let info = self.query_info::<FileAllInformation>().await?;
+        Ok(FileIdentity {
+            file_id: info.internal.index_number,
+            size: info.standard.end_of_file,
+            change_time: info.basic.change_time,
+        })
+    }
+}
+
+impl<T: GetIdentity + ?Sized> GetIdentity for Arc<T> {
+    #[maybe_async]
+    async fn get_identity(&self) -> crate::Result<FileIdentity> {
+        (**self).get_identity().await
+    }
+}
+
+#[cfg(feature = "std-fs-impls")]
+mod impls {
+    use super::*;
+    use crate::sync_helpers::Mutex;
+    #[cfg(not(feature = "async"))]
+    use std::fs::File;
+    #[cfg(feature = "async")]
+    use tokio::fs::File;
+
+    impl GetIdentity for Mutex<File> {
+        #[maybe_async]
+        async fn get_identity(&self) -> crate::Result<FileIdentity> {
+            let file = self
+                .lock()
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            let metadata = file.metadata().await?;
+            identity_of(&metadata)
+        }
+    }
+
+    #[cfg(unix)]
+    fn identity_of(metadata: &std::fs::Metadata) -> crate::Result<FileIdentity> {
+        use std::os::unix::fs::MetadataExt;
+        let change_time = std::time::UNIX_EPOCH
+            + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+        Ok(FileIdentity {
+            file_id: metadata.ino(),
+            size: metadata.len(),
+            change_time: change_time.into(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn identity_of(metadata: &std::fs::Metadata) -> crate::Result<FileIdentity> {
+        Ok(FileIdentity {
+            file_id: 0,
+            size: metadata.len(),
+            change_time: metadata.modified()?.into(),
+        })
+    }
+}
+
+/// Returns whether the checkpoint state file exists, and was saved while copying the identified
+/// source (same file ID, size and change time), so a copy of it may be resumed from the file.
+pub fn checkpoint_matches(path: &Path, identity: &FileIdentity) -> crate::Result<bool> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(CheckpointFile::read(&mut Cursor::new(data))
+            .is_ok_and(|file| file.identity == *identity)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The contents of a checkpoint state file.
+#[binrw::binrw]
+#[derive(Debug, PartialEq, Eq)]
+#[brw(little, magic = b"SMBCKPT\x02")]
+pub(crate) struct CheckpointFile {
+    identity: FileIdentity,
+    /// The file ID of the destination, if available; 0 otherwise.
+    destination_id: u64,
+    #[bw(try_calc = completed.len().try_into())]
+    #[br(temp)]
+    range_count: u32,
+    #[br(count = range_count)]
+    completed: Vec<CompletedRange>,
+}
+
+impl CheckpointFile {
+    /// Returns whether the identified destination may hold the completed ranges: it is the file
+    /// the checkpoint was saved while copying to (when file IDs are available), and it covers them.
+    fn held_by(&self, destination: &FileIdentity) -> bool {
+        let same_file = self.destination_id == 0
+            || destination.file_id == 0
+            || self.destination_id == destination.file_id;
+        same_file
+            && self
+                .completed
+                .iter()
+                .all(|range| range.end <= destination.size)
+    }
+}
+
+/// A range of bytes that has been copied.
+#[binrw::binrw]
+#[derive(Debug, PartialEq, Eq)]
+#[brw(little)]
+struct CompletedRange {
+    start: u64,
+    end: u64,
+}
+
+/// The progress of a checkpointed copy, persisted to a state file.
+pub(crate) struct CopyCheckpoint {
+    path: PathBuf,
+    identity: FileIdentity,
+    destination_id: u64,
+    chunk_size: u64,
+    completed_count: AtomicU64,
+    progress: Mutex<CheckpointProgress>,
+    /// Serializes writing the state file.
+    saving: Mutex<()>,
+}
+
+struct CheckpointProgress {
+    completed: Vec<bool>,
+    last_saved: Instant,
+}
+
+impl CopyCheckpoint {
+    /// The interval between persisting the progress to the state file.
+    const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Opens the checkpoint of copying the identified source to the identified destination,
+    /// in chunks of the given size. The destination must be identified before it is resized.
+    ///
+    /// The progress is resumed from the state file, if it exists, describes the same source,
+    /// and the destination still holds the ranges it completed; Otherwise, the copy starts over.
+    pub fn open(
+        path: &Path,
+        identity: FileIdentity,
+        destination: FileIdentity,
+        chunk_size: u64,
+    ) -> crate::Result<Self> {
+        let chunk_count = identity.size.div_ceil(chunk_size) as usize;
+        let mut completed = vec![false; chunk_count];
+
+        match std::fs::read(path) {
+            Ok(data) => match CheckpointFile::read(&mut Cursor::new(data)) {
+                Ok(file) if file.identity != identity => log::info!(
+                    "Source changed since checkpoint {} was saved ({:?} != {identity:?}), starting over.",
+                    path.display(),
+                    file.identity
+                ),
+                Ok(file) if !file.held_by(&destination) => log::info!(
+                    "Destination changed since checkpoint {} was saved ({destination:?}), starting over.",
+                    path.display()
+                ),
+                Ok(file) => {
+                    // Only chunks that are fully covered by a completed range are resumed.
+                    for range in file.completed {
+                        let first = range.start.div_ceil(chunk_size);
+                        let last = if range.end >= identity.size {
+                            chunk_count as u64
+                        } else {
+                            range.end / chunk_size
+                        };
+                        for chunk in first..last {
+                            completed[chunk as usize] = true;
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Invalid checkpoint {}, starting over: {e}", path.display()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let completed_count = completed.iter().filter(|&&c| c).count() as u64;
+        if completed_count > 0 {
+            log::info!(
+                "Resuming copy from checkpoint {}: {completed_count}/{chunk_count} chunks already copied.",
+                path.display()
+            );
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            identity,
+            destination_id: destination.file_id,
+            chunk_size,
+            completed_count: AtomicU64::new(completed_count),
+            progress: Mutex::new(CheckpointProgress {
+                completed,
+                last_saved: Instant::now(),
+            }),
+            saving: Mutex::new(()),
+        })
+    }
+
+    /// Returns whether the chunk has already been copied.
+    pub fn is_completed(&self, chunk: u64) -> crate::Result<bool> {
+        Ok(self.progress.lock()?.completed[chunk as usize])
+    }
+
+    /// Returns the number of bytes copied so far.
+    pub fn bytes_completed(&self) -> u64 {
+        let completed_count = self.completed_count.load(Ordering::Relaxed);
+        (completed_count * self.chunk_size).min(self.identity.size)
+    }
+
+    /// Marks the chunk as copied.
+    ///
+    /// If the progress was not persisted recently, returns a snapshot of it, to be passed to
+    /// [`save`][Self::save] once the data written so far to the destination is durable.
+    pub fn complete(&self, chunk: u64) -> crate::Result<Option<CheckpointFile>> {
+        let mut progress = self.progress.lock()?;
+        if !std::mem::replace(&mut progress.completed[chunk as usize], true) {
+            self.completed_count.fetch_add(1, Ordering::Relaxed);
+        }
+        if progress.last_saved.elapsed() < Self::SAVE_INTERVAL {
+            return Ok(None);
+        }
+        Ok(Some(self.snapshot(&mut progress)))
+    }
+
+    /// Persists a snapshot of the progress, returned by [`complete`][Self::complete].
+    pub fn save(&self, snapshot: &CheckpointFile) -> crate::Result<()> {
+        let mut data = Cursor::new(Vec::new());
+        snapshot.write(&mut data)?;
+
+        // Replace the state file atomically, so an interruption never leaves a partial one behind.
+        let _saving = self.saving.lock()?;
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut temp_file = std::fs::File::create(&temp_path)?;
+        temp_file.write_all(data.get_ref())?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+
+        log::trace!(
+            "Saved checkpoint {}: {} ranges copied.",
+            self.path.display(),
+            snapshot.completed.len()
+        );
+        Ok(())
+    }
+
+    /// Concludes the copy with its result: on success, removes the state file if all the chunks
+    /// were copied; Otherwise, persists the progress, so the copy may be resumed later.
+    ///
+    /// The data written to the destination must be durable before calling this.
+    pub fn conclude(&self, result: crate::Result<()>) -> crate::Result<()> {
+        let snapshot = self.snapshot(&mut *self.progress.lock()?);
+        if let Err(e) = result {
+            if let Err(save_error) = self.save(&snapshot) {
+                log::warn!(
+                    "Failed to save checkpoint {}: {save_error}",
+                    self.path.display()
+                );
+            }
+            return Err(e);
+        }
+
+        if self.bytes_completed() < self.identity.size {
+            return self.save(&snapshot);
+        }
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn snapshot(&self, progress: &mut CheckpointProgress) -> CheckpointFile {
+        let mut completed: Vec<CompletedRange> = Vec::new();
+        for (chunk, _) in progress.completed.iter().enumerate().filter(|(_, c)| **c) {
+            let start = chunk as u64 * self.chunk_size;
+            let end = (start + self.chunk_size).min(self.identity.size);
+            match completed.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => completed.push(CompletedRange { start, end }),
+            }
+        }
+        progress.last_saved = Instant::now();
+        CheckpointFile {
+            identity: self.identity,
+            destination_id: self.destination_id,
+            completed,
+        }
+    }
+}
+
+impl std::fmt::Debug for CopyCheckpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CopyCheckpoint")
+            .field("path", &self.path)
+            .field("identity", &self.identity)
+            .field("bytes_completed", &self.bytes_completed())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "std-fs-impls"))]
+mod tests {
+    use super::super::file_util::*;
+    use super::*;
+    use crate::sync_helpers::Mutex;
+    use crate::testing::TempDir;
+    #[cfg(not(feature = "async"))]
+    use std::fs;
+    #[cfg(feature = "async")]
+    use tokio::fs;
+
+    const CHUNK_SIZE: u64 = 2u64.pow(16);
+
+    #[maybe_async]
+    async fn copy(source: &Path, destination: &Path, checkpoint: &Path) -> crate::Result<()> {
+        let from = Mutex::new(fs::File::open(source).await?);
+        let to = Mutex::new(
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(destination)
+                .await?,
+        );
+        #[cfg(not(feature = "single_threaded"))]
+        return block_copy_checkpointed(from, to, 2, checkpoint).await;
+        #[cfg(feature = "single_threaded")]
+        return block_copy_checkpointed(from, to, checkpoint);
+    }
+
+    #[maybe_async]
+    async fn file_identity(path: &Path) -> FileIdentity {
+        Mutex::new(fs::File::open(path).await.unwrap())
+            .get_identity()
+            .await
+            .unwrap()
+    }
+
+    /// Saves the checkpoint of an interrupted copy to the destination, that completed the second chunk.
+    #[maybe_async]
+    async fn interrupt(checkpoint: &Path, source: FileIdentity, destination: &Path) {
+        let file = std::fs::File::create(destination).unwrap();
+        file.set_len(source.size).unwrap();
+        let destination = file_identity(destination).await;
+        let interrupted =
+            CopyCheckpoint::open(checkpoint, source, destination, CHUNK_SIZE).unwrap();
+        interrupted.complete(1).unwrap();
+        interrupted.conclude(Ok(())).unwrap();
+        assert!(checkpoint_matches(checkpoint, &source).unwrap());
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_checkpointed_copy() {
+        let dir = TempDir::new("smb-checkpoint");
+        std::fs::create_dir(&dir.0).unwrap();
+        let source = dir.0.join("source.img");
+        let destination = dir.0.join("destination.img");
+        let checkpoint = dir.0.join("destination.img.checkpoint");
+
+        let data = (0..3 * CHUNK_SIZE + 1000)
+            .map(|i| (i % 251) as u8 + 1)
+            .collect::<Vec<_>>();
+        std::fs::write(&source, &data).unwrap();
+        let identity = file_identity(&source).await;
+        assert_eq!(identity.size, data.len() as u64);
+
+        // An interrupted copy of the same source, that completed the second chunk:
+        // Only the other chunks are copied, and the checkpoint is removed.
+        interrupt(&checkpoint, identity, &destination).await;
+        copy(&source, &destination, &checkpoint).await.unwrap();
+        let copied = std::fs::read(&destination).unwrap();
+        let second_chunk = CHUNK_SIZE as usize..2 * CHUNK_SIZE as usize;
+        assert!(copied[second_chunk.clone()].iter().all(|&b| b == 0));
+        assert_eq!(copied[..second_chunk.start], data[..second_chunk.start]);
+        assert_eq!(copied[second_chunk.end..], data[second_chunk.end..]);
+        assert!(!checkpoint.exists());
+
+        // A checkpoint of a different source is ignored, and the whole source is copied.
+        let other_source = FileIdentity {
+            size: identity.size + 1,
+            ..identity
+        };
+        let destination_identity = file_identity(&destination).await;
+        let stale =
+            CopyCheckpoint::open(&checkpoint, other_source, destination_identity, CHUNK_SIZE)
+                .unwrap();
+        stale.complete(1).unwrap();
+        stale.conclude(Ok(())).unwrap();
+        assert!(checkpoint.exists());
+        assert!(!checkpoint_matches(&checkpoint, &identity).unwrap());
+
+        copy(&source, &destination, &checkpoint).await.unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        assert!(!checkpoint.exists());
+
+        // A checkpoint of a truncated destination is ignored.
+        interrupt(&checkpoint, identity, &destination).await;
+        std::fs::File::create(&destination).unwrap();
+        copy(&source, &destination, &checkpoint).await.unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        assert!(!checkpoint.exists());
+
+        // A checkpoint of a destination that was replaced since is ignored, where file IDs are available.
+        #[cfg(unix)]
+        {
+            interrupt(&checkpoint, identity, &destination).await;
+            let replacement = dir.0.join("replacement.img");
+            std::fs::File::create(&replacement)
+                .unwrap()
+                .set_len(identity.size)
+                .unwrap();
+            std::fs::rename(&replacement, &destination).unwrap();
+            copy(&source, &destination, &checkpoint).await.unwrap();
+            assert_eq!(std::fs::read(&destination).unwrap(), data);
+            assert!(!checkpoint.exists());
+        }
+    }
+}
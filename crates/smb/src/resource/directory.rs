@@ -1,6 +1,7 @@
 use super::ResourceHandle;
 use crate::Error;
-use crate::msg_handler::{MessageHandler, ReceiveOptions};
+use crate::msg_handler::{OutgoingMessage, ReceiveOptions};
+use crate::op_options::{self, OpOptions};
 use crate::sync_helpers::*;
 use maybe_async::*;
 use smb_fscc::*;
@@ -210,6 +211,27 @@ impl Directory {
         iter_sync::QueryDirectoryIterator::new(self, pattern.to_string(), buffer_size)
     }
 
+    /// Queries all the directory contents matching the pattern, as [`Directory::query`] does,
+    /// and collects them into a vector.
+    #[cfg(feature = "async")]
+    pub async fn query_all<T>(this: &Arc<Self>, pattern: &str) -> crate::Result<Vec<T>>
+    where
+        T: QueryDirectoryInfoValue + for<'b> binrw::prelude::BinWrite<Args<'b> = ()> + Unpin + Send,
+    {
+        use futures_util::TryStreamExt;
+        Self::query::<T>(this, pattern).await?.try_collect().await
+    }
+
+    /// Queries all the directory contents matching the pattern, as [`Directory::query`] does,
+    /// and collects them into a vector.
+    #[cfg(not(feature = "async"))]
+    pub fn query_all<T>(this: &Arc<Self>, pattern: &str) -> crate::Result<Vec<T>>
+    where
+        T: QueryDirectoryInfoValue,
+    {
+        Self::query::<T>(this, pattern)?.collect()
+    }
+
     /// Watches the directory for changes.
     /// # Arguments
     /// * `filter` - The filter to use for the changes. This is a bitmask of the changes to watch for.
@@ -246,11 +268,45 @@ impl Directory {
             filter,
             recursive,
             ReceiveOptions::new().with_timeout(timeout),
+            &OpOptions::default(),
         )
         .await
         .into()
     }
 
+    /// Watches the directory for changes, bound by the given per-operation options.
+    /// # Arguments
+    /// * `filter` - The filter to use for the changes. This is a bitmask of the changes to watch for.
+    /// * `recursive` - Whether to watch the directory recursively or not.
+    /// * `op` - The options of the operation: once its deadline passes, or it is cancelled,
+    ///   the watch is cancelled on the server, and an error is returned.
+    /// # Returns
+    /// * A vector of [`FileNotifyInformation`] objects, containing the changes that occurred.
+    pub async fn watch_with(
+        &self,
+        filter: NotifyFilter,
+        recursive: bool,
+        op: &OpOptions,
+    ) -> crate::Result<Vec<FileNotifyInformation>> {
+        // The watch is bound by the deadline of the operation only.
+        let timeout = op.remaining().unwrap_or(Duration::MAX);
+        match self
+            ._watch_options(
+                filter,
+                recursive,
+                ReceiveOptions::new().with_timeout(timeout),
+                op,
+            )
+            .await
+        {
+            // Cancelled once the deadline passed.
+            DirectoryWatchResult::Cancelled if op.remaining() == Some(Duration::ZERO) => Err(
+                Error::OperationTimeout(crate::error::TimedOutTask::Operation, timeout),
+            ),
+            result => result.into(),
+        }
+    }
+
     #[cfg(feature = "async")]
     /// Watches the directory for changes, returning a [`Stream`][`futures_core::Stream`] of notifications.
     ///
@@ -303,6 +359,7 @@ impl Directory {
 
             let directory = this.clone();
             async move {
+                let op = OpOptions::default();
                 loop {
                     select! {
                         _ = watch_tx.closed() => {
@@ -310,7 +367,7 @@ impl Directory {
                             break;
                         }
                         result = directory
-                            ._watch_options(filter, recursive, receive_options.clone())
+                            ._watch_options(filter, recursive, receive_options.clone(), &op)
                             =>  {
                             let should_stop = matches!(result, DirectoryWatchResult::Cancelled | DirectoryWatchResult::Cleanup);
                             if watch_tx.send(result).await.is_err() {
@@ -424,7 +481,12 @@ impl Directory {
         // Simply watch in loop and chain the results.
         let veci = std::iter::from_fn(move || {
             match this
-                ._watch_options(filter, recursive, ReceiveOptions::default())
+                ._watch_options(
+                    filter,
+                    recursive,
+                    ReceiveOptions::default(),
+                    &OpOptions::default(),
+                )
                 .into()
             {
                 Ok(result) => Some(Ok(result)),
@@ -446,11 +508,14 @@ impl Directory {
     /// * `timeout` - to set the timeout for the receive operation.
     /// * `async_msg_ids` - to allow async notifications.
     /// * `async_cancel` - to allow cancellation of the receive operation, when crate feature `async` is enabled.
-    async fn _watch_options(
+    ///
+    /// The per-operation options may cancel the watch, and set the priority of the request.
+    pub(crate) async fn _watch_options(
         &self,
         filter: NotifyFilter,
         recursive: bool,
         options: ReceiveOptions<'_>,
+        op: &OpOptions,
     ) -> DirectoryWatchResult {
         if !self.access.list_directory() {
             return DirectoryWatchResult::Error(Error::MissingPermissions(
@@ -464,10 +529,9 @@ impl Directory {
             Err(e) => return DirectoryWatchResult::Error(e),
         };
 
-        let response = self
-            .handle
-            .handler
-            .send_recvo(
+        let response = op_options::sendo_recvo(
+            &*self.handle.handler,
+            OutgoingMessage::new(
                 ChangeNotifyRequest {
                     file_id,
                     flags: NotifyFlags::new().with_watch_tree(recursive),
@@ -475,23 +539,25 @@ impl Directory {
                     output_buffer_length,
                 }
                 .into(),
-                ReceiveOptions {
-                    allow_async: true,
-                    #[cfg(feature = "async")]
-                    async_cancel: options.async_cancel,
-                    async_msg_ids: options.async_msg_ids,
-                    timeout: options.timeout,
-                    cmd: Some(Command::ChangeNotify),
-                    status: &[
-                        Status::Success,
-                        Status::Cancelled,
-                        Status::NotifyCleanup,
-                        Status::NotifyEnumDir,
-                    ],
-                    ..Default::default()
-                },
-            )
-            .await;
+            ),
+            ReceiveOptions {
+                allow_async: true,
+                #[cfg(feature = "async")]
+                async_cancel: options.async_cancel,
+                async_msg_ids: options.async_msg_ids,
+                timeout: options.timeout,
+                cmd: Some(Command::ChangeNotify),
+                status: &[
+                    Status::Success,
+                    Status::Cancelled,
+                    Status::NotifyCleanup,
+                    Status::NotifyEnumDir,
+                ],
+                ..Default::default()
+            },
+            op,
+        )
+        .await;
 
         let response = match response {
             Ok(res) => match res.message.header.status {
@@ -566,6 +632,7 @@ impl Directory {
                 },
                 output_buffer_length,
                 std::any::type_name::<FileQuotaInformation>(),
+                &OpOptions::default(),
             )
             .await?
             .as_quota()?
@@ -582,6 +649,7 @@ impl Directory {
                 info,
                 SetInfoClass::Quota(Default::default()),
                 Default::default(),
+                &OpOptions::default(),
             )
             .await
     }
@@ -746,7 +814,9 @@ pub mod iter_stream {
             match this.receiver.poll_recv(cx) {
                 Poll::Ready(Some(value)) => {
                     if this.receiver.is_empty() {
-                        this.notify_fetch_next.notify_waiters() // Notify that batch is done
+                        // Notify that batch is done. A permit is stored, in case the fetch loop
+                        // is not waiting yet, so the notification is not lost.
+                        this.notify_fetch_next.notify_one()
                     }
                     Poll::Ready(Some(value))
                 }
@@ -961,6 +1031,7 @@ mod iter_mtd {
                             notify_filter,
                             recursive,
                             receive_options.clone(),
+                            &OpOptions::default(),
                         );
                         receive_options.async_msg_ids.as_ref().unwrap().reset();
 
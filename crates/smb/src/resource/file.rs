@@ -54,12 +54,36 @@ impl File {
     /// * `unbuffered` - Whether to try using unbuffered I/O (if supported by the server).
     /// # Returns
     /// The number of bytes read, up to `buf.len()`.
+    #[maybe_async]
+    #[inline]
     pub async fn read_block(
         &self,
         buf: &mut [u8],
         pos: u64,
         channel: Option<u32>,
         unbuffered: bool,
+    ) -> std::io::Result<usize> {
+        self.read_block_with(buf, pos, channel, unbuffered, &OpOptions::default())
+            .await
+    }
+
+    /// Read a block of data from an opened file, as [`File::read_block`] does,
+    /// bound by the given per-operation options.
+    ///
+    /// Use this, for example, to give interactive reads a higher priority than bulk transfers,
+    /// or to cancel a read that is no longer needed.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "smb.read",
+        skip_all,
+        fields(path = self.handle.name(), pos, len = buf.len())
+    ))]
+    pub async fn read_block_with(
+        &self,
+        buf: &mut [u8],
+        pos: u64,
+        channel: Option<u32>,
+        unbuffered: bool,
+        op: &OpOptions,
     ) -> std::io::Result<usize> {
         if buf.is_empty() {
             return Ok(0);
@@ -84,12 +108,8 @@ impl File {
             self.handle.name()
         );
 
+        // Whether to request a compressed response is decided by the compression policy, when sending.
         let mut flags = ReadFlags::new();
-        if self.handle.conn_info.config.compression_enabled
-            && self.handle.conn_info.dialect.supports_compression()
-        {
-            flags.set_read_compressed(true);
-        }
 
         if unbuffered && self.handle.conn_info.negotiation.dialect_rev >= Dialect::Smb0302 {
             flags.set_read_unbuffered(true);
@@ -109,7 +129,7 @@ impl File {
 
         let response = self
             .handle
-            .sendo_recvo(request, ReceiveOptions::new().with_allow_async(true))
+            .sendo_recvo_with(request, ReceiveOptions::new().with_allow_async(true), op)
             .await
             .map_err(|e| std::io::Error::other(e.to_string()))?;
         let content = response
@@ -150,17 +170,51 @@ impl File {
         self.write_block_zc(buf.into(), pos, channel).await
     }
 
+    /// Write a block of data to an opened file, as [`File::write_block`] does,
+    /// bound by the given per-operation options.
+    #[maybe_async]
+    #[inline]
+    pub async fn write_block_with(
+        &self,
+        buf: &[u8],
+        pos: u64,
+        channel: Option<u32>,
+        op: &OpOptions,
+    ) -> std::io::Result<usize> {
+        self.write_block_zc_with(buf.into(), pos, channel, op).await
+    }
+
     /// Write a block of data to an opened file, without copying the data.
     /// # Arguments
     /// * `buf` - The data to write.
     /// * `pos` - The offset in the file to write to.
     /// # Returns
     /// The number of bytes written.
+    #[maybe_async]
+    #[inline]
     pub async fn write_block_zc(
         &self,
         buf: Arc<[u8]>,
         pos: u64,
         channel: Option<u32>,
+    ) -> std::io::Result<usize> {
+        self.write_block_zc_with(buf, pos, channel, &OpOptions::default())
+            .await
+    }
+
+    /// Write a block of data to an opened file, without copying the data,
+    /// bound by the given per-operation options.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "smb.write",
+        skip_all,
+        fields(path = self.handle.name(), pos, len = buf.len())
+    ))]
+    pub async fn write_block_zc_with(
+        &self,
+        buf: Arc<[u8]>,
+        pos: u64,
+        channel: Option<u32>,
+        op: &OpOptions,
     ) -> std::io::Result<usize> {
         if buf.is_empty() {
             return Ok(0);
@@ -196,7 +250,7 @@ impl File {
 
         let response = self
             .handle
-            .sendo_recvo(outgoing, ReceiveOptions::new().with_allow_async(true))
+            .sendo_recvo_with(outgoing, ReceiveOptions::new().with_allow_async(true), op)
             .await
             .map_err(|e| std::io::Error::other(e.to_string()))?;
 
@@ -206,6 +260,7 @@ impl File {
             .to_write()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
         let actual_written_length = content.count as usize;
+        self.handle.mark_modified();
         log::debug!(
             "Wrote {} bytes to {}.",
             actual_written_length,
@@ -215,15 +270,27 @@ impl File {
     }
 
     /// Sends a flush request to the server to flush the file.
+    #[maybe_async]
+    #[inline]
     pub async fn flush(&self) -> std::io::Result<()> {
+        self.flush_with(&OpOptions::default()).await
+    }
+
+    /// Sends a flush request to the server to flush the file,
+    /// bound by the given per-operation options.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smb.flush", skip_all, fields(path = self.handle.name())))]
+    pub async fn flush_with(&self, op: &OpOptions) -> std::io::Result<()> {
         let _response = self
             .handle
-            .send_recvo(
-                FlushRequest {
-                    file_id: self.handle.file_id().map_err(std::io::Error::other)?,
-                }
-                .into(),
+            .sendo_recvo_with(
+                OutgoingMessage::new(
+                    FlushRequest {
+                        file_id: self.handle.file_id().map_err(std::io::Error::other)?,
+                    }
+                    .into(),
+                ),
                 ReceiveOptions::new().with_allow_async(true),
+                op,
             )
             .await
             .map_err(|e| std::io::Error::other(e.to_string()))?;
@@ -404,6 +471,13 @@ impl SetLen for File {
     }
 }
 
+impl SyncAll for File {
+    #[maybe_async]
+    async fn sync_all(&self) -> crate::Result<()> {
+        Ok(self.flush().await?)
+    }
+}
+
 impl Deref for File {
     type Target = ResourceHandle;
 
@@ -128,6 +128,82 @@ pub trait SetLen {
     async fn set_len(&self, len: u64) -> crate::Result<()>;
 }
 
+impl<T: ReadAtChannel + Sync + ?Sized> ReadAtChannel for Arc<T> {
+    #[cfg(feature = "async")]
+    fn read_at_channel(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        channel: Option<u32>,
+    ) -> impl std::future::Future<Output = crate::Result<usize>> + std::marker::Send {
+        (**self).read_at_channel(buf, offset, channel)
+    }
+    #[cfg(not(feature = "async"))]
+    fn read_at_channel(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        channel: Option<u32>,
+    ) -> crate::Result<usize> {
+        (**self).read_at_channel(buf, offset, channel)
+    }
+}
+
+impl<T: WriteAtChannel + Sync + ?Sized> WriteAtChannel for Arc<T> {
+    #[cfg(feature = "async")]
+    fn write_at_channel(
+        &self,
+        buf: &[u8],
+        offset: u64,
+        channel: Option<u32>,
+    ) -> impl std::future::Future<Output = crate::Result<usize>> + std::marker::Send {
+        (**self).write_at_channel(buf, offset, channel)
+    }
+    #[cfg(not(feature = "async"))]
+    fn write_at_channel(
+        &self,
+        buf: &[u8],
+        offset: u64,
+        channel: Option<u32>,
+    ) -> crate::Result<usize> {
+        (**self).write_at_channel(buf, offset, channel)
+    }
+}
+
+impl<T: GetLen + ?Sized> GetLen for Arc<T> {
+    #[maybe_async]
+    async fn get_len(&self) -> crate::Result<u64> {
+        (**self).get_len().await
+    }
+}
+
+impl<T: SetLen + ?Sized> SetLen for Arc<T> {
+    #[maybe_async]
+    async fn set_len(&self, len: u64) -> crate::Result<()> {
+        (**self).set_len(len).await
+    }
+}
+
+/// Trait for copy destinations that can make the data written to them durable,
+/// so a checkpoint never records data that might still be lost as copied.
+pub trait SyncAll {
+    #[cfg(feature = "async")]
+    fn sync_all(&self) -> impl std::future::Future<Output = crate::Result<()>> + std::marker::Send;
+    #[cfg(not(feature = "async"))]
+    fn sync_all(&self) -> crate::Result<()>;
+}
+
+impl<T: SyncAll + Sync + ?Sized> SyncAll for Arc<T> {
+    #[cfg(feature = "async")]
+    fn sync_all(&self) -> impl std::future::Future<Output = crate::Result<()>> + std::marker::Send {
+        (**self).sync_all()
+    }
+    #[cfg(not(feature = "async"))]
+    fn sync_all(&self) -> crate::Result<()> {
+        (**self).sync_all()
+    }
+}
+
 #[cfg(feature = "std-fs-impls")]
 mod impls {
     use super::*;
@@ -183,7 +259,10 @@ mod impls {
                 .await
                 .map_err(|e| std::io::Error::other(e.to_string()))?;
             writer.seek(std::io::SeekFrom::Start(offset)).await?;
-            Ok(writer.write(buf).await?)
+            let written = writer.write(buf).await?;
+            // Buffered writers (e.g. tokio's) complete the write in the background otherwise.
+            writer.flush().await?;
+            Ok(written)
         }
     }
 
@@ -208,6 +287,17 @@ mod impls {
             Ok(File::set_len(&file, len).await?)
         }
     }
+
+    impl SyncAll for Mutex<File> {
+        #[maybe_async]
+        async fn sync_all(&self) -> crate::Result<()> {
+            let file = self
+                .lock()
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            Ok(File::sync_all(&file).await?)
+        }
+    }
 }
 
 #[cfg(feature = "std-fs-impls")]
@@ -217,8 +307,10 @@ pub use impls::*;
 mod copy {
     use super::*;
 
+    use super::super::checkpoint::{CopyCheckpoint, GetIdentity};
     use std::{
         collections::HashMap,
+        path::Path,
         sync::{Arc, atomic::AtomicU64},
     };
 
@@ -231,6 +323,8 @@ mod copy {
 
         max_chunk_size: u64,
         channel_jobs: HashMap<Option<u32>, usize>,
+
+        checkpoint: Option<CopyCheckpoint>,
     }
 
     impl CopyState {
@@ -241,6 +335,9 @@ mod copy {
 
         /// Returns the number of bytes copied so far.
         pub fn bytes_copied(&self) -> u64 {
+            if let Some(checkpoint) = &self.checkpoint {
+                return checkpoint.bytes_completed();
+            }
             let current_block = self.current_block.load(std::sync::atomic::Ordering::SeqCst);
             if current_block > self.last_block {
                 self.total_size
@@ -268,7 +365,7 @@ mod copy {
     ///
     /// # Parameters
     /// - `from`: The source to read from. Must implement `ReadAtChannel` and `GetLen`.
-    /// - `to`: The destination to write to. Must implement `WriteAtChannel` and `SetLen`.
+    /// - `to`: The destination to write to. Must implement `WriteAtChannel`, `SetLen` and `SyncAll`.
     /// - `jobs`: The number of parallel jobs to use. If 0, a default value will be used.
     ///
     /// # Returns
@@ -283,7 +380,7 @@ mod copy {
     #[maybe_async]
     pub async fn block_copy<
         F: ReadAtChannel + GetLen + Send + Sync + 'static,
-        T: WriteAtChannel + SetLen + Send + Sync + 'static,
+        T: WriteAtChannel + SetLen + SyncAll + Send + Sync + 'static,
     >(
         from: F,
         to: T,
@@ -301,7 +398,7 @@ mod copy {
     ///
     /// # Parameters
     /// - `from`: The source to read from. Must implement `ReadAtChannel` and `GetLen`.
-    /// - `to`: The destination to write to. Must implement `WriteAtChannel` and `SetLen`.
+    /// - `to`: The destination to write to. Must implement `WriteAtChannel`, `SetLen` and `SyncAll`.
     /// - `channel_jobs`: A map of channel IDs to the number of jobs to use for each channel.
     ///
     /// # Returns
@@ -314,7 +411,7 @@ mod copy {
     #[maybe_async]
     pub async fn block_copy_channel<
         F: ReadAtChannel + GetLen + Send + Sync + 'static,
-        T: WriteAtChannel + SetLen + Send + Sync + 'static,
+        T: WriteAtChannel + SetLen + SyncAll + Send + Sync + 'static,
     >(
         from: F,
         to: T,
@@ -397,6 +494,7 @@ mod copy {
                 total_size: 0,
                 max_chunk_size: CHUNK_SIZE,
                 channel_jobs,
+                checkpoint: None,
             });
         }
 
@@ -406,16 +504,81 @@ mod copy {
             total_size: file_length,
             max_chunk_size: CHUNK_SIZE,
             channel_jobs,
+            checkpoint: None,
         })
     }
 
+    /// Generic block copy function, that may resume an interrupted copy.
+    ///
+    /// Same as [`block_copy`], but the progress of the copy is persisted to the `checkpoint`
+    /// state file. See [`prepare_checkpointed_copy`] for more details.
+    #[maybe_async]
+    pub async fn block_copy_checkpointed<
+        F: ReadAtChannel + GetLen + GetIdentity + Send + Sync + 'static,
+        T: WriteAtChannel + SetLen + SyncAll + GetIdentity + Send + Sync + 'static,
+    >(
+        from: F,
+        to: T,
+        jobs: usize,
+        checkpoint: &Path,
+    ) -> crate::Result<()> {
+        let copy_state =
+            prepare_checkpointed_copy(&from, &to, HashMap::from([(None, jobs)]), checkpoint)
+                .await?;
+
+        log::debug!("Starting checkpointed parallel copy: {copy_state:?}",);
+        start_parallel_copy(from, to, Arc::new(copy_state)).await?;
+
+        Ok(())
+    }
+
+    /// Returns a CopyState that can be used to start a checkpointed parallel copy.
+    ///
+    /// Same as [`prepare_parallel_copy`], but the ranges copied by [`start_parallel_copy`] are
+    /// periodically persisted to the `checkpoint` state file, as well as when the copy fails.
+    /// If the state file exists, and was saved while copying the same, unchanged source
+    /// (same file ID, size and change time) to the same destination (same file ID, when available),
+    /// which still covers the ranges copied, only the ranges missing from it are copied.
+    /// The state file is removed once the copy completes.
+    ///
+    /// # Notes
+    /// - The contents of the destination must not be modified between an interrupted copy and
+    ///   its resumption, since the ranges that were already copied are not verified.
+    #[maybe_async]
+    pub async fn prepare_checkpointed_copy<
+        F: ReadAtChannel + GetLen + GetIdentity + Send + Sync + 'static,
+        T: WriteAtChannel + SetLen + GetIdentity + Send + Sync + 'static,
+    >(
+        from: &F,
+        to: &T,
+        channel_jobs: HashMap<Option<u32>, usize>,
+        checkpoint: &Path,
+    ) -> crate::Result<CopyState> {
+        let identity = from.get_identity().await?;
+        // Identified before it is resized, so a truncated destination is told apart.
+        let destination = to.get_identity().await?;
+        let mut copy_state = prepare_parallel_copy(from, to, channel_jobs).await?;
+        if identity.size != copy_state.total_size {
+            return Err(crate::Error::InvalidState(
+                "Source size changed while preparing the copy".to_string(),
+            ));
+        }
+        copy_state.checkpoint = Some(CopyCheckpoint::open(
+            checkpoint,
+            identity,
+            destination,
+            copy_state.max_chunk_size,
+        )?);
+        Ok(copy_state)
+    }
+
     /// Starts a parallel copy using the provided [`CopyState`].
     ///
     /// See [`prepare_parallel_copy`] for more details.
     #[cfg(feature = "async")]
     pub async fn start_parallel_copy<
         F: ReadAtChannel + GetLen + Send + Sync + 'static,
-        T: WriteAtChannel + SetLen + Send + Sync + 'static,
+        T: WriteAtChannel + SetLen + SyncAll + Send + Sync + 'static,
     >(
         from: F,
         to: T,
@@ -440,8 +603,8 @@ mod copy {
             }
         }
 
-        handles.join_all().await;
-        Ok(())
+        let result = handles.join_all().await.into_iter().collect();
+        conclude_copy(&state, &*to, result).await
     }
 
     /// Starts a parallel copy using the provided [`CopyState`].
@@ -450,7 +613,7 @@ mod copy {
     #[cfg(feature = "multi_threaded")]
     pub fn start_parallel_copy<
         F: ReadAtChannel + GetLen + Send + Sync + 'static,
-        T: WriteAtChannel + SetLen + Send + Sync + 'static,
+        T: WriteAtChannel + SetLen + SyncAll + Send + Sync + 'static,
     >(
         from: F,
         to: T,
@@ -472,17 +635,40 @@ mod copy {
             }
         }
 
+        // Join all the tasks, even if one fails, so the checkpoint (if any) covers their progress.
+        let mut result = Ok(());
         for handle in handles {
-            handle.join().unwrap()?;
+            let task_result = handle.join().unwrap();
+            if result.is_ok() {
+                result = task_result;
+            }
         }
+        conclude_copy(&state, &*to, result)
+    }
 
-        Ok(())
+    /// Concludes the checkpoint of the copy, if any, with the result of the copy tasks.
+    ///
+    /// The destination is synced first, so the checkpoint never covers data that was not persisted.
+    #[maybe_async]
+    async fn conclude_copy<T: SyncAll>(
+        state: &CopyState,
+        to: &T,
+        result: crate::Result<()>,
+    ) -> crate::Result<()> {
+        let Some(checkpoint) = &state.checkpoint else {
+            return result;
+        };
+        if let Err(e) = to.sync_all().await {
+            log::warn!("Failed to sync the destination, keeping the previous checkpoint: {e}");
+            return result.and(Err(e));
+        }
+        checkpoint.conclude(result)
     }
 
     #[maybe_async]
     async fn block_copy_task<
         F: ReadAtChannel + GetLen + Send + Sync,
-        T: WriteAtChannel + SetLen + Send + Sync,
+        T: WriteAtChannel + SetLen + SyncAll + Send + Sync,
     >(
         from: Arc<F>,
         to: Arc<T>,
@@ -511,6 +697,12 @@ mod copy {
                 state.max_chunk_size
             } as usize;
 
+            if let Some(checkpoint) = &state.checkpoint
+                && checkpoint.is_completed(current_block)?
+            {
+                continue;
+            }
+
             let offset = current_block * state.max_chunk_size;
             let bytes_read = from
                 .read_at_channel(&mut curr_chunk[..chunk_size], offset, channel_id)
@@ -523,6 +715,12 @@ mod copy {
             let valid_chunk_end = bytes_read;
             to.write_at_channel(&curr_chunk[..valid_chunk_end], offset, channel_id)
                 .await?;
+            if let Some(checkpoint) = &state.checkpoint
+                && let Some(snapshot) = checkpoint.complete(current_block)?
+            {
+                to.sync_all().await?;
+                checkpoint.save(&snapshot)?;
+            }
         }
         log::debug!("Copy task {task_id}@{channel_id:?} completed",);
         Ok(())
@@ -533,6 +731,9 @@ mod copy {
 mod copy {
     use super::*;
 
+    use super::super::checkpoint::{CopyCheckpoint, GetIdentity};
+    use std::path::Path;
+
     /// Generic block copy function.
     pub fn block_copy<F: ReadAtChannel + GetLen, T: WriteAtChannel + SetLen>(
         from: F,
@@ -599,6 +800,63 @@ mod copy {
         }
         Ok(())
     }
+
+    /// Generic block copy function, that may resume an interrupted copy.
+    ///
+    /// The ranges copied are periodically persisted to the `checkpoint` state file, as well as
+    /// when the copy fails. If the state file exists, and was saved while copying the same,
+    /// unchanged source (same file ID, size and change time) to the same destination (same file ID,
+    /// when available), which still covers the ranges copied, only the ranges missing from it
+    /// are copied. The state file is removed once the copy completes.
+    ///
+    /// # Notes
+    /// - The contents of the destination must not be modified between an interrupted copy and
+    ///   its resumption, since the ranges that were already copied are not verified.
+    pub fn block_copy_checkpointed<
+        F: ReadAtChannel + GetLen + GetIdentity,
+        T: WriteAtChannel + SetLen + SyncAll + GetIdentity,
+    >(
+        from: F,
+        to: T,
+        checkpoint: &Path,
+    ) -> crate::Result<()> {
+        const CHUNK_SIZE: u64 = 2u64.pow(16);
+
+        let identity = from.get_identity()?;
+        // Identified before it is resized, so a truncated destination is told apart.
+        let destination = to.get_identity()?;
+        let checkpoint = CopyCheckpoint::open(checkpoint, identity, destination, CHUNK_SIZE)?;
+        to.set_len(identity.size)?;
+
+        let mut curr_chunk = vec![0u8; CHUNK_SIZE as usize];
+        let mut copy_missing = || {
+            for chunk in 0..identity.size.div_ceil(CHUNK_SIZE) {
+                if checkpoint.is_completed(chunk)? {
+                    continue;
+                }
+                let offset = chunk * CHUNK_SIZE;
+                let chunk_size = (identity.size - offset).min(CHUNK_SIZE) as usize;
+                let bytes_read = from.read_at(&mut curr_chunk[..chunk_size], offset)?;
+                if bytes_read < chunk_size {
+                    log::warn!(
+                        "Read less bytes than expected. File might be corrupt. Expected: {chunk_size}: {bytes_read}"
+                    );
+                }
+                to.write_at(&curr_chunk[..bytes_read], offset)?;
+                if let Some(snapshot) = checkpoint.complete(chunk)? {
+                    to.sync_all()?;
+                    checkpoint.save(&snapshot)?;
+                }
+            }
+            Ok(())
+        };
+        let result = copy_missing();
+        if let Err(e) = to.sync_all() {
+            log::warn!("Failed to sync the destination, keeping the previous checkpoint: {e}");
+            return result.and(Err(e));
+        }
+        checkpoint.conclude(result)
+    }
 }
 
 pub use copy::*;
@@ -0,0 +1,657 @@
+//! Deferred close and reuse of opened file handles.
+//!
+//! See [`HandleCachingConfig`] for more information.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use maybe_async::maybe_async;
+use smb_dtyp::Guid;
+use smb_fscc::FileAccessMask;
+use smb_msg::*;
+use time::PrimitiveDateTime;
+
+use crate::{
+    FileCreateArgs,
+    connection::{HandleCachingConfig, connection_info::ConnectionInfo},
+    msg_handler::{HandlerReference, MessageHandler},
+    sync_helpers::*,
+    tree::TreeMessageHandler,
+};
+
+type Upstream = HandlerReference<TreeMessageHandler>;
+
+/// A lease held by the client for a single path in a tree.
+///
+/// All the opens of the same path share the same lease key, just like the Windows redirector does,
+/// so the server never breaks the lease due to the client's own opens.
+pub(crate) struct Lease {
+    key: u128,
+    state: AtomicU32,
+    /// The number of handles opened with this lease that are still open on the server
+    /// (including idle handles), and of opens that are being requested with it.
+    /// Only updated while holding the lock of the [`HandleCache`] state.
+    opens: AtomicUsize,
+    /// Incremented whenever the file is modified through any open that holds this lease.
+    /// Cached metadata (such as the end of file) observed at an earlier generation is stale.
+    generation: AtomicU64,
+}
+
+impl Lease {
+    fn new() -> Self {
+        Self {
+            key: Guid::generate().as_u128(),
+            state: AtomicU32::new(0),
+            opens: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> LeaseState {
+        LeaseState::from_bytes(self.state.load(Ordering::SeqCst).to_le_bytes())
+    }
+
+    fn set_state(&self, state: LeaseState) {
+        self.state
+            .store(u32::from_le_bytes(state.into_bytes()), Ordering::SeqCst);
+    }
+
+    pub fn mark_modified(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Returns the lease request create context for this lease, according to the dialect.
+    pub fn make_context(&self, dialect: Dialect) -> CreateContextRequest {
+        let lease_state = LeaseState::new()
+            .with_read_caching(true)
+            .with_handle_caching(true);
+        let request = if dialect >= Dialect::Smb030 {
+            RequestLease::RqLsReqv2(RequestLeaseV2 {
+                lease_key: self.key,
+                lease_state,
+                lease_flags: LeaseFlags::new(),
+                parent_lease_key: 0,
+                epoch: 0,
+            })
+        } else {
+            RequestLease::RqLsReqv1(RequestLeaseV1 {
+                lease_key: self.key,
+                lease_state,
+            })
+        };
+        request.into()
+    }
+
+    /// Updates the lease state from the lease create context in the create response, if any.
+    pub fn granted(&self, contexts: &[CreateContextResponse]) {
+        let state = match CreateContextResponseData::first_rqls(contexts) {
+            Some(RequestLease::RqLsReqv1(v1)) => v1.lease_state,
+            Some(RequestLease::RqLsReqv2(v2)) => v2.lease_state,
+            None => LeaseState::new(),
+        };
+        log::trace!("Lease {:x} granted with state {state:?}", self.key);
+        self.set_state(state);
+    }
+}
+
+/// The caching-related properties of an opened handle, that holds a lease.
+#[derive(Clone)]
+pub(crate) struct HandleLease {
+    pub lease: Arc<Lease>,
+    pub desired_access: FileAccessMask,
+    pub share_access: ShareAccessFlags,
+    pub options: CreateOptions,
+    pub end_of_file: u64,
+    /// The [generation][Lease::generation] of the lease when `end_of_file` was observed.
+    pub generation: u64,
+}
+
+/// A handle that was closed by the user, but is kept open on the server.
+pub(crate) struct IdleHandle {
+    pub file_id: FileId,
+    pub access: FileAccessMask,
+    pub created: PrimitiveDateTime,
+    pub modified: PrimitiveDateTime,
+    pub lease: HandleLease,
+}
+
+impl IdleHandle {
+    /// Returns whether this handle can serve an open of the same path with the specified arguments.
+    fn matches(&self, args: &FileCreateArgs, share_access: ShareAccessFlags) -> bool {
+        let requested = u32::from_le_bytes(args.desired_access.into_bytes());
+        let granted = u32::from_le_bytes(self.lease.desired_access.into_bytes());
+        matches!(
+            args.disposition,
+            CreateDisposition::Open | CreateDisposition::OpenIf
+        ) && args.options == self.lease.options
+            && share_access == self.lease.share_access
+            && requested & !granted == 0
+    }
+}
+
+#[derive(Default)]
+struct HandleCacheState {
+    /// Path => the lease used for opening the path.
+    leases: HashMap<String, Arc<Lease>>,
+    /// Idle handles, ordered from the oldest to the newest.
+    idle: Vec<(String, Instant, IdleHandle)>,
+}
+
+/// Holds the idle handles of a single tree, along with the leases used to open its files.
+pub(crate) struct HandleCache {
+    /// Set only if handle caching is enabled, and supported for the tree.
+    config: Option<HandleCachingConfig>,
+    router: Arc<LeaseRouter>,
+    state: Mutex<HandleCacheState>,
+}
+
+#[maybe_async(AFIT)]
+impl HandleCache {
+    pub fn new(
+        conn_info: &ConnectionInfo,
+        share_type: ShareType,
+        router: Arc<LeaseRouter>,
+    ) -> Self {
+        let supported = conn_info.negotiation.dialect_rev >= Dialect::Smb021
+            && conn_info.negotiation.caps.leasing()
+            && share_type == ShareType::Disk;
+        Self {
+            config: conn_info
+                .config
+                .handle_caching
+                .clone()
+                .filter(|_| supported),
+            router,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns a lease to request when opening the specified path, if handle caching applies to the open.
+    ///
+    /// The open is counted as an open of the lease; if the lease is not granted, or the open fails,
+    /// it must be given back using [`HandleCache::release_lease`].
+    pub async fn lease_for(
+        &self,
+        tree: &Upstream,
+        name: &str,
+        args: &FileCreateArgs,
+    ) -> crate::Result<Option<Arc<Lease>>> {
+        if self.config.is_none() || args.options.directory_file() || args.options.delete_on_close()
+        {
+            return Ok(None);
+        }
+
+        let (lease, is_new) = {
+            let mut state = self.state.lock().await?;
+            let (lease, is_new) = match state.leases.get(name) {
+                Some(lease) => (lease.clone(), false),
+                None => {
+                    let lease = Arc::new(Lease::new());
+                    state.leases.insert(name.to_string(), lease.clone());
+                    (lease, true)
+                }
+            };
+            lease.opens.fetch_add(1, Ordering::SeqCst);
+            (lease, is_new)
+        };
+        if is_new {
+            self.router.register(lease.key, tree.weak()).await?;
+        }
+        Ok(Some(lease))
+    }
+
+    /// Gives back an open of a lease, once its handle is being closed on the server,
+    /// or if the open was not granted the lease.
+    ///
+    /// The lease is forgotten once it has no more opens.
+    pub async fn release_lease(&self, lease: &Arc<Lease>) -> crate::Result<()> {
+        let forgotten = {
+            let mut state = self.state.lock().await?;
+            if lease.opens.fetch_sub(1, Ordering::SeqCst) > 1 {
+                return Ok(());
+            }
+            let count = state.leases.len();
+            state.leases.retain(|_, l| !Arc::ptr_eq(l, lease));
+            state.leases.len() < count
+        };
+        if forgotten {
+            log::trace!("Lease {:x} has no more opens, forgetting it.", lease.key);
+            self.router.unregister(std::iter::once(lease.key)).await?;
+        }
+        Ok(())
+    }
+
+    /// Takes an idle handle that may serve an open of the specified path, if there is one.
+    pub async fn take(
+        &self,
+        tree: &Upstream,
+        name: &str,
+        args: &FileCreateArgs,
+        share_access: ShareAccessFlags,
+    ) -> crate::Result<Option<IdleHandle>> {
+        if self.config.is_none() {
+            return Ok(None);
+        }
+
+        let (found, expired) = {
+            let mut state = self.state.lock().await?;
+            let expired = self.drain_expired(&mut state);
+            let found = state
+                .idle
+                .iter()
+                .position(|(path, _, handle)| path == name && handle.matches(args, share_access))
+                .map(|index| state.idle.remove(index).2);
+            (found, expired)
+        };
+        self.close_all(tree, expired).await;
+
+        if found.is_some() {
+            log::debug!("Reusing idle handle for {name}.");
+        }
+        Ok(found)
+    }
+
+    /// Keeps the specified handle open, for a later reuse.
+    ///
+    /// Returns false if the handle may not be kept open, and should be closed by the caller.
+    pub async fn defer(
+        &self,
+        tree: &Upstream,
+        name: &str,
+        handle: IdleHandle,
+    ) -> crate::Result<bool> {
+        let config = match &self.config {
+            Some(config) if handle.lease.lease.state().handle_caching() => config,
+            _ => return Ok(false),
+        };
+
+        let expired = {
+            let mut state = self.state.lock().await?;
+            state.idle.push((
+                name.to_string(),
+                Instant::now() + config.grace_period,
+                handle,
+            ));
+            let mut expired = self.drain_expired(&mut state);
+            let overflow = state.idle.len().saturating_sub(config.max_idle_handles);
+            expired.extend(state.idle.drain(..overflow).map(|(_, _, handle)| handle));
+            expired
+        };
+        self.close_all(tree, expired).await;
+
+        Self::schedule_sweep(tree, config.grace_period);
+        Ok(true)
+    }
+
+    /// Closes all the idle handles whose grace period has elapsed.
+    #[cfg(not(feature = "single_threaded"))]
+    pub async fn sweep(&self, tree: &Upstream) -> crate::Result<()> {
+        let expired = {
+            let mut state = self.state.lock().await?;
+            self.drain_expired(&mut state)
+        };
+        self.close_all(tree, expired).await;
+        Ok(())
+    }
+
+    /// Handles a lease break notification for a lease of this tree.
+    ///
+    /// Idle handles are closed if handle caching is no longer permitted, and the break
+    /// is acknowledged if the server requires it, and the lease still has opens afterwards.
+    pub async fn lease_break(
+        &self,
+        tree: &Upstream,
+        notify: &LeaseBreakNotify,
+    ) -> crate::Result<()> {
+        let key = notify.lease_key.as_u128();
+        let (lease, to_close) = {
+            let mut state = self.state.lock().await?;
+            let lease = match state.leases.values().find(|l| l.key == key) {
+                Some(lease) => lease.clone(),
+                None => {
+                    log::debug!("Lease break for unknown lease {key:x}, ignoring.");
+                    return Ok(());
+                }
+            };
+
+            lease.set_state(notify.new_lease_state);
+
+            let mut to_close = vec![];
+            if !notify.new_lease_state.handle_caching() {
+                let (broken, kept) = std::mem::take(&mut state.idle)
+                    .into_iter()
+                    .partition(|(_, _, handle)| Arc::ptr_eq(&handle.lease.lease, &lease));
+                state.idle = kept;
+                to_close = broken.into_iter().map(|(_, _, handle)| handle).collect();
+            }
+            (lease, to_close)
+        };
+
+        log::debug!(
+            "Lease {key:x} broken to {:?}, closing {} idle handles.",
+            notify.new_lease_state,
+            to_close.len()
+        );
+        self.close_all(tree, to_close).await;
+
+        let has_opens = lease.opens.load(Ordering::SeqCst) > 0;
+        if notify.ack_required != 0 && has_opens {
+            tree.send_recv(RequestContent::LeaseBreakAck(LeaseBreakAck {
+                lease_key: notify.lease_key,
+                lease_state: notify.new_lease_state,
+            }))
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets all the idle handles and leases of the tree.
+    ///
+    /// This should be called when the tree is disconnected - the server closes all the handles anyway.
+    pub async fn clear(&self) -> crate::Result<()> {
+        let leases = {
+            let mut state = self.state.lock().await?;
+            state.idle.clear();
+            std::mem::take(&mut state.leases)
+        };
+        self.router
+            .unregister(leases.values().map(|lease| lease.key))
+            .await
+    }
+
+    /// Removes all the expired idle handles from the state, and returns them.
+    fn drain_expired(&self, state: &mut HandleCacheState) -> Vec<IdleHandle> {
+        let now = Instant::now();
+        let (expired, kept) = std::mem::take(&mut state.idle)
+            .into_iter()
+            .partition(|(_, expires, _)| *expires <= now);
+        state.idle = kept;
+        expired.into_iter().map(|(_, _, handle)| handle).collect()
+    }
+
+    async fn close_all(&self, tree: &Upstream, handles: Vec<IdleHandle>) {
+        for handle in handles {
+            log::trace!("Closing idle handle {:?}", handle.file_id);
+            // The handle may not be used once its close is sent.
+            if let Err(e) = self.release_lease(&handle.lease.lease).await {
+                log::error!("Failed to release the lease of {:?}: {e}", handle.file_id);
+            }
+            let result = tree
+                .send_recv(
+                    CloseRequest {
+                        file_id: handle.file_id,
+                    }
+                    .into(),
+                )
+                .await;
+            if let Err(e) = result {
+                log::error!("Failed to close idle handle {:?}: {e}", handle.file_id);
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn schedule_sweep(tree: &Upstream, delay: Duration) {
+        let tree = tree.weak();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Some(handler) = tree.upgrade() {
+                let tree = HandlerReference { handler };
+                if let Err(e) = tree.handle_cache().sweep(&tree).await {
+                    log::error!("Failed to sweep idle handles: {e}");
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "multi_threaded")]
+    fn schedule_sweep(tree: &Upstream, delay: Duration) {
+        let tree = tree.weak();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            if let Some(handler) = tree.upgrade() {
+                let tree = HandlerReference { handler };
+                if let Err(e) = tree.handle_cache().sweep(&tree) {
+                    log::error!("Failed to sweep idle handles: {e}");
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "single_threaded")]
+    fn schedule_sweep(_tree: &Upstream, _delay: Duration) {
+        // Handle caching is not supported in single-threaded mode.
+    }
+}
+
+/// Routes lease break notifications, which are received by the connection,
+/// to the tree that holds the broken lease.
+#[derive(Default)]
+pub(crate) struct LeaseRouter {
+    trees: Mutex<HashMap<u128, Weak<TreeMessageHandler>>>,
+}
+
+#[maybe_async(AFIT)]
+impl LeaseRouter {
+    async fn register(&self, key: u128, tree: Weak<TreeMessageHandler>) -> crate::Result<()> {
+        let mut trees = self.trees.lock().await?;
+        trees.retain(|_, tree| tree.strong_count() > 0);
+        trees.insert(key, tree);
+        Ok(())
+    }
+
+    async fn unregister(&self, keys: impl Iterator<Item = u128>) -> crate::Result<()> {
+        let mut trees = self.trees.lock().await?;
+        for key in keys {
+            trees.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Dispatches a lease break notification to the tree holding the lease.
+    pub async fn lease_break(&self, notify: &LeaseBreakNotify) -> crate::Result<()> {
+        let key = notify.lease_key.as_u128();
+        let tree = {
+            let trees = self.trees.lock().await?;
+            trees.get(&key).and_then(|tree| tree.upgrade())
+        };
+
+        match tree {
+            Some(handler) => {
+                let tree = HandlerReference { handler };
+                tree.handle_cache().lease_break(&tree, notify).await
+            }
+            None => {
+                log::debug!("Received lease break for unknown lease {key:x}, ignoring.");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing", not(feature = "single_threaded")))]
+mod tests {
+    use smb_fscc::FileAccessMask;
+
+    use super::*;
+    use crate::resource::GetLen;
+    use crate::testing::{FakeReply, FakeServer, FakeServerConfig};
+    use crate::{Connection, ConnectionConfig, File, Session, Tree};
+
+    fn leasing_server() -> FakeServer {
+        let server = FakeServer::new(FakeServerConfig {
+            leasing: true,
+            ..Default::default()
+        });
+        server.add_file("share", "file.bin", b"hello".to_vec());
+        server
+    }
+
+    #[maybe_async]
+    async fn connect(server: &FakeServer, grace_period: Duration) -> (Connection, Session, Tree) {
+        let config = ConnectionConfig {
+            handle_caching: Some(HandleCachingConfig {
+                grace_period,
+                max_idle_handles: 4,
+            }),
+            ..Default::default()
+        };
+        let connection = server.connect(config).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+        (connection, session, tree)
+    }
+
+    #[maybe_async]
+    async fn open(tree: &Tree, name: &str) -> File {
+        let access = FileAccessMask::new()
+            .with_generic_read(true)
+            .with_generic_write(true);
+        tree.open_existing(name, access)
+            .await
+            .unwrap()
+            .unwrap_file()
+    }
+
+    fn requests(server: &FakeServer, command: Command) -> usize {
+        server.stats().requests.get(&command).copied().unwrap_or(0)
+    }
+
+    /// Waits for requests sent in the background (by a lease break or a sweep) to reach the server.
+    #[maybe_async]
+    async fn wait_for_requests(server: &FakeServer, command: Command, count: usize) {
+        for _ in 0..100 {
+            if requests(server, command) >= count {
+                break;
+            }
+            #[cfg(feature = "async")]
+            {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            #[cfg(not(feature = "async"))]
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(requests(server, command), count);
+    }
+
+    /// Records the lease keys requested by the client when opening files.
+    fn record_lease_keys(server: &FakeServer) -> Arc<std::sync::Mutex<Vec<u128>>> {
+        let keys = Arc::new(std::sync::Mutex::new(vec![]));
+        server.on(Command::Create, {
+            let keys = keys.clone();
+            move |request| {
+                if let RequestContent::Create(req) = &request.content {
+                    match CreateContextRequestData::first_rqls(&req.contexts) {
+                        Some(RequestLease::RqLsReqv1(v1)) => {
+                            keys.lock().unwrap().push(v1.lease_key)
+                        }
+                        Some(RequestLease::RqLsReqv2(v2)) => {
+                            keys.lock().unwrap().push(v2.lease_key)
+                        }
+                        None => {}
+                    }
+                }
+                FakeReply::Default
+            }
+        });
+        keys
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_idle_handle_reuse() {
+        let server = leasing_server();
+        let (_connection, _session, tree) = connect(&server, Duration::from_secs(30)).await;
+
+        let file = open(&tree, "file.bin").await;
+        file.close().await.unwrap();
+        assert_eq!(requests(&server, Command::Close), 0);
+
+        // Reused without a round-trip, as the file was not modified.
+        let file = open(&tree, "file.bin").await;
+        assert_eq!(requests(&server, Command::Create), 1);
+        assert_eq!(requests(&server, Command::QueryInfo), 0);
+        let len = file.get_len().await.unwrap();
+        assert_eq!(len, 5);
+        file.write_block(b", world", 5, None).await.unwrap();
+        file.close().await.unwrap();
+
+        // The file was modified since the handle was cached, so its size is queried once.
+        let file = open(&tree, "file.bin").await;
+        assert_eq!(requests(&server, Command::QueryInfo), 1);
+        let len = file.get_len().await.unwrap();
+        assert_eq!(len, 12);
+        file.close().await.unwrap();
+        let file = open(&tree, "file.bin").await;
+        assert_eq!(requests(&server, Command::QueryInfo), 1);
+        let len = file.get_len().await.unwrap();
+        assert_eq!(len, 12);
+
+        // A handle with insufficient access is not reused, but shares the lease.
+        let full_access = FileAccessMask::new().with_generic_all(true);
+        let other = tree
+            .open_existing("file.bin", full_access)
+            .await
+            .unwrap()
+            .unwrap_file();
+        assert_eq!(requests(&server, Command::Create), 2);
+        other.close().await.unwrap();
+        file.close().await.unwrap();
+        assert_eq!(requests(&server, Command::Close), 0);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_lease_break_with_deferred_close() {
+        let server = leasing_server();
+        server.add_file("share", "other.bin", vec![]);
+        let (_connection, _session, tree) = connect(&server, Duration::from_secs(30)).await;
+        let read_only = LeaseState::new().with_read_caching(true);
+
+        // Only an idle handle holds the lease: it is closed, and no acknowledgment is sent.
+        let file = open(&tree, "file.bin").await;
+        file.close().await.unwrap();
+        assert_eq!(server.break_lease("share", "file.bin", read_only), 1);
+        // The notification is sent along with the response to the next request.
+        let other = open(&tree, "other.bin").await;
+        wait_for_requests(&server, Command::Close, 1).await;
+        assert_eq!(requests(&server, Command::OplockBreak), 0);
+
+        // An open handle holds the lease: the break is acknowledged,
+        // and the handle is closed right away once handle caching is lost.
+        let file = open(&tree, "file.bin").await;
+        assert_eq!(requests(&server, Command::Create), 3);
+        assert_eq!(server.break_lease("share", "file.bin", read_only), 1);
+        other.flush().await.unwrap();
+        wait_for_requests(&server, Command::OplockBreak, 1).await;
+        file.close().await.unwrap();
+        assert_eq!(requests(&server, Command::Close), 2);
+        other.close().await.unwrap();
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_idle_handle_expiry() {
+        let server = leasing_server();
+        let lease_keys = record_lease_keys(&server);
+        let (_connection, _session, tree) = connect(&server, Duration::from_millis(100)).await;
+
+        let file = open(&tree, "file.bin").await;
+        file.close().await.unwrap();
+        assert_eq!(requests(&server, Command::Close), 0);
+        wait_for_requests(&server, Command::Close, 1).await;
+
+        // The lease had no more opens once the handle expired, so a new one is requested.
+        let file = open(&tree, "file.bin").await;
+        assert_eq!(requests(&server, Command::Create), 2);
+        let lease_keys = lease_keys.lock().unwrap().clone();
+        assert_eq!(lease_keys.len(), 2);
+        assert_ne!(lease_keys[0], lease_keys[1]);
+        file.close().await.unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+//! Reports and enforcement of the security properties of connections, sessions and trees.
+//!
+//! Use [`Connection::security_posture`][crate::Connection::security_posture],
+//! [`Session::security_posture`][crate::Session::security_posture] and
+//! [`Tree::security_posture`][crate::Tree::security_posture] to find out how traffic is protected,
+//! e.g. for auditing.
+//!
+//! Set [`ClientConfig::security_policy`][crate::ClientConfig::security_policy] to a [`SecurityPolicy`]
+//! to have the [`Client`][crate::Client] reject sessions and trees that do not meet it.
+
+use smb_msg::{Dialect, EncryptionCipher, SigningAlgorithmId};
+
+use crate::connection::connection_info::ConnectionInfo;
+
+/// The authentication mechanism of a session, as negotiated by SPNEGO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthMechanism {
+    Ntlm,
+    Kerberos,
+    Pku2u,
+}
+
+/// Which of the traffic is encrypted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EncryptionScope {
+    /// Messages are not encrypted.
+    #[default]
+    None,
+    /// Messages of the share are encrypted, since the server requires it for the share.
+    Share,
+    /// All the messages of the session are encrypted, since the server or the client config requires it.
+    Session,
+}
+
+/// Summarizes how the traffic of a connection, session or tree is protected.
+///
+/// Properties that are not known at the queried level (e.g. the authentication mechanism of a connection)
+/// are left unset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityPosture {
+    /// The negotiated dialect.
+    pub dialect: Dialect,
+    /// Whether the negotiation and session setup are protected by pre-authentication integrity (SMB 3.1.1).
+    pub preauth_integrity: bool,
+    /// The algorithm used to sign messages, or `None` if messages are not signed
+    /// (e.g. in guest or anonymous sessions).
+    pub signing_algorithm: Option<SigningAlgorithmId>,
+    /// The cipher used to encrypt messages, when encrypted, or `None` if encryption is not available.
+    pub encryption_cipher: Option<EncryptionCipher>,
+    /// Which of the traffic is encrypted.
+    pub encryption_scope: EncryptionScope,
+    /// The authentication mechanism of the session.
+    pub auth_mechanism: Option<AuthMechanism>,
+    /// Whether the session is a guest session.
+    pub guest: bool,
+    /// Whether the session is an anonymous (null) session.
+    pub anonymous: bool,
+}
+
+impl SecurityPosture {
+    /// Returns the posture of a negotiated connection, before any session is set up.
+    pub(crate) fn for_connection(info: &ConnectionInfo) -> Self {
+        let negotiation = &info.negotiation;
+        let encryption_cipher = if info.dialect.preauth_hash_supported() {
+            negotiation.encryption_cipher
+        } else {
+            // Older SMB 3 dialects always encrypt with AES-128-CCM.
+            let cipher = EncryptionCipher::Aes128Ccm;
+            (info.dialect.supports_encryption()
+                && negotiation.caps.encryption()
+                && !info.config.encryption_mode.is_disabled()
+                && info.config.encryption_ciphers().contains(&cipher))
+            .then_some(cipher)
+        };
+        Self {
+            dialect: negotiation.dialect_rev,
+            preauth_integrity: info.dialect.preauth_hash_supported(),
+            signing_algorithm: Some(
+                negotiation
+                    .signing_algo
+                    .unwrap_or_else(|| info.dialect.default_signing_algo()),
+            ),
+            encryption_cipher,
+            encryption_scope: EncryptionScope::None,
+            auth_mechanism: None,
+            guest: false,
+            anonymous: false,
+        }
+    }
+}
+
+/// The minimal security requirements for sessions and trees of a [`Client`][crate::Client].
+///
+/// The default policy accepts everything that the [`ConnectionConfig`][crate::ConnectionConfig] accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityPolicy {
+    /// The minimal dialect.
+    pub min_dialect: Option<Dialect>,
+    /// Requires pre-authentication integrity (i.e. SMB 3.1.1).
+    pub require_preauth_integrity: bool,
+    /// Requires messages to be signed. This rejects guest and anonymous sessions.
+    pub require_signing: bool,
+    /// Requires the traffic of trees to be encrypted, either by the session or by the share.
+    pub require_encryption: bool,
+    /// The allowed authentication mechanisms. If not set, all mechanisms are allowed.
+    pub auth_mechanisms: Option<Vec<AuthMechanism>>,
+    /// Whether guest sessions are allowed.
+    pub allow_guest: bool,
+    /// Whether anonymous sessions are allowed.
+    pub allow_anonymous: bool,
+}
+
+impl Default for SecurityPolicy {
+    fn default() -> Self {
+        Self {
+            min_dialect: None,
+            require_preauth_integrity: false,
+            require_signing: false,
+            require_encryption: false,
+            auth_mechanisms: None,
+            allow_guest: true,
+            allow_anonymous: true,
+        }
+    }
+}
+
+impl SecurityPolicy {
+    /// Checks the posture of a session against the policy.
+    pub fn check_session(&self, posture: &SecurityPosture) -> crate::Result<()> {
+        if let Some(min_dialect) = self.min_dialect {
+            if posture.dialect < min_dialect {
+                return Self::violation(format!(
+                    "dialect {:?} is below the minimum {min_dialect:?}",
+                    posture.dialect
+                ));
+            }
+        }
+        if self.require_preauth_integrity && !posture.preauth_integrity {
+            return Self::violation("pre-authentication integrity is required".to_string());
+        }
+        if self.require_signing && posture.signing_algorithm.is_none() {
+            return Self::violation("signing is required".to_string());
+        }
+        if let Some(auth_mechanisms) = &self.auth_mechanisms {
+            if !posture
+                .auth_mechanism
+                .is_some_and(|m| auth_mechanisms.contains(&m))
+            {
+                return Self::violation(format!(
+                    "authentication mechanism {:?} is not allowed",
+                    posture.auth_mechanism
+                ));
+            }
+        }
+        if !self.allow_guest && posture.guest {
+            return Self::violation("guest sessions are not allowed".to_string());
+        }
+        if !self.allow_anonymous && posture.anonymous {
+            return Self::violation("anonymous sessions are not allowed".to_string());
+        }
+        Ok(())
+    }
+
+    /// Checks the posture of a tree against the policy.
+    pub fn check_tree(&self, posture: &SecurityPosture) -> crate::Result<()> {
+        self.check_session(posture)?;
+        if self.require_encryption && posture.encryption_scope == EncryptionScope::None {
+            return Self::violation("encryption is required".to_string());
+        }
+        Ok(())
+    }
+
+    fn violation(reason: String) -> crate::Result<()> {
+        Err(crate::Error::SecurityPolicyViolation(reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_check() {
+        let posture = SecurityPosture {
+            dialect: Dialect::Smb0302,
+            preauth_integrity: false,
+            signing_algorithm: Some(SigningAlgorithmId::AesCmac),
+            encryption_cipher: Some(EncryptionCipher::Aes128Ccm),
+            encryption_scope: EncryptionScope::Share,
+            auth_mechanism: Some(AuthMechanism::Ntlm),
+            guest: false,
+            anonymous: false,
+        };
+        let policy = SecurityPolicy {
+            require_signing: true,
+            require_encryption: true,
+            ..Default::default()
+        };
+        policy.check_tree(&posture).unwrap();
+
+        let policy = SecurityPolicy {
+            min_dialect: Some(Dialect::Smb0311),
+            ..Default::default()
+        };
+        assert!(policy.check_session(&posture).is_err());
+
+        let policy = SecurityPolicy {
+            auth_mechanisms: Some(vec![AuthMechanism::Kerberos]),
+            ..Default::default()
+        };
+        assert!(policy.check_session(&posture).is_err());
+
+        let guest = SecurityPosture {
+            signing_algorithm: None,
+            encryption_scope: EncryptionScope::None,
+            guest: true,
+            ..posture
+        };
+        let policy = SecurityPolicy {
+            require_encryption: true,
+            ..Default::default()
+        };
+        policy.check_session(&guest).unwrap();
+        assert!(policy.check_tree(&guest).is_err());
+        let policy = SecurityPolicy {
+            allow_guest: false,
+            ..Default::default()
+        };
+        assert!(policy.check_session(&guest).is_err());
+    }
+}
@@ -7,6 +7,8 @@ use crate::UncPath;
 use crate::connection::connection_info::ConnectionInfo;
 use crate::connection::preauth_hash::{PreauthHashState, PreauthHashValue};
 use crate::connection::worker::Worker;
+use crate::events::SmbEvent;
+use crate::security::SecurityPosture;
 use crate::{
     Error,
     connection::ConnectionMessageHandler,
@@ -24,19 +26,28 @@ use std::collections::HashMap;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicU32};
 
+mod anonymous;
 mod authenticator;
 mod channel;
+mod credentials;
 mod encryptor_decryptor;
+#[cfg(feature = "kerberos")]
+mod kerberos;
 mod setup;
 mod signer;
 #[cfg(feature = "kerberos")]
+mod spn;
+#[cfg(feature = "kerberos")]
 mod sspi_network_client;
 mod state;
 
 pub use channel::*;
+pub use credentials::Credentials;
 pub use encryptor_decryptor::{MessageDecryptor, MessageEncryptor};
 
 pub use signer::MessageSigner;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use state::SessionAlgosFactory;
 pub use state::{ChannelInfo, SessionInfo};
 
 use setup::*;
@@ -57,14 +68,14 @@ impl Session {
     ///
     /// [Session::bind] may be used instead, to bind an existing session to a new connection.
     pub(crate) async fn create(
-        identity: sspi::AuthIdentity,
+        credentials: Credentials,
         upstream: &ChannelUpstream,
         conn_info: &Arc<ConnectionInfo>,
     ) -> crate::Result<Session> {
         const FIRST_CHANNEL_ID: u32 = 0;
 
         let setup_result = SessionSetup::<SmbSessionNew>::new(
-            identity,
+            credentials,
             upstream,
             conn_info,
             FIRST_CHANNEL_ID,
@@ -90,7 +101,7 @@ impl Session {
     /// Returns the channel ID (in the scope of the current session) of the newly created channel.
     pub(crate) async fn bind(
         &self,
-        identity: sspi::AuthIdentity,
+        credentials: Credentials,
         handler: &HandlerReference<ConnectionMessageHandler>,
         conn_info: &Arc<ConnectionInfo>,
     ) -> crate::Result<u32> {
@@ -125,7 +136,7 @@ impl Session {
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
         let setup_result = SessionSetup::<SmbSessionBind>::new(
-            identity,
+            credentials,
             handler,
             conn_info,
             new_channel_id,
@@ -147,6 +158,14 @@ impl Session {
             .await?
             .insert(new_channel_id, channel_handler);
 
+        let session_id = self.session_id();
+        handler.channel_bound(session_id, new_channel_id)?;
+        conn_info.emit_event(|server| SmbEvent::ChannelAdded {
+            server,
+            session_id,
+            channel_id: new_channel_id,
+        });
+
         Ok(new_channel_id)
     }
 
@@ -178,16 +197,31 @@ impl Session {
     /// Connects to the specified tree on the current session.
     /// ## Arguments
     /// * `name` - The name of the tree to connect to.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "smb.tree_connect",
+        skip_all,
+        fields(session_id = self.session_handler.session_id, tree = %name)
+    ))]
     pub async fn tree_connect(&self, name: &UncPath) -> crate::Result<Tree> {
         let name = name.clone().with_no_path().to_string();
         let tree = Tree::connect(&name, &self.session_handler, &self.conn_info).await?;
         Ok(tree)
     }
 
+    /// Returns the security posture of the session.
+    pub async fn security_posture(&self) -> crate::Result<SecurityPosture> {
+        self.session_handler.security_posture(&self.conn_info).await
+    }
+
     /// Logs off the session.
     ///
     /// Any resources held by the session will be released,
     /// and any [`Tree`] objects and their resources will be unusable.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "smb.logoff",
+        skip_all,
+        fields(session_id = self.session_handler.session_id)
+    ))]
     pub async fn logoff(&self) -> crate::Result<()> {
         self.session_handler.logoff().await
     }
@@ -248,6 +282,19 @@ impl SessionMessageHandler {
         }
     }
 
+    pub fn primary_channel(&self) -> &HandlerReference<ChannelMessageHandler> {
+        &self.primary_channel
+    }
+
+    pub async fn security_posture(
+        &self,
+        conn_info: &ConnectionInfo,
+    ) -> crate::Result<SecurityPosture> {
+        let state = self.primary_channel.session_state().read().await?;
+        let session = state.session.read().await?;
+        session.security_posture(conn_info)
+    }
+
     pub async fn logoff(&self) -> crate::Result<()> {
         if self
             .dropping
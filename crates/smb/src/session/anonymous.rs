@@ -0,0 +1,298 @@
+//! Anonymous (null session) authentication, using SPNEGO and NTLM anonymous authentication.
+//!
+//! [`sspi`] always computes NTLM responses from the user's password, so the few messages
+//! of an anonymous exchange are built here: an NTLM NEGOTIATE message, wrapped in a SPNEGO `NegTokenInit`,
+//! and an anonymous AUTHENTICATE message (MS-NLMP 3.1.5.1.2), wrapped in a SPNEGO `NegTokenResp`.
+
+use crate::Error;
+
+/// The DER encoding of the SPNEGO OID, 1.3.6.1.5.5.2.
+const SPNEGO_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+/// The DER encoding of the NTLMSSP OID, 1.3.6.1.4.1.311.2.2.10.
+const NTLMSSP_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0a];
+
+const NTLMSSP_SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+const NTLM_NEGOTIATE: u32 = 1;
+const NTLM_CHALLENGE: u32 = 2;
+const NTLM_AUTHENTICATE: u32 = 3;
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ANONYMOUS: u32 = 0x0000_0800;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSION_SECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSION_SECURITY
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+/// The SPNEGO `reject` negotiation state.
+const NEG_STATE_REJECT: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnonymousState {
+    Initial,
+    Negotiated,
+    Authenticated,
+}
+
+#[derive(Debug)]
+pub struct AnonymousAuthenticator {
+    state: AnonymousState,
+}
+
+impl AnonymousAuthenticator {
+    pub fn new() -> Self {
+        Self {
+            state: AnonymousState::Initial,
+        }
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.state == AnonymousState::Authenticated
+    }
+
+    /// Anonymous sessions have no session key (MS-NLMP 3.3.2), and are not signed.
+    pub fn session_key(&self) -> crate::Result<[u8; 16]> {
+        match self.state {
+            AnonymousState::Authenticated => Ok([0; 16]),
+            _ => Err(Error::InvalidState(
+                "Anonymous authentication is not complete".to_string(),
+            )),
+        }
+    }
+
+    pub fn next(&mut self, gss_token: &[u8]) -> crate::Result<Vec<u8>> {
+        match self.state {
+            AnonymousState::Initial => {
+                self.state = AnonymousState::Negotiated;
+                Ok(Self::neg_token_init(&Self::ntlm_negotiate()))
+            }
+            AnonymousState::Negotiated => {
+                let challenge = Self::parse_neg_token_resp(gss_token)?.ok_or_else(|| {
+                    Error::InvalidMessage("Missing NTLM challenge in SPNEGO response".to_string())
+                })?;
+                let challenge_flags = Self::parse_ntlm_challenge(&challenge)?;
+                self.state = AnonymousState::Authenticated;
+                Ok(Self::neg_token_resp(&Self::ntlm_authenticate(
+                    challenge_flags,
+                )))
+            }
+            AnonymousState::Authenticated => Err(Error::InvalidState(
+                "Anonymous authentication is already complete".to_string(),
+            )),
+        }
+    }
+
+    fn ntlm_negotiate() -> Vec<u8> {
+        let mut message = Vec::with_capacity(32);
+        message.extend_from_slice(NTLMSSP_SIGNATURE);
+        message.extend_from_slice(&NTLM_NEGOTIATE.to_le_bytes());
+        message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+        // Empty domain name and workstation fields.
+        for _ in 0..2 {
+            Self::write_ntlm_field(&mut message, 0, 32);
+        }
+        message
+    }
+
+    /// Returns the flags of an NTLM CHALLENGE message.
+    fn parse_ntlm_challenge(challenge: &[u8]) -> crate::Result<u32> {
+        if challenge.len() < 32
+            || &challenge[..8] != NTLMSSP_SIGNATURE
+            || u32::from_le_bytes(challenge[8..12].try_into().unwrap()) != NTLM_CHALLENGE
+        {
+            return Err(Error::InvalidMessage(
+                "Invalid NTLM challenge message".to_string(),
+            ));
+        }
+        Ok(u32::from_le_bytes(challenge[20..24].try_into().unwrap()))
+    }
+
+    /// Builds an anonymous AUTHENTICATE message: empty user name and NT response,
+    /// and a single zero byte LM response.
+    fn ntlm_authenticate(challenge_flags: u32) -> Vec<u8> {
+        const HEADER_SIZE: u32 = 64;
+        let flags = (challenge_flags & NEGOTIATE_FLAGS) | NEGOTIATE_ANONYMOUS;
+
+        let mut message = Vec::with_capacity(HEADER_SIZE as usize + 1);
+        message.extend_from_slice(NTLMSSP_SIGNATURE);
+        message.extend_from_slice(&NTLM_AUTHENTICATE.to_le_bytes());
+        // LM challenge response: Z(1).
+        Self::write_ntlm_field(&mut message, 1, HEADER_SIZE);
+        // NT challenge response, domain name, user name, workstation and encrypted session key: empty.
+        for _ in 0..5 {
+            Self::write_ntlm_field(&mut message, 0, HEADER_SIZE + 1);
+        }
+        message.extend_from_slice(&flags.to_le_bytes());
+        message.push(0);
+        message
+    }
+
+    fn write_ntlm_field(message: &mut Vec<u8>, len: u16, offset: u32) {
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    /// Wraps `mech_token` in a SPNEGO `NegTokenInit`, offering NTLM only.
+    fn neg_token_init(mech_token: &[u8]) -> Vec<u8> {
+        let mech_types = der(0x30, &der(0x06, NTLMSSP_OID));
+        let neg_token_init = der(
+            0x30,
+            &[der(0xa0, &mech_types), der(0xa2, &der(0x04, mech_token))].concat(),
+        );
+        der(
+            0x60,
+            &[der(0x06, SPNEGO_OID), der(0xa0, &neg_token_init)].concat(),
+        )
+    }
+
+    /// Wraps `response_token` in a SPNEGO `NegTokenResp`.
+    fn neg_token_resp(response_token: &[u8]) -> Vec<u8> {
+        der(0xa1, &der(0x30, &der(0xa2, &der(0x04, response_token))))
+    }
+
+    /// Returns the response token of a SPNEGO `NegTokenResp`, if any.
+    fn parse_neg_token_resp(token: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        let (neg_token_resp, _) = read_der(token, 0xa1)?;
+        let (mut fields, _) = read_der(neg_token_resp, 0x30)?;
+        let mut response_token = None;
+        while !fields.is_empty() {
+            let (tag, content, rest) = read_any_der(fields)?;
+            match tag {
+                0xa0 => {
+                    let (neg_state, _) = read_der(content, 0x0a)?;
+                    if neg_state == [NEG_STATE_REJECT] {
+                        return Err(Error::AuthenticationRejected(
+                            "The server does not allow anonymous sessions.".to_string(),
+                        ));
+                    }
+                }
+                0xa2 => response_token = Some(read_der(content, 0x04)?.0.to_vec()),
+                _ => {}
+            }
+            fields = rest;
+        }
+        Ok(response_token)
+    }
+}
+
+/// Encodes a DER value of `tag`.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    match content.len() {
+        len @ 0..0x80 => result.push(len as u8),
+        len => {
+            let len_bytes = len.to_be_bytes();
+            let len_bytes = &len_bytes[len_bytes.iter().take_while(|&&b| b == 0).count()..];
+            result.push(0x80 | len_bytes.len() as u8);
+            result.extend_from_slice(len_bytes);
+        }
+    }
+    result.extend_from_slice(content);
+    result
+}
+
+/// Reads a DER value of `tag`, returning its content and the rest of the data.
+fn read_der(data: &[u8], tag: u8) -> crate::Result<(&[u8], &[u8])> {
+    let (actual_tag, content, rest) = read_any_der(data)?;
+    if actual_tag != tag {
+        return Err(Error::InvalidMessage(format!(
+            "Unexpected SPNEGO tag {actual_tag:#x}, expected {tag:#x}"
+        )));
+    }
+    Ok((content, rest))
+}
+
+/// Reads a DER value, returning its tag, content and the rest of the data.
+fn read_any_der(data: &[u8]) -> crate::Result<(u8, &[u8], &[u8])> {
+    let invalid = || Error::InvalidMessage("Invalid SPNEGO token".to_string());
+    let (&tag, data) = data.split_first().ok_or_else(invalid)?;
+    let (&len, mut data) = data.split_first().ok_or_else(invalid)?;
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let len_size = (len & 0x7f) as usize;
+        if len_size == 0 || len_size > std::mem::size_of::<usize>() || data.len() < len_size {
+            return Err(invalid());
+        }
+        let (len_bytes, rest) = data.split_at(len_size);
+        data = rest;
+        len_bytes
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize)
+    };
+    if data.len() < len {
+        return Err(invalid());
+    }
+    let (content, rest) = data.split_at(len);
+    Ok((tag, content, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anonymous_exchange() {
+        let mut authenticator = AnonymousAuthenticator::new();
+        let init = authenticator.next(&[]).unwrap();
+        assert_eq!(init[0], 0x60);
+        assert!(!authenticator.is_authenticated());
+
+        let mut challenge = NTLMSSP_SIGNATURE.to_vec();
+        challenge.extend_from_slice(&NTLM_CHALLENGE.to_le_bytes());
+        challenge.extend_from_slice(&[0; 8]);
+        challenge.extend_from_slice(&(NEGOTIATE_UNICODE | NEGOTIATE_NTLM).to_le_bytes());
+        challenge.extend_from_slice(&[0x11; 8 + 8 + 8]);
+        let server_resp = der(
+            0xa1,
+            &der(
+                0x30,
+                &[
+                    der(0xa0, &der(0x0a, &[1])),
+                    der(0xa1, &der(0x06, NTLMSSP_OID)),
+                    der(0xa2, &der(0x04, &challenge)),
+                ]
+                .concat(),
+            ),
+        );
+
+        let resp = authenticator.next(&server_resp).unwrap();
+        assert!(authenticator.is_authenticated());
+        assert_eq!(authenticator.session_key().unwrap(), [0; 16]);
+        let authenticate = AnonymousAuthenticator::parse_neg_token_resp(&resp)
+            .unwrap()
+            .unwrap();
+        assert_eq!(authenticate.len(), 65);
+        assert_eq!(&authenticate[..8], NTLMSSP_SIGNATURE);
+        let flags = u32::from_le_bytes(authenticate[60..64].try_into().unwrap());
+        assert_eq!(
+            flags,
+            NEGOTIATE_UNICODE | NEGOTIATE_NTLM | NEGOTIATE_ANONYMOUS
+        );
+
+        let rejected = der(0xa1, &der(0x30, &der(0xa0, &der(0x0a, &[2]))));
+        assert!(AnonymousAuthenticator::parse_neg_token_resp(&rejected).is_err());
+    }
+
+    #[test]
+    fn test_der_long_length() {
+        let content = vec![0xab; 300];
+        let encoded = der(0x04, &content);
+        assert_eq!(&encoded[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        let (decoded, rest) = read_der(&encoded, 0x04).unwrap();
+        assert_eq!(decoded, content.as_slice());
+        assert!(rest.is_empty());
+    }
+}
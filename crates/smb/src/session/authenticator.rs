@@ -1,19 +1,125 @@
 use std::sync::Arc;
 
+use super::Credentials;
+use super::anonymous::AnonymousAuthenticator;
+#[cfg(feature = "kerberos")]
+use super::kerberos::KerberosAuthenticator;
 use crate::Error;
 use crate::connection::AuthMethodsConfig;
 use crate::connection::connection_info::ConnectionInfo;
+use crate::security::AuthMechanism;
 use maybe_async::*;
+use sspi::negotiate::ProtocolConfig;
 use sspi::{
     AcquireCredentialsHandleResult, AuthIdentity, BufferType, ClientRequestFlags, CredentialUse,
-    DataRepresentation, InitializeSecurityContextResult, Negotiate, SecurityBuffer, Sspi,
-    ntlm::NtlmConfig,
+    DataRepresentation, InitializeSecurityContextResult, Negotiate, NegotiatedProtocol,
+    SecurityBuffer, Sspi, ntlm::NtlmConfig,
 };
 use sspi::{CredentialsBuffers, NegotiateConfig, SspiImpl, Username};
 
+/// Authenticates a session, using SSPI for passwords (and guests),
+/// Kerberos tickets from a credential cache or a keytab, or NTLM anonymous authentication.
 #[derive(Debug)]
-pub struct Authenticator {
-    server_hostname: String,
+#[allow(clippy::large_enum_variant)]
+pub enum Authenticator {
+    Sspi(SspiAuthenticator),
+    Anonymous(AnonymousAuthenticator),
+    #[cfg(feature = "kerberos")]
+    Kerberos(KerberosAuthenticator),
+}
+
+#[maybe_async]
+impl Authenticator {
+    pub async fn build(
+        credentials: Credentials,
+        conn_info: &Arc<ConnectionInfo>,
+    ) -> crate::Result<Authenticator> {
+        match credentials {
+            Credentials::Password(identity) => Ok(Authenticator::Sspi(
+                SspiAuthenticator::build(identity, conn_info).await?,
+            )),
+            Credentials::Guest => {
+                let identity = AuthIdentity {
+                    username: Username::parse(Credentials::GUEST_USER_NAME)
+                        .map_err(|e| Error::SspiError(e.into()))?,
+                    password: String::new().into(),
+                };
+                Ok(Authenticator::Sspi(
+                    SspiAuthenticator::build(identity, conn_info).await?,
+                ))
+            }
+            Credentials::Anonymous => Ok(Authenticator::Anonymous(AnonymousAuthenticator::new())),
+            #[cfg(feature = "kerberos")]
+            credentials => {
+                if !conn_info.config.auth_methods.kerberos {
+                    return Err(Error::InvalidConfiguration(
+                        "Kerberos credentials were provided, but Kerberos authentication is disabled"
+                            .to_string(),
+                    ));
+                }
+                Ok(Authenticator::Kerberos(
+                    KerberosAuthenticator::build(&credentials, conn_info).await?,
+                ))
+            }
+        }
+    }
+
+    pub fn user_name(&self) -> String {
+        match self {
+            Authenticator::Sspi(a) => {
+                let user_name = a.user_name();
+                match user_name.domain_name() {
+                    Some(domain) => format!("{}@{domain}", user_name.account_name()),
+                    None => user_name.account_name().to_string(),
+                }
+            }
+            Authenticator::Anonymous(_) => Credentials::Anonymous.to_string(),
+            #[cfg(feature = "kerberos")]
+            Authenticator::Kerberos(a) => a.user_name(),
+        }
+    }
+
+    pub fn is_authenticated(&self) -> crate::Result<bool> {
+        match self {
+            Authenticator::Sspi(a) => a.is_authenticated(),
+            Authenticator::Anonymous(a) => Ok(a.is_authenticated()),
+            #[cfg(feature = "kerberos")]
+            Authenticator::Kerberos(a) => Ok(a.is_authenticated()),
+        }
+    }
+
+    pub fn session_key(&self) -> crate::Result<[u8; 16]> {
+        match self {
+            Authenticator::Sspi(a) => a.session_key(),
+            Authenticator::Anonymous(a) => a.session_key(),
+            #[cfg(feature = "kerberos")]
+            Authenticator::Kerberos(a) => a.session_key(),
+        }
+    }
+
+    /// Returns the mechanism selected by SPNEGO.
+    pub fn auth_mechanism(&self) -> AuthMechanism {
+        match self {
+            Authenticator::Sspi(a) => a.auth_mechanism(),
+            Authenticator::Anonymous(_) => AuthMechanism::Ntlm,
+            #[cfg(feature = "kerberos")]
+            Authenticator::Kerberos(_) => AuthMechanism::Kerberos,
+        }
+    }
+
+    pub async fn next(&mut self, gss_token: &[u8]) -> crate::Result<Vec<u8>> {
+        match self {
+            Authenticator::Sspi(a) => a.next(gss_token).await,
+            Authenticator::Anonymous(a) => a.next(gss_token),
+            #[cfg(feature = "kerberos")]
+            Authenticator::Kerberos(a) => a.next(gss_token).await,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SspiAuthenticator {
+    target_name: String,
     user_name: Username,
 
     ssp: Negotiate,
@@ -21,20 +127,30 @@ pub struct Authenticator {
     current_state: Option<InitializeSecurityContextResult>,
 }
 
-impl Authenticator {
-    pub fn build(
-        identity: AuthIdentity,
+#[maybe_async]
+impl SspiAuthenticator {
+    pub async fn build(
+        mut identity: AuthIdentity,
         conn_info: &Arc<ConnectionInfo>,
-    ) -> crate::Result<Authenticator> {
+    ) -> crate::Result<SspiAuthenticator> {
         let client_computer_name = conn_info
             .config
             .client_name
             .as_ref()
             .unwrap_or(&String::from("smb-rs"))
             .clone();
+        let auth_methods = &conn_info.config.auth_methods;
+        if let (Some(realm), None) = (&auth_methods.realm, identity.username.domain_name()) {
+            identity.username = Username::new(identity.username.account_name(), Some(realm))
+                .map_err(|e| {
+                    Error::InvalidArgument(format!("Invalid user name for realm {realm}: {e}"))
+                })?;
+        }
+        let ntlm_allowed = auth_methods.ntlm_fallback
+            || !Self::is_kerberos_attempted(auth_methods, &identity).await;
         let mut negotiate_ssp = Negotiate::new_client(NegotiateConfig::new(
-            Box::new(NtlmConfig::default()),
-            Some(Self::get_available_ssp_pkgs(&conn_info.config.auth_methods)),
+            Self::get_protocol_config(auth_methods, &client_computer_name),
+            Some(Self::get_available_ssp_pkgs(auth_methods, ntlm_allowed)),
             client_computer_name,
         ))?;
         let user_name = identity.username.clone();
@@ -45,8 +161,8 @@ impl Authenticator {
             .with_auth_data(&sspi::Credentials::AuthIdentity(identity.clone()))
             .execute(&mut negotiate_ssp)?;
 
-        Ok(Authenticator {
-            server_hostname: conn_info.server_name.clone(),
+        Ok(SspiAuthenticator {
+            target_name: Self::make_sspi_target_name(conn_info).await,
             ssp: negotiate_ssp,
             cred_handle,
             current_state: None,
@@ -72,8 +188,21 @@ impl Authenticator {
         Ok(k.try_into().unwrap())
     }
 
-    fn make_sspi_target_name(server_fqdn: &str) -> String {
-        format!("cifs/{server_fqdn}")
+    /// Returns the mechanism selected by SPNEGO.
+    pub fn auth_mechanism(&self) -> AuthMechanism {
+        match self.ssp.negotiated_protocol() {
+            NegotiatedProtocol::Ntlm(_) => AuthMechanism::Ntlm,
+            NegotiatedProtocol::Kerberos(_) => AuthMechanism::Kerberos,
+            NegotiatedProtocol::Pku2u(_) => AuthMechanism::Pku2u,
+        }
+    }
+
+    async fn make_sspi_target_name(conn_info: &ConnectionInfo) -> String {
+        #[cfg(feature = "kerberos")]
+        return super::spn::server_spn(&conn_info.server_name, &conn_info.config.auth_methods)
+            .await;
+        #[cfg(not(feature = "kerberos"))]
+        return format!("cifs/{}", conn_info.server_name);
     }
 
     fn get_context_requirements() -> ClientRequestFlags {
@@ -101,7 +230,6 @@ impl Authenticator {
         }
 
         let mut output_buffer = vec![SecurityBuffer::new(Vec::new(), BufferType::Token)];
-        let target_name = Self::make_sspi_target_name(&self.server_hostname);
         let mut builder = self
             .ssp
             .initialize_security_context()
@@ -111,7 +239,7 @@ impl Authenticator {
             .with_output(&mut output_buffer);
 
         if cfg!(feature = "kerberos") {
-            builder = builder.with_target_name(&target_name)
+            builder = builder.with_target_name(&self.target_name)
         }
 
         let mut input_buffers = vec![];
@@ -181,13 +309,57 @@ impl Authenticator {
         }
     }
 
-    fn get_available_ssp_pkgs(config: &AuthMethodsConfig) -> String {
+    /// Returns the initial protocol of [`Negotiate`]: Kerberos with the configured KDC, if set,
+    /// and NTLM otherwise (in which case Kerberos is still negotiated, if a KDC is discovered).
+    #[cfg_attr(not(feature = "kerberos"), allow(unused_variables))]
+    fn get_protocol_config(
+        config: &AuthMethodsConfig,
+        client_computer_name: &str,
+    ) -> Box<dyn ProtocolConfig> {
+        match &config.kdc_url {
+            #[cfg(feature = "kerberos")]
+            Some(kdc_url) if config.kerberos => Box::new(sspi::KerberosConfig::new(
+                kdc_url,
+                client_computer_name.to_string(),
+            )),
+            _ => Box::new(NtlmConfig::default()),
+        }
+    }
+
+    /// Returns whether [`Negotiate`] is going to attempt Kerberos: if a KDC is configured,
+    /// or discovered for the domain of the user (off the async runtime, see [`detect_kdc_url`]).
+    ///
+    /// [`detect_kdc_url`]: super::kerberos::detect_kdc_url
+    #[cfg_attr(not(feature = "kerberos"), allow(unused_variables))]
+    async fn is_kerberos_attempted(config: &AuthMethodsConfig, identity: &AuthIdentity) -> bool {
+        #[cfg(feature = "kerberos")]
+        {
+            if !config.kerberos {
+                return false;
+            }
+            if config.kdc_url.is_some() {
+                return true;
+            }
+            match identity.username.domain_name() {
+                Some(domain) => super::kerberos::detect_kdc_url(domain).await.is_some(),
+                None => false,
+            }
+        }
+        #[cfg(not(feature = "kerberos"))]
+        return false;
+    }
+
+    fn get_available_ssp_pkgs(config: &AuthMethodsConfig, ntlm_allowed: bool) -> String {
         let krb_pku2u_config = if cfg!(feature = "kerberos") && config.kerberos {
             "kerberos,!pku2u"
         } else {
             "!kerberos,!pku2u"
         };
-        let ntlm_config = if config.ntlm { "ntlm" } else { "!ntlm" };
+        let ntlm_config = if config.ntlm && ntlm_allowed {
+            "ntlm"
+        } else {
+            "!ntlm"
+        };
         format!("{ntlm_config},{krb_pku2u_config}")
     }
 }
@@ -127,8 +127,11 @@ impl ChannelMessageHandler {
                 "Message not encrypted, but encryption is required for the session!".to_string(),
             ));
         }
-        // and signed, unless allowed not to.
-        if !incoming.form.signed_or_encrypted() && !unsigned_allowed {
+        // and signed, unless allowed not to. Notifications (with the message ID -1) are never signed.
+        if !incoming.form.signed_or_encrypted()
+            && !unsigned_allowed
+            && incoming.message.header.message_id != u64::MAX
+        {
             return Err(Error::InvalidMessage(
                 "Message not signed or encrypted, but signing is required for the session!"
                     .to_string(),
@@ -138,6 +141,44 @@ impl ChannelMessageHandler {
         Ok(())
     }
 
+    /// (Internal)
+    ///
+    /// Verifies the signature of a signed message that was received before the channel was set up.
+    ///
+    /// This may happen to the final session setup response, if it arrives before the client
+    /// finishes deriving the channel keys.
+    #[maybe_async]
+    async fn _verify_late_signature(&self, incoming: &mut IncomingMessage) -> crate::Result<()> {
+        if incoming.form.signed_or_encrypted() || !incoming.message.header.flags.signed() {
+            return Ok(());
+        }
+
+        let mut signer = {
+            let session = self.session_state.read().await?;
+            let channel = session.channel.as_ref().ok_or_else(|| {
+                Error::InvalidState("Channel is not set up, cannot verify signature".to_string())
+            })?;
+            channel.signer()?.clone()
+        };
+        let mut header = incoming.message.header.clone();
+        signer.verify_signature(&mut header, &incoming.raw)?;
+        incoming.form.signed = true;
+        Ok(())
+    }
+
+    /// (Internal)
+    ///
+    /// Verifies a session setup response that was received without security validation,
+    /// once the channel it is signed for is set up.
+    #[maybe_async]
+    pub(crate) async fn verify_setup_response(
+        &self,
+        incoming: &mut IncomingMessage,
+    ) -> crate::Result<()> {
+        self._verify_late_signature(incoming).await?;
+        self._verify_incoming(incoming).await
+    }
+
     /// **Insecure! Insecure! Insecure!**
     ///
     /// Same as [`ChannelMessageHandler::recvo`], but possible skips security validation.
@@ -153,9 +194,10 @@ impl ChannelMessageHandler {
         options: ReceiveOptions<'_>,
         skip_security_validation: bool,
     ) -> crate::Result<IncomingMessage> {
-        let incoming = self.upstream.recvo(options).await?;
+        let mut incoming = self.upstream.recvo(options).await?;
 
         if !skip_security_validation {
+            self._verify_late_signature(&mut incoming).await?;
             self._verify_incoming(&incoming).await?;
         } else {
             // Note: this is performed here for extra security,
@@ -194,6 +236,29 @@ impl ChannelMessageHandler {
     pub fn session_state(&self) -> &Arc<RwLock<SessionAndChannel>> {
         &self.session_state
     }
+
+    pub(crate) fn upstream(&self) -> &ChannelUpstream {
+        &self.upstream
+    }
+
+    /// Raises the event made by `event` from the server name, if an event listener is configured.
+    pub(crate) fn emit_event(&self, event: impl FnOnce(String) -> SmbEvent) {
+        self.upstream.emit_event(event);
+    }
+
+    /// Raises [`SmbEvent::SessionExpired`] if the error is the server's rejection of an expired session.
+    fn _check_expired(&self, error: &Error) {
+        if let Error::UnexpectedMessageStatus(status) | Error::ReceivedErrorMessage(status, _) =
+            error
+        {
+            if *status == Status::U32_NETWORK_SESSION_EXPIRED {
+                self.emit_event(|server| SmbEvent::SessionExpired {
+                    server,
+                    session_id: self.session_id,
+                });
+            }
+        }
+    }
 }
 
 #[maybe_async(AFIT)]
@@ -232,7 +297,11 @@ impl MessageHandler for ChannelMessageHandler {
     }
 
     async fn recvo(&self, options: ReceiveOptions<'_>) -> crate::Result<IncomingMessage> {
-        let incoming = self.upstream.recvo(options).await?;
+        let incoming = self
+            .upstream
+            .recvo(options)
+            .await
+            .inspect_err(|e| self._check_expired(e))?;
 
         self._verify_incoming(&incoming).await?;
 
@@ -246,7 +315,13 @@ impl MessageHandler for ChannelMessageHandler {
             ResponseContent::ServerToClientNotification(s2c_notification) => {
                 match s2c_notification.notification {
                     // TODO: Move this to primary session
-                    Notification::NotifySessionClosed(_) => self._invalidate().await,
+                    Notification::NotifySessionClosed(_) => {
+                        self.emit_event(|server| SmbEvent::SessionClosedByServer {
+                            server,
+                            session_id: self.session_id,
+                        });
+                        self._invalidate().await
+                    }
                 }
             }
             _ => {
@@ -0,0 +1,151 @@
+#[cfg(feature = "kerberos")]
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use sha2::{Digest, Sha256};
+use sspi::{AuthIdentity, Secret, Username};
+
+/// The credentials to authenticate a session with.
+///
+/// Any [`AuthIdentity`] converts into [`Credentials::Password`], so a user name and password
+/// may be passed wherever credentials are expected.
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    /// A user name and password, authenticated using the SSPs enabled in
+    /// [`AuthMethodsConfig`][crate::connection::AuthMethodsConfig].
+    Password(AuthIdentity),
+    /// An anonymous (null) session, authenticated using SPNEGO/NTLM anonymous authentication.
+    ///
+    /// Anonymous sessions are not signed, and may usually access only the `IPC$` share,
+    /// e.g. to [list shares][crate::Client::list_shares] on servers that allow anonymous enumeration.
+    Anonymous,
+    /// A guest session, authenticated as the `Guest` user with an empty password.
+    ///
+    /// Guest sessions are not signed, so servers that require signing reject them.
+    Guest,
+    /// The Kerberos tickets of an existing MIT credential cache, e.g. as obtained by `kinit`.
+    ///
+    /// If no path is specified, the cache set in the `KRB5CCNAME` environment variable is used.
+    /// Only file caches are supported.
+    #[cfg(feature = "kerberos")]
+    KerberosCache(Option<PathBuf>),
+    /// The Kerberos key of `principal` (e.g. `svc-backup@CORP.EXAMPLE.COM`), from a keytab.
+    ///
+    /// If no path is specified, the keytab set in the `KRB5_CLIENT_KTNAME` or `KRB5_KTNAME` environment variables
+    /// is used, falling back to `/etc/krb5.keytab`.
+    /// If the principal has no realm, [`AuthMethodsConfig::realm`][crate::connection::AuthMethodsConfig::realm]
+    /// is used.
+    #[cfg(feature = "kerberos")]
+    KerberosKeytab {
+        principal: String,
+        keytab: Option<PathBuf>,
+    },
+}
+
+impl From<AuthIdentity> for Credentials {
+    fn from(value: AuthIdentity) -> Self {
+        Credentials::Password(value)
+    }
+}
+
+impl std::fmt::Display for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Password(identity) => match identity.username.domain_name() {
+                Some(domain) => write!(f, "{}@{domain}", identity.username.account_name()),
+                None => write!(f, "{}", identity.username.account_name()),
+            },
+            Credentials::Anonymous => write!(f, "anonymous"),
+            Credentials::Guest => write!(f, "guest"),
+            #[cfg(feature = "kerberos")]
+            Credentials::KerberosCache(Some(path)) => {
+                write!(f, "credential cache {}", path.display())
+            }
+            #[cfg(feature = "kerberos")]
+            Credentials::KerberosCache(None) => write!(f, "default credential cache"),
+            #[cfg(feature = "kerberos")]
+            Credentials::KerberosKeytab { principal, .. } => write!(f, "{principal}"),
+        }
+    }
+}
+
+impl Credentials {
+    /// The user name of [`Credentials::Guest`].
+    pub const GUEST_USER_NAME: &str = "Guest";
+
+    /// Returns password credentials for `user_name`, which may be qualified with a domain
+    /// (e.g. `DOMAIN\user` or `user@domain`).
+    pub fn password(user_name: &str, password: String) -> crate::Result<Credentials> {
+        Ok(Credentials::Password(AuthIdentity {
+            username: Username::parse(user_name).map_err(|e| crate::Error::SspiError(e.into()))?,
+            password: Secret::from(password),
+        }))
+    }
+
+    /// Returns the identity the credentials authenticate as, e.g. `alice@corp` for the user `CORP\alice`.
+    ///
+    /// The [`Client`][crate::Client] holds a single session per identity on each connection,
+    /// and identifies the shares it connects by their path and identity.
+    pub fn identity(&self) -> String {
+        self.to_string().to_lowercase()
+    }
+
+    /// Returns a digest of the credentials, including their secret.
+    ///
+    /// Credentials of the same [identity][Credentials::identity] with different secrets
+    /// (e.g. a wrong password) have different digests, so a session set up with one
+    /// is not reused for the other without authenticating them.
+    /// The digest is keyed with a random per-process salt, so it does not outlive the process.
+    pub(crate) fn digest(&self) -> [u8; 32] {
+        static SALT: OnceLock<[u8; 32]> = OnceLock::new();
+        let mut hasher = Sha256::new();
+        hasher.update(SALT.get_or_init(rand::random));
+        hasher.update(self.identity().as_bytes());
+        hasher.update([0]);
+        match self {
+            Credentials::Password(identity) => hasher.update(identity.password.as_ref().as_bytes()),
+            Credentials::Anonymous | Credentials::Guest => {}
+            #[cfg(feature = "kerberos")]
+            Credentials::KerberosCache(path) | Credentials::KerberosKeytab { keytab: path, .. } => {
+                if let Some(path) = path {
+                    hasher.update(path.as_os_str().as_encoded_bytes());
+                }
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    /// Whether the credentials explicitly request an anonymous or guest session,
+    /// which is not signed.
+    pub fn is_anonymous_or_guest(&self) -> bool {
+        matches!(self, Credentials::Anonymous | Credentials::Guest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let down_level = Credentials::password("CORP\\Alice", String::new()).unwrap();
+        let upn = Credentials::password("alice@corp", String::new()).unwrap();
+        assert_eq!(down_level.identity(), "alice@corp");
+        assert_eq!(down_level.identity(), upn.identity());
+        assert_ne!(
+            Credentials::Guest.identity(),
+            Credentials::Anonymous.identity()
+        );
+    }
+
+    #[test]
+    fn test_digest() {
+        let alice = Credentials::password("CORP\\alice", "secret".to_string()).unwrap();
+        let same = Credentials::password("alice@corp", "secret".to_string()).unwrap();
+        let wrong = Credentials::password("CORP\\alice", "Secret".to_string()).unwrap();
+        assert_eq!(alice.identity(), wrong.identity());
+        assert_eq!(alice.digest(), alice.clone().digest());
+        assert_eq!(alice.digest(), same.digest());
+        assert_ne!(alice.digest(), wrong.digest());
+    }
+}
@@ -0,0 +1,994 @@
+//! Kerberos authentication from credential caches and keytabs.
+//!
+//! [`sspi`] authenticates with passwords only, so for [`Credentials::KerberosCache`]
+//! and [`Credentials::KerberosKeytab`], tickets are obtained here, and the AP-REQ
+//! is sent to the server in the initial SPNEGO token. If mutual authentication is required,
+//! the AP-REP of the server is verified when it arrives, along with the final session setup response.
+//! Only the AES encryption types are supported.
+
+use std::fmt::Display;
+use std::sync::Arc;
+
+use maybe_async::maybe_async;
+use picky_asn1::bit_string::BitString;
+use picky_asn1::date::GeneralizedTime;
+use picky_asn1::restricted_string::Ia5String;
+use picky_asn1::wrapper::*;
+use picky_asn1_x509::oids;
+use picky_krb::constants::gss_api::{
+    AP_REP_TOKEN_ID, AP_REQ_TOKEN_ID, AUTHENTICATOR_CHECKSUM_TYPE,
+};
+use picky_krb::constants::key_usages::{AP_REP_ENC, AP_REQ_AUTHENTICATOR};
+use picky_krb::constants::types::{AP_REQ_MSG_TYPE, NT_PRINCIPAL, NT_SRV_INST};
+use picky_krb::crypto::CipherSuite;
+use picky_krb::data_types::{
+    Authenticator, AuthenticatorInner, Checksum, EncApRepPart, EncryptedData, EncryptionKey,
+    KerberosFlags, KerberosStringAsn1, KerberosTime, PrincipalName, Realm, Ticket,
+};
+use picky_krb::gss_api::{
+    ApplicationTag0, GssApiNegInit, KrbMessage, MechTypeList, NegTokenInit, NegTokenTarg1,
+};
+use picky_krb::messages::{ApRep, ApReq, ApReqInner};
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+use super::Credentials;
+use super::spn;
+use crate::Error;
+use crate::connection::connection_info::ConnectionInfo;
+
+mod ccache;
+mod kdc;
+mod keytab;
+
+use ccache::CredentialCache;
+use kdc::KdcClient;
+pub(crate) use kdc::detect_kdc_url;
+use keytab::Keytab;
+
+const KERBEROS_VERSION: u8 = 5;
+const TGS_SERVICE_NAME: &str = "krbtgt";
+/// GSS_C_CONF_FLAG | GSS_C_INTEG_FLAG
+const GSS_FLAGS: u32 = 0x10 | 0x20;
+const GSS_C_MUTUAL_FLAG: u32 = 0x02;
+/// The mutual-required AP option.
+const AP_OPTIONS_MUTUAL_REQUIRED: [u8; 4] = [0x20, 0, 0, 0];
+
+/// Authenticates using Kerberos tickets obtained from a credential cache or a keytab.
+#[derive(Debug)]
+pub struct KerberosAuthenticator {
+    client: Principal,
+    source: TicketSource,
+    service: Principal,
+    kdc_url: Option<String>,
+    timeout: std::time::Duration,
+    mutual_auth: bool,
+
+    /// The AP-REQ that was sent, while waiting for the AP-REP of the server.
+    pending_ap_rep: Option<PendingApRep>,
+    session_key: Option<SessionKey>,
+}
+
+/// The keys and time of an AP-REQ, required to verify the AP-REP of the server.
+#[derive(Debug)]
+struct PendingApRep {
+    ticket_key: SessionKey,
+    subkey: SessionKey,
+    ctime: KerberosTime,
+    cusec: u32,
+}
+
+#[derive(Debug)]
+enum TicketSource {
+    /// The valid tickets of a credential cache.
+    Cache(Vec<TicketCredential>),
+    /// The long-term key of the client, from a keytab.
+    Keytab(SessionKey),
+}
+
+#[maybe_async]
+impl KerberosAuthenticator {
+    pub async fn build(
+        credentials: &Credentials,
+        conn_info: &Arc<ConnectionInfo>,
+    ) -> crate::Result<Self> {
+        let auth_methods = &conn_info.config.auth_methods;
+        let (client, source) = match credentials {
+            Credentials::KerberosCache(path) => {
+                let path = match path {
+                    Some(path) => path.clone(),
+                    None => CredentialCache::default_path()?,
+                };
+                let cache = CredentialCache::load(&path)?;
+                let client = Principal::from(&cache.default_principal);
+                let now = time::OffsetDateTime::now_utc().unix_timestamp();
+                let tickets = cache
+                    .tickets()
+                    .filter(|c| c.end_time as i64 > now)
+                    .filter_map(|c| match TicketCredential::from_cache(c) {
+                        Ok(ticket) => Some(ticket),
+                        Err(e) => {
+                            log::debug!("Skipping cached ticket for {:?}: {e}", c.server);
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                (client, TicketSource::Cache(tickets))
+            }
+            Credentials::KerberosKeytab { principal, keytab } => {
+                let client = Principal::parse(principal, auth_methods.realm.as_deref())?;
+                let path = match keytab {
+                    Some(path) => path.clone(),
+                    None => Keytab::default_path()?,
+                };
+                // Use the strongest key of the latest version.
+                let key = Keytab::load(&path)?
+                    .entries
+                    .iter()
+                    .filter(|e| e.principal().matches(&client))
+                    .filter_map(|e| {
+                        SessionKey::new(e.key_type as u32, e.key.clone())
+                            .ok()
+                            .map(|key| (e.kvno(), key))
+                    })
+                    .max_by_key(|(kvno, key)| (*kvno, u32::from(&key.cipher)))
+                    .map(|(_, key)| key)
+                    .ok_or_else(|| {
+                        Error::KerberosError(format!(
+                            "No AES key for {client} in keytab {}",
+                            path.display()
+                        ))
+                    })?;
+                (client, TicketSource::Keytab(key))
+            }
+            Credentials::Password(_) | Credentials::Anonymous | Credentials::Guest => {
+                return Err(Error::InvalidArgument(format!(
+                    "Credentials of {credentials} are not authenticated using Kerberos"
+                )));
+            }
+        };
+
+        let spn = spn::server_spn(&conn_info.server_name, auth_methods).await;
+        let service = Principal::from_spn(&spn, &client.realm)?;
+        Ok(Self {
+            client,
+            source,
+            service,
+            kdc_url: auth_methods.kdc_url.clone(),
+            timeout: conn_info.config.timeout(),
+            mutual_auth: auth_methods.mutual_auth,
+            pending_ap_rep: None,
+            session_key: None,
+        })
+    }
+
+    pub fn user_name(&self) -> String {
+        self.client.to_string()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.session_key.is_some()
+    }
+
+    pub fn session_key(&self) -> crate::Result<[u8; 16]> {
+        let key = self
+            .session_key
+            .as_ref()
+            .ok_or_else(|| Error::InvalidState("Kerberos authentication is not done.".into()))?;
+        // Use the first 16 bytes of the session key.
+        Ok(key.value[..16].try_into().unwrap())
+    }
+
+    /// Returns the initial SPNEGO token, that contains the AP-REQ for the server.
+    /// If mutual authentication is required, the next call verifies the AP-REP in the final token
+    /// of the server, and returns an empty token.
+    ///
+    /// The first input token is ignored, since Kerberos is sent optimistically.
+    pub async fn next(&mut self, gss_token: &[u8]) -> crate::Result<Vec<u8>> {
+        if self.is_authenticated() {
+            return Err(Error::InvalidState("Authentication already done.".into()));
+        }
+
+        match self.pending_ap_rep.take() {
+            Some(pending) => {
+                self.session_key = Some(pending.verify(gss_token)?);
+                log::debug!("Server {} authenticated", self.service);
+                Ok(Vec::new())
+            }
+            None => self.ap_req().await,
+        }
+    }
+
+    async fn ap_req(&mut self) -> crate::Result<Vec<u8>> {
+        let ticket = self.service_ticket().await?;
+        log::debug!("Authenticating as {} to {}", ticket.client, ticket.server);
+
+        let subkey = SessionKey::random(ticket.key.cipher.clone());
+        let mut checksum = Vec::with_capacity(24);
+        // Channel bindings (length, then MD5 hash) are not used.
+        checksum.extend_from_slice(&16u32.to_le_bytes());
+        checksum.extend_from_slice(&[0; 16]);
+        let (flags, ap_options) = if self.mutual_auth {
+            (GSS_FLAGS | GSS_C_MUTUAL_FLAG, AP_OPTIONS_MUTUAL_REQUIRED)
+        } else {
+            (GSS_FLAGS, [0; 4])
+        };
+        checksum.extend_from_slice(&flags.to_le_bytes());
+        let checksum = Checksum {
+            cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(
+                AUTHENTICATOR_CHECKSUM_TYPE.to_vec(),
+            )),
+            checksum: ExplicitContextTag1::from(OctetStringAsn1::from(checksum)),
+        };
+        let authenticator =
+            ticket.authenticator(Some(checksum), Some(&subkey), Some(OsRng.next_u32()))?;
+        let ap_req = ticket.ap_req(&authenticator, AP_REQ_AUTHENTICATOR, ap_options)?;
+
+        let mech_token = ApplicationTag0(KrbMessage {
+            krb5_oid: ObjectIdentifierAsn1::from(oids::krb5()),
+            krb5_token_id: AP_REQ_TOKEN_ID,
+            krb_msg: ap_req,
+        });
+        let token = ApplicationTag0(GssApiNegInit {
+            oid: ObjectIdentifierAsn1::from(oids::spnego()),
+            neg_token_init: ExplicitContextTag0::from(NegTokenInit {
+                mech_types: Optional::from(Some(ExplicitContextTag0::from(MechTypeList::from(
+                    vec![
+                        ObjectIdentifierAsn1::from(oids::krb5()),
+                        ObjectIdentifierAsn1::from(oids::ms_krb5()),
+                    ],
+                )))),
+                req_flags: Optional::from(None),
+                mech_token: Optional::from(Some(ExplicitContextTag2::from(OctetStringAsn1::from(
+                    to_der(&mech_token)?,
+                )))),
+                mech_list_mic: Optional::from(None),
+            }),
+        });
+
+        if self.mutual_auth {
+            self.pending_ap_rep = Some(PendingApRep {
+                ticket_key: ticket.key,
+                subkey,
+                ctime: authenticator.0.ctime.0.clone(),
+                cusec: integer_value(&authenticator.0.cusec.0),
+            });
+        } else {
+            // Without mutual authentication, the session key is the subkey of the authenticator.
+            self.session_key = Some(subkey);
+        }
+        to_der(&token)
+    }
+
+    /// Returns a ticket for the server: a cached one, or a new one from the KDC.
+    async fn service_ticket(&self) -> crate::Result<TicketCredential> {
+        match &self.source {
+            TicketSource::Cache(tickets) => {
+                if let Some(ticket) = tickets.iter().find(|t| t.server.matches(&self.service)) {
+                    return Ok(ticket.clone());
+                }
+                let tgs = Principal::tgs(&self.client.realm);
+                let tgt = tickets
+                    .iter()
+                    .find(|t| t.server.matches(&tgs))
+                    .ok_or_else(|| {
+                        Error::KerberosError(format!(
+                            "No valid ticket for {} or {tgs} in credential cache",
+                            self.service
+                        ))
+                    })?;
+                self.kdc().await?.tgs_exchange(tgt, &self.service).await
+            }
+            TicketSource::Keytab(key) => {
+                let kdc = self.kdc().await?;
+                let tgt = kdc.as_exchange(&self.client, key).await?;
+                kdc.tgs_exchange(&tgt, &self.service).await
+            }
+        }
+    }
+
+    async fn kdc(&self) -> crate::Result<KdcClient> {
+        KdcClient::new(self.kdc_url.as_deref(), &self.client.realm, self.timeout).await
+    }
+}
+
+/// A Kerberos principal name, with its realm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    name_type: u32,
+    components: Vec<String>,
+    realm: String,
+}
+
+impl Principal {
+    /// Parses a principal name, e.g. `user@REALM` or `service/host@REALM`.
+    pub fn parse(name: &str, default_realm: Option<&str>) -> crate::Result<Self> {
+        let (name, realm) = match name.rsplit_once('@') {
+            Some((name, realm)) => (name, realm),
+            None => (
+                name,
+                default_realm.ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "Principal {name} has no realm, and no default realm is configured"
+                    ))
+                })?,
+            ),
+        };
+        if name.is_empty() || realm.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid principal name: {name}@{realm}"
+            )));
+        }
+        Ok(Self {
+            name_type: NT_PRINCIPAL as u32,
+            components: name.split('/').map(str::to_string).collect(),
+            realm: realm.to_string(),
+        })
+    }
+
+    /// Returns the principal of the ticket-granting service of `realm`.
+    fn tgs(realm: &str) -> Self {
+        Self::service(TGS_SERVICE_NAME, realm, realm)
+    }
+
+    /// Returns the principal of a service, from its SPN (`service/host`, optionally with a realm).
+    fn from_spn(spn: &str, default_realm: &str) -> crate::Result<Self> {
+        Ok(Self {
+            name_type: NT_SRV_INST as u32,
+            ..Self::parse(spn, Some(default_realm))?
+        })
+    }
+
+    fn service(service: &str, host: &str, realm: &str) -> Self {
+        Self {
+            name_type: NT_SRV_INST as u32,
+            components: vec![service.to_string(), host.to_string()],
+            realm: realm.to_string(),
+        }
+    }
+
+    fn is_tgs(&self) -> bool {
+        self.components
+            .first()
+            .is_some_and(|c| c == TGS_SERVICE_NAME)
+    }
+
+    /// Compares the names of the principals, ignoring their types.
+    /// Host names and realms are compared case-insensitively.
+    fn matches(&self, other: &Self) -> bool {
+        self.realm.eq_ignore_ascii_case(&other.realm)
+            && self.components.len() == other.components.len()
+            && self
+                .components
+                .iter()
+                .zip(&other.components)
+                .enumerate()
+                .all(|(i, (a, b))| {
+                    if i == 0 {
+                        a == b
+                    } else {
+                        a.eq_ignore_ascii_case(b)
+                    }
+                })
+    }
+
+    /// Compares the names of the principals case-insensitively, ignoring their types.
+    fn matches_ignore_case(&self, other: &Self) -> bool {
+        self.realm.eq_ignore_ascii_case(&other.realm)
+            && self.components.len() == other.components.len()
+            && self
+                .components
+                .iter()
+                .zip(&other.components)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    fn from_asn1(name: &PrincipalName, realm: &Realm) -> Self {
+        Self {
+            name_type: integer_value(&name.name_type.0),
+            components: name
+                .name_string
+                .0
+                .iter()
+                .map(|c| c.0.as_utf8().to_string())
+                .collect(),
+            realm: realm.0.as_utf8().to_string(),
+        }
+    }
+
+    fn to_asn1(&self) -> crate::Result<PrincipalName> {
+        Ok(PrincipalName {
+            name_type: ExplicitContextTag0::from(integer(self.name_type)),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(
+                self.components
+                    .iter()
+                    .map(|c| kerberos_string(c))
+                    .collect::<crate::Result<Vec<_>>>()?,
+            )),
+        })
+    }
+}
+
+impl Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.components.join("/"), self.realm)
+    }
+}
+
+/// A Kerberos key, of one of the supported (AES) encryption types.
+#[derive(Clone)]
+struct SessionKey {
+    cipher: CipherSuite,
+    value: Vec<u8>,
+}
+
+impl std::fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionKey")
+            .field("cipher", &self.cipher)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionKey {
+    fn new(key_type: u32, value: Vec<u8>) -> crate::Result<Self> {
+        let cipher = match CipherSuite::try_from(key_type as usize) {
+            Ok(cipher @ (CipherSuite::Aes256CtsHmacSha196 | CipherSuite::Aes128CtsHmacSha196)) => {
+                cipher
+            }
+            _ => {
+                return Err(Error::UnsupportedOperation(format!(
+                    "Kerberos encryption type {key_type} is not supported"
+                )));
+            }
+        };
+        if value.len() != cipher.cipher().key_size() {
+            return Err(Error::KerberosError(format!(
+                "Invalid key size {} for {cipher:?}",
+                value.len()
+            )));
+        }
+        Ok(Self { cipher, value })
+    }
+
+    fn random(cipher: CipherSuite) -> Self {
+        let mut value = vec![0; cipher.cipher().key_size()];
+        OsRng.fill_bytes(&mut value);
+        Self { cipher, value }
+    }
+
+    fn from_asn1(key: &EncryptionKey) -> crate::Result<Self> {
+        Self::new(integer_value(&key.key_type.0), key.key_value.0.0.clone())
+    }
+
+    fn to_asn1(&self) -> EncryptionKey {
+        EncryptionKey {
+            key_type: ExplicitContextTag0::from(integer(u32::from(&self.cipher))),
+            key_value: ExplicitContextTag1::from(OctetStringAsn1::from(self.value.clone())),
+        }
+    }
+
+    fn encrypt(&self, key_usage: i32, data: &[u8]) -> crate::Result<EncryptedData> {
+        let cipher = self
+            .cipher
+            .cipher()
+            .encrypt(&self.value, key_usage, data)
+            .map_err(krb_error)?;
+        Ok(EncryptedData {
+            etype: ExplicitContextTag0::from(integer(u32::from(&self.cipher))),
+            kvno: Optional::from(None),
+            cipher: ExplicitContextTag2::from(OctetStringAsn1::from(cipher)),
+        })
+    }
+
+    fn decrypt(&self, key_usage: i32, data: &EncryptedData) -> crate::Result<Vec<u8>> {
+        if integer_value(&data.etype.0) != u32::from(&self.cipher) {
+            return Err(Error::KerberosError(format!(
+                "Unexpected encryption type {}, expected {:?}",
+                integer_value(&data.etype.0),
+                self.cipher
+            )));
+        }
+        self.cipher
+            .cipher()
+            .decrypt(&self.value, key_usage, &data.cipher.0.0)
+            .map_err(krb_error)
+    }
+
+    fn checksum(&self, key_usage: i32, data: &[u8]) -> crate::Result<Checksum> {
+        let checksum_type = self.cipher.cipher().checksum_type();
+        let checksum = checksum_type
+            .hasher()
+            .checksum(&self.value, key_usage, data)
+            .map_err(krb_error)?;
+        Ok(Checksum {
+            cksumtype: ExplicitContextTag0::from(integer(u32::from(&checksum_type))),
+            checksum: ExplicitContextTag1::from(OctetStringAsn1::from(checksum)),
+        })
+    }
+}
+
+/// A ticket, with its session key.
+#[derive(Debug, Clone)]
+struct TicketCredential {
+    client: Principal,
+    server: Principal,
+    key: SessionKey,
+    ticket: Ticket,
+}
+
+impl TicketCredential {
+    fn from_cache(credential: &ccache::CachedCredential) -> crate::Result<Self> {
+        Ok(Self {
+            client: Principal::from(&credential.client),
+            server: Principal::from(&credential.server),
+            key: SessionKey::new(credential.key_type as u32, credential.key.data.clone())?,
+            ticket: picky_asn1_der::from_bytes(&credential.ticket.data).map_err(krb_error)?,
+        })
+    }
+
+    fn authenticator(
+        &self,
+        checksum: Option<Checksum>,
+        subkey: Option<&SessionKey>,
+        seq_number: Option<u32>,
+    ) -> crate::Result<Authenticator> {
+        let now = time::OffsetDateTime::now_utc();
+        Ok(Authenticator::from(AuthenticatorInner {
+            authenticator_vno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            crealm: ExplicitContextTag1::from(kerberos_string(&self.client.realm)?),
+            cname: ExplicitContextTag2::from(self.client.to_asn1()?),
+            cksum: Optional::from(checksum.map(ExplicitContextTag3::from)),
+            cusec: ExplicitContextTag4::from(microseconds(now)),
+            ctime: ExplicitContextTag5::from(kerberos_time(now)),
+            subkey: Optional::from(subkey.map(|k| ExplicitContextTag6::from(k.to_asn1()))),
+            seq_number: Optional::from(seq_number.map(|n| ExplicitContextTag7::from(integer(n)))),
+            authorization_data: Optional::from(None),
+        }))
+    }
+
+    fn ap_req(
+        &self,
+        authenticator: &Authenticator,
+        key_usage: i32,
+        options: [u8; 4],
+    ) -> crate::Result<ApReq> {
+        Ok(ApReq::from(ApReqInner {
+            pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![AP_REQ_MSG_TYPE])),
+            ap_options: ExplicitContextTag2::from(kerberos_flags(options)),
+            ticket: ExplicitContextTag3::from(self.ticket.clone()),
+            authenticator: ExplicitContextTag4::from(
+                self.key.encrypt(key_usage, &to_der(authenticator)?)?,
+            ),
+        }))
+    }
+}
+
+impl PendingApRep {
+    /// Verifies the AP-REP in the final SPNEGO token of the server,
+    /// and returns the session key: the subkey of the server, if any, or the subkey of the client.
+    fn verify(self, gss_token: &[u8]) -> crate::Result<SessionKey> {
+        let token: NegTokenTarg1 = picky_asn1_der::from_bytes(gss_token).map_err(krb_error)?;
+        let mut response_token = token
+            .0
+            .response_token
+            .0
+            .ok_or_else(|| Error::KerberosError("Server did not reply with an AP-REP".to_string()))?
+            .0
+            .0;
+        // picky keeps the OCTET STRING header of the optional response token.
+        // GSS tokens begin with an application tag, so the header is unambiguous.
+        if response_token.first() == Some(&0x04) {
+            response_token = picky_asn1_der::from_bytes::<OctetStringAsn1>(&response_token)
+                .map_err(krb_error)?
+                .0;
+        }
+        let message = KrbMessage::<ApRep>::decode_application_krb_message(&response_token)
+            .map_err(krb_error)?
+            .0;
+        if message.krb5_token_id != AP_REP_TOKEN_ID {
+            return Err(Error::KerberosError(format!(
+                "Unexpected GSS token ID {:02x?}, expected an AP-REP",
+                message.krb5_token_id
+            )));
+        }
+
+        let enc_part = self
+            .ticket_key
+            .decrypt(AP_REP_ENC, &message.krb_msg.0.enc_part.0)?;
+        let enc_part: EncApRepPart = picky_asn1_der::from_bytes(&enc_part).map_err(krb_error)?;
+        if enc_part.0.ctime.0 != self.ctime || integer_value(&enc_part.0.cusec.0) != self.cusec {
+            return Err(Error::KerberosError(
+                "AP-REP does not match the AP-REQ; The server failed mutual authentication"
+                    .to_string(),
+            ));
+        }
+        match enc_part.0.subkey.0 {
+            Some(subkey) => SessionKey::from_asn1(&subkey.0),
+            None => Ok(self.subkey),
+        }
+    }
+}
+
+fn krb_error(e: impl Display) -> Error {
+    Error::KerberosError(e.to_string())
+}
+
+fn to_der<T: serde::Serialize>(value: &T) -> crate::Result<Vec<u8>> {
+    picky_asn1_der::to_vec(value).map_err(krb_error)
+}
+
+fn integer(value: u32) -> IntegerAsn1 {
+    IntegerAsn1::from_bytes_be_unsigned(value.to_be_bytes().to_vec())
+}
+
+fn integer_value(value: &IntegerAsn1) -> u32 {
+    value
+        .as_unsigned_bytes_be()
+        .iter()
+        .fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+fn kerberos_string(value: &str) -> crate::Result<KerberosStringAsn1> {
+    Ia5String::from_string(value.to_string())
+        .map(KerberosStringAsn1::from)
+        .map_err(|_| Error::InvalidArgument(format!("Invalid Kerberos string: {value}")))
+}
+
+fn kerberos_time(time: time::OffsetDateTime) -> KerberosTime {
+    KerberosTime::from(GeneralizedTime::from(time))
+}
+
+fn microseconds(time: time::OffsetDateTime) -> IntegerAsn1 {
+    integer(time.microsecond())
+}
+
+fn kerberos_flags(flags: [u8; 4]) -> KerberosFlags {
+    KerberosFlags::from(BitString::with_bytes(flags.to_vec()))
+}
+
+/// Returns a random, positive nonce.
+fn random_nonce() -> u32 {
+    OsRng.next_u32() & 0x7fff_ffff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use picky_krb::constants::key_usages::{
+        AS_REP_ENC, TGS_REP_ENC_SESSION_KEY, TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR,
+    };
+    use picky_krb::constants::types::{
+        AP_REP_MSG_TYPE, AS_REP_MSG_TYPE, PA_ENC_TIMESTAMP, PA_ENC_TIMESTAMP_KEY_USAGE,
+        PA_TGS_REQ_TYPE, TGS_REP_MSG_TYPE,
+    };
+    use picky_krb::data_types::{EncApRepPartInner, PaEncTsEnc, TicketInner};
+    use picky_krb::gss_api::NegTokenTarg;
+    use picky_krb::messages::{
+        ApRepInner, AsReq, EncAsRepPart, EncKdcRepPart, EncTgsRepPart, KdcRep, KdcReq, TgsReq,
+    };
+
+    fn pending_ap_rep() -> PendingApRep {
+        let now = time::OffsetDateTime::now_utc();
+        PendingApRep {
+            ticket_key: SessionKey::random(CipherSuite::Aes256CtsHmacSha196),
+            subkey: SessionKey::random(CipherSuite::Aes256CtsHmacSha196),
+            ctime: kerberos_time(now),
+            cusec: now.microsecond(),
+        }
+    }
+
+    /// Returns the final SPNEGO token of a server, with an AP-REP for `pending`.
+    fn ap_rep_token(pending: &PendingApRep, cusec: u32, subkey: Option<&SessionKey>) -> Vec<u8> {
+        let enc_part = EncApRepPart::from(EncApRepPartInner {
+            ctime: ExplicitContextTag0::from(pending.ctime.clone()),
+            cusec: ExplicitContextTag1::from(integer(cusec)),
+            subkey: Optional::from(subkey.map(|k| ExplicitContextTag2::from(k.to_asn1()))),
+            seq_number: Optional::from(None),
+        });
+        let ap_rep = ApRep::from(ApRepInner {
+            pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![AP_REP_MSG_TYPE])),
+            enc_part: ExplicitContextTag2::from(
+                pending
+                    .ticket_key
+                    .encrypt(AP_REP_ENC, &to_der(&enc_part).unwrap())
+                    .unwrap(),
+            ),
+        });
+        let message = ApplicationTag0(KrbMessage {
+            krb5_oid: ObjectIdentifierAsn1::from(oids::krb5()),
+            krb5_token_id: AP_REP_TOKEN_ID,
+            krb_msg: ap_rep,
+        });
+        to_der(&NegTokenTarg1::from(NegTokenTarg {
+            neg_result: Optional::from(None),
+            supported_mech: Optional::from(None),
+            response_token: Optional::from(Some(ExplicitContextTag2::from(OctetStringAsn1::from(
+                to_der(&message).unwrap(),
+            )))),
+            mech_list_mic: Optional::from(None),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_ap_rep() {
+        let pending = pending_ap_rep();
+        let server_subkey = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+        let token = ap_rep_token(&pending, pending.cusec, Some(&server_subkey));
+        assert_eq!(pending.verify(&token).unwrap().value, server_subkey.value);
+
+        // Without a server subkey, the subkey of the client is used.
+        let pending = pending_ap_rep();
+        let client_subkey = pending.subkey.value.clone();
+        let token = ap_rep_token(&pending, pending.cusec, None);
+        assert_eq!(pending.verify(&token).unwrap().value, client_subkey);
+    }
+
+    #[test]
+    fn test_verify_ap_rep_mismatch() {
+        let pending = pending_ap_rep();
+        let token = ap_rep_token(&pending, pending.cusec.wrapping_add(1), None);
+        assert!(matches!(
+            pending.verify(&token),
+            Err(Error::KerberosError(_))
+        ));
+
+        // Encrypted with another key.
+        let pending = pending_ap_rep();
+        let token = ap_rep_token(&pending_ap_rep(), pending.cusec, None);
+        assert!(pending.verify(&token).is_err());
+    }
+
+    const REALM: &str = "CORP.EXAMPLE.COM";
+
+    /// Changes a KDC reply before it is encrypted and sent.
+    type Tamper = fn(&mut KdcRep, &mut EncKdcRepPart);
+
+    /// A KDC serving an AS exchange, and then a TGS exchange, on a loopback port.
+    ///
+    /// The KDC checks the pre-authentication of the client, and the authenticator of the TGS request.
+    struct FakeKdc {
+        address: String,
+        /// The session key of the issued service ticket.
+        service_key: SessionKey,
+    }
+
+    impl FakeKdc {
+        fn start(client_key: SessionKey, tamper: Tamper) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let tgt_key = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+            let service_key = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+            let kdc_service_key = service_key.clone();
+            std::thread::spawn(move || {
+                let mut stream = listener.accept().unwrap().0;
+                let request = Self::receive(&mut stream);
+                let request = picky_asn1_der::from_bytes::<AsReq>(&request).unwrap().0;
+                let timestamp = Self::pa_data(&request, &PA_ENC_TIMESTAMP);
+                let timestamp = client_key
+                    .decrypt(
+                        PA_ENC_TIMESTAMP_KEY_USAGE,
+                        &picky_asn1_der::from_bytes(&timestamp).unwrap(),
+                    )
+                    .unwrap();
+                picky_asn1_der::from_bytes::<PaEncTsEnc>(&timestamp).unwrap();
+                let client = request.req_body.0.cname.0.clone().unwrap().0;
+                let reply = Self::reply(&request, client, &client_key, &tgt_key, tamper, true);
+                Self::send(&mut stream, &reply);
+
+                let mut stream = listener.accept().unwrap().0;
+                let request = Self::receive(&mut stream);
+                let request = picky_asn1_der::from_bytes::<TgsReq>(&request).unwrap().0;
+                let ap_req = Self::pa_data(&request, &PA_TGS_REQ_TYPE);
+                let ap_req = picky_asn1_der::from_bytes::<ApReq>(&ap_req).unwrap().0;
+                let authenticator = tgt_key
+                    .decrypt(
+                        TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR,
+                        &ap_req.authenticator.0,
+                    )
+                    .unwrap();
+                let authenticator =
+                    picky_asn1_der::from_bytes::<Authenticator>(&authenticator).unwrap();
+                let client = authenticator.0.cname.0;
+                let reply =
+                    Self::reply(&request, client, &tgt_key, &kdc_service_key, tamper, false);
+                Self::send(&mut stream, &reply);
+            });
+            Self {
+                address,
+                service_key,
+            }
+        }
+
+        fn pa_data(request: &KdcReq, padata_type: &[u8]) -> Vec<u8> {
+            let padata = request.padata.0.as_ref().unwrap();
+            let padata = padata
+                .0
+                .0
+                .iter()
+                .find(|p| p.padata_type.0.as_unsigned_bytes_be() == padata_type)
+                .unwrap();
+            padata.padata_data.0.0.clone()
+        }
+
+        /// Returns the reply to a request for `client`, whose encrypted part is encrypted
+        /// with `reply_key`, and contains the new `session_key`.
+        fn reply(
+            request: &KdcReq,
+            client: PrincipalName,
+            reply_key: &SessionKey,
+            session_key: &SessionKey,
+            tamper: Tamper,
+            as_rep: bool,
+        ) -> Vec<u8> {
+            let body = &request.req_body.0;
+            let sname = body.sname.0.as_ref().unwrap().0.clone();
+            let now = time::OffsetDateTime::now_utc();
+            let ticket = Ticket::from(TicketInner {
+                tkt_vno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+                realm: ExplicitContextTag1::from(body.realm.0.clone()),
+                sname: ExplicitContextTag2::from(sname.clone()),
+                enc_part: ExplicitContextTag3::from(session_key.encrypt(2, b"ticket").unwrap()),
+            });
+            let mut enc_part = EncKdcRepPart {
+                key: ExplicitContextTag0::from(session_key.to_asn1()),
+                last_req: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![])),
+                nonce: ExplicitContextTag2::from(body.nonce.0.clone()),
+                key_expiration: Optional::from(None),
+                flags: ExplicitContextTag4::from(kerberos_flags([0; 4])),
+                auth_time: ExplicitContextTag5::from(kerberos_time(now)),
+                start_time: Optional::from(None),
+                end_time: ExplicitContextTag7::from(kerberos_time(now + time::Duration::hours(1))),
+                renew_till: Optional::from(None),
+                srealm: ExplicitContextTag9::from(body.realm.0.clone()),
+                sname: ExplicitContextTag10::from(sname),
+                caadr: Optional::from(None),
+                encrypted_pa_data: Optional::from(None),
+            };
+            let mut reply = KdcRep {
+                pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+                msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![if as_rep {
+                    AS_REP_MSG_TYPE
+                } else {
+                    TGS_REP_MSG_TYPE
+                }])),
+                padata: Optional::from(None),
+                crealm: ExplicitContextTag3::from(body.realm.0.clone()),
+                cname: ExplicitContextTag4::from(client),
+                ticket: ExplicitContextTag5::from(ticket),
+                enc_part: ExplicitContextTag6::from(session_key.encrypt(0, b"").unwrap()),
+            };
+            tamper(&mut reply, &mut enc_part);
+            let enc_part = if as_rep {
+                let enc_part = to_der(&EncAsRepPart::from(enc_part)).unwrap();
+                reply_key.encrypt(AS_REP_ENC, &enc_part).unwrap()
+            } else {
+                let enc_part = to_der(&EncTgsRepPart::from(enc_part)).unwrap();
+                reply_key
+                    .encrypt(TGS_REP_ENC_SESSION_KEY, &enc_part)
+                    .unwrap()
+            };
+            reply.enc_part = ExplicitContextTag6::from(enc_part);
+            if as_rep {
+                to_der(&picky_krb::messages::AsRep::from(reply)).unwrap()
+            } else {
+                to_der(&picky_krb::messages::TgsRep::from(reply)).unwrap()
+            }
+        }
+
+        fn receive(stream: &mut std::net::TcpStream) -> Vec<u8> {
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut request = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut request).unwrap();
+            request
+        }
+
+        fn send(stream: &mut std::net::TcpStream, reply: &[u8]) {
+            stream
+                .write_all(&(reply.len() as u32).to_be_bytes())
+                .unwrap();
+            stream.write_all(reply).unwrap();
+        }
+    }
+
+    /// Returns an authenticator of `user@REALM`, using the key as if it came from a keytab.
+    fn keytab_authenticator(kdc: &FakeKdc, client_key: &SessionKey) -> KerberosAuthenticator {
+        KerberosAuthenticator {
+            client: Principal::parse("user", Some(REALM)).unwrap(),
+            source: TicketSource::Keytab(client_key.clone()),
+            service: Principal::from_spn("cifs/fs01.corp.example.com", REALM).unwrap(),
+            kdc_url: Some(kdc.address.clone()),
+            timeout: std::time::Duration::from_secs(5),
+            mutual_auth: true,
+            pending_ap_rep: None,
+            session_key: None,
+        }
+    }
+
+    /// Returns the authenticator of the AP-REQ in the initial SPNEGO token of a client.
+    fn accept_ap_req(token: &[u8], service_key: &SessionKey) -> Authenticator {
+        let token: ApplicationTag0<GssApiNegInit> = picky_asn1_der::from_bytes(token).unwrap();
+        let mech_token = token.0.neg_token_init.0.mech_token.0.unwrap().0.0;
+        let message = KrbMessage::<ApReq>::decode_application_krb_message(&mech_token)
+            .unwrap()
+            .0;
+        let authenticator = service_key
+            .decrypt(AP_REQ_AUTHENTICATOR, &message.krb_msg.0.authenticator.0)
+            .unwrap();
+        picky_asn1_der::from_bytes(&authenticator).unwrap()
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_keytab_authentication() {
+        let client_key = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+        let kdc = FakeKdc::start(client_key.clone(), |_, _| {});
+        let mut authenticator = keytab_authenticator(&kdc, &client_key);
+        let token = authenticator.next(&[]).await.unwrap();
+        assert!(!authenticator.is_authenticated());
+
+        let ap_req = accept_ap_req(&token, &kdc.service_key);
+        let pending = PendingApRep {
+            ticket_key: kdc.service_key.clone(),
+            subkey: SessionKey::from_asn1(&ap_req.0.subkey.0.unwrap().0).unwrap(),
+            ctime: ap_req.0.ctime.0,
+            cusec: integer_value(&ap_req.0.cusec.0),
+        };
+        let server_subkey = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+        let token = ap_rep_token(&pending, pending.cusec, Some(&server_subkey));
+        let token = authenticator.next(&token).await.unwrap();
+        assert!(token.is_empty());
+        assert_eq!(
+            authenticator.session_key().unwrap(),
+            server_subkey.value[..16]
+        );
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_kdc_reply_names_mismatch() {
+        let client_key = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+        let kdc = FakeKdc::start(client_key.clone(), |reply, _| {
+            reply.cname.0 = Principal::parse("admin", Some(REALM))
+                .unwrap()
+                .to_asn1()
+                .unwrap();
+        });
+        let result = keytab_authenticator(&kdc, &client_key).next(&[]).await;
+        assert!(matches!(result, Err(Error::KerberosError(_))));
+
+        // The ticket-granting ticket is issued, but the service ticket is for another server.
+        let kdc = FakeKdc::start(client_key.clone(), |_, enc_part| {
+            if !Principal::from_asn1(&enc_part.sname.0, &enc_part.srealm.0).is_tgs() {
+                enc_part.sname.0 = Principal::service("cifs", "evil", REALM).to_asn1().unwrap();
+            }
+        });
+        let result = keytab_authenticator(&kdc, &client_key).next(&[]).await;
+        assert!(matches!(result, Err(Error::KerberosError(_))));
+    }
+
+    #[test]
+    fn test_principal_from_spn() {
+        let principal =
+            Principal::from_spn("cifs/FS01.corp.example.com", "CORP.EXAMPLE.COM").unwrap();
+        assert_eq!(
+            principal.to_string(),
+            "cifs/FS01.corp.example.com@CORP.EXAMPLE.COM"
+        );
+        assert!(principal.matches(&Principal::service(
+            "cifs",
+            "fs01.corp.example.com",
+            "corp.example.com"
+        )));
+        assert!(!principal.matches(&Principal::service(
+            "CIFS",
+            "fs01.corp.example.com",
+            "CORP.EXAMPLE.COM"
+        )));
+    }
+}
@@ -0,0 +1,202 @@
+//! MIT Kerberos credential cache (version 4) parsing.
+//!
+//! See <https://web.mit.edu/kerberos/krb5-devel/doc/formats/ccache_file_format.html>.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use binrw::prelude::*;
+
+use super::Principal;
+use crate::Error;
+
+/// Credentials of this realm are used by MIT to store configuration, rather than tickets.
+const CONFIG_REALM: &str = "X-CACHECONF:";
+
+#[binrw::binread]
+#[derive(Debug, PartialEq, Eq)]
+#[br(big, magic = 0x0504u16)]
+pub struct CredentialCache {
+    #[br(temp)]
+    header_len: u16,
+    #[br(temp, count = header_len)]
+    _header: Vec<u8>,
+    pub default_principal: CachePrincipal,
+    #[br(parse_with = binrw::helpers::until_eof)]
+    pub credentials: Vec<CachedCredential>,
+}
+
+#[binrw::binread]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[br(big)]
+pub struct CachePrincipal {
+    pub name_type: u32,
+    #[br(temp)]
+    count: u32,
+    pub realm: CacheString,
+    #[br(count = count)]
+    pub components: Vec<CacheString>,
+}
+
+#[binrw::binread]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[br(big)]
+pub struct CachedCredential {
+    pub client: CachePrincipal,
+    pub server: CachePrincipal,
+    pub key_type: u16,
+    pub key: CacheData,
+    pub auth_time: u32,
+    pub start_time: u32,
+    pub end_time: u32,
+    pub renew_till: u32,
+    pub is_skey: u8,
+    pub ticket_flags: u32,
+    #[br(temp)]
+    address_count: u32,
+    #[br(count = address_count)]
+    pub addresses: Vec<CacheTypedData>,
+    #[br(temp)]
+    auth_data_count: u32,
+    #[br(count = auth_data_count)]
+    pub auth_data: Vec<CacheTypedData>,
+    /// The DER encoded ticket.
+    pub ticket: CacheData,
+    pub second_ticket: CacheData,
+}
+
+#[binrw::binread]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[br(big)]
+pub struct CacheTypedData {
+    pub data_type: u16,
+    pub data: CacheData,
+}
+
+#[binrw::binread]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[br(big)]
+pub struct CacheData {
+    #[br(temp)]
+    len: u32,
+    #[br(count = len)]
+    pub data: Vec<u8>,
+}
+
+#[binrw::binread]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[br(big)]
+pub struct CacheString {
+    #[br(temp)]
+    len: u32,
+    #[br(count = len, try_map = String::from_utf8)]
+    pub value: String,
+}
+
+impl From<&CachePrincipal> for Principal {
+    fn from(value: &CachePrincipal) -> Self {
+        Principal {
+            name_type: value.name_type,
+            components: value.components.iter().map(|c| c.value.clone()).collect(),
+            realm: value.realm.value.clone(),
+        }
+    }
+}
+
+impl CredentialCache {
+    /// Returns the path of the default credential cache, from the `KRB5CCNAME` environment variable.
+    ///
+    /// Only file caches (`FILE:<path>`, or a plain path) are supported.
+    pub fn default_path() -> crate::Result<PathBuf> {
+        let name = std::env::var("KRB5CCNAME").map_err(|_| {
+            Error::InvalidConfiguration(
+                "No credential cache specified, and KRB5CCNAME is not set".to_string(),
+            )
+        })?;
+        match name.split_once(':') {
+            Some(("FILE", path)) => Ok(PathBuf::from(path)),
+            Some((kind, _)) if !kind.contains('/') => Err(Error::UnsupportedOperation(format!(
+                "Credential cache type {kind} is not supported"
+            ))),
+            _ => Ok(PathBuf::from(name)),
+        }
+    }
+
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        let data = std::fs::read(path)?;
+        Ok(Self::read(&mut Cursor::new(data))?)
+    }
+
+    /// Returns the ticket credentials of the cache, skipping configuration entries.
+    pub fn tickets(&self) -> impl Iterator<Item = &CachedCredential> {
+        self.credentials
+            .iter()
+            .filter(|c| c.server.realm.value != CONFIG_REALM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut data = (value.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(value.as_bytes());
+        data
+    }
+
+    fn principal(realm: &str, components: &[&str]) -> Vec<u8> {
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&(components.len() as u32).to_be_bytes());
+        data.extend_from_slice(&string(realm));
+        for component in components {
+            data.extend_from_slice(&string(component));
+        }
+        data
+    }
+
+    fn credential(server_realm: &str, server: &[&str], ticket: &[u8]) -> Vec<u8> {
+        let mut data = principal("EXAMPLE", &["user"]);
+        data.extend_from_slice(&principal(server_realm, server));
+        data.extend_from_slice(&18u16.to_be_bytes());
+        data.extend_from_slice(&(32u32).to_be_bytes());
+        data.extend_from_slice(&[0x33; 32]);
+        for time in [1, 2, 3, 4] {
+            data.extend_from_slice(&(time as u32).to_be_bytes());
+        }
+        data.push(0);
+        data.extend_from_slice(&0x4000_0000u32.to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&(ticket.len() as u32).to_be_bytes());
+        data.extend_from_slice(ticket);
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    #[test]
+    fn test_parse_ccache() {
+        let mut data = vec![0x05, 0x04, 0x00, 0x0c];
+        // A time offset header tag.
+        data.extend_from_slice(&[0, 1, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&principal("EXAMPLE", &["user"]));
+        data.extend_from_slice(&credential(
+            CONFIG_REALM,
+            &["krb5_ccache_conf_data", "pa_type"],
+            b"2",
+        ));
+        data.extend_from_slice(&credential("EXAMPLE", &["krbtgt", "EXAMPLE"], &[0x61, 0]));
+
+        let cache = CredentialCache::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(
+            Principal::from(&cache.default_principal).to_string(),
+            "user@EXAMPLE"
+        );
+        assert_eq!(cache.credentials.len(), 2);
+        let tickets = cache.tickets().collect::<Vec<_>>();
+        assert_eq!(tickets.len(), 1);
+        assert!(Principal::from(&tickets[0].server).is_tgs());
+        assert_eq!(tickets[0].key.data, vec![0x33; 32]);
+        assert_eq!(tickets[0].end_time, 3);
+        assert_eq!(tickets[0].ticket.data, vec![0x61, 0]);
+    }
+}
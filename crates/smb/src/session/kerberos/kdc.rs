@@ -0,0 +1,500 @@
+//! Ticket requests to the KDC (AS & TGS exchanges), over TCP.
+
+use std::time::Duration;
+
+use maybe_async::*;
+use picky_asn1::wrapper::*;
+use picky_krb::constants::key_usages::{
+    AS_REP_ENC, TGS_REP_ENC_SESSION_KEY, TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR,
+    TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR_CKSUM,
+};
+use picky_krb::constants::types::{
+    AS_REQ_MSG_TYPE, PA_ENC_TIMESTAMP, PA_ENC_TIMESTAMP_KEY_USAGE, PA_PAC_REQUEST_TYPE,
+    PA_TGS_REQ_TYPE, TGS_REQ_MSG_TYPE,
+};
+use picky_krb::crypto::CipherSuite;
+use picky_krb::data_types::{KerbPaPacRequest, KrbResult, PaData, PaEncTsEnc, ResultExt};
+use picky_krb::messages::{
+    AsRep, AsReq, EncAsRepPart, EncKdcRepPart, EncTgsRepPart, KdcRep, KdcReq, KdcReqBody, KrbError,
+    TgsRep, TgsReq,
+};
+use url::Url;
+
+use super::*;
+
+/// The default port of the KDC.
+const KDC_PORT: u16 = 88;
+/// Replies larger than this are considered invalid.
+const MAX_REPLY_SIZE: usize = 0x100000;
+/// Requested ticket lifetime; The KDC may issue shorter lifetimes.
+const TICKET_LIFETIME: time::Duration = time::Duration::days(1);
+/// Canonicalize.
+const KDC_OPTIONS: [u8; 4] = [0x00, 0x01, 0x00, 0x00];
+
+pub struct KdcClient {
+    address: String,
+    timeout: Duration,
+}
+
+#[maybe_async]
+impl KdcClient {
+    /// Creates a client for the KDC of the specified realm.
+    ///
+    /// If `kdc_url` is not set, the KDC is discovered using [`detect_kdc_url`].
+    pub async fn new(kdc_url: Option<&str>, realm: &str, timeout: Duration) -> crate::Result<Self> {
+        let url = match kdc_url {
+            Some(kdc_url) => Self::parse_url(kdc_url)?,
+            None => detect_kdc_url(realm).await.ok_or_else(|| {
+                Error::KerberosError(format!("Unable to find a KDC for realm {realm}"))
+            })?,
+        };
+        if url.scheme() != "tcp" {
+            return Err(Error::UnsupportedOperation(format!(
+                "KDC transport {} is not supported; Use tcp://",
+                url.scheme()
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::InvalidConfiguration(format!("Invalid KDC URL: {url}")))?;
+        Ok(Self {
+            address: format!("{host}:{}", url.port().unwrap_or(KDC_PORT)),
+            timeout,
+        })
+    }
+
+    /// Parses a KDC URL, as set in [`AuthMethodsConfig::kdc_url`][crate::connection::AuthMethodsConfig::kdc_url].
+    pub fn parse_url(kdc_url: &str) -> crate::Result<Url> {
+        if kdc_url.contains("://") {
+            Ok(Url::parse(kdc_url)?)
+        } else {
+            Ok(Url::parse(&format!("tcp://{kdc_url}"))?)
+        }
+    }
+
+    /// Requests a ticket-granting ticket for `client`, pre-authenticating with its long-term key.
+    pub async fn as_exchange(
+        &self,
+        client: &Principal,
+        key: &SessionKey,
+    ) -> crate::Result<TicketCredential> {
+        let nonce = random_nonce();
+        let body = Self::request_body(
+            Some(client),
+            &Principal::tgs(&client.realm),
+            nonce,
+            std::slice::from_ref(&key.cipher),
+        )?;
+
+        let now = time::OffsetDateTime::now_utc();
+        let timestamp = PaEncTsEnc {
+            patimestamp: ExplicitContextTag0::from(kerberos_time(now)),
+            pausec: Optional::from(Some(ExplicitContextTag1::from(microseconds(now)))),
+        };
+        let timestamp = key.encrypt(PA_ENC_TIMESTAMP_KEY_USAGE, &to_der(&timestamp)?)?;
+        let pac_request = KerbPaPacRequest {
+            include_pac: ExplicitContextTag0::from(true),
+        };
+        let request = AsReq::from(KdcReq {
+            pvno: ExplicitContextTag1::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag2::from(IntegerAsn1::from(vec![AS_REQ_MSG_TYPE])),
+            padata: Optional::from(Some(ExplicitContextTag3::from(Asn1SequenceOf::from(vec![
+                pa_data(&PA_ENC_TIMESTAMP, to_der(&timestamp)?),
+                pa_data(&PA_PAC_REQUEST_TYPE, to_der(&pac_request)?),
+            ])))),
+            req_body: ExplicitContextTag4::from(body),
+        });
+
+        let reply = self.send(&to_der(&request)?).await?;
+        let reply: KrbResult<AsRep> =
+            KrbResult::deserialize(&mut picky_asn1_der::Deserializer::new_from_bytes(&reply))
+                .map_err(krb_error)?;
+        let reply = reply.map_err(Self::kdc_error)?;
+        Self::credential(
+            reply.0,
+            key,
+            AS_REP_ENC,
+            nonce,
+            client,
+            &Principal::tgs(&client.realm),
+        )
+    }
+
+    /// Requests a ticket for `service`, using the ticket-granting ticket `tgt`.
+    pub async fn tgs_exchange(
+        &self,
+        tgt: &TicketCredential,
+        service: &Principal,
+    ) -> crate::Result<TicketCredential> {
+        let nonce = random_nonce();
+        let body = Self::request_body(
+            None,
+            service,
+            nonce,
+            &[
+                CipherSuite::Aes256CtsHmacSha196,
+                CipherSuite::Aes128CtsHmacSha196,
+            ],
+        )?;
+
+        // The body is protected by a keyed checksum in the authenticator.
+        let checksum = tgt
+            .key
+            .checksum(TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR_CKSUM, &to_der(&body)?)?;
+        let authenticator = tgt.authenticator(Some(checksum), None, None)?;
+        let ap_req = tgt.ap_req(&authenticator, TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR, [0; 4])?;
+        let request = TgsReq::from(KdcReq {
+            pvno: ExplicitContextTag1::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag2::from(IntegerAsn1::from(vec![TGS_REQ_MSG_TYPE])),
+            padata: Optional::from(Some(ExplicitContextTag3::from(Asn1SequenceOf::from(vec![
+                pa_data(&PA_TGS_REQ_TYPE, to_der(&ap_req)?),
+            ])))),
+            req_body: ExplicitContextTag4::from(body),
+        });
+
+        let reply = self.send(&to_der(&request)?).await?;
+        let reply: KrbResult<TgsRep> =
+            KrbResult::deserialize(&mut picky_asn1_der::Deserializer::new_from_bytes(&reply))
+                .map_err(krb_error)?;
+        let reply = reply.map_err(Self::kdc_error)?;
+        Self::credential(
+            reply.0,
+            &tgt.key,
+            TGS_REP_ENC_SESSION_KEY,
+            nonce,
+            &tgt.client,
+            service,
+        )
+    }
+
+    fn request_body(
+        client: Option<&Principal>,
+        server: &Principal,
+        nonce: u32,
+        ciphers: &[CipherSuite],
+    ) -> crate::Result<KdcReqBody> {
+        let till = time::OffsetDateTime::now_utc() + TICKET_LIFETIME;
+        Ok(KdcReqBody {
+            kdc_options: ExplicitContextTag0::from(kerberos_flags(KDC_OPTIONS)),
+            cname: Optional::from(
+                client
+                    .map(|c| c.to_asn1().map(ExplicitContextTag1::from))
+                    .transpose()?,
+            ),
+            realm: ExplicitContextTag2::from(kerberos_string(&server.realm)?),
+            sname: Optional::from(Some(ExplicitContextTag3::from(server.to_asn1()?))),
+            from: Optional::from(None),
+            till: ExplicitContextTag5::from(kerberos_time(till)),
+            rtime: Optional::from(None),
+            nonce: ExplicitContextTag7::from(integer(nonce)),
+            etype: ExplicitContextTag8::from(Asn1SequenceOf::from(
+                ciphers
+                    .iter()
+                    .map(|c| integer(u32::from(c)))
+                    .collect::<Vec<_>>(),
+            )),
+            addresses: Optional::from(None),
+            enc_authorization_data: Optional::from(None),
+            additional_tickets: Optional::from(None),
+        })
+    }
+
+    fn kdc_error(error: KrbError) -> Error {
+        let text = error
+            .0
+            .e_text
+            .0
+            .as_ref()
+            .map(|t| format!(" ({})", t.0.0.as_utf8()))
+            .unwrap_or_default();
+        Error::KerberosError(format!("KDC returned error {}{text}", error.0.error_code.0))
+    }
+
+    /// Decrypts the encrypted part of a KDC reply, and returns the issued ticket,
+    /// after checking that it was issued to `client`, for `server`.
+    fn credential(
+        reply: KdcRep,
+        reply_key: &SessionKey,
+        key_usage: i32,
+        nonce: u32,
+        client: &Principal,
+        server: &Principal,
+    ) -> crate::Result<TicketCredential> {
+        let enc_part = reply_key.decrypt(key_usage, &reply.enc_part.0)?;
+        // Some KDCs tag the encrypted part of TGS replies as AS replies (and vice versa).
+        let enc_part: EncKdcRepPart = match picky_asn1_der::from_bytes::<EncTgsRepPart>(&enc_part) {
+            Ok(part) => part.0,
+            Err(_) => {
+                picky_asn1_der::from_bytes::<EncAsRepPart>(&enc_part)
+                    .map_err(krb_error)?
+                    .0
+            }
+        };
+        if integer_value(&enc_part.nonce.0) != nonce {
+            return Err(Error::KerberosError(
+                "KDC reply nonce does not match the request".to_string(),
+            ));
+        }
+        let credential = TicketCredential {
+            client: Principal::from_asn1(&reply.cname.0, &reply.crealm.0),
+            server: Principal::from_asn1(&enc_part.sname.0, &enc_part.srealm.0),
+            key: SessionKey::from_asn1(&enc_part.key.0)?,
+            ticket: reply.ticket.0,
+        };
+
+        // The KDC may canonicalize the case of the client name (e.g. Active Directory).
+        if !credential.client.matches_ignore_case(client) {
+            return Err(Error::KerberosError(format!(
+                "KDC reply is for client {}, expected {client}",
+                credential.client
+            )));
+        }
+        if credential.server.is_tgs() && !server.is_tgs() {
+            return Err(Error::UnsupportedOperation(format!(
+                "Cross-realm referral to {} is not supported",
+                credential.server
+            )));
+        }
+        if !credential.server.matches(server) {
+            return Err(Error::KerberosError(format!(
+                "KDC reply is for server {}, expected {server}",
+                credential.server
+            )));
+        }
+        Ok(credential)
+    }
+
+    /// Sends a request to the KDC, and returns the reply.
+    ///
+    /// Over TCP, messages are prefixed with their length.
+    async fn send(&self, request: &[u8]) -> crate::Result<Vec<u8>> {
+        let mut message = (request.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(request);
+
+        log::debug!("Sending Kerberos request to KDC {}", self.address);
+        #[cfg(feature = "async")]
+        let reply = {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let exchange = async {
+                let mut stream = tokio::net::TcpStream::connect(&self.address).await?;
+                stream.write_all(&message).await?;
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).await?;
+                let len = Self::reply_len(len)?;
+                let mut reply = vec![0; len];
+                stream.read_exact(&mut reply).await?;
+                Ok::<_, std::io::Error>(reply)
+            };
+            tokio::time::timeout(self.timeout, exchange)
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??
+        };
+        #[cfg(not(feature = "async"))]
+        let reply = {
+            use std::io::{Read, Write};
+            use std::net::ToSocketAddrs;
+            let address = self.address.to_socket_addrs()?.next().ok_or_else(|| {
+                Error::KerberosError(format!("Unable to resolve KDC {}", self.address))
+            })?;
+            let mut stream = std::net::TcpStream::connect_timeout(&address, self.timeout)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            stream.write_all(&message)?;
+            let mut len = [0; 4];
+            stream.read_exact(&mut len)?;
+            let mut reply = vec![0; Self::reply_len(len)?];
+            stream.read_exact(&mut reply)?;
+            reply
+        };
+        Ok(reply)
+    }
+
+    fn reply_len(len: [u8; 4]) -> std::io::Result<usize> {
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_REPLY_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("KDC reply too large: {len} bytes"),
+            ));
+        }
+        Ok(len)
+    }
+}
+
+/// Discovers the KDC of the realm, using the `SSPI_KDC_URL` environment variables, `krb5.conf`,
+/// or DNS.
+///
+/// The discovery may block on DNS lookups, so in async builds, it runs on a blocking thread.
+#[maybe_async]
+pub async fn detect_kdc_url(realm: &str) -> Option<Url> {
+    #[cfg(feature = "async")]
+    {
+        let realm = realm.to_string();
+        tokio::task::spawn_blocking(move || sspi::detect_kdc_url(&realm))
+            .await
+            .ok()
+            .flatten()
+    }
+    #[cfg(not(feature = "async"))]
+    sspi::detect_kdc_url(realm)
+}
+
+fn pa_data(padata_type: &[u8], data: Vec<u8>) -> PaData {
+    PaData {
+        padata_type: ExplicitContextTag1::from(IntegerAsn1::from(padata_type.to_vec())),
+        padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Shadows the `maybe_async::test` glob import.
+    use picky_krb::constants::types::KRB_ERROR_MSG_TYPE;
+    use picky_krb::messages::KrbErrorInner;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::prelude::v1::test;
+
+    const REALM: &str = "CORP.EXAMPLE.COM";
+    /// KDC_ERR_PREAUTH_FAILED
+    const PREAUTH_FAILED: u32 = 24;
+
+    /// Starts a KDC on a loopback port, that sends `reply` (with its length prefix) to the
+    /// first request, and returns its address, and a handle returning the request.
+    fn serve_once(reply: Vec<u8>) -> (String, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut request = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&reply).unwrap();
+            request
+        });
+        (address, handle)
+    }
+
+    fn with_length(message: Vec<u8>) -> Vec<u8> {
+        let mut data = (message.len() as u32).to_be_bytes().to_vec();
+        data.extend(message);
+        data
+    }
+
+    fn krb_error_reply(error_code: u32, text: Option<&str>) -> Vec<u8> {
+        let error = KrbError::from(KrbErrorInner {
+            pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![KRB_ERROR_MSG_TYPE])),
+            ctime: Optional::from(None),
+            cusec: Optional::from(None),
+            stime: ExplicitContextTag4::from(kerberos_time(time::OffsetDateTime::now_utc())),
+            susec: ExplicitContextTag5::from(integer(0)),
+            error_code: ExplicitContextTag6::from(error_code),
+            crealm: Optional::from(None),
+            cname: Optional::from(None),
+            realm: ExplicitContextTag9::from(kerberos_string(REALM).unwrap()),
+            sname: ExplicitContextTag10::from(Principal::tgs(REALM).to_asn1().unwrap()),
+            e_text: Optional::from(
+                text.map(|text| ExplicitContextTag11::from(kerberos_string(text).unwrap())),
+            ),
+            e_data: Optional::from(None),
+        });
+        with_length(to_der(&error).unwrap())
+    }
+
+    fn client(address: &str) -> KdcClient {
+        KdcClient {
+            address: address.to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn test_request_body() {
+        let client = Principal::parse("user", Some(REALM)).unwrap();
+        let service = Principal::service("cifs", "fs01.corp.example.com", REALM);
+        let body = KdcClient::request_body(
+            Some(&client),
+            &service,
+            1234,
+            &[CipherSuite::Aes256CtsHmacSha196],
+        )
+        .unwrap();
+        let cname = body.cname.0.as_ref().unwrap();
+        assert_eq!(
+            Principal::from_asn1(&cname.0, &body.realm.0),
+            Principal::parse("user", Some(REALM)).unwrap()
+        );
+        assert!(
+            Principal::from_asn1(&body.sname.0.as_ref().unwrap().0, &body.realm.0)
+                .matches(&service)
+        );
+        assert_eq!(integer_value(&body.nonce.0), 1234);
+        let etypes = body.etype.0.0.iter().map(integer_value).collect::<Vec<_>>();
+        assert_eq!(etypes, [u32::from(&CipherSuite::Aes256CtsHmacSha196)]);
+
+        // TGS requests identify the client by their ticket.
+        let body = KdcClient::request_body(None, &service, 1, &[]).unwrap();
+        assert!(body.cname.0.is_none());
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_kdc_error_reply() {
+        let (address, kdc) = serve_once(krb_error_reply(PREAUTH_FAILED, Some("bad key")));
+        let user = Principal::parse("user", Some(REALM)).unwrap();
+        let key = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+        let result = client(&address).as_exchange(&user, &key).await;
+        let Err(Error::KerberosError(message)) = result else {
+            panic!("Expected a Kerberos error, got {result:?}");
+        };
+        assert_eq!(message, "KDC returned error 24 (bad key)");
+
+        // The request was an AS-REQ for the user.
+        let request = kdc.join().unwrap();
+        let request = picky_asn1_der::from_bytes::<AsReq>(&request).unwrap().0;
+        let cname = request.req_body.0.cname.0.unwrap().0;
+        assert_eq!(
+            Principal::from_asn1(&cname, &request.req_body.0.realm.0),
+            user
+        );
+
+        // Without a text.
+        let (address, _kdc) = serve_once(krb_error_reply(PREAUTH_FAILED, None));
+        let result = client(&address).as_exchange(&user, &key).await;
+        assert!(matches!(result, Err(Error::KerberosError(m)) if m == "KDC returned error 24"));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_invalid_reply() {
+        let user = Principal::parse("user", Some(REALM)).unwrap();
+        let key = SessionKey::random(CipherSuite::Aes256CtsHmacSha196);
+
+        let (address, _kdc) = serve_once(with_length(b"not a kerberos message".to_vec()));
+        let result = client(&address).as_exchange(&user, &key).await;
+        assert!(matches!(result, Err(Error::KerberosError(_))));
+
+        // The length of the reply is checked before it is received.
+        let (address, _kdc) = serve_once((MAX_REPLY_SIZE as u32 + 1).to_be_bytes().to_vec());
+        let result = client(&address).as_exchange(&user, &key).await;
+        assert!(
+            matches!(result, Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_new() {
+        let timeout = Duration::from_secs(1);
+        let kdc = KdcClient::new(Some("kdc.corp.example.com"), REALM, timeout)
+            .await
+            .unwrap();
+        assert_eq!(kdc.address, "kdc.corp.example.com:88");
+        let kdc = KdcClient::new(Some("tcp://kdc.corp.example.com:1088"), REALM, timeout)
+            .await
+            .unwrap();
+        assert_eq!(kdc.address, "kdc.corp.example.com:1088");
+        let result = KdcClient::new(Some("udp://kdc.corp.example.com"), REALM, timeout).await;
+        assert!(matches!(result, Err(Error::UnsupportedOperation(_))));
+    }
+}
@@ -0,0 +1,157 @@
+//! MIT Kerberos keytab (version 2) parsing.
+//!
+//! See <https://web.mit.edu/kerberos/krb5-devel/doc/formats/keytab_file_format.html>.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use binrw::prelude::*;
+
+use super::Principal;
+use crate::Error;
+
+const DEFAULT_KEYTAB: &str = "/etc/krb5.keytab";
+
+#[binrw::binread]
+#[derive(Debug, PartialEq, Eq)]
+#[br(big, magic = 0x0502u16)]
+pub struct Keytab {
+    #[br(parse_with = parse_entries)]
+    pub entries: Vec<KeytabEntry>,
+}
+
+#[binrw::binread]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[br(big)]
+pub struct KeytabEntry {
+    #[br(temp)]
+    count: u16,
+    pub realm: KeytabString,
+    #[br(count = count)]
+    pub components: Vec<KeytabString>,
+    pub name_type: u32,
+    pub timestamp: u32,
+    pub kvno8: u8,
+    pub key_type: u16,
+    #[br(temp)]
+    key_len: u16,
+    #[br(count = key_len)]
+    pub key: Vec<u8>,
+    /// Present if there is room for it in the entry; Overrides `kvno8` if set.
+    #[br(try)]
+    pub kvno32: Option<u32>,
+}
+
+#[binrw::binread]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[br(big)]
+pub struct KeytabString {
+    #[br(temp)]
+    len: u16,
+    #[br(count = len, try_map = String::from_utf8)]
+    pub value: String,
+}
+
+/// Reads the size-prefixed entries until the end of the file.
+/// Entries with negative sizes are holes, left by deleted entries.
+#[binrw::parser(reader, endian)]
+fn parse_entries() -> BinResult<Vec<KeytabEntry>> {
+    let mut entries = Vec::new();
+    loop {
+        let size = match i32::read_options(reader, endian, ()) {
+            Ok(size) => size,
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e),
+        };
+        let mut entry = vec![0; size.unsigned_abs() as usize];
+        reader.read_exact(&mut entry)?;
+        if size > 0 {
+            entries.push(KeytabEntry::read_options(
+                &mut Cursor::new(entry),
+                endian,
+                (),
+            )?);
+        }
+    }
+    Ok(entries)
+}
+
+impl KeytabEntry {
+    pub fn kvno(&self) -> u32 {
+        self.kvno32
+            .filter(|&kvno| kvno != 0)
+            .unwrap_or(self.kvno8 as u32)
+    }
+
+    pub fn principal(&self) -> Principal {
+        Principal {
+            name_type: self.name_type,
+            components: self.components.iter().map(|c| c.value.clone()).collect(),
+            realm: self.realm.value.clone(),
+        }
+    }
+}
+
+impl Keytab {
+    /// Returns the path of the default client keytab:
+    /// `KRB5_CLIENT_KTNAME`, `KRB5_KTNAME`, or `/etc/krb5.keytab`, in that order.
+    pub fn default_path() -> crate::Result<PathBuf> {
+        let name = std::env::var("KRB5_CLIENT_KTNAME")
+            .or_else(|_| std::env::var("KRB5_KTNAME"))
+            .unwrap_or_else(|_| DEFAULT_KEYTAB.to_string());
+        match name.split_once(':') {
+            Some(("FILE" | "WRFILE", path)) => Ok(PathBuf::from(path)),
+            Some((kind, _)) if !kind.contains('/') => Err(Error::UnsupportedOperation(format!(
+                "Keytab type {kind} is not supported"
+            ))),
+            _ => Ok(PathBuf::from(name)),
+        }
+    }
+
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        let data = std::fs::read(path)?;
+        Ok(Self::read(&mut Cursor::new(data))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(kvno: u32, key_type: u16, key: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0, 1, 0, 7];
+        entry.extend_from_slice(b"EXAMPLE");
+        entry.extend_from_slice(&[0, 4]);
+        entry.extend_from_slice(b"user");
+        entry.extend_from_slice(&1u32.to_be_bytes());
+        entry.extend_from_slice(&0x6500_0000u32.to_be_bytes());
+        entry.push(kvno as u8);
+        entry.extend_from_slice(&key_type.to_be_bytes());
+        entry.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        entry.extend_from_slice(key);
+        entry.extend_from_slice(&kvno.to_be_bytes());
+        entry
+    }
+
+    #[test]
+    fn test_parse_keytab() {
+        let first = entry(3, 17, &[0x11; 16]);
+        let second = entry(0x104, 18, &[0x22; 32]);
+        let mut data = vec![0x05, 0x02];
+        data.extend_from_slice(&(first.len() as i32).to_be_bytes());
+        data.extend_from_slice(&first);
+        // A hole left by a deleted entry.
+        data.extend_from_slice(&(-8i32).to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&(second.len() as i32).to_be_bytes());
+        data.extend_from_slice(&second);
+
+        let keytab = Keytab::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(keytab.entries.len(), 2);
+        assert_eq!(keytab.entries[0].kvno(), 3);
+        assert_eq!(keytab.entries[0].key, vec![0x11; 16]);
+        assert_eq!(keytab.entries[1].kvno(), 0x104);
+        assert_eq!(keytab.entries[1].key_type, 18);
+        assert_eq!(keytab.entries[1].principal().to_string(), "user@EXAMPLE");
+    }
+}
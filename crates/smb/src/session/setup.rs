@@ -23,6 +23,10 @@ where
     result: Option<Arc<RwLock<SessionAndChannel>>>,
 
     authenticator: Authenticator,
+    /// Whether the session may be an (unsigned) guest or anonymous session.
+    allow_unsigned: bool,
+    /// Whether guest or anonymous access was explicitly requested.
+    anonymous_or_guest: bool,
     upstream: &'a ChannelUpstream,
     conn_info: &'a Arc<ConnectionInfo>,
 
@@ -39,13 +43,15 @@ where
     T: SessionSetupProperties,
 {
     pub async fn new(
-        identity: sspi::AuthIdentity,
+        credentials: Credentials,
         upstream: &'a ChannelUpstream,
         conn_info: &'a Arc<ConnectionInfo>,
         new_channel_id: u32,
         primary_session: Option<&Arc<RwLock<SessionAndChannel>>>,
     ) -> crate::Result<Self> {
-        let authenticator = Authenticator::build(identity, conn_info)?;
+        let anonymous_or_guest = credentials.is_anonymous_or_guest();
+        let allow_unsigned = anonymous_or_guest || conn_info.config.allow_unsigned_guest_access;
+        let authenticator = Authenticator::build(credentials, conn_info).await?;
 
         let mut result = Self {
             last_setup_response: None,
@@ -54,6 +60,8 @@ where
             handler: None,
             preauth_hash: Some(conn_info.preauth_hash.clone()),
             authenticator,
+            allow_unsigned,
+            anonymous_or_guest,
             upstream,
             conn_info,
             channel: None,
@@ -93,15 +101,15 @@ where
     /// by calling impl functions, this function's behavior is modified to support both new sessions and binding to existing sessions.
     pub(crate) async fn setup(&mut self) -> crate::Result<Arc<RwLock<SessionAndChannel>>> {
         log::debug!(
-            "Setting up session for user {} (@{}).",
-            self.authenticator.user_name().account_name(),
-            self.authenticator.user_name().domain_name().unwrap_or("")
+            "Setting up session for user {}.",
+            self.authenticator.user_name()
         );
 
         let result = self._setup_loop().await;
         match result {
             Ok(()) => Ok(self.result.take().unwrap()),
             Err(e) => {
+                let e = self.rejection_error(e);
                 log::error!("Failed to setup session: {}", e);
                 if let Err(ce) = T::error_cleanup(self).await {
                     log::error!("Failed to cleanup after setup error: {}", ce);
@@ -117,6 +125,10 @@ where
     ///
     /// This function loops until the authentication is complete, requesting GSS tokens
     /// and passing them to the server.
+    ///
+    /// The server may complete the setup along with its final GSS token (e.g. a Kerberos AP-REP,
+    /// for mutual authentication), which must be processed before the channel keys are known.
+    /// The final response is therefore verified by this function, once the channel is set up.
     async fn _setup_loop(&mut self) -> crate::Result<()> {
         // While there's a response to process, do so.
         while !self.authenticator.is_authenticated()? {
@@ -126,47 +138,74 @@ where
             };
             let is_auth_done = self.authenticator.is_authenticated()?;
 
-            // If keys are exchanged, set them up, to enable validation of next response!
+            // If keys are exchanged, set them up, so the next response may be verified.
+            // On the first iteration, the session is not known yet; the channel is set up later.
             let request = self.send_setup_request(next_buf).await?;
+            let mut channel_ready = false;
             if is_auth_done {
                 self.preauth_hash = self.preauth_hash.take().unwrap().finish().into();
-                self.make_channel().await?;
+                if self.result.is_some() {
+                    self.make_channel().await?;
+                    channel_ready = true;
+                }
             }
 
-            let response = self.receive_setup_response(request.msg_id).await?;
-            let message_form = response.form;
+            let mut response = self
+                .receive_setup_response(request.msg_id, is_auth_done)
+                .await?;
             let session_id = response.message.header.session_id;
-            let session_setup_response = response.message.content.to_sessionsetup()?;
+            let is_setup_done = response.message.header.status == Status::Success as u32;
+            let session_flags = response.message.content.as_sessionsetup()?.session_flags;
 
             // First iteration: construct a session state object.
-            // TODO: currently, there's a bug which prevents authentication on first attempt
-            // to complete successfully: since we need the session ID to construct the session state,
-            // which is required for channel construction and signature validation,
-            // the first request must arrive here, and then be validated.
             if self.result.is_none() {
                 log::trace!("Creating session state with id {session_id}.");
                 self.set_session(T::init_session(self, session_id).await?)
                     .await?;
             }
 
-            if is_auth_done {
-                // Important: If we did NOT make sure the message's signature is valid,
-                // we should do it now, as long as the session is not anonymous or guest.
-                if !session_setup_response
-                    .session_flags
-                    .is_guest_or_null_session()
-                    && !message_form.signed_or_encrypted()
+            if is_setup_done {
+                if !is_auth_done {
+                    // The final token of the server arrived along with the final response.
+                    let buffer = &response.message.content.as_sessionsetup()?.buffer;
+                    self.authenticator.next(buffer).await?;
+                    if !self.authenticator.is_authenticated()? {
+                        return Err(Error::InvalidState(
+                            "Server completed session setup, but authentication is not complete"
+                                .to_string(),
+                        ));
+                    }
+                    self.preauth_hash = self.preauth_hash.take().unwrap().finish().into();
+                }
+                if !channel_ready {
+                    self.make_channel().await?;
+                }
+
+                // Important: Make sure the message's signature is valid,
+                // as long as the session is not anonymous or guest.
+                self.handler
+                    .as_ref()
+                    .unwrap()
+                    .verify_setup_response(&mut response)
+                    .await?;
+                if !session_flags.is_guest_or_null_session() && !response.form.signed_or_encrypted()
                 {
                     return Err(Error::InvalidMessage(
                         "Expected a signed message!".to_string(),
                     ));
                 }
             } else {
+                if is_auth_done {
+                    return Err(Error::InvalidMessage(
+                        "Authentication is complete, but server requires more processing"
+                            .to_string(),
+                    ));
+                }
                 self.next_preauth_hash(&response.raw);
             }
 
-            self.flags = Some(session_setup_response.session_flags);
-            self.last_setup_response = Some(session_setup_response)
+            self.flags = Some(session_flags);
+            self.last_setup_response = Some(response.message.content.to_sessionsetup()?)
         }
 
         self.flags.ok_or(Error::InvalidState(
@@ -179,6 +218,27 @@ where
         Ok(())
     }
 
+    /// Turns a server's rejection of explicitly requested guest or anonymous access into a clear error.
+    fn rejection_error(&self, error: Error) -> Error {
+        const REJECTION_STATUSES: [u32; 3] = [
+            Status::U32_LOGON_FAILURE,
+            Status::U32_ACCESS_DENIED,
+            Status::U32_ACCOUNT_DISABLED,
+        ];
+        match error {
+            Error::UnexpectedMessageStatus(status) | Error::ReceivedErrorMessage(status, _)
+                if self.anonymous_or_guest && REJECTION_STATUSES.contains(&status) =>
+            {
+                Error::AuthenticationRejected(format!(
+                    "The server does not allow {} sessions ({}).",
+                    self.authenticator.user_name(),
+                    Status::try_display_as_status(status)
+                ))
+            }
+            error => error,
+        }
+    }
+
     async fn set_session(&mut self, session: Arc<RwLock<SessionInfo>>) -> crate::Result<()> {
         let session_id = session.read().await?.id();
         let result = SessionAndChannel::new(session_id, session);
@@ -199,13 +259,19 @@ where
         Ok(())
     }
 
-    async fn receive_setup_response(&mut self, for_msg_id: u64) -> crate::Result<IncomingMessage> {
-        let is_auth_done = self.authenticator.is_authenticated()?;
-
-        let expected_status = if is_auth_done {
+    /// Receives the response to a session setup request.
+    ///
+    /// Final (success) responses are not verified here, but by the setup loop,
+    /// once the channel is set up.
+    async fn receive_setup_response(
+        &mut self,
+        for_msg_id: u64,
+        is_auth_done: bool,
+    ) -> crate::Result<IncomingMessage> {
+        let expected_status: &[Status] = if is_auth_done {
             &[Status::Success]
         } else {
-            &[Status::MoreProcessingRequired]
+            &[Status::MoreProcessingRequired, Status::Success]
         };
 
         let roptions = ReceiveOptions::new()
@@ -221,18 +287,26 @@ where
                 .await?
                 .channel
                 .is_some();
-        let skip_security_validation = !is_auth_done && !channel_set_up;
-        if let Some(handler) = &self.handler {
-            log::trace!(
-                "setup loop: receiving with channel handler; skip_security_validation={skip_security_validation}"
-            );
-            handler
-                .recvo_internal(roptions, skip_security_validation)
-                .await
-        } else {
-            assert!(skip_security_validation);
-            log::trace!("setup loop: receiving with upstream handler");
-            self.upstream.handler.recvo(roptions).await
+        match &self.handler {
+            // When binding, the channel of the existing session is set up, and verifies intermediate responses.
+            Some(handler) if channel_set_up => {
+                log::trace!("setup loop: receiving with channel handler");
+                let mut response = self.upstream.handler.recvo(roptions).await?;
+                if response.message.header.status != Status::Success as u32 {
+                    handler.verify_setup_response(&mut response).await?;
+                }
+                Ok(response)
+            }
+            Some(handler) => {
+                log::trace!(
+                    "setup loop: receiving with channel handler; skipping security validation"
+                );
+                handler.recvo_internal(roptions, true).await
+            }
+            None => {
+                log::trace!("setup loop: receiving with upstream handler");
+                self.upstream.handler.recvo(roptions).await
+            }
         }
     }
 
@@ -461,6 +535,7 @@ impl SessionSetupProperties for SmbSessionNew {
                 &setup.session_key()?,
                 &setup.preauth_hash_value(),
                 setup.conn_info,
+                setup.allow_unsigned,
             )
     }
 
@@ -471,7 +546,11 @@ impl SessionSetupProperties for SmbSessionNew {
         log::trace!("Session setup successful");
         let result = setup.result.as_ref().unwrap().read().await?;
         let mut session = result.session.write().await?;
-        session.ready(setup.flags.unwrap(), setup.conn_info)
+        session.ready(
+            setup.flags.unwrap(),
+            setup.authenticator.auth_mechanism(),
+            setup.conn_info,
+        )
     }
 
     async fn init_session<T>(
@@ -0,0 +1,135 @@
+//! Resolution of the service principal names (SPNs) of servers, for Kerberos authentication.
+
+use std::net::IpAddr;
+
+use dns_lookup::AddrInfoHints;
+use maybe_async::maybe_async;
+
+use crate::connection::{AuthMethodsConfig, SpnCanonicalization};
+
+/// The service class of SMB servers.
+pub const SMB_SERVICE_NAME: &str = "cifs";
+
+/// `AI_CANONNAME`, which has the same value on all supported platforms.
+const AI_CANONNAME: i32 = 0x2;
+
+/// Returns the SPN of the server, in the form `service/host`:
+/// either the configured SPN, or `cifs/<host>`, where `<host>` is derived from the server name
+/// according to the aliases and canonicalization configured in `config`.
+#[maybe_async]
+pub async fn server_spn(server_name: &str, config: &AuthMethodsConfig) -> String {
+    if let Some(spn) = &config.spn {
+        return spn.clone();
+    }
+
+    let host = config
+        .spn_host_aliases
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(server_name))
+        .map_or(server_name, |(_, host)| host.as_str());
+    let host = match config.spn_canonicalization {
+        SpnCanonicalization::None => host.to_string(),
+        SpnCanonicalization::Forward => resolve(host, canonical_name).await.unwrap_or_else(|e| {
+            log::warn!("Failed to canonicalize {host}: {e}. Using it as is.");
+            host.to_string()
+        }),
+        SpnCanonicalization::Reverse => resolve(host, reverse_name).await.unwrap_or_else(|e| {
+            log::warn!("Failed to reverse-resolve {host}: {e}. Using it as is.");
+            host.to_string()
+        }),
+    };
+    log::debug!("Using SPN {SMB_SERVICE_NAME}/{host} for server {server_name}");
+    format!("{SMB_SERVICE_NAME}/{host}")
+}
+
+/// Runs a blocking DNS lookup of `host`, on a blocking thread in async builds.
+#[maybe_async]
+async fn resolve(
+    host: &str,
+    lookup: fn(&str) -> std::io::Result<String>,
+) -> std::io::Result<String> {
+    #[cfg(feature = "async")]
+    {
+        let host = host.to_string();
+        tokio::task::spawn_blocking(move || lookup(&host))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+    #[cfg(not(feature = "async"))]
+    lookup(host)
+}
+
+/// Returns the canonical name of `host`, following CNAME records.
+fn canonical_name(host: &str) -> std::io::Result<String> {
+    if host.parse::<IpAddr>().is_ok() {
+        return Ok(host.to_string());
+    }
+    let hints = AddrInfoHints {
+        flags: AI_CANONNAME,
+        address: 0,
+        socktype: 0,
+        protocol: 0,
+    };
+    dns_lookup::getaddrinfo(Some(host), None, Some(hints))?
+        .filter_map(Result::ok)
+        .find_map(|info| info.canonname)
+        .map(|name| name.to_ascii_lowercase())
+        .ok_or_else(|| std::io::Error::other("No canonical name"))
+}
+
+/// Returns the name the (first) address of `host` reversely resolves to.
+fn reverse_name(host: &str) -> std::io::Result<String> {
+    let address = match host.parse::<IpAddr>() {
+        Ok(address) => address,
+        Err(_) => dns_lookup::lookup_host(host)?
+            .next()
+            .ok_or_else(|| std::io::Error::other("No addresses"))?,
+    };
+    let name = dns_lookup::lookup_addr(&address)?;
+    if name.parse::<IpAddr>().is_ok() {
+        return Err(std::io::Error::other(format!("No name for {address}")));
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_server_spn() {
+        let mut config = AuthMethodsConfig::default();
+        let spn = server_spn("fs01.corp.example.com", &config).await;
+        assert_eq!(spn, "cifs/fs01.corp.example.com");
+
+        config.spn_host_aliases.insert(
+            "Files.Corp.Example.Com".to_string(),
+            "fs01.corp.example.com".to_string(),
+        );
+        config
+            .spn_host_aliases
+            .insert("10.0.0.5".to_string(), "fs02.corp.example.com".to_string());
+        let spn = server_spn("files.corp.example.com", &config).await;
+        assert_eq!(spn, "cifs/fs01.corp.example.com");
+        let spn = server_spn("10.0.0.5", &config).await;
+        assert_eq!(spn, "cifs/fs02.corp.example.com");
+
+        config.spn = Some("cifs/cluster.corp.example.com".to_string());
+        let spn = server_spn("10.0.0.5", &config).await;
+        assert_eq!(spn, "cifs/cluster.corp.example.com");
+    }
+
+    /// Canonicalization of an address does not resolve it, and leaves it as is.
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_server_spn_canonicalization_of_address() {
+        let mut config = AuthMethodsConfig {
+            spn_canonicalization: SpnCanonicalization::Forward,
+            ..Default::default()
+        };
+        let spn = server_spn("127.0.0.1", &config).await;
+        assert_eq!(spn, "cifs/127.0.0.1");
+        config.spn_canonicalization = SpnCanonicalization::Reverse;
+        let spn = server_spn("127.0.0.1", &config).await;
+        assert!(spn.starts_with("cifs/"));
+    }
+}
@@ -7,8 +7,10 @@ use crate::dialects::DialectImpl;
 use crate::connection::connection_info::ConnectionInfo;
 use crate::connection::preauth_hash::PreauthHashValue;
 use crate::crypto::{
-    CryptoError, DerivedKey, KeyToDerive, kbkdf_hmacsha256, make_encrypting_algo, make_signing_algo,
+    CryptoError, DerivedKey, KeyToDerive, SessionKeys, export_session_keys, kbkdf_hmacsha256,
+    make_encrypting_algo, make_signing_algo,
 };
+use crate::security::{AuthMechanism, EncryptionScope, SecurityPosture};
 use smb_msg::{Dialect, EncryptionCipher, SessionFlags, SigningAlgorithmId};
 
 use super::{MessageDecryptor, MessageEncryptor, MessageSigner};
@@ -17,6 +19,7 @@ use super::{MessageDecryptor, MessageEncryptor, MessageSigner};
 struct SessionAlgos {
     encryptor: Option<MessageEncryptor>,
     decryptor: Option<MessageDecryptor>,
+    cipher: Option<EncryptionCipher>,
 }
 
 #[derive(Clone)]
@@ -27,13 +30,14 @@ struct ChannelAlgos {
 /// A factory for creating session and channel algorithms.
 ///
 /// See [`SessionAlgos::new_session`] and [`SessionAlgos::new_channel`].
-struct SessionAlgosFactory;
+pub(crate) struct SessionAlgosFactory;
 impl SessionAlgosFactory {
-    const NO_PREAUTH_HASH_DERIVE_SIGN_CTX: &'static [u8] = b"SmbSign\x00";
-    const NO_PREAUTH_HASH_DERIVE_ENCRYPT_S2C_CTX: &'static [u8] = b"ServerOut\x00";
-    const NO_PREAUTH_HASH_DERIVE_ENCRYPT_C2S_CTX: &'static [u8] = b"ServerIn \x00";
+    pub(crate) const NO_PREAUTH_HASH_DERIVE_SIGN_CTX: &'static [u8] = b"SmbSign\x00";
+    pub(crate) const NO_PREAUTH_HASH_DERIVE_ENCRYPT_S2C_CTX: &'static [u8] = b"ServerOut\x00";
+    pub(crate) const NO_PREAUTH_HASH_DERIVE_ENCRYPT_C2S_CTX: &'static [u8] = b"ServerIn \x00";
 
-    pub fn new_session(
+    fn new_session(
+        session_id: u64,
         session_key: &KeyToDerive,
         preauth_hash: &Option<PreauthHashValue>,
         info: &ConnectionInfo,
@@ -45,26 +49,37 @@ impl SessionAlgosFactory {
             ));
         }
 
-        if cfg!(feature = "__debug-dump-keys") {
-            log::debug!(
-                "Building session algorithms for dialect {:?} with session key {:02x?} and preauth hash {:02x?}",
-                info.negotiation.dialect_rev,
-                session_key,
-                preauth_hash.as_ref().map(|h| h.as_ref())
-            );
+        let cipher_keys = if info.negotiation.dialect_rev.is_smb3() {
+            Self::smb3xx_derive_cipher_keys(&KeyDeriver::new(session_key), info, preauth_hash)?
+        } else {
+            None
+        };
+
+        let keys = SessionKeys {
+            session_id,
+            session_key,
+            cipher: cipher_keys.as_ref().map(|(cipher, _, _)| *cipher),
+            s2c_key: cipher_keys.as_ref().map(|(_, _, dec_key)| dec_key),
+            c2s_key: cipher_keys.as_ref().map(|(_, enc_key, _)| enc_key),
+        };
+        export_session_keys(&info.config.keylog_file, &keys);
+        if let Some(observer) = &info.keys_observer {
+            let signer = Self::new_channel(session_key, preauth_hash, info)?.signer;
+            observer.session_keys(&keys, &signer);
         }
 
         if info.negotiation.dialect_rev.is_smb3() {
-            Self::smb3xx_make_ciphers(session_key, preauth_hash, info)
+            Self::smb3xx_make_ciphers(cipher_keys, info)
         } else {
             Ok(SessionAlgos {
                 encryptor: None,
                 decryptor: None,
+                cipher: None,
             })
         }
     }
 
-    pub fn new_channel(
+    fn new_channel(
         channel_session_key: &KeyToDerive,
         preauth_hash: &Option<PreauthHashValue>,
         info: &ConnectionInfo,
@@ -96,15 +111,11 @@ impl SessionAlgosFactory {
     }
 
     fn smb3xx_make_ciphers(
-        session_key: &KeyToDerive,
-        preauth_hash: &Option<PreauthHashValue>,
+        cipher_keys: Option<(EncryptionCipher, DerivedKey, DerivedKey)>,
         info: &ConnectionInfo,
     ) -> crate::Result<SessionAlgos> {
-        let deriver = KeyDeriver::new(session_key);
-
-        let (enc, dec) = if let Some((e, d)) =
-            Self::smb3xx_make_cipher_pair(&deriver, info, preauth_hash)?
-        {
+        let cipher = cipher_keys.as_ref().map(|(cipher, _, _)| *cipher);
+        let (enc, dec) = if let Some((e, d)) = Self::smb3xx_make_cipher_pair(cipher_keys)? {
             (Some(e), Some(d))
         } else {
             // There's no matching algorithm, so no encryption/decryption.
@@ -120,6 +131,7 @@ impl SessionAlgosFactory {
         Ok(SessionAlgos {
             encryptor: enc,
             decryptor: dec,
+            cipher,
         })
     }
 
@@ -144,10 +156,26 @@ impl SessionAlgosFactory {
     }
 
     fn smb3xx_make_cipher_pair(
+        cipher_keys: Option<(EncryptionCipher, DerivedKey, DerivedKey)>,
+    ) -> Result<Option<(MessageEncryptor, MessageDecryptor)>, CryptoError> {
+        let (cipher, enc_key, dec_key) = match cipher_keys {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+
+        Ok(Some((
+            MessageEncryptor::new(make_encrypting_algo(cipher, &enc_key)?),
+            MessageDecryptor::new(make_encrypting_algo(cipher, &dec_key)?),
+        )))
+    }
+
+    /// Derives the encryption (client-to-server) and decryption (server-to-client) keys of the session,
+    /// along with the cipher to use, if encryption is available.
+    fn smb3xx_derive_cipher_keys(
         deriver: &KeyDeriver,
         info: &ConnectionInfo,
         preauth_hash: &Option<PreauthHashValue>,
-    ) -> Result<Option<(MessageEncryptor, MessageDecryptor)>, CryptoError> {
+    ) -> Result<Option<(EncryptionCipher, DerivedKey, DerivedKey)>, CryptoError> {
         // Not supported
         if !info.dialect.supports_encryption() {
             return Ok(None);
@@ -168,8 +196,8 @@ impl SessionAlgosFactory {
             EncryptionCipher::Aes128Ccm
         };
 
-        // Check if the cipher is supported in the current build.
-        if !crate::crypto::ENCRYPTING_ALGOS.contains(&cipher) {
+        // Check if the cipher is supported in the current build, and allowed by the configuration.
+        if !info.config.encryption_ciphers().contains(&cipher) {
             return Ok(None);
         }
 
@@ -183,10 +211,7 @@ impl SessionAlgosFactory {
             Self::preauth_hash_or(preauth_hash, Self::NO_PREAUTH_HASH_DERIVE_ENCRYPT_S2C_CTX),
         )?;
 
-        Ok(Some((
-            MessageEncryptor::new(make_encrypting_algo(cipher, &enc_key)?),
-            MessageDecryptor::new(make_encrypting_algo(cipher, &dec_key)?),
-        )))
+        Ok(Some((cipher, enc_key, dec_key)))
     }
 
     fn preauth_hash_or<'a>(
@@ -215,6 +240,7 @@ enum SessionInfoState {
         algos: SessionAlgos,
         flags: SessionFlags,
         force_encryption: bool,
+        auth_mechanism: AuthMechanism,
     },
     /// The session is invalid, and should not be used anymore.
     Invalid,
@@ -302,11 +328,15 @@ impl SessionInfo {
     }
 
     /// Starts the session setup process.
+    ///
+    /// `allow_unsigned` sets whether the session may turn out to be a guest or anonymous session,
+    /// which is not signed.
     pub fn setup(
         &mut self,
         session_key: &KeyToDerive,
         preauth_hash: &Option<PreauthHashValue>,
         info: &ConnectionInfo,
+        allow_unsigned: bool,
     ) -> crate::Result<()> {
         if !matches!(self.state, Some(SessionInfoState::Initial)) {
             return Err(crate::Error::InvalidState(
@@ -314,14 +344,13 @@ impl SessionInfo {
             ));
         }
 
-        let algos = SessionAlgosFactory::new_session(session_key, preauth_hash, info)?;
+        let algos =
+            SessionAlgosFactory::new_session(self.session_id, session_key, preauth_hash, info)?;
         log::trace!("Session algos set up: {algos:?}");
 
-        let info_allows_unsigned = info.config.allow_unsigned_guest_access;
-
         self.state = Some(SessionInfoState::SettingUp {
             algos,
-            allow_unsigned: info_allows_unsigned,
+            allow_unsigned,
         });
 
         Ok(())
@@ -329,8 +358,14 @@ impl SessionInfo {
 
     /// Turns the session into a ready state.
     ///
-    /// Verifies the session flags against the connection config, and sets them in the session info.
-    pub fn ready(&mut self, flags: SessionFlags, conn_info: &ConnectionInfo) -> crate::Result<()> {
+    /// Verifies the session flags against the connection config and the unsigned access allowed in [`SessionInfo::setup`],
+    /// and sets them in the session info.
+    pub fn ready(
+        &mut self,
+        flags: SessionFlags,
+        auth_mechanism: AuthMechanism,
+        conn_info: &ConnectionInfo,
+    ) -> crate::Result<()> {
         if !self.is_setting_up() {
             return Err(crate::Error::InvalidState(
                 "Session is not set up, cannot set flags.".to_string(),
@@ -341,6 +376,12 @@ impl SessionInfo {
         // if it is required for us. Also, make sure it is not a null/guest session.
 
         let force_encryption = if conn_info.config.encryption_mode.is_required() {
+            if flags.is_null_session() {
+                return Err(crate::Error::InvalidConfiguration(
+                    "Encryption is required by the connection config, but anonymous sessions cannot be encrypted."
+                        .to_string(),
+                ));
+            }
             if !flags.encrypt_data() {
                 log::debug!(
                     "Note! session does not require encryption, but it is required by the connection config. Forcing encryption."
@@ -363,9 +404,16 @@ impl SessionInfo {
             false
         };
 
-        if !conn_info.config.allow_unsigned_guest_access && flags.is_guest_or_null_session() {
+        let allow_unsigned = matches!(
+            self.state,
+            Some(SessionInfoState::SettingUp {
+                allow_unsigned: true,
+                ..
+            })
+        );
+        if !allow_unsigned && flags.is_guest_or_null_session() {
             return Err(crate::Error::InvalidMessage(
-                "Signing may be disabled to allow guest or anonymous logins.".to_string(),
+                "The server set up an unsigned guest or anonymous session. Use guest or anonymous credentials, or allow unsigned guest access in the connection config, to accept it.".to_string(),
             ));
         }
 
@@ -374,6 +422,7 @@ impl SessionInfo {
                 algos,
                 flags,
                 force_encryption,
+                auth_mechanism,
             }),
             _ => unreachable!(),
         };
@@ -437,6 +486,40 @@ impl SessionInfo {
         }
     }
 
+    /// Returns the security posture of the session.
+    /// If the session is not ready, it will return an error.
+    pub fn security_posture(&self, conn_info: &ConnectionInfo) -> crate::Result<SecurityPosture> {
+        match &self.state {
+            Some(SessionInfoState::Ready {
+                algos,
+                flags,
+                auth_mechanism,
+                ..
+            }) => {
+                let connection = SecurityPosture::for_connection(conn_info);
+                let encryption_scope = if self.should_encrypt()? {
+                    EncryptionScope::Session
+                } else {
+                    EncryptionScope::None
+                };
+                Ok(SecurityPosture {
+                    signing_algorithm: connection
+                        .signing_algorithm
+                        .filter(|_| !flags.is_guest_or_null_session()),
+                    encryption_cipher: algos.cipher.filter(|_| algos.encryptor.is_some()),
+                    encryption_scope,
+                    auth_mechanism: Some(*auth_mechanism),
+                    guest: flags.is_guest(),
+                    anonymous: flags.is_null_session(),
+                    ..connection
+                })
+            }
+            _ => Err(crate::Error::InvalidState(
+                "Session is not ready!".to_string(),
+            )),
+        }
+    }
+
     pub fn decryptor(&self) -> crate::Result<Option<&MessageDecryptor>> {
         match &self.state {
             Some(SessionInfoState::Ready { algos, .. }) => Ok(algos.decryptor.as_ref()),
@@ -0,0 +1,1081 @@
+//! Synchronization of a local directory tree with a directory tree on a share.
+//!
+//! [`synchronize`] compares the source and the destination trees, and transfers only what changed:
+//! - Files are compared by size, last write time and the read-only attribute, or by their
+//!   SHA-256 content hashes when [`SyncOptions::checksum`] is set.
+//! - Changed files are copied using [`start_parallel_copy`][crate::resource::start_parallel_copy],
+//!   and their last write time and attributes are copied along.
+//! - When uploading with [`SyncOptions::checksum`], a new file with the same contents as an extraneous
+//!   file of the share (e.g. since it was moved) is copied on the server side, instead of being uploaded again.
+//! - In mirror mode, files and directories of the destination that do not exist in the source are deleted.
+//!
+//! ```no_run
+//! # use smb::{Client, ClientConfig, Credentials, UncPath};
+//! # use smb::sync::{SyncDirection, SyncOptions, synchronize};
+//! # use std::str::FromStr;
+//! # #[cfg(not(feature = "async"))] fn main() {}
+//! # #[cfg(feature = "async")]
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = Client::new(ClientConfig::default());
+//! let share = UncPath::from_str(r"\\server\share")?;
+//! client
+//!     .share_connect_with_credentials(&share, Credentials::password("user", "password".to_string())?)
+//!     .await?;
+//! let tree = client.get_tree(&share).await?;
+//! let options = SyncOptions {
+//!     direction: SyncDirection::Upload,
+//!     mirror: true,
+//!     exclude: vec!["*.tmp".to_string()],
+//!     ..Default::default()
+//! };
+//! let report = synchronize(&tree, r"backups\site", "/var/www/site".as_ref(), &options).await?;
+//! for action in &report.actions {
+//!     println!("{action:?}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+use maybe_async::{async_impl, maybe_async, sync_impl};
+use sha2::{Digest, Sha256};
+use smb_dtyp::binrw_util::prelude::FileTime;
+use smb_fscc::{
+    DirAccessMask, FileAccessMask, FileAttributes, FileBasicInformation,
+    FileDispositionInformation, FileIdBothDirectoryInformation,
+};
+use smb_msg::{CreateDisposition, CreateOptions, Status};
+
+#[cfg(not(feature = "async"))]
+use std::{
+    fs,
+    io::{Read, Write},
+};
+#[cfg(feature = "async")]
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::resource::Resource;
+use crate::sync_helpers::{Arc, Mutex};
+use crate::{Directory, Error, File, FileCreateArgs, Tree};
+
+/// The direction of a [`synchronize`] operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncDirection {
+    /// Copy the local directory to the share.
+    #[default]
+    Upload,
+    /// Copy the directory on the share to the local directory.
+    Download,
+}
+
+/// Options of a [`synchronize`] operation.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub direction: SyncDirection,
+    /// Whether to delete files and directories of the destination that do not exist in the source.
+    pub mirror: bool,
+    /// Whether to compare the contents of files of the same size, instead of their last write times.
+    ///
+    /// Also required to copy moved files on the server side, instead of uploading them again.
+    pub checksum: bool,
+    /// Whether to only plan the actions, without performing them.
+    pub dry_run: bool,
+    /// Patterns of names or relative paths to exclude, e.g. `*.tmp` or `logs\*`.
+    /// Patterns support the `*` and `?` wildcards, and are matched case-insensitively.
+    ///
+    /// Excluded files are neither copied nor deleted.
+    pub exclude: Vec<String>,
+    /// The number of parallel jobs to copy each file with. `0` uses the default number of jobs.
+    pub jobs: usize,
+}
+
+/// An action performed (or planned, in a dry run) by [`synchronize`].
+///
+/// Paths are relative to the synchronized directories, and separated by backslashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    /// A directory was created in the destination.
+    CreateDirectory(String),
+    /// A file was copied from the source to the destination.
+    Copy(String),
+    /// A file was copied on the server, from another file of the share with the same contents.
+    ServerCopy { from: String, to: String },
+    /// The last write time or the attributes of a file of the destination were updated.
+    UpdateAttributes(String),
+    /// A file or a directory was deleted from the destination.
+    Delete(String),
+}
+
+/// The result of a [`synchronize`] operation.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The actions performed, in order.
+    pub actions: Vec<SyncAction>,
+    /// The number of bytes copied through the client (or to be copied, in a dry run).
+    pub bytes_copied: u64,
+}
+
+/// Synchronizes the local directory with the directory at the `remote` path of the tree,
+/// in the direction specified by the options.
+///
+/// The destination directory is created if it does not exist. See the [module documentation][self]
+/// for how the directories are compared.
+#[maybe_async]
+pub async fn synchronize(
+    tree: &Tree,
+    remote: &str,
+    local: &Path,
+    options: &SyncOptions,
+) -> crate::Result<SyncReport> {
+    let sync = Synchronizer {
+        tree,
+        remote: remote.replace('/', "\\").trim_matches('\\').to_string(),
+        local: local.to_path_buf(),
+        options,
+        exclude: options
+            .exclude
+            .iter()
+            .map(|pattern| pattern.replace('/', "\\").to_lowercase())
+            .collect(),
+    };
+    let local = sync.list_local().await?;
+    let remote = sync.list_remote().await?;
+    let (source, destination) = match options.direction {
+        SyncDirection::Upload => (local, remote),
+        SyncDirection::Download => (remote, local),
+    };
+
+    let report = sync.plan(&source, &destination).await?;
+    log::debug!(
+        "Synchronization of {} with {}: {} actions, {} bytes to copy",
+        sync.local.display(),
+        sync.remote,
+        report.actions.len(),
+        report.bytes_copied
+    );
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    if !destination.exists {
+        sync.create_root().await?;
+    }
+    for action in &report.actions {
+        log::debug!("Applying {action:?}");
+        sync.apply(action, &source, &destination).await?;
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    is_dir: bool,
+    size: u64,
+    modified: FileTime,
+    readonly: bool,
+}
+
+#[derive(Default)]
+struct Listing {
+    exists: bool,
+    entries: BTreeMap<String, Entry>,
+    excluded: Vec<String>,
+}
+
+enum Difference {
+    Same,
+    Attributes,
+    Content,
+}
+
+struct Synchronizer<'a> {
+    tree: &'a Tree,
+    remote: String,
+    local: PathBuf,
+    options: &'a SyncOptions,
+    exclude: Vec<String>,
+}
+
+#[maybe_async(AFIT)]
+impl Synchronizer<'_> {
+    async fn list_local(&self) -> crate::Result<Listing> {
+        let mut listing = Listing::default();
+        match fs::metadata(&self.local).await {
+            Ok(metadata) if metadata.is_dir() => listing.exists = true,
+            Ok(_) => {
+                return Err(Error::InvalidArgument(format!(
+                    "{} is not a directory",
+                    self.local.display()
+                )));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(listing),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut pending = vec![String::new()];
+        while let Some(relative) = pending.pop() {
+            for (name, metadata) in read_dir(&self.local_path(&relative)?).await? {
+                let Some(name) = name.to_str().filter(|name| !name.contains('\\')) else {
+                    log::warn!("Skipping {name:?} in {}: unsupported name", relative);
+                    continue;
+                };
+                let path = join(&relative, name);
+                if self.is_excluded(&path) {
+                    listing.excluded.push(path);
+                    continue;
+                }
+                // Symbolic links and special files are not synchronized.
+                if !metadata.is_dir() && !metadata.is_file() {
+                    continue;
+                }
+                if metadata.is_dir() {
+                    pending.push(path.clone());
+                }
+                let entry = Entry {
+                    is_dir: metadata.is_dir(),
+                    size: if metadata.is_dir() { 0 } else { metadata.len() },
+                    modified: metadata.modified()?.into(),
+                    readonly: metadata.permissions().readonly(),
+                };
+                listing.entries.insert(path, entry);
+            }
+        }
+        Ok(listing)
+    }
+
+    async fn list_remote(&self) -> crate::Result<Listing> {
+        let mut listing = Listing::default();
+        let mut pending = vec![String::new()];
+        while let Some(relative) = pending.pop() {
+            let path = self.remote_path(&relative);
+            let access = DirAccessMask::new().with_list_directory(true).into();
+            let directory = match self.tree.open_existing(&path, access).await {
+                Err(Error::ReceivedErrorMessage(Status::U32_OBJECT_NAME_NOT_FOUND, _))
+                    if relative.is_empty() =>
+                {
+                    return Ok(listing);
+                }
+                result => result?,
+            };
+            let directory: Arc<Directory> = Arc::new(
+                directory
+                    .try_into()
+                    .map_err(|_| Error::InvalidArgument(format!("{path} is not a directory")))?,
+            );
+            listing.exists = true;
+            let entries =
+                Directory::query_all::<FileIdBothDirectoryInformation>(&directory, "*").await;
+            directory.close().await.ok();
+            for entry in entries? {
+                let name = entry.file_name.to_string();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                // Names that are not plain file names could point outside of the synchronized directories.
+                if name.is_empty() || name.contains(['\\', '/', ':']) {
+                    return Err(Error::InvalidMessage(format!(
+                        "Invalid file name {name:?} in the listing of {path}"
+                    )));
+                }
+                let path = join(&relative, &name);
+                if self.is_excluded(&path) {
+                    listing.excluded.push(path);
+                    continue;
+                }
+                let is_dir = entry.file_attributes.directory();
+                if is_dir && !entry.file_attributes.reparse_point() {
+                    pending.push(path.clone());
+                }
+                let entry = Entry {
+                    is_dir,
+                    size: if is_dir { 0 } else { entry.end_of_file },
+                    modified: entry.last_write_time,
+                    readonly: entry.file_attributes.readonly(),
+                };
+                listing.entries.insert(path, entry);
+            }
+        }
+        Ok(listing)
+    }
+
+    async fn plan(&self, source: &Listing, destination: &Listing) -> crate::Result<SyncReport> {
+        // Destination entries replaced by an entry of another type are deleted first.
+        let mut replaced = BTreeSet::new();
+        let mut created = vec![];
+        let mut copied = vec![];
+        let mut bytes_copied = 0;
+
+        // Files that are about to be deleted from the share, are likely to have been moved.
+        // Local and remote files share no identity, so a candidate is only copied on the server
+        // once its contents are verified to be the same. Without checksums, files are uploaded.
+        let mut moved_files = HashMap::<_, Vec<_>>::new();
+        if self.options.direction == SyncDirection::Upload && self.options.checksum {
+            for (path, entry) in &destination.entries {
+                if !entry.is_dir && entry.size > 0 && !source.entries.contains_key(path) {
+                    moved_files
+                        .entry((entry.size, *entry.modified))
+                        .or_default()
+                        .push(path);
+                }
+            }
+        }
+
+        for (path, entry) in &source.entries {
+            let existing = match destination.entries.get(path) {
+                Some(existing) if existing.is_dir != entry.is_dir => {
+                    replaced.extend(subtree(destination, path));
+                    None
+                }
+                existing => existing,
+            };
+            if entry.is_dir {
+                if existing.is_none() {
+                    created.push(SyncAction::CreateDirectory(path.clone()));
+                }
+                continue;
+            }
+
+            let action = match existing {
+                Some(existing) => match self.compare(path, entry, existing).await? {
+                    Difference::Same => continue,
+                    Difference::Attributes => SyncAction::UpdateAttributes(path.clone()),
+                    Difference::Content => SyncAction::Copy(path.clone()),
+                },
+                None => match self.find_moved(&moved_files, path, entry).await? {
+                    Some(from) => SyncAction::ServerCopy {
+                        from: from.clone(),
+                        to: path.clone(),
+                    },
+                    None => SyncAction::Copy(path.clone()),
+                },
+            };
+            if matches!(action, SyncAction::Copy(_)) {
+                bytes_copied += entry.size;
+            }
+            copied.push(action);
+        }
+
+        let mut extraneous = BTreeSet::new();
+        if self.options.mirror {
+            for path in destination.entries.keys() {
+                if source.entries.contains_key(path) || replaced.contains(path) {
+                    continue;
+                }
+                let prefix = format!("{path}\\");
+                if destination.excluded.iter().any(|e| e.starts_with(&prefix)) {
+                    log::debug!("Keeping {path}, since it contains excluded entries");
+                    continue;
+                }
+                extraneous.insert(path.clone());
+            }
+        }
+
+        // Deletions go in reverse order, so that the contents of directories are deleted first.
+        let mut actions = replaced
+            .into_iter()
+            .rev()
+            .map(SyncAction::Delete)
+            .collect::<Vec<_>>();
+        actions.extend(created);
+        actions.extend(copied);
+        actions.extend(extraneous.into_iter().rev().map(SyncAction::Delete));
+        Ok(SyncReport {
+            actions,
+            bytes_copied,
+        })
+    }
+
+    async fn compare(
+        &self,
+        path: &str,
+        source: &Entry,
+        destination: &Entry,
+    ) -> crate::Result<Difference> {
+        let same_content = source.size == destination.size
+            && if self.options.checksum {
+                self.local_hash(path).await? == self.remote_hash(path).await?
+            } else {
+                source.modified == destination.modified
+            };
+        Ok(if !same_content {
+            Difference::Content
+        } else if source.modified != destination.modified || source.readonly != destination.readonly
+        {
+            Difference::Attributes
+        } else {
+            Difference::Same
+        })
+    }
+
+    /// Returns the file of the share, among the `moved_files` candidates of the same size and
+    /// last write time, with the same contents as the local file at `path`.
+    async fn find_moved<'a>(
+        &self,
+        moved_files: &HashMap<(u64, u64), Vec<&'a String>>,
+        path: &str,
+        entry: &Entry,
+    ) -> crate::Result<Option<&'a String>> {
+        let Some(candidates) = moved_files.get(&(entry.size, *entry.modified)) else {
+            return Ok(None);
+        };
+        let hash = self.local_hash(path).await?;
+        for candidate in candidates {
+            if self.remote_hash(candidate).await? == hash {
+                return Ok(Some(candidate));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn local_hash(&self, path: &str) -> crate::Result<Vec<u8>> {
+        let mut file = fs::File::open(self.local_path(path)?).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; HASH_BUFFER_SIZE];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+
+    async fn remote_hash(&self, path: &str) -> crate::Result<Vec<u8>> {
+        let file = self
+            .open_remote_file(path, FileAccessMask::new().with_generic_read(true))
+            .await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; HASH_BUFFER_SIZE];
+        let mut offset = 0;
+        let result = loop {
+            match file.read_block(&mut buffer, offset, None, false).await {
+                Ok(0) => break Ok(()),
+                Ok(read) => {
+                    hasher.update(&buffer[..read]);
+                    offset += read as u64;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        file.close().await?;
+        result?;
+        Ok(hasher.finalize().to_vec())
+    }
+
+    async fn apply(
+        &self,
+        action: &SyncAction,
+        source: &Listing,
+        destination: &Listing,
+    ) -> crate::Result<()> {
+        match (self.options.direction, action) {
+            (SyncDirection::Upload, SyncAction::CreateDirectory(path)) => {
+                self.create_remote_directory(&self.remote_path(path)).await
+            }
+            (SyncDirection::Upload, SyncAction::Copy(path)) => {
+                if let Some(existing) = destination.entries.get(path).filter(|e| e.readonly) {
+                    let access = FileAccessMask::new().with_file_write_attributes(true);
+                    let file = self.open_remote_file(path, access).await?;
+                    let writable = Entry {
+                        modified: FileTime::ZERO,
+                        readonly: false,
+                        ..*existing
+                    };
+                    let result = set_remote_info(&file, &writable).await;
+                    file.close().await?;
+                    result?;
+                }
+                let from = Mutex::new(fs::File::open(self.local_path(path)?).await?);
+                let to = Arc::new(self.create_remote_file(path).await?);
+                let result = match copy(from, to.clone(), self.options.jobs).await {
+                    Ok(()) => set_remote_info(&to, &source.entries[path]).await,
+                    Err(e) => Err(e),
+                };
+                to.close().await?;
+                result
+            }
+            (SyncDirection::Upload, SyncAction::ServerCopy { from, to }) => {
+                let source_file = self
+                    .open_remote_file(from, FileAccessMask::new().with_generic_read(true))
+                    .await?;
+                let result = match self.create_remote_file(to).await {
+                    Ok(file) => {
+                        let mut result = file.srv_copy(&source_file).await;
+                        if result.is_ok() {
+                            result = set_remote_info(&file, &source.entries[to]).await;
+                        }
+                        file.close().await?;
+                        result
+                    }
+                    Err(e) => Err(e),
+                };
+                source_file.close().await?;
+                result
+            }
+            (SyncDirection::Upload, SyncAction::UpdateAttributes(path)) => {
+                let access = FileAccessMask::new().with_file_write_attributes(true);
+                let file = self.open_remote_file(path, access).await?;
+                let result = set_remote_info(&file, &source.entries[path]).await;
+                file.close().await?;
+                result
+            }
+            (SyncDirection::Upload, SyncAction::Delete(path)) => {
+                let access = FileAccessMask::new().with_delete(true);
+                let resource = self
+                    .tree
+                    .open_existing(&self.remote_path(path), access)
+                    .await?;
+                let handle = match &resource {
+                    Resource::File(file) => file.handle(),
+                    Resource::Directory(directory) => &directory.handle,
+                    _ => {
+                        return Err(Error::InvalidArgument(format!(
+                            "{path} is not a file or a directory"
+                        )));
+                    }
+                };
+                let result = handle.set_info(FileDispositionInformation::default()).await;
+                handle.close().await?;
+                result
+            }
+            (SyncDirection::Download, SyncAction::CreateDirectory(path)) => {
+                Ok(fs::create_dir_all(self.local_path(path)?).await?)
+            }
+            (SyncDirection::Download, SyncAction::Copy(path)) => {
+                let local = self.local_path(path)?;
+                if destination.entries.get(path).is_some_and(|e| e.readonly) {
+                    set_readonly(&local, false).await?;
+                }
+                let from = Arc::new(
+                    self.open_remote_file(path, FileAccessMask::new().with_generic_read(true))
+                        .await?,
+                );
+                let to = fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&local)
+                    .await;
+                let to = match to {
+                    Ok(to) => Arc::new(Mutex::new(to)),
+                    Err(e) => {
+                        from.close().await?;
+                        return Err(e.into());
+                    }
+                };
+                let result = copy(from.clone(), to.clone(), self.options.jobs).await;
+                from.close().await?;
+                result?;
+                to.lock()
+                    .await
+                    .map_err(|e| std::io::Error::other(e.to_string()))?
+                    .flush()
+                    .await?;
+                set_local_info(&local, &source.entries[path]).await
+            }
+            (SyncDirection::Download, SyncAction::UpdateAttributes(path)) => {
+                set_local_info(&self.local_path(path)?, &source.entries[path]).await
+            }
+            (SyncDirection::Download, SyncAction::Delete(path)) => {
+                let local = self.local_path(path)?;
+                if destination.entries[path].is_dir {
+                    Ok(fs::remove_dir(local).await?)
+                } else {
+                    Ok(fs::remove_file(local).await?)
+                }
+            }
+            (SyncDirection::Download, SyncAction::ServerCopy { .. }) => Err(Error::InvalidState(
+                "Server-side copies are only planned when uploading".to_string(),
+            )),
+        }
+    }
+
+    async fn create_root(&self) -> crate::Result<()> {
+        match self.options.direction {
+            SyncDirection::Upload => self.create_remote_directory(&self.remote).await,
+            SyncDirection::Download => Ok(fs::create_dir_all(&self.local).await?),
+        }
+    }
+
+    async fn create_remote_directory(&self, path: &str) -> crate::Result<()> {
+        let directory = self
+            .tree
+            .create_directory(
+                path,
+                CreateDisposition::OpenIf,
+                FileAccessMask::new().with_generic_read(true),
+            )
+            .await?
+            .unwrap_dir();
+        directory.close().await
+    }
+
+    async fn create_remote_file(&self, path: &str) -> crate::Result<File> {
+        let args = FileCreateArgs::make_overwrite(
+            FileAttributes::new().with_archive(true),
+            CreateOptions::new().with_non_directory_file(true),
+        );
+        let path = self.remote_path(path);
+        self.tree
+            .create(&path, &args)
+            .await?
+            .try_into()
+            .map_err(|_| Error::InvalidArgument(format!("{path} is not a file")))
+    }
+
+    async fn open_remote_file(&self, path: &str, access: FileAccessMask) -> crate::Result<File> {
+        let path = self.remote_path(path);
+        self.tree
+            .open_existing(&path, access)
+            .await?
+            .try_into()
+            .map_err(|_| Error::InvalidArgument(format!("{path} is not a file")))
+    }
+}
+
+impl Synchronizer<'_> {
+    /// Returns the local path of a relative path. Fails if a part of the path is not a plain file name,
+    /// so the path may not point outside of the local directory.
+    fn local_path(&self, relative: &str) -> crate::Result<PathBuf> {
+        let mut path = self.local.clone();
+        for name in relative.split('\\').filter(|name| !name.is_empty()) {
+            let mut components = Path::new(name).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => path.push(name),
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "Invalid file name {name:?} in {relative}"
+                    )));
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    fn remote_path(&self, relative: &str) -> String {
+        join(&self.remote, relative)
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        let path = path.to_lowercase();
+        let name = path.rsplit('\\').next().unwrap_or_default();
+        self.exclude
+            .iter()
+            .any(|pattern| matches_pattern(pattern, name) || matches_pattern(pattern, &path))
+    }
+}
+
+const HASH_BUFFER_SIZE: usize = 0x10000;
+
+#[cfg(not(feature = "single_threaded"))]
+#[maybe_async]
+async fn copy<F, T>(from: F, to: T, jobs: usize) -> crate::Result<()>
+where
+    F: crate::resource::ReadAtChannel + crate::resource::GetLen + Send + Sync + 'static,
+    T: crate::resource::WriteAtChannel
+        + crate::resource::SetLen
+        + crate::resource::SyncAll
+        + Send
+        + Sync
+        + 'static,
+{
+    use crate::resource::{prepare_parallel_copy, start_parallel_copy};
+
+    let state = prepare_parallel_copy(&from, &to, HashMap::from([(None, jobs)])).await?;
+    start_parallel_copy(from, to, Arc::new(state)).await
+}
+
+#[cfg(feature = "single_threaded")]
+fn copy<F, T>(from: F, to: T, _jobs: usize) -> crate::Result<()>
+where
+    F: crate::resource::ReadAtChannel + crate::resource::GetLen,
+    T: crate::resource::WriteAtChannel + crate::resource::SetLen,
+{
+    crate::resource::block_copy(from, to)
+}
+
+#[maybe_async]
+async fn set_remote_info(file: &File, entry: &Entry) -> crate::Result<()> {
+    file.set_info(FileBasicInformation {
+        creation_time: FileTime::ZERO,
+        last_access_time: FileTime::ZERO,
+        last_write_time: entry.modified,
+        change_time: FileTime::ZERO,
+        file_attributes: FileAttributes::new()
+            .with_archive(true)
+            .with_readonly(entry.readonly),
+    })
+    .await
+}
+
+#[maybe_async]
+async fn set_local_info(path: &Path, entry: &Entry) -> crate::Result<()> {
+    set_readonly(path, false).await?;
+    let file = fs::OpenOptions::new().write(true).open(path).await?;
+    #[cfg(feature = "async")]
+    let file = file.into_std().await;
+    file.set_modified(entry.modified.into())?;
+    drop(file);
+    if entry.readonly {
+        set_readonly(path, true).await?;
+    }
+    Ok(())
+}
+
+#[maybe_async]
+async fn set_readonly(path: &Path, readonly: bool) -> crate::Result<()> {
+    let mut permissions = fs::metadata(path).await?.permissions();
+    if permissions.readonly() == readonly {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Only the owner is granted write access back, unlike `Permissions::set_readonly`.
+        let mode = permissions.mode();
+        permissions.set_mode(if readonly {
+            mode & !0o222
+        } else {
+            mode | 0o200
+        });
+    }
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(readonly);
+    Ok(fs::set_permissions(path, permissions).await?)
+}
+
+#[async_impl]
+async fn read_dir(path: &Path) -> std::io::Result<Vec<(OsString, std::fs::Metadata)>> {
+    let mut entries = vec![];
+    let mut directory = fs::read_dir(path).await?;
+    while let Some(entry) = directory.next_entry().await? {
+        entries.push((entry.file_name(), entry.metadata().await?));
+    }
+    Ok(entries)
+}
+
+#[sync_impl]
+fn read_dir(path: &Path) -> std::io::Result<Vec<(OsString, std::fs::Metadata)>> {
+    fs::read_dir(path)?
+        .map(|entry| {
+            let entry = entry?;
+            Ok((entry.file_name(), entry.metadata()?))
+        })
+        .collect()
+}
+
+fn join(base: &str, name: &str) -> String {
+    match (base.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (_, true) => base.to_string(),
+        _ => format!("{base}\\{name}"),
+    }
+}
+
+/// Returns the path and the paths of the descendants of an entry in the listing.
+fn subtree(listing: &Listing, path: &str) -> Vec<String> {
+    let prefix = format!("{path}\\");
+    listing
+        .entries
+        .keys()
+        .filter(|key| *key == path || key.starts_with(&prefix))
+        .cloned()
+        .collect()
+}
+
+/// Matches a text against a pattern with `*` and `?` wildcards.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` in the pattern, and the text position it was matched at.
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last `*` match one more character.
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use binrw::BinWrite;
+    use smb_fscc::{ChainedItemList, QueryDirectoryInfo};
+    use smb_msg::{Command, QueryDirectoryResponse};
+
+    use super::*;
+    use crate::ConnectionConfig;
+    use crate::testing::{FakeReply, FakeServer, FakeServerConfig, TempDir};
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_synchronize() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_directory("share", "backup");
+        server.add_file("share", r"backup\stale.txt", b"stale".to_vec());
+        let connection = server.connect(ConnectionConfig::default()).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+
+        let local = TempDir::new("smb-sync");
+        std::fs::create_dir_all(local.0.join("sub")).unwrap();
+        std::fs::write(local.0.join("a.txt"), b"hello").unwrap();
+        std::fs::write(local.0.join("sub").join("b.txt"), b"world").unwrap();
+        std::fs::write(local.0.join("skip.tmp"), b"skipped").unwrap();
+
+        let upload = SyncOptions {
+            mirror: true,
+            exclude: vec!["*.TMP".to_string()],
+            ..Default::default()
+        };
+        let report = synchronize(&tree, "backup", &local.0, &upload)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.actions,
+            vec![
+                SyncAction::CreateDirectory("sub".to_string()),
+                SyncAction::Copy("a.txt".to_string()),
+                SyncAction::Copy(r"sub\b.txt".to_string()),
+                SyncAction::Delete("stale.txt".to_string()),
+            ]
+        );
+        assert_eq!(
+            server.file("share", r"backup\a.txt"),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(
+            server.file("share", r"backup\sub\b.txt"),
+            Some(b"world".to_vec())
+        );
+        assert_eq!(server.file("share", r"backup\stale.txt"), None);
+        assert_eq!(server.file("share", r"backup\skip.tmp"), None);
+
+        // Last write times were copied along, so nothing changed since.
+        let report = synchronize(&tree, "backup", &local.0, &upload)
+            .await
+            .unwrap();
+        assert_eq!(report.actions, vec![]);
+
+        let target = TempDir::new("smb-sync");
+        let mut download = SyncOptions {
+            direction: SyncDirection::Download,
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = synchronize(&tree, "backup", &target.0, &download)
+            .await
+            .unwrap();
+        assert_eq!(report.actions.len(), 3);
+        assert_eq!(report.bytes_copied, 10);
+        assert!(!target.0.exists());
+
+        download.dry_run = false;
+        synchronize(&tree, "backup", &target.0, &download)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(target.0.join("sub").join("b.txt")).unwrap(),
+            b"world"
+        );
+        let modified = |dir: &TempDir| {
+            std::fs::metadata(dir.0.join("a.txt"))
+                .unwrap()
+                .modified()
+                .map(FileTime::from)
+                .unwrap()
+        };
+        assert_eq!(modified(&target), modified(&local));
+    }
+
+    /// Moved files of the same size and last write time are told apart by their contents.
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_synchronize_moved_files() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_directory("share", "backup");
+        let connection = server.connect(ConnectionConfig::default()).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+
+        let local = TempDir::new("smb-sync");
+        std::fs::create_dir_all(&local.0).unwrap();
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        for (name, data) in [("a.txt", b"aaaa"), ("b.txt", b"bbbb")] {
+            let file = std::fs::File::create(local.0.join(name)).unwrap();
+            std::io::Write::write_all(&mut &file, data).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+        let mut options = SyncOptions {
+            mirror: true,
+            ..Default::default()
+        };
+        synchronize(&tree, "backup", &local.0, &options)
+            .await
+            .unwrap();
+        std::fs::rename(local.0.join("a.txt"), local.0.join("c.txt")).unwrap();
+        std::fs::rename(local.0.join("b.txt"), local.0.join("d.txt")).unwrap();
+
+        // Without checksums, moved files are uploaded again.
+        options.dry_run = true;
+        let report = synchronize(&tree, "backup", &local.0, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.actions,
+            vec![
+                SyncAction::Copy("c.txt".to_string()),
+                SyncAction::Copy("d.txt".to_string()),
+                SyncAction::Delete("b.txt".to_string()),
+                SyncAction::Delete("a.txt".to_string()),
+            ]
+        );
+
+        options.checksum = true;
+        let report = synchronize(&tree, "backup", &local.0, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.actions,
+            vec![
+                SyncAction::ServerCopy {
+                    from: "a.txt".to_string(),
+                    to: "c.txt".to_string()
+                },
+                SyncAction::ServerCopy {
+                    from: "b.txt".to_string(),
+                    to: "d.txt".to_string()
+                },
+                SyncAction::Delete("b.txt".to_string()),
+                SyncAction::Delete("a.txt".to_string()),
+            ]
+        );
+        assert_eq!(report.bytes_copied, 0);
+    }
+
+    /// A listing with a single file of the specified name.
+    fn listing(name: &str) -> FakeReply {
+        let entry = FileIdBothDirectoryInformation {
+            file_index: 0,
+            creation_time: FileTime::ZERO,
+            last_access_time: FileTime::ZERO,
+            last_write_time: FileTime::ZERO,
+            change_time: FileTime::ZERO,
+            end_of_file: 4,
+            allocation_size: 4,
+            file_attributes: FileAttributes::new().with_archive(true),
+            ea_size: Some(0),
+            reparse_tag: None,
+            short_name_length: 0,
+            short_name: Default::default(),
+            file_id: 1,
+            file_name: name.into(),
+        };
+        let mut output_buffer = Cursor::new(vec![]);
+        ChainedItemList::<_, { QueryDirectoryInfo::CHAINED_ALIGNMENT }>::from(vec![entry])
+            .write_le(&mut output_buffer)
+            .unwrap();
+        FakeReply::Respond(
+            QueryDirectoryResponse {
+                output_buffer: output_buffer.into_inner(),
+            }
+            .into(),
+        )
+    }
+
+    /// Names of a malicious listing may not point outside of the synchronized directories.
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_synchronize_invalid_names() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_directory("share", "backup");
+        server.add_file("share", "outside.txt", b"outside".to_vec());
+        let name = Arc::new(std::sync::Mutex::new(String::new()));
+        let queries = Arc::new(AtomicUsize::new(0));
+        server.on(Command::QueryDirectory, {
+            let name = name.clone();
+            move |_| match queries.fetch_add(1, Ordering::SeqCst) % 2 {
+                0 => listing(&name.lock().unwrap()),
+                _ => FakeReply::Error(Status::NoMoreFiles),
+            }
+        });
+        let connection = server.connect(ConnectionConfig::default()).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+        let tree = session
+            .tree_connect(&server.share_path("share"))
+            .await
+            .unwrap();
+
+        let local = TempDir::new("smb-sync");
+        let target = local.0.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        let download = SyncOptions {
+            direction: SyncDirection::Download,
+            ..Default::default()
+        };
+        let mirror = SyncOptions {
+            mirror: true,
+            ..Default::default()
+        };
+        for invalid in [r"..\outside.txt", "../outside.txt", "C:outside.txt"] {
+            *name.lock().unwrap() = invalid.to_string();
+            let result = synchronize(&tree, "backup", &target, &download).await;
+            assert!(matches!(result, Err(Error::InvalidMessage(_))));
+            let result = synchronize(&tree, "backup", &target, &mirror).await;
+            assert!(matches!(result, Err(Error::InvalidMessage(_))));
+        }
+        assert!(!local.0.join("outside.txt").exists());
+        assert_eq!(
+            server.file("share", "outside.txt"),
+            Some(b"outside".to_vec())
+        );
+
+        let sync = Synchronizer {
+            tree: &tree,
+            remote: "backup".to_string(),
+            local: target.clone(),
+            options: &download,
+            exclude: vec![],
+        };
+        assert_eq!(
+            sync.local_path(r"sub\a.txt").unwrap(),
+            target.join("sub").join("a.txt")
+        );
+        for invalid in [
+            "..",
+            r"sub\..\..\a.txt",
+            "sub/../../a.txt",
+            "/a.txt",
+            r"sub\.\a.txt",
+        ] {
+            assert!(sync.local_path(invalid).is_err());
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Utilities for testing SMB clients offline, without a real server.
+//!
+//! The [`FakeServer`] is an in-process, scriptable SMB2 server. It runs over a
+//! [`MemoryTransport`] pair, and answers negotiate, session setup (using a real NTLM exchange),
+//! tree connect, create, read, write and close requests over an in-memory file store.
+//! Signing, encryption and compression are performed using the same algorithms as the client,
+//! so those paths are exercised end-to-end, deterministically.
+//!
+//! Any command may be scripted using [`FakeServer::on`], to return custom responses or error statuses,
+//! to go async ([`FakeReply::Pending`]), to wait for cancellation ([`FakeReply::PendingUntilCancelled`]),
+//! or to never be answered ([`FakeReply::Drop`]).
+//! When [`FakeServerConfig::leasing`] is set, requested leases are granted, and may be broken
+//! using [`FakeServer::break_lease`]. Oplock breaks and session closures are sent using
+//! [`FakeServer::break_oplock`] and [`FakeServer::close_session`], and when [`FakeServerConfig::multichannel`]
+//! is set, sessions may be bound to several connections.
+//!
+//! To test how the client copes with network failures, wrap its transport with a [`FaultyTransport`]
+//! (or use [`FakeServer::connect_with_faults`]): frames may be dropped, delayed, duplicated, reordered,
+//! truncated or answered with arbitrary statuses, as decided by a [`FaultPolicy`], such as [`FaultRules`].
+//!
+//! With the `async` feature, a [`Simulation`] runs clients and fake servers under a simulated clock
+//! and network, so races in the connection worker may be reproduced from a seed.
+//!
+//! This module is available when the `testing` crate feature is enabled.
+//!
+//! ```
+//! # use smb::{*, testing::*};
+//! # #[cfg(not(feature = "async"))] fn main() {}
+//! # #[cfg(feature = "async")]
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> smb::Result<()> {
+//! let server = FakeServer::new(FakeServerConfig::default());
+//! server.add_file("share", "hello.txt", b"Hello, world!".to_vec());
+//! server.on(Command::Flush, |_| FakeReply::Error(Status::AccessDenied));
+//!
+//! let connection = server.connect(ConnectionConfig::default()).await?;
+//! let session = connection.authenticate(server.identity()).await?;
+//! let _tree = session.tree_connect(&server.share_path("share")).await?;
+//! # Ok(()) }
+//! ```
+
+mod faults;
+mod files;
+mod server;
+mod session;
+#[cfg(feature = "async")]
+mod simulation;
+
+pub use faults::*;
+pub use files::FakeFile;
+pub use server::*;
+#[cfg(feature = "async")]
+pub use simulation::*;
+pub use smb_transport::MemoryTransport;
+
+/// Returns deterministic, incompressible data, generated by a linear congruential generator.
+#[cfg(test)]
+pub(crate) fn random_bytes(seed: u32, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+/// A path for a local test directory, named `{prefix}-{random}`, whose tree is removed when dropped.
+///
+/// The directory itself is not created.
+#[cfg(test)]
+pub(crate) struct TempDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("{prefix}-{:016x}", rand::random::<u64>())))
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
@@ -0,0 +1,1105 @@
+//! Fault injection: a transport wrapper that perturbs the traffic passing through it.
+
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(not(feature = "async"))]
+use std::{sync::Condvar, time::Instant};
+#[cfg(feature = "async")]
+use tokio::time::Instant;
+
+use binrw::prelude::*;
+#[cfg(feature = "async")]
+use futures_core::future::BoxFuture;
+#[cfg(feature = "async")]
+use futures_util::FutureExt;
+use smb_msg::*;
+use smb_transport::error::Result;
+use smb_transport::{
+    IoVec, SmbTcpMessageHeader, SmbTransport, SmbTransportRead, SmbTransportWrite, TransportError,
+};
+
+/// The direction of a frame passing through a [`FaultyTransport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameDirection {
+    /// From the client to the server (requests).
+    Outgoing,
+    /// From the server to the client (responses).
+    Incoming,
+}
+
+/// A frame (a single transport message) passing through a [`FaultyTransport`].
+#[derive(Debug, Clone)]
+pub struct FaultFrame {
+    pub direction: FrameDirection,
+    /// The index of the frame among the frames in the same direction, starting at 0.
+    pub index: u64,
+    /// The command of the (first) message in the frame.
+    ///
+    /// `None` for frames that are not plain SMB2 messages, e.g. encrypted or compressed ones.
+    pub command: Option<Command>,
+    /// The message ID of the (first) message in the frame, for plain SMB2 messages.
+    pub message_id: Option<u64>,
+    /// The raw frame, without the transport header.
+    pub data: Vec<u8>,
+}
+
+impl FaultFrame {
+    fn new(direction: FrameDirection, index: u64, data: Vec<u8>) -> Self {
+        let header = Self::parse_header(&data);
+        Self {
+            direction,
+            index,
+            command: header.as_ref().map(|h| h.command),
+            message_id: header.as_ref().map(|h| h.message_id),
+            data,
+        }
+    }
+
+    fn parse_header(data: &[u8]) -> Option<Header> {
+        Header::read(&mut Cursor::new(data)).ok()
+    }
+}
+
+/// What a [`FaultyTransport`] does with a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Passes the frame as-is.
+    Pass,
+    /// Silently drops the frame.
+    Drop,
+    /// Passes the frame after the specified delay.
+    ///
+    /// Outgoing frames are sent in order, so the frames following a delayed outgoing frame
+    /// are delayed as well. Delayed incoming frames may be overtaken by the frames following them.
+    Delay(Duration),
+    /// Passes the frame twice.
+    Duplicate,
+    /// Holds the frame back, and passes it right after the next frame passed in the same direction.
+    Hold,
+    /// Passes only the first bytes of the frame, as if its tail was lost.
+    Truncate(usize),
+    /// Answers the frame with an error response with the specified status:
+    /// outgoing requests are not forwarded to the server, and incoming responses are replaced.
+    ///
+    /// When the status is [`Status::Pending`], an interim response is sent with a new async ID,
+    /// and the client keeps waiting for a final response that never arrives
+    /// (until the operation is cancelled, or times out).
+    ///
+    /// Injected responses are neither signed nor encrypted: a client accepts error responses
+    /// only before the session is set up, or on sessions that do not require signing.
+    /// Interim responses are accepted on signed sessions, as they are never signed anyway.
+    /// Frames that are not plain SMB2 messages are passed as-is.
+    Respond(Status),
+    /// Drops the frame, and disconnects the transport.
+    Close,
+}
+
+/// Decides what to do with each frame passing through a [`FaultyTransport`].
+///
+/// Implemented for closures, and by [`FaultRules`].
+pub trait FaultPolicy: Send {
+    fn on_frame(&mut self, frame: &FaultFrame) -> FaultAction;
+}
+
+impl<F> FaultPolicy for F
+where
+    F: FnMut(&FaultFrame) -> FaultAction + Send,
+{
+    fn on_frame(&mut self, frame: &FaultFrame) -> FaultAction {
+        self(frame)
+    }
+}
+
+/// A single rule of [`FaultRules`].
+#[derive(Debug, Clone)]
+pub struct FaultRule {
+    action: FaultAction,
+    direction: Option<FrameDirection>,
+    command: Option<Command>,
+    skip: usize,
+    times: Option<usize>,
+}
+
+impl FaultRule {
+    /// Creates a rule applying the action to all frames.
+    pub fn new(action: FaultAction) -> Self {
+        Self {
+            action,
+            direction: None,
+            command: None,
+            skip: 0,
+            times: None,
+        }
+    }
+
+    /// Matches only frames in the specified direction.
+    pub fn direction(mut self, direction: FrameDirection) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Matches only frames of the specified command.
+    pub fn command(mut self, command: Command) -> Self {
+        self.command = Some(command);
+        self
+    }
+
+    /// Lets the first `count` matching frames pass, before applying the action.
+    pub fn skip(mut self, count: usize) -> Self {
+        self.skip = count;
+        self
+    }
+
+    /// Applies the action at most `count` times.
+    pub fn times(mut self, count: usize) -> Self {
+        self.times = Some(count);
+        self
+    }
+
+    fn matches(&self, frame: &FaultFrame) -> bool {
+        self.direction.is_none_or(|d| d == frame.direction)
+            && self.command.is_none_or(|c| Some(c) == frame.command)
+    }
+}
+
+/// A [`FaultPolicy`] made of rules: each frame gets the action of the first matching rule
+/// that is still active, or [`FaultAction::Pass`] if there is none.
+///
+/// ```
+/// # use smb::{Command, Status, testing::*};
+/// let rules = FaultRules::new()
+///     // Never answer the first flush.
+///     .rule(FaultRule::new(FaultAction::Drop).direction(FrameDirection::Outgoing).command(Command::Flush).times(1))
+///     // Fail the second tree connect.
+///     .rule(FaultRule::new(FaultAction::Respond(Status::AccessDenied)).command(Command::TreeConnect).skip(1));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FaultRules {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule, with a lower priority than the rules added before it.
+    pub fn rule(mut self, rule: FaultRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+}
+
+impl FaultPolicy for FaultRules {
+    fn on_frame(&mut self, frame: &FaultFrame) -> FaultAction {
+        for rule in self.rules.iter_mut() {
+            if rule.times == Some(0) || !rule.matches(frame) {
+                continue;
+            }
+            if rule.skip > 0 {
+                rule.skip -= 1;
+                continue;
+            }
+            if let Some(times) = rule.times.as_mut() {
+                *times -= 1;
+            }
+            return rule.action.clone();
+        }
+        FaultAction::Pass
+    }
+}
+
+/// A record of an action applied by a [`FaultyTransport`] to a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultRecord {
+    pub direction: FrameDirection,
+    pub index: u64,
+    pub command: Option<Command>,
+    pub message_id: Option<u64>,
+    pub action: FaultAction,
+}
+
+struct FaultState {
+    policy: Box<dyn FaultPolicy>,
+    next_index: [u64; 2],
+    held: [Option<Vec<u8>>; 2],
+    next_async_id: u64,
+    history: Vec<FaultRecord>,
+}
+
+/// The result of applying the policy to a frame.
+#[derive(Default)]
+struct Decision {
+    delay: Option<Duration>,
+    /// Frames to pass on, in the frame's direction.
+    pass: Vec<Vec<u8>>,
+    /// Frames to deliver to the client, in place of the server.
+    inject: Vec<Vec<u8>>,
+    close: bool,
+}
+
+/// A controller of a [`FaultyTransport`], usable after the transport was moved into a connection.
+#[derive(Clone)]
+pub struct FaultController {
+    state: Arc<Mutex<FaultState>>,
+    incoming: Arc<FrameQueue>,
+}
+
+impl FaultController {
+    /// Replaces the policy, starting with the next frame.
+    pub fn set_policy(&self, policy: impl FaultPolicy + 'static) {
+        self.state.lock().unwrap().policy = Box::new(policy);
+    }
+
+    /// Returns the actions applied so far, excluding [`FaultAction::Pass`].
+    pub fn history(&self) -> Vec<FaultRecord> {
+        self.state.lock().unwrap().history.clone()
+    }
+
+    /// Disconnects the transport, as if the network went down.
+    pub fn disconnect(&self) {
+        self.incoming.close();
+    }
+
+    fn decide(&self, direction: FrameDirection, data: Vec<u8>) -> Decision {
+        let mut state = self.state.lock().unwrap();
+        let slot = direction as usize;
+        let index = state.next_index[slot];
+        state.next_index[slot] += 1;
+
+        let frame = FaultFrame::new(direction, index, data);
+        let action = state.policy.on_frame(&frame);
+        if action != FaultAction::Pass {
+            log::debug!(
+                "Fault injection: {action:?} for {direction:?} frame #{index} ({:?})",
+                frame.command
+            );
+            state.history.push(FaultRecord {
+                direction,
+                index,
+                command: frame.command,
+                message_id: frame.message_id,
+                action: action.clone(),
+            });
+        }
+
+        let mut decision = Decision::default();
+        let data = frame.data;
+        match action {
+            FaultAction::Pass => decision.pass.push(data),
+            FaultAction::Drop => return decision,
+            FaultAction::Delay(delay) => {
+                decision.delay = Some(delay);
+                decision.pass.push(data);
+            }
+            FaultAction::Duplicate => decision.pass.extend([data.clone(), data]),
+            FaultAction::Hold => {
+                // A frame that is already held is released, to keep at most one held frame.
+                decision.pass.extend(state.held[slot].replace(data));
+                return decision;
+            }
+            FaultAction::Truncate(length) => {
+                decision.pass.push(data[..length.min(data.len())].to_vec())
+            }
+            FaultAction::Respond(status) => match Self::error_response(&mut state, &data, status) {
+                Some(response) => match direction {
+                    FrameDirection::Outgoing => decision.inject.push(response),
+                    FrameDirection::Incoming => decision.pass.push(response),
+                },
+                None => {
+                    log::warn!("Fault injection: cannot respond to a non-plain frame, passing it");
+                    decision.pass.push(data);
+                }
+            },
+            FaultAction::Close => {
+                decision.close = true;
+                return decision;
+            }
+        }
+        decision.pass.extend(state.held[slot].take());
+        decision
+    }
+
+    /// Builds an (unsigned) error response, for the plain message in `data`.
+    fn error_response(state: &mut FaultState, data: &[u8], status: Status) -> Option<Vec<u8>> {
+        let request = FaultFrame::parse_header(data)?;
+        let mut header = Header {
+            credit_charge: request.credit_charge,
+            status: status as u32,
+            command: request.command,
+            credit_request: request.credit_request.max(1),
+            flags: HeaderFlags::new().with_server_to_redir(true),
+            next_command: 0,
+            message_id: request.message_id,
+            tree_id: Some(request.tree_id.unwrap_or(0)),
+            async_id: None,
+            session_id: request.session_id,
+            signature: 0,
+        };
+        if status == Status::Pending {
+            state.next_async_id += 1;
+            header.credit_request = 0;
+            header.to_async(state.next_async_id);
+        }
+        let response = PlainResponse {
+            header,
+            content: ErrorResponse { error_data: vec![] }.into(),
+        };
+        let mut buffer = Vec::new();
+        response.write(&mut Cursor::new(&mut buffer)).ok()?;
+        Some(buffer)
+    }
+}
+
+#[derive(Default)]
+struct FrameQueueState {
+    /// The frames, ordered by the time they are due.
+    frames: VecDeque<(Instant, Vec<u8>)>,
+    closed: bool,
+    #[cfg(not(feature = "async"))]
+    read_timeout: Option<Duration>,
+}
+
+/// The frames to be received by the client.
+#[derive(Default)]
+struct FrameQueue {
+    state: Mutex<FrameQueueState>,
+    #[cfg(feature = "async")]
+    available: tokio::sync::Notify,
+    #[cfg(not(feature = "async"))]
+    available: Condvar,
+}
+
+impl FrameQueue {
+    /// Queues the frames, to be received after the delay, if any.
+    fn push(&self, frames: Vec<Vec<u8>>, delay: Option<Duration>) {
+        if frames.is_empty() {
+            return;
+        }
+        let due = Instant::now() + delay.unwrap_or_default();
+        {
+            let mut state = self.state.lock().unwrap();
+            let position = state.frames.partition_point(|(d, _)| *d <= due);
+            for (i, frame) in frames.into_iter().enumerate() {
+                state.frames.insert(position + i, (due, frame));
+            }
+        }
+        self.notify();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify();
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn set_read_timeout(&self, timeout: Duration) {
+        self.state.lock().unwrap().read_timeout = Some(timeout);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    fn notify(&self) {
+        #[cfg(feature = "async")]
+        self.available.notify_one();
+        #[cfg(not(feature = "async"))]
+        self.available.notify_all();
+    }
+
+    /// Pops the next frame if it is due, or returns the time the next frame is due.
+    fn try_pop(
+        state: &mut FrameQueueState,
+    ) -> Result<std::result::Result<Vec<u8>, Option<Instant>>> {
+        if state.closed {
+            return Err(TransportError::NotConnected);
+        }
+        Ok(match state.frames.front() {
+            Some((due, _)) if *due <= Instant::now() => Ok(state.frames.pop_front().unwrap().1),
+            Some((due, _)) => Err(Some(*due)),
+            None => Err(None),
+        })
+    }
+
+    #[cfg(feature = "async")]
+    async fn pop(&self) -> Result<Vec<u8>> {
+        loop {
+            let next_due = match Self::try_pop(&mut self.state.lock().unwrap())? {
+                Ok(frame) => return Ok(frame),
+                Err(next_due) => next_due,
+            };
+            match next_due {
+                Some(due) => {
+                    tokio::select! {
+                        biased;
+                        _ = self.available.notified() => {}
+                        _ = tokio::time::sleep_until(due) => {}
+                    }
+                }
+                None => self.available.notified().await,
+            }
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn pop(&self) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let deadline = state
+            .read_timeout
+            .and_then(|t| Instant::now().checked_add(t));
+        loop {
+            let next_due = match Self::try_pop(&mut state)? {
+                Ok(frame) => return Ok(frame),
+                Err(next_due) => next_due,
+            };
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+            }
+            state = match next_due.into_iter().chain(deadline).min() {
+                Some(wake) => {
+                    self.available
+                        .wait_timeout(state, wake.saturating_duration_since(now))
+                        .unwrap()
+                        .0
+                }
+                None => self.available.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+/// A transport wrapper that drops, delays, duplicates, reorders, truncates or answers frames,
+/// as decided by a [`FaultPolicy`], to test how the client copes with network failures
+/// and misbehaving servers.
+///
+/// Frames sent by the client are handled as they are sent. Frames received from the server
+/// are read in the background, and handled as soon as they arrive.
+///
+/// ```
+/// # use smb::{*, testing::*};
+/// # #[cfg(not(feature = "async"))] fn main() {}
+/// # #[cfg(feature = "async")]
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> smb::Result<()> {
+/// let server = FakeServer::new(FakeServerConfig::default());
+/// let (client, server_side) = MemoryTransport::pair();
+/// server.start(server_side);
+///
+/// // Lose the response to the first echo.
+/// let policy = FaultRules::new().rule(
+///     FaultRule::new(FaultAction::Drop)
+///         .direction(FrameDirection::Incoming)
+///         .command(Command::Echo)
+///         .times(1),
+/// );
+/// let transport = FaultyTransport::new(Box::new(client), policy);
+/// let faults = transport.controller();
+/// let connection = Connection::from_transport(
+///     Box::new(transport),
+///     FakeServer::SERVER_NAME,
+///     Guid::generate(),
+///     ConnectionConfig::default(),
+/// )
+/// .await?;
+/// # drop((connection, faults));
+/// # Ok(()) }
+/// ```
+pub struct FaultyTransport {
+    /// The wrapped transport, until it is connected and split.
+    inner: Option<Box<dyn SmbTransport>>,
+    halves: Option<(FaultyReader, FaultyWriter)>,
+    default_port: u16,
+    remote_address: Option<SocketAddr>,
+    controller: FaultController,
+}
+
+impl FaultyTransport {
+    /// Wraps the transport, applying the policy to the frames passing through it.
+    pub fn new(inner: Box<dyn SmbTransport>, policy: impl FaultPolicy + 'static) -> Self {
+        Self {
+            default_port: inner.default_port(),
+            inner: Some(inner),
+            halves: None,
+            remote_address: None,
+            controller: FaultController {
+                state: Arc::new(Mutex::new(FaultState {
+                    policy: Box::new(policy),
+                    next_index: [0; 2],
+                    held: [None, None],
+                    next_async_id: 0,
+                    history: vec![],
+                })),
+                incoming: Default::default(),
+            },
+        }
+    }
+
+    /// Returns a controller of the transport, to change its policy and inspect its history.
+    pub fn controller(&self) -> FaultController {
+        self.controller.clone()
+    }
+
+    /// Returns the halves of the transport, splitting the wrapped transport on first use.
+    fn halves(&mut self) -> Result<&mut (FaultyReader, FaultyWriter)> {
+        if self.halves.is_none() {
+            let inner = self.inner.take().ok_or(TransportError::NotConnected)?;
+            self.remote_address = inner.remote_address().ok();
+            let (reader, writer) = inner.split()?;
+            let reader = FaultyReader::new(reader, self.controller.clone());
+            let writer = FaultyWriter {
+                inner: writer,
+                controller: self.controller.clone(),
+            };
+            self.halves = Some((reader, writer));
+        }
+        Ok(self.halves.as_mut().unwrap())
+    }
+}
+
+impl SmbTransport for FaultyTransport {
+    #[cfg(feature = "async")]
+    fn connect<'a>(
+        &'a mut self,
+        server_name: &'a str,
+        address: SocketAddr,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let inner = self.inner.as_mut().ok_or(TransportError::NotConnected)?;
+            inner.connect(server_name, address).await
+        }
+        .boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn connect(&mut self, server_name: &str, address: SocketAddr) -> Result<()> {
+        let inner = self.inner.as_mut().ok_or(TransportError::NotConnected)?;
+        inner.connect(server_name, address)
+    }
+
+    fn default_port(&self) -> u16 {
+        self.default_port
+    }
+
+    fn split(self: Box<Self>) -> Result<(Box<dyn SmbTransportRead>, Box<dyn SmbTransportWrite>)> {
+        let mut this = *self;
+        this.halves()?;
+        let (reader, writer) = this.halves.take().unwrap();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    fn remote_address(&self) -> Result<SocketAddr> {
+        match &self.inner {
+            Some(inner) => inner.remote_address(),
+            None => self.remote_address.ok_or(TransportError::NotConnected),
+        }
+    }
+}
+
+impl SmbTransportRead for FaultyTransport {
+    #[cfg(feature = "async")]
+    fn receive_exact<'a>(&'a mut self, out_buf: &'a mut [u8]) -> BoxFuture<'a, Result<()>> {
+        async move { self.halves()?.0.receive_exact(out_buf).await }.boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn receive_exact(&mut self, out_buf: &mut [u8]) -> Result<()> {
+        self.halves()?.0.receive_exact(out_buf)
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn set_read_timeout(&self, timeout: Duration) -> Result<()> {
+        self.controller.incoming.set_read_timeout(timeout);
+        Ok(())
+    }
+}
+
+impl SmbTransportWrite for FaultyTransport {
+    #[cfg(feature = "async")]
+    fn send_raw<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move { self.halves()?.1.send_raw(buf).await }.boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn send_raw(&mut self, buf: &[u8]) -> Result<()> {
+        self.halves()?.1.send_raw(buf)
+    }
+
+    #[cfg(feature = "async")]
+    fn send<'a>(&'a mut self, data: &'a IoVec) -> BoxFuture<'a, Result<()>> {
+        async move { self.halves()?.1.send(data).await }.boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn send(&mut self, data: &IoVec) -> Result<()> {
+        self.halves()?.1.send(data)
+    }
+}
+
+/// The receiving half of a [`FaultyTransport`].
+struct FaultyReader {
+    controller: FaultController,
+    /// The rest of the frame being received, including its transport header.
+    pending: VecDeque<u8>,
+    #[cfg(feature = "async")]
+    pump: tokio::task::JoinHandle<()>,
+}
+
+impl FaultyReader {
+    fn new(reader: Box<dyn SmbTransportRead>, controller: FaultController) -> Self {
+        #[cfg(not(feature = "async"))]
+        Self::start_pump(reader, controller.clone());
+        Self {
+            #[cfg(feature = "async")]
+            pump: Self::start_pump(reader, controller.clone()),
+            controller,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Starts reading frames from the server in the background, applying the policy to them.
+    #[cfg(feature = "async")]
+    fn start_pump(
+        mut reader: Box<dyn SmbTransportRead>,
+        controller: FaultController,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Ok(frame) = reader.receive().await {
+                let decision = controller.decide(FrameDirection::Incoming, frame);
+                controller.incoming.push(decision.pass, decision.delay);
+                if decision.close {
+                    break;
+                }
+            }
+            controller.incoming.close();
+        })
+    }
+
+    /// Starts reading frames from the server in the background, applying the policy to them.
+    ///
+    /// The thread exits once the underlying transport is disconnected.
+    #[cfg(not(feature = "async"))]
+    fn start_pump(mut reader: Box<dyn SmbTransportRead>, controller: FaultController) {
+        std::thread::spawn(move || {
+            while let Ok(frame) = reader.receive() {
+                if controller.incoming.is_closed() {
+                    break;
+                }
+                let decision = controller.decide(FrameDirection::Incoming, frame);
+                controller.incoming.push(decision.pass, decision.delay);
+                if decision.close {
+                    break;
+                }
+            }
+            controller.incoming.close();
+        });
+    }
+
+    fn take_pending(&mut self, out_buf: &mut [u8]) -> bool {
+        let len = out_buf.len();
+        if self.pending.len() < len {
+            return false;
+        }
+        for (dst, src) in out_buf.iter_mut().zip(self.pending.drain(..len)) {
+            *dst = src;
+        }
+        true
+    }
+
+    fn add_pending(&mut self, frame: Vec<u8>) -> Result<()> {
+        let header = SmbTcpMessageHeader {
+            stream_protocol_length: frame.len() as u32,
+        };
+        let mut header_buf = Vec::with_capacity(SmbTcpMessageHeader::SIZE);
+        header.write(&mut Cursor::new(&mut header_buf))?;
+        self.pending.extend(header_buf);
+        self.pending.extend(frame);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for FaultyReader {
+    fn drop(&mut self) {
+        self.pump.abort();
+    }
+}
+
+impl SmbTransportRead for FaultyReader {
+    #[cfg(feature = "async")]
+    fn receive_exact<'a>(&'a mut self, out_buf: &'a mut [u8]) -> BoxFuture<'a, Result<()>> {
+        async {
+            while !self.take_pending(out_buf) {
+                let frame = self.controller.incoming.pop().await?;
+                self.add_pending(frame)?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn receive_exact(&mut self, out_buf: &mut [u8]) -> Result<()> {
+        while !self.take_pending(out_buf) {
+            let frame = self.controller.incoming.pop()?;
+            self.add_pending(frame)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn set_read_timeout(&self, timeout: Duration) -> Result<()> {
+        self.controller.incoming.set_read_timeout(timeout);
+        Ok(())
+    }
+}
+
+/// The sending half of a [`FaultyTransport`].
+struct FaultyWriter {
+    inner: Box<dyn SmbTransportWrite>,
+    controller: FaultController,
+}
+
+impl FaultyWriter {
+    fn prepare(&self, data: &IoVec) -> Result<Decision> {
+        if self.controller.incoming.is_closed() {
+            return Err(TransportError::NotConnected);
+        }
+        let frame = data
+            .iter()
+            .flat_map(|buf| buf.as_ref().iter().copied())
+            .collect();
+        let decision = self.controller.decide(FrameDirection::Outgoing, frame);
+        if decision.close {
+            self.controller.incoming.close();
+            return Err(TransportError::NotConnected);
+        }
+        Ok(decision)
+    }
+}
+
+impl SmbTransportWrite for FaultyWriter {
+    #[cfg(feature = "async")]
+    fn send_raw<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.inner.send_raw(buf)
+    }
+    #[cfg(not(feature = "async"))]
+    fn send_raw(&mut self, buf: &[u8]) -> Result<()> {
+        self.inner.send_raw(buf)
+    }
+
+    #[cfg(feature = "async")]
+    fn send<'a>(&'a mut self, data: &'a IoVec) -> BoxFuture<'a, Result<()>> {
+        async {
+            let decision = self.prepare(data)?;
+            if let Some(delay) = decision.delay {
+                tokio::time::sleep(delay).await;
+            }
+            for frame in decision.pass {
+                self.inner.send(&IoVec::from(frame)).await?;
+            }
+            self.controller.incoming.push(decision.inject, None);
+            Ok(())
+        }
+        .boxed()
+    }
+    #[cfg(not(feature = "async"))]
+    fn send(&mut self, data: &IoVec) -> Result<()> {
+        let decision = self.prepare(data)?;
+        if let Some(delay) = decision.delay {
+            std::thread::sleep(delay);
+        }
+        for frame in decision.pass {
+            self.inner.send(&IoVec::from(frame))?;
+        }
+        self.controller.incoming.push(decision.inject, None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConnectionConfig;
+    use crate::testing::{FakeServer, FakeServerConfig};
+    use smb_transport::MemoryTransport;
+
+    fn config() -> ConnectionConfig {
+        ConnectionConfig {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        }
+    }
+
+    fn frame(direction: FrameDirection, index: u64, command: Option<Command>) -> FaultFrame {
+        FaultFrame {
+            direction,
+            index,
+            command,
+            message_id: None,
+            data: vec![],
+        }
+    }
+
+    #[test]
+    fn test_fault_rules_selectors() {
+        use FrameDirection::*;
+        let mut rules = FaultRules::new()
+            .rule(
+                FaultRule::new(FaultAction::Drop)
+                    .direction(Outgoing)
+                    .command(Command::Read)
+                    .skip(1)
+                    .times(2),
+            )
+            .rule(FaultRule::new(FaultAction::Duplicate).direction(Incoming));
+
+        // Frames of other commands, or in the other direction, do not match the first rule.
+        assert_eq!(
+            rules.on_frame(&frame(Outgoing, 0, Some(Command::Write))),
+            FaultAction::Pass
+        );
+        assert_eq!(
+            rules.on_frame(&frame(Incoming, 0, Some(Command::Read))),
+            FaultAction::Duplicate
+        );
+        // The first matching frame is skipped, the next two are dropped, and the rest pass.
+        let actions = (1..5)
+            .map(|index| rules.on_frame(&frame(Outgoing, index, Some(Command::Read))))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [
+                FaultAction::Pass,
+                FaultAction::Drop,
+                FaultAction::Drop,
+                FaultAction::Pass
+            ]
+        );
+        // Frames that are not plain messages only match rules without a command.
+        assert_eq!(rules.on_frame(&frame(Outgoing, 5, None)), FaultAction::Pass);
+        assert_eq!(
+            rules.on_frame(&frame(Incoming, 1, None)),
+            FaultAction::Duplicate
+        );
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_dropped_request() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Drop)
+                .direction(FrameDirection::Outgoing)
+                .command(Command::TreeConnect)
+                .times(1),
+        );
+        let (connection, faults) = server.connect_with_faults(config(), policy).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+
+        let share = server.share_path("share");
+        let result = session.tree_connect(&share).await;
+        assert!(matches!(result, Err(crate::Error::OperationTimeout(..))));
+        // Unlike a dropped response, the request never reached the server.
+        assert!(!server.stats().requests.contains_key(&Command::TreeConnect));
+        let history = faults.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, FrameDirection::Outgoing);
+        assert_eq!(history[0].command, Some(Command::TreeConnect));
+        assert_eq!(history[0].action, FaultAction::Drop);
+
+        // The rule applied once: the next request passes.
+        session.tree_connect(&share).await.unwrap();
+        assert_eq!(faults.history().len(), 1);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_delayed_response() {
+        const DELAY: Duration = Duration::from_millis(300);
+        let server = FakeServer::new(FakeServerConfig::default());
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Delay(DELAY))
+                .direction(FrameDirection::Incoming)
+                .command(Command::TreeConnect),
+        );
+        let (connection, faults) = server.connect_with_faults(config(), policy).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+
+        let start = std::time::Instant::now();
+        let tree = session.tree_connect(&server.share_path("share")).await;
+        assert!(tree.is_ok());
+        assert!(start.elapsed() >= DELAY);
+        let history = faults.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, FrameDirection::Incoming);
+        assert_eq!(history[0].action, FaultAction::Delay(DELAY));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_duplicated_response() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Duplicate)
+                .direction(FrameDirection::Incoming)
+                .command(Command::TreeConnect)
+                .times(1),
+        );
+        let (connection, faults) = server.connect_with_faults(config(), policy).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+
+        let share = server.share_path("share");
+        let tree = session.tree_connect(&share).await;
+        assert!(tree.is_ok());
+        let history = faults.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].command, Some(Command::TreeConnect));
+        assert_eq!(history[0].action, FaultAction::Duplicate);
+
+        let next = session.tree_connect(&share).await;
+        // Parallel workers keep the unexpected copy aside, while the single worker
+        // receives it in place of the next response.
+        #[cfg(not(feature = "single_threaded"))]
+        assert!(next.is_ok());
+        #[cfg(feature = "single_threaded")]
+        assert!(matches!(next, Err(crate::Error::UnexpectedMessageId(..))));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_held_frames() {
+        let (client, mut server) = MemoryTransport::pair();
+        let policy = FaultRules::new().rule(FaultRule::new(FaultAction::Hold).times(1));
+        let transport = FaultyTransport::new(Box::new(client), policy);
+        let faults = transport.controller();
+        let (mut reader, mut writer) = (Box::new(transport) as Box<dyn SmbTransport>)
+            .split()
+            .unwrap();
+
+        // The first outgoing frame is held back, and passed right after the second one.
+        for frame in [b"first", b"secnd"] {
+            writer.send(&IoVec::from(frame.to_vec())).await.unwrap();
+        }
+        let received = server.receive().await.unwrap();
+        assert_eq!(received, b"secnd");
+        let received = server.receive().await.unwrap();
+        assert_eq!(received, b"first");
+
+        // The rule applied once: incoming frames pass in order.
+        for frame in [b"third", b"forth"] {
+            server.send(&IoVec::from(frame.to_vec())).await.unwrap();
+        }
+        let received = reader.receive().await.unwrap();
+        assert_eq!(received, b"third");
+        let received = reader.receive().await.unwrap();
+        assert_eq!(received, b"forth");
+
+        let history = faults.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, FrameDirection::Outgoing);
+        assert_eq!(history[0].index, 0);
+        assert_eq!(history[0].command, None);
+        assert_eq!(history[0].action, FaultAction::Hold);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_truncated_response() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Truncate(32))
+                .direction(FrameDirection::Incoming)
+                .command(Command::TreeConnect),
+        );
+        let (connection, faults) = server.connect_with_faults(config(), policy).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+
+        let result = session.tree_connect(&server.share_path("share")).await;
+        // Parallel workers discard the malformed frame, and keep waiting for the response,
+        // while the single worker fails to parse it in place of the response.
+        #[cfg(not(feature = "single_threaded"))]
+        assert!(matches!(result, Err(crate::Error::OperationTimeout(..))));
+        #[cfg(feature = "single_threaded")]
+        assert!(matches!(result, Err(crate::Error::BinRWError(..))));
+        let history = faults.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, FrameDirection::Incoming);
+        assert_eq!(history[0].action, FaultAction::Truncate(32));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_closed_connection() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Close)
+                .direction(FrameDirection::Outgoing)
+                .command(Command::TreeConnect),
+        );
+        let (connection, faults) = server.connect_with_faults(config(), policy).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+
+        let share = server.share_path("share");
+        let result = session.tree_connect(&share).await;
+        assert!(result.is_err());
+        assert!(!server.stats().requests.contains_key(&Command::TreeConnect));
+        // The connection is gone for good.
+        let result = connection.authenticate(server.identity()).await;
+        assert!(result.is_err());
+
+        let history = faults.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, FrameDirection::Outgoing);
+        assert_eq!(history[0].command, Some(Command::TreeConnect));
+        assert_eq!(history[0].action, FaultAction::Close);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_dropped_response_times_out() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Drop)
+                .direction(FrameDirection::Incoming)
+                .command(Command::TreeConnect)
+                .times(1),
+        );
+        let config = ConnectionConfig {
+            timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let (connection, faults) = server.connect_with_faults(config, policy).await.unwrap();
+        let session = connection.authenticate(server.identity()).await.unwrap();
+
+        let share = server.share_path("share");
+        let result = session.tree_connect(&share).await;
+        assert!(matches!(result, Err(crate::Error::OperationTimeout(..))));
+        let history = faults.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, FrameDirection::Incoming);
+        assert_eq!(history[0].command, Some(Command::TreeConnect));
+        assert_eq!(history[0].action, FaultAction::Drop);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_faults_injected_status() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Respond(Status::AccessDenied))
+                .command(Command::SessionSetup)
+                .skip(1),
+        );
+        let config = ConnectionConfig {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let (connection, faults) = server.connect_with_faults(config, policy).await.unwrap();
+
+        let error = connection
+            .authenticate(server.identity())
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("Access Denied"));
+        // The second session setup request never reached the server.
+        assert_eq!(server.stats().requests[&Command::SessionSetup], 1);
+        assert_eq!(faults.history().len(), 1);
+
+        faults.disconnect();
+        let result = connection.authenticate(server.identity()).await;
+        assert!(result.is_err());
+    }
+}
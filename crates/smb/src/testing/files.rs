@@ -0,0 +1,193 @@
+//! The in-memory file store of the fake server.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use smb_dtyp::binrw_util::prelude::FileTime;
+use smb_fscc::FileAttributes;
+use smb_msg::{CreateAction, CreateDisposition, CreateOptions, Status};
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+/// A file or a directory, stored in the [`FakeServer`][super::FakeServer].
+#[derive(Debug, Clone)]
+pub struct FakeFile {
+    /// The unique ID of the file, kept when it is renamed.
+    pub id: u64,
+    pub data: Vec<u8>,
+    pub is_dir: bool,
+    pub created: FileTime,
+    pub modified: FileTime,
+}
+
+impl FakeFile {
+    pub fn new_file(data: Vec<u8>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let now = Self::now();
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            data,
+            is_dir: false,
+            created: now,
+            modified: now,
+        }
+    }
+
+    pub fn new_dir() -> Self {
+        Self {
+            is_dir: true,
+            ..Self::new_file(vec![])
+        }
+    }
+
+    pub fn attributes(&self) -> FileAttributes {
+        if self.is_dir {
+            FileAttributes::new().with_directory(true)
+        } else {
+            FileAttributes::new().with_archive(true)
+        }
+    }
+
+    /// The allocation size of the file, rounded up to a 4KiB cluster.
+    pub fn allocation_size(&self) -> u64 {
+        (self.data.len() as u64).div_ceil(4096) * 4096
+    }
+
+    pub(super) fn now() -> FileTime {
+        let now = OffsetDateTime::now_utc();
+        PrimitiveDateTime::new(now.date(), now.time()).into()
+    }
+}
+
+/// Files of all the shares, keyed by share name and path.
+///
+/// Share names are case-insensitive, and paths are normalized to use backslashes,
+/// without leading or trailing separators. The root directory of a share always exists.
+#[derive(Debug, Default)]
+pub(super) struct FileStore {
+    files: BTreeMap<(String, String), FakeFile>,
+}
+
+impl FileStore {
+    pub fn key(share: &str, path: &str) -> (String, String) {
+        let path = path.replace('/', "\\");
+        (share.to_lowercase(), path.trim_matches('\\').to_string())
+    }
+
+    pub fn get(&self, key: &(String, String)) -> Option<&FakeFile> {
+        self.files.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &(String, String)) -> Option<&mut FakeFile> {
+        self.files.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: (String, String), file: FakeFile) {
+        self.files.insert(key, file);
+    }
+
+    pub fn remove(&mut self, key: &(String, String)) -> Option<FakeFile> {
+        self.files.remove(key)
+    }
+
+    /// Returns the names and files of the direct children of a directory.
+    pub fn children(&self, key: &(String, String)) -> Vec<(String, &FakeFile)> {
+        self.files
+            .iter()
+            .filter(|((share, _), _)| *share == key.0)
+            .filter_map(|((_, path), file)| {
+                let name = match path.rsplit_once('\\') {
+                    Some((parent, name)) if *parent == key.1 => name,
+                    None if key.1.is_empty() && !path.is_empty() => path.as_str(),
+                    _ => return None,
+                };
+                Some((name.to_string(), file))
+            })
+            .collect()
+    }
+
+    /// Moves a file or a directory, along with its descendants.
+    pub fn rename(&mut self, from: &(String, String), to: &(String, String)) -> Result<(), Status> {
+        if self.files.contains_key(to) {
+            return Err(Status::ObjectNameCollision);
+        }
+        let descendants = format!("{}\\", from.1);
+        let moved = self
+            .files
+            .keys()
+            .filter(|(share, path)| {
+                *share == from.0 && (*path == from.1 || path.starts_with(&descendants))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if moved.is_empty() {
+            return Err(Status::ObjectNameNotFound);
+        }
+        for key in moved {
+            let file = self.files.remove(&key).unwrap();
+            let path = format!("{}{}", to.1, &key.1[from.1.len()..]);
+            self.files.insert((to.0.clone(), path), file);
+        }
+        Ok(())
+    }
+
+    /// Opens or creates a file, according to the disposition and options of a create request.
+    pub fn open(
+        &mut self,
+        key: &(String, String),
+        disposition: CreateDisposition,
+        options: &CreateOptions,
+    ) -> Result<(CreateAction, &FakeFile), Status> {
+        if key.1.is_empty() {
+            // Share root
+            self.files
+                .entry(key.clone())
+                .or_insert_with(FakeFile::new_dir);
+        }
+
+        let action = match (self.files.get_mut(key), disposition) {
+            (Some(_), CreateDisposition::Create) => return Err(Status::ObjectNameCollision),
+            (Some(_), CreateDisposition::Open | CreateDisposition::OpenIf) => CreateAction::Opened,
+            (Some(file), _) => {
+                if file.is_dir {
+                    return Err(Status::FileIsADirectory);
+                }
+                file.data.clear();
+                file.modified = FakeFile::now();
+                CreateAction::Overwritten
+            }
+            (None, CreateDisposition::Open | CreateDisposition::Overwrite) => {
+                return Err(Status::ObjectNameNotFound);
+            }
+            (None, _) => {
+                let parent = match key.1.rsplit_once('\\') {
+                    Some((parent, _)) => parent,
+                    None => "",
+                };
+                if !parent.is_empty()
+                    && !self
+                        .files
+                        .get(&(key.0.clone(), parent.to_string()))
+                        .is_some_and(|f| f.is_dir)
+                {
+                    return Err(Status::ObjectPathNotFound);
+                }
+                let file = if options.directory_file() {
+                    FakeFile::new_dir()
+                } else {
+                    FakeFile::new_file(vec![])
+                };
+                self.files.insert(key.clone(), file);
+                CreateAction::Created
+            }
+        };
+
+        let file = self.files.get(key).unwrap();
+        if file.is_dir && options.non_directory_file() {
+            return Err(Status::FileIsADirectory);
+        }
+        if !file.is_dir && options.directory_file() {
+            return Err(Status::ObjectNameCollision);
+        }
+        Ok((action, file))
+    }
+}
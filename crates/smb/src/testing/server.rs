@@ -0,0 +1,1983 @@
+//! The fake server: configuration, scripting and the serving loop.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use binrw::prelude::*;
+use maybe_async::maybe_async;
+use smb_dtyp::{Guid, binrw_util::prelude::FileTime};
+use smb_fscc::{
+    ChainedItemList, FileIdBothDirectoryInformation, FileStandardInformation, QueryDirectoryInfo,
+    QueryDirectoryInfoValue, QueryFileInfoValue, SetFileInfo,
+};
+use smb_msg::*;
+use smb_transport::{IoVec, MemoryTransport, SmbTransportRead, SmbTransportWrite};
+
+use super::faults::{FaultController, FaultPolicy, FaultyTransport};
+use super::files::{FakeFile, FileStore};
+use super::session::{FakeSession, SetupStep};
+use crate::compression::{Compressor, Decompressor};
+use crate::connection::preauth_hash::PreauthHashState;
+use crate::dialects::DialectImpl;
+use crate::session::MessageSigner;
+use crate::{Connection, ConnectionConfig, UncPath};
+
+/// Configuration of a [`FakeServer`].
+#[derive(Debug, Clone)]
+pub struct FakeServerConfig {
+    /// The dialect the server selects. Negotiation fails if the client does not offer it.
+    pub dialect: Dialect,
+    /// The only user name accepted by the server.
+    pub user_name: String,
+    /// The password of [`FakeServerConfig::user_name`].
+    pub password: String,
+    /// The preferred signing algorithm (SMB 3.1.1), if offered by the client.
+    pub signing_algo: Option<SigningAlgorithmId>,
+    /// The preferred encryption cipher (SMB 3.1.1), if offered by the client.
+    pub cipher: Option<EncryptionCipher>,
+    /// Compression algorithms the server supports (SMB 3.1.1). Empty disables compression.
+    pub compression_algorithms: Vec<CompressionAlgorithm>,
+    /// Whether to require encryption for all sessions (SMB 3.x).
+    pub encrypt_data: bool,
+    /// The maximal number of credits the client may hold at once.
+    pub max_credits: u16,
+    /// Names of the disk shares. `IPC$` is always available.
+    pub shares: Vec<String>,
+    /// Names of the shares that require encryption (SMB 3.x).
+    pub encrypted_shares: Vec<String>,
+    /// Whether the server grants the leases requested by the client (SMB 2.1 and later).
+    /// Leases are broken only by [`FakeServer::break_lease`].
+    pub leasing: bool,
+    /// Whether the server supports multichannel (SMB 3.x): sessions may be bound to
+    /// other connections to the server. Sessions that encrypt data cannot be bound.
+    pub multichannel: bool,
+}
+
+impl Default for FakeServerConfig {
+    fn default() -> Self {
+        Self {
+            dialect: Dialect::Smb0311,
+            user_name: "user".to_string(),
+            password: "password".to_string(),
+            signing_algo: None,
+            cipher: None,
+            compression_algorithms: vec![],
+            encrypt_data: false,
+            max_credits: 128,
+            shares: vec!["share".to_string()],
+            encrypted_shares: vec![],
+            leasing: false,
+            multichannel: false,
+        }
+    }
+}
+
+/// Counters collected by a [`FakeServer`], across all connections.
+#[derive(Debug, Default, Clone)]
+pub struct FakeServerStats {
+    /// Number of requests received, per command.
+    pub requests: HashMap<Command, usize>,
+    pub signed_requests: usize,
+    pub encrypted_requests: usize,
+    pub compressed_requests: usize,
+    pub compressed_responses: usize,
+    /// Requests sent with message IDs that were not granted by the server.
+    pub credit_violations: usize,
+    /// Signed requests with an invalid signature.
+    pub bad_signatures: usize,
+    /// Pending requests that were cancelled by the client.
+    pub cancelled: usize,
+}
+
+/// A request received by the [`FakeServer`], passed to scripts.
+#[derive(Debug)]
+pub struct FakeRequest {
+    pub header: Header,
+    pub content: RequestContent,
+    /// The data of a write request; empty for other requests.
+    pub write_data: Vec<u8>,
+    /// The name of the share the request's tree is connected to, if any.
+    pub share: Option<String>,
+    pub signed: bool,
+    pub encrypted: bool,
+    pub compressed: bool,
+}
+
+/// How the [`FakeServer`] replies to a request.
+#[derive(Debug)]
+pub enum FakeReply {
+    /// Let the server handle the request, as it would without scripting.
+    Default,
+    /// Reply with a successful response.
+    Respond(ResponseContent),
+    /// Reply with an error response, with the given status.
+    Error(Status),
+    /// Send an interim (`STATUS_PENDING`) response, followed by the given reply.
+    Pending(Box<FakeReply>),
+    /// Send an interim response, and complete the request only when the client cancels it.
+    PendingUntilCancelled,
+    /// Never reply to the request.
+    Drop,
+}
+
+type Script = Arc<dyn Fn(&FakeRequest) -> FakeReply + Send + Sync>;
+
+/// A lease granted by the server, by its lease key.
+struct FakeLease {
+    connection_id: u64,
+    key: (String, String),
+    state: LeaseState,
+    epoch: u16,
+}
+
+/// A message the server sends on its own initiative. It is queued until the next request
+/// of a connection it is for, and sent along with the response.
+enum Unsolicited {
+    /// A lease break, for the connection with the specified ID.
+    LeaseBreak(u64, LeaseBreakNotify),
+    /// An oplock break, for the opens of the file (by its key) of the first connection to have it open.
+    OplockBreak((String, String), OplockLevel),
+    /// A session closed notification, for a connection of the session.
+    SessionClosed(u64),
+}
+
+/// A session that may be bound to other connections.
+struct BindableSession {
+    /// The signer of the first channel of the session, which signs binding requests.
+    signer: MessageSigner,
+}
+
+struct Shared {
+    config: FakeServerConfig,
+    server_guid: Guid,
+    scripts: Vec<(Command, Script)>,
+    files: FileStore,
+    stats: FakeServerStats,
+    leases: HashMap<u128, FakeLease>,
+    /// Messages waiting to be sent, see [`Unsolicited`].
+    unsolicited: Vec<Unsolicited>,
+    /// Sessions that may be bound, by their ID, when [`FakeServerConfig::multichannel`] is set.
+    bindable_sessions: HashMap<u64, BindableSession>,
+    next_connection_id: u64,
+    /// The last ID of a session, tree, open or async operation.
+    /// IDs are unique across connections, so sessions (and their opens) may be bound to several connections.
+    next_id: u64,
+}
+
+/// An in-process, scriptable SMB2 server. See the [module documentation](super) for details.
+///
+/// The server is cheap to clone; all clones share the same files, scripts and stats.
+#[derive(Clone)]
+pub struct FakeServer {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl FakeServer {
+    /// The server name to use when connecting to the fake server.
+    pub const SERVER_NAME: &'static str = "fakeserver";
+
+    pub fn new(config: FakeServerConfig) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                config,
+                server_guid: Guid::generate(),
+                scripts: vec![],
+                files: FileStore::default(),
+                stats: FakeServerStats::default(),
+                leases: HashMap::new(),
+                unsolicited: vec![],
+                bindable_sessions: HashMap::new(),
+                next_connection_id: 1,
+                next_id: 1,
+            })),
+        }
+    }
+
+    /// Scripts the replies to requests of the given command.
+    ///
+    /// Scripts are evaluated from the most recently added one; the first one not returning
+    /// [`FakeReply::Default`] determines the reply.
+    pub fn on<F>(&self, command: Command, script: F) -> &Self
+    where
+        F: Fn(&FakeRequest) -> FakeReply + Send + Sync + 'static,
+    {
+        self.lock().scripts.push((command, Arc::new(script)));
+        self
+    }
+
+    /// Serves a single client connection over the given transport, in the background.
+    ///
+    /// The server stops serving the connection once the client side of the transport is dropped.
+    pub fn start(&self, transport: MemoryTransport) {
+        let connection = ServerConnection::new(self.clone());
+        #[cfg(feature = "async")]
+        tokio::spawn(connection.serve(transport));
+        #[cfg(not(feature = "async"))]
+        std::thread::spawn(move || connection.serve(transport));
+    }
+
+    /// Starts serving a new in-memory connection, and connects a client to it.
+    #[maybe_async]
+    pub async fn connect(&self, config: ConnectionConfig) -> crate::Result<Connection> {
+        let (client, server) = MemoryTransport::pair();
+        self.start(server);
+        Connection::from_transport(
+            Box::new(client),
+            Self::SERVER_NAME,
+            Guid::generate(),
+            config,
+        )
+        .await
+    }
+
+    /// Starts serving a new in-memory connection, and connects a client to it
+    /// through a [`FaultyTransport`] applying the policy.
+    #[maybe_async]
+    pub async fn connect_with_faults(
+        &self,
+        config: ConnectionConfig,
+        policy: impl FaultPolicy + 'static,
+    ) -> crate::Result<(Connection, FaultController)> {
+        let (client, server) = MemoryTransport::pair();
+        self.start(server);
+        let transport = FaultyTransport::new(Box::new(client), policy);
+        let controller = transport.controller();
+        let connection = Connection::from_transport(
+            Box::new(transport),
+            Self::SERVER_NAME,
+            Guid::generate(),
+            config,
+        )
+        .await?;
+        Ok((connection, controller))
+    }
+
+    /// The identity accepted by the server.
+    pub fn identity(&self) -> sspi::AuthIdentity {
+        let config = &self.lock().config;
+        sspi::AuthIdentity {
+            username: sspi::Username::new(&config.user_name, None)
+                .expect("Invalid user name in fake server config"),
+            password: config.password.clone().into(),
+        }
+    }
+
+    /// The UNC path of a share on the server.
+    pub fn share_path(&self, share: &str) -> UncPath {
+        UncPath::new(Self::SERVER_NAME)
+            .and_then(|path| path.with_share(share))
+            .expect("Invalid share name")
+    }
+
+    /// Adds a file to a share, replacing any existing file with the same path.
+    pub fn add_file(&self, share: &str, path: &str, data: Vec<u8>) {
+        self.lock()
+            .files
+            .insert(FileStore::key(share, path), FakeFile::new_file(data));
+    }
+
+    /// Adds a directory to a share.
+    pub fn add_directory(&self, share: &str, path: &str) {
+        self.lock()
+            .files
+            .insert(FileStore::key(share, path), FakeFile::new_dir());
+    }
+
+    /// Renames (moves) a file or a directory in a share.
+    pub fn rename(&self, share: &str, from: &str, to: &str) {
+        self.lock()
+            .files
+            .rename(&FileStore::key(share, from), &FileStore::key(share, to))
+            .expect("Failed to rename file");
+    }
+
+    /// Returns the current content of a file, if it exists.
+    pub fn file(&self, share: &str, path: &str) -> Option<Vec<u8>> {
+        self.lock()
+            .files
+            .get(&FileStore::key(share, path))
+            .filter(|f| !f.is_dir)
+            .map(|f| f.data.clone())
+    }
+
+    /// Breaks the leases held on a file to the specified state, returning the number of broken leases.
+    ///
+    /// The lease break notification is sent to the client holding the lease
+    /// along with the response to its next request.
+    pub fn break_lease(&self, share: &str, path: &str, new_state: LeaseState) -> usize {
+        let key = FileStore::key(share, path);
+        let mut shared = self.lock();
+        let Shared {
+            leases,
+            unsolicited,
+            ..
+        } = &mut *shared;
+        let mut broken = 0;
+        for (lease_key, lease) in leases.iter_mut() {
+            if lease.key != key || lease.state == new_state {
+                continue;
+            }
+            // Breaking a lease without write or handle caching requires no acknowledgment.
+            let ack_required = lease.state.write_caching() || lease.state.handle_caching();
+            lease.epoch = lease.epoch.wrapping_add(1);
+            unsolicited.push(Unsolicited::LeaseBreak(
+                lease.connection_id,
+                LeaseBreakNotify {
+                    new_epoch: lease.epoch,
+                    ack_required: ack_required as u32,
+                    lease_key: Guid::from(lease_key.to_le_bytes()),
+                    current_lease_state: lease.state,
+                    new_lease_state: new_state,
+                },
+            ));
+            lease.state = new_state;
+            broken += 1;
+        }
+        broken
+    }
+
+    /// Breaks the oplocks held on a file to the specified level.
+    ///
+    /// The fake server does not grant oplocks, so a break notification is sent for each open
+    /// of the file, regardless of its oplock level. The notifications are sent to the first
+    /// client having the file open, along with the response to its next request.
+    pub fn break_oplock(&self, share: &str, path: &str, new_level: OplockLevel) {
+        let key = FileStore::key(share, path);
+        self.lock()
+            .unsolicited
+            .push(Unsolicited::OplockBreak(key, new_level));
+    }
+
+    /// Closes a session, as if its user was logged off by an administrator.
+    ///
+    /// The client is notified (`SMB2_NOTIFY_SESSION_CLOSED`, SMB 3.1.1) along with the response
+    /// to its next request on a connection of the session, and the session is removed afterwards.
+    pub fn close_session(&self, session_id: u64) {
+        let mut shared = self.lock();
+        shared.bindable_sessions.remove(&session_id);
+        shared
+            .unsolicited
+            .push(Unsolicited::SessionClosed(session_id));
+    }
+
+    pub fn stats(&self) -> FakeServerStats {
+        self.lock().stats.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap()
+    }
+}
+
+/// Properties of a negotiated connection.
+pub(super) struct Negotiated {
+    pub dialect: Arc<DialectImpl>,
+    pub signing_algo: Option<SigningAlgorithmId>,
+    pub cipher: Option<EncryptionCipher>,
+    pub compression: Option<CompressionCapabilities>,
+    pub preauth_hash: PreauthHashState,
+}
+
+/// Tracks the message IDs the client is allowed to use.
+struct CreditWindow {
+    available: BTreeSet<u64>,
+    next: u64,
+    max: u16,
+}
+
+impl CreditWindow {
+    fn new(max: u16) -> Self {
+        Self {
+            available: BTreeSet::from([0]),
+            next: 1,
+            max,
+        }
+    }
+
+    /// Consumes the message IDs used by a request. Returns false if any of them was not granted.
+    fn consume(&mut self, message_id: u64, charge: u16) -> bool {
+        let end = message_id.saturating_add(charge.max(1) as u64);
+        let mut valid = true;
+        for id in message_id..end {
+            valid &= self.available.remove(&id);
+        }
+        valid
+    }
+
+    /// Grants credits to the client, returning the number of credits granted.
+    fn grant(&mut self, requested: u16) -> u16 {
+        let room = self.max.saturating_sub(self.available.len() as u16);
+        let granted = requested.min(room).max(1);
+        self.available.extend(self.next..self.next + granted as u64);
+        self.next += granted as u64;
+        granted
+    }
+}
+
+/// Where to update the preauth integrity hash with a response.
+enum PreauthUpdate {
+    None,
+    Connection,
+    Session(u64),
+}
+
+/// The result of processing a request.
+struct Outcome {
+    status: Status,
+    content: ResponseContent,
+    session_id: Option<u64>,
+    tree_id: Option<u32>,
+    /// Sign the response, even if the request was not signed.
+    sign: bool,
+    compress: bool,
+    preauth_update: PreauthUpdate,
+}
+
+impl Outcome {
+    fn success(content: impl Into<ResponseContent>) -> Self {
+        Self::with_status(Status::Success, content.into())
+    }
+
+    fn error(status: Status) -> Self {
+        Self::with_status(status, ErrorResponse { error_data: vec![] }.into())
+    }
+
+    fn with_status(status: Status, content: ResponseContent) -> Self {
+        Self {
+            status,
+            content,
+            session_id: None,
+            tree_id: None,
+            sign: false,
+            compress: false,
+            preauth_update: PreauthUpdate::None,
+        }
+    }
+}
+
+struct PendingRequest {
+    async_id: u64,
+    request: FakeRequest,
+}
+
+struct OpenFile {
+    key: (String, String),
+    /// Whether the directory was enumerated since the last restart of the scan.
+    enumerated: bool,
+    /// Whether the file is deleted once closed.
+    delete_on_close: bool,
+}
+
+/// The state of a single connection to the server.
+struct ServerConnection {
+    id: u64,
+    server: FakeServer,
+    negotiated: Option<Negotiated>,
+    credits: CreditWindow,
+    sessions: HashMap<u64, FakeSession>,
+    trees: HashMap<u32, String>,
+    opens: HashMap<u64, OpenFile>,
+    pending: Vec<PendingRequest>,
+}
+
+impl ServerConnection {
+    const MAX_TRANSACT_SIZE: u32 = 0x800000;
+    const COMPRESSION_THRESHOLD: usize = 1024;
+    const FULL_ACCESS: u32 = 0x001f01ff;
+
+    fn new(server: FakeServer) -> Self {
+        let (id, max_credits) = {
+            let mut shared = server.lock();
+            shared.next_connection_id += 1;
+            (shared.next_connection_id, shared.config.max_credits)
+        };
+        Self {
+            id,
+            server,
+            negotiated: None,
+            credits: CreditWindow::new(max_credits),
+            sessions: HashMap::new(),
+            trees: HashMap::new(),
+            opens: HashMap::new(),
+            pending: vec![],
+        }
+    }
+
+    #[maybe_async]
+    async fn serve(mut self, mut transport: MemoryTransport) {
+        loop {
+            let raw = match SmbTransportRead::receive(&mut transport).await {
+                Ok(raw) => raw,
+                Err(_) => return,
+            };
+            let responses = match self.handle(raw) {
+                Ok(responses) => responses,
+                Err(e) => {
+                    log::warn!("Fake server failed to process a message: {e}");
+                    continue;
+                }
+            };
+            let responses = match self.unsolicited() {
+                Ok(mut notifications) => {
+                    notifications.extend(responses);
+                    notifications
+                }
+                Err(e) => {
+                    log::warn!("Fake server failed to send notifications: {e}");
+                    responses
+                }
+            };
+            for response in responses {
+                if SmbTransportWrite::send(&mut transport, &response)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        let mut shared = self.server.lock();
+        shared.next_id += 1;
+        shared.next_id
+    }
+
+    /// Processes a single incoming message, returning the messages to send back.
+    fn handle(&mut self, raw: Vec<u8>) -> crate::Result<Vec<IoVec>> {
+        if raw.starts_with(b"\xffSMB") {
+            return self.handle_smb1_negotiate().map(|r| vec![r]);
+        }
+
+        let (data, encrypted, compressed) = self.unwrap_transforms(raw)?;
+        let message = PlainRequest::read_le(&mut Cursor::new(&data))?;
+        let signed = message.header.flags.signed();
+        let write_data = match &message.content {
+            RequestContent::Write(write) => Self::write_data(&data, write.length)?,
+            _ => vec![],
+        };
+        let request = FakeRequest {
+            share: message
+                .header
+                .tree_id
+                .and_then(|id| self.trees.get(&id).cloned()),
+            header: message.header,
+            content: message.content,
+            write_data,
+            signed,
+            encrypted,
+            compressed,
+        };
+
+        {
+            let mut shared = self.server.lock();
+            let stats = &mut shared.stats;
+            *stats.requests.entry(request.header.command).or_default() += 1;
+            stats.signed_requests += signed as usize;
+            stats.encrypted_requests += encrypted as usize;
+            stats.compressed_requests += compressed as usize;
+        }
+
+        if signed && !self.verify_signature(&request.header, &data) {
+            self.server.lock().stats.bad_signatures += 1;
+            let response = self.respond(&request, Outcome::error(Status::AccessDenied), None)?;
+            return Ok(vec![response]);
+        }
+
+        if let RequestContent::Cancel(_) = request.content {
+            return self.handle_cancel(&request.header);
+        }
+
+        if !self
+            .credits
+            .consume(request.header.message_id, request.header.credit_charge)
+        {
+            self.server.lock().stats.credit_violations += 1;
+        }
+
+        let reply = self.run_scripts(&request);
+        let raw_request = IoVec::from(data);
+        self.reply(request, reply, &raw_request)
+    }
+
+    /// Removes encryption and compression transforms, returning the plain message.
+    fn unwrap_transforms(&mut self, raw: Vec<u8>) -> crate::Result<(Vec<u8>, bool, bool)> {
+        let mut data = raw;
+        let mut encrypted = false;
+        let mut compressed = false;
+
+        if data.starts_with(b"\xfdSMB") {
+            let message = EncryptedMessage::read_le(&mut Cursor::new(&data))?;
+            let decryptor = self
+                .sessions
+                .get_mut(&message.header.session_id)
+                .and_then(|s| s.decryptor.as_mut())
+                .ok_or_else(|| {
+                    crate::Error::InvalidMessage(
+                        "Encrypted message for a session without encryption".to_string(),
+                    )
+                })?;
+            let mut buffer = message.encrypted_message;
+            decryptor.decrypt(
+                &mut buffer,
+                &message.header.aead_bytes(),
+                &message.header.nonce,
+                message.header.signature,
+            )?;
+            data = buffer;
+            encrypted = true;
+        }
+
+        if data.starts_with(b"\xfcSMB") {
+            let message = CompressedMessage::read_le(&mut Cursor::new(&data))?;
+            let caps = self
+                .negotiated
+                .as_ref()
+                .and_then(|n| n.compression.as_ref())
+                .ok_or_else(|| {
+                    crate::Error::InvalidMessage(
+                        "Compressed message without negotiated compression".to_string(),
+                    )
+                })?;
+            data = Decompressor::new(caps).decompress_bytes(&message)?;
+            compressed = true;
+        }
+
+        Ok((data, encrypted, compressed))
+    }
+
+    /// Extracts the data of a write request from the plain message.
+    fn write_data(data: &[u8], length: u32) -> crate::Result<Vec<u8>> {
+        const DATA_OFFSET_POSITION: usize = Header::STRUCT_SIZE + 2;
+        let offset = data
+            .get(DATA_OFFSET_POSITION..DATA_OFFSET_POSITION + 2)
+            .map(|o| u16::from_le_bytes([o[0], o[1]]) as usize);
+        offset
+            .and_then(|offset| data.get(offset..offset + length as usize))
+            .map(|d| d.to_vec())
+            .ok_or_else(|| crate::Error::InvalidMessage("Invalid write data offset".to_string()))
+    }
+
+    /// Returns the signer of the session on this connection.
+    ///
+    /// Until a channel binding the session is set up, its messages are signed by the session's first channel.
+    /// Signers are single-use, so a fresh clone is returned for each message.
+    fn signer(&self, session_id: u64) -> Option<MessageSigner> {
+        self.sessions
+            .get(&session_id)
+            .and_then(|s| s.signer.clone())
+            .or_else(|| {
+                let shared = self.server.lock();
+                let session = shared.bindable_sessions.get(&session_id)?;
+                Some(session.signer.clone())
+            })
+    }
+
+    fn verify_signature(&self, header: &Header, data: &[u8]) -> bool {
+        match self.signer(header.session_id) {
+            Some(mut signer) => signer
+                .verify_signature(&mut header.clone(), &IoVec::from(data.to_vec()))
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    fn run_scripts(&self, request: &FakeRequest) -> FakeReply {
+        let scripts = self
+            .server
+            .lock()
+            .scripts
+            .iter()
+            .rev()
+            .filter(|(command, _)| *command == request.header.command)
+            .map(|(_, script)| script.clone())
+            .collect::<Vec<_>>();
+        scripts
+            .iter()
+            .map(|script| script(request))
+            .find(|reply| !matches!(reply, FakeReply::Default))
+            .unwrap_or(FakeReply::Default)
+    }
+
+    fn reply(
+        &mut self,
+        request: FakeRequest,
+        reply: FakeReply,
+        raw_request: &IoVec,
+    ) -> crate::Result<Vec<IoVec>> {
+        let outcome = match reply {
+            FakeReply::Default => match request.header.command {
+                Command::ChangeNotify => {
+                    return self.reply(request, FakeReply::PendingUntilCancelled, raw_request);
+                }
+                _ => self.builtin(&request, raw_request),
+            },
+            FakeReply::Respond(content) => Outcome::success(content),
+            FakeReply::Error(status) => Outcome::error(status),
+            FakeReply::Pending(then) => {
+                let async_id = self.next_id();
+                let interim = self.interim(&request, async_id)?;
+                let mut responses = vec![interim];
+                let outcome = match *then {
+                    FakeReply::Default => self.builtin(&request, raw_request),
+                    FakeReply::Respond(content) => Outcome::success(content),
+                    FakeReply::Error(status) => Outcome::error(status),
+                    _ => {
+                        return Err(crate::Error::InvalidArgument(
+                            "Pending replies may only be followed by a final reply".to_string(),
+                        ));
+                    }
+                };
+                responses.push(self.respond(&request, outcome, Some(async_id))?);
+                return Ok(responses);
+            }
+            FakeReply::PendingUntilCancelled => {
+                let async_id = self.next_id();
+                let interim = self.interim(&request, async_id)?;
+                self.pending.push(PendingRequest { async_id, request });
+                return Ok(vec![interim]);
+            }
+            FakeReply::Drop => return Ok(vec![]),
+        };
+        let logged_off =
+            request.header.command == Command::Logoff && outcome.status == Status::Success;
+        let response = self.respond(&request, outcome, None)?;
+        if logged_off {
+            self.sessions.remove(&request.header.session_id);
+            self.server
+                .lock()
+                .bindable_sessions
+                .remove(&request.header.session_id);
+        }
+        Ok(vec![response])
+    }
+
+    /// Takes the unsolicited messages queued for this connection, and serializes them.
+    fn unsolicited(&mut self) -> crate::Result<Vec<IoVec>> {
+        let messages = {
+            let mut shared = self.server.lock();
+            let (ours, others) = std::mem::take(&mut shared.unsolicited)
+                .into_iter()
+                .partition::<Vec<_>, _>(|message| match message {
+                    Unsolicited::LeaseBreak(connection_id, _) => *connection_id == self.id,
+                    Unsolicited::OplockBreak(key, _) => {
+                        self.opens.values().any(|open| open.key == *key)
+                    }
+                    Unsolicited::SessionClosed(session_id) => {
+                        self.sessions.contains_key(session_id)
+                    }
+                });
+            shared.unsolicited = others;
+            ours
+        };
+        let mut notifications = vec![];
+        for message in messages {
+            match message {
+                Unsolicited::LeaseBreak(_, notify) => {
+                    notifications.push(Self::notification(ResponseContent::LeaseBreakNotify(
+                        notify,
+                    ))?);
+                }
+                Unsolicited::OplockBreak(key, new_level) => {
+                    for (&id, _) in self.opens.iter().filter(|(_, open)| open.key == key) {
+                        let notify = OplockBreakNotify {
+                            oplock_level: new_level,
+                            file_id: FileId {
+                                persistent: id,
+                                volatile: id,
+                            },
+                        };
+                        notifications.push(Self::notification(
+                            ResponseContent::OplockBreakNotify(notify),
+                        )?);
+                    }
+                }
+                Unsolicited::SessionClosed(session_id) => {
+                    notifications.push(self.session_closed(session_id)?);
+                    self.sessions.remove(&session_id);
+                }
+            }
+        }
+        Ok(notifications)
+    }
+
+    /// Serializes a notification that is not associated with a session (a lease or an oplock break).
+    fn notification(content: ResponseContent) -> crate::Result<IoVec> {
+        let message = PlainResponse {
+            header: Header {
+                credit_charge: 0,
+                status: Status::Success as u32,
+                command: Command::OplockBreak,
+                credit_request: 0,
+                flags: HeaderFlags::new().with_server_to_redir(true),
+                next_command: 0,
+                message_id: u64::MAX,
+                tree_id: Some(0),
+                async_id: None,
+                session_id: 0,
+                signature: 0,
+            },
+            content,
+        };
+        let mut buffer = Vec::new();
+        message.write(&mut Cursor::new(&mut buffer))?;
+        Ok(IoVec::from(buffer))
+    }
+
+    /// Serializes a notification that the session was closed, encrypted if the session requires it.
+    ///
+    /// Like other messages with the message ID -1, the notification is not signed.
+    fn session_closed(&mut self, session_id: u64) -> crate::Result<IoVec> {
+        let encrypt = self
+            .sessions
+            .get(&session_id)
+            .is_some_and(|session| session.encrypt_data);
+        let message = PlainResponse {
+            header: Header {
+                credit_charge: 0,
+                status: Status::Success as u32,
+                command: Command::ServerToClientNotification,
+                credit_request: 0,
+                flags: HeaderFlags::new().with_server_to_redir(true),
+                next_command: 0,
+                message_id: u64::MAX,
+                tree_id: Some(0),
+                async_id: None,
+                session_id,
+                signature: 0,
+            },
+            content: ResponseContent::ServerToClientNotification(ServerToClientNotification::new(
+                Notification::NotifySessionClosed(NotifySessionClosed::default()),
+            )),
+        };
+        self.transform_outgoing(message, false, encrypt, false, PreauthUpdate::None)
+    }
+
+    fn handle_cancel(&mut self, header: &Header) -> crate::Result<Vec<IoVec>> {
+        let position = self.pending.iter().position(|p| match header.async_id {
+            Some(async_id) => p.async_id == async_id,
+            None => p.request.header.message_id == header.message_id,
+        });
+        let Some(position) = position else {
+            return Ok(vec![]);
+        };
+        let pending = self.pending.remove(position);
+        self.server.lock().stats.cancelled += 1;
+        let response = self.respond(
+            &pending.request,
+            Outcome::error(Status::Cancelled),
+            Some(pending.async_id),
+        )?;
+        Ok(vec![response])
+    }
+
+    fn handle_smb1_negotiate(&mut self) -> crate::Result<IoVec> {
+        self.credits.consume(0, 1);
+        let granted = self.credits.grant(1);
+        let response = self.negotiate_response(NegotiateDialect::Smb02Wildcard, None)?;
+        let mut message = PlainResponse::new(response.into());
+        message.header.message_id = 0;
+        message.header.credit_request = granted;
+        message.header.flags.set_server_to_redir(true);
+        let mut buffer = Vec::new();
+        message.write(&mut Cursor::new(&mut buffer))?;
+        Ok(IoVec::from(buffer))
+    }
+
+    /// Builds an interim (`STATUS_PENDING`) response for the request.
+    fn interim(&mut self, request: &FakeRequest, async_id: u64) -> crate::Result<IoVec> {
+        let mut header = self.response_header(request, Status::Pending, None, None);
+        header.credit_request = 0;
+        header.to_async(async_id);
+        self.transform_outgoing(
+            PlainResponse {
+                header,
+                content: ErrorResponse { error_data: vec![] }.into(),
+            },
+            false,
+            request.encrypted,
+            false,
+            PreauthUpdate::None,
+        )
+    }
+
+    fn respond(
+        &mut self,
+        request: &FakeRequest,
+        outcome: Outcome,
+        async_id: Option<u64>,
+    ) -> crate::Result<IoVec> {
+        let mut header =
+            self.response_header(request, outcome.status, outcome.session_id, outcome.tree_id);
+        header.credit_request = self.credits.grant(request.header.credit_request);
+        if let Some(async_id) = async_id {
+            header.to_async(async_id);
+        }
+        self.transform_outgoing(
+            PlainResponse {
+                header,
+                content: outcome.content,
+            },
+            outcome.sign || request.signed,
+            request.encrypted,
+            outcome.compress,
+            outcome.preauth_update,
+        )
+    }
+
+    fn response_header(
+        &self,
+        request: &FakeRequest,
+        status: Status,
+        session_id: Option<u64>,
+        tree_id: Option<u32>,
+    ) -> Header {
+        Header {
+            credit_charge: request.header.credit_charge,
+            status: status as u32,
+            command: request.header.command,
+            credit_request: 0,
+            flags: HeaderFlags::new().with_server_to_redir(true),
+            next_command: 0,
+            message_id: request.header.message_id,
+            tree_id: Some(tree_id.or(request.header.tree_id).unwrap_or(0)),
+            async_id: None,
+            session_id: session_id.unwrap_or(request.header.session_id),
+            signature: 0,
+        }
+    }
+
+    /// Serializes a response, and signs, compresses and encrypts it as required.
+    fn transform_outgoing(
+        &mut self,
+        mut message: PlainResponse,
+        sign: bool,
+        encrypt: bool,
+        compress: bool,
+        preauth_update: PreauthUpdate,
+    ) -> crate::Result<IoVec> {
+        let session_id = message.header.session_id;
+        let is_interim = message.header.status == Status::Pending as u32;
+        let sign = sign && !is_interim && !encrypt;
+        message.header.flags.set_signed(sign);
+
+        let mut buffer = Vec::new();
+        message.write(&mut Cursor::new(&mut buffer))?;
+        let mut data = IoVec::from(buffer);
+
+        if sign {
+            let mut signer = self.signer(session_id).ok_or_else(|| {
+                crate::Error::InvalidState("No signer for the response's session".to_string())
+            })?;
+            signer.sign_message(&mut message.header, &mut data)?;
+        }
+
+        // The hash covers the response as sent, e.g. signed responses of a session binding.
+        match preauth_update {
+            PreauthUpdate::None => {}
+            PreauthUpdate::Connection => {
+                if let Some(negotiated) = self.negotiated.as_mut() {
+                    negotiated.preauth_hash = negotiated.preauth_hash.clone().next(&data);
+                }
+            }
+            PreauthUpdate::Session(id) => {
+                if let Some(session) = self.sessions.get_mut(&id) {
+                    session.next_preauth_hash(&data);
+                }
+            }
+        }
+
+        let compression = self
+            .negotiated
+            .as_ref()
+            .and_then(|n| n.compression.as_ref());
+        if let Some(caps) = compression.filter(|_| compress) {
+            if data.total_size() > Self::COMPRESSION_THRESHOLD {
+                let segments = data.iter().map(|b| &**b).collect::<Vec<_>>();
+                if let Some(compressed) = Compressor::new(caps).compress(&segments)? {
+                    let mut buffer = Vec::with_capacity(compressed.total_size());
+                    compressed.write(&mut Cursor::new(&mut buffer))?;
+                    data = IoVec::from(buffer);
+                    self.server.lock().stats.compressed_responses += 1;
+                }
+            }
+        }
+
+        if encrypt {
+            let encryptor = self
+                .sessions
+                .get_mut(&session_id)
+                .and_then(|s| s.encryptor.as_mut())
+                .ok_or_else(|| {
+                    crate::Error::InvalidState(
+                        "No encryptor for the response's session".to_string(),
+                    )
+                })?;
+            let header = encryptor.encrypt_message(&mut data, session_id)?;
+            let message = EncryptedMessage {
+                header,
+                encrypted_message: data.first().unwrap().to_vec(),
+            };
+            let mut buffer = Vec::new();
+            message.write_le(&mut Cursor::new(&mut buffer))?;
+            data = IoVec::from(buffer);
+        }
+
+        Ok(data)
+    }
+
+    /// Handles a request the same way a real server would.
+    fn builtin(&mut self, request: &FakeRequest, raw_request: &IoVec) -> Outcome {
+        let result = match &request.content {
+            RequestContent::Negotiate(req) => self.negotiate(req, raw_request),
+            RequestContent::SessionSetup(req) => Ok(self.session_setup(request, req, raw_request)),
+            // The session is removed once the response is signed, see `reply`.
+            RequestContent::Logoff(_) => Ok(Outcome::success(LogoffResponse {})),
+            RequestContent::TreeConnect(req) => Ok(self.tree_connect(req)),
+            RequestContent::TreeDisconnect(_) => {
+                if let Some(tree_id) = request.header.tree_id {
+                    self.trees.remove(&tree_id);
+                }
+                Ok(Outcome::success(TreeDisconnectResponse {}))
+            }
+            RequestContent::Create(req) => Ok(self.create(request, req)),
+            RequestContent::Close(req) => Ok(self.close(req)),
+            RequestContent::Flush(_) => Ok(Outcome::success(FlushResponse {})),
+            RequestContent::Read(req) => Ok(self.read(req)),
+            RequestContent::Write(req) => Ok(self.write(request, req)),
+            RequestContent::QueryDirectory(req) => self.query_directory(req),
+            RequestContent::SetInfo(req) => self.set_info(req),
+            RequestContent::QueryInfo(req) => self.query_info(req),
+            RequestContent::LeaseBreakAck(ack) => Ok(Outcome::success(
+                ResponseContent::LeaseBreak(LeaseBreakResponse {
+                    lease_key: ack.lease_key,
+                    lease_state: ack.lease_state,
+                }),
+            )),
+            RequestContent::Echo(_) => Ok(Outcome::success(ResponseContent::Echo(
+                EchoMessage::default(),
+            ))),
+            _ => Ok(Outcome::error(Status::NotSupported)),
+        };
+        result.unwrap_or_else(|e| {
+            log::warn!(
+                "Fake server failed to handle {}: {e}",
+                request.header.command
+            );
+            Outcome::error(Status::InvalidParameter)
+        })
+    }
+
+    fn negotiate(&mut self, req: &NegotiateRequest, raw_request: &IoVec) -> crate::Result<Outcome> {
+        let config = self.server.lock().config.clone();
+        if self.negotiated.is_some() || !req.dialects.contains(&config.dialect) {
+            return Ok(Outcome::error(Status::NotSupported));
+        }
+        let dialect = DialectImpl::new(config.dialect);
+
+        let mut signing_algo = None;
+        let mut cipher = None;
+        let mut compression = None;
+        let mut contexts: Option<Vec<NegotiateContext>> = None;
+        let preauth_hash = if dialect.preauth_hash_supported() {
+            let mut ctx_list: Vec<NegotiateContext> = vec![
+                PreauthIntegrityCapabilities {
+                    hash_algorithms: vec![HashAlgorithm::Sha512],
+                    salt: vec![0; 32],
+                }
+                .into(),
+            ];
+
+            signing_algo = Self::select(
+                config.signing_algo,
+                &[
+                    SigningAlgorithmId::AesGmac,
+                    SigningAlgorithmId::AesCmac,
+                    SigningAlgorithmId::HmacSha256,
+                ],
+                req.get_ctx_signing_capabilities()
+                    .map(|c| c.signing_algorithms.as_slice()),
+                crate::crypto::SIGNING_ALGOS,
+            );
+            if let Some(algo) = signing_algo {
+                ctx_list.push(
+                    SigningCapabilities {
+                        signing_algorithms: vec![algo],
+                    }
+                    .into(),
+                );
+            }
+
+            let offered_ciphers = req
+                .get_ctx_encryption_capabilities()
+                .map(|c| c.ciphers.as_slice());
+            cipher = Self::select(
+                config.cipher,
+                offered_ciphers.unwrap_or_default(),
+                offered_ciphers,
+                crate::crypto::ENCRYPTING_ALGOS,
+            );
+            if let Some(cipher) = cipher {
+                ctx_list.push(
+                    EncryptionCapabilities {
+                        ciphers: vec![cipher],
+                    }
+                    .into(),
+                );
+            }
+
+            let (chained, algorithms) = req
+                .get_ctx_compression_capabilities()
+                .map(|c| {
+                    let algorithms = c
+                        .compression_algorithms
+                        .iter()
+                        .filter(|a| config.compression_algorithms.contains(a))
+                        .copied()
+                        .collect::<Vec<_>>();
+                    (c.flags.chained(), algorithms)
+                })
+                .unwrap_or_default();
+            if !algorithms.is_empty() {
+                let caps = CompressionCapabilities {
+                    flags: CompressionCapsFlags::new().with_chained(chained),
+                    compression_algorithms: algorithms,
+                };
+                ctx_list.push(caps.clone().into());
+                compression = Some(caps);
+            }
+
+            contexts = Some(ctx_list);
+            PreauthHashState::begin().next(raw_request)
+        } else {
+            if config.dialect.is_smb3()
+                && req.capabilities.encryption()
+                && crate::crypto::ENCRYPTING_ALGOS.contains(&EncryptionCipher::Aes128Ccm)
+            {
+                cipher = Some(EncryptionCipher::Aes128Ccm);
+            }
+            PreauthHashState::unsupported()
+        };
+
+        let mut response =
+            self.negotiate_response(Self::negotiate_dialect(config.dialect), contexts)?;
+        response.capabilities = GlobalCapabilities::new()
+            .with_large_mtu(config.dialect > Dialect::Smb0202)
+            .with_encryption(cipher.is_some() && !dialect.preauth_hash_supported())
+            .with_leasing(config.leasing && config.dialect >= Dialect::Smb021)
+            .with_multi_channel(config.multichannel && config.dialect.is_smb3())
+            .with_notifications(
+                config.dialect == Dialect::Smb0311 && req.capabilities.notifications(),
+            );
+
+        self.negotiated = Some(Negotiated {
+            dialect,
+            signing_algo,
+            cipher,
+            compression,
+            preauth_hash,
+        });
+
+        let mut outcome = Outcome::success(response);
+        outcome.preauth_update = PreauthUpdate::Connection;
+        Ok(outcome)
+    }
+
+    /// Selects an algorithm: the preferred one if offered, otherwise the first candidate offered.
+    /// Only algorithms supported by the current build are selected.
+    fn select<T: PartialEq + Copy>(
+        preferred: Option<T>,
+        candidates: &[T],
+        offered: Option<&[T]>,
+        supported: &[T],
+    ) -> Option<T> {
+        let offered = offered?;
+        preferred
+            .into_iter()
+            .chain(candidates.iter().copied())
+            .find(|algo| offered.contains(algo) && supported.contains(algo))
+    }
+
+    fn negotiate_dialect(dialect: Dialect) -> NegotiateDialect {
+        match dialect {
+            Dialect::Smb0202 => NegotiateDialect::Smb0202,
+            Dialect::Smb021 => NegotiateDialect::Smb021,
+            Dialect::Smb030 => NegotiateDialect::Smb030,
+            Dialect::Smb0302 => NegotiateDialect::Smb0302,
+            Dialect::Smb0311 => NegotiateDialect::Smb0311,
+        }
+    }
+
+    fn negotiate_response(
+        &self,
+        dialect_revision: NegotiateDialect,
+        negotiate_context_list: Option<Vec<NegotiateContext>>,
+    ) -> crate::Result<NegotiateResponse> {
+        let server_guid = self.server.lock().server_guid;
+        Ok(NegotiateResponse {
+            security_mode: NegotiateSecurityMode::new().with_signing_enabled(true),
+            dialect_revision,
+            server_guid,
+            capabilities: GlobalCapabilities::new(),
+            max_transact_size: Self::MAX_TRANSACT_SIZE,
+            max_read_size: Self::MAX_TRANSACT_SIZE,
+            max_write_size: Self::MAX_TRANSACT_SIZE,
+            system_time: FakeFile::now(),
+            server_start_time: FileTime::default(),
+            buffer: vec![],
+            negotiate_context_list,
+        })
+    }
+
+    fn session_setup(
+        &mut self,
+        request: &FakeRequest,
+        req: &SessionSetupRequest,
+        raw_request: &IoVec,
+    ) -> Outcome {
+        let Some(preauth_hash) = self.negotiated.as_ref().map(|n| n.preauth_hash.clone()) else {
+            return Outcome::error(Status::InvalidParameter);
+        };
+        let binding = req.flags.binding();
+        let session_id = match request.header.session_id {
+            id if id != 0 && self.sessions.contains_key(&id) => id,
+            id if id == 0
+                || (binding
+                    && request.signed
+                    && self.server.lock().bindable_sessions.contains_key(&id)) =>
+            {
+                let session = match FakeSession::new(preauth_hash) {
+                    Ok(session) => session,
+                    Err(_) => return Outcome::error(Status::LogonFailure),
+                };
+                let id = if binding { id } else { self.next_id() };
+                self.sessions.insert(id, session);
+                id
+            }
+            _ => return Outcome::error(Status::UserSessionDeleted),
+        };
+
+        let config = self.server.lock().config.clone();
+        let negotiated = self.negotiated.as_ref().unwrap();
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        let step = session.accept(
+            raw_request,
+            &req.buffer,
+            negotiated,
+            &config.user_name,
+            &config.password,
+        );
+
+        let mut outcome = match step {
+            Ok(SetupStep::Continue(token)) => {
+                let mut outcome = Outcome::with_status(
+                    Status::MoreProcessingRequired,
+                    SessionSetupResponse {
+                        session_flags: SessionFlags::new(),
+                        buffer: token,
+                    }
+                    .into(),
+                );
+                outcome.preauth_update = PreauthUpdate::Session(session_id);
+                outcome
+            }
+            Ok(SetupStep::Done) => {
+                if binding {
+                    // The channel signs with its own key, but encrypts with the keys of the session,
+                    // which are not shared, since only sessions that do not encrypt are bindable.
+                    session.encryptor = None;
+                    session.decryptor = None;
+                } else {
+                    session.encrypt_data = config.encrypt_data && session.encryptor.is_some();
+                }
+                if config.multichannel && !binding && !session.encrypt_data {
+                    let signer = session.signer.clone().unwrap();
+                    self.server
+                        .lock()
+                        .bindable_sessions
+                        .insert(session_id, BindableSession { signer });
+                }
+                let mut outcome = Outcome::success(SessionSetupResponse {
+                    session_flags: SessionFlags::new().with_encrypt_data(session.encrypt_data),
+                    buffer: vec![],
+                });
+                outcome.sign = true;
+                outcome
+            }
+            Err(e) => {
+                log::debug!("Fake server rejected session setup: {e}");
+                self.sessions.remove(&session_id);
+                Outcome::error(Status::LogonFailure)
+            }
+        };
+        outcome.session_id = Some(session_id);
+        outcome
+    }
+
+    fn tree_connect(&mut self, req: &TreeConnectRequest) -> Outcome {
+        let path = req.buffer.to_string();
+        let share = path.rsplit('\\').next().unwrap_or_default().to_lowercase();
+        let config = self.server.lock().config.clone();
+        let share_type = if share == "ipc$" {
+            ShareType::Pipe
+        } else if config.shares.iter().any(|s| s.eq_ignore_ascii_case(&share)) {
+            ShareType::Disk
+        } else {
+            return Outcome::error(Status::BadNetworkName);
+        };
+        let encrypt_data = config.dialect.is_smb3()
+            && config
+                .encrypted_shares
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(&share));
+
+        let tree_id = self.next_id() as u32;
+        self.trees.insert(tree_id, share);
+        let mut outcome = Outcome::success(TreeConnectResponse {
+            share_type,
+            share_flags: ShareFlags::new().with_encrypt_data(encrypt_data),
+            capabilities: TreeCapabilities::new(),
+            maximal_access: Self::FULL_ACCESS,
+        });
+        outcome.tree_id = Some(tree_id);
+        outcome
+    }
+
+    fn create(&mut self, request: &FakeRequest, req: &CreateRequest) -> Outcome {
+        let Some(share) = request.share.as_ref() else {
+            return Outcome::error(Status::NetworkNameDeleted);
+        };
+        let key = FileStore::key(share, &req.name.to_string());
+        let mut shared = self.server.lock();
+        let lease = match CreateContextRequestData::first_rqls(&req.contexts) {
+            Some(request) if shared.config.leasing && !req.create_options.directory_file() => {
+                Some(self.grant_lease(&mut shared, &key, request))
+            }
+            _ => None,
+        };
+        let (create_action, file) =
+            match shared
+                .files
+                .open(&key, req.create_disposition, &req.create_options)
+            {
+                Ok(result) => result,
+                Err(status) => return Outcome::error(status),
+            };
+        let response = CreateResponse {
+            oplock_level: match lease {
+                Some(_) => OplockLevel::Lease,
+                None => OplockLevel::None,
+            },
+            flags: CreateResponseFlags::new(),
+            create_action,
+            creation_time: file.created,
+            last_access_time: file.modified,
+            last_write_time: file.modified,
+            change_time: file.modified,
+            allocation_size: file.allocation_size(),
+            endof_file: file.data.len() as u64,
+            file_attributes: file.attributes(),
+            file_id: FileId::EMPTY,
+            create_contexts: lease.map(Into::into).into_iter().collect::<Vec<_>>().into(),
+        };
+        drop(shared);
+
+        let id = self.next_id();
+        self.opens.insert(
+            id,
+            OpenFile {
+                key,
+                enumerated: false,
+                delete_on_close: false,
+            },
+        );
+        Outcome::success(CreateResponse {
+            file_id: FileId {
+                persistent: id,
+                volatile: id,
+            },
+            ..response
+        })
+    }
+
+    /// Grants the requested lease, keeping the state of a lease that is already held.
+    fn grant_lease(
+        &self,
+        shared: &mut Shared,
+        key: &(String, String),
+        request: &RequestLease,
+    ) -> RequestLease {
+        let (lease_key, requested) = match request {
+            RequestLease::RqLsReqv1(v1) => (v1.lease_key, v1.lease_state),
+            RequestLease::RqLsReqv2(v2) => (v2.lease_key, v2.lease_state),
+        };
+        let lease = shared.leases.entry(lease_key).or_insert_with(|| FakeLease {
+            connection_id: self.id,
+            key: key.clone(),
+            state: requested,
+            epoch: 0,
+        });
+        match request {
+            RequestLease::RqLsReqv1(_) => RequestLease::RqLsReqv1(RequestLeaseV1 {
+                lease_key,
+                lease_state: lease.state,
+            }),
+            RequestLease::RqLsReqv2(v2) => RequestLease::RqLsReqv2(RequestLeaseV2 {
+                lease_key,
+                lease_state: lease.state,
+                lease_flags: LeaseFlags::new(),
+                parent_lease_key: v2.parent_lease_key,
+                epoch: lease.epoch,
+            }),
+        }
+    }
+
+    fn close(&mut self, req: &CloseRequest) -> Outcome {
+        let Some(open) = self.opens.remove(&req.file_id.volatile) else {
+            return Outcome::error(Status::InvalidParameter);
+        };
+        let mut shared = self.server.lock();
+        let Some(file) = shared.files.get(&open.key) else {
+            return Outcome::error(Status::ObjectNameNotFound);
+        };
+        let outcome = Outcome::success(CloseResponse {
+            flags: CloseFlags::new(),
+            creation_time: file.created,
+            last_access_time: file.modified,
+            last_write_time: file.modified,
+            change_time: file.modified,
+            allocation_size: file.allocation_size(),
+            endof_file: file.data.len() as u64,
+            file_attributes: file.attributes(),
+        });
+        if open.delete_on_close {
+            shared.files.remove(&open.key);
+        }
+        outcome
+    }
+
+    fn read(&mut self, req: &ReadRequest) -> Outcome {
+        let Some(open) = self.opens.get(&req.file_id.volatile) else {
+            return Outcome::error(Status::InvalidParameter);
+        };
+        let shared = self.server.lock();
+        let Some(file) = shared.files.get(&open.key) else {
+            return Outcome::error(Status::ObjectNameNotFound);
+        };
+        let start = req.offset.min(file.data.len() as u64) as usize;
+        let end = (start + req.length as usize).min(file.data.len());
+        if start >= end {
+            return Outcome::error(Status::EndOfFile);
+        }
+        let mut outcome = Outcome::success(ReadResponse {
+            buffer: file.data[start..end].to_vec(),
+        });
+        outcome.compress = req.flags.read_compressed();
+        outcome
+    }
+
+    fn write(&mut self, request: &FakeRequest, req: &WriteRequest) -> Outcome {
+        let Some(open) = self.opens.get(&req.file_id.volatile) else {
+            return Outcome::error(Status::InvalidParameter);
+        };
+        let mut shared = self.server.lock();
+        let Some(file) = shared.files.get_mut(&open.key) else {
+            return Outcome::error(Status::ObjectNameNotFound);
+        };
+        let start = req.offset as usize;
+        let end = start + request.write_data.len();
+        if file.data.len() < end {
+            file.data.resize(end, 0);
+        }
+        file.data[start..end].copy_from_slice(&request.write_data);
+        file.modified = FakeFile::now();
+        Outcome::success(WriteResponse {
+            count: request.write_data.len() as u32,
+        })
+    }
+
+    /// Queries file information. Only [`FileStandardInformation`] is supported.
+    fn query_info(&mut self, req: &QueryInfoRequest) -> crate::Result<Outcome> {
+        let Some(open) = self.opens.get(&req.file_id.volatile) else {
+            return Ok(Outcome::error(Status::InvalidParameter));
+        };
+        if req.info_class != QueryInfoClass::File(FileStandardInformation::CLASS_ID) {
+            return Ok(Outcome::error(Status::NotSupported));
+        }
+        let shared = self.server.lock();
+        let Some(file) = shared.files.get(&open.key) else {
+            return Ok(Outcome::error(Status::ObjectNameNotFound));
+        };
+        let info = FileStandardInformation {
+            allocation_size: file.allocation_size(),
+            end_of_file: file.data.len() as u64,
+            number_of_links: 1,
+            delete_pending: open.delete_on_close.into(),
+            directory: file.is_dir.into(),
+        };
+        let mut data = Cursor::new(vec![]);
+        info.write_le(&mut data)?;
+        Ok(Outcome::success(QueryInfoResponse {
+            data: data.into_inner().into(),
+        }))
+    }
+
+    /// Sets the end of file, the last write time, or the delete disposition of a file.
+    fn set_info(&mut self, req: &SetInfoRequest) -> crate::Result<Outcome> {
+        let Some(open) = self.opens.get_mut(&req.file_id.volatile) else {
+            return Ok(Outcome::error(Status::InvalidParameter));
+        };
+        let (SetInfoData::File(data), SetInfoClass::File(class)) = (&req.data, &req.info_class)
+        else {
+            return Ok(Outcome::error(Status::NotSupported));
+        };
+        let info = data.parse(*class)?;
+        let mut shared = self.server.lock();
+        if let SetFileInfo::DispositionInformation(info) = info {
+            let delete_pending = info.delete_pending.into();
+            if delete_pending && !shared.files.children(&open.key).is_empty() {
+                return Ok(Outcome::error(Status::DirectoryNotEmpty));
+            }
+            open.delete_on_close = delete_pending;
+            return Ok(Outcome::success(SetInfoResponse {}));
+        }
+        let Some(file) = shared.files.get_mut(&open.key) else {
+            return Ok(Outcome::error(Status::ObjectNameNotFound));
+        };
+        match info {
+            SetFileInfo::EndOfFileInformation(info) => {
+                file.data.resize(info.end_of_file as usize, 0);
+                file.modified = FakeFile::now();
+            }
+            SetFileInfo::BasicInformation(info) => {
+                if !info.last_write_time.is_zero() {
+                    file.modified = info.last_write_time;
+                }
+            }
+            _ => return Ok(Outcome::error(Status::NotSupported)),
+        }
+        Ok(Outcome::success(SetInfoResponse {}))
+    }
+
+    /// Lists a directory, in a single response. Only [`FileIdBothDirectoryInformation`] is supported.
+    fn query_directory(&mut self, req: &QueryDirectoryRequest) -> crate::Result<Outcome> {
+        let Some(open) = self.opens.get_mut(&req.file_id.volatile) else {
+            return Ok(Outcome::error(Status::InvalidParameter));
+        };
+        if req.file_information_class != FileIdBothDirectoryInformation::CLASS_ID {
+            return Ok(Outcome::error(Status::NotSupported));
+        }
+        if req.flags.restart_scans() || req.flags.reopen() {
+            open.enumerated = false;
+        }
+        if open.enumerated {
+            return Ok(Outcome::error(Status::NoMoreFiles));
+        }
+        open.enumerated = true;
+
+        let shared = self.server.lock();
+        let entries = shared
+            .files
+            .children(&open.key)
+            .into_iter()
+            .map(|(name, file)| FileIdBothDirectoryInformation {
+                file_index: 0,
+                creation_time: file.created,
+                last_access_time: file.modified,
+                last_write_time: file.modified,
+                change_time: file.modified,
+                end_of_file: file.data.len() as u64,
+                allocation_size: file.allocation_size(),
+                file_attributes: file.attributes(),
+                ea_size: Some(0),
+                reparse_tag: None,
+                short_name_length: 0,
+                short_name: Default::default(),
+                file_id: file.id,
+                file_name: name.as_str().into(),
+            })
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return Ok(Outcome::error(Status::NoMoreFiles));
+        }
+        let mut output_buffer = Cursor::new(vec![]);
+        ChainedItemList::<_, { QueryDirectoryInfo::CHAINED_ALIGNMENT }>::from(entries)
+            .write_le(&mut output_buffer)?;
+        Ok(Outcome::success(QueryDirectoryResponse {
+            output_buffer: output_buffer.into_inner(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "compress_lz77")]
+    use crate::compression::CompressionPolicy;
+    use crate::connection::EncryptionMode;
+    use crate::security::{AuthMechanism, EncryptionScope, SecurityPolicy};
+    use crate::{File, Session, Tree};
+    use smb_fscc::FileAccessMask;
+
+    #[maybe_async]
+    async fn connect_tree(
+        server: &FakeServer,
+        config: ConnectionConfig,
+    ) -> crate::Result<(Connection, Session, Tree)> {
+        let connection = server.connect(config).await?;
+        let session = connection.authenticate(server.identity()).await?;
+        let tree = session.tree_connect(&server.share_path("share")).await?;
+        Ok((connection, session, tree))
+    }
+
+    #[maybe_async]
+    async fn create_file(tree: &Tree) -> crate::Result<File> {
+        let access = FileAccessMask::new()
+            .with_generic_read(true)
+            .with_generic_write(true);
+        Ok(tree
+            .create_file("file.bin", CreateDisposition::OverwriteIf, access)
+            .await?
+            .unwrap_file())
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_roundtrip() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let (_connection, _session, tree) = connect_tree(&server, ConnectionConfig::default())
+            .await
+            .unwrap();
+
+        let data = (0..4096u32).map(|i| i as u8).collect::<Vec<_>>();
+        let file = create_file(&tree).await.unwrap();
+        let written = file.write_block(&data, 0, None).await.unwrap();
+        assert_eq!(written, data.len());
+        file.close().await.unwrap();
+        assert_eq!(server.file("share", "file.bin").as_ref(), Some(&data));
+
+        let file = tree
+            .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        let mut read = vec![0; data.len()];
+        let read_len = file.read_block(&mut read, 0, None, false).await.unwrap();
+        assert_eq!(read_len, data.len());
+        assert_eq!(read, data);
+        file.close().await.unwrap();
+
+        let stats = server.stats();
+        assert_eq!(stats.credit_violations, 0);
+        assert_eq!(stats.bad_signatures, 0);
+        assert!(stats.signed_requests > 0);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_scripted_replies() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.on(Command::Flush, |_| {
+            FakeReply::Pending(Box::new(FakeReply::Error(Status::AccessDenied)))
+        });
+        let (_connection, _session, tree) = connect_tree(&server, ConnectionConfig::default())
+            .await
+            .unwrap();
+
+        let file = create_file(&tree).await.unwrap();
+        let result = file.flush().await;
+        assert!(result.unwrap_err().to_string().contains("Access Denied"));
+        assert_eq!(server.stats().requests[&Command::Flush], 1);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_encrypted_session() {
+        let server = FakeServer::new(FakeServerConfig {
+            encrypt_data: true,
+            ..Default::default()
+        });
+        let config = ConnectionConfig {
+            encryption_mode: EncryptionMode::Required,
+            ..Default::default()
+        };
+        let (_connection, _session, tree) = connect_tree(&server, config).await.unwrap();
+        let file = create_file(&tree).await.unwrap();
+        file.write_block(b"secret", 0, None).await.unwrap();
+        file.close().await.unwrap();
+
+        assert_eq!(server.file("share", "file.bin").unwrap(), b"secret");
+        let stats = server.stats();
+        assert!(stats.encrypted_requests > 0);
+        assert_eq!(stats.credit_violations, 0);
+    }
+
+    #[cfg(all(feature = "encrypt_aes128gcm", feature = "sign_gmac"))]
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_algorithm_preferences() {
+        let server = FakeServer::new(FakeServerConfig {
+            encrypt_data: true,
+            ..Default::default()
+        });
+        let config = ConnectionConfig {
+            encryption_mode: EncryptionMode::Required,
+            encryption_ciphers: Some(vec![EncryptionCipher::Aes128Gcm]),
+            signing_algorithms: Some(vec![SigningAlgorithmId::AesGmac]),
+            ..Default::default()
+        };
+        let (connection, _session, tree) = connect_tree(&server, config).await.unwrap();
+        let negotiation = &connection.conn_info().unwrap().negotiation;
+        assert_eq!(
+            negotiation.encryption_cipher,
+            Some(EncryptionCipher::Aes128Gcm)
+        );
+        assert_eq!(negotiation.signing_algo, Some(SigningAlgorithmId::AesGmac));
+        let file = create_file(&tree).await.unwrap();
+        file.write_block(b"secret", 0, None).await.unwrap();
+        file.close().await.unwrap();
+        assert!(server.stats().encrypted_requests > 0);
+
+        // SMB 3.0.2 always signs with AES-CMAC, which is not allowed.
+        let server = FakeServer::new(FakeServerConfig {
+            dialect: Dialect::Smb0302,
+            ..Default::default()
+        });
+        let config = ConnectionConfig {
+            signing_algorithms: Some(vec![SigningAlgorithmId::AesGmac]),
+            ..Default::default()
+        };
+        let result = server.connect(config).await;
+        assert!(matches!(result, Err(crate::Error::NegotiationError(_))));
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_security_posture() {
+        let server = FakeServer::new(FakeServerConfig {
+            shares: vec!["share".to_string(), "secret".to_string()],
+            encrypted_shares: vec!["secret".to_string()],
+            ..Default::default()
+        });
+        let (connection, session, tree) = connect_tree(&server, ConnectionConfig::default())
+            .await
+            .unwrap();
+        let posture = connection.security_posture().unwrap();
+        assert_eq!(posture.dialect, Dialect::Smb0311);
+        assert!(posture.preauth_integrity);
+        assert_eq!(posture.auth_mechanism, None);
+
+        let posture = session.security_posture().await.unwrap();
+        assert_eq!(posture.auth_mechanism, Some(AuthMechanism::Ntlm));
+        assert!(posture.signing_algorithm.is_some());
+        assert_eq!(posture.encryption_scope, EncryptionScope::None);
+        assert!(!posture.guest && !posture.anonymous);
+        let tree_posture = tree.security_posture().await.unwrap();
+        assert_eq!(tree_posture, posture);
+
+        let secret = session
+            .tree_connect(&server.share_path("secret"))
+            .await
+            .unwrap();
+        let posture = secret.security_posture().await.unwrap();
+        assert_eq!(posture.encryption_scope, EncryptionScope::Share);
+        let policy = SecurityPolicy {
+            require_encryption: true,
+            auth_mechanisms: Some(vec![AuthMechanism::Ntlm]),
+            ..Default::default()
+        };
+        policy.check_tree(&posture).unwrap();
+        let posture = tree.security_posture().await.unwrap();
+        assert!(policy.check_tree(&posture).is_err());
+
+        let file = create_file(&secret).await.unwrap();
+        file.write_block(b"secret", 0, None).await.unwrap();
+        file.close().await.unwrap();
+        assert!(server.stats().encrypted_requests > 0);
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_rejects_anonymous() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        let connection = server.connect(ConnectionConfig::default()).await.unwrap();
+        let result = connection.authenticate(crate::Credentials::Anonymous).await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::AuthenticationRejected(_))
+        ));
+    }
+
+    /// Lets the stream and its fetch loop race on separate threads, which used to lose
+    /// the wakeup of the fetch loop and hang the query.
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_fake_server_query_directory_stream() {
+        use crate::Directory;
+
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_directory("share", "dir");
+        for i in 0..300 {
+            server.add_file("share", &format!("dir\\file{i:03}"), vec![]);
+        }
+        let (_connection, _session, tree) = connect_tree(&server, ConnectionConfig::default())
+            .await
+            .unwrap();
+        let directory = tree
+            .open_existing("dir", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_dir();
+        let directory = Arc::new(directory);
+
+        for _ in 0..50 {
+            let query = Directory::query_all::<FileIdBothDirectoryInformation>(&directory, "*");
+            let entries = tokio::time::timeout(std::time::Duration::from_secs(5), query)
+                .await
+                .expect("directory query hung")
+                .unwrap();
+            assert_eq!(entries.len(), 300);
+        }
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_rejects_unsigned_responses() {
+        use super::super::faults::{FaultAction, FaultRule, FaultRules, FrameDirection};
+
+        let server = FakeServer::new(FakeServerConfig::default());
+        // Replace the tree connect response with an unsigned, successful one.
+        let policy = FaultRules::new().rule(
+            FaultRule::new(FaultAction::Respond(Status::Success))
+                .direction(FrameDirection::Incoming)
+                .command(Command::TreeConnect)
+                .times(1),
+        );
+        let (connection, faults) = server
+            .connect_with_faults(ConnectionConfig::default(), policy)
+            .await
+            .unwrap();
+        // The final, signed session setup response is verified once the channel is set up.
+        let session = connection.authenticate(server.identity()).await.unwrap();
+
+        let result = session.tree_connect(&server.share_path("share")).await;
+        assert!(matches!(result, Err(crate::Error::InvalidMessage(_))));
+        assert_eq!(faults.history().len(), 1);
+    }
+
+    #[cfg(feature = "compress_lz77_huffman")]
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_compressed_transfer() {
+        let server = FakeServer::new(FakeServerConfig {
+            compression_algorithms: crate::compression::SUPPORTED_ALGORITHMS.to_vec(),
+            ..Default::default()
+        });
+        let config = ConnectionConfig {
+            compression_enabled: true,
+            compression_algorithms: Some(vec![CompressionAlgorithm::LZ77Huffman]),
+            ..Default::default()
+        };
+        let (_connection, _session, tree) = connect_tree(&server, config).await.unwrap();
+
+        let data = b"compressible "
+            .iter()
+            .cycle()
+            .take(0x10000)
+            .copied()
+            .collect::<Vec<_>>();
+        let file = create_file(&tree).await.unwrap();
+        file.write_block(&data, 0, None).await.unwrap();
+        file.close().await.unwrap();
+
+        let file = tree
+            .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        let mut read = vec![0; data.len()];
+        let read_len = file.read_block(&mut read, 0, None, false).await.unwrap();
+        assert_eq!(read_len, data.len());
+        assert_eq!(read, data);
+        file.close().await.unwrap();
+
+        assert_eq!(server.file("share", "file.bin").as_ref(), Some(&data));
+        let stats = server.stats();
+        assert!(stats.compressed_requests > 0);
+        assert!(stats.compressed_responses > 0);
+        assert_eq!(stats.bad_signatures, 0);
+    }
+
+    #[cfg(all(feature = "compress_pattern_v1", feature = "compress_lz77"))]
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_chained_compression() {
+        let server = FakeServer::new(FakeServerConfig {
+            compression_algorithms: vec![
+                CompressionAlgorithm::LZ77,
+                CompressionAlgorithm::PatternV1,
+            ],
+            ..Default::default()
+        });
+        let config = ConnectionConfig {
+            compression_enabled: true,
+            ..Default::default()
+        };
+        let (_connection, _session, tree) = connect_tree(&server, config).await.unwrap();
+
+        // A mostly-zero block, like those of a sparse disk image.
+        let mut data = vec![0; 0x8000];
+        data[0x4000..0x4100].copy_from_slice(&[0x5A; 0x100]);
+        let file = create_file(&tree).await.unwrap();
+        file.write_block(&data, 0, None).await.unwrap();
+        file.close().await.unwrap();
+
+        assert_eq!(server.file("share", "file.bin").as_ref(), Some(&data));
+        assert_eq!(server.stats().compressed_requests, 1);
+    }
+
+    #[cfg(feature = "compress_lz77")]
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_fake_server_compression_policy() {
+        let server = FakeServer::new(FakeServerConfig {
+            compression_algorithms: vec![CompressionAlgorithm::LZ77],
+            shares: vec!["share".to_string(), "media".to_string()],
+            ..Default::default()
+        });
+        let config = ConnectionConfig {
+            compression_enabled: true,
+            compression_policy: CompressionPolicy {
+                shares: [("media".to_string(), false)].into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (connection, session, tree) = connect_tree(&server, config).await.unwrap();
+        let compressible = b"compressible ".repeat(0x1000);
+        let incompressible = crate::testing::random_bytes(1, 0x10000);
+
+        let file = create_file(&tree).await.unwrap();
+        file.write_block(&compressible, 0, None).await.unwrap();
+        file.write_block(&incompressible, 0, None).await.unwrap();
+        file.close().await.unwrap();
+        let stats = connection.compression_stats().unwrap();
+        assert_eq!(stats.messages_compressed, 1);
+        assert_eq!(stats.messages_skipped, 1);
+        assert!(stats.bytes_saved() > compressible.len() as u64 / 2);
+        assert_eq!(server.stats().compressed_requests, 1);
+
+        // Compression is disabled for the media share.
+        let media = session
+            .tree_connect(&server.share_path("media"))
+            .await
+            .unwrap();
+        let file = create_file(&media).await.unwrap();
+        file.write_block(&compressible, 0, None).await.unwrap();
+        file.close().await.unwrap();
+        let file = media
+            .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+            .await
+            .unwrap()
+            .unwrap_file();
+        let mut read = vec![0; compressible.len()];
+        file.read_block(&mut read, 0, None, false).await.unwrap();
+        assert_eq!(read, compressible);
+        file.close().await.unwrap();
+        let stats = server.stats();
+        assert_eq!(stats.compressed_requests, 1);
+        assert_eq!(stats.compressed_responses, 0);
+    }
+}
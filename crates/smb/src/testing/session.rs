@@ -0,0 +1,179 @@
+//! Server-side session state of the fake server: authentication & key derivation.
+
+use sspi::{
+    AuthIdentity, BufferType, CredentialUse, DataRepresentation, Ntlm, SecurityBuffer,
+    SecurityStatus, ServerRequestFlags, Sspi, SspiEx, SspiImpl,
+};
+
+use smb_msg::SigningAlgorithmId;
+use smb_transport::IoVec;
+
+use super::server::Negotiated;
+use crate::connection::preauth_hash::PreauthHashState;
+use crate::crypto::{
+    EncryptingAlgo, KeyToDerive, kbkdf_hmacsha256, make_encrypting_algo, make_signing_algo,
+};
+use crate::session::{MessageEncryptor, MessageSigner, SessionAlgosFactory};
+
+/// The result of processing a single session setup request.
+pub(super) enum SetupStep {
+    /// More processing is required; the token should be returned to the client.
+    Continue(Vec<u8>),
+    /// Authentication is complete, and the session is ready.
+    Done,
+}
+
+/// A session, as seen by the fake server.
+pub(super) struct FakeSession {
+    ntlm: Ntlm,
+    credentials: <Ntlm as SspiImpl>::CredentialsHandle,
+    preauth_hash: PreauthHashState,
+    ready: bool,
+
+    pub signer: Option<MessageSigner>,
+    pub encryptor: Option<MessageEncryptor>,
+    /// Decrypts client-to-server messages. [`crate::session::MessageDecryptor`] only handles responses,
+    /// so the algorithm is used directly.
+    pub decryptor: Option<Box<dyn EncryptingAlgo>>,
+    pub encrypt_data: bool,
+}
+
+impl FakeSession {
+    pub fn new(preauth_hash: PreauthHashState) -> crate::Result<Self> {
+        let mut ntlm = Ntlm::new();
+        let credentials = ntlm
+            .acquire_credentials_handle()
+            .with_credential_use(CredentialUse::Inbound)
+            .execute(&mut ntlm)?
+            .credentials_handle;
+        Ok(Self {
+            ntlm,
+            credentials,
+            preauth_hash,
+            ready: false,
+            signer: None,
+            encryptor: None,
+            decryptor: None,
+            encrypt_data: false,
+        })
+    }
+
+    /// Processes the security token of a session setup request.
+    ///
+    /// `raw_request` is the plain request, as received, for the preauth integrity hash.
+    pub fn accept(
+        &mut self,
+        raw_request: &IoVec,
+        token: &[u8],
+        negotiated: &Negotiated,
+        user_name: &str,
+        password: &str,
+    ) -> crate::Result<SetupStep> {
+        if self.ready {
+            return Err(crate::Error::InvalidState(
+                "Session is already set up".to_string(),
+            ));
+        }
+        self.preauth_hash = self.preauth_hash.clone().next(raw_request);
+
+        let mut input = [SecurityBuffer::new(token.to_vec(), BufferType::Token)];
+        let mut output = [SecurityBuffer::new(Vec::new(), BufferType::Token)];
+        let builder = self
+            .ntlm
+            .accept_security_context()
+            .with_credentials_handle(&mut self.credentials)
+            .with_context_requirements(ServerRequestFlags::empty())
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(&mut input)
+            .with_output(&mut output);
+        let result =
+            SspiImpl::accept_security_context_impl(&mut self.ntlm, builder)?.resolve_to_result()?;
+
+        match result.status {
+            SecurityStatus::ContinueNeeded => {
+                let [output] = output;
+                Ok(SetupStep::Continue(output.buffer))
+            }
+            SecurityStatus::CompleteNeeded | SecurityStatus::Ok => {
+                let username = self.ntlm.query_context_names()?.username;
+                if !username.account_name().eq_ignore_ascii_case(user_name) {
+                    return Err(crate::Error::InvalidArgument(format!(
+                        "Unknown user {}",
+                        username.account_name()
+                    )));
+                }
+                self.ntlm.custom_set_auth_identity(AuthIdentity {
+                    username,
+                    password: password.to_string().into(),
+                })?;
+                // Fails on a bad password, since the MIC would not match.
+                self.ntlm.complete_auth_token(&mut [])?;
+
+                let session_key = self.ntlm.query_context_session_key()?;
+                let session_key: KeyToDerive =
+                    session_key.session_key.as_ref()[..16].try_into().unwrap();
+                self.preauth_hash = self.preauth_hash.clone().finish();
+                self.derive_algos(&session_key, negotiated)?;
+                self.ready = true;
+                Ok(SetupStep::Done)
+            }
+            status => Err(crate::Error::InvalidState(format!(
+                "Unexpected NTLM status: {status:?}"
+            ))),
+        }
+    }
+
+    /// Updates the preauth integrity hash with a non-final session setup response.
+    pub fn next_preauth_hash(&mut self, raw_response: &IoVec) {
+        self.preauth_hash = self.preauth_hash.clone().next(raw_response);
+    }
+
+    fn derive_algos(
+        &mut self,
+        session_key: &KeyToDerive,
+        negotiated: &Negotiated,
+    ) -> crate::Result<()> {
+        let preauth_hash = self.preauth_hash.unwrap_final_hash();
+        let context = |no_preauth_ctx: &'static [u8]| match preauth_hash {
+            Some(hash) => hash.as_slice(),
+            None => no_preauth_ctx,
+        };
+        let dialect = &negotiated.dialect;
+
+        let signing_algo = if dialect.dialect.is_smb3() {
+            let signing_key = kbkdf_hmacsha256(
+                session_key,
+                dialect.get_signing_derive_label(),
+                context(SessionAlgosFactory::NO_PREAUTH_HASH_DERIVE_SIGN_CTX),
+            )?;
+            make_signing_algo(
+                negotiated
+                    .signing_algo
+                    .unwrap_or_else(|| dialect.default_signing_algo()),
+                &signing_key,
+            )?
+        } else {
+            make_signing_algo(SigningAlgorithmId::HmacSha256, session_key)?
+        };
+        self.signer = Some(MessageSigner::new(signing_algo));
+
+        if let Some(cipher) = negotiated.cipher {
+            let c2s_key = kbkdf_hmacsha256(
+                session_key,
+                dialect.c2s_encrypt_key_derive_label(),
+                context(SessionAlgosFactory::NO_PREAUTH_HASH_DERIVE_ENCRYPT_C2S_CTX),
+            )?;
+            let s2c_key = kbkdf_hmacsha256(
+                session_key,
+                dialect.s2c_encrypt_key_derive_label(),
+                context(SessionAlgosFactory::NO_PREAUTH_HASH_DERIVE_ENCRYPT_S2C_CTX),
+            )?;
+            self.encryptor = Some(MessageEncryptor::new(make_encrypting_algo(
+                cipher, &s2c_key,
+            )?));
+            self.decryptor = Some(make_encrypting_algo(cipher, &c2s_key)?);
+        }
+
+        Ok(())
+    }
+}
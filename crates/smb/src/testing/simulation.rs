@@ -0,0 +1,187 @@
+//! Deterministic simulation: a simulated clock and network, driven by a seed.
+
+use std::future::Future;
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use super::faults::{FaultAction, FaultController, FaultFrame, FaultPolicy, FrameDirection};
+use super::server::FakeServer;
+use crate::{Connection, ConnectionConfig};
+
+/// Runs clients (and [`FakeServer`]s) deterministically, under a simulated clock and network.
+///
+/// The simulation runs on a single-threaded Tokio runtime, whose clock is paused:
+/// time advances only when all the tasks are idle, jumping straight to the next timer.
+/// Timeouts therefore elapse instantly, and tasks are scheduled in an order that depends only
+/// on their inputs. The only randomness, the latency of each frame received by the client
+/// (see [`SimulatedLatency`]), is drawn from the seed, so a seed reproduces the exact same
+/// interleaving of the connection worker, its waiters and the credit semaphore,
+/// and a failing seed may be replayed to debug it.
+///
+/// ```
+/// # use smb::{*, testing::*};
+/// let simulation = Simulation::new(42);
+/// simulation.run(async {
+///     let server = FakeServer::new(FakeServerConfig::default());
+///     let (connection, _) = simulation
+///         .connect(&server, ConnectionConfig::default())
+///         .await
+///         .unwrap();
+///     let session = connection.authenticate(server.identity()).await.unwrap();
+///     // ...
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    seed: u64,
+    min_latency: Duration,
+    max_latency: Duration,
+}
+
+impl Simulation {
+    /// The default maximal latency of the simulated network.
+    pub const DEFAULT_MAX_LATENCY: Duration = Duration::from_millis(10);
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            min_latency: Duration::ZERO,
+            max_latency: Self::DEFAULT_MAX_LATENCY,
+        }
+    }
+
+    /// Sets the range of the latency of the simulated network.
+    pub fn with_latency(mut self, min: Duration, max: Duration) -> Self {
+        assert!(
+            min <= max,
+            "Minimal latency must not exceed the maximal latency"
+        );
+        self.min_latency = min;
+        self.max_latency = max;
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Runs the future to completion, under the simulated clock.
+    ///
+    /// Must not be called from within another runtime.
+    pub fn run<F: Future>(&self, future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .expect("Failed to build the simulation runtime")
+            .block_on(future)
+    }
+
+    /// Returns a policy simulating the network latency, drawn from the seed.
+    pub fn latency(&self) -> SimulatedLatency {
+        SimulatedLatency {
+            rng: StdRng::seed_from_u64(self.seed),
+            min: self.min_latency,
+            max: self.max_latency,
+        }
+    }
+
+    /// Starts serving a new connection on the fake server, and connects a client to it
+    /// over the simulated network.
+    ///
+    /// The returned controller may be used to inject further faults, see [`FaultController::set_policy`].
+    pub async fn connect(
+        &self,
+        server: &FakeServer,
+        config: ConnectionConfig,
+    ) -> crate::Result<(Connection, FaultController)> {
+        server.connect_with_faults(config, self.latency()).await
+    }
+}
+
+/// A [`FaultPolicy`] delaying each frame received by the client by a random latency,
+/// so responses may be received in a different order than they were sent.
+///
+/// See [`Simulation::latency`].
+pub struct SimulatedLatency {
+    rng: StdRng,
+    min: Duration,
+    max: Duration,
+}
+
+impl FaultPolicy for SimulatedLatency {
+    fn on_frame(&mut self, frame: &FaultFrame) -> FaultAction {
+        match frame.direction {
+            FrameDirection::Outgoing => FaultAction::Pass,
+            FrameDirection::Incoming => FaultAction::Delay(self.rng.gen_range(self.min..=self.max)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeServerConfig, FaultRecord};
+    use futures_util::future::join_all;
+    use smb_fscc::FileAccessMask;
+
+    const REQUESTS: usize = 2000;
+    const BLOCK_SIZE: usize = 64;
+
+    /// Reads a file with many concurrent requests, returning the trace of the received frames.
+    fn concurrent_reads(seed: u64) -> Vec<FaultRecord> {
+        let simulation = Simulation::new(seed);
+        simulation.run(async {
+            let data = (0..REQUESTS * BLOCK_SIZE)
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>();
+            let server = FakeServer::new(FakeServerConfig::default());
+            server.add_file("share", "file.bin", data.clone());
+
+            let (connection, faults) = simulation
+                .connect(&server, ConnectionConfig::default())
+                .await
+                .unwrap();
+            let session = connection.authenticate(server.identity()).await.unwrap();
+            let tree = session
+                .tree_connect(&server.share_path("share"))
+                .await
+                .unwrap();
+            let file = tree
+                .open_existing("file.bin", FileAccessMask::new().with_generic_read(true))
+                .await
+                .unwrap()
+                .unwrap_file();
+
+            let reads = (0..REQUESTS).map(|i| {
+                let file = &file;
+                let expected = &data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE];
+                async move {
+                    let mut buf = [0; BLOCK_SIZE];
+                    let offset = (i * BLOCK_SIZE) as u64;
+                    let read = file
+                        .read_block(&mut buf, offset, None, false)
+                        .await
+                        .unwrap();
+                    assert_eq!(read, BLOCK_SIZE);
+                    assert_eq!(buf, expected);
+                }
+            });
+            join_all(reads).await;
+            file.close().await.unwrap();
+
+            let stats = server.stats();
+            assert_eq!(stats.requests[&smb_msg::Command::Read], REQUESTS);
+            assert_eq!(stats.credit_violations, 0);
+            faults.history()
+        })
+    }
+
+    #[test]
+    fn test_simulation_is_reproducible() {
+        let trace = concurrent_reads(7);
+        assert!(trace.len() > REQUESTS);
+        assert_eq!(concurrent_reads(7), trace);
+    }
+}
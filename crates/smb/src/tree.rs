@@ -6,9 +6,13 @@ use smb_msg::{FileId, FsctlRequest, IoctlRequest, IoctlRequestFlags};
 
 use crate::FileCreateArgs;
 use crate::connection::connection_info::ConnectionInfo;
+use crate::events::SmbEvent;
+use crate::op_options::OpOptions;
+use crate::resource::HandleCache;
+use crate::security::{EncryptionScope, SecurityPosture};
 use smb_fscc::{FileAccessMask, FileAttributes};
 use smb_msg::{
-    CreateOptions, RequestContent, ShareFlags, ShareType,
+    CreateOptions, RequestContent, ShareFlags, ShareType, Status,
     create::CreateDisposition,
     tree_connect::{TreeConnectRequest, TreeDisconnectRequest},
 };
@@ -93,18 +97,26 @@ impl Tree {
             ))?;
 
         log::info!("Connected to tree {name} (#{tree_id})");
+        conn_info.compression.tree_connected(tree_id, name);
 
         let tree_connect_info = TreeConnectInfo {
             share_type: content.share_type,
             share_flags: content.share_flags,
         };
 
+        let handle_cache = HandleCache::new(
+            conn_info,
+            content.share_type,
+            upstream.primary_channel().upstream().lease_router().clone(),
+        );
+
         let t = Tree {
             handler: TreeMessageHandler::new(
                 upstream,
                 tree_id,
                 name.to_string(),
                 tree_connect_info,
+                handle_cache,
             ),
             conn_info: conn_info.clone(),
         };
@@ -125,6 +137,23 @@ impl Tree {
     /// * *DFS operations*: If the share has been opened as a DFS referral share, the create operation will modify the file name to include the DFS path.
     ///     That is, assuming it is NOT prefixed with "\\". This is rquired for a proper DFS referral file open. ("DFS normalization", MS-SMB2 2.2.13 + 3.3.5.9)
     pub async fn create(&self, file_name: &str, args: &FileCreateArgs) -> crate::Result<Resource> {
+        self.create_with(file_name, args, &OpOptions::default())
+            .await
+    }
+
+    /// Creates (or opens) a resource on the remote server, as [Tree::create] does,
+    /// bound by the given per-operation options.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "smb.create",
+        skip_all,
+        fields(tree = %self.handler.tree_name, path = file_name)
+    ))]
+    pub async fn create_with(
+        &self,
+        file_name: &str,
+        args: &FileCreateArgs,
+        op: &OpOptions,
+    ) -> crate::Result<Resource> {
         let info = self.handler.info()?;
         Resource::create(
             file_name,
@@ -133,6 +162,7 @@ impl Tree {
             &self.conn_info,
             info.share_type,
             info.share_flags.dfs(),
+            op,
         )
         .await
     }
@@ -212,9 +242,30 @@ impl Tree {
         IpcTreeRef::new(self)
     }
 
+    /// Returns the security posture of the tree: its session's posture,
+    /// and whether its traffic is encrypted since the share requires it.
+    pub async fn security_posture(&self) -> crate::Result<SecurityPosture> {
+        let mut posture = self
+            .handler
+            .upstream
+            .security_posture(&self.conn_info)
+            .await?;
+        if posture.encryption_scope == EncryptionScope::None
+            && self.handler.info()?.share_flags.encrypt_data()
+        {
+            posture.encryption_scope = EncryptionScope::Share;
+        }
+        Ok(posture)
+    }
+
     /// Disconnects from the tree (share) on the server.
     ///
     /// After calling this method, none of the resources held open by the tree are accessible.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "smb.tree_disconnect",
+        skip_all,
+        fields(tree = %self.handler.tree_name)
+    ))]
     pub async fn disconnect(&self) -> crate::Result<()> {
         self.handler.disconnect().await?;
         Ok(())
@@ -254,6 +305,9 @@ pub(crate) struct TreeMessageHandler {
 
     tree_name: String,
     info: TreeConnectInfo,
+
+    /// Idle handles and leases of the files opened from the tree.
+    handle_cache: HandleCache,
 }
 
 impl TreeMessageHandler {
@@ -264,12 +318,14 @@ impl TreeMessageHandler {
         tree_id: u32,
         tree_name: String,
         info: TreeConnectInfo,
+        handle_cache: HandleCache,
     ) -> HandlerReference<TreeMessageHandler> {
         HandlerReference::new(TreeMessageHandler {
             tree_id: AtomicU32::new(tree_id),
             upstream: upstream.clone(),
             info,
             tree_name,
+            handle_cache,
         })
     }
 
@@ -292,8 +348,40 @@ impl TreeMessageHandler {
             // Already disconnected
             return Ok(());
         }
+        // The server closes all the opens of the tree.
+        self.handle_cache.clear().await?;
         let encrypt = self.info.share_flags.encrypt_data();
-        Self::_disconnect(self.upstream.clone(), tree_id, encrypt).await
+        Self::_disconnect(self.upstream.clone(), tree_id, encrypt).await?;
+        self.emit_disconnected(tree_id, false);
+        Ok(())
+    }
+
+    /// Marks the tree as disconnected if the error is the server's indication that the tree
+    /// no longer exists (e.g. the share was deleted).
+    fn _check_deleted(&self, error: &Error) {
+        if let Error::UnexpectedMessageStatus(status) | Error::ReceivedErrorMessage(status, _) =
+            error
+        {
+            if *status != Status::U32_NETWORK_NAME_DELETED {
+                return;
+            }
+            let tree_id = self.tree_id.swap(Self::INVALID_TREE_ID, Ordering::SeqCst);
+            if tree_id != Self::INVALID_TREE_ID {
+                log::warn!("Tree {} was disconnected by the server.", self.tree_name);
+                self.emit_disconnected(tree_id, true);
+            }
+        }
+    }
+
+    fn emit_disconnected(&self, tree_id: u32, by_server: bool) {
+        let channel = self.upstream.primary_channel();
+        channel.emit_event(|server| SmbEvent::TreeDisconnected {
+            server,
+            session_id: channel.session_id(),
+            tree_id,
+            share: self.tree_name.clone(),
+            by_server,
+        });
     }
 
     pub fn info(&self) -> crate::Result<&TreeConnectInfo> {
@@ -303,6 +391,10 @@ impl TreeMessageHandler {
 
         Ok(&self.info)
     }
+
+    pub fn handle_cache(&self) -> &HandleCache {
+        &self.handle_cache
+    }
 }
 
 impl MessageHandler for TreeMessageHandler {
@@ -326,7 +418,11 @@ impl MessageHandler for TreeMessageHandler {
         &self,
         options: crate::msg_handler::ReceiveOptions<'_>,
     ) -> crate::Result<crate::msg_handler::IncomingMessage> {
-        let msg = self.upstream.recvo(options).await?;
+        let msg = self
+            .upstream
+            .recvo(options)
+            .await
+            .inspect_err(|e| self._check_deleted(e))?;
 
         if !msg.message.header.flags.async_command()
             && msg.message.header.tree_id.unwrap() != self.tree_id.load(Ordering::SeqCst)
@@ -0,0 +1,869 @@
+//! A robust watcher of directory trees, built on top of [`Directory::watch`].
+//!
+//! [`DirectoryWatcher`] turns the raw change notifications of the server into [`WatchEvent`]s:
+//! - Rename notifications (old name, new name) are paired into a single [`WatchEvent::Moved`].
+//! - Bursts of notifications are debounced, and the changes of the same path are coalesced.
+//! - When the server drops notifications (`STATUS_NOTIFY_ENUM_DIR`), the directory tree is rescanned,
+//!   and compared against its last known state.
+//! - When watching fails, e.g. since the connection was lost, the watcher reconnects through its
+//!   [`WatchTarget`], and rescans the directory tree as well.
+//!
+//! ```no_run
+//! # use smb::{Client, ClientConfig, Credentials, UncPath};
+//! # use smb::watcher::{ClientWatchTarget, DirectoryWatcher, WatcherConfig};
+//! # use std::str::FromStr;
+//! # #[cfg(not(feature = "async"))] fn main() {}
+//! # #[cfg(feature = "async")]
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = Client::new(ClientConfig::default());
+//! let share = UncPath::from_str(r"\\server\share")?;
+//! let credentials = Credentials::password("user", "password".to_string())?;
+//! let target = ClientWatchTarget::new(&client, &share, credentials);
+//! let mut watcher = DirectoryWatcher::start(target, r"data\inbox", WatcherConfig::default()).await?;
+//! loop {
+//!     for event in watcher.next_events().await? {
+//!         println!("{event:?}");
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use maybe_async::maybe_async;
+use smb_dtyp::binrw_util::prelude::FileTime;
+use smb_fscc::{
+    DirAccessMask, FileIdBothDirectoryInformation, FileNotifyInformation, NotifyAction,
+};
+use smb_msg::{NotifyFilter, Status};
+
+use crate::msg_handler::ReceiveOptions;
+use crate::resource::directory::DirectoryWatchResult;
+use crate::{Client, Credentials, Directory, Error, OpOptions, Tree, UncPath};
+
+/// A change in a watched directory tree.
+///
+/// Paths are relative to the watched directory, and separated by backslashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// A file or a directory was created.
+    Created(String),
+    /// The data or the attributes of a file or a directory were modified.
+    Modified(String),
+    /// A file or a directory was removed.
+    Removed(String),
+    /// A file or a directory was renamed (moved), along with its descendants.
+    Moved { from: String, to: String },
+    /// Changes might have been missed, so the directory tree was rescanned.
+    /// The changes found by the rescan follow this event.
+    Rescanned(RescanReason),
+}
+
+impl WatchEvent {
+    /// Whether this event changes the specified path (or moves it away).
+    fn touches(&self, path: &str) -> bool {
+        match self {
+            WatchEvent::Created(p) | WatchEvent::Modified(p) | WatchEvent::Removed(p) => p == path,
+            WatchEvent::Moved { from, to } => from == path || to == path,
+            WatchEvent::Rescanned(_) => false,
+        }
+    }
+}
+
+/// The reason of a [`WatchEvent::Rescanned`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RescanReason {
+    /// The server had more changes than it could report (`STATUS_NOTIFY_ENUM_DIR`).
+    Overflow,
+    /// Watching failed, and the directory was opened again, e.g. after reconnecting to the server.
+    Reconnected,
+}
+
+/// Configuration of a [`DirectoryWatcher`].
+#[derive(Debug, Clone)]
+pub struct WatcherConfig {
+    /// The changes to watch for.
+    pub filter: NotifyFilter,
+    /// Whether to watch the subdirectories of the directory as well.
+    pub recursive: bool,
+    /// Changes are reported once no further change arrives for this long.
+    pub debounce: Duration,
+    /// The longest time a change is held back while debouncing a continuous burst of changes.
+    pub max_delay: Duration,
+    /// The delay between attempts to re-arm the watcher, after watching failed.
+    pub retry_delay: Duration,
+    /// The maximum number of consecutive attempts to re-arm the watcher. `None` retries forever.
+    ///
+    /// Defaults to 10.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        Self {
+            filter: NotifyFilter::all(),
+            recursive: true,
+            debounce: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            retry_delay: Duration::from_secs(1),
+            max_retries: Some(10),
+        }
+    }
+}
+
+/// Provides the tree (share) of a watched directory, to a [`DirectoryWatcher`].
+#[maybe_async(AFIT)]
+#[allow(async_fn_in_trait)]
+pub trait WatchTarget {
+    /// Returns the tree of the watched directory, connecting to it if required.
+    ///
+    /// Called when the watcher starts, and again whenever watching failed,
+    /// e.g. since the connection to the server was lost.
+    async fn tree(&self) -> crate::Result<Arc<Tree>>;
+}
+
+/// A connected tree is a target that is never reconnected.
+#[maybe_async(AFIT)]
+impl WatchTarget for Arc<Tree> {
+    async fn tree(&self) -> crate::Result<Arc<Tree>> {
+        Ok(self.clone())
+    }
+}
+
+/// A share connected through a [`Client`], which reconnects to the server once the connection is lost.
+pub struct ClientWatchTarget<'a> {
+    client: &'a Client,
+    share: UncPath,
+    credentials: Credentials,
+}
+
+impl<'a> ClientWatchTarget<'a> {
+    pub fn new(client: &'a Client, share: &UncPath, credentials: impl Into<Credentials>) -> Self {
+        Self {
+            client,
+            share: share.clone().with_no_path(),
+            credentials: credentials.into(),
+        }
+    }
+}
+
+#[maybe_async(AFIT)]
+impl WatchTarget for ClientWatchTarget<'_> {
+    async fn tree(&self) -> crate::Result<Arc<Tree>> {
+        // Connecting replaces a lost connection to the server, along with its shares.
+        self.client.connect(self.share.server()).await?;
+        self.client
+            .share_connect_with_credentials(&self.share, self.credentials.clone())
+            .await?;
+        self.client
+            .get_tree_as(&self.share, &self.credentials.identity())
+            .await
+    }
+}
+
+/// The last known state of an entry in the watched directory tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    file_id: u64,
+    is_dir: bool,
+    /// The size and the last write time of the entry, unless it was modified since it was scanned.
+    state: Option<(u64, FileTime)>,
+}
+
+/// The watched directory tree, by relative path. `None` marks entries created since the tree was scanned.
+type Snapshot = BTreeMap<String, Option<Entry>>;
+
+/// The changes reported by the server in a single debounce window.
+enum Batch {
+    Changes(Vec<FileNotifyInformation>),
+    Overflow,
+}
+
+/// Watches a directory tree for changes, without missing any.
+///
+/// See the [module documentation][self] for more information.
+pub struct DirectoryWatcher<T: WatchTarget> {
+    target: T,
+    path: String,
+    config: WatcherConfig,
+    /// The tree and the open watched directory, unless watching failed.
+    opened: Option<(Arc<Tree>, Arc<Directory>)>,
+    snapshot: Snapshot,
+}
+
+#[maybe_async(AFIT)]
+impl<T: WatchTarget> DirectoryWatcher<T> {
+    /// How long the watch is armed before scanning the directory tree. See [`Self::open`].
+    const PRIME_TIMEOUT: Duration = Duration::from_millis(10);
+
+    /// Starts watching the directory at `path`, relative to the share of the target.
+    pub async fn start(target: T, path: &str, config: WatcherConfig) -> crate::Result<Self> {
+        let mut watcher = Self {
+            target,
+            path: path.replace('/', "\\").trim_matches('\\').to_string(),
+            config,
+            opened: None,
+            snapshot: Snapshot::new(),
+        };
+        let tree = watcher.target.tree().await?;
+        watcher.snapshot = watcher.open(tree).await?;
+        Ok(watcher)
+    }
+
+    /// Waits for the next changes in the directory tree.
+    ///
+    /// Returns the changes of a single debounced burst. When changes might have been missed,
+    /// returns a [`WatchEvent::Rescanned`] event, followed by the changes found by rescanning.
+    pub async fn next_events(&mut self) -> crate::Result<Vec<WatchEvent>> {
+        let mut failures = 0;
+        loop {
+            let Some((tree, directory)) = self.opened.clone() else {
+                match self.rearm().await {
+                    Ok(events) => return Ok(events),
+                    Err(e) if Self::is_transient(&e) => {
+                        failures += 1;
+                        if self.config.max_retries.is_some_and(|max| failures > max) {
+                            return Err(e);
+                        }
+                        log::warn!(
+                            "Failed to re-arm the watcher of {} ({failures}): {e}",
+                            self.path
+                        );
+                        sleep(self.config.retry_delay).await;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+
+            let result = match self.wait(&directory).await {
+                Ok(Batch::Changes(notifications)) => Ok(self.apply(notifications)),
+                Ok(Batch::Overflow) => {
+                    log::debug!("Notifications of {} overflowed. Rescanning.", self.path);
+                    self.rescan(&tree, RescanReason::Overflow).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(events) if events.is_empty() => continue,
+                Ok(events) => return Ok(events),
+                Err(e) if Self::is_transient(&e) => {
+                    log::warn!("Watching {} failed: {e}. Re-arming the watcher.", self.path);
+                    self.opened = None;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Stops watching, and closes the directory.
+    pub async fn close(mut self) -> crate::Result<()> {
+        match self.opened.take() {
+            Some((_, directory)) => directory.close().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Opens the directory of a new tree, and compares it against the last known state.
+    async fn rearm(&mut self) -> crate::Result<Vec<WatchEvent>> {
+        let tree = self.target.tree().await?;
+        let snapshot = self.open(tree).await?;
+        Ok(self.replace_snapshot(snapshot, RescanReason::Reconnected))
+    }
+
+    /// Scans the directory tree again, and compares it against the last known state.
+    async fn rescan(
+        &mut self,
+        tree: &Tree,
+        reason: RescanReason,
+    ) -> crate::Result<Vec<WatchEvent>> {
+        let snapshot = self.scan(tree).await?;
+        Ok(self.replace_snapshot(snapshot, reason))
+    }
+
+    /// Opens the watched directory, and scans its tree.
+    async fn open(&mut self, tree: Arc<Tree>) -> crate::Result<Snapshot> {
+        let directory = Arc::new(Self::open_directory(&tree, &self.path).await?);
+        // The server records changes for the next watch request only once a watch was requested
+        // on the handle, so register a watch before scanning, to keep changes made while scanning.
+        let prime = directory
+            ._watch_options(
+                self.config.filter,
+                self.config.recursive,
+                ReceiveOptions::new().with_timeout(Self::PRIME_TIMEOUT),
+                &OpOptions::new().with_timeout(Self::PRIME_TIMEOUT),
+            )
+            .await;
+        if let DirectoryWatchResult::Error(e) = prime {
+            return Err(e);
+        }
+        let snapshot = self.scan(&tree).await?;
+        self.opened = Some((tree, directory));
+        Ok(snapshot)
+    }
+
+    /// Waits for a batch of changes, until the changes stop arriving for the debounce period.
+    async fn wait(&self, directory: &Directory) -> crate::Result<Batch> {
+        let mut notifications = match self.watch(directory, None).await? {
+            Some(Batch::Changes(notifications)) => notifications,
+            _ => return Ok(Batch::Overflow),
+        };
+        let started = Instant::now();
+        while started.elapsed() < self.config.max_delay {
+            match self.watch(directory, Some(self.config.debounce)).await? {
+                Some(Batch::Changes(more)) => notifications.extend(more),
+                Some(Batch::Overflow) => return Ok(Batch::Overflow),
+                None => break,
+            }
+        }
+        Ok(Batch::Changes(notifications))
+    }
+
+    /// Watches the directory once. Returns `None` if no change arrived within the timeout.
+    async fn watch(
+        &self,
+        directory: &Directory,
+        timeout: Option<Duration>,
+    ) -> crate::Result<Option<Batch>> {
+        let op = match timeout {
+            Some(timeout) => OpOptions::new().with_timeout(timeout),
+            None => OpOptions::new(),
+        };
+        let result = directory
+            ._watch_options(
+                self.config.filter,
+                self.config.recursive,
+                ReceiveOptions::new().with_timeout(timeout.unwrap_or(Duration::MAX)),
+                &op,
+            )
+            .await;
+        match result {
+            // Some servers report an overflow as an empty response.
+            DirectoryWatchResult::Notifications(n) if n.is_empty() => Ok(Some(Batch::Overflow)),
+            DirectoryWatchResult::Notifications(n) => Ok(Some(Batch::Changes(n))),
+            DirectoryWatchResult::NotifyEnumDir { .. } => Ok(Some(Batch::Overflow)),
+            DirectoryWatchResult::Cancelled if timeout.is_some() => Ok(None),
+            DirectoryWatchResult::Cancelled => Err(Error::Cancelled("watch cancelled")),
+            DirectoryWatchResult::Cleanup => Err(Error::Cancelled("watch cleaned up by server")),
+            DirectoryWatchResult::Error(e) => Err(e),
+        }
+    }
+
+    /// Lists the watched directory tree.
+    async fn scan(&self, tree: &Tree) -> crate::Result<Snapshot> {
+        let mut snapshot = Snapshot::new();
+        let mut pending = vec![String::new()];
+        while let Some(relative) = pending.pop() {
+            let directory =
+                Arc::new(Self::open_directory(tree, &join(&self.path, &relative)).await?);
+            let entries =
+                Directory::query_all::<FileIdBothDirectoryInformation>(&directory, "*").await;
+            directory.close().await.ok();
+            for entry in entries? {
+                let name = entry.file_name.to_string();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let path = join(&relative, &name);
+                let is_dir = entry.file_attributes.directory();
+                // Do not follow links, which might lead outside of the tree, or loop.
+                if is_dir && self.config.recursive && !entry.file_attributes.reparse_point() {
+                    pending.push(path.clone());
+                }
+                snapshot.insert(
+                    path,
+                    Some(Entry {
+                        file_id: entry.file_id,
+                        is_dir,
+                        state: Some((entry.end_of_file, entry.last_write_time)),
+                    }),
+                );
+            }
+        }
+        Ok(snapshot)
+    }
+
+    async fn open_directory(tree: &Tree, path: &str) -> crate::Result<Directory> {
+        tree.open_existing(path, DirAccessMask::new().with_list_directory(true).into())
+            .await?
+            .try_into()
+            .map_err(|_| Error::InvalidArgument(format!("{path} is not a directory")))
+    }
+
+    /// Whether watching may succeed once re-armed, i.e. the connection, session or tree was lost.
+    fn is_transient(error: &Error) -> bool {
+        match error {
+            Error::ConnectionStopped
+            | Error::IoError(_)
+            | Error::TransportError(_)
+            | Error::OperationTimeout(..) => true,
+            Error::UnexpectedMessageStatus(status) | Error::ReceivedErrorMessage(status, _) => [
+                Status::NetworkNameDeleted,
+                Status::UserSessionDeleted,
+                Status::NetworkSessionExpired,
+            ]
+            .iter()
+            .any(|s| *s as u32 == *status),
+            _ => false,
+        }
+    }
+
+    /// Turns notifications into events, and applies them to the last known state.
+    fn apply(&mut self, notifications: Vec<FileNotifyInformation>) -> Vec<WatchEvent> {
+        let mut events = Coalescer::default();
+        let mut renamed_from = None;
+        for notification in notifications {
+            let path = notification.file_name.to_string();
+            match notification.action {
+                NotifyAction::Added => events.push(WatchEvent::Created(path)),
+                NotifyAction::Removed => events.push(WatchEvent::Removed(path)),
+                NotifyAction::RenamedOldName => {
+                    if let Some(from) = renamed_from.replace(path) {
+                        events.push(WatchEvent::Removed(from));
+                    }
+                }
+                NotifyAction::RenamedNewName => match renamed_from.take() {
+                    Some(from) => events.push(WatchEvent::Moved { from, to: path }),
+                    None => events.push(WatchEvent::Created(path)),
+                },
+                // Changes of named streams (`file:stream`) modify the file.
+                _ => {
+                    let path = match path.split_once(':') {
+                        Some((file, _)) => file.to_string(),
+                        None => path,
+                    };
+                    events.push(WatchEvent::Modified(path));
+                }
+            }
+        }
+        if let Some(from) = renamed_from {
+            events.push(WatchEvent::Removed(from));
+        }
+
+        for event in &events.events {
+            self.update_snapshot(event);
+        }
+        events.events
+    }
+
+    fn update_snapshot(&mut self, event: &WatchEvent) {
+        match event {
+            WatchEvent::Created(path) => {
+                self.snapshot.insert(path.clone(), None);
+            }
+            WatchEvent::Modified(path) => {
+                if let Some(entry) = self.snapshot.entry(path.clone()).or_insert(None) {
+                    entry.state = None;
+                }
+            }
+            WatchEvent::Removed(path) => {
+                let removed = subtree(&self.snapshot, path);
+                for key in removed {
+                    self.snapshot.remove(&key);
+                }
+            }
+            WatchEvent::Moved { from, to } => {
+                let moved = subtree(&self.snapshot, from);
+                for key in moved {
+                    let entry = self.snapshot.remove(&key).unwrap();
+                    self.snapshot
+                        .insert(format!("{to}{}", &key[from.len()..]), entry);
+                }
+            }
+            WatchEvent::Rescanned(_) => {}
+        }
+    }
+
+    /// Replaces the last known state with a new scan, returning the differences between them.
+    fn replace_snapshot(&mut self, snapshot: Snapshot, reason: RescanReason) -> Vec<WatchEvent> {
+        let mut events = vec![WatchEvent::Rescanned(reason)];
+        events.extend(diff(&self.snapshot, &snapshot));
+        self.snapshot = snapshot;
+        events
+    }
+}
+
+#[maybe_async]
+async fn sleep(duration: Duration) {
+    #[cfg(feature = "async")]
+    {
+        tokio::time::sleep(duration).await;
+    }
+    #[cfg(not(feature = "async"))]
+    {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Joins a relative path to a base path.
+fn join(base: &str, name: &str) -> String {
+    match (base.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (_, true) => base.to_string(),
+        _ => format!("{base}\\{name}"),
+    }
+}
+
+/// Returns the path and the paths of the descendants of it.
+fn subtree(snapshot: &Snapshot, path: &str) -> Vec<String> {
+    let prefix = format!("{path}\\");
+    snapshot
+        .keys()
+        .filter(|key| *key == path || key.starts_with(&prefix))
+        .cloned()
+        .collect()
+}
+
+/// Returns the changes between two states of a directory tree.
+///
+/// Entries removed from one path and created in another with the same file ID are reported as moved.
+fn diff(old: &Snapshot, new: &Snapshot) -> Vec<WatchEvent> {
+    let mut removed = old
+        .iter()
+        .filter(|(path, _)| !new.contains_key(*path))
+        .collect::<Vec<_>>();
+    let mut created = new
+        .keys()
+        .filter(|path| !old.contains_key(*path))
+        .collect::<Vec<_>>();
+
+    // Pair moved entries by their file ID.
+    let removed_ids = removed
+        .iter()
+        .filter_map(|(path, entry)| entry.filter(|e| e.file_id != 0).map(|e| (e.file_id, *path)))
+        .collect::<HashMap<_, _>>();
+    let mut moves = vec![];
+    let mut modified = vec![];
+    created.retain(|path| {
+        let entry = new[*path].unwrap();
+        match removed_ids.get(&entry.file_id) {
+            Some(from) if entry.file_id != 0 => {
+                if is_modified(&old[*from], &entry) {
+                    modified.push((*path).clone());
+                }
+                moves.push(((*from).clone(), (*path).clone()));
+                false
+            }
+            _ => true,
+        }
+    });
+    removed.retain(|(path, _)| !moves.iter().any(|(from, _)| from == *path));
+
+    // Descendants are moved along with their moved ancestors.
+    let top_moves = moves
+        .iter()
+        .filter(|(from, to)| {
+            !moves.iter().any(|(parent_from, parent_to)| {
+                from.strip_prefix(&format!("{parent_from}\\"))
+                    .is_some_and(|rest| *to == format!("{parent_to}\\{rest}"))
+            })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    // Paths of removed descendants of moved directories are reported after the move.
+    let moved_path = |path: &str| {
+        top_moves
+            .iter()
+            .find_map(|(from, to)| {
+                path.strip_prefix(&format!("{from}\\"))
+                    .map(|rest| format!("{to}\\{rest}"))
+            })
+            .unwrap_or_else(|| path.to_string())
+    };
+
+    let mut events = top_moves
+        .iter()
+        .map(|(from, to)| WatchEvent::Moved {
+            from: from.clone(),
+            to: to.clone(),
+        })
+        .collect::<Vec<_>>();
+    events.extend(
+        removed
+            .iter()
+            .map(|(path, _)| WatchEvent::Removed(moved_path(path))),
+    );
+    for (path, entry) in new {
+        let Some(previous) = old.get(path) else {
+            continue;
+        };
+        let entry = entry.unwrap();
+        if previous.is_some_and(|p| p.is_dir != entry.is_dir) {
+            events.push(WatchEvent::Removed(path.clone()));
+            created.push(path);
+        } else if is_modified(previous, &entry) {
+            modified.push(path.clone());
+        }
+    }
+    created.sort();
+    events.extend(created.into_iter().cloned().map(WatchEvent::Created));
+    events.extend(modified.into_iter().map(WatchEvent::Modified));
+    events
+}
+
+/// Whether a file differs from its last known state. Changes of directories are reported by their entries.
+fn is_modified(previous: &Option<Entry>, current: &Entry) -> bool {
+    if current.is_dir {
+        return false;
+    }
+    match previous {
+        Some(previous) => previous.state.is_none() || previous.state != current.state,
+        None => true,
+    }
+}
+
+/// Coalesces the changes of the same paths within a batch.
+#[derive(Default)]
+struct Coalescer {
+    events: Vec<WatchEvent>,
+}
+
+impl Coalescer {
+    fn last(&self, path: &str) -> Option<usize> {
+        self.events.iter().rposition(|e| e.touches(path))
+    }
+
+    fn push(&mut self, event: WatchEvent) {
+        match event {
+            WatchEvent::Modified(path) => match self.last(&path).map(|i| &self.events[i]) {
+                Some(WatchEvent::Created(p) | WatchEvent::Modified(p)) if *p == path => {}
+                _ => self.events.push(WatchEvent::Modified(path)),
+            },
+            WatchEvent::Created(path) => match self.last(&path) {
+                // Replaced.
+                Some(i) if self.events[i] == WatchEvent::Removed(path.clone()) => {
+                    self.events[i] = WatchEvent::Modified(path);
+                }
+                _ => self.events.push(WatchEvent::Created(path)),
+            },
+            WatchEvent::Removed(path) => match self.last(&path).map(|i| (i, &self.events[i])) {
+                // Created and removed within the batch.
+                Some((i, WatchEvent::Created(p))) if *p == path => {
+                    self.events.remove(i);
+                }
+                Some((i, WatchEvent::Modified(p))) if *p == path => {
+                    self.events.remove(i);
+                    self.push(WatchEvent::Removed(path));
+                }
+                Some((i, WatchEvent::Moved { from, to })) if *to == path => {
+                    let from = from.clone();
+                    self.events.remove(i);
+                    self.push(WatchEvent::Removed(from));
+                }
+                _ => self.events.push(WatchEvent::Removed(path)),
+            },
+            WatchEvent::Moved { from, to } => {
+                match self.last(&from).map(|i| (i, &self.events[i])) {
+                    Some((i, WatchEvent::Created(p))) if *p == from => {
+                        self.events[i] = WatchEvent::Created(to);
+                    }
+                    Some((
+                        i,
+                        WatchEvent::Moved {
+                            from: origin,
+                            to: p,
+                        },
+                    )) if *p == from => {
+                        if *origin == to {
+                            // Moved back.
+                            self.events.remove(i);
+                        } else {
+                            self.events[i] = WatchEvent::Moved {
+                                from: origin.clone(),
+                                to,
+                            };
+                        }
+                    }
+                    _ => self.events.push(WatchEvent::Moved { from, to }),
+                }
+            }
+            WatchEvent::Rescanned(_) => self.events.push(event),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::ConnectionConfig;
+    use crate::testing::{FakeReply, FakeServer, FakeServerConfig, FaultController, FaultRules};
+    use smb_msg::{ChangeNotifyResponse, Command};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Makes a new connection to the fake server whenever the tree is requested.
+    struct FakeTarget {
+        server: FakeServer,
+        faults: Mutex<Option<FaultController>>,
+    }
+
+    #[maybe_async(AFIT)]
+    impl WatchTarget for FakeTarget {
+        async fn tree(&self) -> crate::Result<Arc<Tree>> {
+            let (connection, faults) = self
+                .server
+                .connect_with_faults(ConnectionConfig::default(), FaultRules::new())
+                .await?;
+            *self.faults.lock().unwrap() = Some(faults);
+            let session = connection.authenticate(self.server.identity()).await?;
+            let tree = session
+                .tree_connect(&self.server.share_path("share"))
+                .await?;
+            Ok(Arc::new(tree))
+        }
+    }
+
+    fn notify(changes: Vec<(NotifyAction, &str)>) -> FakeReply {
+        let buffer = changes
+            .into_iter()
+            .map(|(action, file_name)| FileNotifyInformation {
+                action,
+                file_name: file_name.into(),
+            })
+            .collect();
+        FakeReply::Respond(ChangeNotifyResponse { buffer }.into())
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_directory_watcher() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_directory("share", "watched");
+        server.add_file("share", r"watched\a.txt", b"a".to_vec());
+        let replies = Arc::new(Mutex::new(VecDeque::new()));
+        server.on(Command::ChangeNotify, {
+            let replies = replies.clone();
+            move |_| {
+                replies
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .unwrap_or(FakeReply::Default)
+            }
+        });
+        let target = FakeTarget {
+            server: server.clone(),
+            faults: Mutex::new(None),
+        };
+        let config = WatcherConfig {
+            debounce: Duration::from_millis(50),
+            retry_delay: Duration::from_millis(10),
+            max_retries: Some(3),
+            ..Default::default()
+        };
+        let mut watcher = DirectoryWatcher::start(target, "watched", config)
+            .await
+            .unwrap();
+
+        // A burst: renames are paired, and changes of the same file are coalesced.
+        server.rename("share", r"watched\a.txt", r"watched\b.txt");
+        server.add_file("share", r"watched\c.txt", vec![]);
+        replies.lock().unwrap().extend([
+            notify(vec![
+                (NotifyAction::RenamedOldName, "a.txt"),
+                (NotifyAction::RenamedNewName, "b.txt"),
+                (NotifyAction::Added, "c.txt"),
+            ]),
+            notify(vec![(NotifyAction::Modified, "c.txt")]),
+        ]);
+        let events = watcher.next_events().await.unwrap();
+        assert_eq!(
+            events,
+            vec![
+                WatchEvent::Moved {
+                    from: "a.txt".to_string(),
+                    to: "b.txt".to_string()
+                },
+                WatchEvent::Created("c.txt".to_string()),
+            ]
+        );
+
+        // An overflow: the tree is rescanned, and moves are detected by file IDs.
+        server.rename("share", r"watched\b.txt", r"watched\e.txt");
+        server.add_file("share", r"watched\d.txt", vec![1]);
+        replies
+            .lock()
+            .unwrap()
+            .push_back(FakeReply::Error(Status::NotifyEnumDir));
+        let events = watcher.next_events().await.unwrap();
+        assert_eq!(
+            events,
+            vec![
+                WatchEvent::Rescanned(RescanReason::Overflow),
+                WatchEvent::Moved {
+                    from: "b.txt".to_string(),
+                    to: "e.txt".to_string()
+                },
+                WatchEvent::Created("d.txt".to_string()),
+                // Not scanned since it was created.
+                WatchEvent::Modified("c.txt".to_string()),
+            ]
+        );
+
+        // A lost connection: the watcher reconnects, and finds the changes made meanwhile.
+        server.add_directory("share", r"watched\sub");
+        server.add_file("share", r"watched\sub\f.txt", vec![]);
+        let faults = watcher.target.faults.lock().unwrap().take().unwrap();
+        faults.disconnect();
+        let events = watcher.next_events().await.unwrap();
+        assert_eq!(
+            events,
+            vec![
+                WatchEvent::Rescanned(RescanReason::Reconnected),
+                WatchEvent::Created("sub".to_string()),
+                WatchEvent::Created(r"sub\f.txt".to_string()),
+            ]
+        );
+        watcher.close().await.unwrap();
+    }
+
+    #[maybe_async::test(feature = "__is_sync", async(not(feature = "__is_sync"), tokio::test))]
+    async fn test_watch_errors() {
+        let server = FakeServer::new(FakeServerConfig::default());
+        server.add_directory("share", "watched");
+        let replies = Arc::new(Mutex::new(VecDeque::new()));
+        server.on(Command::ChangeNotify, {
+            let replies = replies.clone();
+            move |_| {
+                replies
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .unwrap_or(FakeReply::Default)
+            }
+        });
+        let target = FakeTarget {
+            server: server.clone(),
+            faults: Mutex::new(None),
+        };
+        let config = WatcherConfig {
+            retry_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut watcher = DirectoryWatcher::start(target, "watched", config)
+            .await
+            .unwrap();
+
+        // A deleted tree is connected again.
+        replies
+            .lock()
+            .unwrap()
+            .push_back(FakeReply::Error(Status::NetworkNameDeleted));
+        let events = watcher.next_events().await.unwrap();
+        assert_eq!(
+            events,
+            vec![WatchEvent::Rescanned(RescanReason::Reconnected)]
+        );
+
+        // Other errors are not retried.
+        replies
+            .lock()
+            .unwrap()
+            .push_back(FakeReply::Error(Status::AccessDenied));
+        let result = watcher.next_events().await;
+        assert!(result.is_err());
+        watcher.close().await.unwrap();
+    }
+}
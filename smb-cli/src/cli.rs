@@ -1,12 +1,15 @@
-use crate::{copy::CopyCmd, info::InfoCmd, security::SecurityCmd, watch::WatchCmd};
+use crate::{copy::CopyCmd, info::InfoCmd, security::SecurityCmd, sync::SyncCmd, watch::WatchCmd};
 use clap::{Parser, Subcommand, ValueEnum};
 use smb::connection::MultiChannelConfig;
 use smb::transport::config::*;
 use smb::{
-    ClientConfig, ConnectionConfig,
-    connection::{AuthMethodsConfig, EncryptionMode},
+    ClientConfig, ConnectionConfig, Credentials,
+    client::{CredentialProviderHandle, CredentialsFileProvider},
+    connection::{AuthMethodsConfig, EncryptionMode, SpnCanonicalization},
 };
 use smb::{Dialect, Guid};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -40,18 +43,47 @@ pub struct Cli {
     /// Disables Kerberos authentication.
     #[arg(long)]
     pub no_kerberos: bool,
+    /// The KDC to use for Kerberos authentication, instead of discovering it.
+    #[arg(long)]
+    pub kdc_url: Option<String>,
+    /// The Kerberos realm of the user, if not specified in the user name.
+    #[arg(long)]
+    pub realm: Option<String>,
+    /// The SPN of the server (e.g. `cifs/fs01.corp.example.com`), instead of deriving it from the server name.
+    #[arg(long)]
+    pub spn: Option<String>,
+    /// How to derive the SPN of the server from its name.
+    #[arg(long, default_value_t = CliSpnCanonicalization::default())]
+    pub spn_canonicalization: CliSpnCanonicalization,
+    /// Fails authentication if Kerberos is attempted but fails, instead of falling back to NTLM.
+    #[arg(long)]
+    pub no_ntlm_fallback: bool,
 
     /// Selects a transport protocol to use.
     #[arg(long)]
     pub use_transport: Option<CliUseTransport>,
 
-    #[arg(short, long)]
-    pub username: String,
-    #[arg(short, long)]
-    pub password: String,
+    #[arg(short, long, required_unless_present_any = ["anonymous", "guest"])]
+    pub username: Option<String>,
+    #[arg(short, long, required_unless_present_any = ["anonymous", "guest"])]
+    pub password: Option<String>,
+
+    /// Connects using an anonymous (null) session, e.g. to list shares.
+    #[arg(long, conflicts_with_all = ["username", "password", "guest"])]
+    pub anonymous: bool,
+
+    /// Connects as the guest user.
+    #[arg(long, conflicts_with_all = ["username", "password"])]
+    pub guest: bool,
+
+    /// A `mount.cifs`-style credentials file, with the credentials for servers and shares
+    /// other than the target (e.g. DFS referral targets).
+    #[arg(long)]
+    pub credentials_file: Option<PathBuf>,
 
     /// Disables message signing.
-    /// This may should only be used when logging in with a guest user.
+    /// This may should only be used when logging in with a guest user;
+    /// `--guest` and `--anonymous` do not require it.
     #[arg(long)]
     pub disable_message_signing: bool,
 
@@ -109,6 +141,38 @@ impl std::fmt::Display for MultiChannelMode {
     }
 }
 
+/// Describes how the SPN of the server is derived from its name.
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum CliSpnCanonicalization {
+    /// Use the server name as is.
+    #[default]
+    None,
+    /// Use the canonical name of the server, following DNS CNAME records.
+    Forward,
+    /// Use the name that the address of the server reversely resolves to.
+    Reverse,
+}
+
+impl From<CliSpnCanonicalization> for SpnCanonicalization {
+    fn from(mode: CliSpnCanonicalization) -> Self {
+        match mode {
+            CliSpnCanonicalization::None => SpnCanonicalization::None,
+            CliSpnCanonicalization::Forward => SpnCanonicalization::Forward,
+            CliSpnCanonicalization::Reverse => SpnCanonicalization::Reverse,
+        }
+    }
+}
+
+impl std::fmt::Display for CliSpnCanonicalization {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CliSpnCanonicalization::None => write!(f, "none"),
+            CliSpnCanonicalization::Forward => write!(f, "forward"),
+            CliSpnCanonicalization::Reverse => write!(f, "reverse"),
+        }
+    }
+}
+
 #[derive(ValueEnum, Copy, Clone, Debug)]
 #[cfg(feature = "rdma")]
 pub enum RdmaType {
@@ -132,12 +196,36 @@ impl From<RdmaType> for smb::transport::RdmaType {
 }
 
 impl Cli {
+    /// Returns the credentials selected by the command line.
+    pub fn credentials(&self) -> smb::Result<Credentials> {
+        if self.anonymous {
+            return Ok(Credentials::Anonymous);
+        }
+        if self.guest {
+            return Ok(Credentials::Guest);
+        }
+        Credentials::password(
+            self.username.as_deref().unwrap_or_default(),
+            self.password.clone().unwrap_or_default(),
+        )
+    }
+
     pub fn make_smb_client_config(&self) -> Result<ClientConfig, &'static str> {
         Ok(ClientConfig {
             dfs: !self.no_dfs,
             #[cfg(feature = "rdma")]
             rdma_type: self.rdma_type.map(|x| x.into()),
             client_guid: Guid::generate(),
+            security_policy: Default::default(),
+            credential_provider: self
+                .credentials_file
+                .as_ref()
+                .map(|path| {
+                    CredentialsFileProvider::from_file(path)
+                        .map(|provider| CredentialProviderHandle::new(Arc::new(provider)))
+                        .map_err(|_| "Failed to read the credentials file")
+                })
+                .transpose()?,
             connection: ConnectionConfig {
                 max_dialect: Some(Dialect::MAX),
                 encryption_mode: EncryptionMode::Allowed,
@@ -170,6 +258,12 @@ impl Cli {
                 auth_methods: AuthMethodsConfig {
                     ntlm: !self.no_ntlm,
                     kerberos: !self.no_kerberos,
+                    kdc_url: self.kdc_url.clone(),
+                    realm: self.realm.clone(),
+                    spn: self.spn.clone(),
+                    spn_canonicalization: self.spn_canonicalization.into(),
+                    ntlm_fallback: !self.no_ntlm_fallback,
+                    ..Default::default()
                 },
                 allow_unsigned_guest_access: self.disable_message_signing,
                 compression_enabled: self.compress,
@@ -188,6 +282,8 @@ pub enum Commands {
     Info(InfoCmd),
     /// Configures object security
     Security(SecurityCmd),
+    /// Synchronizes a local directory with a directory on a share.
+    Sync(SyncCmd),
     /// Watches for changes in a directory.
     Watch(WatchCmd),
 }
@@ -3,11 +3,12 @@ use clap::Parser;
 use indicatif::{ProgressBar, ProgressStyle};
 use maybe_async::*;
 use smb::sync_helpers::*;
-use smb::{Client, CreateOptions, FileAccessMask, FileAttributes, resource::*};
+use smb::{Client, CreateDisposition, CreateOptions, FileAccessMask, FileAttributes, resource::*};
 use std::collections::HashMap;
 use std::error::Error;
 #[cfg(not(feature = "async"))]
 use std::fs;
+use std::path::PathBuf;
 #[cfg(not(feature = "single_threaded"))]
 use std::sync::Arc;
 #[cfg(feature = "multi_threaded")]
@@ -22,6 +23,11 @@ pub struct CopyCmd {
     #[arg(short, long)]
     pub force: bool,
 
+    /// Persist the progress of the copy to this state file. If the file exists,
+    /// an interrupted copy to the same destination is resumed from it.
+    #[arg(long)]
+    pub checkpoint: Option<PathBuf>,
+
     /// Source path
     pub from: Path,
     /// Destination path
@@ -46,27 +52,38 @@ impl CopyFile {
         cli: &Cli,
         cmd: &CopyCmd,
         read: bool,
+        resume: bool,
     ) -> Result<Self, smb::Error> {
+        // Resuming a copy keeps the data already copied to the destination.
+        let resume = !read && resume;
         let value = match path {
             Path::Local(path_buf) => {
                 let file = fs::OpenOptions::new()
                     .read(read)
                     .write(!read)
                     .create(!read)
-                    .create_new(!read && !cmd.force)
-                    .truncate(!read)
+                    .create_new(!read && !cmd.force && !resume)
+                    .truncate(!read && !resume)
                     .open(path_buf)
                     .await?;
                 CopyFileValue::Local(Mutex::new(file))
             }
             Path::Remote(unc_path) => {
                 client
-                    .share_connect(unc_path, cli.username.as_str(), cli.password.clone())
+                    .share_connect_with_credentials(unc_path, cli.credentials()?)
                     .await?;
                 let create_args = if read {
                     FileCreateArgs::make_open_existing(
                         FileAccessMask::new().with_generic_read(true),
                     )
+                } else if resume {
+                    FileCreateArgs {
+                        disposition: CreateDisposition::OpenIf,
+                        ..FileCreateArgs::make_overwrite(
+                            FileAttributes::new().with_archive(true),
+                            CreateOptions::new(),
+                        )
+                    }
                 } else if cmd.force {
                     FileCreateArgs::make_overwrite(
                         FileAttributes::new().with_archive(true),
@@ -91,6 +108,30 @@ impl CopyFile {
         })
     }
 
+    /// Returns whether a copy from this source may resume from the checkpoint, which requires
+    /// that it was saved while copying the same, unchanged source.
+    ///
+    /// A checkpoint of another source is discarded, so the copy starts over.
+    #[maybe_async]
+    async fn can_resume(&self, checkpoint: &std::path::Path) -> Result<bool, smb::Error> {
+        let identity = match &self.value {
+            CopyFileValue::Local(file) => file.get_identity().await?,
+            CopyFileValue::Remote(file) => file.get_identity().await?,
+        };
+        if checkpoint_matches(checkpoint, &identity)? {
+            return Ok(true);
+        }
+        match std::fs::remove_file(checkpoint) {
+            Ok(()) => log::info!(
+                "Discarded checkpoint {}, since it does not match the source.",
+                checkpoint.display()
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(false)
+    }
+
     #[cfg(not(feature = "single_threaded"))]
     #[maybe_async]
     async fn _get_channel_to_jobs_map(
@@ -147,7 +188,12 @@ impl CopyFile {
     }
 
     #[maybe_async]
-    async fn copy_to(self, to: CopyFile, client: &Client) -> Result<(), smb::Error> {
+    async fn copy_to(
+        self,
+        to: CopyFile,
+        client: &Client,
+        checkpoint: Option<&std::path::Path>,
+    ) -> Result<(), smb::Error> {
         use CopyFileValue::*;
 
         let channel_jobs = self._get_channel_to_jobs_map(&to, client).await?;
@@ -155,10 +201,14 @@ impl CopyFile {
         match self.value {
             Local(from_local) => match to.value {
                 Local(_) => unreachable!(),
-                Remote(to_remote) => Self::do_copy(from_local, to_remote, channel_jobs).await?,
+                Remote(to_remote) => {
+                    Self::do_copy(from_local, to_remote, channel_jobs, checkpoint).await?
+                }
             },
             Remote(from_remote) => match to.value {
-                Local(to_local) => Self::do_copy(from_remote, to_local, channel_jobs).await?,
+                Local(to_local) => {
+                    Self::do_copy(from_remote, to_local, channel_jobs, checkpoint).await?
+                }
                 Remote(to_remote) => {
                     if to.path.as_remote().unwrap().server()
                         == self.path.as_remote().unwrap().server()
@@ -166,9 +216,12 @@ impl CopyFile {
                             == self.path.as_remote().unwrap().share()
                     {
                         // Use server-side copy if both files are on the same server
+                        if checkpoint.is_some() {
+                            log::warn!("Server-side copy does not support checkpoints.");
+                        }
                         to_remote.srv_copy(&from_remote).await?
                     } else {
-                        Self::do_copy(from_remote, to_remote, channel_jobs).await?
+                        Self::do_copy(from_remote, to_remote, channel_jobs, checkpoint).await?
                     }
                 }
             },
@@ -179,14 +232,20 @@ impl CopyFile {
     #[maybe_async]
     #[cfg(not(feature = "single_threaded"))]
     pub async fn do_copy<
-        F: ReadAtChannel + GetLen + Send + Sync + 'static,
-        T: WriteAtChannel + SetLen + Send + Sync + 'static,
+        F: ReadAtChannel + GetLen + GetIdentity + Send + Sync + 'static,
+        T: WriteAtChannel + SetLen + SyncAll + GetIdentity + Send + Sync + 'static,
     >(
         from: F,
         to: T,
         channel_jobs: HashMap<Option<u32>, usize>,
+        checkpoint: Option<&std::path::Path>,
     ) -> smb::Result<()> {
-        let state = prepare_parallel_copy(&from, &to, channel_jobs).await?;
+        let state = match checkpoint {
+            Some(checkpoint) => {
+                prepare_checkpointed_copy(&from, &to, channel_jobs, checkpoint).await?
+            }
+            None => prepare_parallel_copy(&from, &to, channel_jobs).await?,
+        };
         let state = Arc::new(state);
         let progress_handle = Self::progress(state.clone());
         start_parallel_copy(from, to, state).await?;
@@ -200,11 +259,18 @@ impl CopyFile {
 
     /// Single-threaded copy implementation.
     #[cfg(feature = "single_threaded")]
-    pub fn do_copy<F: ReadAtChannel + GetLen, T: WriteAtChannel + SetLen>(
+    pub fn do_copy<
+        F: ReadAtChannel + GetLen + GetIdentity,
+        T: WriteAtChannel + SetLen + SyncAll + GetIdentity,
+    >(
         from: F,
         to: T,
         _channels: HashMap<Option<u32>, usize>,
+        checkpoint: Option<&std::path::Path>,
     ) -> smb::Result<()> {
+        if let Some(checkpoint) = checkpoint {
+            return block_copy_checkpointed(from, to, checkpoint);
+        }
         let progress = Self::make_progress_bar(from.get_len()?);
         block_copy_progress(
             from,
@@ -261,10 +327,14 @@ pub async fn copy(cmd: &CopyCmd, cli: &Cli) -> Result<(), Box<dyn Error>> {
     }
 
     let client = Client::new(cli.make_smb_client_config()?);
-    let from = CopyFile::open(&cmd.from, &client, cli, cmd, true).await?;
-    let to = CopyFile::open(&cmd.to, &client, cli, cmd, false).await?;
+    let from = CopyFile::open(&cmd.from, &client, cli, cmd, true, false).await?;
+    let resume = match &cmd.checkpoint {
+        Some(checkpoint) => from.can_resume(checkpoint).await?,
+        None => false,
+    };
+    let to = CopyFile::open(&cmd.to, &client, cli, cmd, false, resume).await?;
 
-    let copy_ok = from.copy_to(to, &client).await;
+    let copy_ok = from.copy_to(to, &client, cmd.checkpoint.as_deref()).await;
 
     client.close().await?;
 
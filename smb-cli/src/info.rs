@@ -56,7 +56,7 @@ pub async fn info(cmd: &InfoCmd, cli: &Cli) -> Result<(), Box<dyn Error>> {
 
     if cmd.path.share().is_none() || cmd.path.share().unwrap().is_empty() {
         client
-            .ipc_connect(cmd.path.server(), &cli.username, cli.password.clone())
+            .ipc_connect_with_credentials(cmd.path.server(), cli.credentials()?)
             .await?;
         let shares_info = client.list_shares(cmd.path.server()).await?;
         log::info!("Available shares on {}: ", cmd.path.server());
@@ -67,7 +67,7 @@ pub async fn info(cmd: &InfoCmd, cli: &Cli) -> Result<(), Box<dyn Error>> {
     }
 
     client
-        .share_connect(&cmd.path, cli.username.as_ref(), cli.password.clone())
+        .share_connect_with_credentials(&cmd.path, cli.credentials()?)
         .await?;
     let resource = client
         .create_file(
@@ -3,6 +3,7 @@ pub mod copy;
 pub mod info;
 pub mod path;
 pub mod security;
+pub mod sync;
 pub mod watch;
 
 pub use cli::*;
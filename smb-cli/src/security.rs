@@ -105,11 +105,7 @@ async fn open_resource(
     }
 
     client
-        .share_connect(
-            &security_cmd.path,
-            cli.username.as_ref(),
-            cli.password.clone(),
-        )
+        .share_connect_with_credentials(&security_cmd.path, cli.credentials()?)
         .await?;
     let resource = client
         .create_file(
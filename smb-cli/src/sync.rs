@@ -0,0 +1,77 @@
+use crate::{Cli, path::*};
+use clap::Parser;
+use maybe_async::*;
+use smb::Client;
+use smb::sync::{SyncDirection, SyncOptions, synchronize};
+use std::error::Error;
+
+#[derive(Parser, Debug)]
+pub struct SyncCmd {
+    /// Delete files and directories of the destination that do not exist in the source.
+    #[arg(long)]
+    pub mirror: bool,
+
+    /// Compare the contents of files, instead of their last write times.
+    #[arg(long)]
+    pub checksum: bool,
+
+    /// Only print the actions to perform, without performing them.
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Exclude names or relative paths matching the pattern (e.g. `*.tmp`). May be repeated.
+    #[arg(short = 'x', long)]
+    pub exclude: Vec<String>,
+
+    /// The number of parallel jobs to copy each file with. Uses a default if not specified.
+    #[arg(short, long, default_value_t = 0)]
+    pub jobs: usize,
+
+    /// Source directory path
+    pub from: Path,
+    /// Destination directory path
+    pub to: Path,
+}
+
+#[maybe_async]
+pub async fn sync(cmd: &SyncCmd, cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let (local, remote, direction) = match (&cmd.from, &cmd.to) {
+        (Path::Local(local), Path::Remote(remote)) => (local, remote, SyncDirection::Upload),
+        (Path::Remote(remote), Path::Local(local)) => (local, remote, SyncDirection::Download),
+        _ => return Err("Exactly one of the paths must be a remote (UNC) path".into()),
+    };
+    if remote.share().is_none_or(|share| share.is_empty()) {
+        return Err("Remote path must include a share name".into());
+    }
+
+    let client = Client::new(cli.make_smb_client_config()?);
+    client
+        .share_connect_with_credentials(remote, cli.credentials()?)
+        .await?;
+    let tree = client.get_tree(remote).await?;
+
+    let options = SyncOptions {
+        direction,
+        mirror: cmd.mirror,
+        checksum: cmd.checksum,
+        dry_run: cmd.dry_run,
+        exclude: cmd.exclude.clone(),
+        jobs: cmd.jobs,
+    };
+    let result = synchronize(&tree, remote.path().unwrap_or_default(), local, &options).await;
+
+    client.close().await?;
+
+    let report = result?;
+    for action in &report.actions {
+        log::info!("{action:?}");
+    }
+    log::info!(
+        "{} {} actions, {} bytes {}.",
+        if cmd.dry_run { "Planned" } else { "Performed" },
+        report.actions.len(),
+        report.bytes_copied,
+        if cmd.dry_run { "to copy" } else { "copied" }
+    );
+    Ok(())
+}
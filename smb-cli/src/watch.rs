@@ -26,7 +26,7 @@ pub async fn watch(cmd: &WatchCmd, cli: &Cli) -> Result<(), Box<dyn Error>> {
 
     let client = Client::new(cli.make_smb_client_config()?);
     client
-        .share_connect(&cmd.path, &cli.username, cli.password.clone())
+        .share_connect_with_credentials(&cmd.path, cli.credentials()?)
         .await?;
 
     let dir_resource = client